@@ -0,0 +1,208 @@
+use crate::api::openai_server::MODEL_ALIASES;
+use crate::core::state::{EncoderEntry, ModelState, SharedState};
+use crate::models::common::{EmbeddingOptions, embed_token_batches, shape_embeddings};
+
+use serde::Serialize;
+use std::sync::Arc;
+use tokenizers::Tokenizer;
+
+/// Векторы текстов в порядке входа
+#[derive(Debug, Clone, Serialize)]
+pub struct EmbeddingsResult {
+    pub embeddings: Vec<Vec<f32>>,
+    /// Суммарное число токенов входа
+    pub prompt_tokens: usize,
+}
+
+/// Ошибка построения эмбеддингов
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum EmbeddingsError {
+    /// Запрос не подходит модели: пустой или слишком длинный вход, `dimensions` больше hidden size
+    InvalidRequest(String),
+    /// Модель не загружена или прогон завершился ошибкой
+    Failed(String),
+}
+
+impl std::fmt::Display for EmbeddingsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidRequest(msg) | Self::Failed(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl From<String> for EmbeddingsError {
+    fn from(msg: String) -> Self {
+        Self::Failed(msg)
+    }
+}
+
+impl From<&str> for EmbeddingsError {
+    fn from(msg: &str) -> Self {
+        Self::Failed(msg.to_string())
+    }
+}
+
+fn tokenize_inputs(
+    tokenizer: &Tokenizer,
+    inputs: &[String],
+) -> Result<Vec<Vec<u32>>, EmbeddingsError> {
+    let texts = inputs
+        .iter()
+        .map(|text| {
+            tokenizer
+                .encode(text.as_str(), true)
+                .map(|enc| enc.get_ids().to_vec())
+                .map_err(|e| e.to_string())
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if let Some(index) = texts.iter().position(Vec::is_empty) {
+        return Err(EmbeddingsError::InvalidRequest(format!(
+            "Input {} is empty",
+            index
+        )));
+    }
+    Ok(texts)
+}
+
+/// Модель считает векторы без обрезки и нормализации: `dimensions` проверяется
+/// по фактическому hidden size уже как ошибка запроса
+fn raw_options(options: &EmbeddingOptions) -> EmbeddingOptions {
+    EmbeddingOptions {
+        pooling: options.pooling,
+        normalize: false,
+        dimensions: None,
+    }
+}
+
+fn shape(
+    vectors: Vec<Vec<f32>>,
+    options: &EmbeddingOptions,
+) -> Result<Vec<Vec<f32>>, EmbeddingsError> {
+    let hidden_size = vectors.first().map_or(0, Vec::len);
+    shape_embeddings(vectors, hidden_size, options).map_err(EmbeddingsError::InvalidRequest)
+}
+
+/// Энкодер из слота эмбеддингов, если он отвечает на запрос с селектором `model`
+///
+/// Энкодер выбирается по своему id, а также когда чат-модель не загружена.
+/// Id чат-модели и общие алиасы из `/v1/models` выбирают чат-модель; любой
+/// другой id — ошибка запроса, а не молчаливый подсчёт другой моделью.
+/// Клон `Arc` позволяет считать эмбеддинги, отпустив блокировку состояния.
+pub(crate) fn embedding_encoder(
+    state: &ModelState,
+    model: Option<&str>,
+) -> Result<Option<Arc<EncoderEntry>>, EmbeddingsError> {
+    let encoder = state.encoders.embedding.clone();
+    let chat_id = state
+        .scheduler
+        .get_model_id()
+        .unwrap_or_else(|| "loaded-model".to_string());
+    match model {
+        Some(id) if encoder.as_ref().is_some_and(|entry| entry.id == id) => Ok(encoder),
+        Some(id) if id != chat_id && !MODEL_ALIASES.contains(&id) => {
+            Err(EmbeddingsError::InvalidRequest(format!(
+                "Model '{id}' is not loaded; use the embedding model or the chat model id"
+            )))
+        }
+        _ => Ok(encoder.filter(|_| !state.scheduler.has_model())),
+    }
+}
+
+/// Эмбеддинги текстов энкодерной моделью; длинные входы обрезаются токенизатором
+pub(crate) fn compute_encoder_embeddings(
+    entry: &EncoderEntry,
+    inputs: &[String],
+    options: &EmbeddingOptions,
+) -> Result<EmbeddingsResult, EmbeddingsError> {
+    let texts = tokenize_inputs(&entry.tokenizer, inputs)?;
+    let prompt_tokens = texts.iter().map(Vec::len).sum();
+    let embeddings = entry
+        .model
+        .embed(&texts, &raw_options(options))
+        .map_err(|e| format!("Embedding failed: {}", e))?;
+    Ok(EmbeddingsResult {
+        embeddings: shape(embeddings, options)?,
+        prompt_tokens,
+    })
+}
+
+/// Эмбеддинги текстов активной моделью (Tauri-команда и `/v1/embeddings`)
+///
+/// Прогон с позиции 0 перезаписывает KV-кэш чат-модели, поэтому кэш префиксов
+/// и восстановленная сессия сбрасываются: следующий ход чата заново обработает
+/// весь промпт. Энкодер из слота эмбеддингов чат не затрагивает.
+pub(crate) fn compute_embeddings(
+    state: &mut ModelState,
+    inputs: &[String],
+    options: &EmbeddingOptions,
+) -> Result<EmbeddingsResult, EmbeddingsError> {
+    let tokenizer = state
+        .tokenizer
+        .as_ref()
+        .ok_or("Model/tokenizer is not loaded")?;
+    let texts = tokenize_inputs(tokenizer, inputs)?;
+    let context_length = state.context_length.max(1);
+    if let Some((index, tokens)) = texts
+        .iter()
+        .enumerate()
+        .find(|(_, tokens)| tokens.len() > context_length)
+    {
+        return Err(EmbeddingsError::InvalidRequest(format!(
+            "Input {} has {} tokens, the context holds {}",
+            index,
+            tokens.len(),
+            context_length
+        )));
+    }
+    let prompt_tokens = texts.iter().map(Vec::len).sum();
+
+    let mut entry = state.scheduler.take_model().ok_or("Model is not loaded")?;
+    let result = embed_token_batches(
+        entry.model.as_mut(),
+        &texts,
+        &raw_options(options),
+        &state.device,
+    );
+    state.scheduler.restore_model(entry);
+    // Прогон с позиции 0 перезаписал KV-кэш модели
+    state.prefix_cache.clear();
+    state.kv_prompt_tokens.clear();
+    state.restored_session = None;
+
+    let embeddings = result.map_err(|e| format!("Embedding failed: {}", e))?;
+    Ok(EmbeddingsResult {
+        embeddings: shape(embeddings, options)?,
+        prompt_tokens,
+    })
+}
+
+/// Эмбеддинги текстов загруженной моделью с выбранным пулингом
+///
+/// `model` выбирает энкодер из слота эмбеддингов; без него считает чат-модель,
+/// сбрасывая её KV-кэш (см. [`compute_embeddings`]).
+/// Без `options` энкодер берёт пулинг и нормализацию из своей конфигурации.
+#[tauri::command]
+pub async fn get_embeddings(
+    state: tauri::State<'_, SharedState>,
+    inputs: Vec<String>,
+    options: Option<EmbeddingOptions>,
+    model: Option<String>,
+) -> Result<EmbeddingsResult, String> {
+    let state_arc = state.inner().clone();
+    tauri::async_runtime::spawn_blocking(move || -> Result<EmbeddingsResult, String> {
+        let mut guard = state_arc.lock().map_err(|e| e.to_string())?;
+        if let Some(entry) =
+            embedding_encoder(&guard, model.as_deref()).map_err(|e| e.to_string())?
+        {
+            drop(guard);
+            let options = options.unwrap_or(entry.default_options);
+            return compute_encoder_embeddings(&entry, &inputs, &options)
+                .map_err(|e| e.to_string());
+        }
+        compute_embeddings(&mut guard, &inputs, &options.unwrap_or_default())
+            .map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|e| format!("get_embeddings join error: {}", e))?
+}
@@ -0,0 +1,167 @@
+use crate::core::state::{EncoderEntry, SharedState};
+use crate::core::types::LoadEncoderRequest;
+use crate::log_load;
+use crate::models::common::Pooling;
+use crate::models::encoder::{EncoderArch, EncoderTask};
+
+use serde::Serialize;
+use std::sync::Arc;
+
+/// Описание загруженной энкодерной модели
+#[derive(Debug, Clone, Serialize)]
+pub struct EncoderModelInfo {
+    pub id: String,
+    pub arch: EncoderArch,
+    pub task: EncoderTask,
+    pub hidden_size: usize,
+    pub max_length: usize,
+    pub pooling: Pooling,
+    pub normalize: bool,
+}
+
+impl From<&EncoderEntry> for EncoderModelInfo {
+    fn from(entry: &EncoderEntry) -> Self {
+        Self {
+            id: entry.id.clone(),
+            arch: entry.model.arch(),
+            task: entry.model.task(),
+            hidden_size: entry.model.hidden_size(),
+            max_length: entry.model.max_length(),
+            pooling: entry.default_options.pooling,
+            normalize: entry.default_options.normalize,
+        }
+    }
+}
+
+/// Оценка документа для запроса
+#[derive(Debug, Clone, Serialize)]
+pub struct RerankResult {
+    /// Позиция документа во входном списке
+    pub index: usize,
+    pub relevance_score: f32,
+}
+
+/// Документы по убыванию релевантности
+#[derive(Debug, Clone, Serialize)]
+pub struct RerankResponse {
+    pub results: Vec<RerankResult>,
+    /// Суммарное число токенов всех пар запрос–документ
+    pub total_tokens: usize,
+}
+
+/// Оценивает документы реранкером и сортирует их по убыванию релевантности
+pub(crate) fn rerank_documents(
+    entry: &EncoderEntry,
+    query: &str,
+    documents: &[String],
+    top_n: Option<usize>,
+) -> Result<RerankResponse, String> {
+    let pairs = documents
+        .iter()
+        .map(|doc| {
+            entry
+                .tokenizer
+                .encode((query, doc.as_str()), true)
+                .map(|enc| (enc.get_ids().to_vec(), enc.get_type_ids().to_vec()))
+                .map_err(|e| e.to_string())
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let total_tokens = pairs.iter().map(|(ids, _)| ids.len()).sum();
+    let scores = entry
+        .model
+        .score(&pairs)
+        .map_err(|e| format!("Rerank failed: {}", e))?;
+
+    let mut results: Vec<RerankResult> = scores
+        .into_iter()
+        .enumerate()
+        .map(|(index, relevance_score)| RerankResult {
+            index,
+            relevance_score,
+        })
+        .collect();
+    results.sort_by(|a, b| b.relevance_score.total_cmp(&a.relevance_score));
+    results.truncate(top_n.unwrap_or(results.len()));
+    Ok(RerankResponse {
+        results,
+        total_tokens,
+    })
+}
+
+/// Загружает энкодер эмбеддингов или реранкер в свой слот, не трогая чат-модель
+#[tauri::command]
+pub async fn load_encoder_model(
+    state: tauri::State<'_, SharedState>,
+    req: LoadEncoderRequest,
+) -> Result<EncoderModelInfo, String> {
+    let state_arc = state.inner().clone();
+    tauri::async_runtime::spawn_blocking(move || -> Result<EncoderModelInfo, String> {
+        // Веса грузятся без блокировки состояния, чтобы не мешать генерации
+        let entry = crate::api::model_loading::encoder::load_encoder_model(req)?;
+        let info = EncoderModelInfo::from(&entry);
+        let mut guard = state_arc.lock().map_err(|e| e.to_string())?;
+        let slot = match info.task {
+            EncoderTask::Embedding => &mut guard.encoders.embedding,
+            EncoderTask::Rerank => &mut guard.encoders.reranker,
+        };
+        *slot = Some(Arc::new(entry));
+        log_load!("encoder {} loaded into {:?} slot", info.id, info.task);
+        Ok(info)
+    })
+    .await
+    .map_err(|e| format!("load_encoder_model join error: {}", e))?
+}
+
+/// Выгружает энкодер из слота
+#[tauri::command]
+pub fn unload_encoder_model(
+    state: tauri::State<'_, SharedState>,
+    task: EncoderTask,
+) -> Result<(), String> {
+    let mut guard = state.lock().map_err(|e| e.to_string())?;
+    let slot = match task {
+        EncoderTask::Embedding => &mut guard.encoders.embedding,
+        EncoderTask::Rerank => &mut guard.encoders.reranker,
+    };
+    if let Some(entry) = slot.take() {
+        log_load!("encoder {} unloaded from {:?} slot", entry.id, task);
+    }
+    Ok(())
+}
+
+/// Загруженные энкодеры (эмбеддинги и реранкер)
+#[tauri::command]
+pub fn get_encoder_models(
+    state: tauri::State<'_, SharedState>,
+) -> Result<Vec<EncoderModelInfo>, String> {
+    let guard = state.lock().map_err(|e| e.to_string())?;
+    Ok([&guard.encoders.embedding, &guard.encoders.reranker]
+        .into_iter()
+        .flatten()
+        .map(|entry| EncoderModelInfo::from(entry.as_ref()))
+        .collect())
+}
+
+/// Сортирует документы по релевантности запросу кросс-энкодером
+#[tauri::command]
+pub async fn rerank(
+    state: tauri::State<'_, SharedState>,
+    query: String,
+    documents: Vec<String>,
+    top_n: Option<usize>,
+) -> Result<RerankResponse, String> {
+    let state_arc = state.inner().clone();
+    tauri::async_runtime::spawn_blocking(move || -> Result<RerankResponse, String> {
+        // Реранкер клонируется из слота, чтобы считать без блокировки состояния
+        let entry = state_arc
+            .lock()
+            .map_err(|e| e.to_string())?
+            .encoders
+            .reranker
+            .clone()
+            .ok_or("Reranker model is not loaded")?;
+        rerank_documents(&entry, &query, &documents, top_n)
+    })
+    .await
+    .map_err(|e| format!("rerank join error: {}", e))?
+}
@@ -22,3 +22,8 @@ pub async fn generate_stream(
 pub fn cancel_generation() -> Result<(), String> {
     generate::cancel_generation_cmd()
 }
+
+#[tauri::command]
+pub fn cancel_generation_request(request_id: String) -> Result<(), String> {
+    generate::cancel_generation_request_cmd(request_id)
+}
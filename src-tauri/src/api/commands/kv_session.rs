@@ -0,0 +1,125 @@
+use crate::core::kv_session::{KvSession, SESSION_EXTENSION, model_fingerprint, session_path};
+use crate::core::state::{ModelState, SharedState};
+
+use serde::Serialize;
+use tauri::AppHandle;
+
+/// Описание файла сессии KV-кэша
+#[derive(Debug, Clone, Serialize)]
+pub struct KvSessionInfo {
+    pub name: String,
+    pub path: String,
+    /// Число токенов промпта в сессии (None в списке — файл не читается)
+    pub tokens: Option<usize>,
+    pub size_bytes: u64,
+}
+
+impl KvSessionInfo {
+    fn new(name: &str, path: &std::path::Path, tokens: Option<usize>) -> Self {
+        Self {
+            name: name.to_string(),
+            path: path.to_string_lossy().to_string(),
+            tokens,
+            size_bytes: std::fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+        }
+    }
+}
+
+/// Сохраняет KV-кэш промпта последней генерации в именованную сессию
+#[tauri::command]
+pub async fn save_kv_session(
+    app: AppHandle,
+    state: tauri::State<'_, SharedState>,
+    name: String,
+) -> Result<KvSessionInfo, String> {
+    let state_arc = state.inner().clone();
+    tauri::async_runtime::spawn_blocking(move || -> Result<KvSessionInfo, String> {
+        let path = session_path(&ModelState::kv_sessions_dir(&app)?, &name)?;
+        let guard = state_arc.lock().map_err(|e| e.to_string())?;
+        let fingerprint = model_fingerprint(&guard).ok_or("Model is not loaded")?;
+        let entry = guard
+            .scheduler
+            .active_model
+            .as_ref()
+            .ok_or("Model is not loaded")?;
+        // Состояние рекуррентной модели уже включает ответ, берём снимок промпта
+        let session = if entry.model.is_recurrent() {
+            guard
+                .recurrent_prompt
+                .clone()
+                .filter(|session| session.fingerprint == fingerprint)
+                .ok_or("No prompt state to save yet")?
+        } else {
+            KvSession::capture(entry.model.as_ref(), fingerprint, &guard.kv_prompt_tokens)?
+                .with_cache_type(guard.kv_cache_type)
+        };
+        // Запись на диск идёт без блокировки состояния
+        drop(guard);
+        session.save(&path)?;
+        Ok(KvSessionInfo::new(&name, &path, Some(session.tokens.len())))
+    })
+    .await
+    .map_err(|e| format!("save_kv_session join error: {}", e))?
+}
+
+/// Загружает сессию в KV-кэш активной модели
+///
+/// Следующий запрос, промпт которого начинается с токенов сессии,
+/// пропускает их prefill.
+#[tauri::command]
+pub async fn restore_kv_session(
+    app: AppHandle,
+    state: tauri::State<'_, SharedState>,
+    name: String,
+) -> Result<KvSessionInfo, String> {
+    let state_arc = state.inner().clone();
+    tauri::async_runtime::spawn_blocking(move || -> Result<KvSessionInfo, String> {
+        let path = session_path(&ModelState::kv_sessions_dir(&app)?, &name)?;
+        let device = state_arc.lock().map_err(|e| e.to_string())?.device.clone();
+        // Файл читается и разбирается без блокировки состояния
+        let session = KvSession::load(&path, &device)?;
+        let mut guard = state_arc.lock().map_err(|e| e.to_string())?;
+        let fingerprint = model_fingerprint(&guard).ok_or("Model is not loaded")?;
+        let state = &mut *guard;
+        let entry = state
+            .scheduler
+            .active_model
+            .as_mut()
+            .ok_or("Model is not loaded")?;
+        session.restore(entry.model.as_mut(), &fingerprint)?;
+        // KV-кэш больше не соответствует записям in-memory prefix cache
+        state.prefix_cache.clear();
+        state.kv_prompt_tokens = session.tokens.clone();
+        state.restored_session = Some(session.tokens.clone());
+        Ok(KvSessionInfo::new(&name, &path, Some(session.tokens.len())))
+    })
+    .await
+    .map_err(|e| format!("restore_kv_session join error: {}", e))?
+}
+
+/// Список сохранённых сессий
+#[tauri::command]
+pub fn list_kv_sessions(app: AppHandle) -> Result<Vec<KvSessionInfo>, String> {
+    let dir = ModelState::kv_sessions_dir(&app)?;
+    let Ok(read_dir) = std::fs::read_dir(&dir) else {
+        return Ok(Vec::new());
+    };
+    let mut sessions: Vec<KvSessionInfo> = read_dir
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == SESSION_EXTENSION))
+        .filter_map(|path| {
+            let name = path.file_stem()?.to_str()?.to_string();
+            Some(KvSessionInfo::new(&name, &path, None))
+        })
+        .collect();
+    sessions.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(sessions)
+}
+
+/// Удаляет сохранённую сессию
+#[tauri::command]
+pub fn delete_kv_session(app: AppHandle, name: String) -> Result<(), String> {
+    let path = session_path(&ModelState::kv_sessions_dir(&app)?, &name)?;
+    std::fs::remove_file(&path).map_err(|e| format!("Failed to delete KV session: {}", e))
+}
@@ -1,7 +1,10 @@
 pub mod device;
+pub mod embeddings;
+pub mod encoder;
 pub mod experimental;
 pub mod general;
 pub mod generation;
+pub mod kv_session;
 pub mod locale;
 pub mod metadata;
 pub mod model;
@@ -11,9 +14,12 @@ pub mod stt;
 pub mod threads;
 
 pub use device::*;
+pub use embeddings::*;
+pub use encoder::*;
 pub use experimental::*;
 pub use general::*;
 pub use generation::*;
+pub use kv_session::*;
 pub use locale::*;
 pub use metadata::*;
 pub use model::*;
@@ -1,30 +1,69 @@
 use crate::core::background_mode::BackgroundModeGuard;
+use crate::core::kv_session::{model_fingerprint, prompt_cache_dir};
 use crate::core::state::{ModelState, SharedState};
 use crate::core::types::LoadRequest;
 use crate::generate::cancel::{CANCEL_LOADING, cancel_model_loading_cmd};
 use crate::log_load;
 use crate::log_load_warn;
-
-use std::sync::Arc;
+use crate::models::common::{LoraAdapterSpec, StreamingWindow, load_adapters};
 
 pub fn clone_state_arc(state: &tauri::State<'_, SharedState>) -> SharedState {
     state.inner().clone()
 }
 
-fn snapshot_for_loading(
-    guard: &ModelState,
-) -> (
-    candle::Device,
-    crate::core::precision::PrecisionPolicy,
-    Option<usize>,
-    Arc<crate::core::performance::PerformanceMonitor>,
-) {
-    (
-        guard.device.clone(),
-        guard.precision_policy.clone(),
-        guard.rayon_thread_limit,
-        guard.performance_monitor.clone(),
-    )
+/// Готовит состояние для новой загрузки: общие настройки приложения переносятся из
+/// текущего состояния, параметры модели берутся только из запроса
+fn next_state_for_load(current: &ModelState, req: &LoadRequest) -> ModelState {
+    let mut next_state = ModelState::new(current.device.clone());
+    next_state.precision_policy = current.precision_policy.clone();
+    next_state.rayon_thread_limit = current.rayon_thread_limit;
+    next_state.performance_monitor = current.performance_monitor.clone();
+    next_state.kv_cache_type = req.kv_cache_type();
+    next_state.rope_scaling = req.rope_scaling();
+    next_state.lora_adapters = req.lora_adapters();
+    next_state.quantize = req.quantize();
+    next_state
+}
+
+/// Включает потоковый режим внимания у только что загруженной модели
+fn apply_streaming_window(
+    state: &mut ModelState,
+    window: Option<StreamingWindow>,
+) -> Result<(), String> {
+    let Some(window) = window else {
+        return Ok(());
+    };
+    let entry = state
+        .scheduler
+        .active_model
+        .as_mut()
+        .ok_or_else(|| "Model is not loaded".to_string())?;
+    window.validate(entry.model.max_seq_len())?;
+    if !entry.model.set_streaming_window(Some(window)) {
+        return Err(format!(
+            "Streaming attention is not supported for model type '{}'",
+            entry.model.model_type()
+        ));
+    }
+    log_load!(
+        "streaming attention enabled: sink_tokens={}, window={}",
+        window.sink_tokens,
+        window.window
+    );
+    Ok(())
+}
+
+/// Подключает disk tier prefix cache к каталогу загруженной модели
+fn attach_prompt_cache(app: &tauri::AppHandle, state: &mut ModelState) {
+    let Some(fingerprint) = model_fingerprint(state) else {
+        return;
+    };
+    match ModelState::kv_sessions_dir(app) {
+        Ok(dir) => state
+            .prefix_cache
+            .set_disk_dir(Some(prompt_cache_dir(&dir, &fingerprint))),
+        Err(e) => log_load_warn!("prompt cache directory unavailable: {}", e),
+    }
 }
 
 #[tauri::command]
@@ -33,6 +72,12 @@ pub async fn load_model(
     state: tauri::State<'_, SharedState>,
     req: LoadRequest,
 ) -> Result<(), String> {
+    if let Some(scaling) = req.rope_scaling() {
+        scaling.validate()?;
+    }
+    for adapter in req.lora_adapters() {
+        adapter.validate()?;
+    }
     CANCEL_LOADING.store(false, std::sync::atomic::Ordering::SeqCst);
 
     let app_clone = app.clone();
@@ -55,8 +100,9 @@ pub async fn load_model(
                 .is_some_and(|v| v == "1")
                 .then(BackgroundModeGuard::new);
 
-            let (device, precision_policy, rayon_thread_limit, performance_monitor) = {
-                let guard = match state_arc.lock() {
+            let mut unloaded_previous = false;
+            let mut next_state = {
+                let mut guard = match state_arc.lock() {
                     Ok(g) => g,
                     Err(e) => {
                         crate::api::model_loading::emit_load_progress(
@@ -70,24 +116,43 @@ pub async fn load_model(
                         return Err(e.to_string());
                     }
                 };
-                snapshot_for_loading(&guard)
+                // Освобождаем веса текущей модели до загрузки новой, чтобы пик памяти
+                // не включал обе модели сразу. Старое состояние при этом не восстановить.
+                if req.unload_previous() && guard.scheduler.has_model() {
+                    crate::api::model_loading::emit_load_progress(
+                        &app_for_blocking,
+                        "unload_previous",
+                        0,
+                        Some("Unloading current model"),
+                        false,
+                        None,
+                    );
+                    guard.scheduler.unload_model();
+                    guard.tokenizer = None;
+                    guard.chat_template = None;
+                    unloaded_previous = true;
+                    log_load!("previous model unloaded before load");
+                }
+                next_state_for_load(&guard, &req)
             };
 
-            let mut next_state = ModelState::new(device);
-            next_state.precision_policy = precision_policy;
-            next_state.rayon_thread_limit = rayon_thread_limit;
-            next_state.performance_monitor = performance_monitor;
-
+            let streaming = req.streaming();
             let res: Result<(), String> = match req {
                 LoadRequest::Gguf {
                     model_path,
-                    tokenizer_path: _,
+                    tokenizer_path,
                     context_length,
                     device,
+                    streaming: _,
+                    kv_cache_type: _,
+                    rope_scaling: _,
+                    lora_adapters: _,
+                    unload_previous: _,
                 } => crate::api::model_loading::gguf::load_gguf_model(
                     &app_for_blocking,
                     &mut next_state,
                     model_path,
+                    tokenizer_path,
                     context_length,
                     device,
                 ),
@@ -97,6 +162,11 @@ pub async fn load_model(
                     filename,
                     context_length,
                     device,
+                    streaming: _,
+                    kv_cache_type: _,
+                    rope_scaling: _,
+                    lora_adapters: _,
+                    unload_previous: _,
                 } => crate::api::model_loading::hub_gguf::load_hub_gguf_model(
                     &app_for_blocking,
                     &mut next_state,
@@ -111,6 +181,12 @@ pub async fn load_model(
                     revision,
                     context_length,
                     device,
+                    streaming: _,
+                    kv_cache_type: _,
+                    rope_scaling: _,
+                    lora_adapters: _,
+                    unload_previous: _,
+                    quantize: _,
                 } => crate::api::model_loading::safetensors::load_hub_safetensors_model(
                     &app_for_blocking,
                     &mut next_state,
@@ -123,6 +199,12 @@ pub async fn load_model(
                     model_path,
                     context_length,
                     device,
+                    streaming: _,
+                    kv_cache_type: _,
+                    rope_scaling: _,
+                    lora_adapters: _,
+                    unload_previous: _,
+                    quantize: _,
                 } => crate::api::model_loading::safetensors::load_local_safetensors_model(
                     &app_for_blocking,
                     &mut next_state,
@@ -131,10 +213,24 @@ pub async fn load_model(
                     device,
                 ),
             };
+            let res = res
+                .and_then(|_| apply_streaming_window(&mut next_state, streaming))
+                // Прежняя модель уже выгружена: без пометки UI решит, что она осталась активной
+                .map_err(|e| {
+                    if unloaded_previous {
+                        format!("{e} (the previously loaded model was unloaded; load it again)")
+                    } else {
+                        e
+                    }
+                });
+            if res.is_ok() {
+                attach_prompt_cache(&app_for_blocking, &mut next_state);
+            }
 
             if res.is_ok() {
                 match state_arc.lock() {
                     Ok(mut guard) => {
+                        next_state.encoders = std::mem::take(&mut guard.encoders);
                         *guard = next_state;
                     }
                     Err(e) => {
@@ -228,7 +324,9 @@ pub async fn unload_model(
             false,
             None,
         );
+        let encoders = std::mem::take(&mut guard.encoders);
         *guard = ModelState::new(device);
+        guard.encoders = encoders;
         crate::api::model_loading::emit_load_progress(
             &app_clone,
             "unload_complete",
@@ -244,8 +342,119 @@ pub async fn unload_model(
     .map_err(|e| format!("unload_model join error: {}", e))?
 }
 
+/// Заменяет LoRA-адаптеры загруженной модели без перезагрузки базовых весов
+///
+/// Пустой список отключает адаптеры.
+/// Замена адаптеров без перезагрузки доступна моделям с side-car LoRA (Qwen3, а также
+/// GGUF и квантованные при загрузке Llama и Qwen2); safetensors Llama и Qwen2 вливают
+/// адаптеры в веса при загрузке
+fn ensure_lora_swap_supported(model: &dyn crate::models::ModelBackend) -> Result<(), String> {
+    if model.supports_lora() {
+        return Ok(());
+    }
+    Err(format!(
+        "Model type '{}' cannot swap LoRA adapters at runtime; reload it with lora_adapters",
+        model.model_type()
+    ))
+}
+
+#[tauri::command]
+pub async fn set_lora_adapters(
+    app: tauri::AppHandle,
+    state: tauri::State<'_, SharedState>,
+    adapters: Vec<LoraAdapterSpec>,
+) -> Result<(), String> {
+    for adapter in &adapters {
+        adapter.validate()?;
+    }
+    let state_arc = clone_state_arc(&state);
+    tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
+        // Файлы адаптеров читаются без блокировки состояния, под ней только замена
+        let device = {
+            let guard = state_arc.lock().map_err(|e| e.to_string())?;
+            let entry = guard
+                .scheduler
+                .active_model
+                .as_ref()
+                .ok_or("Model is not loaded")?;
+            ensure_lora_swap_supported(entry.model.as_ref())?;
+            guard.device.clone()
+        };
+        let loaded = load_adapters(&adapters, &device)?;
+        let mut guard = state_arc.lock().map_err(|e| e.to_string())?;
+        let state = &mut *guard;
+        let entry = state
+            .scheduler
+            .active_model
+            .as_mut()
+            .ok_or("Model is not loaded")?;
+        // Модель могли заменить, пока адаптеры читались с диска
+        ensure_lora_swap_supported(entry.model.as_ref())?;
+        entry
+            .model
+            .set_lora_adapters(&loaded)
+            .map_err(|e| format!("Failed to apply LoRA adapters: {}", e))?;
+        // KV-кэш и сохранённые префиксы посчитаны с прежними адаптерами
+        entry.model.clear_kv_cache();
+        state.prefix_cache.clear();
+        state.kv_prompt_tokens.clear();
+        state.restored_session = None;
+        state.recurrent_prompt = None;
+        state.lora_adapters = adapters;
+        attach_prompt_cache(&app, state);
+        log_load!("LoRA adapters swapped: {}", loaded.len());
+        Ok(())
+    })
+    .await
+    .map_err(|e| format!("set_lora_adapters join error: {}", e))?
+}
+
 #[tauri::command]
 pub fn is_model_loaded(state: tauri::State<'_, SharedState>) -> Result<bool, String> {
     let guard = state.lock().map_err(|e| e.to_string())?;
     Ok(guard.scheduler.has_model() && guard.tokenizer.is_some())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::precision::{PrecisionPolicy, QuantizationTarget};
+
+    fn local_request(quantize: Option<&str>) -> LoadRequest {
+        let mut req = serde_json::json!({
+            "format": "local_safetensors",
+            "model_path": "model",
+            "context_length": 4096,
+        });
+        if let Some(target) = quantize {
+            req["quantize"] = target.into();
+        }
+        serde_json::from_value(req).expect("valid load request")
+    }
+
+    #[test]
+    fn quantize_applies_only_to_its_own_load() {
+        let initial = ModelState::new(candle::Device::Cpu);
+
+        let first = next_state_for_load(&initial, &local_request(Some("q8_0")));
+        assert_eq!(first.quantize, Some(QuantizationTarget::Q8_0));
+        assert_eq!(first.precision_policy, PrecisionPolicy::Default);
+
+        // После успешной загрузки next_state становится текущим состоянием
+        let second = next_state_for_load(&first, &local_request(None));
+        assert_eq!(second.quantize, None);
+        assert_eq!(second.precision_policy, PrecisionPolicy::Default);
+    }
+
+    #[test]
+    fn next_state_keeps_user_precision_policy() {
+        let mut current = ModelState::new(candle::Device::Cpu);
+        current.precision_policy = PrecisionPolicy::MemoryEfficient;
+        current.rayon_thread_limit = Some(4);
+
+        let next = next_state_for_load(&current, &local_request(Some("q4_k")));
+        assert_eq!(next.precision_policy, PrecisionPolicy::MemoryEfficient);
+        assert_eq!(next.rayon_thread_limit, Some(4));
+        assert_eq!(next.quantize, Some(QuantizationTarget::Q4K));
+    }
+}
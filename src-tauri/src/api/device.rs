@@ -1,17 +1,16 @@
 use crate::core::device::device_label;
 use crate::core::state::ModelState;
 use crate::core::tokenizer::{
-    extract_chat_template, find_chat_template_in_metadata, mark_special_chat_tokens,
-    tokenizer_from_gguf_metadata,
+    extract_chat_template, find_chat_template_in_metadata, gguf_model_vocab_size,
+    load_external_tokenizer, mark_special_chat_tokens, tokenizer_from_gguf_metadata,
 };
 
+use crate::models::common::gguf_split::open_gguf;
 use crate::models::registry::detect_arch;
 use crate::models::registry::get_model_factory;
 use crate::{log_device, log_device_error, log_load};
-use candle::quantized::gguf_file;
 use candle::utils::{cuda_is_available, metal_is_available};
-use std::fs::File;
-use std::path::PathBuf;
+use std::path::Path;
 
 pub fn set_device(
     guard: &mut ModelState,
@@ -96,24 +95,39 @@ pub fn set_device(
         // Простая проверка: если это GGUF файл
         if model_path.ends_with(".gguf") {
             let ctx_len = guard.context_length.max(1);
-            let mut file = File::open(&model_path).map_err(|e| e.to_string())?;
-            let content = gguf_file::Content::read(&mut file)
-                .map_err(|e| format!("{}", e.with_path(PathBuf::from(model_path.clone()))))?;
+            let (mut content, mut file) = open_gguf(Path::new(&model_path))?;
+            if let Some(scaling) = guard.rope_scaling {
+                scaling.apply_to_gguf(&mut content.metadata);
+            }
 
             // Токенизатор и шаблон чата
-            let mut tokenizer = tokenizer_from_gguf_metadata(&content.metadata)?;
-            mark_special_chat_tokens(&mut tokenizer);
-            let chat_tpl = extract_chat_template(&tokenizer)
-                .or_else(|| find_chat_template_in_metadata(&content.metadata));
+            let (tokenizer, chat_tpl) = match guard.tokenizer_path.as_deref() {
+                Some(path) => {
+                    let external =
+                        load_external_tokenizer(Path::new(path), gguf_model_vocab_size(&content))?;
+                    let chat_tpl = external
+                        .chat_template
+                        .or_else(|| find_chat_template_in_metadata(&content.metadata));
+                    (external.tokenizer, chat_tpl)
+                }
+                None => {
+                    let mut tokenizer = tokenizer_from_gguf_metadata(&content.metadata)?;
+                    mark_special_chat_tokens(&mut tokenizer);
+                    let chat_tpl = extract_chat_template(&tokenizer)
+                        .or_else(|| find_chat_template_in_metadata(&content.metadata));
+                    (tokenizer, chat_tpl)
+                }
+            };
 
             // Архитектура
             let arch = detect_arch(&content.metadata)
                 .ok_or_else(|| "Unsupported GGUF architecture".to_string())?;
 
             // Универсальное создание модели через фабрику (под выбранное устройство)
-            let model_backend = get_model_factory()
+            let mut model_backend = get_model_factory()
                 .build_from_gguf(arch, content, &mut file, &guard.device, ctx_len, false)
                 .map_err(|e| format!("Failed to rebuild model for new device: {}", e))?;
+            crate::api::model_loading::apply_lora_adapters(model_backend.as_mut(), guard)?;
 
             guard.scheduler.load_model(model_backend, model_path);
             guard.tokenizer = Some(tokenizer);
@@ -738,6 +738,112 @@ async fn start_task(app: AppHandle, job: DownloadJob) -> Result<(), String> {
     Ok(())
 }
 
+/// Register a job that produces a file locally (e.g. a GGUF conversion) so that
+/// its progress is reported through the same events as downloads.
+pub(crate) async fn begin_local_job(
+    app: &AppHandle,
+    repo_id: &str,
+    filename: &str,
+    destination_dir: &Path,
+    total_bytes: Option<u64>,
+) -> Result<String, String> {
+    MANAGER.ensure_history_loaded(app).await?;
+    let job_id = build_job_id(repo_id, filename);
+    let now = Utc::now();
+    {
+        let mut guard = MANAGER.state.write().await;
+        if guard.active.contains_key(&job_id) {
+            return Err("Job is already in progress".to_string());
+        }
+        guard.active.insert(
+            job_id.clone(),
+            DownloadJob {
+                id: job_id.clone(),
+                repo_id: repo_id.to_string(),
+                filename: filename.to_string(),
+                download_url: String::new(),
+                destination_dir: destination_dir.to_path_buf(),
+                total_bytes,
+                downloaded_bytes: 0,
+                status: DownloadStatus::Downloading,
+                speed_bytes_per_sec: None,
+                eta_seconds: None,
+                started_at: Some(now),
+                updated_at: Some(now),
+                finished_at: None,
+                error: None,
+                sha256: None,
+                group_id: None,
+                display_name: None,
+            },
+        );
+    }
+    MANAGER.emit_update(app).await;
+    Ok(job_id)
+}
+
+/// Update progress of a local job. Returns an error once the job was cancelled.
+pub(crate) async fn update_local_job(
+    app: &AppHandle,
+    job_id: &str,
+    processed_bytes: u64,
+    total_bytes: u64,
+) -> Result<(), String> {
+    let updated = MANAGER
+        .update_job(job_id, |job| {
+            let elapsed = job
+                .started_at
+                .and_then(|started| (Utc::now() - started).to_std().ok())
+                .unwrap_or_default();
+            let (speed, eta) =
+                compute_speed_and_eta(0, processed_bytes, elapsed, Some(total_bytes));
+            job.total_bytes = Some(total_bytes);
+            job.downloaded_bytes = processed_bytes;
+            job.speed_bytes_per_sec = speed;
+            job.eta_seconds = eta;
+            job.updated_at = Some(Utc::now());
+        })
+        .await;
+    if updated.is_none() {
+        return Err("Job was cancelled".to_string());
+    }
+    MANAGER.emit_update(app).await;
+    Ok(())
+}
+
+/// Move a local job to history with its final status.
+pub(crate) async fn finish_local_job(app: &AppHandle, job_id: &str, result: Result<(), String>) {
+    let Some(job) = MANAGER.remove_job(job_id).await else {
+        // Cancelled: cancel_download has already recorded the history entry
+        MANAGER.emit_update(app).await;
+        return;
+    };
+    let (status, error) = match result {
+        Ok(()) => (DownloadStatus::Completed, None),
+        Err(err) => (DownloadStatus::Error, Some(err)),
+    };
+    MANAGER
+        .record_history(DownloadHistoryEntry {
+            id: job.id.clone(),
+            repo_id: job.repo_id.clone(),
+            filename: job.filename.clone(),
+            destination_path: resolve_destination_path(&job.destination_dir, &job.filename),
+            status,
+            total_bytes: job.total_bytes,
+            downloaded_bytes: job.downloaded_bytes,
+            finished_at: Utc::now(),
+            error,
+            sha256: None,
+            group_id: None,
+            display_name: None,
+        })
+        .await;
+    if let Err(err) = MANAGER.persist_history(app).await {
+        log::warn!("Failed to persist download history: {err}");
+    }
+    MANAGER.emit_update(app).await;
+}
+
 /// Start a download job and return the queued job information.
 #[tauri::command]
 pub async fn start_model_download(
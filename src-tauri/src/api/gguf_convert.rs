@@ -0,0 +1,200 @@
+//! Конвертация локальных safetensors-чекпойнтов в квантованный GGUF
+//!
+//! Прогресс передаётся событиями менеджера загрузок, а результат пишется
+//! рядом с остальными моделями, чтобы его подхватил `scan_models_folder`.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use candle::quantized::gguf_file::Value;
+use serde::Deserialize;
+use tauri::AppHandle;
+
+use super::download_manager::{begin_local_job, finish_local_job, update_local_job};
+use super::model_manager::manifest::load_manifest;
+use crate::core::precision::QuantizationTarget;
+use crate::core::tokenizer::{chat_template_from_config, gguf_tokenizer_metadata};
+use crate::core::weights::local_list_safetensors;
+use crate::models::common::quantize::{GgufLayout, gguf_file_type, write_gguf};
+use crate::models::registry::detect_arch_from_config;
+
+/// Запрос на конвертацию каталога safetensors-модели
+#[derive(Debug, Deserialize)]
+pub struct ConvertModelRequest {
+    /// Каталог с config.json, tokenizer.json и *.safetensors
+    pub model_dir: String,
+    /// Каталог, куда пишется GGUF
+    pub destination_dir: String,
+    pub quantization: QuantizationTarget,
+    /// Имя файла без расширения; по умолчанию `<модель>-<квантование>`
+    #[serde(default)]
+    pub output_name: Option<String>,
+}
+
+fn read_json(path: &Path) -> Result<serde_json::Value, String> {
+    let raw = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+    serde_json::from_str(&raw).map_err(|e| format!("Failed to parse {}: {e}", path.display()))
+}
+
+fn quantization_label(target: QuantizationTarget) -> &'static str {
+    match target {
+        QuantizationTarget::Q8_0 => "Q8_0",
+        QuantizationTarget::Q4K => "Q4_K",
+    }
+}
+
+/// Всё, что нужно для записи GGUF, собранное до запуска задачи
+struct ConversionPlan {
+    files: Vec<PathBuf>,
+    layout: GgufLayout,
+    metadata: HashMap<String, Value>,
+    repo_id: String,
+    filename: String,
+}
+
+fn plan_conversion(dir: &Path, request: &ConvertModelRequest) -> Result<ConversionPlan, String> {
+    let files: Vec<PathBuf> = local_list_safetensors(dir)?
+        .into_iter()
+        .map(PathBuf::from)
+        .collect();
+    let config = read_json(&dir.join("config.json"))?;
+    let arch = detect_arch_from_config(&config)
+        .ok_or_else(|| "Unsupported architecture in config.json".to_string())?;
+    let layout = arch
+        .gguf_layout()
+        .ok_or_else(|| format!("Conversion to GGUF is not supported for {arch:?}"))?;
+
+    let tokenizer_json = std::fs::read_to_string(dir.join("tokenizer.json"))
+        .map_err(|e| format!("Failed to read tokenizer.json: {e}"))?;
+    let tokenizer_config_path = dir.join("tokenizer_config.json");
+    let tokenizer_config = tokenizer_config_path
+        .exists()
+        .then(|| read_json(&tokenizer_config_path))
+        .transpose()?;
+    let chat_template = tokenizer_config
+        .as_ref()
+        .and_then(chat_template_from_config)
+        .or_else(|| std::fs::read_to_string(dir.join("chat_template.jinja")).ok());
+
+    let model_name = dir
+        .file_name()
+        .and_then(|s| s.to_str())
+        .unwrap_or("model")
+        .to_string();
+    let manifest = load_manifest(dir);
+    let repo_id = manifest
+        .as_ref()
+        .map(|m| m.repo_id.clone())
+        .unwrap_or_else(|| format!("local/{model_name}"));
+    let label = quantization_label(request.quantization);
+    let stem = match request.output_name.as_deref().map(str::trim) {
+        Some(name) if !name.is_empty() => {
+            // Имя — только файл в каталоге назначения, без выхода за его пределы
+            if name.contains(['/', '\\']) || name == ".." || name == "." {
+                return Err(format!("Invalid output name '{name}'"));
+            }
+            name.to_string()
+        }
+        _ => format!("{model_name}-{label}"),
+    };
+
+    let target = request.quantization.ggml_dtype();
+    let mut metadata = layout.metadata(&config, None)?;
+    metadata.extend(gguf_tokenizer_metadata(
+        &tokenizer_json,
+        tokenizer_config.as_ref(),
+        &config,
+        chat_template.as_deref(),
+    )?);
+    metadata.insert("general.name".into(), Value::String(model_name));
+    metadata.insert("general.quantization_version".into(), Value::U32(2));
+    metadata.insert(
+        "general.alignment".into(),
+        Value::U32(candle::quantized::gguf_file::DEFAULT_ALIGNMENT as u32),
+    );
+    if let Some(file_type) = gguf_file_type(target) {
+        metadata.insert("general.file_type".into(), Value::U32(file_type));
+    }
+    if manifest.is_some() {
+        metadata.insert(
+            "general.source_hf_repo".into(),
+            Value::String(repo_id.clone()),
+        );
+    }
+
+    Ok(ConversionPlan {
+        files,
+        layout,
+        metadata,
+        repo_id,
+        filename: format!("{stem}.gguf"),
+    })
+}
+
+/// Конвертирует локальную safetensors-модель в квантованный GGUF
+///
+/// Возвращает путь к записанному файлу.
+#[tauri::command]
+pub async fn convert_safetensors_to_gguf(
+    app: AppHandle,
+    request: ConvertModelRequest,
+) -> Result<String, String> {
+    if request.destination_dir.trim().is_empty() {
+        return Err("Destination directory cannot be empty".to_string());
+    }
+    let dir = PathBuf::from(&request.model_dir);
+    let plan = plan_conversion(&dir, &request)?;
+    let destination_dir = PathBuf::from(&request.destination_dir);
+    std::fs::create_dir_all(&destination_dir)
+        .map_err(|e| format!("Failed to create destination directory: {e}"))?;
+    let final_path = destination_dir.join(&plan.filename);
+    let partial_path = destination_dir.join(format!("{}.part", plan.filename));
+
+    let job_id =
+        begin_local_job(&app, &plan.repo_id, &plan.filename, &destination_dir, None).await?;
+
+    let target = request.quantization.ggml_dtype();
+    let runtime = tokio::runtime::Handle::current();
+    let (task_app, task_job, task_partial) = (app.clone(), job_id.clone(), partial_path.clone());
+    let result = tokio::task::spawn_blocking(move || {
+        let file = std::fs::File::create(&task_partial)
+            .map_err(|e| format!("Failed to create {}: {e}", task_partial.display()))?;
+        write_gguf(
+            std::io::BufWriter::new(file),
+            &plan.files,
+            plan.layout,
+            &plan.metadata,
+            target,
+            |done, total| runtime.block_on(update_local_job(&task_app, &task_job, done, total)),
+        )
+    })
+    .await
+    .map_err(|e| format!("Conversion task failed: {e}"))
+    .and_then(|r| r)
+    .and_then(|stats| {
+        std::fs::rename(&partial_path, &final_path)
+            .map_err(|e| format!("Failed to finalize GGUF file: {e}"))?;
+        Ok(stats)
+    });
+
+    match result {
+        Ok(stats) => {
+            log::info!(
+                "Converted {} to {} ({} tensors, {:.1}MB saved)",
+                request.model_dir,
+                final_path.display(),
+                stats.tensors,
+                stats.saved_mb()
+            );
+            finish_local_job(&app, &job_id, Ok(())).await;
+            Ok(final_path.to_string_lossy().to_string())
+        }
+        Err(err) => {
+            log::error!("GGUF conversion of {} failed: {err}", request.model_dir);
+            let _ = std::fs::remove_file(&partial_path);
+            finish_local_job(&app, &job_id, Err(err.clone())).await;
+            Err(err)
+        }
+    }
+}
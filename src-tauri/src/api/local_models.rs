@@ -10,8 +10,10 @@ use crate::api::model_manager::manifest::{
     DownloadManifest, infer_quantization_from_label, load_manifest, save_manifest,
 };
 use crate::core::weights::local_list_safetensors;
+use crate::models::common::gguf_split::{gguf_paths, open_gguf, parse_shard_name};
+use crate::models::encoder::{EncoderTask, detect_encoder};
 use crate::models::registry::{ArchKind, detect_arch, detect_arch_from_config};
-use candle::quantized::gguf_file::{self, Content, Value as GgufValue, VersionedMagic};
+use candle::quantized::gguf_file::{self, Value as GgufValue, VersionedMagic};
 use chrono::{DateTime, Utc};
 use hf_hub::api::tokio::{ApiBuilder, Progress as HubProgress};
 use once_cell::sync::{Lazy, OnceCell};
@@ -21,7 +23,6 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
 use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::io::BufReader;
 use std::path::{Path, PathBuf};
 use std::sync::{
     Arc,
@@ -138,6 +139,10 @@ pub struct ModelInfo {
     pub source_repo_name: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub source_quantization: Option<String>,
+    /// Set for BERT-family encoders that load into the embedding or reranker slot
+    /// (`load_encoder_model`) instead of the chat model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoder_task: Option<EncoderTask>,
     /// Indicates that Candle can instantiate the detected architecture and that
     /// validation did not fail. Use this flag together with `validation_status`
     /// to determine whether the registry can support the model.
@@ -157,6 +162,48 @@ pub struct RemoteGGUFFile {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub quantization: Option<String>,
     pub download_url: String,
+    /// All parts of a split model (`-00001-of-0000N.gguf`), in order.
+    /// `filename`/`size` then describe the first part and the whole set.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shards: Vec<RemoteGGUFFile>,
+}
+
+/// Collapse split GGUF parts into one entry per model.
+/// Incomplete sets are dropped since they cannot be loaded.
+fn group_gguf_shards(files: Vec<RemoteGGUFFile>) -> Vec<RemoteGGUFFile> {
+    let by_name: HashMap<&str, &RemoteGGUFFile> =
+        files.iter().map(|f| (f.filename.as_str(), f)).collect();
+    let mut grouped = Vec::new();
+    for file in &files {
+        let (dir, name) = match file.filename.rsplit_once('/') {
+            Some((dir, name)) => (Some(dir), name),
+            None => (None, file.filename.as_str()),
+        };
+        let Some(shard) = parse_shard_name(name) else {
+            grouped.push(file.clone());
+            continue;
+        };
+        if shard.index != 1 {
+            continue;
+        }
+        let shards: Option<Vec<RemoteGGUFFile>> = shard
+            .file_names()
+            .into_iter()
+            .map(|name| match dir {
+                Some(dir) => format!("{dir}/{name}"),
+                None => name,
+            })
+            .map(|name| by_name.get(name.as_str()).map(|&f| f.clone()))
+            .collect();
+        if let Some(shards) = shards {
+            grouped.push(RemoteGGUFFile {
+                size: shards.iter().map(|s| s.size).sum(),
+                shards,
+                ..file.clone()
+            });
+        }
+    }
+    grouped
 }
 
 /// Remote model listing entry.
@@ -606,10 +653,8 @@ fn read_gguf_metadata(path: &Path, include_tokens: bool) -> Result<MetadataEnvel
         ));
     }
 
-    let file = fs::File::open(path).map_err(|e| format!("Failed to open GGUF file: {e}"))?;
-    let mut reader = BufReader::new(file);
-    let content = Content::read(&mut reader).map_err(|e| {
-        let err_str = e.to_string();
+    // Для шардов заголовки всех файлов сливаются: тензоры считаются по всей модели
+    let (content, _) = open_gguf(path).map_err(|err_str| {
         // Проверяем, не ошибка ли это из-за неподдерживаемого типа данных
         if err_str.contains("unknown dtype") {
             format!(
@@ -749,6 +794,14 @@ fn scan_directory(dir: &Path) -> Result<Vec<ModelInfo>, String> {
                 .map(|ext| ext.eq_ignore_ascii_case("gguf"))
                 .unwrap_or(false)
             {
+                // Шардированная модель показывается одной записью по первому шарду
+                let shard = path
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .and_then(parse_shard_name);
+                if shard.is_some_and(|s| s.index != 1) {
+                    continue;
+                }
                 match build_model_info(&path) {
                     Ok(Some(info)) => models.push(info),
                     Ok(None) => {
@@ -775,8 +828,11 @@ fn build_model_info(path: &Path) -> Result<Option<ModelInfo>, String> {
 
     let envelope = read_gguf_metadata(path, false)?;
     let file_name = path
-        .file_stem()
+        .file_name()
         .and_then(|s| s.to_str())
+        .and_then(parse_shard_name)
+        .map(|shard| shard.prefix)
+        .or_else(|| path.file_stem().and_then(|s| s.to_str()))
         .unwrap_or("unknown")
         .to_string();
 
@@ -849,7 +905,11 @@ fn build_model_info(path: &Path) -> Result<Option<ModelInfo>, String> {
     Ok(Some(ModelInfo {
         name: file_name,
         path: path.to_path_buf(),
-        file_size: metadata_fs.len(),
+        file_size: gguf_paths(path)
+            .iter()
+            .filter_map(|p| fs::metadata(p).ok())
+            .map(|m| m.len())
+            .sum(),
         format: ModelFormat::Gguf,
         architecture: envelope.metadata.architecture.clone(),
         detected_architecture,
@@ -863,6 +923,7 @@ fn build_model_info(path: &Path) -> Result<Option<ModelInfo>, String> {
         source_repo_id,
         source_repo_name,
         source_quantization,
+        encoder_task: None,
         candle_compatible: envelope.detected_arch.is_some(),
         validation_status: envelope.validation,
         created_at,
@@ -916,7 +977,12 @@ fn build_safetensors_model_info(dir: &Path) -> Result<Option<ModelInfo>, String>
         .map(|s| s.to_string());
 
     let detected_arch = detect_arch_from_config(config_ref);
-    let architecture = detected_arch.as_ref().map(|kind| format!("{kind:?}"));
+    let encoder = detect_encoder(config_ref);
+    let architecture = detected_arch
+        .as_ref()
+        .map(|kind| format!("{kind:?}"))
+        .or_else(|| encoder.map(|(arch, _)| format!("{arch:?}")));
+    // Энкодеры грузятся в слоты эмбеддингов (`encoder_task`), а не в чат
     let candle_ready = detected_arch.is_some();
 
     let mut total_bytes = 0u64;
@@ -1000,6 +1066,7 @@ fn build_safetensors_model_info(dir: &Path) -> Result<Option<ModelInfo>, String>
         source_repo_id,
         source_repo_name,
         source_quantization,
+        encoder_task: encoder.map(|(_, task)| task),
         candle_compatible: candle_ready,
         validation_status: ValidationStatus {
             level: ValidationLevel::Warning,
@@ -1070,11 +1137,6 @@ fn convert_detail_to_info(
         .filter(|file| file.rfilename.to_lowercase().ends_with(".gguf"))
         .filter_map(|file| {
             let size = file.size?;
-            if let Some(limit) = filters.max_file_size
-                && size > limit
-            {
-                return None;
-            }
             let quant = extract_quantization_from_filename(&file.rfilename)
                 .map(|raw| canonicalize_quantization(&raw));
             let quant = match quant {
@@ -1101,9 +1163,14 @@ fn convert_detail_to_info(
                 sha256: file.lfs.as_ref().and_then(|lfs| lfs.sha256.clone()),
                 quantization: quant,
                 download_url,
+                shards: Vec::new(),
             })
         })
         .collect();
+    let gguf_files: Vec<RemoteGGUFFile> = group_gguf_shards(gguf_files)
+        .into_iter()
+        .filter(|file| filters.max_file_size.is_none_or(|limit| file.size <= limit))
+        .collect();
 
     if gguf_files.is_empty() {
         return Ok(None);
@@ -1,6 +1,7 @@
 pub mod commands;
 pub mod device;
 pub mod download_manager;
+pub mod gguf_convert;
 pub mod local_models;
 pub mod model_cards;
 pub mod model_loading;
@@ -3,6 +3,7 @@ use crate::api::model_manager::manifest::{
     DownloadManifest, infer_quantization_from_label, save_manifest,
 };
 use crate::log_load;
+use crate::models::common::gguf_split::parse_shard_name;
 use chrono::Utc;
 use hf_hub::api::tokio::{Api, ApiBuilder};
 use hf_hub::{Repo, RepoType};
@@ -130,6 +131,7 @@ pub async fn download_model_card_format(
     let files = match card_format {
         ModelCardFormat::Gguf => card
             .files_for_format(ModelCardFormat::Gguf, quantization.as_deref())
+            .map(expand_gguf_shards)
             .map_err(|e| format!("Карточка некорректна: {e}"))?,
         ModelCardFormat::Safetensors => collect_safetensors_files(&api, &card).await?,
     };
@@ -234,6 +236,30 @@ pub async fn download_model_card_format(
     })
 }
 
+/// Дополняет шард GGUF остальными файлами его группы
+///
+/// Карточке достаточно указать первый шард (`-00001-of-0000N.gguf`):
+/// загружаются все части, и менеджер загрузок показывает их одной группой.
+fn expand_gguf_shards(files: Vec<ModelCardFile>) -> Vec<ModelCardFile> {
+    let mut seen = HashSet::new();
+    let mut expanded = Vec::new();
+    for file in files {
+        let names = match parse_shard_name(&file.filename) {
+            Some(shard) => shard.file_names(),
+            None => vec![file.filename.clone()],
+        };
+        for filename in names {
+            if seen.insert(filename.clone()) {
+                expanded.push(ModelCardFile {
+                    filename,
+                    ..file.clone()
+                });
+            }
+        }
+    }
+    expanded
+}
+
 #[tauri::command]
 pub fn import_model_cards(config_path: String) -> Result<ModelCardsResponse, String> {
     let config = read_model_cards_config(Path::new(&config_path))?;
@@ -1,5 +1,7 @@
 use candle::Device;
 
+use crate::models::common::KvCacheType;
+
 /// Constants for memory estimation
 const VRAM_HEADROOM_MB: usize = 1024; // 1GB reserved safety buffer
 const WHISPER_RESERVE_MB: usize = 384; // Reserve for Whisper/STT (~300MB for small models + buffers)
@@ -11,13 +13,31 @@ pub struct ModelCacheParams {
     pub n_kv_head: usize,
     pub head_dim: usize,
     pub dtype_size: usize, // e.g. 2 for f16, 4 for f32
+    /// KV cache storage format (Q8/Q4 blocks shrink the cache)
+    pub kv_cache_type: KvCacheType,
 }
 
 impl ModelCacheParams {
     /// Calculate the memory required for a specific context length (in bytes)
     pub fn memory_required(&self, ctx_len: usize) -> usize {
-        // KV Cache = 2 (K+V) * n_layer * ctx_len * n_kv_head * head_dim * dtype_size
-        2 * self.n_layer * ctx_len * self.n_kv_head * self.head_dim * self.dtype_size
+        // KV Cache = 2 (K+V) * n_layer * ctx_len * n_kv_head * head_dim * bytes_per_element
+        let elements = 2 * ctx_len * self.n_kv_head * self.head_dim;
+        let per_element = self.kv_cache_type.bytes_per_element(self.dtype_size);
+        let cache = (elements as f64 * self.n_layer as f64 * per_element).ceil() as usize;
+        match self.kv_cache_type {
+            KvCacheType::Full => cache,
+            // Quantized blocks are dequantized one layer at a time inside attention
+            _ => cache + elements * self.dtype_size,
+        }
+    }
+}
+
+/// Key for autotuned context settings: the result depends on the KV cache format
+pub fn settings_key(model_id: &str, kv_cache_type: KvCacheType) -> String {
+    match kv_cache_type {
+        KvCacheType::Full => model_id.to_string(),
+        KvCacheType::Q8 => format!("{model_id}@kv-q8"),
+        KvCacheType::Q4 => format!("{model_id}@kv-q4"),
     }
 }
 
@@ -95,3 +115,30 @@ pub fn estimate_best_context(
 
     best_ctx
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(kv_cache_type: KvCacheType) -> ModelCacheParams {
+        ModelCacheParams {
+            n_layer: 32,
+            n_kv_head: 8,
+            head_dim: 128,
+            dtype_size: 4,
+            kv_cache_type,
+        }
+    }
+
+    #[test]
+    fn quantized_kv_cache_needs_less_memory() {
+        let full = params(KvCacheType::Full).memory_required(32768);
+        let q8 = params(KvCacheType::Q8).memory_required(32768);
+        let q4 = params(KvCacheType::Q4).memory_required(32768);
+        assert_eq!(full, 2 * 32 * 32768 * 8 * 128 * 4);
+        assert!(q8 < full / 3);
+        assert!(q4 < q8);
+        assert_eq!(settings_key("m.gguf", KvCacheType::Full), "m.gguf");
+        assert_ne!(settings_key("m.gguf", KvCacheType::Q4), "m.gguf");
+    }
+}
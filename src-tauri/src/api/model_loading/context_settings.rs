@@ -19,10 +19,19 @@ pub struct ModelContextSettings {
     pub last_autotune: Option<u64>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TokenizerOverride {
+    /// External tokenizer.json; tokenizer_config.json is read from the same directory
+    pub tokenizer_path: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct ContextSettingsStore {
     // Map model_id (or path) -> settings
     pub models: HashMap<String, ModelContextSettings>,
+    // Map model_id -> tokenizer used instead of the embedded GGUF one
+    #[serde(default)]
+    pub tokenizers: HashMap<String, TokenizerOverride>,
 }
 
 pub struct ContextSettingsManager {
@@ -65,9 +74,30 @@ impl ContextSettingsManager {
     ) -> Result<(), String> {
         let mut store = self.store.lock().unwrap();
         store.models.insert(model_id.to_string(), settings);
+        self.persist(&store)
+    }
+
+    pub fn get_tokenizer_override(&self, model_id: &str) -> Option<TokenizerOverride> {
+        let store = self.store.lock().unwrap();
+        store.tokenizers.get(model_id).cloned()
+    }
+
+    /// `None` clears the override so the embedded tokenizer is used again
+    pub fn save_tokenizer_override(
+        &self,
+        model_id: &str,
+        tokenizer: Option<TokenizerOverride>,
+    ) -> Result<(), String> {
+        let mut store = self.store.lock().unwrap();
+        match tokenizer {
+            Some(tokenizer) => store.tokenizers.insert(model_id.to_string(), tokenizer),
+            None => store.tokenizers.remove(model_id),
+        };
+        self.persist(&store)
+    }
 
-        // Persist to disk
-        let json = serde_json::to_string_pretty(&*store).map_err(|e| e.to_string())?;
+    fn persist(&self, store: &ContextSettingsStore) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(store).map_err(|e| e.to_string())?;
         fs::write(&self.config_path, json).map_err(|e| e.to_string())?;
         Ok(())
     }
@@ -0,0 +1,138 @@
+//! Загрузка энкодерных моделей эмбеддингов и реранкеров (safetensors)
+
+use std::path::{Path, PathBuf};
+
+use candle_nn::VarBuilder;
+use tokenizers::{Tokenizer, TruncationParams};
+
+use crate::core::device::{device_label, select_device};
+use crate::core::state::EncoderEntry;
+use crate::core::types::LoadEncoderRequest;
+use crate::core::weights::{local_list_safetensors, validate_safetensors_files};
+use crate::log_load;
+use crate::models::HubDownloader;
+use crate::models::encoder::{ENCODER_DTYPE, EncoderModel, default_embedding_options};
+
+/// Файлы энкодерной модели, найденные локально или скачанные с Hub
+struct EncoderFiles {
+    id: String,
+    config: PathBuf,
+    tokenizer: PathBuf,
+    weights: Vec<PathBuf>,
+    /// `1_Pooling/config.json` из sentence-transformers
+    pooling: Option<PathBuf>,
+    /// `modules.json` из sentence-transformers
+    modules: Option<PathBuf>,
+}
+
+fn local_files(model_path: &str) -> Result<EncoderFiles, String> {
+    let path = Path::new(model_path);
+    let dir = if path.is_file() {
+        path.parent().ok_or("Cannot determine parent directory")?
+    } else {
+        path
+    };
+    let weights = local_list_safetensors(dir)?;
+    validate_safetensors_files(&weights)?;
+    let optional = |name: &str| Some(dir.join(name)).filter(|p| p.exists());
+    Ok(EncoderFiles {
+        id: dir
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| model_path.to_string()),
+        config: dir.join("config.json"),
+        tokenizer: dir.join("tokenizer.json"),
+        weights: weights.into_iter().map(PathBuf::from).collect(),
+        pooling: optional("1_Pooling/config.json"),
+        modules: optional("modules.json"),
+    })
+}
+
+fn hub_files(repo_id: &str, revision: Option<&str>) -> Result<EncoderFiles, String> {
+    let hub = HubDownloader::new().map_err(|e| e.to_string())?;
+    let config = hub
+        .get_config(repo_id, revision)
+        .map_err(|e| format!("config.json download failed: {}", e))?;
+    let tokenizer = hub
+        .get_tokenizer(repo_id, revision)
+        .map_err(|e| format!("tokenizer.json download failed: {}", e))?;
+    let weights = hub
+        .get_safetensors_files(repo_id, revision)
+        .map_err(|e| format!("safetensors download failed: {}", e))?;
+    // Файлы sentence-transformers есть не у всех репозиториев
+    let optional = |name: &str| hub.get_file(repo_id, name, revision).ok();
+    Ok(EncoderFiles {
+        id: repo_id.to_string(),
+        config,
+        tokenizer,
+        weights,
+        pooling: optional("1_Pooling/config.json"),
+        modules: optional("modules.json"),
+    })
+}
+
+fn read_json(path: &Path) -> Result<serde_json::Value, String> {
+    let bytes =
+        std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    serde_json::from_slice(&bytes).map_err(|e| format!("Failed to parse {}: {}", path.display(), e))
+}
+
+/// Загружает энкодер; слот (эмбеддинги или реранкер) определяет сама модель
+pub fn load_encoder_model(req: LoadEncoderRequest) -> Result<EncoderEntry, String> {
+    let (files, device_pref) = match req {
+        LoadEncoderRequest::LocalSafetensors { model_path, device } => {
+            (local_files(&model_path)?, device)
+        }
+        LoadEncoderRequest::HubSafetensors {
+            repo_id,
+            revision,
+            device,
+        } => (hub_files(&repo_id, revision.as_deref())?, device),
+    };
+    let device = select_device(device_pref);
+    log_load!(
+        "encoder {}: {} weight files on {}",
+        files.id,
+        files.weights.len(),
+        device_label(&device)
+    );
+
+    let config = read_json(&files.config)?;
+    // SAFETY: файлы весов не изменяются, пока модель загружена
+    let vb = unsafe {
+        VarBuilder::from_mmaped_safetensors(&files.weights, ENCODER_DTYPE, &device)
+            .map_err(|e| format!("Failed to map encoder weights: {}", e))?
+    };
+    let model = EncoderModel::load(&config, vb)
+        .map_err(|e| format!("Failed to build encoder model: {}", e))?;
+
+    let mut tokenizer = Tokenizer::from_file(&files.tokenizer)
+        .map_err(|e| format!("tokenizer.json load failed: {}", e))?;
+    // Паддинг батча делает модель; длинные входы обрезаются, как в sentence-transformers
+    tokenizer.with_padding(None);
+    tokenizer
+        .with_truncation(Some(TruncationParams {
+            max_length: model.max_length(),
+            ..Default::default()
+        }))
+        .map_err(|e| format!("Failed to configure truncation: {}", e))?;
+
+    let pooling = files.pooling.as_deref().map(read_json).transpose()?;
+    let modules = files.modules.as_deref().map(read_json).transpose()?;
+    let default_options =
+        default_embedding_options(model.arch(), pooling.as_ref(), modules.as_ref());
+    log_load!(
+        "encoder {} ready: {:?} {:?}, hidden={}, max_length={}",
+        files.id,
+        model.arch(),
+        model.task(),
+        model.hidden_size(),
+        model.max_length()
+    );
+    Ok(EncoderEntry {
+        id: files.id,
+        model,
+        tokenizer,
+        default_options,
+    })
+}
@@ -1,19 +1,21 @@
 use super::{LoadDebugCtx, emit_load_progress_debug};
+use crate::api::model_loading::context_settings::{ContextSettingsManager, TokenizerOverride};
 use crate::core::device::{device_label, select_device};
 use crate::core::performance::ModelLoadTracker;
 use crate::core::state::ModelState;
 use crate::core::tokenizer::{
-    extract_chat_template, find_chat_template_in_metadata, mark_special_chat_tokens,
-    tokenizer_from_gguf_metadata,
+    extract_chat_template, find_chat_template_in_metadata, gguf_model_vocab_size,
+    load_external_tokenizer, mark_special_chat_tokens, tokenizer_from_gguf_metadata,
 };
 use crate::generate::cancel::CANCEL_LOADING;
 
+use crate::models::common::gguf_mmap::MmapReader;
+use crate::models::common::gguf_split::{gguf_paths, merge_shards};
 use crate::models::registry::{detect_arch, get_model_factory};
 use crate::{log_load, log_template, log_template_error};
 use candle::quantized::gguf_file;
 use std::collections::HashSet;
-use std::fs::File;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::Ordering;
 use tauri::Emitter;
 
@@ -21,6 +23,7 @@ pub fn load_gguf_model(
     app: &tauri::AppHandle,
     guard: &mut ModelState,
     model_path: String,
+    tokenizer_path: Option<String>,
     request_context_length: usize, // Shadowed later
     device_pref: Option<crate::core::types::DevicePreference>,
 ) -> Result<(), String> {
@@ -60,10 +63,16 @@ pub fn load_gguf_model(
         None,
     );
 
-    let mut file = File::open(&model_path).map_err(|e| {
-        emit_load_progress_debug(&dbg, app, "open_file", 8, None, false, Some(&e.to_string()));
-        e.to_string()
-    })?;
+    // Для шардов (`-00001-of-0000N.gguf`) открываются все файлы группы
+    let model_files = gguf_paths(Path::new(&model_path));
+    let files = model_files
+        .iter()
+        .map(|p| MmapReader::open(p))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            emit_load_progress_debug(&dbg, app, "open_file", 8, None, false, Some(&e.to_string()));
+            e.to_string()
+        })?;
     tracker.start_stage("file_opening");
     emit_load_progress_debug(&dbg, app, "open_file", 10, Some("Файл открыт"), false, None);
     if CANCEL_LOADING.load(Ordering::SeqCst) {
@@ -82,7 +91,7 @@ pub fn load_gguf_model(
     tracker.start_stage("read_header");
     dbg.stage_begin("read_header");
     let read_header_start = std::time::Instant::now();
-    let content = gguf_file::Content::read(&mut file).map_err(|e| {
+    let (mut content, mut file) = merge_shards(files).map_err(|e| {
         let error_msg = e.with_path(PathBuf::from(model_path.clone())).to_string();
 
         // Улучшаем сообщение об ошибке для пользователя
@@ -96,6 +105,9 @@ pub fn load_gguf_model(
         enhanced_msg
     })?;
     dbg.stage_end("read_header", read_header_start.elapsed());
+    if let Some(scaling) = guard.rope_scaling {
+        scaling.apply_to_gguf(&mut content.metadata);
+    }
 
     let rope_metadata: Vec<_> = content
         .metadata
@@ -127,20 +139,48 @@ pub fn load_gguf_model(
     }
 
     tracker.start_stage("tokenizer_init");
-    // Токенизатор обязан быть в метаданных GGUF. Никакого внешнего tokenizer.json не допускается.
-    let (mut tokenizer, tokenizer_source): (tokenizers::Tokenizer, &'static str) =
-        match tokenizer_from_gguf_metadata(&content.metadata) {
-            Ok(tk) => (tk, "embedded"),
-            Err(e) => {
-                return Err(format!(
-                    "Tokenizer must be embedded in GGUF metadata: {}",
-                    e
-                ));
-            }
-        };
-    mark_special_chat_tokens(&mut tokenizer);
-    let chat_tpl = extract_chat_template(&tokenizer)
-        .or_else(|| find_chat_template_in_metadata(&content.metadata));
+    // Токенизатор берётся из метаданных GGUF, если для модели не задан внешний tokenizer.json.
+    // Переопределение из запроса сохраняется для модели; пустая строка его сбрасывает.
+    let settings_manager = ContextSettingsManager::new(app);
+    let model_id = Path::new(&model_path)
+        .file_name()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_else(|| "unknown_model".to_string());
+    let tokenizer_override = match tokenizer_path {
+        Some(path) if path.trim().is_empty() => {
+            settings_manager.save_tokenizer_override(&model_id, None)?;
+            None
+        }
+        Some(path) => Some(TokenizerOverride {
+            tokenizer_path: path,
+        }),
+        None => settings_manager.get_tokenizer_override(&model_id),
+    };
+    let (tokenizer, chat_tpl, tokenizer_source) = match tokenizer_override {
+        Some(over) => {
+            let external = load_external_tokenizer(
+                Path::new(&over.tokenizer_path),
+                gguf_model_vocab_size(&content),
+            )
+            .inspect_err(|e| {
+                emit_load_progress_debug(&dbg, app, "tokenizer", 30, None, false, Some(e));
+            })?;
+            settings_manager.save_tokenizer_override(&model_id, Some(over.clone()))?;
+            guard.tokenizer_path = Some(over.tokenizer_path);
+            let chat_tpl = external
+                .chat_template
+                .or_else(|| find_chat_template_in_metadata(&content.metadata));
+            (external.tokenizer, chat_tpl, "external")
+        }
+        None => {
+            let mut tokenizer = tokenizer_from_gguf_metadata(&content.metadata)
+                .map_err(|e| format!("Tokenizer must be embedded in GGUF metadata: {}", e))?;
+            mark_special_chat_tokens(&mut tokenizer);
+            let chat_tpl = extract_chat_template(&tokenizer)
+                .or_else(|| find_chat_template_in_metadata(&content.metadata));
+            (tokenizer, chat_tpl, "embedded")
+        }
+    };
     // Не отключаем шаблон даже при ошибке — логируем, но сохраняем сырой вариант (рендер сам уйдёт в fallback)
     let chat_tpl = match chat_tpl {
         Some(raw) => {
@@ -275,19 +315,12 @@ pub fn load_gguf_model(
 
     // Determine final context length
     let context_length = if n_layer > 0 && n_embd > 0 && n_head > 0 {
-        use crate::api::model_loading::context_algo::{ModelCacheParams, estimate_best_context};
-        use crate::api::model_loading::context_settings::{
-            ContextSettingsManager, ContextSource, ModelContextSettings,
+        use crate::api::model_loading::context_algo::{
+            ModelCacheParams, estimate_best_context, settings_key,
         };
+        use crate::api::model_loading::context_settings::{ContextSource, ModelContextSettings};
 
-        let settings_manager = ContextSettingsManager::new(app);
-        // Use filename as ID
-        let model_id = std::path::Path::new(&model_path)
-            .file_name()
-            .map(|s| s.to_string_lossy().to_string())
-            .unwrap_or_else(|| "unknown_model".to_string());
-
-        let existing = settings_manager.get_settings(&model_id);
+        let existing = settings_manager.get_settings(&settings_key(&model_id, guard.kv_cache_type));
         if let Some(s) = existing {
             log::info!(
                 "Using saved context settings for {}: size={}, source={:?}",
@@ -304,6 +337,7 @@ pub fn load_gguf_model(
                 n_kv_head,
                 head_dim,
                 dtype_size: 2, // Assuming F16/Q8 equivalent cache size (safe upper estimation)
+                kv_cache_type: guard.kv_cache_type,
             };
 
             // Candidates: 8192, 16384, 32768, 65536
@@ -325,7 +359,8 @@ pub fn load_gguf_model(
                         .as_secs(),
                 ),
             };
-            let _ = settings_manager.save_settings(&model_id, new_settings);
+            let _ = settings_manager
+                .save_settings(&settings_key(&model_id, guard.kv_cache_type), new_settings);
 
             best_ctx
         }
@@ -410,6 +445,12 @@ pub fn load_gguf_model(
             emit_load_progress_debug(&dbg, app, "build_model", 60, None, false, Some(&e));
             format!("Failed to build model: {}", e)
         })?;
+    super::apply_lora_adapters(model_backend.as_mut(), guard).inspect_err(|e| {
+        emit_load_progress_debug(&dbg, app, "build_model", 60, None, false, Some(e));
+    })?;
+    super::apply_kv_cache_type(model_backend.as_mut(), guard).inspect_err(|e| {
+        emit_load_progress_debug(&dbg, app, "build_model", 60, None, false, Some(e));
+    })?;
     dbg.stage_end("build_model_backend", build_start.elapsed());
 
     // Если модель предоставляет возможность применения конфигурации - применим
@@ -471,7 +512,6 @@ pub fn load_gguf_model(
     };
     guard.context_length = ctx;
     guard.model_path = Some(model_path);
-    guard.tokenizer_path = None;
     log_load!(
         "gguf loaded, context_length={}, tokenizer_source={}",
         guard.context_length,
@@ -488,9 +528,11 @@ pub fn load_gguf_model(
     );
 
     // Финализируем метрики загрузки
-    let model_size_mb = std::fs::metadata(guard.model_path.as_ref().unwrap())
+    let model_size_mb = model_files
+        .iter()
+        .filter_map(|p| std::fs::metadata(p).ok())
         .map(|m| m.len() as f64 / (1024.0 * 1024.0))
-        .unwrap_or(0.0);
+        .sum::<f64>();
 
     let metrics = tokio::runtime::Runtime::new()
         .map_err(|e| e.to_string())?
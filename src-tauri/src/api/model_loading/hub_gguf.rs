@@ -6,11 +6,11 @@ use crate::core::tokenizer::{
 };
 use crate::generate::cancel::CANCEL_LOADING;
 
+use crate::models::common::gguf_split::{open_gguf, parse_shard_name};
 use crate::models::registry::{detect_arch, get_model_factory};
 use crate::{log_hub, log_load, log_template};
 use candle::quantized::gguf_file;
 use std::collections::HashSet;
-use std::fs::File;
 use std::sync::atomic::Ordering;
 
 pub fn load_hub_gguf_model(
@@ -45,6 +45,31 @@ pub fn load_hub_gguf_model(
         format!("hf_hub get {} failed: {}", filename, e)
     })?;
     log_hub!("gguf cached at {}", model_path.display());
+    // Остальные шарды скачиваются в тот же снапшот рядом с первым
+    let shard = std::path::Path::new(&filename)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .and_then(parse_shard_name);
+    if let Some(shard) = shard {
+        for name in shard.file_names() {
+            let remote = match filename.rsplit_once('/') {
+                Some((dir, _)) => format!("{dir}/{name}"),
+                None => name,
+            };
+            api.get(&remote).map_err(|e| {
+                emit_load_progress_debug(
+                    &dbg,
+                    app,
+                    "hub_get",
+                    12,
+                    None,
+                    false,
+                    Some(&e.to_string()),
+                );
+                format!("hf_hub get {} failed: {}", remote, e)
+            })?;
+        }
+    }
     emit_load_progress_debug(
         &dbg,
         app,
@@ -54,12 +79,12 @@ pub fn load_hub_gguf_model(
         false,
         None,
     );
-    let mut file = File::open(&model_path).map_err(|e| e.to_string())?;
-    let content = gguf_file::Content::read(&mut file).map_err(|e| {
-        let msg = e.with_path(model_path.clone()).to_string();
-        emit_load_progress_debug(&dbg, app, "read_header", 25, None, false, Some(&msg));
-        msg
+    let (mut content, mut file) = open_gguf(&model_path).inspect_err(|msg| {
+        emit_load_progress_debug(&dbg, app, "read_header", 25, None, false, Some(msg));
     })?;
+    if let Some(scaling) = guard.rope_scaling {
+        scaling.apply_to_gguf(&mut content.metadata);
+    }
     emit_load_progress_debug(
         &dbg,
         app,
@@ -193,6 +218,12 @@ pub fn load_hub_gguf_model(
             emit_load_progress_debug(&dbg, app, "build_model", 65, None, false, Some(&e));
             format!("Failed to build model: {}", e)
         })?;
+    super::apply_lora_adapters(model_backend.as_mut(), guard).inspect_err(|e| {
+        emit_load_progress_debug(&dbg, app, "build_model", 65, None, false, Some(e));
+    })?;
+    super::apply_kv_cache_type(model_backend.as_mut(), guard).inspect_err(|e| {
+        emit_load_progress_debug(&dbg, app, "build_model", 65, None, false, Some(e));
+    })?;
 
     // Если есть JSON-конфигурация в guard.model_config_json — применим её
     if let Some(gg) = guard.model_config_json.as_ref()
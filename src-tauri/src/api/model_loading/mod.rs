@@ -1,5 +1,6 @@
 pub mod context_algo;
 pub mod context_settings;
+pub mod encoder;
 pub mod gguf;
 pub mod hub_gguf;
 pub mod safetensors;
@@ -126,3 +127,39 @@ pub fn emit_load_progress_debug(
     }
     emit_load_progress(app, stage, progress, message, done, error);
 }
+
+/// Подключает LoRA-адаптеры из состояния загрузки к собранной GGUF-модели
+pub fn apply_lora_adapters(
+    model: &mut dyn crate::models::ModelBackend,
+    state: &crate::core::state::ModelState,
+) -> Result<(), String> {
+    if state.lora_adapters.is_empty() {
+        return Ok(());
+    }
+    let adapters = crate::models::common::load_adapters(&state.lora_adapters, &state.device)?;
+    model
+        .set_lora_adapters(&adapters)
+        .map_err(|e| format!("Failed to apply LoRA adapters: {}", e))?;
+    crate::log_load!("LoRA adapters attached: {}", adapters.len());
+    Ok(())
+}
+
+/// Переключает KV-кэш собранной модели на формат из запроса загрузки.
+/// Вызывается до установки модели, чтобы неподдерживаемый формат не дошёл до события "complete"
+pub fn apply_kv_cache_type(
+    model: &mut dyn crate::models::ModelBackend,
+    state: &crate::core::state::ModelState,
+) -> Result<(), String> {
+    let cache_type = state.kv_cache_type;
+    if cache_type == crate::models::common::KvCacheType::Full {
+        return Ok(());
+    }
+    if !model.set_kv_cache_type(cache_type) {
+        return Err(format!(
+            "Quantized KV cache is not supported for model type '{}'",
+            model.model_type()
+        ));
+    }
+    crate::log_load!("KV cache storage: {:?}", cache_type);
+    Ok(())
+}
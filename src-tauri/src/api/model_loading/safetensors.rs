@@ -8,6 +8,7 @@ use std::path::Path;
 
 use super::emit_load_progress;
 use crate::core::device::{device_label, select_device};
+use crate::core::performance::ModelLoadTracker;
 use crate::core::state::ModelState;
 use crate::core::template_registry::match_template;
 use crate::core::tokenizer::{extract_chat_template, mark_special_chat_tokens};
@@ -16,9 +17,11 @@ use crate::core::weights::{
 };
 use crate::generate::cancel::CANCEL_LOADING;
 use crate::models::ModelBackend;
-use crate::models::registry::{detect_arch_from_config, get_model_factory};
+use crate::models::common::LoraAdapter;
+use crate::models::registry::{ArchKind, detect_arch_from_config, get_model_factory};
 use crate::{log_hub_error, log_load, log_local_error, log_template};
 use std::sync::atomic::Ordering;
+use tauri::Emitter;
 
 /// Load a model from local safetensors files using the ModelBuilder pattern
 pub fn load_local_safetensors_model(
@@ -28,6 +31,9 @@ pub fn load_local_safetensors_model(
     request_context_length: usize, // Shadowed later
     device_pref: Option<crate::core::types::DevicePreference>,
 ) -> Result<(), String> {
+    let mut tracker = tokio::runtime::Runtime::new()
+        .map_err(|e| e.to_string())?
+        .block_on(ModelLoadTracker::new(guard.performance_monitor.clone()));
     emit_load_progress(
         app,
         "start",
@@ -196,7 +202,7 @@ pub fn load_local_safetensors_model(
 
         context_length = if n_layer > 0 && n_embd > 0 && n_head > 0 {
             use crate::api::model_loading::context_algo::{
-                ModelCacheParams, estimate_best_context,
+                ModelCacheParams, estimate_best_context, settings_key,
             };
             use crate::api::model_loading::context_settings::{
                 ContextSettingsManager, ContextSource, ModelContextSettings,
@@ -208,7 +214,8 @@ pub fn load_local_safetensors_model(
                 .map(|s| s.to_string_lossy().to_string())
                 .unwrap_or_else(|| model_path.to_string_lossy().to_string());
 
-            let existing = settings_manager.get_settings(&model_id);
+            let existing =
+                settings_manager.get_settings(&settings_key(&model_id, guard.kv_cache_type));
             if let Some(s) = existing {
                 log::info!(
                     "Using saved context settings for {}: size={}, source={:?}",
@@ -224,6 +231,7 @@ pub fn load_local_safetensors_model(
                     n_kv_head,
                     head_dim,
                     dtype_size: 2,
+                    kv_cache_type: guard.kv_cache_type,
                 };
                 let candidates = vec![4096, 8192, 16384, 24576, 32768, 49152, 65536];
                 let best_ctx = estimate_best_context(&guard.device, &cache_params, &candidates);
@@ -238,7 +246,8 @@ pub fn load_local_safetensors_model(
                             .as_secs(),
                     ),
                 };
-                let _ = settings_manager.save_settings(&model_id, new_settings);
+                let _ = settings_manager
+                    .save_settings(&settings_key(&model_id, guard.kv_cache_type), new_settings);
                 best_ctx
             }
         } else {
@@ -273,7 +282,7 @@ pub fn load_local_safetensors_model(
 
         context_length = if n_layer > 0 && n_embd > 0 && n_head > 0 {
             use crate::api::model_loading::context_algo::{
-                ModelCacheParams, estimate_best_context,
+                ModelCacheParams, estimate_best_context, settings_key,
             };
             use crate::api::model_loading::context_settings::{
                 ContextSettingsManager, ContextSource, ModelContextSettings,
@@ -285,7 +294,8 @@ pub fn load_local_safetensors_model(
                 .map(|s| s.to_string_lossy().to_string())
                 .unwrap_or_else(|| model_path.to_string_lossy().to_string());
 
-            let existing = settings_manager.get_settings(&model_id);
+            let existing =
+                settings_manager.get_settings(&settings_key(&model_id, guard.kv_cache_type));
             if let Some(s) = existing {
                 log::info!(
                     "Using saved context settings for {}: size={}, source={:?}",
@@ -301,6 +311,7 @@ pub fn load_local_safetensors_model(
                     n_kv_head,
                     head_dim,
                     dtype_size: 2,
+                    kv_cache_type: guard.kv_cache_type,
                 };
                 let candidates = vec![4096, 8192, 16384, 24576, 32768, 49152, 65536];
                 let best_ctx = estimate_best_context(&guard.device, &cache_params, &candidates);
@@ -315,7 +326,8 @@ pub fn load_local_safetensors_model(
                             .as_secs(),
                     ),
                 };
-                let _ = settings_manager.save_settings(&model_id, new_settings);
+                let _ = settings_manager
+                    .save_settings(&settings_key(&model_id, guard.kv_cache_type), new_settings);
                 best_ctx
             }
         } else {
@@ -344,8 +356,18 @@ pub fn load_local_safetensors_model(
 
             // Use the model factory to build the model
             emit_load_progress(app, "build_model", 60, None, false, None);
-            match get_model_factory().build_from_safetensors(arch, &filenames, &config, &dev, dtype)
-            {
+            let lora = crate::models::common::load_adapters(&guard.lora_adapters, &dev)?;
+            tracker.start_stage("build_model");
+            match build_model(
+                guard,
+                &mut tracker,
+                arch,
+                &filenames,
+                &config,
+                &dev,
+                dtype,
+                &lora,
+            ) {
                 Ok(model_backend) => {
                     built_model_opt = Some(model_backend);
                     emit_load_progress(
@@ -365,7 +387,10 @@ pub fn load_local_safetensors_model(
         }
     }
 
-    if let Some(model) = built_model_opt {
+    if let Some(mut model) = built_model_opt {
+        super::apply_kv_cache_type(model.as_mut(), guard).inspect_err(|e| {
+            emit_load_progress(app, "build_model", 65, None, false, Some(e));
+        })?;
         guard
             .scheduler
             .load_model(model, model_path.to_string_lossy().to_string());
@@ -387,6 +412,11 @@ pub fn load_local_safetensors_model(
         "local safetensors loaded with ModelBuilder, context_length={}",
         guard.context_length
     );
+    emit_load_metrics(
+        app,
+        tracker,
+        guard.safetensors_files.as_deref().unwrap_or_default(),
+    )?;
     emit_load_progress(
         app,
         "finalize",
@@ -409,6 +439,9 @@ pub fn load_hub_safetensors_model(
     request_context_length: usize, // Shadowed later
     device_pref: Option<crate::core::types::DevicePreference>,
 ) -> Result<(), String> {
+    let mut tracker = tokio::runtime::Runtime::new()
+        .map_err(|e| e.to_string())?
+        .block_on(ModelLoadTracker::new(guard.performance_monitor.clone()));
     emit_load_progress(
         app,
         "start",
@@ -569,7 +602,7 @@ pub fn load_hub_safetensors_model(
 
         context_length = if n_layer > 0 && n_embd > 0 && n_head > 0 {
             use crate::api::model_loading::context_algo::{
-                ModelCacheParams, estimate_best_context,
+                ModelCacheParams, estimate_best_context, settings_key,
             };
             use crate::api::model_loading::context_settings::{
                 ContextSettingsManager, ContextSource, ModelContextSettings,
@@ -579,7 +612,8 @@ pub fn load_hub_safetensors_model(
             // Use repo_id as ID
             let model_id = repo_id.clone();
 
-            let existing = settings_manager.get_settings(&model_id);
+            let existing =
+                settings_manager.get_settings(&settings_key(&model_id, guard.kv_cache_type));
             if let Some(s) = existing {
                 log::info!(
                     "Using saved context settings for {}: size={}, source={:?}",
@@ -595,6 +629,7 @@ pub fn load_hub_safetensors_model(
                     n_kv_head,
                     head_dim,
                     dtype_size: 2,
+                    kv_cache_type: guard.kv_cache_type,
                 };
                 let candidates = vec![4096, 8192, 16384, 24576, 32768, 49152, 65536];
                 let best_ctx = estimate_best_context(&guard.device, &cache_params, &candidates);
@@ -609,7 +644,8 @@ pub fn load_hub_safetensors_model(
                             .as_secs(),
                     ),
                 };
-                let _ = settings_manager.save_settings(&model_id, new_settings);
+                let _ = settings_manager
+                    .save_settings(&settings_key(&model_id, guard.kv_cache_type), new_settings);
                 best_ctx
             }
         } else {
@@ -637,12 +673,17 @@ pub fn load_hub_safetensors_model(
 
             // Use the model factory to build the model
             emit_load_progress(app, "build_model", 70, None, false, None);
-            match get_model_factory().build_from_safetensors(
+            let lora = crate::models::common::load_adapters(&guard.lora_adapters, &dev)?;
+            tracker.start_stage("build_model");
+            match build_model(
+                guard,
+                &mut tracker,
                 arch,
                 &cached_filenames,
                 &config,
                 &dev,
                 dtype,
+                &lora,
             ) {
                 Ok(model_backend) => {
                     built_model_opt = Some(model_backend);
@@ -663,7 +704,10 @@ pub fn load_hub_safetensors_model(
         }
     }
 
-    if let Some(model) = built_model_opt {
+    if let Some(mut model) = built_model_opt {
+        super::apply_kv_cache_type(model.as_mut(), guard).inspect_err(|e| {
+            emit_load_progress(app, "build_model", 75, None, false, Some(e));
+        })?;
         guard.scheduler.load_model(model, repo_id.clone());
     } else {
         return Err("Failed to build model".into());
@@ -681,6 +725,11 @@ pub fn load_hub_safetensors_model(
         "hub safetensors loaded with ModelBuilder, context_length={}",
         guard.context_length
     );
+    emit_load_metrics(
+        app,
+        tracker,
+        guard.safetensors_files.as_deref().unwrap_or_default(),
+    )?;
     emit_load_progress(
         app,
         "finalize",
@@ -694,6 +743,80 @@ pub fn load_hub_safetensors_model(
     Ok(())
 }
 
+/// Строит модель из safetensors: как есть или с квантованием весов при чтении
+#[allow(clippy::too_many_arguments)]
+fn build_model(
+    guard: &ModelState,
+    tracker: &mut ModelLoadTracker,
+    arch: ArchKind,
+    files: &[String],
+    config: &serde_json::Value,
+    dev: &candle::Device,
+    dtype: candle::DType,
+    lora: &[LoraAdapter],
+) -> Result<Box<dyn ModelBackend + Send>, String> {
+    let factory = get_model_factory();
+    match guard
+        .quantize
+        .or_else(|| guard.precision_policy.quantization())
+    {
+        Some(target) => {
+            let (model, stats) = factory.build_quantized_from_safetensors(
+                arch,
+                files,
+                config,
+                dev,
+                target.ggml_dtype(),
+                dtype,
+                guard.rope_scaling,
+                lora,
+            )?;
+            log_load!(
+                "quantized {} tensors to {:?}: {:.1}MB -> {:.1}MB",
+                stats.tensors,
+                target,
+                stats.source_bytes as f64 / (1024.0 * 1024.0),
+                stats.quantized_bytes as f64 / (1024.0 * 1024.0)
+            );
+            tracker.record_memory_saved(stats.saved_mb());
+            Ok(model)
+        }
+        None => factory.build_from_safetensors(
+            arch,
+            files,
+            config,
+            dev,
+            dtype,
+            guard.rope_scaling,
+            lora,
+        ),
+    }
+}
+
+/// Завершает трекер загрузки и отправляет метрики на фронтенд
+fn emit_load_metrics(
+    app: &tauri::AppHandle,
+    tracker: ModelLoadTracker,
+    files: &[String],
+) -> Result<(), String> {
+    let model_size_mb = files
+        .iter()
+        .filter_map(|f| std::fs::metadata(f).ok())
+        .map(|m| m.len() as f64 / (1024.0 * 1024.0))
+        .sum();
+    let metrics = tokio::runtime::Runtime::new()
+        .map_err(|e| e.to_string())?
+        .block_on(tracker.finish(model_size_mb));
+    log_load!(
+        "Метрики загрузки: total_time={}ms, memory_delta={:.2}MB, memory_saved={:.2}MB",
+        metrics.total_duration_ms,
+        metrics.memory_delta_mb,
+        metrics.memory_saved_mb
+    );
+    let _ = app.emit("model_load_metrics", &metrics);
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -1,12 +1,14 @@
 //! OpenAI-compatible HTTP API server.
 //!
-//! Provides `/v1/chat/completions` and `/v1/models` endpoints for compatibility
-//! with OpenAI clients (Cursor, Continue, Open WebUI, etc.).
+//! Provides `/v1/chat/completions`, `/v1/embeddings`, `/v1/rerank`,
+//! `/v1/audio/transcriptions`, `/v1/audio/translations` and `/v1/models`
+//! endpoints for compatibility with OpenAI clients (Cursor, Continue,
+//! Open WebUI, etc.).
 
 use axum::{
     Json, Router,
-    extract::State,
-    http::StatusCode,
+    extract::{DefaultBodyLimit, Multipart, State},
+    http::{StatusCode, header},
     response::{
         IntoResponse, Response,
         sse::{Event, KeepAlive, Sse},
@@ -24,12 +26,20 @@ use std::{
 use tokio::sync::broadcast;
 use tower_http::cors::{Any, CorsLayer};
 
+use crate::api::commands::embeddings::{
+    EmbeddingsError, compute_embeddings, compute_encoder_embeddings, embedding_encoder,
+};
+use crate::api::commands::encoder::rerank_documents;
+use crate::core::audio_decode::decode_audio;
 use crate::core::state::SharedState;
+use crate::core::stt_whisper::{SttTask, is_supported_language, transcribe_segments};
+use crate::core::transcript::{ResponseFormat, Transcript, TranscriptSegment};
 use crate::core::types::{ChatMessage, GenerateRequest, ToolChoice};
+use crate::generate::batch::{BatchConfig, BatchEngine, is_batchable};
 use crate::generate::emit::{EmissionBackend, GenerationEvent};
 use crate::generate::stream::generate_stream_with_backend;
 use crate::generate::tool_call_parser::{Tool, ToolCall};
-use candle::Tensor;
+use crate::models::common::{EmbeddingOptions, Pooling};
 
 // ============================================================================
 // OpenAI API Types
@@ -41,6 +51,15 @@ pub struct EmbeddingRequest {
     pub input: EmbeddingInput,
     #[serde(default)]
     pub user: Option<String>,
+    /// Truncate vectors to the first N components (Matryoshka models)
+    #[serde(default)]
+    pub dimensions: Option<usize>,
+    /// Extension: how token states are pooled (`mean`, `last_token`, `cls`)
+    #[serde(default)]
+    pub pooling: Option<Pooling>,
+    /// Extension: L2-normalize the vectors
+    #[serde(default)]
+    pub normalize: Option<bool>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -71,8 +90,84 @@ pub struct EmbeddingUsage {
     pub total_tokens: usize,
 }
 
+/// `/v1/rerank` request (Jina/Cohere-compatible)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RerankRequest {
+    #[serde(default)]
+    pub model: Option<String>,
+    pub query: String,
+    pub documents: Vec<String>,
+    #[serde(default)]
+    pub top_n: Option<usize>,
+    #[serde(default)]
+    pub return_documents: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RerankResponse {
+    pub model: String,
+    pub results: Vec<RerankData>,
+    pub usage: RerankUsage,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RerankData {
+    pub index: usize,
+    pub relevance_score: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub document: Option<RerankDocument>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RerankDocument {
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RerankUsage {
+    pub total_tokens: usize,
+}
+
+/// `/v1/audio/*` upload (multipart form fields)
+#[derive(Debug, Default)]
+pub struct AudioRequest {
+    pub file: Vec<u8>,
+    pub filename: Option<String>,
+    /// ISO-639-1 code; detected from the audio when absent
+    pub language: Option<String>,
+    pub response_format: ResponseFormat,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AudioTextResponse {
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AudioVerboseResponse {
+    pub task: SttTask,
+    pub language: String,
+    pub duration: f64,
+    pub text: String,
+    pub segments: Vec<AudioSegment>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AudioSegment {
+    pub id: usize,
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+}
+
 pub const OPENAI_PORT: u16 = 11434;
 
+/// Upload limit for `/v1/audio/*`: long meeting recordings exceed axum's 2 MB default
+const MAX_AUDIO_UPLOAD_BYTES: usize = 512 * 1024 * 1024;
+
+/// Generic ids listed by `/v1/models` that stand for the loaded chat model
+pub(crate) const MODEL_ALIASES: [&str; 3] = ["local-model", "gpt-3.5-turbo", "gpt-4"];
+
 #[derive(Serialize)]
 pub struct ServerConfig {
     pub port: u16,
@@ -113,6 +208,18 @@ pub struct ChatCompletionRequest {
     /// Tool choice: auto, none, required, or specific function
     #[serde(default)]
     pub tool_choice: Option<ToolChoice>,
+    /// Extension: enable prompt lookup (n-gram speculative) decoding
+    #[serde(default)]
+    pub prompt_lookup: Option<bool>,
+    /// Extension: shift the context instead of stopping when it is full
+    #[serde(default)]
+    pub context_shift: Option<bool>,
+    /// Extension: summarize history that does not fit instead of dropping it
+    #[serde(default)]
+    pub rolling_summary: Option<bool>,
+    /// Extension: conversation id the rolling summary is cached under
+    #[serde(default)]
+    pub session_id: Option<String>,
 }
 
 /// Stop tokens can be a single string or an array of strings
@@ -296,6 +403,12 @@ pub struct CompletionRequest {
     pub temperature: Option<f64>,
     #[serde(default)]
     pub top_p: Option<f64>,
+    /// Extension: enable prompt lookup (n-gram speculative) decoding
+    #[serde(default)]
+    pub prompt_lookup: Option<bool>,
+    /// Extension: shift the context instead of stopping when it is full
+    #[serde(default)]
+    pub context_shift: Option<bool>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -333,6 +446,10 @@ impl EmissionBackend for OpenAIBackend {
     fn emit(&self, event: GenerationEvent) {
         let _ = self.tx.send(event);
     }
+
+    fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
 }
 
 // ============================================================================
@@ -342,6 +459,42 @@ impl EmissionBackend for OpenAIBackend {
 pub struct OpenAIServerState {
     pub model_state: SharedState,
     pub shutdown_tx: broadcast::Sender<()>,
+    /// Continuous batching для параллельных клиентов (None — только последовательный путь)
+    pub batch_engine: Option<BatchEngine>,
+    /// Хэндл приложения для Whisper (настройки и ресурсы STT)
+    pub app: tauri::AppHandle,
+}
+
+/// Запускает генерацию: через batch engine, если модель и запрос это позволяют,
+/// иначе последовательно в blocking-потоке
+fn spawn_generation(
+    state: &OpenAIServerState,
+    gen_req: GenerateRequest,
+    backend: Box<dyn EmissionBackend>,
+) {
+    let model_batchable = state.model_state.lock().ok().is_some_and(|guard| {
+        guard
+            .scheduler
+            .active_model
+            .as_ref()
+            .is_some_and(|entry| entry.model.supports_batching())
+    });
+    if let Some(engine) = state.batch_engine.as_ref()
+        && model_batchable
+        && is_batchable(&gen_req)
+    {
+        if let Err(e) = engine.submit(gen_req, backend) {
+            log::error!("Generation failed: {}", e);
+        }
+        return;
+    }
+
+    let state_clone = state.model_state.clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        if let Err(e) = generate_stream_with_backend(state_clone, gen_req, backend) {
+            log::error!("Generation failed: {}", e);
+        }
+    });
 }
 
 // ============================================================================
@@ -380,25 +533,33 @@ async fn models_handler(
         vec![]
     };
 
+    // Энкодеры эмбеддингов и реранкер доступны по своим id
+    for entry in [&guard.encoders.embedding, &guard.encoders.reranker]
+        .into_iter()
+        .flatten()
+    {
+        models.push(Model {
+            id: entry.id.clone(),
+            object: "model".to_string(),
+            created: now_unix(),
+            owned_by: "oxide-lab".to_string(),
+        });
+    }
+
     // Always add generic aliases to satisfy clients checking for specific models
-    models.push(Model {
-        id: "local-model".to_string(),
-        object: "model".to_string(),
-        created: now_unix(),
-        owned_by: "oxide-lab".to_string(),
-    });
-    models.push(Model {
-        id: "gpt-3.5-turbo".to_string(),
-        object: "model".to_string(),
-        created: now_unix(),
-        owned_by: "openai".to_string(),
-    });
-    models.push(Model {
-        id: "gpt-4".to_string(),
-        object: "model".to_string(),
-        created: now_unix(),
-        owned_by: "openai".to_string(),
-    });
+    for alias in MODEL_ALIASES {
+        models.push(Model {
+            id: alias.to_string(),
+            object: "model".to_string(),
+            created: now_unix(),
+            owned_by: if alias == "local-model" {
+                "oxide-lab"
+            } else {
+                "openai"
+            }
+            .to_string(),
+        });
+    }
 
     Ok(Json(ModelList {
         object: "list".to_string(),
@@ -406,92 +567,285 @@ async fn models_handler(
     }))
 }
 
+/// `/v1/embeddings`: vectors from the embedding encoder slot or the chat model
+///
+/// `model` picks the encoder by its id; the chat model id and the generic
+/// aliases select the chat model, and any other id is rejected with 400.
+///
+/// Without an encoder the chat model runs the inputs from position 0, which
+/// overwrites its KV cache: the prefix cache and the restored chat session are
+/// dropped, and the next chat turn re-processes its whole prompt. SafeTensors
+/// Llama and Qwen2-MoE expose only logits and cannot serve embeddings; their
+/// GGUF variants or an encoder can.
 async fn embeddings_handler(
     State(state): State<Arc<OpenAIServerState>>,
     Json(req): Json<EmbeddingRequest>,
 ) -> Result<impl IntoResponse, (StatusCode, Json<ErrorResponse>)> {
-    let mut guard = state
-        .model_state
-        .lock()
-        .map_err(|_| server_error("Lock failed"))?;
-
-    if !guard.scheduler.has_model() || guard.tokenizer.is_none() {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            Json(ErrorResponse {
-                error: ApiError {
-                    message: "Model/tokenizer is not loaded".into(),
-                    error_type: "invalid_request_error".into(),
-                    code: None,
-                },
-            }),
-        ));
-    }
-
-    let tokenizer = guard.tokenizer.clone().unwrap();
-    let model_name = req.model.clone();
-
     let inputs = match req.input {
         EmbeddingInput::String(s) => vec![s],
         EmbeddingInput::Array(v) => v,
     };
-
-    let mut data = Vec::new();
-    let mut total_tokens = 0;
-
-    for (index, text) in inputs.into_iter().enumerate() {
-        let tokens = tokenizer
-            .encode(text, true)
-            .map_err(|e| server_error(&e.to_string()))?;
-        let token_ids = tokens.get_ids();
-        let prompt_tokens = token_ids.len();
-        total_tokens += prompt_tokens;
-
-        let input_tensor = Tensor::new(token_ids, &guard.device)
-            .map_err(|e| server_error(&e.to_string()))?
-            .unsqueeze(0)
-            .map_err(|e| server_error(&e.to_string()))?;
-
-        let mut entry = guard
-            .scheduler
-            .take_model()
-            .ok_or_else(|| server_error("Model missing during execution"))?;
-
-        let result = (|| -> candle::Result<Vec<f32>> {
-            let hidden_states = entry.model.get_embeddings(&input_tensor)?;
-            let embedding_tensor = hidden_states.mean(1)?.squeeze(0)?;
-            embedding_tensor.to_vec1()
-        })();
-
-        let embedding = match result {
-            Ok(emb) => {
-                guard.scheduler.restore_model(entry);
-                emb
-            }
-            Err(e) => {
-                guard.scheduler.restore_model(entry);
-                return Err(server_error(&format!("Embedding failed: {}", e)));
-            }
+    if inputs.is_empty() {
+        return Err(invalid_request("input must not be empty"));
+    }
+    if req.dimensions == Some(0) {
+        return Err(invalid_request("dimensions must be positive"));
+    }
+    let model_state = state.model_state.clone();
+    let model = req.model.clone();
+    let result = tauri::async_runtime::spawn_blocking(move || {
+        let mut guard = model_state
+            .lock()
+            .map_err(|_| EmbeddingsError::Failed("Lock failed".into()))?;
+        // Энкодер из слота эмбеддингов берёт пулинг и нормализацию из своей конфигурации
+        if let Some(entry) = embedding_encoder(&guard, Some(&model))? {
+            drop(guard);
+            let options = EmbeddingOptions {
+                pooling: req.pooling.unwrap_or(entry.default_options.pooling),
+                normalize: req.normalize.unwrap_or(entry.default_options.normalize),
+                dimensions: req.dimensions,
+            };
+            return compute_encoder_embeddings(&entry, &inputs, &options).map(Some);
+        }
+        if !guard.scheduler.has_model() || guard.tokenizer.is_none() {
+            return Ok(None);
+        }
+        let options = EmbeddingOptions {
+            pooling: req.pooling.unwrap_or_default(),
+            normalize: req.normalize.unwrap_or(false),
+            dimensions: req.dimensions,
         };
-
-        data.push(EmbeddingData {
+        compute_embeddings(&mut guard, &inputs, &options).map(Some)
+    })
+    .await
+    .map_err(|e| server_error(&e.to_string()))?
+    .map_err(|e| match e {
+        EmbeddingsError::InvalidRequest(msg) => invalid_request(&msg),
+        EmbeddingsError::Failed(msg) => server_error(&msg),
+    })?
+    .ok_or_else(|| invalid_request("Model/tokenizer is not loaded"))?;
+
+    let data = result
+        .embeddings
+        .into_iter()
+        .enumerate()
+        .map(|(index, embedding)| EmbeddingData {
             object: "embedding".to_string(),
             index,
             embedding,
-        });
-    }
+        })
+        .collect();
 
     Ok(Json(EmbeddingResponse {
         object: "list".to_string(),
         data,
-        model: model_name,
+        model: req.model,
         usage: EmbeddingUsage {
-            prompt_tokens: total_tokens,
-            total_tokens,
+            prompt_tokens: result.prompt_tokens,
+            total_tokens: result.prompt_tokens,
         },
     }))
 }
 
+/// `/v1/rerank`: scores documents with the reranker slot; a `model` other than
+/// the loaded reranker id is rejected with 400
+async fn rerank_handler(
+    State(state): State<Arc<OpenAIServerState>>,
+    Json(req): Json<RerankRequest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorResponse>)> {
+    if req.documents.is_empty() {
+        return Err(invalid_request("documents must not be empty"));
+    }
+    let model_state = state.model_state.clone();
+    let (query, documents, top_n) = (req.query, req.documents, req.top_n);
+    let requested = req.model;
+    let (model, ranked, documents) = tauri::async_runtime::spawn_blocking(move || {
+        let guard = model_state
+            .lock()
+            .map_err(|_| server_error("Lock failed"))?;
+        let entry = guard
+            .encoders
+            .reranker
+            .clone()
+            .ok_or_else(|| invalid_request("Reranker model is not loaded"))?;
+        drop(guard);
+        if let Some(requested) = requested
+            && requested != entry.id
+        {
+            return Err(invalid_request(&format!(
+                "Model '{}' is not the loaded reranker '{}'",
+                requested, entry.id
+            )));
+        }
+        let ranked =
+            rerank_documents(&entry, &query, &documents, top_n).map_err(|e| server_error(&e))?;
+        Ok((entry.id.clone(), ranked, documents))
+    })
+    .await
+    .map_err(|e| server_error(&e.to_string()))??;
+
+    let results = ranked
+        .results
+        .into_iter()
+        .map(|r| RerankData {
+            index: r.index,
+            relevance_score: r.relevance_score,
+            document: req.return_documents.then(|| RerankDocument {
+                text: documents[r.index].clone(),
+            }),
+        })
+        .collect();
+
+    Ok(Json(RerankResponse {
+        model,
+        results,
+        usage: RerankUsage {
+            total_tokens: ranked.total_tokens,
+        },
+    }))
+}
+
+async fn audio_transcriptions_handler(
+    State(state): State<Arc<OpenAIServerState>>,
+    multipart: Multipart,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    transcribe_upload(state, multipart, SttTask::Transcribe).await
+}
+
+async fn audio_translations_handler(
+    State(state): State<Arc<OpenAIServerState>>,
+    multipart: Multipart,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    transcribe_upload(state, multipart, SttTask::Translate).await
+}
+
+/// Reads the multipart form
+///
+/// `model`, `prompt` and `temperature` are accepted but ignored: the Whisper model
+/// comes from the STT settings and decoding is greedy.
+async fn read_audio_request(
+    mut multipart: Multipart,
+) -> Result<AudioRequest, (StatusCode, Json<ErrorResponse>)> {
+    let mut req = AudioRequest::default();
+    let mut has_file = false;
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| invalid_request(&format!("Invalid multipart body: {}", e)))?
+    {
+        let name = field.name().unwrap_or_default().to_string();
+        if name == "file" {
+            req.filename = field.file_name().map(str::to_string);
+            req.file = field
+                .bytes()
+                .await
+                .map_err(|e| invalid_request(&format!("Failed to read file: {}", e)))?
+                .to_vec();
+            has_file = true;
+            continue;
+        }
+        let value = field
+            .text()
+            .await
+            .map_err(|e| invalid_request(&format!("Invalid field '{}': {}", name, e)))?;
+        match name.as_str() {
+            "language" => {
+                if !value.is_empty() && !is_supported_language(&value) {
+                    return Err(invalid_request(&format!(
+                        "Unsupported language '{}': expected an ISO-639-1 code supported by Whisper",
+                        value
+                    )));
+                }
+                req.language = Some(value).filter(|v| !v.is_empty())
+            }
+            "response_format" => {
+                req.response_format = value.parse().map_err(|e: String| invalid_request(&e))?
+            }
+            _ => {}
+        }
+    }
+    if !has_file || req.file.is_empty() {
+        return Err(invalid_request("file is required"));
+    }
+    Ok(req)
+}
+
+async fn transcribe_upload(
+    state: Arc<OpenAIServerState>,
+    multipart: Multipart,
+    task: SttTask,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let req = read_audio_request(multipart).await?;
+    let extension = req
+        .filename
+        .as_deref()
+        .and_then(|name| std::path::Path::new(name).extension())
+        .map(|ext| ext.to_string_lossy().to_lowercase());
+    let file = req.file;
+    let samples =
+        tauri::async_runtime::spawn_blocking(move || decode_audio(file, extension.as_deref()))
+            .await
+            .map_err(|e| server_error(&e.to_string()))?
+            .map_err(|e| invalid_request(&e))?;
+
+    let app = state.app.clone();
+    let language = req.language;
+    let timestamps = req.response_format.needs_timestamps();
+    let transcript = tauri::async_runtime::spawn_blocking(move || {
+        transcribe_segments(&app, &samples, language.as_deref(), task, timestamps)
+    })
+    .await
+    .map_err(|e| server_error(&e.to_string()))?
+    .map_err(|e| server_error(&e))?;
+
+    Ok(render_transcript(transcript, req.response_format, task))
+}
+
+fn render_transcript(transcript: Transcript, format: ResponseFormat, task: SttTask) -> Response {
+    const TEXT_PLAIN: &str = "text/plain; charset=utf-8";
+    match format {
+        ResponseFormat::Json => Json(AudioTextResponse {
+            text: transcript.text(),
+        })
+        .into_response(),
+        ResponseFormat::Text => {
+            ([(header::CONTENT_TYPE, TEXT_PLAIN)], transcript.text()).into_response()
+        }
+        ResponseFormat::Srt => {
+            ([(header::CONTENT_TYPE, TEXT_PLAIN)], transcript.to_srt()).into_response()
+        }
+        ResponseFormat::Vtt => (
+            [(header::CONTENT_TYPE, "text/vtt; charset=utf-8")],
+            transcript.to_vtt(),
+        )
+            .into_response(),
+        ResponseFormat::VerboseJson => {
+            let text = transcript.text();
+            let segments = transcript
+                .segments
+                .into_iter()
+                .enumerate()
+                .map(
+                    |(id, TranscriptSegment { start, end, text })| AudioSegment {
+                        id,
+                        start,
+                        end,
+                        text,
+                    },
+                )
+                .collect();
+            Json(AudioVerboseResponse {
+                task,
+                language: transcript.language,
+                duration: transcript.duration,
+                text,
+                segments,
+            })
+            .into_response()
+        }
+    }
+}
+
 async fn chat_completions_handler(
     State(state): State<Arc<OpenAIServerState>>,
     Json(req): Json<ChatCompletionRequest>,
@@ -576,21 +930,21 @@ async fn create_completion(
         tracing: None,
         verbose_prompt: None,
         split_prompt: None,
+        prefill_chunk_size: None,
         attachments: None,
         edit_index: None,
         format: None,
         stop_sequences,
         tool_choice: req.tool_choice,
+        prompt_lookup: req.prompt_lookup,
+        context_shift: req.context_shift,
+        context_shift_keep: None,
+        rolling_summary: req.rolling_summary,
+        session_id: req.session_id,
+        request_id: Some(id.clone()),
     };
 
-    let state_clone = state.model_state.clone();
-
-    // Spawn generation in blocking thread
-    tauri::async_runtime::spawn_blocking(move || {
-        if let Err(e) = generate_stream_with_backend(state_clone, gen_req, backend) {
-            log::error!("Generation failed: {}", e);
-        }
-    });
+    spawn_generation(&state, gen_req, backend);
 
     let mut full_content = String::new();
     let mut tool_calls = Vec::new();
@@ -611,6 +965,7 @@ async fn create_completion(
                 usage.completion_tokens = m.generated_tokens;
                 usage.total_tokens = m.prompt_tokens + m.generated_tokens;
             }
+            GenerationEvent::Error(message) => return Err(server_error(&message)),
             GenerationEvent::Done => {}
             _ => {}
         }
@@ -699,21 +1054,21 @@ async fn create_completion_stream(
         tracing: None,
         verbose_prompt: None,
         split_prompt: None,
+        prefill_chunk_size: None,
         attachments: None,
         edit_index: None,
         format: None,
         stop_sequences,
         tool_choice: req.tool_choice,
+        prompt_lookup: req.prompt_lookup,
+        context_shift: req.context_shift,
+        context_shift_keep: None,
+        rolling_summary: req.rolling_summary,
+        session_id: req.session_id,
+        request_id: Some(id.clone()),
     };
 
-    let state_clone = state.model_state.clone();
-
-    // Spawn generation in blocking thread
-    tauri::async_runtime::spawn_blocking(move || {
-        if let Err(e) = generate_stream_with_backend(state_clone, gen_req, backend) {
-            log::error!("Generation failed: {}", e);
-        }
-    });
+    spawn_generation(&state, gen_req, backend);
 
     let stream = stream::unfold(
         (rx, id, model_id, false, false), // Added done_sent state
@@ -731,6 +1086,11 @@ async fn create_completion_stream(
             }
 
             match rx.recv().await {
+                // Ошибка посреди потока: объект ошибки OpenAI, затем [DONE]
+                Some(GenerationEvent::Error(message)) => Some((
+                    Ok(stream_error_event(&message)),
+                    (rx, id, model_id, true, done_sent),
+                )),
                 Some(event) => {
                     let chunk = match event {
                         GenerationEvent::Start => ChatCompletionChunk {
@@ -804,19 +1164,22 @@ async fn create_completion_stream(
                                 }],
                             }
                         }
-                        GenerationEvent::Metrics(_) | GenerationEvent::PromptDump(_) => {
-                            ChatCompletionChunk {
-                                id: id.clone(),
-                                object: "chat.completion.chunk".to_string(),
-                                created: now_unix(),
-                                model: model_id.clone(),
-                                choices: vec![ChunkChoice {
-                                    index: 0,
-                                    delta: Delta::default(),
-                                    finish_reason: None,
-                                }],
-                            }
-                        }
+                        GenerationEvent::Metrics(_)
+                        | GenerationEvent::PromptDump(_)
+                        | GenerationEvent::PrefillProgress(_)
+                        | GenerationEvent::ContextReport(_)
+                        // Ошибка уже отправлена отдельным событием выше
+                        | GenerationEvent::Error(_) => ChatCompletionChunk {
+                            id: id.clone(),
+                            object: "chat.completion.chunk".to_string(),
+                            created: now_unix(),
+                            model: model_id.clone(),
+                            choices: vec![ChunkChoice {
+                                index: 0,
+                                delta: Delta::default(),
+                                finish_reason: None,
+                            }],
+                        },
                         GenerationEvent::Done => {
                             finished = true;
                             ChatCompletionChunk {
@@ -907,20 +1270,21 @@ async fn create_legacy_completion(
         tracing: None,
         verbose_prompt: None,
         split_prompt: None,
+        prefill_chunk_size: None,
         attachments: None,
         edit_index: None,
         format: None,
         stop_sequences: None,
         tool_choice: None,
+        prompt_lookup: req.prompt_lookup,
+        context_shift: req.context_shift,
+        context_shift_keep: None,
+        rolling_summary: None,
+        session_id: None,
+        request_id: Some(id.clone()),
     };
 
-    let state_clone = state.model_state.clone();
-
-    tauri::async_runtime::spawn_blocking(move || {
-        if let Err(e) = generate_stream_with_backend(state_clone, gen_req, backend) {
-            log::error!("Generation failed: {}", e);
-        }
-    });
+    spawn_generation(&state, gen_req, backend);
 
     let mut full_text = String::new();
     let mut usage = Usage {
@@ -938,6 +1302,7 @@ async fn create_legacy_completion(
                 usage.completion_tokens = m.generated_tokens;
                 usage.total_tokens = m.prompt_tokens + m.generated_tokens;
             }
+            GenerationEvent::Error(message) => return Err(server_error(&message)),
             _ => {}
         }
     }
@@ -1001,20 +1366,21 @@ async fn create_legacy_completion_stream(
         tracing: None,
         verbose_prompt: None,
         split_prompt: None,
+        prefill_chunk_size: None,
         attachments: None,
         edit_index: None,
         format: None,
         stop_sequences: None,
         tool_choice: None,
+        prompt_lookup: req.prompt_lookup,
+        context_shift: req.context_shift,
+        context_shift_keep: None,
+        rolling_summary: None,
+        session_id: None,
+        request_id: Some(id.clone()),
     };
 
-    let state_clone = state.model_state.clone();
-
-    tauri::async_runtime::spawn_blocking(move || {
-        if let Err(e) = generate_stream_with_backend(state_clone, gen_req, backend) {
-            log::error!("Generation failed: {}", e);
-        }
-    });
+    spawn_generation(&state, gen_req, backend);
 
     let stream = stream::unfold(
         (rx, id, model_id, false, false),
@@ -1031,6 +1397,11 @@ async fn create_legacy_completion_stream(
             }
 
             match rx.recv().await {
+                // Ошибка посреди потока: объект ошибки OpenAI, затем [DONE]
+                Some(GenerationEvent::Error(message)) => Some((
+                    Ok(stream_error_event(&message)),
+                    (rx, id, model_id, true, done_sent),
+                )),
                 Some(event) => {
                     let chunk = match event {
                         GenerationEvent::Token(t) => CompletionResponse {
@@ -1130,6 +1501,25 @@ fn server_error(msg: &str) -> (StatusCode, Json<ErrorResponse>) {
     )
 }
 
+/// SSE-событие с ошибкой генерации в формате ответа об ошибке OpenAI
+fn stream_error_event(msg: &str) -> Event {
+    let (_, Json(body)) = server_error(msg);
+    Event::default().data(serde_json::to_string(&body).unwrap_or_default())
+}
+
+fn invalid_request(msg: &str) -> (StatusCode, Json<ErrorResponse>) {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(ErrorResponse {
+            error: ApiError {
+                message: msg.into(),
+                error_type: "invalid_request_error".into(),
+                code: None,
+            },
+        }),
+    )
+}
+
 fn now_unix() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -1167,6 +1557,15 @@ pub fn create_router(state: Arc<OpenAIServerState>) -> Router {
         .route("/v1/chat/completions", post(chat_completions_handler))
         .route("/v1/completions", post(completions_handler))
         .route("/v1/embeddings", post(embeddings_handler))
+        .route("/v1/rerank", post(rerank_handler))
+        .route(
+            "/v1/audio/transcriptions",
+            post(audio_transcriptions_handler).layer(DefaultBodyLimit::max(MAX_AUDIO_UPLOAD_BYTES)),
+        )
+        .route(
+            "/v1/audio/translations",
+            post(audio_translations_handler).layer(DefaultBodyLimit::max(MAX_AUDIO_UPLOAD_BYTES)),
+        )
         .layer(cors)
         .with_state(state)
 }
@@ -1177,16 +1576,28 @@ pub fn create_router(state: Arc<OpenAIServerState>) -> Router {
 
 pub async fn start_server(
     model_state: SharedState,
+    app: tauri::AppHandle,
     port: u16,
 ) -> Result<broadcast::Sender<()>, std::io::Error> {
     let (shutdown_tx, _) = broadcast::channel::<()>(1);
 
+    // Continuous batching: параллельные клиенты делят один forward-проход
+    let batch_engine = match BatchEngine::spawn(model_state.clone(), BatchConfig::default()) {
+        Ok(engine) => Some(engine),
+        Err(e) => {
+            log::warn!("Batch engine disabled: {}", e);
+            None
+        }
+    };
+
     let state = Arc::new(OpenAIServerState {
         model_state,
         shutdown_tx: shutdown_tx.clone(),
+        batch_engine,
+        app,
     });
 
-    let app = create_router(state);
+    let router = create_router(state);
     let addr = SocketAddr::from(([127, 0, 0, 1], port));
 
     log::info!("OpenAI API server starting on http://{}", addr);
@@ -1196,7 +1607,7 @@ pub async fn start_server(
     let shutdown_rx = shutdown_tx.subscribe();
 
     tokio::spawn(async move {
-        axum::serve(listener, app)
+        axum::serve(listener, router)
             .with_graceful_shutdown(async move {
                 let mut rx = shutdown_rx;
                 let _ = rx.recv().await;
@@ -11,6 +11,8 @@ pub struct PrefixCacheInfo {
     pub enabled: bool,
     /// Максимальное число записей
     pub max_entries: usize,
+    /// Сохраняются ли длинные промпты на диск
+    pub disk_tier: bool,
     /// Текущая статистика
     pub stats: PrefixCacheStatsDto,
 }
@@ -22,6 +24,9 @@ pub struct PrefixCacheStatsDto {
     pub misses: u64,
     pub evictions: u64,
     pub entries: usize,
+    pub disk_hits: u64,
+    pub disk_writes: u64,
+    pub disk_entries: usize,
 }
 
 impl From<PrefixCacheStats> for PrefixCacheStatsDto {
@@ -31,6 +36,9 @@ impl From<PrefixCacheStats> for PrefixCacheStatsDto {
             misses: s.misses,
             evictions: s.evictions,
             entries: s.entries,
+            disk_hits: s.disk_hits,
+            disk_writes: s.disk_writes,
+            disk_entries: s.disk_entries,
         }
     }
 }
@@ -47,26 +55,35 @@ pub fn get_prefix_cache_info(
     Ok(PrefixCacheInfo {
         enabled,
         max_entries: if enabled { 32 } else { 0 }, // TODO: expose from config
+        disk_tier: guard.prefix_cache.config().disk_tier,
         stats: stats.into(),
     })
 }
 
 /// Включить/выключить Prefix Cache
+///
+/// `disk_tier` включает сохранение длинных промптов на диск; без него
+/// настройка остаётся прежней.
 #[tauri::command]
 pub fn set_prefix_cache_enabled(
     state: tauri::State<'_, SharedState>,
     enabled: bool,
     max_entries: Option<usize>,
+    disk_tier: Option<bool>,
 ) -> Result<(), String> {
     let mut guard = state.lock().map_err(|e| e.to_string())?;
 
-    let config = if enabled {
+    let mut config = if enabled {
         PrefixCacheConfig::enabled(max_entries.unwrap_or(32))
     } else {
         PrefixCacheConfig::disabled()
     };
+    config.disk_tier = disk_tier.unwrap_or(guard.prefix_cache.config().disk_tier);
 
+    // Каталог disk tier привязан к загруженной модели, а не к настройке
+    let disk_dir = guard.prefix_cache.disk_dir().map(|p| p.to_path_buf());
     guard.prefix_cache = crate::core::prefix_cache::PrefixCache::new(config);
+    guard.prefix_cache.set_disk_dir(disk_dir);
     Ok(())
 }
 
@@ -118,9 +118,11 @@ pub fn run() {
             get_app_info,
             crate::api::load_model,
             crate::api::unload_model,
+            crate::api::set_lora_adapters,
             crate::api::cancel_model_loading,
             crate::api::generate_stream,
             crate::api::cancel_generation,
+            crate::api::cancel_generation_request,
             crate::api::set_device,
             crate::api::is_model_loaded,
             crate::api::get_chat_template,
@@ -169,12 +171,22 @@ pub fn run() {
             crate::api::download_manager::cancel_download,
             crate::api::download_manager::remove_download_entry,
             crate::api::download_manager::clear_download_history,
+            crate::api::gguf_convert::convert_safetensors_to_gguf,
             crate::api::get_locale,
             crate::api::set_locale,
             crate::api::openai_server::get_server_config,
             crate::api::prefix_cache_api::get_prefix_cache_info,
             crate::api::prefix_cache_api::set_prefix_cache_enabled,
             crate::api::prefix_cache_api::clear_prefix_cache,
+            crate::api::save_kv_session,
+            crate::api::restore_kv_session,
+            crate::api::list_kv_sessions,
+            crate::api::delete_kv_session,
+            crate::api::get_embeddings,
+            crate::api::load_encoder_model,
+            crate::api::unload_encoder_model,
+            crate::api::get_encoder_models,
+            crate::api::rerank,
         ])
         .setup(move |app| {
             // Hybrid responsiveness: keep the window/event-loop thread slightly prioritized on Windows,
@@ -231,9 +243,12 @@ pub fn run() {
 
             // Start OpenAI-compatible API server
             let openai_state = shared.clone();
+            let openai_app = app.handle().clone();
             tauri::async_runtime::spawn(async move {
                 use crate::api::openai_server::OPENAI_PORT;
-                match crate::api::openai_server::start_server(openai_state, OPENAI_PORT).await {
+                match crate::api::openai_server::start_server(openai_state, openai_app, OPENAI_PORT)
+                    .await
+                {
                     Ok(_shutdown_tx) => {
                         log::info!("OpenAI API server started on port {}", OPENAI_PORT);
                     }
@@ -0,0 +1,140 @@
+//! Декодирование аудиофайлов (WAV/MP3/FLAC/OGG) в моно PCM для Whisper
+
+use std::io::Cursor;
+
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{CODEC_TYPE_NULL, DecoderOptions};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+use crate::core::audio_capture::resample_linear;
+
+/// Частота дискретизации, которую ожидает Whisper
+pub const WHISPER_SAMPLE_RATE: u32 = 16_000;
+
+/// Декодирует файл в моно f32 с частотой [`WHISPER_SAMPLE_RATE`]
+///
+/// `extension` (из имени загруженного файла) подсказывает формат; без него
+/// формат определяется по содержимому.
+pub fn decode_audio(bytes: Vec<u8>, extension: Option<&str>) -> Result<Vec<f32>, String> {
+    let stream = MediaSourceStream::new(Box::new(Cursor::new(bytes)), Default::default());
+    let mut hint = Hint::new();
+    if let Some(ext) = extension {
+        hint.with_extension(ext);
+    }
+    let probed = symphonia::default::get_probe()
+        .format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .map_err(|e| format!("Unsupported or corrupt audio file: {e}"))?;
+    let mut format = probed.format;
+
+    let track = format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or_else(|| "Audio file has no decodable track".to_string())?;
+    let track_id = track.id;
+    let sample_rate = track
+        .codec_params
+        .sample_rate
+        .ok_or_else(|| "Audio track has no sample rate".to_string())?;
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .map_err(|e| format!("Unsupported audio codec: {e}"))?;
+
+    let mut mono = Vec::new();
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            // Конец потока symphonia сообщает ошибкой ввода-вывода
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                break;
+            }
+            Err(SymphoniaError::ResetRequired) => break,
+            Err(e) => return Err(format!("Failed to read audio packet: {e}")),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // Битый пакет пропускаем, как делают плееры
+            Err(SymphoniaError::DecodeError(e)) => {
+                log::warn!("Skipping undecodable audio packet: {e}");
+                continue;
+            }
+            Err(e) => return Err(format!("Failed to decode audio: {e}")),
+        };
+        let spec = *decoded.spec();
+        let channels = spec.channels.count().max(1);
+        let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+        buffer.copy_interleaved_ref(decoded);
+        mono.extend(
+            buffer
+                .samples()
+                .chunks(channels)
+                .map(|frame| frame.iter().sum::<f32>() / channels as f32),
+        );
+    }
+
+    if mono.is_empty() {
+        return Err("Audio file contains no samples".to_string());
+    }
+    Ok(resample_linear(&mono, sample_rate, WHISPER_SAMPLE_RATE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 16-битный PCM WAV с чередующимися каналами
+    fn wav(sample_rate: u32, channels: u16, frames: &[&[i16]]) -> Vec<u8> {
+        let data_len = (frames.len() * channels as usize * 2) as u32;
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&(36 + data_len).to_le_bytes());
+        bytes.extend_from_slice(b"WAVEfmt ");
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&channels.to_le_bytes());
+        bytes.extend_from_slice(&sample_rate.to_le_bytes());
+        bytes.extend_from_slice(&(sample_rate * channels as u32 * 2).to_le_bytes());
+        bytes.extend_from_slice(&(channels * 2).to_le_bytes());
+        bytes.extend_from_slice(&16u16.to_le_bytes());
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&data_len.to_le_bytes());
+        for frame in frames {
+            for sample in *frame {
+                bytes.extend_from_slice(&sample.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn stereo_wav_is_downmixed_and_resampled() {
+        // 0.1 с при 44.1 кГц: левый канал 0.5, правый -0.25
+        let frames = vec![[16384i16, -8192].as_slice(); 4410];
+        let samples = decode_audio(wav(44_100, 2, &frames), Some("wav")).unwrap();
+        assert_eq!(samples.len(), 1600);
+        assert!(
+            samples.iter().all(|s| (s - 0.125).abs() < 1e-6),
+            "{samples:?}"
+        );
+    }
+
+    #[test]
+    fn empty_and_corrupt_files_are_rejected() {
+        assert!(decode_audio(Vec::new(), Some("wav")).is_err());
+        assert!(decode_audio(b"definitely not an audio file".repeat(64), None).is_err());
+        // Заголовок без единого отсчёта
+        assert!(decode_audio(wav(44_100, 2, &[]), Some("wav")).is_err());
+    }
+}
@@ -0,0 +1,518 @@
+//! Сессии KV-кэша на диске (аналог `--prompt-cache` / slot save в llama.cpp)
+//!
+//! Файл сессии — safetensors со следующими тензорами:
+//!
+//! - `layers.{i}.k` / `layers.{i}.v` — KV-кэш слоя для токенов промпта
+//! - `state.{i}` — рекуррентное состояние (Mamba и т.п.) вместо KV-кэша
+//! - `tokens` (U32) — токены, которым соответствует кэш
+//! - `fingerprint` (U8) — UTF-8 отпечаток модели
+//!
+//! Если сессия снята с квантованного KV-кэша (Q8/Q4), `layers.{i}.k` / `layers.{i}.v`
+//! хранят сырые GGML-блоки (U8), форма лежит в `layers.{i}.k.shape` (U32),
+//! а `kv_cache_type` и `kv_dtype` (U8, UTF-8) — формат блоков и dtype модели.
+//!
+//! Восстановление сверяет отпечаток: кэш другой модели (или той же модели
+//! с другой точностью весов) не будет загружен.
+
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+use candle::quantized::QTensor;
+use candle::quantized::ggml_file::qtensor_from_ggml;
+use candle::{DType, Device, Tensor};
+
+use crate::core::state::ModelState;
+use crate::models::ModelBackend;
+use crate::models::common::{KvCacheType, LayerKv, RecurrentState};
+
+/// Расширение файлов сессий
+pub const SESSION_EXTENSION: &str = "kvsession";
+
+const TOKENS_KEY: &str = "tokens";
+const FINGERPRINT_KEY: &str = "fingerprint";
+const CACHE_TYPE_KEY: &str = "kv_cache_type";
+const KV_DTYPE_KEY: &str = "kv_dtype";
+
+/// Сохранённое состояние промпта: KV-кэш, токены и отпечаток модели
+///
+/// Для рекуррентных моделей `layers` пуст, а `state` содержит состояние после
+/// обработки ровно `tokens`.
+#[derive(Clone)]
+pub struct KvSession {
+    pub fingerprint: String,
+    pub tokens: Vec<u32>,
+    pub layers: Vec<LayerKv>,
+    pub state: Vec<Tensor>,
+    /// Формат хранения KV в файле (квантованный — как у кэша модели)
+    pub cache_type: KvCacheType,
+}
+
+impl KvSession {
+    /// Снимок первых `tokens.len()` позиций активного KV-кэша модели
+    ///
+    /// Рекуррентное состояние нельзя укоротить, поэтому у таких моделей оно
+    /// должно отражать ровно `tokens`.
+    pub fn capture(
+        model: &dyn ModelBackend,
+        fingerprint: String,
+        tokens: &[u32],
+    ) -> Result<Self, String> {
+        if tokens.is_empty() {
+            return Err("KV session: no prompt tokens in the KV cache".into());
+        }
+        if model.is_recurrent() {
+            let state = model
+                .export_state()
+                .map_err(|e| format!("KV session: {e}"))?;
+            if state.seq_len != tokens.len() {
+                return Err(format!(
+                    "KV session: recurrent state covers {} tokens, expected {}",
+                    state.seq_len,
+                    tokens.len()
+                ));
+            }
+            return Ok(Self {
+                fingerprint,
+                tokens: tokens.to_vec(),
+                layers: Vec::new(),
+                state: state.tensors,
+                cache_type: KvCacheType::Full,
+            });
+        }
+        if !model.supports_kv_snapshot() {
+            return Err(format!(
+                "KV session: snapshots are not supported for model type '{}'",
+                model.model_type()
+            ));
+        }
+        let layers = model
+            .export_kv_cache()
+            .map_err(|e| e.to_string())?
+            .into_iter()
+            .map(|(k, v)| {
+                let len = k.dim(2)?;
+                if len < tokens.len() {
+                    candle::bail!(
+                        "KV cache holds {} positions, expected at least {}",
+                        len,
+                        tokens.len()
+                    );
+                }
+                Ok((k.narrow(2, 0, tokens.len())?, v.narrow(2, 0, tokens.len())?))
+            })
+            .collect::<candle::Result<Vec<_>>>()
+            .map_err(|e| format!("KV session: {e}"))?;
+        Ok(Self {
+            fingerprint,
+            tokens: tokens.to_vec(),
+            layers,
+            state: Vec::new(),
+            cache_type: KvCacheType::Full,
+        })
+    }
+
+    /// Хранить KV в файле в формате `cache_type` (формат KV-кэша модели)
+    pub fn with_cache_type(mut self, cache_type: KvCacheType) -> Self {
+        self.cache_type = cache_type;
+        self
+    }
+
+    /// Сессия рекуррентной модели (состояние вместо KV-кэша)
+    pub fn is_recurrent(&self) -> bool {
+        !self.state.is_empty()
+    }
+
+    /// Оставляет только первые `len` позиций
+    ///
+    /// Рекуррентное состояние укоротить нельзя — для таких сессий это ошибка.
+    pub fn truncate(&mut self, len: usize) -> Result<(), String> {
+        if len >= self.tokens.len() {
+            return Ok(());
+        }
+        if self.is_recurrent() {
+            return Err("KV session: recurrent state cannot be truncated".into());
+        }
+        self.tokens.truncate(len);
+        for (k, v) in self.layers.iter_mut() {
+            *k = k.narrow(2, 0, len).map_err(|e| e.to_string())?;
+            *v = v.narrow(2, 0, len).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Записывает сессию в файл (через временный файл, чтобы не оставить битую сессию)
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| format!("Failed to create KV session directory: {e}"))?;
+        }
+        let mut tensors: HashMap<String, Tensor> = HashMap::new();
+        let quantized = self.quantized_layout();
+        for (i, (k, v)) in self.layers.iter().enumerate() {
+            for (name, t) in [(format!("layers.{i}.k"), k), (format!("layers.{i}.v"), v)] {
+                let t = t.contiguous().map_err(|e| e.to_string())?;
+                match quantized {
+                    Some(ggml_dtype) => {
+                        let q = QTensor::quantize(&t, ggml_dtype).map_err(|e| e.to_string())?;
+                        let data = q.data().map_err(|e| e.to_string())?;
+                        let dims: Vec<u32> = t.dims().iter().map(|&d| d as u32).collect();
+                        tensors.insert(
+                            format!("{name}.shape"),
+                            Tensor::new(dims.as_slice(), &Device::Cpu)
+                                .map_err(|e| e.to_string())?,
+                        );
+                        tensors.insert(
+                            name,
+                            Tensor::new(data.as_ref(), &Device::Cpu).map_err(|e| e.to_string())?,
+                        );
+                    }
+                    None => {
+                        tensors.insert(name, t);
+                    }
+                }
+            }
+        }
+        if quantized.is_some()
+            && let Some((k, _)) = self.layers.first()
+        {
+            tensors.insert(
+                CACHE_TYPE_KEY.into(),
+                Tensor::new(self.cache_type.as_str().as_bytes(), &Device::Cpu)
+                    .map_err(|e| e.to_string())?,
+            );
+            tensors.insert(
+                KV_DTYPE_KEY.into(),
+                Tensor::new(k.dtype().as_str().as_bytes(), &Device::Cpu)
+                    .map_err(|e| e.to_string())?,
+            );
+        }
+        for (i, t) in self.state.iter().enumerate() {
+            tensors.insert(
+                format!("state.{i}"),
+                t.contiguous().map_err(|e| e.to_string())?,
+            );
+        }
+        tensors.insert(
+            TOKENS_KEY.into(),
+            Tensor::new(self.tokens.as_slice(), &Device::Cpu).map_err(|e| e.to_string())?,
+        );
+        tensors.insert(
+            FINGERPRINT_KEY.into(),
+            Tensor::new(self.fingerprint.as_bytes(), &Device::Cpu).map_err(|e| e.to_string())?,
+        );
+
+        let tmp = path.with_extension(format!("{SESSION_EXTENSION}.tmp"));
+        candle::safetensors::save(&tensors, &tmp)
+            .map_err(|e| format!("Failed to write KV session: {e}"))?;
+        std::fs::rename(&tmp, path).map_err(|e| format!("Failed to write KV session: {e}"))
+    }
+
+    /// Читает сессию; KV-тензоры загружаются на `device`
+    pub fn load(path: &Path, device: &Device) -> Result<Self, String> {
+        let mut tensors = candle::safetensors::load(path, device)
+            .map_err(|e| format!("Failed to read KV session {}: {e}", path.display()))?;
+        let tokens = tensors
+            .remove(TOKENS_KEY)
+            .ok_or("KV session: missing tokens")?
+            .to_dtype(DType::U32)
+            .and_then(|t| t.to_vec1::<u32>())
+            .map_err(|e| e.to_string())?;
+        let fingerprint = tensors
+            .remove(FINGERPRINT_KEY)
+            .ok_or("KV session: missing fingerprint")?
+            .to_vec1::<u8>()
+            .map_err(|e| e.to_string())?;
+        let fingerprint = String::from_utf8(fingerprint).map_err(|e| e.to_string())?;
+        let cache_type = match take_string(&mut tensors, CACHE_TYPE_KEY)? {
+            Some(name) => KvCacheType::from_name(&name)
+                .ok_or_else(|| format!("KV session: unknown KV cache type '{name}'"))?,
+            None => KvCacheType::Full,
+        };
+        let kv_dtype = match take_string(&mut tensors, KV_DTYPE_KEY)? {
+            Some(name) => name
+                .parse::<DType>()
+                .map_err(|e| format!("KV session: {e}"))?,
+            None => DType::F32,
+        };
+
+        let mut layers = Vec::new();
+        while let (Some(k), Some(v)) = (
+            tensors.remove(&format!("layers.{}.k", layers.len())),
+            tensors.remove(&format!("layers.{}.v", layers.len())),
+        ) {
+            let i = layers.len();
+            let (k, v) = match cache_type.ggml_dtype() {
+                None => (k, v),
+                Some(ggml_dtype) => {
+                    let mut dequantize = |name: String, data: Tensor| {
+                        let dims = tensors
+                            .remove(&format!("{name}.shape"))
+                            .ok_or_else(|| format!("KV session: missing {name}.shape"))?
+                            .to_dtype(DType::U32)
+                            .and_then(|t| t.to_vec1::<u32>())
+                            .map_err(|e| e.to_string())?;
+                        let data = data.to_vec1::<u8>().map_err(|e| e.to_string())?;
+                        let dims = dims.into_iter().map(|d| d as usize).collect();
+                        qtensor_from_ggml(ggml_dtype, &data, dims, device)
+                            .and_then(|q| q.dequantize(device))
+                            .and_then(|t| t.to_dtype(kv_dtype))
+                            .map_err(|e| format!("KV session: {e}"))
+                    };
+                    (
+                        dequantize(format!("layers.{i}.k"), k)?,
+                        dequantize(format!("layers.{i}.v"), v)?,
+                    )
+                }
+            };
+            layers.push((k, v));
+        }
+        let mut state = Vec::new();
+        while let Some(t) = tensors.remove(&format!("state.{}", state.len())) {
+            state.push(t);
+        }
+        if layers.is_empty() && state.is_empty() {
+            return Err("KV session: no KV layers".into());
+        }
+        Ok(Self {
+            fingerprint,
+            tokens,
+            layers,
+            state,
+            cache_type,
+        })
+    }
+
+    /// Тип GGML-блоков для записи KV, если кэш квантованный и головы делятся на блоки
+    fn quantized_layout(&self) -> Option<candle::quantized::GgmlDType> {
+        let ggml_dtype = self.cache_type.ggml_dtype()?;
+        let head_dims_fit = self.layers.iter().all(|(k, v)| {
+            [k, v].into_iter().all(|t| {
+                t.dims()
+                    .last()
+                    .is_some_and(|&d| self.cache_type.supports_head_dim(d))
+            })
+        });
+        head_dims_fit.then_some(ggml_dtype)
+    }
+
+    /// Загружает KV-кэш сессии в модель, если отпечаток совпадает
+    pub fn restore(&self, model: &mut dyn ModelBackend, fingerprint: &str) -> Result<(), String> {
+        if self.fingerprint != fingerprint {
+            return Err("KV session was saved for a different model".into());
+        }
+        if self.is_recurrent() {
+            let state = RecurrentState {
+                seq_len: self.tokens.len(),
+                tensors: self.state.clone(),
+            };
+            return model
+                .import_state(&state)
+                .map_err(|e| format!("KV session: {e}"));
+        }
+        if !model.supports_kv_snapshot() {
+            return Err(format!(
+                "KV session: snapshots are not supported for model type '{}'",
+                model.model_type()
+            ));
+        }
+        model
+            .import_kv_cache(&self.layers)
+            .map_err(|e| format!("KV session: {e}"))
+    }
+}
+
+/// Забирает из файла сессии строковое поле (UTF-8 в U8-тензоре)
+fn take_string(tensors: &mut HashMap<String, Tensor>, key: &str) -> Result<Option<String>, String> {
+    let Some(bytes) = tensors.remove(key) else {
+        return Ok(None);
+    };
+    let bytes = bytes.to_vec1::<u8>().map_err(|e| e.to_string())?;
+    String::from_utf8(bytes)
+        .map(Some)
+        .map_err(|e| format!("KV session: {key}: {e}"))
+}
+
+/// Отпечаток загруженной модели: источник весов, размер и время изменения файла,
+/// архитектура, политика точности (от неё зависит dtype KV-кэша) и RoPE scaling
+pub fn model_fingerprint(state: &ModelState) -> Option<String> {
+    let model_id = state.scheduler.get_model_id()?;
+    let file_stamp = state
+        .model_path
+        .as_deref()
+        .and_then(|p| std::fs::metadata(p).ok())
+        .map(|m| {
+            let modified = m
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_secs());
+            format!("{}@{}", m.len(), modified)
+        })
+        .unwrap_or_default();
+    Some(format!(
+        "{}|{}|{}|{}|{}|{}|{:?}|{:?}|{:?}|{:?}|{:?}",
+        model_id,
+        state.model_path.as_deref().unwrap_or_default(),
+        file_stamp,
+        state.hub_repo_id.as_deref().unwrap_or_default(),
+        state.hub_revision.as_deref().unwrap_or_default(),
+        state
+            .safetensors_files
+            .as_ref()
+            .map(|files| files.join(","))
+            .unwrap_or_default(),
+        state.arch,
+        state.precision_policy,
+        state.quantize,
+        // Ключи в KV-кэше повёрнуты RoPE с этим масштабированием
+        state.rope_scaling,
+        // LoRA меняет активации, а значит и содержимое KV-кэша
+        state.lora_adapters,
+    ))
+}
+
+/// Каталог disk tier prefix cache для модели с отпечатком `fingerprint`
+pub fn prompt_cache_dir(sessions_dir: &Path, fingerprint: &str) -> PathBuf {
+    let mut hasher = DefaultHasher::new();
+    fingerprint.hash(&mut hasher);
+    sessions_dir
+        .join("prompt_cache")
+        .join(format!("{:016x}", hasher.finish()))
+}
+
+/// Путь к именованной сессии в каталоге `dir`
+///
+/// Имя очищается от разделителей путей, чтобы сессия не вышла за пределы каталога.
+pub fn session_path(dir: &Path, name: &str) -> Result<PathBuf, String> {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let name = name.trim_matches('.');
+    if name.is_empty() {
+        return Err("KV session name is empty".into());
+    }
+    Ok(dir.join(format!("{name}.{SESSION_EXTENSION}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_round_trip_and_truncate() {
+        let dev = Device::Cpu;
+        let k = Tensor::arange(0f32, 48., &dev)
+            .unwrap()
+            .reshape((1, 2, 6, 4))
+            .unwrap();
+        let v = (&k + 1.).unwrap();
+        let session = KvSession {
+            fingerprint: "model|fp".into(),
+            tokens: vec![1, 2, 3, 4, 5, 6],
+            layers: vec![(k.clone(), v.clone()), (v, k)],
+            state: Vec::new(),
+            cache_type: KvCacheType::Full,
+        };
+        let path = std::env::temp_dir().join(format!(
+            "oxide-kv-session-test-{}.{SESSION_EXTENSION}",
+            std::process::id()
+        ));
+        session.save(&path).unwrap();
+        let mut loaded = KvSession::load(&path, &dev).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(loaded.fingerprint, "model|fp");
+        assert_eq!(loaded.tokens, session.tokens);
+        assert_eq!(loaded.layers.len(), 2);
+        assert_eq!(loaded.layers[1].0.dims(), &[1, 2, 6, 4]);
+
+        loaded.truncate(4).unwrap();
+        assert_eq!(loaded.tokens, vec![1, 2, 3, 4]);
+        assert_eq!(loaded.layers[0].1.dims(), &[1, 2, 4, 4]);
+    }
+
+    #[test]
+    fn recurrent_session_round_trip() {
+        let dev = Device::Cpu;
+        let conv = Tensor::arange(0f32, 12., &dev)
+            .unwrap()
+            .reshape((1, 3, 4))
+            .unwrap();
+        let ssm = Tensor::ones((1, 4, 2), DType::F32, &dev).unwrap();
+        let mut session = KvSession {
+            fingerprint: "mamba|fp".into(),
+            tokens: vec![7, 8, 9],
+            layers: Vec::new(),
+            state: vec![conv, ssm],
+            cache_type: KvCacheType::Full,
+        };
+        let path = std::env::temp_dir().join(format!(
+            "oxide-kv-session-recurrent-test-{}.{SESSION_EXTENSION}",
+            std::process::id()
+        ));
+        session.save(&path).unwrap();
+        let loaded = KvSession::load(&path, &dev).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert!(loaded.is_recurrent());
+        assert!(loaded.layers.is_empty());
+        assert_eq!(loaded.state[0].dims(), &[1, 3, 4]);
+        assert_eq!(loaded.state[1].dims(), &[1, 4, 2]);
+
+        // Состояние после трёх токенов нельзя откатить к двум
+        assert!(session.truncate(3).is_ok());
+        assert!(session.truncate(2).is_err());
+    }
+
+    #[test]
+    fn quantized_session_keeps_cache_format() {
+        let dev = Device::Cpu;
+        let k = Tensor::randn(0f32, 1., (1, 2, 64, 32), &dev).unwrap();
+        let v = Tensor::randn(0f32, 1., (1, 2, 64, 32), &dev).unwrap();
+        let session = KvSession {
+            fingerprint: "model|fp".into(),
+            tokens: (0..64).collect(),
+            layers: vec![(k.clone(), v.clone())],
+            state: Vec::new(),
+            cache_type: KvCacheType::Full,
+        }
+        .with_cache_type(KvCacheType::Q8);
+        let path = std::env::temp_dir().join(format!(
+            "oxide-kv-session-q8-test-{}.{SESSION_EXTENSION}",
+            std::process::id()
+        ));
+        session.save(&path).unwrap();
+        let file_size = std::fs::metadata(&path).unwrap().len();
+        let loaded = KvSession::load(&path, &dev).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        // Q8_0: 34 байта на 32 элемента против 128 байт F32
+        assert!(file_size < 2 * 2 * 64 * 32 * 4 / 2);
+        assert_eq!(loaded.cache_type, KvCacheType::Q8);
+        assert_eq!(loaded.layers[0].0.dims(), &[1, 2, 64, 32]);
+        assert_eq!(loaded.layers[0].0.dtype(), DType::F32);
+        let diff = (&loaded.layers[0].1 - &v)
+            .and_then(|d| d.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>())
+            .unwrap();
+        assert!(diff < 0.05);
+    }
+
+    #[test]
+    fn session_path_stays_in_directory() {
+        let dir = Path::new("sessions");
+        assert_eq!(
+            session_path(dir, "../../etc/passwd").unwrap(),
+            dir.join("_.._etc_passwd.kvsession")
+        );
+        assert!(session_path(dir, "..").is_err());
+    }
+}
@@ -1,6 +1,8 @@
 pub mod audio_capture;
+pub mod audio_decode;
 pub mod config;
 pub mod device;
+pub mod kv_session;
 pub mod log;
 pub mod performance;
 pub mod precision;
@@ -11,6 +13,7 @@ pub mod state;
 pub mod stt_whisper;
 pub mod token_output_stream;
 pub mod tokenizer;
+pub mod transcript;
 pub mod types;
 pub mod weights;
 // Убрали мультимодальность: vision/audio/multimodal/attachments/attachment_router удалены
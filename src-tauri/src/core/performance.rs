@@ -1,4 +1,5 @@
 // Модуль для мониторинга производительности
+use crate::generate::prompt_lookup::SpeculativeStats;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
@@ -24,6 +25,9 @@ pub struct ModelLoadMetrics {
     pub memory_before_mb: f64,
     pub memory_after_mb: f64,
     pub memory_delta_mb: f64,
+    /// Память, сэкономленная квантованием весов при загрузке
+    #[serde(default)]
+    pub memory_saved_mb: f64,
 }
 
 /// Стадия загрузки модели
@@ -45,6 +49,9 @@ pub struct InferenceMetrics {
     pub prefill_tokens_per_second: f64,
     pub memory_usage_mb: f64,
     pub timestamp: String,
+    /// Статистика prompt lookup decoding (если был включён)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speculative: Option<SpeculativeStats>,
 }
 
 /// Метрики запуска приложения
@@ -292,6 +299,7 @@ pub struct ModelLoadTracker {
     start: Instant,
     stages: Vec<(String, Instant)>,
     memory_before_mb: f64,
+    memory_saved_mb: f64,
     monitor: Arc<PerformanceMonitor>,
 }
 
@@ -304,6 +312,7 @@ impl ModelLoadTracker {
             start: Instant::now(),
             stages: Vec::new(),
             memory_before_mb: memory_before,
+            memory_saved_mb: 0.0,
             monitor,
         }
     }
@@ -313,6 +322,11 @@ impl ModelLoadTracker {
         self.stages.push((stage_name.into(), Instant::now()));
     }
 
+    /// Учесть память, сэкономленную квантованием весов
+    pub fn record_memory_saved(&mut self, saved_mb: f64) {
+        self.memory_saved_mb += saved_mb;
+    }
+
     /// Завершить трекинг и вернуть метрики
     pub async fn finish(self, model_size_mb: f64) -> ModelLoadMetrics {
         let total_duration_ms = self.start.elapsed().as_millis() as u64;
@@ -338,6 +352,7 @@ impl ModelLoadTracker {
             memory_before_mb: self.memory_before_mb,
             memory_after_mb,
             memory_delta_mb,
+            memory_saved_mb: self.memory_saved_mb,
         }
     }
 }
@@ -349,6 +364,7 @@ pub struct InferenceTracker {
     generation_start: Option<Instant>,
     prompt_tokens: usize,
     generated_tokens: usize,
+    speculative: Option<SpeculativeStats>,
     monitor: Arc<PerformanceMonitor>,
 }
 
@@ -361,6 +377,7 @@ impl InferenceTracker {
             generation_start: None,
             prompt_tokens,
             generated_tokens: 0,
+            speculative: None,
             monitor,
         }
     }
@@ -380,6 +397,11 @@ impl InferenceTracker {
         self.generated_tokens += 1;
     }
 
+    /// Сохранить статистику prompt lookup decoding
+    pub fn set_speculative_stats(&mut self, stats: SpeculativeStats) {
+        self.speculative = Some(stats);
+    }
+
     /// Завершить трекинг и вернуть метрики
     pub async fn finish(self) -> InferenceMetrics {
         let total_duration_ms = self.start.elapsed().as_millis() as u64;
@@ -424,6 +446,7 @@ impl InferenceTracker {
             prefill_tokens_per_second,
             memory_usage_mb,
             timestamp: chrono::Utc::now().to_rfc3339(),
+            speculative: self.speculative,
         }
     }
 }
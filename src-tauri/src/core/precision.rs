@@ -4,6 +4,7 @@
 //! based on the target device and user preferences. The policy helps ensure
 //! consistent memory usage and performance across different hardware platforms.
 
+use candle::quantized::GgmlDType;
 use candle::{DType, Device};
 use serde::{Deserialize, Serialize};
 
@@ -16,6 +17,38 @@ pub enum PrecisionPolicy {
     MemoryEfficient,
     /// Maximum precision policy (GPU=F32)
     MaximumPrecision,
+    /// SafeTensors weights are quantized at load time (activations as in Default)
+    Quantized(QuantizationTarget),
+}
+
+impl PrecisionPolicy {
+    /// Quantization target for load-time quantization, if any
+    pub fn quantization(&self) -> Option<QuantizationTarget> {
+        match self {
+            PrecisionPolicy::Quantized(target) => Some(*target),
+            _ => None,
+        }
+    }
+}
+
+/// Block format used when quantizing SafeTensors weights at load time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QuantizationTarget {
+    /// 8-bit blocks of 32 weights
+    #[serde(rename = "q8_0")]
+    Q8_0,
+    /// 4-bit k-quant super-blocks of 256 weights
+    #[serde(rename = "q4_k")]
+    Q4K,
+}
+
+impl QuantizationTarget {
+    pub fn ggml_dtype(self) -> GgmlDType {
+        match self {
+            QuantizationTarget::Q8_0 => GgmlDType::Q8_0,
+            QuantizationTarget::Q4K => GgmlDType::Q4K,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
@@ -135,7 +168,7 @@ pub fn select_dtype_default(device: &Device) -> DType {
 /// * `PrecisionConfig` - The corresponding precision configuration
 pub fn policy_to_config(policy: &PrecisionPolicy) -> PrecisionConfig {
     match policy {
-        PrecisionPolicy::Default => PrecisionConfig::default(),
+        PrecisionPolicy::Default | PrecisionPolicy::Quantized(_) => PrecisionConfig::default(),
         PrecisionPolicy::MemoryEfficient => PrecisionConfig::memory_efficient(),
         PrecisionPolicy::MaximumPrecision => PrecisionConfig::maximum_precision(),
     }
@@ -174,7 +207,7 @@ impl Default for GpuKernelConfig {
 impl GpuKernelConfig {
     pub fn from_policy(policy: &PrecisionPolicy) -> Self {
         match policy {
-            PrecisionPolicy::Default => Self {
+            PrecisionPolicy::Default | PrecisionPolicy::Quantized(_) => Self {
                 reduced_precision_f16: true,
                 reduced_precision_bf16: true,
                 force_dmmv: false,
@@ -232,6 +265,14 @@ mod tests {
         assert!(config.allow_override);
     }
 
+    #[test]
+    fn test_quantized_policy() {
+        let policy: PrecisionPolicy = serde_json::from_str(r#"{"Quantized":"q4_k"}"#).unwrap();
+        assert_eq!(policy.quantization(), Some(QuantizationTarget::Q4K));
+        assert_eq!(policy_to_config(&policy), PrecisionConfig::default());
+        assert_eq!(PrecisionPolicy::Default.quantization(), None);
+    }
+
     #[test]
     fn test_dtype_selection() {
         let cpu_device = Device::Cpu;
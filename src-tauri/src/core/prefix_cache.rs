@@ -8,7 +8,11 @@
 //!
 //! - **Без блоков**: кэшируем весь промпт целиком (не по блокам как в PagedAttention)
 //! - **LRU eviction**: по числу записей (не по памяти)
-//! - **In-memory only**: без персистентности между сессиями
+//! - **Disk tier (опционально, `disk_tier`)**: длинные промпты сохраняются файлами
+//!   сессий KV-кэша (`core::kv_session`) и переживают перезапуск приложения или
+//!   выгрузку модели; поиск по диску — по самому длинному сохранённому префиксу.
+//!   Файлы пишутся в фоне: путь резервируется `reserve_disk`, а после записи
+//!   регистрируется `register_disk`
 //!
 //! ## Пример использования
 //!
@@ -29,10 +33,13 @@
 //! assert_eq!(match_result.unwrap().kv_position, 5);
 //! ```
 
-use std::collections::HashMap;
 use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
-use std::time::Instant;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime};
+
+use crate::core::kv_session::SESSION_EXTENSION;
 
 /// Конфигурация Prefix Cache
 #[derive(Clone, Debug)]
@@ -41,6 +48,12 @@ pub struct PrefixCacheConfig {
     pub enabled: bool,
     /// Максимальное число записей в кэше
     pub max_entries: usize,
+    /// Сохранять ли длинные промпты на диск (выключено по умолчанию)
+    pub disk_tier: bool,
+    /// Минимальная длина промпта (в токенах) для сохранения на диск
+    pub disk_min_tokens: usize,
+    /// Максимальное число файлов на диске для одной модели
+    pub max_disk_entries: usize,
 }
 
 impl Default for PrefixCacheConfig {
@@ -48,6 +61,9 @@ impl Default for PrefixCacheConfig {
         Self {
             enabled: false,
             max_entries: 32,
+            disk_tier: false,
+            disk_min_tokens: 1024,
+            max_disk_entries: 16,
         }
     }
 }
@@ -58,6 +74,7 @@ impl PrefixCacheConfig {
         Self {
             enabled: true,
             max_entries,
+            ..Self::default()
         }
     }
 
@@ -78,6 +95,15 @@ pub struct PrefixMatch {
     pub tokens_hash: u64,
 }
 
+/// Совпадение с файлом сессии на диске
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiskPrefixMatch {
+    /// Файл сессии KV-кэша
+    pub path: PathBuf,
+    /// Длина сохранённого префикса (в токенах)
+    pub matched_tokens: usize,
+}
+
 /// Статистика кэша
 #[derive(Clone, Debug, Default)]
 pub struct PrefixCacheStats {
@@ -89,6 +115,12 @@ pub struct PrefixCacheStats {
     pub evictions: u64,
     /// Текущее число записей
     pub entries: usize,
+    /// Попадания в disk tier
+    pub disk_hits: u64,
+    /// Записанные файлы сессий
+    pub disk_writes: u64,
+    /// Текущее число файлов на диске
+    pub disk_entries: usize,
 }
 
 /// Внутренняя запись кэша
@@ -102,11 +134,25 @@ struct CacheEntry {
     last_access: Instant,
 }
 
+/// Запись disk tier: файл `{hash:016x}-{token_count}.kvsession`
+#[derive(Clone, Debug)]
+struct DiskEntry {
+    token_count: usize,
+    path: PathBuf,
+    saved_at: SystemTime,
+}
+
 /// Prefix Cache для переиспользования KV-кэшей
 pub struct PrefixCache {
     config: PrefixCacheConfig,
     /// Записи: hash токенов -> entry
     entries: HashMap<u64, CacheEntry>,
+    /// Каталог файлов сессий активной модели (None = disk tier выключен)
+    disk_dir: Option<PathBuf>,
+    /// Файлы на диске: hash префикса -> entry
+    disk_entries: HashMap<u64, DiskEntry>,
+    /// Промпты, файлы которых сейчас пишутся в фоне
+    disk_pending: HashSet<u64>,
     /// Статистика
     stats: PrefixCacheStats,
 }
@@ -117,10 +163,18 @@ impl PrefixCache {
         Self {
             config,
             entries: HashMap::new(),
+            disk_dir: None,
+            disk_entries: HashMap::new(),
+            disk_pending: HashSet::new(),
             stats: PrefixCacheStats::default(),
         }
     }
 
+    /// Конфигурация кэша
+    pub fn config(&self) -> &PrefixCacheConfig {
+        &self.config
+    }
+
     /// Проверяет, включён ли кэш
     pub fn enabled(&self) -> bool {
         self.config.enabled && self.config.max_entries > 0
@@ -130,6 +184,7 @@ impl PrefixCache {
     pub fn stats(&self) -> PrefixCacheStats {
         let mut stats = self.stats.clone();
         stats.entries = self.entries.len();
+        stats.disk_entries = self.disk_entries.len();
         stats
     }
 
@@ -165,6 +220,26 @@ impl PrefixCache {
         None
     }
 
+    /// Есть ли для `tokens` сохранённый префикс (в памяти или на диске)
+    ///
+    /// В отличие от `match_prefix` не трогает LRU и статистику.
+    pub fn has_reusable_prefix(&self, tokens: &[u32]) -> bool {
+        if !self.enabled() || tokens.is_empty() {
+            return false;
+        }
+        let in_memory = self
+            .entries
+            .get(&Self::hash_tokens(tokens))
+            .is_some_and(|entry| entry.token_count == tokens.len());
+        in_memory
+            || (self.disk_enabled()
+                && self.disk_entries.iter().any(|(&hash, entry)| {
+                    entry.token_count > 0
+                        && entry.token_count <= tokens.len()
+                        && Self::hash_tokens(&tokens[..entry.token_count]) == hash
+                }))
+    }
+
     /// Добавляет запись в кэш
     ///
     /// # Arguments
@@ -188,11 +263,168 @@ impl PrefixCache {
         self.entries.insert(hash, entry);
     }
 
-    /// Очищает весь кэш
+    /// Очищает in-memory записи (файлы на диске не зависят от состояния модели)
     pub fn clear(&mut self) {
         self.entries.clear();
     }
 
+    /// Подключает каталог disk tier и индексирует уже сохранённые файлы
+    ///
+    /// Каталог должен быть свой для каждой модели: файлы сопоставляются
+    /// только по токенам.
+    pub fn set_disk_dir(&mut self, dir: Option<PathBuf>) {
+        self.disk_entries.clear();
+        self.disk_pending.clear();
+        if let Some(dir) = &dir
+            && let Ok(read_dir) = std::fs::read_dir(dir)
+        {
+            for entry in read_dir.flatten() {
+                let path = entry.path();
+                let Some((hash, token_count)) = Self::parse_disk_name(&path) else {
+                    continue;
+                };
+                let saved_at = entry
+                    .metadata()
+                    .and_then(|m| m.modified())
+                    .unwrap_or(SystemTime::UNIX_EPOCH);
+                self.disk_entries.insert(
+                    hash,
+                    DiskEntry {
+                        token_count,
+                        path,
+                        saved_at,
+                    },
+                );
+            }
+        }
+        self.disk_dir = dir;
+    }
+
+    /// Каталог disk tier
+    pub fn disk_dir(&self) -> Option<&Path> {
+        self.disk_dir.as_deref()
+    }
+
+    /// Включён ли disk tier
+    pub fn disk_enabled(&self) -> bool {
+        self.enabled()
+            && self.config.disk_tier
+            && self.disk_dir.is_some()
+            && self.config.max_disk_entries > 0
+    }
+
+    /// Ищет на диске самый длинный сохранённый префикс `tokens`
+    ///
+    /// Совпадение по hash префикса; содержимое файла (токены и отпечаток модели)
+    /// проверяет вызывающая сторона после загрузки.
+    pub fn match_disk(&mut self, tokens: &[u32]) -> Option<DiskPrefixMatch> {
+        if !self.disk_enabled() {
+            return None;
+        }
+        let mut lengths: Vec<usize> = self
+            .disk_entries
+            .values()
+            .map(|e| e.token_count)
+            .filter(|&n| n > 0 && n <= tokens.len())
+            .collect();
+        lengths.sort_unstable_by(|a, b| b.cmp(a));
+        lengths.dedup();
+        for len in lengths {
+            let hash = Self::hash_tokens(&tokens[..len]);
+            if let Some(entry) = self.disk_entries.get(&hash)
+                && entry.token_count == len
+            {
+                self.stats.disk_hits += 1;
+                return Some(DiskPrefixMatch {
+                    path: entry.path.clone(),
+                    matched_tokens: len,
+                });
+            }
+        }
+        None
+    }
+
+    /// Путь для сохранения промпта на диск, если его стоит сохранять
+    ///
+    /// `None`, когда disk tier выключен, промпт короче `disk_min_tokens`,
+    /// уже сохранён или как раз записывается.
+    pub fn disk_path(&self, tokens: &[u32]) -> Option<PathBuf> {
+        if !self.disk_enabled() || tokens.len() < self.config.disk_min_tokens {
+            return None;
+        }
+        let hash = Self::hash_tokens(tokens);
+        if self.disk_pending.contains(&hash) {
+            return None;
+        }
+        if self
+            .disk_entries
+            .get(&hash)
+            .is_some_and(|e| e.token_count == tokens.len())
+        {
+            return None;
+        }
+        let dir = self.disk_dir.as_ref()?;
+        Some(dir.join(format!("{hash:016x}-{}.{SESSION_EXTENSION}", tokens.len())))
+    }
+
+    /// Резервирует путь для фоновой записи промпта (см. [`PrefixCache::disk_path`])
+    ///
+    /// После записи файл регистрируется `register_disk`, при ошибке — `release_disk`.
+    pub fn reserve_disk(&mut self, tokens: &[u32]) -> Option<PathBuf> {
+        let path = self.disk_path(tokens)?;
+        self.disk_pending.insert(Self::hash_tokens(tokens));
+        Some(path)
+    }
+
+    /// Снимает резерв неудавшейся записи
+    pub fn release_disk(&mut self, tokens: &[u32]) {
+        self.disk_pending.remove(&Self::hash_tokens(tokens));
+    }
+
+    /// Регистрирует записанный файл; при переполнении удаляет самый старый
+    pub fn register_disk(&mut self, tokens: &[u32], path: PathBuf) {
+        self.release_disk(tokens);
+        if self.disk_dir.as_deref() != path.parent() {
+            // Пока файл писался, подключили каталог другой модели: файл
+            // останется в своём каталоге и проиндексируется при её загрузке
+            return;
+        }
+        while self.disk_entries.len() >= self.config.max_disk_entries.max(1) {
+            let oldest = self
+                .disk_entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.saved_at)
+                .map(|(hash, _)| *hash);
+            let Some(hash) = oldest else { break };
+            if let Some(entry) = self.disk_entries.remove(&hash) {
+                let _ = std::fs::remove_file(&entry.path);
+            }
+        }
+        self.disk_entries.insert(
+            Self::hash_tokens(tokens),
+            DiskEntry {
+                token_count: tokens.len(),
+                path,
+                saved_at: SystemTime::now(),
+            },
+        );
+        self.stats.disk_writes += 1;
+    }
+
+    /// Забывает файл (например, повреждённый) и удаляет его с диска
+    pub fn remove_disk(&mut self, path: &Path) {
+        self.disk_entries.retain(|_, entry| entry.path != path);
+        let _ = std::fs::remove_file(path);
+    }
+
+    fn parse_disk_name(path: &Path) -> Option<(u64, usize)> {
+        if path.extension()? != SESSION_EXTENSION {
+            return None;
+        }
+        let (hash, count) = path.file_stem()?.to_str()?.split_once('-')?;
+        Some((u64::from_str_radix(hash, 16).ok()?, count.parse().ok()?))
+    }
+
     /// Вытесняет записи по LRU если кэш переполнен
     fn evict_if_needed(&mut self) {
         while self.entries.len() >= self.config.max_entries {
@@ -256,6 +488,19 @@ mod tests {
         assert_eq!(m.matched_tokens, 5);
     }
 
+    #[test]
+    fn test_has_reusable_prefix_leaves_stats_alone() {
+        let mut cache = PrefixCache::new(PrefixCacheConfig::enabled(32));
+        let tokens = vec![1u32, 2, 3];
+        assert!(!cache.has_reusable_prefix(&tokens));
+
+        cache.insert(&tokens, 3);
+        assert!(cache.has_reusable_prefix(&tokens));
+        assert!(!cache.has_reusable_prefix(&[1, 2]));
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (0, 0));
+    }
+
     #[test]
     fn test_prefix_cache_no_match_different_tokens() {
         let mut cache = PrefixCache::new(PrefixCacheConfig::enabled(32));
@@ -331,6 +576,61 @@ mod tests {
         assert!(cache.match_prefix(&tokens).is_none());
     }
 
+    #[test]
+    fn test_prefix_cache_disk_tier() {
+        let dir = std::env::temp_dir().join(format!("oxide-prefix-disk-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut cache = PrefixCache::new(PrefixCacheConfig {
+            disk_tier: true,
+            disk_min_tokens: 3,
+            max_disk_entries: 1,
+            ..PrefixCacheConfig::enabled(4)
+        });
+        assert!(cache.disk_path(&[1, 2, 3]).is_none());
+        cache.set_disk_dir(Some(dir.clone()));
+
+        // Короткие промпты на диск не пишутся
+        assert!(cache.disk_path(&[1, 2]).is_none());
+        let short = cache.reserve_disk(&[1, 2, 3]).unwrap();
+        // Пока файл пишется, второй раз путь не выдаётся
+        assert!(cache.reserve_disk(&[1, 2, 3]).is_none());
+        std::fs::write(&short, b"x").unwrap();
+        cache.register_disk(&[1, 2, 3], short.clone());
+        assert!(cache.disk_path(&[1, 2, 3]).is_none());
+
+        // Самый длинный сохранённый префикс
+        let m = cache.match_disk(&[1, 2, 3, 4, 5]).unwrap();
+        assert_eq!(m.matched_tokens, 3);
+        assert_eq!(m.path, short);
+        assert!(cache.match_disk(&[9, 2, 3, 4]).is_none());
+
+        // Переиндексация существующих файлов
+        let mut reopened = PrefixCache::new(PrefixCacheConfig::enabled(4));
+        reopened.set_disk_dir(Some(dir.clone()));
+        assert_eq!(reopened.stats().disk_entries, 1);
+
+        // Переполнение удаляет старый файл
+        let long = cache.disk_path(&[1, 2, 3, 4]).unwrap();
+        std::fs::write(&long, b"y").unwrap();
+        cache.register_disk(&[1, 2, 3, 4], long.clone());
+        assert!(!short.exists());
+        assert_eq!(cache.match_disk(&[1, 2, 3, 4]).unwrap().path, long);
+        assert_eq!(cache.stats().disk_writes, 2);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_prefix_cache_disk_tier_is_opt_in() {
+        let mut cache = PrefixCache::new(PrefixCacheConfig {
+            disk_min_tokens: 1,
+            ..PrefixCacheConfig::enabled(4)
+        });
+        cache.set_disk_dir(Some(std::env::temp_dir().join("oxide-prefix-disk-off")));
+        assert!(!cache.disk_enabled());
+        assert!(cache.reserve_disk(&[1, 2, 3]).is_none());
+    }
+
     #[test]
     fn test_prefix_cache_empty_tokens() {
         let mut cache = PrefixCache::new(PrefixCacheConfig::enabled(32));
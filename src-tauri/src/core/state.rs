@@ -2,6 +2,7 @@ use crate::core::performance::PerformanceMonitor;
 use crate::core::precision::{Precision, PrecisionPolicy};
 use crate::core::prefix_cache::{PrefixCache, PrefixCacheConfig};
 use crate::core::scheduler::{ModelScheduler, SchedulerConfig};
+use crate::generate::summary::SummaryCache;
 use candle::Device;
 use serde_json;
 use std::fs::File;
@@ -12,6 +13,26 @@ use tauri::AppHandle;
 use tauri::Manager;
 use tokenizers::Tokenizer;
 
+/// Загруженная энкодерная модель вместе со своим токенизатором
+pub struct EncoderEntry {
+    /// Идентификатор для селектора `model` (repo_id или имя каталога)
+    pub(crate) id: String,
+    pub(crate) model: crate::models::encoder::EncoderModel,
+    pub(crate) tokenizer: Tokenizer,
+    /// Пулинг и нормализация по умолчанию из sentence-transformers
+    pub(crate) default_options: crate::models::common::EmbeddingOptions,
+}
+
+/// Слоты энкодерных моделей, независимые от чат-модели
+///
+/// Записи лежат в `Arc`: инференс клонирует запись и отпускает блокировку
+/// `ModelState`, чтобы эмбеддинги и реранкинг не задерживали чат.
+#[derive(Default)]
+pub struct EncoderSlots {
+    pub(crate) embedding: Option<Arc<EncoderEntry>>,
+    pub(crate) reranker: Option<Arc<EncoderEntry>>,
+}
+
 /// Универсальное состояние для любой модели
 pub struct ModelState {
     pub(crate) scheduler: ModelScheduler,
@@ -37,6 +58,25 @@ pub struct ModelState {
     pub(crate) performance_monitor: Arc<PerformanceMonitor>,
     /// Prefix Cache для переиспользования KV-кэшей
     pub(crate) prefix_cache: PrefixCache,
+    /// Rolling summary отброшенной истории по сессиям
+    pub(crate) summary_cache: SummaryCache,
+    /// Токены промпта, которым соответствует начало KV-кэша после последней генерации
+    pub(crate) kv_prompt_tokens: Vec<u32>,
+    /// KV-кэш восстановлен из сессии и содержит ровно эти токены
+    pub(crate) restored_session: Option<Vec<u32>>,
+    /// Рекуррентные модели: состояние после всех токенов последнего промпта,
+    /// кроме последнего (их состояние нельзя откатить после генерации)
+    pub(crate) recurrent_prompt: Option<crate::core::kv_session::KvSession>,
+    /// Формат хранения KV-кэша, запрошенный при загрузке модели
+    pub(crate) kv_cache_type: crate::models::common::KvCacheType,
+    /// Переопределение RoPE scaling, запрошенное при загрузке модели
+    pub(crate) rope_scaling: Option<crate::models::common::RopeScaling>,
+    /// Квантование весов SafeTensors, запрошенное при загрузке модели
+    pub(crate) quantize: Option<crate::core::precision::QuantizationTarget>,
+    /// Активные LoRA-адаптеры (заданные при загрузке или заменённые позже)
+    pub(crate) lora_adapters: Vec<crate::models::common::LoraAdapterSpec>,
+    /// Энкодеры эмбеддингов и реранкеры; переживают загрузку и выгрузку чат-модели
+    pub(crate) encoders: EncoderSlots,
 }
 
 impl ModelState {
@@ -59,6 +99,15 @@ impl ModelState {
             performance_monitor: Arc::new(PerformanceMonitor::new(1000)),
             // Prefix cache включён по умолчанию (32 записи)
             prefix_cache: PrefixCache::new(PrefixCacheConfig::enabled(32)),
+            summary_cache: SummaryCache::new(),
+            kv_prompt_tokens: Vec::new(),
+            restored_session: None,
+            recurrent_prompt: None,
+            kv_cache_type: Default::default(),
+            rope_scaling: None,
+            quantize: None,
+            lora_adapters: Vec::new(),
+            encoders: EncoderSlots::default(),
         }
     }
 
@@ -95,6 +144,11 @@ impl ModelState {
         Ok(dir.join("oxide-lab"))
     }
 
+    /// Каталог файлов сессий KV-кэша
+    pub fn kv_sessions_dir(app: &AppHandle) -> Result<PathBuf, String> {
+        Ok(Self::profile_dir(app)?.join("kv_sessions"))
+    }
+
     fn ensure_profile_dir(app: &AppHandle) -> Result<PathBuf, String> {
         let profile_dir = Self::profile_dir(app)?;
         create_dir_all(&profile_dir)
@@ -10,6 +10,7 @@ use serde::{Deserialize, Serialize};
 use tauri::{AppHandle, Manager};
 use tokenizers::Tokenizer;
 
+use crate::core::transcript::{Transcript, segments_from_tokens, window_advance};
 use crate::core::types::{SttModelSource, SttSettings};
 
 use candle_transformers::models::whisper::{self as whisper, Config};
@@ -20,6 +21,9 @@ const TOKENIZER_FILENAME: &str = "tokenizer-tiny.json";
 const CONFIG_FILENAME: &str = "config-tiny.json";
 const SETTINGS_FILENAME: &str = "stt_settings.json";
 
+/// Первая метка окна — не дальше секунды от его начала (шаги по 20 мс)
+const MAX_INITIAL_TIMESTAMP: usize = 50;
+
 static WHISPER_STATE: OnceCell<Mutex<Option<WhisperState>>> = OnceCell::new();
 
 struct WhisperState {
@@ -37,6 +41,16 @@ pub struct TranscribeRequest {
     pub language: Option<String>,
 }
 
+/// Задача декодера Whisper
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SttTask {
+    /// Текст на языке речи
+    Transcribe,
+    /// Перевод речи на английский
+    Translate,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DownloadRequest {
     pub repo_id: String,
@@ -81,6 +95,111 @@ pub fn transcribe(app: &AppHandle, req: TranscribeRequest) -> Result<String, Str
         ));
     }
 
+    with_whisper(app, |state| {
+        let mel = mel_tensor(state, &req.samples)?;
+        let language = match req.language.as_deref() {
+            None | Some("auto") => detect_language(&mut state.model, &state.tokenizer, &mel)
+                .map_err(|e| format!("Failed to detect language: {e}"))?,
+            Some(language) => language,
+        };
+        let language_token = language_token(&state.tokenizer, language)?;
+        let tokens = decode_greedy(
+            &mut state.model,
+            &state.tokenizer,
+            &state.config,
+            &mel,
+            Some(language_token),
+            SttTask::Transcribe,
+            false,
+        )
+        .map_err(|e| format!("Failed to decode audio: {e}"))?;
+        state
+            .tokenizer
+            .decode(&tokens, true)
+            .map_err(|e| format!("Failed to decode tokens: {e}"))
+    })
+}
+
+/// Распознаёт (или переводит на английский) запись любой длины окнами по 30 секунд
+///
+/// `samples` — моно 16 кГц; сегменты получают время от начала записи.
+/// Без `timestamps` каждое окно даёт один сегмент на всю его длину.
+pub fn transcribe_segments(
+    app: &AppHandle,
+    samples: &[f32],
+    language: Option<&str>,
+    task: SttTask,
+    timestamps: bool,
+) -> Result<Transcript, String> {
+    if samples.is_empty() {
+        return Err("Audio contains no samples".to_string());
+    }
+    with_whisper(app, |state| {
+        let mel = mel_tensor(state, samples)?;
+        let language = match language {
+            None | Some("auto") => detect_language(&mut state.model, &state.tokenizer, &mel)
+                .map_err(|e| format!("Failed to detect language: {e}"))?,
+            Some(language) => language,
+        };
+        let language_token = language_token(&state.tokenizer, language)?;
+        let eot_token = token_id(&state.tokenizer, whisper::EOT_TOKEN)?;
+        let timestamp_begin = token_id(&state.tokenizer, whisper::NO_TIMESTAMPS_TOKEN)? + 1;
+
+        let frames = mel.dim(2).map_err(|e| format!("{e}"))?;
+        // pcm_to_mel дополняет запись тишиной; окна идут только по реальному звуку
+        let content_frames = samples.len() / whisper::HOP_LENGTH;
+        let mut segments = Vec::new();
+        let mut seek = 0;
+        while seek < content_frames {
+            let size = usize::min(frames - seek, whisper::N_FRAMES);
+            let window = mel
+                .narrow(2, seek, size)
+                .map_err(|e| format!("Failed to slice mel: {e}"))?;
+            state.model.reset_kv_cache();
+            let tokens = decode_greedy(
+                &mut state.model,
+                &state.tokenizer,
+                &state.config,
+                &window,
+                Some(language_token),
+                task,
+                timestamps,
+            )
+            .map_err(|e| format!("Failed to decode audio: {e}"))?;
+            let (keep, advance) = window_advance(&tokens, eot_token, timestamp_begin, size);
+            let tokenizer = &state.tokenizer;
+            segments.extend(segments_from_tokens(
+                &tokens[..keep],
+                eot_token,
+                timestamp_begin,
+                frames_to_seconds(seek),
+                frames_to_seconds(usize::min(seek + size, content_frames)),
+                |ids| {
+                    tokenizer
+                        .decode(ids, true)
+                        .map_err(|e| format!("Failed to decode tokens: {e}"))
+                },
+            )?);
+            seek += advance;
+        }
+
+        Ok(Transcript {
+            language: LANGUAGES
+                .iter()
+                .find(|(code, _)| *code == language)
+                .map_or(language, |(_, name)| *name)
+                .to_string(),
+            duration: samples.len() as f64 / whisper::SAMPLE_RATE as f64,
+            segments,
+        })
+    })
+}
+
+/// Выполняет `f` над загруженной моделью, перезагружая её при смене настроек
+fn with_whisper<T>(
+    app: &AppHandle,
+    f: impl FnOnce(&mut WhisperState) -> Result<T, String>,
+) -> Result<T, String> {
     let settings = load_settings(app)?;
     let cache = WHISPER_STATE.get_or_init(|| Mutex::new(None));
     let mut guard = cache
@@ -98,36 +217,26 @@ pub fn transcribe(app: &AppHandle, req: TranscribeRequest) -> Result<String, Str
         .ok_or_else(|| "Failed to initialize STT state".to_string())?;
 
     state.model.reset_kv_cache();
+    f(state)
+}
 
-    let device = Device::Cpu;
-    let mel = whisper::audio::pcm_to_mel(&state.config, &req.samples, &state.mel_filters);
+fn mel_tensor(state: &WhisperState, samples: &[f32]) -> Result<Tensor, String> {
+    let mel = whisper::audio::pcm_to_mel(&state.config, samples, &state.mel_filters);
     let mel_len = mel.len();
-    let mel = Tensor::from_vec(
+    Tensor::from_vec(
         mel,
         (
             1,
             state.config.num_mel_bins,
             mel_len / state.config.num_mel_bins,
         ),
-        &device,
+        &Device::Cpu,
     )
-    .map_err(|e| format!("Failed to build mel tensor: {e}"))?;
+    .map_err(|e| format!("Failed to build mel tensor: {e}"))
+}
 
-    let language_token = match req.language.as_deref() {
-        None | Some("auto") => Some(
-            detect_language(&mut state.model, &state.tokenizer, &mel)
-                .map_err(|e| format!("Failed to detect language: {e}"))?,
-        ),
-        Some(language) => Some(language_token(&state.tokenizer, language)?),
-    };
-    decode_greedy(
-        &mut state.model,
-        &state.tokenizer,
-        &state.config,
-        &mel,
-        language_token,
-    )
-    .map_err(|e| format!("Failed to decode audio: {e}"))
+fn frames_to_seconds(frames: usize) -> f64 {
+    (frames * whisper::HOP_LENGTH) as f64 / whisper::SAMPLE_RATE as f64
 }
 
 pub async fn download_model(
@@ -303,34 +412,50 @@ fn token_id(tokenizer: &Tokenizer, token: &str) -> Result<u32, String> {
     Ok(token)
 }
 
+/// Есть ли у Whisper токен языка с таким кодом (`en`, `ru`, ...)
+pub fn is_supported_language(language: &str) -> bool {
+    LANGUAGES.iter().any(|(code, _)| *code == language)
+}
+
 fn language_token(tokenizer: &Tokenizer, language: &str) -> Result<u32, String> {
-    let supported = LANGUAGES.iter().any(|(code, _)| *code == language);
-    if !supported {
+    if !is_supported_language(language) {
         return Err(format!("Unsupported STT language: {language}"));
     }
     token_id(tokenizer, &format!("<|{language}|>"))
 }
 
+/// Жадное декодирование одного окна; возвращает токены после промпта
+///
+/// С `timestamps` декодер размечает сегменты временными метками.
 fn decode_greedy(
     model: &mut whisper::quantized_model::Whisper,
     tokenizer: &Tokenizer,
     config: &Config,
     mel: &Tensor,
     language_token: Option<u32>,
-) -> Result<String, String> {
+    task: SttTask,
+    timestamps: bool,
+) -> Result<Vec<u32>, String> {
     let device = mel.device();
     let audio_features = model
         .encoder
         .forward(mel, true)
         .map_err(|e| format!("Failed to run encoder: {e}"))?;
 
+    let no_timestamps_token = token_id(tokenizer, whisper::NO_TIMESTAMPS_TOKEN)?;
     let mut tokens = Vec::new();
     tokens.push(token_id(tokenizer, whisper::SOT_TOKEN)?);
     if let Some(lang) = language_token {
         tokens.push(lang);
     }
-    tokens.push(token_id(tokenizer, whisper::TRANSCRIBE_TOKEN)?);
-    tokens.push(token_id(tokenizer, whisper::NO_TIMESTAMPS_TOKEN)?);
+    tokens.push(match task {
+        SttTask::Transcribe => token_id(tokenizer, whisper::TRANSCRIBE_TOKEN)?,
+        SttTask::Translate => token_id(tokenizer, whisper::TRANSLATE_TOKEN)?,
+    });
+    if !timestamps {
+        tokens.push(no_timestamps_token);
+    }
+    let prompt_len = tokens.len();
 
     let eot_token = token_id(tokenizer, whisper::EOT_TOKEN)?;
     while tokens.len() < config.max_target_positions {
@@ -357,6 +482,14 @@ fn decode_greedy(
                 *score = f32::NEG_INFINITY;
             }
         }
+        if timestamps {
+            apply_timestamp_rules(
+                &mut scores,
+                &tokens[prompt_len..],
+                eot_token,
+                no_timestamps_token,
+            );
+        }
         let (next_token, _) = scores
             .iter()
             .enumerate()
@@ -369,9 +502,47 @@ fn decode_greedy(
         }
     }
 
-    tokenizer
-        .decode(&tokens, true)
-        .map_err(|e| format!("Failed to decode tokens: {e}"))
+    Ok(tokens.split_off(prompt_len))
+}
+
+/// Правила меток Whisper: окно начинается с метки не позже секунды,
+/// метки идут парами (конец и начало сегмента) и не убывают
+fn apply_timestamp_rules(
+    scores: &mut [f32],
+    generated: &[u32],
+    eot_token: u32,
+    no_timestamps_token: u32,
+) {
+    let begin = no_timestamps_token as usize + 1;
+    let len = scores.len();
+    let mut suppress = |range: std::ops::Range<usize>| {
+        for score in &mut scores[range.start.min(len)..range.end.min(len)] {
+            *score = f32::NEG_INFINITY;
+        }
+    };
+    suppress(no_timestamps_token as usize..begin);
+
+    let is_timestamp = |token: &u32| *token as usize >= begin;
+    if generated.is_empty() {
+        suppress(0..begin);
+        suppress(begin + MAX_INITIAL_TIMESTAMP + 1..len);
+        return;
+    }
+    let last_was_timestamp = generated.last().is_some_and(is_timestamp);
+    let penultimate_was_timestamp =
+        generated.len() < 2 || is_timestamp(&generated[generated.len() - 2]);
+    if last_was_timestamp {
+        if penultimate_was_timestamp {
+            // Пара меток закрыта: дальше текст
+            suppress(begin..len);
+        } else {
+            // Текст закрыт меткой: дальше метка начала или конец окна
+            suppress(0..eot_token as usize);
+        }
+    }
+    if let Some(last) = generated.iter().rev().find(|t| is_timestamp(t)) {
+        suppress(begin..*last as usize);
+    }
 }
 
 const LANGUAGES: [(&str, &str); 99] = [
@@ -480,7 +651,7 @@ fn detect_language(
     model: &mut whisper::quantized_model::Whisper,
     tokenizer: &Tokenizer,
     mel: &Tensor,
-) -> Result<u32, String> {
+) -> Result<&'static str, String> {
     let (_bsize, _, seq_len) = mel.dims3().map_err(|e| format!("{e}"))?;
     let mel = mel
         .narrow(2, 0, usize::min(seq_len, model.config.max_source_positions))
@@ -520,6 +691,5 @@ fn detect_language(
         .map_err(|e| format!("{e}"))?;
     let mut probs = LANGUAGES.iter().zip(probs.iter()).collect::<Vec<_>>();
     probs.sort_by(|(_, p1), (_, p2)| p2.total_cmp(p1));
-    let language = token_id(tokenizer, &format!("<|{}|>", probs[0].0.0))?;
-    Ok(language)
+    Ok(probs[0].0.0)
 }
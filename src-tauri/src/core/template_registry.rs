@@ -106,6 +106,12 @@ fn check_markers(content: &str, name: &str) -> f64 {
                 return 0.85;
             }
         }
+        "glm4" => {
+            // `[gMASK]<sop>` есть только у GLM; роли <|user|>/<|assistant|> совпадают с Phi-3
+            if content.contains("[gMASK]") && content.contains("<|user|>") {
+                return 0.85;
+            }
+        }
         "mistral-instruct" => {
             if content.contains("[INST]") && content.contains("[/INST]") {
                 return 0.5;
@@ -179,6 +185,14 @@ mod tests {
         assert_eq!(matched.unwrap().name, "deepseekv3");
     }
 
+    #[test]
+    fn test_fuzzy_match_glm4() {
+        let raw = "[gMASK]<sop>{% for item in messages %}<|user|>\n{{ item['content'] }}{% endfor %}<|assistant|>";
+        let matched = match_template(raw);
+        assert!(matched.is_some());
+        assert_eq!(matched.unwrap().name, "glm4");
+    }
+
     #[test]
     fn test_all_templates_syntax() {
         use minijinja::Environment;
@@ -0,0 +1,12 @@
+use crate::core::template_registry::TemplateEntry;
+
+pub const TEMPLATE: TemplateEntry = TemplateEntry {
+    name: "glm4",
+    template: r#"[gMASK]<sop>{% for message in messages %}{% if message['role'] == 'system' %}<|system|>
+{{ message['content'] }}{% elif message['role'] == 'user' %}<|user|>
+{{ message['content'] }}{% elif message['role'] == 'assistant' %}<|assistant|>
+{{ message['content'] }}{% elif message['role'] == 'tool' or message['role'] == 'observation' %}<|observation|>
+{{ message['content'] }}{% endif %}{% endfor %}{% if add_generation_prompt %}<|assistant|>{% endif %}"#,
+    stop_tokens: &["<|endoftext|>", "<|user|>", "<|observation|>"],
+    force_bos: false,
+};
@@ -7,6 +7,7 @@ mod deepseekr1_llama;
 mod deepseekv3;
 mod gemma2;
 mod gemma3;
+mod glm4;
 mod llama;
 mod llama3;
 mod llama32;
@@ -25,6 +26,7 @@ pub fn get_all() -> Vec<TemplateEntry> {
         qwen3::TEMPLATE,
         gemma2::TEMPLATE,
         gemma3::TEMPLATE,
+        glm4::TEMPLATE,
         qwen3coder::TEMPLATE,
         zephyr::TEMPLATE,
         vicuna::TEMPLATE,
@@ -1,6 +1,7 @@
 use candle::quantized::gguf_file;
 use serde::Deserialize;
 use std::collections::HashMap;
+use std::path::Path;
 use tokenizers::{AddedToken, Tokenizer};
 
 pub fn find_tokenizer_json_in_metadata(md: &HashMap<String, gguf_file::Value>) -> Option<String> {
@@ -15,6 +16,7 @@ pub fn find_tokenizer_json_in_metadata(md: &HashMap<String, gguf_file::Value>) -
         "tokenizer_json",
         "tokenizer",
         "tokenizer.ggml.tokenizer_json",
+        "tokenizer.huggingface.json",
         "tokenizer.model",
     ] {
         if let Some(v) = md.get(key)
@@ -88,6 +90,156 @@ pub fn try_reconstruct_tokenizer_from_bpe(
     Some(json.to_string())
 }
 
+fn get_f32_array(md: &HashMap<String, gguf_file::Value>, key: &str) -> Option<Vec<f32>> {
+    let values = md.get(key)?.to_vec().ok()?;
+    values.iter().map(|v| v.to_f32().ok()).collect()
+}
+
+fn get_i32_array(md: &HashMap<String, gguf_file::Value>, key: &str) -> Option<Vec<i32>> {
+    let values = md.get(key)?.to_vec().ok()?;
+    values.iter().map(|v| v.to_i32().ok()).collect()
+}
+
+/// Реконструкция SentencePiece-токенизатора (`tokenizer.ggml.model` = `llama` / `t5`)
+///
+/// Для `llama` llama.cpp сливает соседние куски по наибольшему score
+/// склеенного токена, что совпадает с BPE, где ранг слияния задан score
+/// результата; слияния строятся из всех разбиений токенов словаря. `t5` —
+/// Unigram с log-вероятностями в scores. Символы вне словаря раскладываются
+/// на байтовые токены `<0xNN>`, если они есть в словаре.
+pub fn try_reconstruct_tokenizer_from_spm(
+    md: &HashMap<String, gguf_file::Value>,
+) -> Option<String> {
+    let unigram = match md.get("tokenizer.ggml.model")?.to_string().ok()?.as_str() {
+        "llama" => false,
+        "t5" => true,
+        _ => return None,
+    };
+    let tokens = get_string_array(md, "tokenizer.ggml.tokens")?;
+    let scores = get_f32_array(md, "tokenizer.ggml.scores").filter(|s| s.len() == tokens.len())?;
+    let types = get_i32_array(md, "tokenizer.ggml.token_type")
+        .filter(|t| t.len() == tokens.len())
+        .unwrap_or_else(|| vec![TOKEN_TYPE_NORMAL; tokens.len()]);
+    let unk_id = md
+        .get("tokenizer.ggml.unknown_token_id")
+        .and_then(|v| v.to_u32().ok())
+        .map(|id| id as usize)
+        .filter(|&id| id < tokens.len())
+        .or_else(|| types.iter().position(|&t| t == TOKEN_TYPE_UNKNOWN));
+    let byte_fallback = types.contains(&TOKEN_TYPE_BYTE);
+    let add_space_prefix = md
+        .get("tokenizer.ggml.add_space_prefix")
+        .and_then(|v| v.to_bool().ok())
+        .unwrap_or(true);
+
+    let model = if unigram {
+        let vocab: Vec<serde_json::Value> = tokens
+            .iter()
+            .zip(&scores)
+            .map(|(tok, score)| serde_json::json!([tok, score]))
+            .collect();
+        serde_json::json!({
+            "type": "Unigram",
+            "unk_id": unk_id,
+            "vocab": vocab,
+            "byte_fallback": byte_fallback,
+        })
+    } else {
+        let mut ids: HashMap<&str, usize> = HashMap::with_capacity(tokens.len());
+        for (id, tok) in tokens.iter().enumerate() {
+            ids.entry(tok.as_str()).or_insert(id);
+        }
+        // (склеенный, левый, правый): любое разбиение токена на два токена словаря
+        let mut merges: Vec<(usize, usize, usize)> = Vec::new();
+        for (&tok, &id) in &ids {
+            if !matches!(types[id], TOKEN_TYPE_NORMAL | TOKEN_TYPE_USER_DEFINED) {
+                continue;
+            }
+            for (split, _) in tok.char_indices().skip(1) {
+                if let (Some(&left), Some(&right)) =
+                    (ids.get(&tok[..split]), ids.get(&tok[split..]))
+                {
+                    merges.push((id, left, right));
+                }
+            }
+        }
+        merges.sort_by(|a, b| {
+            scores[b.0]
+                .total_cmp(&scores[a.0])
+                .then_with(|| (a.0, a.1, a.2).cmp(&(b.0, b.1, b.2)))
+        });
+        let merges: Vec<serde_json::Value> = merges
+            .into_iter()
+            .map(|(_, left, right)| serde_json::json!([tokens[left], tokens[right]]))
+            .collect();
+        let vocab: serde_json::Map<String, serde_json::Value> = ids
+            .iter()
+            .map(|(tok, id)| (tok.to_string(), serde_json::json!(id)))
+            .collect();
+        serde_json::json!({
+            "type": "BPE",
+            "dropout": null,
+            "unk_token": unk_id.map(|id| tokens[id].clone()),
+            "continuing_subword_prefix": null,
+            "end_of_word_suffix": null,
+            "fuse_unk": true,
+            "byte_fallback": byte_fallback,
+            "vocab": vocab,
+            "merges": merges,
+        })
+    };
+
+    // Управляющие и пользовательские токены распознаются в тексте целиком
+    let added_tokens: Vec<serde_json::Value> = tokens
+        .iter()
+        .zip(&types)
+        .enumerate()
+        .filter(|(_, (_, t))| matches!(**t, TOKEN_TYPE_CONTROL | TOKEN_TYPE_USER_DEFINED))
+        .map(|(id, (tok, t))| {
+            serde_json::json!({
+                "id": id,
+                "content": tok,
+                "single_word": false,
+                "lstrip": false,
+                "rstrip": false,
+                "normalized": false,
+                "special": *t == TOKEN_TYPE_CONTROL,
+            })
+        })
+        .collect();
+
+    let escape_spaces =
+        serde_json::json!({ "type": "Replace", "pattern": { "String": " " }, "content": "▁" });
+    let normalizer = if add_space_prefix {
+        serde_json::json!({
+            "type": "Sequence",
+            "normalizers": [{ "type": "Prepend", "prepend": "▁" }, escape_spaces],
+        })
+    } else {
+        escape_spaces
+    };
+    let mut decoders = vec![
+        serde_json::json!({ "type": "Replace", "pattern": { "String": "▁" }, "content": " " }),
+        serde_json::json!({ "type": "ByteFallback" }),
+        serde_json::json!({ "type": "Fuse" }),
+    ];
+    if add_space_prefix {
+        decoders
+            .push(serde_json::json!({ "type": "Strip", "content": " ", "start": 1, "stop": 0 }));
+    }
+
+    let json = serde_json::json!({
+        "version": "1.0",
+        "added_tokens": added_tokens,
+        "normalizer": normalizer,
+        "pre_tokenizer": null,
+        "post_processor": null,
+        "decoder": { "type": "Sequence", "decoders": decoders },
+        "model": model,
+    });
+    Some(json.to_string())
+}
+
 pub fn mark_special_chat_tokens(tokenizer: &mut Tokenizer) {
     let vocab = tokenizer.get_vocab(true);
     let specials = [
@@ -172,7 +324,15 @@ pub fn tokenizer_from_gguf_metadata(
         }
     }
 
-    // 2) Если не нашли полноценный JSON или он не парсится, пробуем реконструировать BPE
+    // 2) SentencePiece (`llama` / `t5`): словарь со scores и типами токенов.
+    //    Слияния, если они есть, llama.cpp для этих моделей не использует.
+    if let Some(json) = try_reconstruct_tokenizer_from_spm(md)
+        && let Ok(tok) = Tokenizer::from_bytes(json.as_bytes())
+    {
+        return Ok(tok);
+    }
+
+    // 3) Если не нашли полноценный JSON или он не парсится, пробуем реконструировать BPE
     //    (только если поля BPE действительно есть).
     if let Some(json) = try_reconstruct_tokenizer_from_bpe(md)
         && let Ok(tok) = Tokenizer::from_bytes(json.as_bytes())
@@ -180,7 +340,7 @@ pub fn tokenizer_from_gguf_metadata(
         return Ok(tok);
     }
 
-    // 3) Если BPE реконструкция невозможна или тоже не парсится, пробуем собрать
+    // 4) Если BPE реконструкция невозможна или тоже не парсится, пробуем собрать
     //    простой WordLevel токенизатор из списка токенов.
     if let Some(json) = try_build_wordlevel_tokenizer_from_tokens(md)
         && let Ok(tok) = Tokenizer::from_bytes(json.as_bytes())
@@ -389,3 +549,766 @@ pub fn find_chat_template_in_metadata(md: &HashMap<String, gguf_file::Value>) ->
     }
     best
 }
+
+/// chat_template из tokenizer_config.json: строка или список именованных шаблонов
+pub fn chat_template_from_config(tokenizer_config: &serde_json::Value) -> Option<String> {
+    match tokenizer_config.get("chat_template")? {
+        serde_json::Value::String(template) => Some(template.clone()),
+        serde_json::Value::Array(templates) => {
+            let named = |name: &str| {
+                templates
+                    .iter()
+                    .find(|t| t.get("name").and_then(|v| v.as_str()) == Some(name))
+            };
+            named("default")
+                .or_else(|| templates.first())?
+                .get("template")?
+                .as_str()
+                .map(str::to_string)
+        }
+        _ => None,
+    }
+}
+
+/// Типы токенов `tokenizer.ggml.token_type` (llama_token_type)
+const TOKEN_TYPE_NORMAL: i32 = 1;
+const TOKEN_TYPE_UNKNOWN: i32 = 2;
+const TOKEN_TYPE_CONTROL: i32 = 3;
+const TOKEN_TYPE_USER_DEFINED: i32 = 4;
+const TOKEN_TYPE_UNUSED: i32 = 5;
+const TOKEN_TYPE_BYTE: i32 = 6;
+
+/// Строка специального токена из tokenizer_config.json (строка или объект с `content`)
+fn config_token(tokenizer_config: Option<&serde_json::Value>, key: &str) -> Option<String> {
+    let value = tokenizer_config?.get(key)?;
+    value
+        .as_str()
+        .or_else(|| value.get("content").and_then(|v| v.as_str()))
+        .map(str::to_string)
+}
+
+/// Регулярные выражения `Split` из `pre_tokenizer` (одиночного или в `Sequence`)
+fn pre_tokenizer_regexes(pre: &serde_json::Value) -> Vec<&str> {
+    match pre.get("type").and_then(|v| v.as_str()) {
+        Some("Sequence") => pre
+            .get("pretokenizers")
+            .and_then(|v| v.as_array())
+            .into_iter()
+            .flatten()
+            .flat_map(pre_tokenizer_regexes)
+            .collect(),
+        Some("Split") => pre
+            .get("pattern")
+            .and_then(|p| p.get("Regex"))
+            .and_then(|v| v.as_str())
+            .into_iter()
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Имя пре-токенизатора llama.cpp (`tokenizer.ggml.pre`) для byte-level BPE
+///
+/// llama.cpp выбирает по нему регулярку разбиения текста перед BPE. Имя
+/// определяется по регулярке из tokenizer.json, а если она незнакома —
+/// по `model_type` из config.json; иначе `default`.
+fn gguf_pre_tokenizer(json: &serde_json::Value, config: &serde_json::Value) -> &'static str {
+    const CONTRACTIONS: &str = "(?i:'s|'t|'re|'ve|'m|'ll|'d)";
+    const GPT2_CONTRACTIONS: &str = "'s|'t|'re|'ve|'m|'ll|'d";
+
+    let pre = json
+        .get("pre_tokenizer")
+        .unwrap_or(&serde_json::Value::Null);
+    let regexes = pre_tokenizer_regexes(pre);
+    for regex in &regexes {
+        if regex.contains(CONTRACTIONS) {
+            // Llama 3 группирует цифры по три, Qwen2 разбивает по одной
+            if regex.contains(r"\p{N}{1,3}") {
+                return "llama-bpe";
+            }
+            if regex.contains(r"\p{N}|") {
+                return "qwen2";
+            }
+        }
+    }
+    // Голый ByteLevel со встроенной регуляркой GPT-2
+    let byte_level_regex = pre.get("type").and_then(|v| v.as_str()) == Some("ByteLevel")
+        && pre.get("use_regex").and_then(|v| v.as_bool()) != Some(false);
+    if byte_level_regex || regexes.iter().any(|r| r.starts_with(GPT2_CONTRACTIONS)) {
+        return "gpt-2";
+    }
+
+    match config.get("model_type").and_then(|v| v.as_str()) {
+        Some("qwen2" | "qwen2_moe" | "qwen3" | "qwen3_moe") => "qwen2",
+        Some("llama") => "llama-bpe",
+        Some("glm4") => "glm4",
+        _ => "default",
+    }
+}
+
+/// Метаданные `tokenizer.*` для GGUF из tokenizer.json HuggingFace
+///
+/// Словарь записывается в формате llama.cpp (`gpt2` для byte-level BPE,
+/// `llama` для SentencePiece BPE/Unigram), а исходный tokenizer.json
+/// сохраняется в `tokenizer.huggingface.json`, чтобы загрузка GGUF
+/// восстанавливала токенизатор без потерь.
+pub fn gguf_tokenizer_metadata(
+    tokenizer_json: &str,
+    tokenizer_config: Option<&serde_json::Value>,
+    config: &serde_json::Value,
+    chat_template: Option<&str>,
+) -> Result<HashMap<String, gguf_file::Value>, String> {
+    use gguf_file::Value;
+
+    let json: serde_json::Value = serde_json::from_str(tokenizer_json)
+        .map_err(|e| format!("tokenizer.json parse error: {}", e))?;
+    let model = json.get("model").ok_or("tokenizer.json: missing model")?;
+    let model_type = model.get("type").and_then(|v| v.as_str()).unwrap_or("BPE");
+    let byte_fallback = model
+        .get("byte_fallback")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+
+    // id -> (токен, score)
+    let mut entries: HashMap<u32, (String, f32)> = HashMap::new();
+    match (model_type, model.get("vocab")) {
+        ("BPE", Some(serde_json::Value::Object(vocab))) => {
+            for (token, id) in vocab {
+                let id = id.as_u64().ok_or("tokenizer.json: invalid BPE vocab")? as u32;
+                entries.insert(id, (token.clone(), -(id as f32)));
+            }
+        }
+        ("Unigram", Some(serde_json::Value::Array(vocab))) => {
+            for (id, entry) in vocab.iter().enumerate() {
+                let token = entry.get(0).and_then(|v| v.as_str());
+                let score = entry.get(1).and_then(|v| v.as_f64());
+                let (Some(token), Some(score)) = (token, score) else {
+                    return Err("tokenizer.json: invalid Unigram vocab".into());
+                };
+                entries.insert(id as u32, (token.to_string(), score as f32));
+            }
+        }
+        _ => {
+            return Err(format!(
+                "Tokenizer model {} cannot be written to GGUF",
+                model_type
+            ));
+        }
+    }
+
+    let mut types: HashMap<u32, i32> = HashMap::new();
+    if let Some(added) = json.get("added_tokens").and_then(|v| v.as_array()) {
+        for token in added {
+            let id = token.get("id").and_then(|v| v.as_u64());
+            let content = token.get("content").and_then(|v| v.as_str());
+            let (Some(id), Some(content)) = (id, content) else {
+                continue;
+            };
+            let special = token
+                .get("special")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false);
+            let id = id as u32;
+            entries
+                .entry(id)
+                .or_insert_with(|| (content.to_string(), 0.0));
+            let kind = if special {
+                TOKEN_TYPE_CONTROL
+            } else {
+                TOKEN_TYPE_USER_DEFINED
+            };
+            types.insert(id, kind);
+        }
+    }
+
+    // Словарь дополняется до vocab_size модели, как в конвертере llama.cpp
+    let config_vocab = config
+        .get("vocab_size")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(0) as usize;
+    let n_vocab = entries
+        .keys()
+        .map(|&id| id as usize + 1)
+        .max()
+        .unwrap_or(0)
+        .max(config_vocab);
+    let unk = model.get("unk_token").and_then(|v| v.as_str());
+    let is_byte = |t: &str| t.len() == 6 && t.starts_with("<0x") && t.ends_with('>');
+    let mut tokens = Vec::with_capacity(n_vocab);
+    let mut scores = Vec::with_capacity(n_vocab);
+    let mut token_types = Vec::with_capacity(n_vocab);
+    let mut ids: HashMap<&str, u32> = HashMap::new();
+    for id in 0..n_vocab as u32 {
+        let (token, score, kind) = match entries.get(&id) {
+            Some((token, score)) => {
+                let kind = types
+                    .get(&id)
+                    .copied()
+                    .unwrap_or(if Some(token.as_str()) == unk {
+                        TOKEN_TYPE_UNKNOWN
+                    } else if byte_fallback && is_byte(token) {
+                        TOKEN_TYPE_BYTE
+                    } else {
+                        TOKEN_TYPE_NORMAL
+                    });
+                ids.insert(token.as_str(), id);
+                (token.clone(), *score, kind)
+            }
+            None => (format!("[PAD{}]", id), 0.0, TOKEN_TYPE_UNUSED),
+        };
+        tokens.push(Value::String(token));
+        scores.push(Value::F32(score));
+        token_types.push(Value::I32(kind));
+    }
+
+    let ggml_model = if model_type == "BPE" && !byte_fallback {
+        "gpt2"
+    } else {
+        "llama"
+    };
+    let mut md = HashMap::from([
+        (
+            "tokenizer.ggml.model".to_string(),
+            Value::String(ggml_model.to_string()),
+        ),
+        ("tokenizer.ggml.tokens".to_string(), Value::Array(tokens)),
+        (
+            "tokenizer.ggml.token_type".to_string(),
+            Value::Array(token_types),
+        ),
+        (
+            "tokenizer.huggingface.json".to_string(),
+            Value::String(tokenizer_json.to_string()),
+        ),
+    ]);
+    if ggml_model == "llama" {
+        md.insert("tokenizer.ggml.scores".to_string(), Value::Array(scores));
+    }
+    // SentencePiece-словари llama.cpp разбирает без пре-токенизатора
+    let pre = match ggml_model {
+        "gpt2" => gguf_pre_tokenizer(&json, config),
+        _ => "default",
+    };
+    md.insert(
+        "tokenizer.ggml.pre".to_string(),
+        Value::String(pre.to_string()),
+    );
+    if let Some(merges) = model.get("merges").and_then(|v| v.as_array()) {
+        // Новые tokenizer.json хранят пары массивами, старые — строками "a b"
+        let merges = merges
+            .iter()
+            .filter_map(|m| match m {
+                serde_json::Value::String(s) => Some(s.clone()),
+                serde_json::Value::Array(pair) => {
+                    let pair: Vec<&str> = pair.iter().filter_map(|v| v.as_str()).collect();
+                    (pair.len() == 2).then(|| pair.join(" "))
+                }
+                _ => None,
+            })
+            .map(Value::String)
+            .collect();
+        md.insert("tokenizer.ggml.merges".to_string(), Value::Array(merges));
+    }
+
+    // Специальные токены: tokenizer_config.json, затем id из config.json
+    for (role, key) in [
+        ("bos", "bos_token_id"),
+        ("eos", "eos_token_id"),
+        ("unk", "unk_token_id"),
+        ("pad", "pad_token_id"),
+    ] {
+        let from_config = || {
+            let value = config.get(key)?;
+            value
+                .as_u64()
+                .or_else(|| value.as_array()?.first()?.as_u64())
+                .map(|id| id as u32)
+        };
+        let id = config_token(tokenizer_config, &format!("{role}_token"))
+            .and_then(|t| ids.get(t.as_str()).copied())
+            .or_else(from_config);
+        let gguf_key = match role {
+            "unk" => "tokenizer.ggml.unknown_token_id".to_string(),
+            "pad" => "tokenizer.ggml.padding_token_id".to_string(),
+            _ => format!("tokenizer.ggml.{role}_token_id"),
+        };
+        if let Some(id) = id {
+            md.insert(gguf_key, Value::U32(id));
+        }
+    }
+    for flag in ["add_bos_token", "add_eos_token"] {
+        if let Some(value) = tokenizer_config
+            .and_then(|c| c.get(flag))
+            .and_then(|v| v.as_bool())
+        {
+            md.insert(format!("tokenizer.ggml.{flag}"), Value::Bool(value));
+        }
+    }
+    if let Some(template) = chat_template {
+        md.insert(
+            "tokenizer.chat_template".to_string(),
+            Value::String(template.to_string()),
+        );
+    }
+    Ok(md)
+}
+
+/// Токенизатор из внешних файлов, заменяющий встроенный в GGUF
+pub struct ExternalTokenizer {
+    pub tokenizer: Tokenizer,
+    /// chat_template из tokenizer_config.json, если он лежит рядом
+    pub chat_template: Option<String>,
+}
+
+/// Загружает tokenizer.json и, если есть, соседний tokenizer_config.json
+///
+/// Специальные токены из конфига (`bos_token`, `eos_token`, …,
+/// `added_tokens_decoder`) помечаются как special. `model_vocab_size` —
+/// число строк эмбеддингов модели: токенизатор не может выдавать id за его
+/// пределами.
+pub fn load_external_tokenizer(
+    tokenizer_path: &Path,
+    model_vocab_size: Option<usize>,
+) -> Result<ExternalTokenizer, String> {
+    let mut tokenizer = Tokenizer::from_file(tokenizer_path)
+        .map_err(|e| format!("Failed to load {}: {}", tokenizer_path.display(), e))?;
+
+    let config_path = tokenizer_path.with_file_name("tokenizer_config.json");
+    let tokenizer_config = match std::fs::read_to_string(&config_path) {
+        Ok(raw) => Some(
+            serde_json::from_str::<serde_json::Value>(&raw)
+                .map_err(|e| format!("Failed to parse {}: {}", config_path.display(), e))?,
+        ),
+        Err(_) => None,
+    };
+
+    let vocab = tokenizer.get_vocab(true);
+    let mut specials: Vec<String> = ["bos", "eos", "unk", "pad"]
+        .iter()
+        .filter_map(|role| config_token(tokenizer_config.as_ref(), &format!("{role}_token")))
+        .collect();
+    if let Some(decoder) = tokenizer_config
+        .as_ref()
+        .and_then(|c| c.get("added_tokens_decoder"))
+        .and_then(|v| v.as_object())
+    {
+        specials.extend(
+            decoder
+                .values()
+                .filter(|t| t.get("special").and_then(|v| v.as_bool()) == Some(true))
+                .filter_map(|t| t.get("content").and_then(|v| v.as_str()))
+                .map(str::to_string),
+        );
+    }
+    let to_add: Vec<AddedToken> = specials
+        .into_iter()
+        .filter(|tok| vocab.contains_key(tok))
+        .map(|tok| AddedToken::from(tok, true))
+        .collect();
+    if !to_add.is_empty() {
+        tokenizer.add_special_tokens(&to_add);
+    }
+    mark_special_chat_tokens(&mut tokenizer);
+
+    if let Some(model_vocab) = model_vocab_size {
+        let max_id = tokenizer.get_vocab(true).into_values().max();
+        if let Some(max_id) = max_id.filter(|&id| id as usize >= model_vocab) {
+            return Err(format!(
+                "Tokenizer {} does not match the model: token id {} is outside the model vocabulary of {} tokens",
+                tokenizer_path.display(),
+                max_id,
+                model_vocab
+            ));
+        }
+    }
+
+    let chat_template = tokenizer_config
+        .as_ref()
+        .and_then(chat_template_from_config)
+        .or_else(|| extract_chat_template(&tokenizer));
+    Ok(ExternalTokenizer {
+        tokenizer,
+        chat_template,
+    })
+}
+
+/// Размер словаря модели GGUF: строки `token_embd.weight` или длина `tokenizer.ggml.tokens`
+pub fn gguf_model_vocab_size(content: &gguf_file::Content) -> Option<usize> {
+    content
+        .tensor_infos
+        .get("token_embd.weight")
+        .and_then(|info| info.shape.dims().first().copied())
+        .or_else(|| {
+            content
+                .metadata
+                .get("tokenizer.ggml.tokens")
+                .and_then(|v| v.to_vec().ok())
+                .map(|tokens| tokens.len())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BYTE_LEVEL_BPE: &str = r#"{
+        "version": "1.0",
+        "added_tokens": [
+            {"id": 5, "content": "<|endoftext|>", "single_word": false, "lstrip": false,
+             "rstrip": false, "normalized": false, "special": true}
+        ],
+        "normalizer": null,
+        "pre_tokenizer": {"type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true, "use_regex": true},
+        "post_processor": null,
+        "decoder": {"type": "ByteLevel", "add_prefix_space": true, "trim_offsets": true, "use_regex": true},
+        "model": {
+            "type": "BPE", "dropout": null, "unk_token": null, "continuing_subword_prefix": null,
+            "end_of_word_suffix": null, "fuse_unk": false, "byte_fallback": false,
+            "vocab": {"a": 0, "b": 1, "c": 2, "ab": 3, "abc": 4},
+            "merges": [["a", "b"], ["ab", "c"]]
+        }
+    }"#;
+
+    #[test]
+    fn writes_byte_level_bpe_to_gguf_metadata() -> Result<(), String> {
+        let tokenizer_config = serde_json::json!({
+            "eos_token": {"content": "<|endoftext|>"},
+            "add_bos_token": false,
+            "chat_template": [
+                {"name": "tool_use", "template": "tools"},
+                {"name": "default", "template": "{{ messages }}"}
+            ]
+        });
+        let config = serde_json::json!({"vocab_size": 8, "bos_token_id": 5});
+        let template = chat_template_from_config(&tokenizer_config);
+        assert_eq!(template.as_deref(), Some("{{ messages }}"));
+
+        let md = gguf_tokenizer_metadata(
+            BYTE_LEVEL_BPE,
+            Some(&tokenizer_config),
+            &config,
+            template.as_deref(),
+        )?;
+        let string = |key: &str| md[key].to_string().cloned().map_err(|e| e.to_string());
+        assert_eq!(string("tokenizer.ggml.model")?, "gpt2");
+        assert_eq!(string("tokenizer.ggml.pre")?, "gpt-2");
+        assert_eq!(
+            get_string_array(&md, "tokenizer.ggml.tokens"),
+            Some(
+                [
+                    "a",
+                    "b",
+                    "c",
+                    "ab",
+                    "abc",
+                    "<|endoftext|>",
+                    "[PAD6]",
+                    "[PAD7]"
+                ]
+                .map(String::from)
+                .to_vec()
+            )
+        );
+        assert_eq!(
+            get_string_array(&md, "tokenizer.ggml.merges"),
+            Some(vec!["a b".to_string(), "ab c".to_string()])
+        );
+        let types: Vec<i32> = md["tokenizer.ggml.token_type"]
+            .to_vec()
+            .map_err(|e| e.to_string())?
+            .iter()
+            .filter_map(|v| v.to_i32().ok())
+            .collect();
+        assert_eq!(types, [1, 1, 1, 1, 1, 3, 5, 5]);
+        assert!(!md.contains_key("tokenizer.ggml.scores"));
+        let id = |key: &str| md[key].to_u32().map_err(|e| e.to_string());
+        assert_eq!(id("tokenizer.ggml.eos_token_id")?, 5);
+        assert_eq!(id("tokenizer.ggml.bos_token_id")?, 5);
+        assert!(matches!(
+            md["tokenizer.ggml.add_bos_token"],
+            gguf_file::Value::Bool(false)
+        ));
+        assert_eq!(string("tokenizer.chat_template")?, "{{ messages }}");
+
+        // Загрузчик GGUF восстанавливает тот же токенизатор
+        let restored = tokenizer_from_gguf_metadata(&md)?;
+        let encoding = restored.encode("abc", false).map_err(|e| e.to_string())?;
+        assert_eq!(encoding.get_ids(), [4]);
+        Ok(())
+    }
+
+    #[test]
+    fn writes_sentencepiece_bpe_with_scores() -> Result<(), String> {
+        let json = serde_json::json!({
+            "model": {
+                "type": "BPE",
+                "byte_fallback": true,
+                "unk_token": "<unk>",
+                "vocab": {"<unk>": 0, "<0x41>": 1, "▁a": 2},
+                "merges": ["▁ a"]
+            }
+        });
+        let md = gguf_tokenizer_metadata(&json.to_string(), None, &serde_json::json!({}), None)?;
+        assert_eq!(
+            md["tokenizer.ggml.model"]
+                .to_string()
+                .map_err(|e| e.to_string())?,
+            "llama"
+        );
+        assert_eq!(
+            md["tokenizer.ggml.pre"]
+                .to_string()
+                .map_err(|e| e.to_string())?,
+            "default"
+        );
+        let types: Vec<i32> = md["tokenizer.ggml.token_type"]
+            .to_vec()
+            .map_err(|e| e.to_string())?
+            .iter()
+            .filter_map(|v| v.to_i32().ok())
+            .collect();
+        assert_eq!(types, [2, 6, 1]);
+        let scores: Vec<f32> = md["tokenizer.ggml.scores"]
+            .to_vec()
+            .map_err(|e| e.to_string())?
+            .iter()
+            .filter_map(|v| v.to_f32().ok())
+            .collect();
+        assert_eq!(scores, [0.0, -1.0, -2.0]);
+        Ok(())
+    }
+
+    #[test]
+    fn detects_pre_tokenizer_from_split_regex() {
+        let split = |regex: &str| {
+            serde_json::json!({
+                "pre_tokenizer": {"type": "Sequence", "pretokenizers": [
+                    {"type": "Split", "pattern": {"Regex": regex}, "behavior": "Isolated"},
+                    {"type": "ByteLevel", "add_prefix_space": false, "use_regex": false}
+                ]}
+            })
+        };
+        let llama3 = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+        let qwen2 = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+        let no_config = serde_json::json!({});
+        assert_eq!(gguf_pre_tokenizer(&split(llama3), &no_config), "llama-bpe");
+        assert_eq!(gguf_pre_tokenizer(&split(qwen2), &no_config), "qwen2");
+
+        // Незнакомая регулярка: имя берётся из model_type
+        let unknown = split(r"\p{L}+");
+        let qwen3 = serde_json::json!({"model_type": "qwen3"});
+        assert_eq!(gguf_pre_tokenizer(&unknown, &qwen3), "qwen2");
+        assert_eq!(gguf_pre_tokenizer(&unknown, &no_config), "default");
+    }
+
+    #[test]
+    fn loads_external_tokenizer_with_config() -> Result<(), String> {
+        let dir = std::env::temp_dir().join(format!("oxide-ext-tokenizer-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+        let tokenizer_path = dir.join("tokenizer.json");
+        std::fs::write(&tokenizer_path, BYTE_LEVEL_BPE).map_err(|e| e.to_string())?;
+        let config = serde_json::json!({
+            "bos_token": "abc",
+            "chat_template": "{{ messages }}"
+        });
+        std::fs::write(dir.join("tokenizer_config.json"), config.to_string())
+            .map_err(|e| e.to_string())?;
+
+        let external = load_external_tokenizer(&tokenizer_path, Some(8))?;
+        assert_eq!(external.chat_template.as_deref(), Some("{{ messages }}"));
+        let encoding = external
+            .tokenizer
+            .encode("abcab", false)
+            .map_err(|e| e.to_string())?;
+        // `abc` стал special и не сливается с соседями
+        assert_eq!(encoding.get_ids(), [4, 3]);
+        let decoded = external
+            .tokenizer
+            .decode(&[4, 3], true)
+            .map_err(|e| e.to_string())?;
+        assert_eq!(decoded, "ab");
+
+        let err = load_external_tokenizer(&tokenizer_path, Some(5)).err();
+        std::fs::remove_dir_all(&dir).ok();
+        assert!(err.is_some_and(|e| e.contains("outside the model vocabulary")));
+        Ok(())
+    }
+
+    /// Метаданные SPM-словаря: `<unk>`, `<s>`, `</s>`, 256 байтовых токенов, затем куски
+    fn spm_metadata(model: &str, pieces: &[(&str, f32)]) -> HashMap<String, gguf_file::Value> {
+        use gguf_file::Value;
+        let mut tokens = vec![
+            ("<unk>".to_string(), 0.0, TOKEN_TYPE_UNKNOWN),
+            ("<s>".to_string(), 0.0, TOKEN_TYPE_CONTROL),
+            ("</s>".to_string(), 0.0, TOKEN_TYPE_CONTROL),
+        ];
+        tokens.extend((0..=255u8).map(|b| (format!("<0x{b:02X}>"), 0.0, TOKEN_TYPE_BYTE)));
+        tokens.extend(
+            pieces
+                .iter()
+                .map(|(piece, score)| (piece.to_string(), *score, TOKEN_TYPE_NORMAL)),
+        );
+        HashMap::from([
+            (
+                "tokenizer.ggml.model".to_string(),
+                Value::String(model.into()),
+            ),
+            (
+                "tokenizer.ggml.tokens".to_string(),
+                Value::Array(tokens.iter().map(|t| Value::String(t.0.clone())).collect()),
+            ),
+            (
+                "tokenizer.ggml.scores".to_string(),
+                Value::Array(tokens.iter().map(|t| Value::F32(t.1)).collect()),
+            ),
+            (
+                "tokenizer.ggml.token_type".to_string(),
+                Value::Array(tokens.iter().map(|t| Value::I32(t.2)).collect()),
+            ),
+            ("tokenizer.ggml.unknown_token_id".to_string(), Value::U32(0)),
+        ])
+    }
+
+    /// Эталон: алгоритм `llm_tokenizer_spm` из llama.cpp, переписанный здесь —
+    /// жадное слияние пары с наибольшим score (при равенстве левее), затем
+    /// байтовый fallback
+    fn spm_reference(md: &HashMap<String, gguf_file::Value>, text: &str) -> Vec<u32> {
+        let tokens = get_string_array(md, "tokenizer.ggml.tokens").unwrap();
+        let scores = get_f32_array(md, "tokenizer.ggml.scores").unwrap();
+        let ids: HashMap<&str, u32> = tokens
+            .iter()
+            .enumerate()
+            .map(|(id, tok)| (tok.as_str(), id as u32))
+            .collect();
+        let text = format!("▁{}", text.replace(' ', "▁"));
+        let mut symbols: Vec<String> = text.chars().map(String::from).collect();
+        loop {
+            let mut best: Option<(usize, f32)> = None;
+            for i in 1..symbols.len() {
+                let merged = format!("{}{}", symbols[i - 1], symbols[i]);
+                if let Some(&id) = ids.get(merged.as_str()) {
+                    let score = scores[id as usize];
+                    if best.is_none_or(|(_, s)| score > s) {
+                        best = Some((i - 1, score));
+                    }
+                }
+            }
+            let Some((i, _)) = best else { break };
+            let right = symbols.remove(i + 1);
+            symbols[i].push_str(&right);
+        }
+        symbols
+            .iter()
+            .flat_map(|sym| match ids.get(sym.as_str()) {
+                Some(&id) => vec![id],
+                None => sym
+                    .bytes()
+                    .map(|b| ids[format!("<0x{b:02X}>").as_str()])
+                    .collect(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sentencepiece_bpe_matches_greedy_merge_reference() -> Result<(), String> {
+        // Как в SPM BPE: score = -порядок слияния
+        let pieces = [
+            "▁", "h", "e", "l", "o", "w", "r", "d", "▁h", "he", "ll", "lo", "▁he", "llo", "▁hel",
+            "▁hello", "▁w", "or", "▁wor", "ld", "▁world", "el", "▁l",
+        ];
+        let pieces: Vec<(&str, f32)> = pieces
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (*p, -(i as f32)))
+            .collect();
+        let md = spm_metadata("llama", &pieces);
+        let tokenizer = tokenizer_from_gguf_metadata(&md)?;
+        let id = |tok: &str| tokenizer.token_to_id(tok).unwrap();
+
+        let encode = |text: &str| -> Result<Vec<u32>, String> {
+            Ok(tokenizer
+                .encode(text, false)
+                .map_err(|e| e.to_string())?
+                .get_ids()
+                .to_vec())
+        };
+        assert_eq!(encode("hello world")?, [id("▁hello"), id("▁world")]);
+        // ö нет в словаре — два байтовых токена UTF-8
+        assert_eq!(
+            encode("wörld")?,
+            [id("▁w"), id("<0xC3>"), id("<0xB6>"), id("r"), id("ld")]
+        );
+        // Фиксированные id: 3 служебных и 256 байтовых токенов (<0xNN> = 3 + NN),
+        // затем куски с 259. Ожидания выведены вручную по правилу SentencePiece
+        // BPE: сливается пара с наибольшим score, при равенстве — левая. Например,
+        // "▁held▁lol": ▁h (-8), lo (-11), ▁he (-12), ▁hel (-14); у "▁hel d ▁ lo l"
+        // слияний больше нет
+        for (text, expected) in [
+            ("held lol", &[273, 266, 259, 270, 262][..]),
+            (
+                "dlrow olleh",
+                &[259, 266, 262, 265, 263, 264, 259, 263, 269, 261, 260],
+            ),
+            // ▁h, he, ll (левая из двух), lo
+            (
+                "hhheeelllooo",
+                &[267, 260, 268, 261, 261, 269, 270, 263, 263],
+            ),
+            ("wörld hello", &[275, 198, 185, 265, 278, 274]),
+        ] {
+            assert_eq!(encode(text)?, expected, "{text}");
+        }
+        for text in [
+            "hello world",
+            "hello  world",
+            "held lol",
+            "wörld hello",
+            "dlrow olleh",
+            "hhheeelllooo",
+            "привет",
+        ] {
+            let ids = encode(text)?;
+            assert_eq!(ids, spm_reference(&md, text), "{text}");
+            let decoded = tokenizer.decode(&ids, false).map_err(|e| e.to_string())?;
+            assert_eq!(decoded, text);
+        }
+
+        // Управляющие токены распознаются целиком и пропускаются при декодировании
+        assert_eq!(encode("</s>hello")?, [2, id("▁hello")]);
+        assert_eq!(
+            tokenizer
+                .decode(&[1, id("▁hello")], true)
+                .map_err(|e| e.to_string())?,
+            "hello"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn sentencepiece_unigram_uses_scores() -> Result<(), String> {
+        let md = spm_metadata(
+            "t5",
+            &[
+                ("▁", -2.0),
+                ("a", -2.0),
+                ("b", -1.0),
+                ("▁a", -1.0),
+                ("▁ab", -3.0),
+                ("ab", -0.5),
+            ],
+        );
+        let tokenizer = tokenizer_from_gguf_metadata(&md)?;
+        let id = |tok: &str| tokenizer.token_to_id(tok).unwrap();
+        let encoding = tokenizer
+            .encode("ab ab", false)
+            .map_err(|e| e.to_string())?;
+        // ▁a+b (-2) выгоднее ▁+ab (-2.5) и целого ▁ab (-3)
+        assert_eq!(encoding.get_ids(), [id("▁a"), id("b"), id("▁a"), id("b")]);
+        let encoding = tokenizer.encode("bä", false).map_err(|e| e.to_string())?;
+        assert_eq!(
+            encoding.get_ids(),
+            [id("▁"), id("b"), id("<0xC3>"), id("<0xA4>")]
+        );
+        Ok(())
+    }
+}
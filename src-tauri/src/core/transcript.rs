@@ -0,0 +1,296 @@
+//! Сегменты распознанной речи и их форматы для `/v1/audio/*`
+
+use std::str::FromStr;
+
+use serde::Serialize;
+
+/// Длительность одного шага временной метки Whisper, секунды
+pub const TIMESTAMP_STEP: f64 = 0.02;
+
+/// Кадров mel-спектрограммы на шаг временной метки (кадр — 10 мс)
+const FRAMES_PER_TIMESTAMP: usize = 2;
+
+/// Фрагмент речи с границами в секундах от начала записи
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TranscriptSegment {
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+}
+
+/// Результат распознавания всей записи
+#[derive(Debug, Clone, Serialize)]
+pub struct Transcript {
+    /// Название языка речи (например, `english`)
+    pub language: String,
+    /// Длительность записи, секунды
+    pub duration: f64,
+    pub segments: Vec<TranscriptSegment>,
+}
+
+/// Формат ответа `response_format`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ResponseFormat {
+    #[default]
+    Json,
+    Text,
+    Srt,
+    Vtt,
+    VerboseJson,
+}
+
+impl FromStr for ResponseFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "text" => Ok(Self::Text),
+            "srt" => Ok(Self::Srt),
+            "vtt" => Ok(Self::Vtt),
+            "verbose_json" => Ok(Self::VerboseJson),
+            other => Err(format!(
+                "Unsupported response_format '{}' (expected json, text, srt, vtt or verbose_json)",
+                other
+            )),
+        }
+    }
+}
+
+impl ResponseFormat {
+    /// Нужны ли временные метки для построения ответа
+    pub fn needs_timestamps(self) -> bool {
+        matches!(self, Self::Srt | Self::Vtt | Self::VerboseJson)
+    }
+}
+
+/// Разбивает токены окна декодирования на сегменты по временным меткам
+///
+/// Токены `>= timestamp_begin` — метки с шагом [`TIMESTAMP_STEP`] от `offset`;
+/// прочие служебные токены (`>= eot`) пропускаются, декодирование заканчивается на `eot`.
+/// Незакрытый меткой хвост завершается в `window_end`.
+pub fn segments_from_tokens(
+    tokens: &[u32],
+    eot: u32,
+    timestamp_begin: u32,
+    offset: f64,
+    window_end: f64,
+    decode: impl Fn(&[u32]) -> Result<String, String>,
+) -> Result<Vec<TranscriptSegment>, String> {
+    let mut segments = Vec::new();
+    let mut start = offset;
+    let mut text_tokens: Vec<u32> = Vec::new();
+    let mut push = |start: f64, end: f64, text_tokens: &mut Vec<u32>| -> Result<(), String> {
+        let text = decode(text_tokens)?;
+        text_tokens.clear();
+        let text = text.trim();
+        if !text.is_empty() {
+            segments.push(TranscriptSegment {
+                start,
+                end: end.max(start),
+                text: text.to_string(),
+            });
+        }
+        Ok(())
+    };
+    for &token in tokens {
+        if token == eot {
+            break;
+        }
+        if token >= timestamp_begin {
+            let time = offset + f64::from(token - timestamp_begin) * TIMESTAMP_STEP;
+            if !text_tokens.is_empty() {
+                push(start, time, &mut text_tokens)?;
+            }
+            start = time;
+        } else if token < eot {
+            text_tokens.push(token);
+        }
+    }
+    if !text_tokens.is_empty() {
+        push(start, window_end, &mut text_tokens)?;
+    }
+    Ok(segments)
+}
+
+/// Сколько токенов окна оставить и на сколько кадров сдвинуть следующее окно
+///
+/// Как в эталонном Whisper: если окно не кончается одиночной меткой, последний
+/// сегмент обрезан границей окна. Токены после последней закрытой пары меток
+/// отбрасываются, и следующее окно начинается с закрывающей метки. Без пар
+/// меток окно сдвигается целиком (`window_frames`).
+pub fn window_advance(
+    tokens: &[u32],
+    eot: u32,
+    timestamp_begin: u32,
+    window_frames: usize,
+) -> (usize, usize) {
+    let end = tokens
+        .iter()
+        .position(|&t| t == eot)
+        .unwrap_or(tokens.len());
+    let tokens = &tokens[..end];
+    let is_timestamp = |i: usize| tokens[i] >= timestamp_begin;
+    let single_ending = end >= 2 && !is_timestamp(end - 2) && is_timestamp(end - 1);
+    let last_pair = (1..end)
+        .rev()
+        .find(|&i| is_timestamp(i - 1) && is_timestamp(i));
+    match last_pair {
+        Some(i) if !single_ending => {
+            let frames = (tokens[i - 1] - timestamp_begin) as usize * FRAMES_PER_TIMESTAMP;
+            // Пара в самом начале окна не продвигает запись — берём окно целиком
+            let advance = if frames == 0 { window_frames } else { frames };
+            (i, advance.min(window_frames))
+        }
+        _ => (end, window_frames),
+    }
+}
+
+impl Transcript {
+    /// Сплошной текст всех сегментов
+    pub fn text(&self) -> String {
+        self.segments
+            .iter()
+            .map(|s| s.text.as_str())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Субтитры SubRip
+    pub fn to_srt(&self) -> String {
+        let mut out = String::new();
+        for (index, segment) in self.segments.iter().enumerate() {
+            out.push_str(&format!(
+                "{}\n{} --> {}\n{}\n\n",
+                index + 1,
+                format_timestamp(segment.start, ','),
+                format_timestamp(segment.end, ','),
+                segment.text
+            ));
+        }
+        out
+    }
+
+    /// Субтитры WebVTT
+    pub fn to_vtt(&self) -> String {
+        let mut out = String::from("WEBVTT\n\n");
+        for segment in &self.segments {
+            out.push_str(&format!(
+                "{} --> {}\n{}\n\n",
+                format_timestamp(segment.start, '.'),
+                format_timestamp(segment.end, '.'),
+                segment.text
+            ));
+        }
+        out
+    }
+}
+
+/// `HH:MM:SS<sep>mmm`; SRT отделяет миллисекунды запятой, WebVTT — точкой
+fn format_timestamp(seconds: f64, separator: char) -> String {
+    let millis = (seconds.max(0.0) * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}{}{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        separator,
+        millis % 1000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EOT: u32 = 100;
+    const TS: u32 = 200;
+
+    fn decode(tokens: &[u32]) -> Result<String, String> {
+        Ok(tokens.iter().map(|t| format!(" w{t}")).collect())
+    }
+
+    fn transcript() -> Transcript {
+        Transcript {
+            language: "english".into(),
+            duration: 3725.5,
+            segments: vec![
+                TranscriptSegment {
+                    start: 0.0,
+                    end: 2.4,
+                    text: "Hello.".into(),
+                },
+                TranscriptSegment {
+                    start: 3661.25,
+                    end: 3662.0,
+                    text: "Bye.".into(),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn segments_split_on_timestamps_with_offset() {
+        // <|0.00|> w1 w2 <|1.00|> <|1.00|> w3 <|2.50|> <|eot|>
+        let tokens = [TS, 1, 2, TS + 50, TS + 50, 3, TS + 125, EOT, 4];
+        let segments = segments_from_tokens(&tokens, EOT, TS, 30.0, 60.0, decode).unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].text, "w1 w2");
+        assert!((segments[0].start - 30.0).abs() < 1e-9);
+        assert!((segments[0].end - 31.0).abs() < 1e-9);
+        assert_eq!(segments[1].text, "w3");
+        assert!((segments[1].end - 32.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn unterminated_segment_ends_at_window_end() {
+        let tokens = [TS + 10, 7, EOT + 1, 8];
+        let segments = segments_from_tokens(&tokens, EOT, TS, 0.0, 12.0, decode).unwrap();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].text, "w7 w8");
+        assert!((segments[0].start - 0.2).abs() < 1e-9);
+        assert!((segments[0].end - 12.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn window_advances_to_last_closed_pair() {
+        // Одиночная метка в конце: окно разобрано полностью
+        let tokens = [TS, 1, 2, TS + 50, TS + 50, 3, TS + 125, EOT];
+        assert_eq!(window_advance(&tokens, EOT, TS, 3000), (7, 3000));
+
+        // Хвост w4 обрезан окном: следующее окно начинается с <|2.50|>
+        let tokens = [TS, 1, TS + 50, TS + 50, 3, TS + 125, TS + 125, 4, EOT];
+        assert_eq!(window_advance(&tokens, EOT, TS, 3000), (6, 250));
+        let segments = segments_from_tokens(&tokens[..6], EOT, TS, 0.0, 30.0, decode).unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[1].text, "w3");
+
+        // Без меток окно сдвигается целиком
+        assert_eq!(window_advance(&[1, 2, EOT], EOT, TS, 1200), (2, 1200));
+    }
+
+    #[test]
+    fn srt_and_vtt_layout() {
+        let t = transcript();
+        assert_eq!(
+            t.to_srt(),
+            "1\n00:00:00,000 --> 00:00:02,400\nHello.\n\n\
+             2\n01:01:01,250 --> 01:01:02,000\nBye.\n\n"
+        );
+        assert_eq!(
+            t.to_vtt(),
+            "WEBVTT\n\n00:00:00.000 --> 00:00:02.400\nHello.\n\n\
+             01:01:01.250 --> 01:01:02.000\nBye.\n\n"
+        );
+        assert_eq!(t.text(), "Hello. Bye.");
+    }
+
+    #[test]
+    fn response_format_parsing() {
+        assert_eq!("srt".parse(), Ok(ResponseFormat::Srt));
+        assert_eq!("verbose_json".parse(), Ok(ResponseFormat::VerboseJson));
+        assert!("xml".parse::<ResponseFormat>().is_err());
+        assert!(!ResponseFormat::Json.needs_timestamps());
+        assert!(ResponseFormat::Vtt.needs_timestamps());
+    }
+}
@@ -1,5 +1,8 @@
 use serde::{Deserialize, Serialize};
 
+use crate::core::precision::QuantizationTarget;
+use crate::models::common::{KvCacheType, LoraAdapterSpec, RopeScaling, StreamingWindow};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatMessage {
     pub role: String,
@@ -35,9 +38,27 @@ pub enum DevicePreference {
 pub enum LoadRequest {
     Gguf {
         model_path: String,
+        /// Внешний tokenizer.json вместо встроенного в GGUF (запоминается для модели;
+        /// пустая строка возвращает встроенный)
+        #[serde(default)]
         tokenizer_path: Option<String>,
         context_length: usize,
         device: Option<DevicePreference>,
+        /// Потоковый режим внимания (attention sinks + скользящее окно KV)
+        #[serde(default)]
+        streaming: Option<StreamingWindow>,
+        /// Формат хранения KV-кэша (full / q8 / q4)
+        #[serde(default)]
+        kv_cache_type: Option<KvCacheType>,
+        /// Переопределение RoPE scaling (расширение контекста сверх обученного)
+        #[serde(default)]
+        rope_scaling: Option<RopeScaling>,
+        /// LoRA-адаптеры в формате PEFT (директория + масштаб)
+        #[serde(default)]
+        lora_adapters: Option<Vec<LoraAdapterSpec>>,
+        /// Выгрузить текущую модель до загрузки новой, чтобы две модели не занимали память одновременно
+        #[serde(default)]
+        unload_previous: bool,
     },
     #[serde(rename = "hub_gguf")]
     HubGguf {
@@ -49,6 +70,21 @@ pub enum LoadRequest {
         filename: String,
         context_length: usize,
         device: Option<DevicePreference>,
+        /// Потоковый режим внимания (attention sinks + скользящее окно KV)
+        #[serde(default)]
+        streaming: Option<StreamingWindow>,
+        /// Формат хранения KV-кэша (full / q8 / q4)
+        #[serde(default)]
+        kv_cache_type: Option<KvCacheType>,
+        /// Переопределение RoPE scaling (расширение контекста сверх обученного)
+        #[serde(default)]
+        rope_scaling: Option<RopeScaling>,
+        /// LoRA-адаптеры в формате PEFT (директория + масштаб)
+        #[serde(default)]
+        lora_adapters: Option<Vec<LoraAdapterSpec>>,
+        /// Выгрузить текущую модель до загрузки новой, чтобы две модели не занимали память одновременно
+        #[serde(default)]
+        unload_previous: bool,
     },
     #[serde(rename = "hub_safetensors")]
     HubSafetensors {
@@ -60,6 +96,24 @@ pub enum LoadRequest {
         context_length: usize,
         /// Предпочтительное устройство
         device: Option<DevicePreference>,
+        /// Потоковый режим внимания (attention sinks + скользящее окно KV)
+        #[serde(default)]
+        streaming: Option<StreamingWindow>,
+        /// Формат хранения KV-кэша (full / q8 / q4)
+        #[serde(default)]
+        kv_cache_type: Option<KvCacheType>,
+        /// Переопределение RoPE scaling (расширение контекста сверх обученного)
+        #[serde(default)]
+        rope_scaling: Option<RopeScaling>,
+        /// LoRA-адаптеры в формате PEFT (директория + масштаб)
+        #[serde(default)]
+        lora_adapters: Option<Vec<LoraAdapterSpec>>,
+        /// Квантовать веса при загрузке (Q8_0 / Q4_K) вместо хранения в dtype модели
+        #[serde(default)]
+        quantize: Option<QuantizationTarget>,
+        /// Выгрузить текущую модель до загрузки новой, чтобы две модели не занимали память одновременно
+        #[serde(default)]
+        unload_previous: bool,
     },
     #[serde(rename = "local_safetensors")]
     LocalSafetensors {
@@ -69,9 +123,119 @@ pub enum LoadRequest {
         context_length: usize,
         /// Предпочтительное устройство
         device: Option<DevicePreference>,
+        /// Потоковый режим внимания (attention sinks + скользящее окно KV)
+        #[serde(default)]
+        streaming: Option<StreamingWindow>,
+        /// Формат хранения KV-кэша (full / q8 / q4)
+        #[serde(default)]
+        kv_cache_type: Option<KvCacheType>,
+        /// Переопределение RoPE scaling (расширение контекста сверх обученного)
+        #[serde(default)]
+        rope_scaling: Option<RopeScaling>,
+        /// LoRA-адаптеры в формате PEFT (директория + масштаб)
+        #[serde(default)]
+        lora_adapters: Option<Vec<LoraAdapterSpec>>,
+        /// Квантовать веса при загрузке (Q8_0 / Q4_K) вместо хранения в dtype модели
+        #[serde(default)]
+        quantize: Option<QuantizationTarget>,
+        /// Выгрузить текущую модель до загрузки новой, чтобы две модели не занимали память одновременно
+        #[serde(default)]
+        unload_previous: bool,
     },
 }
 
+/// Запрос загрузки энкодерной модели эмбеддингов или реранкера (safetensors)
+///
+/// Слот (эмбеддинги или реранкер) выбирается по `architectures` из config.json.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "format", rename_all = "snake_case")]
+pub enum LoadEncoderRequest {
+    /// Локальная директория с config.json, tokenizer.json и весами
+    LocalSafetensors {
+        model_path: String,
+        device: Option<DevicePreference>,
+    },
+    /// Репозиторий на HF Hub: например, "BAAI/bge-small-en-v1.5"
+    HubSafetensors {
+        repo_id: String,
+        revision: Option<String>,
+        device: Option<DevicePreference>,
+    },
+}
+
+impl LoadRequest {
+    /// Конфигурация потокового режима внимания из запроса
+    pub fn streaming(&self) -> Option<StreamingWindow> {
+        match self {
+            LoadRequest::Gguf { streaming, .. }
+            | LoadRequest::HubGguf { streaming, .. }
+            | LoadRequest::HubSafetensors { streaming, .. }
+            | LoadRequest::LocalSafetensors { streaming, .. } => *streaming,
+        }
+    }
+
+    /// Формат хранения KV-кэша из запроса (по умолчанию — полная точность)
+    pub fn kv_cache_type(&self) -> KvCacheType {
+        match self {
+            LoadRequest::Gguf { kv_cache_type, .. }
+            | LoadRequest::HubGguf { kv_cache_type, .. }
+            | LoadRequest::HubSafetensors { kv_cache_type, .. }
+            | LoadRequest::LocalSafetensors { kv_cache_type, .. } => {
+                kv_cache_type.unwrap_or_default()
+            }
+        }
+    }
+
+    /// Переопределение RoPE scaling из запроса
+    pub fn rope_scaling(&self) -> Option<RopeScaling> {
+        match self {
+            LoadRequest::Gguf { rope_scaling, .. }
+            | LoadRequest::HubGguf { rope_scaling, .. }
+            | LoadRequest::HubSafetensors { rope_scaling, .. }
+            | LoadRequest::LocalSafetensors { rope_scaling, .. } => *rope_scaling,
+        }
+    }
+
+    /// Формат квантования SafeTensors весов при загрузке (GGUF уже квантован)
+    pub fn quantize(&self) -> Option<QuantizationTarget> {
+        match self {
+            LoadRequest::Gguf { .. } | LoadRequest::HubGguf { .. } => None,
+            LoadRequest::HubSafetensors { quantize, .. }
+            | LoadRequest::LocalSafetensors { quantize, .. } => *quantize,
+        }
+    }
+
+    /// Выгружать ли текущую модель до загрузки новой
+    pub fn unload_previous(&self) -> bool {
+        match self {
+            LoadRequest::Gguf {
+                unload_previous, ..
+            }
+            | LoadRequest::HubGguf {
+                unload_previous, ..
+            }
+            | LoadRequest::HubSafetensors {
+                unload_previous, ..
+            }
+            | LoadRequest::LocalSafetensors {
+                unload_previous, ..
+            } => *unload_previous,
+        }
+    }
+
+    /// LoRA-адаптеры из запроса (пустой список — без адаптеров)
+    pub fn lora_adapters(&self) -> Vec<LoraAdapterSpec> {
+        match self {
+            LoadRequest::Gguf { lora_adapters, .. }
+            | LoadRequest::HubGguf { lora_adapters, .. }
+            | LoadRequest::HubSafetensors { lora_adapters, .. }
+            | LoadRequest::LocalSafetensors { lora_adapters, .. } => {
+                lora_adapters.clone().unwrap_or_default()
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GenerateRequest {
     pub prompt: String,
@@ -94,6 +258,9 @@ pub struct GenerateRequest {
     pub seed: Option<u64>,
     #[serde(default)]
     pub split_prompt: Option<bool>,
+    /// Размер чанка prefill в токенах (None — значение по умолчанию)
+    #[serde(default)]
+    pub prefill_chunk_size: Option<usize>,
     #[serde(default)]
     pub verbose_prompt: Option<bool>,
     #[serde(default)]
@@ -114,6 +281,24 @@ pub struct GenerateRequest {
     /// Tool choice: auto, none, required, or specific function
     #[serde(default)]
     pub tool_choice: Option<ToolChoice>,
+    /// Prompt lookup decoding: черновики из n-грамм промпта, проверяемые одним проходом
+    #[serde(default)]
+    pub prompt_lookup: Option<bool>,
+    /// Context shift: при заполнении KV-кэша отбрасывать старейшие токены вместо остановки
+    #[serde(default)]
+    pub context_shift: Option<bool>,
+    /// Сколько первых токенов промпта закрепить при сдвиге (не меньше системного промпта)
+    #[serde(default)]
+    pub context_shift_keep: Option<usize>,
+    /// Rolling summary: сжимать не поместившуюся историю моделью вместо отбрасывания
+    #[serde(default)]
+    pub rolling_summary: Option<bool>,
+    /// Идентификатор диалога, под которым кэшируется rolling summary
+    #[serde(default)]
+    pub session_id: Option<String>,
+    /// Идентификатор запроса для точечной отмены (`cancel_generation_request`)
+    #[serde(default)]
+    pub request_id: Option<String>,
 }
 
 /// Tool choice options for controlling function calling behavior
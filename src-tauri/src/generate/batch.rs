@@ -0,0 +1,657 @@
+//! Continuous batching: несколько последовательностей за один forward-проход
+//!
+//! Каждая последовательность получает собственный слот KV-кэша
+//! (`ModelBackend::prefill_slot`), позицию и состояние семплера.
+//! Новые запросы подключаются к идущему батчу между шагами декодирования,
+//! завершённые последовательности сразу освобождают слот.
+//!
+//! Движок работает в отдельном потоке и берёт lock состояния только на время
+//! одного prefill или одного шага `forward_batch`.
+
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+
+use candle::Tensor;
+use candle_transformers::generation::LogitsProcessor;
+
+use super::{
+    cancel::{clear_request_cancel, is_generation_cancelled},
+    ctx::ContextSlice,
+    emit::{ChunkEmitter, EmissionBackend, GenerationEvent},
+    minp::MinPFilter,
+    sampling::build_logits_processor_from_options,
+    stream::{
+        DEFAULT_PREFILL_CHUNK, STOP_MARKERS, generate_stream_with_backend, push_stop_window,
+        render_request_prompt, resolve_sampling_options, sample_logits, with_active_model,
+    },
+    thinking_parser::ThinkingParser,
+};
+use crate::core::performance::InferenceTracker;
+use crate::core::state::SharedState;
+use crate::core::token_output_stream::TokenOutputStream;
+use crate::core::tokenizer::{extract_bos_token_str, extract_eos_ids};
+use crate::core::types::GenerateRequest;
+use crate::log_infer;
+
+/// Конфигурация движка continuous batching
+#[derive(Debug, Clone)]
+pub struct BatchConfig {
+    /// Максимальное число одновременно декодируемых последовательностей
+    pub max_batch_size: usize,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self { max_batch_size: 4 }
+    }
+}
+
+/// Запрос на генерацию, поставленный в очередь движка
+struct BatchJob {
+    request: GenerateRequest,
+    backend: Box<dyn EmissionBackend>,
+}
+
+/// Движок continuous batching (очередь запросов + поток декодирования)
+pub struct BatchEngine {
+    tx: Sender<BatchJob>,
+}
+
+impl BatchEngine {
+    /// Запускает поток движка
+    pub fn spawn(state: SharedState, config: BatchConfig) -> Result<Self, String> {
+        let (tx, rx) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("batch-engine".into())
+            .spawn(move || run(state, rx, config))
+            .map_err(|e| e.to_string())?;
+        Ok(Self { tx })
+    }
+
+    /// Ставит запрос в очередь; события генерации уходят в `backend`
+    pub fn submit(
+        &self,
+        request: GenerateRequest,
+        backend: Box<dyn EmissionBackend>,
+    ) -> Result<(), String> {
+        self.tx
+            .send(BatchJob { request, backend })
+            .map_err(|_| "Batch engine is not running".to_string())
+    }
+}
+
+/// Может ли запрос обслуживаться батч-движком
+///
+/// Tool calling, grammar, prompt lookup, rolling summary, трассировка и
+/// потокенный prefill работают только в последовательном пути
+/// `generate_stream_with_backend`. Длинные промпты и промпты с сохранённым
+/// префиксом передаются туда же уже после токенизации (см. `prefill`).
+pub fn is_batchable(req: &GenerateRequest) -> bool {
+    req.tools.is_none()
+        && !req
+            .format
+            .as_ref()
+            .map(|f| f.requires_grammar())
+            .unwrap_or(false)
+        && !req.prompt_lookup.unwrap_or(false)
+        && !req.rolling_summary.unwrap_or(false)
+        && !req.tracing.unwrap_or(false)
+        && !req.verbose_prompt.unwrap_or(false)
+        && !req.split_prompt.unwrap_or(false)
+}
+
+/// Состояние одной последовательности в батче
+struct Sequence {
+    slot: usize,
+    request_id: Option<String>,
+    model_id: String,
+    tos: TokenOutputStream,
+    logits_processor: LogitsProcessor,
+    minp: MinPFilter,
+    repeat_penalty: Option<f32>,
+    repeat_last_n: usize,
+    stop_ids: Vec<u32>,
+    stop_sequences: Vec<String>,
+    stop_text_buf: String,
+    thinking_parser: ThinkingParser,
+    emitter: ChunkEmitter,
+    tracker: InferenceTracker,
+    /// Сгенерированные токены
+    tokens: Vec<u32>,
+    /// Позиция в KV-кэше слота для следующего входного токена
+    position: usize,
+    max_new_tokens: usize,
+    finished: bool,
+}
+
+impl Sequence {
+    /// Обрабатывает сэмплированный токен: эмиссия текста и проверка остановки
+    fn accept(&mut self, token: u32) -> Result<(), String> {
+        self.tokens.push(token);
+        self.tracker.increment_generated_tokens();
+
+        if self.stop_ids.contains(&token) {
+            self.finished = true;
+            return Ok(());
+        }
+        if let Some(t) = self.tos.next_token(token).map_err(|e| e.to_string())? {
+            let chunk = self.thinking_parser.process_token(&t);
+            self.emitter.emit_message(chunk);
+            push_stop_window(&mut self.stop_text_buf, &t);
+            if self
+                .stop_sequences
+                .iter()
+                .any(|s| self.stop_text_buf.contains(s))
+                || STOP_MARKERS.iter().any(|m| self.stop_text_buf.contains(m))
+            {
+                self.finished = true;
+            }
+        }
+        if self.tokens.len() >= self.max_new_tokens
+            || self.emitter.is_closed()
+            || is_generation_cancelled(self.request_id.as_deref())
+        {
+            self.finished = true;
+        }
+        Ok(())
+    }
+
+    /// Сэмплирует следующий токен из логитов шага и принимает его
+    fn sample_and_accept(&mut self, logits: &Tensor) -> Result<(), String> {
+        let token = sample_logits(
+            logits,
+            &mut self.logits_processor,
+            &mut self.minp,
+            self.repeat_penalty,
+            self.repeat_last_n,
+            &self.tokens,
+        )?;
+        self.accept(token)
+    }
+
+    /// Завершает последовательность с ошибкой, видимой клиенту
+    fn fail(&mut self, error: &str) {
+        log::error!("batch engine: slot {} failed: {}", self.slot, error);
+        self.emitter.emit_error(error);
+        self.finished = true;
+    }
+
+    /// Последний сэмплированный токен (вход следующего шага)
+    fn next_token(&self) -> u32 {
+        self.tokens.last().copied().unwrap_or_default()
+    }
+}
+
+fn run(state: SharedState, rx: Receiver<BatchJob>, config: BatchConfig) {
+    let runtime = match tokio::runtime::Builder::new_current_thread().build() {
+        Ok(rt) => rt,
+        Err(e) => {
+            log::error!("batch engine: failed to create runtime: {}", e);
+            return;
+        }
+    };
+    let max_batch_size = config.max_batch_size.max(1);
+    let mut active: Vec<Sequence> = Vec::new();
+    let mut disconnected = false;
+
+    loop {
+        // Пустой батч: блокируемся до следующего запроса
+        if active.is_empty() {
+            if disconnected {
+                break;
+            }
+            match rx.recv() {
+                Ok(job) => admit(&state, job, &mut active, &runtime),
+                Err(_) => break,
+            }
+        }
+        // Подключаем новые запросы между шагами декодирования
+        while !disconnected && active.len() < max_batch_size {
+            match rx.try_recv() {
+                Ok(job) => admit(&state, job, &mut active, &runtime),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => disconnected = true,
+            }
+        }
+        // Отмена (общий флаг «Стоп» или по id) завершает последовательности до шага
+        for seq in active.iter_mut() {
+            if is_generation_cancelled(seq.request_id.as_deref()) {
+                seq.finished = true;
+            }
+        }
+        retire_finished(&state, &mut active, &runtime);
+        if active.is_empty() {
+            continue;
+        }
+
+        if let Err(e) = decode_step(&state, &mut active) {
+            for seq in active.iter_mut() {
+                seq.fail(&e);
+            }
+        }
+        retire_finished(&state, &mut active, &runtime);
+    }
+    log::info!("batch engine stopped");
+}
+
+/// Завершает и убирает из батча законченные последовательности
+fn retire_finished(
+    state: &SharedState,
+    active: &mut Vec<Sequence>,
+    runtime: &tokio::runtime::Runtime,
+) {
+    let (done, rest): (Vec<_>, Vec<_>) =
+        std::mem::take(active).into_iter().partition(|s| s.finished);
+    *active = rest;
+    for seq in done {
+        finish(state, seq, runtime);
+    }
+}
+
+/// Передаёт запрос последовательному пути в отдельном потоке
+fn run_sequential(
+    state: &SharedState,
+    request: GenerateRequest,
+    backend: Box<dyn EmissionBackend>,
+) {
+    let state = state.clone();
+    std::thread::spawn(move || {
+        if let Err(e) = generate_stream_with_backend(state, request, backend) {
+            log::error!("Generation failed: {}", e);
+        }
+    });
+}
+
+/// Prefill нового запроса в свободный слот
+fn admit(
+    state: &SharedState,
+    job: BatchJob,
+    active: &mut Vec<Sequence>,
+    runtime: &tokio::runtime::Runtime,
+) {
+    let BatchJob { request, backend } = job;
+    let supported = state.lock().ok().is_some_and(|guard| {
+        guard
+            .scheduler
+            .active_model
+            .as_ref()
+            .is_some_and(|entry| entry.model.supports_batching())
+    });
+    if !supported {
+        // Модель сменилась на не поддерживающую слоты: последовательный путь
+        run_sequential(state, request, backend);
+        return;
+    }
+
+    let slot = (0..)
+        .find(|id| active.iter().all(|s| s.slot != *id))
+        .unwrap_or_default();
+    // Бэкенд переходит в последовательность только после успешного prefill
+    let mut backend = Some(backend);
+    match prefill(state, &request, &mut backend, slot) {
+        Ok(Prefill::Batched(seq)) => {
+            log_infer!(
+                "batch: admitted sequence into slot {} (active={})",
+                slot,
+                active.len() + 1
+            );
+            if seq.finished {
+                finish(state, *seq, runtime);
+            } else {
+                active.push(*seq);
+            }
+        }
+        Ok(Prefill::Sequential) => {
+            if let Some(backend) = backend {
+                run_sequential(state, request, backend);
+            }
+        }
+        Ok(Prefill::Cancelled) => {
+            if let Some(backend) = backend {
+                backend.emit(GenerationEvent::Done);
+            }
+        }
+        Err(e) => {
+            log::error!("batch engine: prefill failed: {}", e);
+            if let Some(backend) = backend {
+                backend.emit(GenerationEvent::Error(e));
+            }
+        }
+    }
+}
+
+/// Итог prefill нового запроса
+enum Prefill {
+    /// Последовательность подключена к батчу
+    Batched(Box<Sequence>),
+    /// Запросу нужен chunked prefill или сохранённый префикс: последовательный путь
+    Sequential,
+    /// Запрос отменён до prefill
+    Cancelled,
+}
+
+/// Нужен ли промпту последовательный путь: chunked prefill (с прогрессом и отменой
+/// между чанками) или переиспользование prefix cache
+fn needs_sequential_prefill(
+    req: &GenerateRequest,
+    prompt_len: usize,
+    supports_chunked: bool,
+    has_cached_prefix: bool,
+) -> bool {
+    let chunk_size = req
+        .prefill_chunk_size
+        .unwrap_or(DEFAULT_PREFILL_CHUNK)
+        .max(1);
+    has_cached_prefix || (supports_chunked && prompt_len > chunk_size)
+}
+
+fn prefill(
+    state: &SharedState,
+    req: &GenerateRequest,
+    backend: &mut Option<Box<dyn EmissionBackend>>,
+    slot: usize,
+) -> Result<Prefill, String> {
+    let mut guard = state.lock().map_err(|e| e.to_string())?;
+    let tokenizer = guard
+        .tokenizer
+        .clone()
+        .ok_or_else(|| "Model/tokenizer is not loaded".to_string())?;
+    let model_id = guard
+        .scheduler
+        .get_model_id()
+        .ok_or_else(|| "Model is not loaded".to_string())?;
+
+    let sampling_options = resolve_sampling_options(req);
+    let rendered = render_request_prompt(
+        req,
+        &tokenizer,
+        &guard.chat_template,
+        guard.context_length,
+        extract_bos_token_str(&tokenizer),
+        None,
+    )?;
+    let (prompt, prompt_limit) = (rendered.prompt, rendered.limit);
+    let starts_in_thinking = prompt.trim_end().ends_with("<think>");
+    let encoded = tokenizer
+        .encode(prompt, true)
+        .map_err(|e| e.to_string())?
+        .get_ids()
+        .to_vec();
+    let ctx_slice = ContextSlice::new(encoded, prompt_limit);
+    let supports_chunked = guard
+        .scheduler
+        .active_model
+        .as_ref()
+        .is_some_and(|entry| entry.model.supports_chunked_prefill());
+    if needs_sequential_prefill(
+        req,
+        ctx_slice.effective_context_tokens.len(),
+        supports_chunked,
+        guard
+            .prefix_cache
+            .has_reusable_prefix(&ctx_slice.effective_context_tokens),
+    ) {
+        log_infer!(
+            "batch: prompt of {} tokens goes to the sequential path",
+            ctx_slice.effective_context_tokens.len()
+        );
+        return Ok(Prefill::Sequential);
+    }
+    if is_generation_cancelled(req.request_id.as_deref()) {
+        clear_request_cancel(req.request_id.as_deref());
+        return Ok(Prefill::Cancelled);
+    }
+    if let Some(report) = rendered.report
+        && let Some(backend) = backend.as_ref()
+    {
+        backend.emit(GenerationEvent::ContextReport(report));
+    }
+    let context_slack = guard
+        .context_length
+        .saturating_sub(ctx_slice.base_context_len)
+        .saturating_sub(1);
+    let max_new_tokens = req
+        .max_new_tokens
+        .unwrap_or(context_slack)
+        .min(context_slack);
+
+    let stop_ids = extract_eos_ids(&tokenizer);
+    if stop_ids.is_empty() {
+        return Err("Tokenizer: unable to determine EOS/STOP ids".into());
+    }
+
+    let mut tracker = InferenceTracker::new(
+        ctx_slice.base_context_len,
+        guard.performance_monitor.clone(),
+    );
+    tracker.start_prefill();
+
+    let input = Tensor::new(ctx_slice.effective_context_tokens.as_slice(), &guard.device)
+        .map_err(|e| e.to_string())?
+        .unsqueeze(0)
+        .map_err(|e| e.to_string())?;
+    let logits = with_active_model(&mut guard.scheduler, |model| {
+        model.prefill_slot(slot, &input, 0)
+    })?;
+    drop(guard);
+
+    let logits = logits.squeeze(0).map_err(|e| e.to_string())?;
+    let (mut logits_processor, _) = build_logits_processor_from_options(&sampling_options);
+    let mut minp = MinPFilter::new(sampling_options.min_p, sampling_options.temperature);
+    let first = sample_logits(&logits, &mut logits_processor, &mut minp, None, 0, &[])?;
+    tracker.start_generation();
+
+    let backend = backend
+        .take()
+        .ok_or_else(|| "Emission backend is already in use".to_string())?;
+    let emitter = ChunkEmitter::new(backend);
+    emitter.emit_start();
+
+    let mut seq = Sequence {
+        slot,
+        request_id: req.request_id.clone(),
+        model_id,
+        tos: TokenOutputStream::new(tokenizer),
+        logits_processor,
+        minp,
+        repeat_penalty: sampling_options.repeat_penalty,
+        repeat_last_n: sampling_options.repeat_last_n,
+        stop_ids,
+        stop_sequences: req.stop_sequences.clone().unwrap_or_default(),
+        stop_text_buf: String::new(),
+        thinking_parser: if starts_in_thinking {
+            ThinkingParser::new_in_thinking_mode()
+        } else {
+            ThinkingParser::new()
+        },
+        emitter,
+        tracker,
+        tokens: Vec::new(),
+        position: ctx_slice.base_context_len,
+        max_new_tokens,
+        finished: max_new_tokens == 0,
+    };
+    if !seq.finished
+        && let Err(e) = seq.accept(first)
+    {
+        seq.fail(&e);
+    }
+    Ok(Prefill::Batched(Box::new(seq)))
+}
+
+/// Один шаг декодирования для всех активных последовательностей
+fn decode_step(state: &SharedState, active: &mut [Sequence]) -> Result<(), String> {
+    let tokens: Vec<u32> = active.iter().map(Sequence::next_token).collect();
+    let slots: Vec<usize> = active.iter().map(|s| s.slot).collect();
+    let positions: Vec<usize> = active.iter().map(|s| s.position).collect();
+
+    let logits = {
+        let mut guard = state.lock().map_err(|e| e.to_string())?;
+        let model_id = guard.scheduler.get_model_id();
+        if active
+            .iter()
+            .any(|s| model_id.as_deref() != Some(s.model_id.as_str()))
+        {
+            return Err("Model was unloaded or replaced during generation".into());
+        }
+        with_active_model(&mut guard.scheduler, |model| {
+            model.forward_batch(&tokens, &slots, &positions)
+        })?
+    };
+
+    // Ошибка семплирования касается только своей последовательности
+    for (i, seq) in active.iter_mut().enumerate() {
+        seq.position += 1;
+        let step = logits
+            .get(i)
+            .map_err(|e| e.to_string())
+            .and_then(|row| seq.sample_and_accept(&row));
+        if let Err(e) = step {
+            seq.fail(&e);
+        }
+    }
+    Ok(())
+}
+
+/// Завершает последовательность: освобождает слот, досылает текст и метрики
+fn finish(state: &SharedState, mut seq: Sequence, runtime: &tokio::runtime::Runtime) {
+    if let Ok(mut guard) = state.lock()
+        && guard.scheduler.get_model_id().as_deref() == Some(seq.model_id.as_str())
+        && let Some(entry) = guard.scheduler.active_model.as_mut()
+    {
+        entry.model.release_slot(seq.slot);
+    }
+
+    if let Ok(Some(rest)) = seq.tos.decode_rest() {
+        let chunk = seq.thinking_parser.process_token(&rest);
+        seq.emitter.emit_message(chunk);
+    }
+    let final_chunk = seq.thinking_parser.flush();
+    seq.emitter.emit_message(final_chunk);
+    seq.emitter.finalize();
+
+    let metrics = runtime.block_on(seq.tracker.finish());
+    log_infer!(
+        "batch: slot {} done, generated_tokens={}, tokens/sec={:.2}",
+        seq.slot,
+        metrics.generated_tokens,
+        metrics.tokens_per_second
+    );
+    seq.emitter.emit_metrics(metrics);
+    clear_request_cancel(seq.request_id.as_deref());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::state::ModelState;
+    use crate::models::ModelBackend;
+    use std::sync::{Arc, Mutex};
+
+    const TOKENIZER: &str = r#"{"version":"1.0","truncation":null,"padding":null,
+        "added_tokens":[{"id":1,"content":"</s>","single_word":false,"lstrip":false,
+        "rstrip":false,"normalized":false,"special":true}],"normalizer":null,
+        "pre_tokenizer":{"type":"Whitespace"},"post_processor":null,"decoder":null,
+        "model":{"type":"WordLevel","vocab":{"<unk>":0,"</s>":1,"hi":2},"unk_token":"<unk>"}}"#;
+
+    /// Модель со слотами, у которой prefill всегда падает
+    struct FailingPrefill;
+
+    impl ModelBackend for FailingPrefill {
+        fn forward(&mut self, _input: &Tensor, _pos: usize) -> candle::Result<Tensor> {
+            candle::bail!("forward is not used by the batch engine")
+        }
+
+        fn clear_kv_cache(&mut self) {}
+
+        fn model_type(&self) -> &str {
+            "failing-prefill"
+        }
+
+        fn vocab_size(&self) -> usize {
+            3
+        }
+
+        fn supports_batching(&self) -> bool {
+            true
+        }
+
+        fn prefill_slot(
+            &mut self,
+            _slot: usize,
+            _input: &Tensor,
+            _pos: usize,
+        ) -> candle::Result<Tensor> {
+            candle::bail!("slot prefill failed")
+        }
+    }
+
+    /// Собирает события генерации в канал
+    struct Capture(Sender<GenerationEvent>);
+
+    impl EmissionBackend for Capture {
+        fn emit(&self, event: GenerationEvent) {
+            let _ = self.0.send(event);
+        }
+    }
+
+    fn request(prompt: &str) -> GenerateRequest {
+        serde_json::from_value(serde_json::json!({
+            "prompt": prompt,
+            "repeat_last_n": 64,
+        }))
+        .expect("valid generate request")
+    }
+
+    #[test]
+    fn prefill_error_reaches_backend() {
+        let mut model_state = ModelState::new(candle::Device::Cpu);
+        model_state.tokenizer =
+            Some(tokenizers::Tokenizer::from_bytes(TOKENIZER.as_bytes()).expect("tokenizer"));
+        model_state
+            .scheduler
+            .load_model(Box::new(FailingPrefill), "failing".into());
+        let state: SharedState = Arc::new(Mutex::new(model_state));
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .expect("runtime");
+
+        let (tx, rx) = mpsc::channel();
+        let job = BatchJob {
+            request: request("hi hi"),
+            backend: Box::new(Capture(tx)),
+        };
+        let mut active = Vec::new();
+        admit(&state, job, &mut active, &runtime);
+
+        assert!(active.is_empty());
+        let events: Vec<_> = rx.try_iter().collect();
+        assert!(
+            matches!(events.as_slice(), [GenerationEvent::Error(msg)] if msg.contains("slot prefill failed")),
+            "{events:?}"
+        );
+    }
+
+    #[test]
+    fn long_prompts_and_cached_prefixes_go_sequential() {
+        let req = request("hi");
+        assert!(!needs_sequential_prefill(&req, 16, true, false));
+        assert!(needs_sequential_prefill(&req, 16, true, true));
+        assert!(needs_sequential_prefill(
+            &req,
+            DEFAULT_PREFILL_CHUNK + 1,
+            true,
+            false
+        ));
+        // Модель без chunked prefill получает весь промпт одним проходом в любом пути
+        assert!(!needs_sequential_prefill(
+            &req,
+            DEFAULT_PREFILL_CHUNK + 1,
+            false,
+            false
+        ));
+
+        let mut small_chunks = request("hi");
+        small_chunks.prefill_chunk_size = Some(8);
+        assert!(needs_sequential_prefill(&small_chunks, 16, true, false));
+    }
+}
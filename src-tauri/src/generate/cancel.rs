@@ -1,4 +1,7 @@
+use std::collections::HashMap;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
 
 // Глобальный флаг отмены генерации (разделяем с модулем stream)
 pub(crate) static CANCEL_GENERATION: AtomicBool = AtomicBool::new(false);
@@ -9,6 +12,52 @@ pub fn cancel_generation_cmd() -> Result<(), String> {
     Ok(())
 }
 
+// Точечная отмена: идентификаторы запросов (`GenerateRequest::request_id`)
+// с моментом отмены. Запрос снимает отметку по завершении; отметки запросов,
+// которые так и не запустились, удаляются через `CANCEL_TTL`.
+static CANCELLED_REQUESTS: LazyLock<Mutex<HashMap<String, Instant>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Сколько хранится отметка отмены запроса, который не дошёл до генерации
+const CANCEL_TTL: Duration = Duration::from_secs(10 * 60);
+
+pub fn cancel_generation_request_cmd(request_id: String) -> Result<(), String> {
+    log::info!("cancel_generation_request_cmd called for '{}'", request_id);
+    let mut cancelled = CANCELLED_REQUESTS.lock().map_err(|e| e.to_string())?;
+    let now = Instant::now();
+    prune_expired(&mut cancelled, now);
+    cancelled.insert(request_id, now);
+    Ok(())
+}
+
+fn prune_expired(cancelled: &mut HashMap<String, Instant>, now: Instant) {
+    cancelled.retain(|_, at| now.saturating_duration_since(*at) < CANCEL_TTL);
+}
+
+/// Проверяет отмену запроса: глобальный флаг или точечная отмена по id
+pub(crate) fn is_generation_cancelled(request_id: Option<&str>) -> bool {
+    CANCEL_GENERATION.load(Ordering::SeqCst) || is_request_cancelled(request_id)
+}
+
+/// Проверяет только точечную отмену по id
+pub(crate) fn is_request_cancelled(request_id: Option<&str>) -> bool {
+    request_id.is_some_and(|id| {
+        CANCELLED_REQUESTS
+            .lock()
+            .map(|cancelled| cancelled.contains_key(id))
+            .unwrap_or(false)
+    })
+}
+
+/// Снимает отметку точечной отмены (по завершении запроса)
+pub(crate) fn clear_request_cancel(request_id: Option<&str>) {
+    if let Some(id) = request_id
+        && let Ok(mut cancelled) = CANCELLED_REQUESTS.lock()
+    {
+        cancelled.remove(id);
+    }
+}
+
 // Глобальный флаг отмены загрузки модели
 pub(crate) static CANCEL_LOADING: AtomicBool = AtomicBool::new(false);
 
@@ -16,3 +65,19 @@ pub fn cancel_model_loading_cmd() -> Result<(), String> {
     CANCEL_LOADING.store(true, Ordering::SeqCst);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_cancel_marks_expire() {
+        let start = Instant::now();
+        let mut cancelled = HashMap::new();
+        cancelled.insert("old".to_string(), start);
+        cancelled.insert("fresh".to_string(), start + CANCEL_TTL);
+        prune_expired(&mut cancelled, start + CANCEL_TTL + Duration::from_secs(1));
+        assert!(!cancelled.contains_key("old"));
+        assert!(cancelled.contains_key("fresh"));
+    }
+}
@@ -30,6 +30,83 @@ impl ContextSlice {
     }
 }
 
+/// Context shift: сколько токенов закреплено в начале KV-кэша при сдвиге
+///
+/// Когда генерация упирается в `context_length`, из кэша отбрасывается половина
+/// незакреплённой части (как `n_keep` / `n_discard` в llama.cpp), а оставшиеся
+/// ключи ре-ротируются моделью (`ModelBackend::shift_kv_cache`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContextShift {
+    pub keep: usize,
+}
+
+impl ContextShift {
+    /// Минимум закреплённых токенов (BOS + attention sink)
+    pub const DEFAULT_KEEP: usize = 4;
+
+    /// Закрепляет системный промпт и не меньше `n_keep` первых токенов;
+    /// не более половины контекста, чтобы сдвигу было что отбрасывать
+    pub fn new(system_tokens: usize, n_keep: Option<usize>, context_length: usize) -> Self {
+        let keep = system_tokens
+            .max(n_keep.unwrap_or(Self::DEFAULT_KEEP))
+            .min(context_length / 2);
+        Self { keep }
+    }
+
+    /// Сколько токенов отбросить при заполненном кэше длиной `kv_len`
+    pub fn discard(&self, kv_len: usize) -> usize {
+        (kv_len.saturating_sub(self.keep) / 2).max(1)
+    }
+}
+
+/// Число токенов системного промпта в начале `context_tokens`
+///
+/// Рендерит только системные сообщения и возвращает длину общего префикса
+/// с токенами полного промпта (хвост шаблона вроде generation prompt не учитывается).
+pub fn system_prompt_tokens(
+    tokenizer: &Tokenizer,
+    chat_template: &Option<String>,
+    messages: &[ChatMessage],
+    bos_token: Option<String>,
+    context_tokens: &[u32],
+) -> usize {
+    let system_msgs: Vec<crate::core::prompt::ChatMessage> = messages
+        .iter()
+        .filter(|m| m.role == "system")
+        .map(|m| crate::core::prompt::ChatMessage {
+            role: m.role.clone(),
+            content: m.content.clone(),
+        })
+        .collect();
+    if system_msgs.is_empty() {
+        return 0;
+    }
+    let builder = PromptBuilder::new(chat_template.clone()).with_bos(bos_token);
+    let Ok(rendered) = builder.render_prompt(system_msgs) else {
+        return 0;
+    };
+    let Ok(encoded) = tokenizer.encode(rendered, true) else {
+        return 0;
+    };
+    encoded
+        .get_ids()
+        .iter()
+        .zip(context_tokens)
+        .take_while(|(a, b)| a == b)
+        .count()
+}
+
+/// Результат smart truncation: промпт и отброшенная история
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TruncatedPrompt {
+    /// Итоговый промпт
+    pub prompt: String,
+    /// Индексы (в исходном `messages`) отброшенных сообщений, по возрастанию
+    pub dropped: Vec<usize>,
+    /// Длина итогового промпта в токенах
+    pub prompt_tokens: usize,
+}
+
 /// Truncates the conversation to fit within the context limit.
 ///
 /// Strategy:
@@ -47,11 +124,30 @@ pub fn smart_truncate(
     bos_token: Option<String>,
     limit: usize,
 ) -> Result<String, String> {
+    smart_truncate_report(tokenizer, chat_template, messages, bos_token, limit).map(|t| t.prompt)
+}
+
+/// Same as [`smart_truncate`], but also reports which messages were dropped
+/// and the token length of the resulting prompt.
+pub fn smart_truncate_report(
+    tokenizer: &Tokenizer,
+    chat_template: &Option<String>,
+    messages: &[ChatMessage],
+    bos_token: Option<String>,
+    limit: usize,
+) -> Result<TruncatedPrompt, String> {
     if messages.is_empty() {
-        return Ok(String::new());
+        return Ok(TruncatedPrompt::default());
     }
 
     let builder = PromptBuilder::new(chat_template.clone()).with_bos(bos_token.clone());
+    let count_tokens = |p: &str| -> Result<usize, String> {
+        Ok(tokenizer
+            .encode(p, true)
+            .map_err(|e| e.to_string())?
+            .get_ids()
+            .len())
+    };
 
     // Connect core::types::ChatMessage to core::prompt::ChatMessage
     // They are identical in structure, but distinct types.
@@ -67,31 +163,40 @@ pub fn smart_truncate(
         .map(map_msg)
         .collect();
 
-    let other_msgs: Vec<crate::core::types::ChatMessage> = messages
+    // Indices of non-system messages in the original slice (for the drop report)
+    let other_indices: Vec<usize> = messages
         .iter()
-        .filter(|m| m.role != "system")
-        .cloned()
+        .enumerate()
+        .filter(|(_, m)| m.role != "system")
+        .map(|(i, _)| i)
         .collect();
+    let other_msgs: Vec<&ChatMessage> = other_indices.iter().map(|&i| &messages[i]).collect();
 
     // If no other messages, just return system prompt (or empty)
     if other_msgs.is_empty() {
-        return builder.render_prompt(system_msgs);
+        let prompt = builder.render_prompt(system_msgs)?;
+        let prompt_tokens = count_tokens(&prompt)?;
+        return Ok(TruncatedPrompt {
+            prompt,
+            dropped: Vec::new(),
+            prompt_tokens,
+        });
     }
 
     // 2. Measure System Prompt Overhead
     // We build a prompt with JUST system messages to see how much it takes.
     // Note: Some templates might add BOS/Extra for empty user msgs, but this is a good baseline.
     let sys_prompt_str = builder.render_prompt(system_msgs.clone())?;
-    let sys_tokens = tokenizer
-        .encode(sys_prompt_str.clone(), true)
-        .map_err(|e| e.to_string())?
-        .get_ids()
-        .len();
+    let sys_tokens = count_tokens(&sys_prompt_str)?;
 
     if sys_tokens >= limit {
         // Edge case: System prompt alone exceeds limit.
         // We return it anyway; ContextSlice calls later will handle hard clip.
-        return Ok(sys_prompt_str); // Or maybe standard prompt building
+        return Ok(TruncatedPrompt {
+            prompt: sys_prompt_str,
+            dropped: other_indices,
+            prompt_tokens: sys_tokens,
+        });
     }
 
     let remaining_budget = limit.saturating_sub(sys_tokens);
@@ -154,27 +259,45 @@ pub fn smart_truncate(
         let subset_others = &other_msgs[current_start..];
         let mut candidate_msgs = system_msgs.clone();
         let mapped_subset: Vec<crate::core::prompt::ChatMessage> =
-            subset_others.iter().map(map_msg).collect();
+            subset_others.iter().copied().map(map_msg).collect();
         candidate_msgs.extend(mapped_subset);
 
         let p = builder.render_prompt(candidate_msgs)?;
-        let encoded_len = tokenizer
-            .encode(p.clone(), true)
-            .map_err(|e| e.to_string())?
-            .len();
+        let encoded_len = count_tokens(&p)?;
 
-        if encoded_len <= limit {
-            // Fits!
-            return Ok(p);
-        } else {
-            // Too big. Drop one more oldest message.
-            if current_start < n - 1 {
-                current_start += 1;
-            } else {
-                // If we are at the last message and it still doesn't fit...
-                // We must return it (System + Last) and let hard truncation handle it.
-                return Ok(p);
-            }
+        // If we are at the last message and it still doesn't fit...
+        // We must return it (System + Last) and let hard truncation handle it.
+        if encoded_len <= limit || current_start >= n - 1 {
+            return Ok(TruncatedPrompt {
+                prompt: p,
+                dropped: other_indices[..current_start].to_vec(),
+                prompt_tokens: encoded_len,
+            });
         }
+        // Too big. Drop one more oldest message.
+        current_start += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn context_shift_keeps_system_prompt() {
+        let shift = ContextShift::new(120, None, 4096);
+        assert_eq!(shift.keep, 120);
+        assert_eq!(shift.discard(4096), (4096 - 120) / 2);
+    }
+
+    #[test]
+    fn context_shift_keep_is_bounded() {
+        assert_eq!(
+            ContextShift::new(0, None, 4096).keep,
+            ContextShift::DEFAULT_KEEP
+        );
+        assert_eq!(ContextShift::new(0, Some(32), 4096).keep, 32);
+        assert_eq!(ContextShift::new(3000, None, 4096).keep, 2048);
+        assert_eq!(ContextShift::new(0, None, 4096).discard(3), 1);
     }
 }
@@ -1,3 +1,4 @@
+use serde::Serialize;
 use std::time::{Duration, Instant};
 use tauri::Emitter; // Keep for TauriBackend
 
@@ -17,15 +18,47 @@ pub enum GenerationEvent {
     Token(String),          // Legacy raw token
     Message(StreamMessage), // Structured message (thinking + content)
     ToolCall(ToolCall),
-    // Variant removed
+    /// Generation failed; the message is meant for the client
+    Error(String),
     Metrics(InferenceMetrics),
     PromptDump(String),
+    PrefillProgress(PrefillProgress),
+    ContextReport(ContextReport),
     Done,
 }
 
+/// Прогресс chunked prefill
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct PrefillProgress {
+    /// Обработано токенов промпта
+    pub processed: usize,
+    /// Всего токенов промпта
+    pub total: usize,
+}
+
+/// Отчёт об усечении истории диалога под контекст
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ContextReport {
+    /// Индексы отброшенных сообщений (в `GenerateRequest::messages`)
+    pub dropped: Vec<usize>,
+    /// Отброшенная история заменена сводкой (rolling summary)
+    pub summarized: bool,
+    /// Длина промпта в токенах
+    pub prompt_tokens: usize,
+    /// Бюджет токенов на промпт (context_length минус резерв на генерацию)
+    pub budget: usize,
+    /// Полный размер контекста модели
+    pub context_length: usize,
+}
+
 /// Trait abstracting the destination of generation events
 pub trait EmissionBackend: Send {
     fn emit(&self, event: GenerationEvent);
+
+    /// Получатель больше не слушает события (например, клиент отключился)
+    fn is_closed(&self) -> bool {
+        false
+    }
 }
 
 /// Backend that emits events to Tauri frontend
@@ -56,6 +89,10 @@ impl EmissionBackend for TauriBackend {
                 log::debug!("[emit] tool_call: name={}", tc.function.name);
                 let _ = self.app.emit("tool_call", tc);
             }
+            GenerationEvent::Error(message) => {
+                log::error!("[emit] generation_error: {}", message);
+                let _ = self.app.emit("generation_error", message);
+            }
             GenerationEvent::Metrics(metrics) => {
                 log::debug!("[emit] inference_metrics");
                 let _ = self.app.emit("inference_metrics", metrics);
@@ -63,6 +100,13 @@ impl EmissionBackend for TauriBackend {
             GenerationEvent::PromptDump(dump) => {
                 let _ = self.app.emit("prompt_tokens_dump", dump);
             }
+            GenerationEvent::PrefillProgress(progress) => {
+                let _ = self.app.emit("prefill_progress", progress);
+            }
+            GenerationEvent::ContextReport(report) => {
+                log::debug!("[emit] context_report: dropped={}", report.dropped.len());
+                let _ = self.app.emit("context_report", report);
+            }
             GenerationEvent::Done => {
                 let _ = self.app.emit("token", "[DONE]"); // Legacy compatible
                 let _ = self.app.emit("message_done", ());
@@ -176,6 +220,18 @@ impl ChunkEmitter {
             .emit(GenerationEvent::ToolCall(tool_call.clone()));
     }
 
+    /// Emit a generation error after the text produced so far.
+    pub fn emit_error(&mut self, message: &str) {
+        self.flush();
+        self.backend
+            .emit(GenerationEvent::Error(message.to_string()));
+    }
+
+    /// Whether the receiving side has gone away.
+    pub fn is_closed(&self) -> bool {
+        self.backend.is_closed()
+    }
+
     /// Emit inference metrics.
     pub fn emit_metrics(&self, metrics: InferenceMetrics) {
         self.backend.emit(GenerationEvent::Metrics(metrics));
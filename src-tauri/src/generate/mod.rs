@@ -1,14 +1,17 @@
+pub mod batch;
 pub mod cancel;
 pub mod ctx;
 pub mod emit;
 pub mod grammar;
 pub mod minp;
+pub mod prompt_lookup;
 pub mod sampling;
 pub mod stream;
+pub mod summary;
 pub mod thinking_parser;
 pub mod tool_call_parser;
 
-pub use cancel::cancel_generation_cmd;
+pub use cancel::{cancel_generation_cmd, cancel_generation_request_cmd};
 pub use stream::generate_stream_cmd;
 // Back-compat re-export for tests/examples
 pub use stream::build_prompt_with_template;
@@ -0,0 +1,173 @@
+//! Prompt lookup decoding (n-gram speculative decoding без draft-модели)
+//!
+//! Для суммаризации, правки кода и RAG-ответов значительная часть вывода
+//! копируется из промпта. Вместо отдельной draft-модели ищем последние
+//! `n` сгенерированных токенов в контексте и предлагаем токены, которые
+//! следовали за найденным совпадением. Черновик проверяется одним
+//! forward-проходом модели (`ModelBackend::forward_all`).
+
+use serde::{Deserialize, Serialize};
+
+/// Параметры поиска черновиков
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PromptLookupConfig {
+    /// Максимальная длина n-граммы для поиска
+    pub max_ngram: usize,
+    /// Минимальная длина n-граммы для поиска
+    pub min_ngram: usize,
+    /// Максимальное число черновых токенов за шаг
+    pub num_draft_tokens: usize,
+}
+
+impl Default for PromptLookupConfig {
+    fn default() -> Self {
+        Self {
+            max_ngram: 3,
+            min_ngram: 1,
+            num_draft_tokens: 10,
+        }
+    }
+}
+
+/// Статистика принятия черновиков
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SpeculativeStats {
+    /// Количество шагов верификации (forward-проходов с черновиком)
+    pub draft_steps: usize,
+    /// Всего предложено черновых токенов
+    pub drafted_tokens: usize,
+    /// Принято черновых токенов
+    pub accepted_tokens: usize,
+}
+
+impl SpeculativeStats {
+    /// Доля принятых черновых токенов (0.0..=1.0)
+    pub fn acceptance_rate(&self) -> f64 {
+        if self.drafted_tokens == 0 {
+            0.0
+        } else {
+            self.accepted_tokens as f64 / self.drafted_tokens as f64
+        }
+    }
+}
+
+/// Генератор черновиков на основе n-грамм промпта
+#[derive(Debug, Clone)]
+pub struct PromptLookup {
+    config: PromptLookupConfig,
+    stats: SpeculativeStats,
+}
+
+impl PromptLookup {
+    pub fn new(config: PromptLookupConfig) -> Self {
+        Self {
+            config,
+            stats: SpeculativeStats::default(),
+        }
+    }
+
+    pub fn config(&self) -> &PromptLookupConfig {
+        &self.config
+    }
+
+    pub fn stats(&self) -> &SpeculativeStats {
+        &self.stats
+    }
+
+    /// Предлагает черновые токены-продолжения.
+    ///
+    /// Ищем последние `n` токенов `generated` (от `max_ngram` до `min_ngram`)
+    /// в `context`. Берём самое позднее совпадение и возвращаем до `limit`
+    /// токенов, следующих за ним. Пустой результат означает, что совпадений нет.
+    pub fn propose(&self, context: &[u32], generated: &[u32], limit: usize) -> Vec<u32> {
+        let limit = limit.min(self.config.num_draft_tokens);
+        if limit == 0 || generated.is_empty() {
+            return Vec::new();
+        }
+        let max_n = self.config.max_ngram.min(generated.len());
+        let min_n = self.config.min_ngram.max(1);
+        for n in (min_n..=max_n).rev() {
+            let pattern = &generated[generated.len() - n..];
+            if context.len() <= n {
+                continue;
+            }
+            // Последнее вхождение, за которым есть хотя бы один токен
+            let found = (0..context.len() - n)
+                .rev()
+                .find(|&start| &context[start..start + n] == pattern);
+            if let Some(start) = found {
+                let from = start + n;
+                let to = (from + limit).min(context.len());
+                return context[from..to].to_vec();
+            }
+        }
+        Vec::new()
+    }
+
+    /// Учитывает результат одного шага верификации
+    pub fn record(&mut self, drafted: usize, accepted: usize) {
+        self.stats.draft_steps += 1;
+        self.stats.drafted_tokens += drafted;
+        self.stats.accepted_tokens += accepted.min(drafted);
+    }
+}
+
+impl Default for PromptLookup {
+    fn default() -> Self {
+        Self::new(PromptLookupConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_propose_continuation_from_context() {
+        let pl = PromptLookup::default();
+        let context = vec![1u32, 2, 3, 4, 5, 6];
+        let drafts = pl.propose(&context, &[9, 2, 3], 3);
+        assert_eq!(drafts, vec![4, 5, 6]);
+    }
+
+    #[test]
+    fn test_propose_prefers_longest_ngram() {
+        let pl = PromptLookup::default();
+        // "3" встречается дважды, но "2 3" — только в начале
+        let context = vec![2u32, 3, 10, 11, 7, 3, 20, 21];
+        let drafts = pl.propose(&context, &[2, 3], 2);
+        assert_eq!(drafts, vec![10, 11]);
+    }
+
+    #[test]
+    fn test_propose_no_match() {
+        let pl = PromptLookup::default();
+        let drafts = pl.propose(&[1, 2, 3], &[42], 5);
+        assert!(drafts.is_empty());
+    }
+
+    #[test]
+    fn test_propose_respects_limits() {
+        let pl = PromptLookup::new(PromptLookupConfig {
+            max_ngram: 2,
+            min_ngram: 1,
+            num_draft_tokens: 2,
+        });
+        let context = vec![5u32, 6, 7, 8, 9];
+        assert_eq!(pl.propose(&context, &[5], 10), vec![6, 7]);
+        assert_eq!(pl.propose(&context, &[5], 1), vec![6]);
+        assert!(pl.propose(&context, &[5], 0).is_empty());
+    }
+
+    #[test]
+    fn test_acceptance_stats() {
+        let mut pl = PromptLookup::default();
+        pl.record(4, 3);
+        pl.record(2, 0);
+        let stats = pl.stats();
+        assert_eq!(stats.draft_steps, 2);
+        assert_eq!(stats.drafted_tokens, 6);
+        assert_eq!(stats.accepted_tokens, 3);
+        assert!((stats.acceptance_rate() - 0.5).abs() < f64::EPSILON);
+    }
+}
@@ -1,31 +1,47 @@
 use candle::{DType, Tensor};
 // use tauri::Emitter; // Removed
 
-use super::cancel::CANCEL_GENERATION;
+use super::cancel::{CANCEL_GENERATION, clear_request_cancel, is_generation_cancelled};
 use super::{
-    ctx::ContextSlice,
-    emit::{ChunkEmitter, EmissionBackend, GenerationEvent, TauriBackend},
+    ctx::{
+        ContextShift, ContextSlice, TruncatedPrompt, smart_truncate_report, system_prompt_tokens,
+    },
+    emit::{
+        ChunkEmitter, ContextReport, EmissionBackend, GenerationEvent, PrefillProgress,
+        TauriBackend,
+    },
     minp::MinPFilter,
+    prompt_lookup::PromptLookup,
     sampling::build_logits_processor_from_options,
+    summary::{SUMMARY_MAX_TOKENS, complete_greedy, summary_prompt, with_summary},
     thinking_parser::ThinkingParser,
     tool_call_parser::ToolCallParser,
 };
 use crate::core::attachments_text::gather_text_from_attachments;
 use crate::core::config::SamplingOptions;
+use crate::core::kv_session::{KvSession, model_fingerprint};
 use crate::core::performance::InferenceTracker;
 use crate::core::prompt::PromptBuilder;
-use crate::core::state::SharedState;
+use crate::core::scheduler::ModelScheduler;
+use crate::core::state::{ModelState, SharedState};
 use crate::core::token_output_stream::TokenOutputStream;
 use crate::core::tokenizer::{extract_bos_token_str, extract_eos_ids};
 use crate::core::types::{ChatMessage, GenerateRequest};
 
+use crate::models::ModelBackend;
 use crate::{log_infer, log_template_error};
+use std::collections::VecDeque;
 use std::sync::atomic::Ordering;
 use tracing_subscriber::prelude::*;
 // Мультимодальные вложения отключены
 
 use crate::generate::grammar::GrammarSampler; // Import
 
+/// Размер чанка prefill по умолчанию (токенов за один forward)
+pub(crate) const DEFAULT_PREFILL_CHUNK: usize = 512;
+/// Минимальный шаг (в токенах) между событиями PrefillProgress
+const PREFILL_PROGRESS_STEP: usize = 64;
+
 pub async fn generate_stream_cmd(
     app: tauri::AppHandle,
     state: tauri::State<'_, SharedState>,
@@ -62,6 +78,7 @@ pub fn generate_stream_with_backend(
         None
     };
     let mut guard = state.lock().map_err(|e| e.to_string())?;
+    let request_id = req.request_id.as_deref();
 
     // Check if model is loaded via scheduler
     if !guard.scheduler.has_model() || guard.tokenizer.is_none() {
@@ -73,108 +90,63 @@ pub fn generate_stream_with_backend(
     let bos_opt = extract_bos_token_str(&tokenizer);
     let mut tos = TokenOutputStream::new(tokenizer);
 
-    // Дефолты семплинга не зависят от режима размышлений.
-    let (def_temp, def_top_p, def_min_p, def_top_k) =
-        (0.7_f64, Some(0.9_f64), Some(0.0_f64), Some(20_usize));
-    // Вычисляем эффективные значения. Если пользовательские параметры включены,
-    // не используем дефолты, а берём только переданные параметры; для температуры
-    // нейтральным значением считаем 1.0.
-    let temperature: f64 = if req.use_custom_params {
-        req.temperature.unwrap_or(1.0_f64)
-    } else {
-        def_temp
-    };
-    let top_p: Option<f64> = if req.use_custom_params {
-        req.top_p
-    } else {
-        def_top_p
-    };
-    let top_k: Option<usize> = if req.use_custom_params {
-        req.top_k
-    } else {
-        def_top_k
-    };
-    let min_p: Option<f64> = if req.use_custom_params {
-        req.min_p
-    } else {
-        def_min_p
-    };
-    // Включаем лёгкий repeat_penalty по умолчанию только когда пользовательские параметры выключены
-    let repeat_penalty: Option<f32> = if req.use_custom_params {
-        req.repeat_penalty
-    } else {
-        Some(1.1_f32)
-    };
-    log_infer!(
-        "request: prompt_len={}, temperature={:.3}, top_k={:?}, top_p={:?}, min_p={:?}, repeat_penalty={:?}, repeat_last_n={}, use_custom_params={}",
-        req.prompt.len(),
-        temperature,
-        top_k,
-        top_p,
-        min_p,
-        repeat_penalty,
-        req.repeat_last_n,
-        req.use_custom_params
-    );
+    let sampling_options = resolve_sampling_options(&req);
+    let temperature = sampling_options.temperature;
+    let min_p = sampling_options.min_p;
+    let repeat_penalty = sampling_options.repeat_penalty;
     backend.emit(GenerationEvent::Token(String::new())); // Keep this direct emit for now as it's separate from generation loop
 
-    // Текстовые вложения (.txt/.md): читаем и подмешиваем в последний user или в prompt
-    let mut msgs = req.messages.clone();
-    let mut prompt_str = req.prompt.clone();
-    if let Some(attachments) = req.attachments.as_ref() {
-        let combined = gather_text_from_attachments(attachments).map_err(|e| e.to_string())?;
-        if !combined.is_empty() {
-            if let Some(ref mut m) = msgs {
-                if let Some(last) = m.last_mut() {
-                    if last.role.to_lowercase() == "user" {
-                        last.content = format!("{}\n\n{}", last.content, combined);
-                    } else {
-                        m.push(ChatMessage {
-                            role: "user".into(),
-                            content: combined,
-                        });
-                    }
-                } else {
-                    m.push(ChatMessage {
-                        role: "user".into(),
-                        content: combined,
-                    });
-                }
-            } else if !prompt_str.is_empty() {
-                prompt_str = format!("{}\n\n{}", prompt_str, combined);
-            } else {
-                prompt_str = combined;
-            }
-        }
-    }
-
-    // Determine limit for prompt: context_length - reservation
-    // This ensures we always have space for generation.
-    let reserve_default = 512;
-    let limit_reserve = (guard.context_length as f64 * 0.4) as usize;
-    let generation_reserve = req
-        .max_new_tokens
-        .unwrap_or(reserve_default)
-        .min(limit_reserve)
-        .max(64);
-    let prompt_limit = guard
-        .context_length
-        .saturating_sub(generation_reserve)
-        .max(1);
-
-    // Ollama-style "smart" truncation via ctx::smart_truncate
-    let prompt = if let Some(messages) = msgs {
-        use crate::generate::ctx::smart_truncate;
-        smart_truncate(
-            tos.tokenizer(),
-            &guard.chat_template,
-            &messages,
+    // Rolling summary: отброшенная история сжимается загруженной моделью
+    let rendered = {
+        let state = &mut *guard;
+        let tokenizer = tos.tokenizer();
+        let session_id = req.session_id.as_deref();
+        let mut summarize = |dropped: &[ChatMessage]| {
+            state
+                .summary_cache
+                .summarize(session_id, dropped, |previous, messages| {
+                    let prompt =
+                        summary_prompt(&state.chat_template, bos_opt.clone(), previous, messages)?;
+                    // Служебная генерация перезаписывает KV-кэш модели
+                    state.prefix_cache.clear();
+                    state.restored_session = None;
+                    complete_greedy(
+                        &mut state.scheduler,
+                        tokenizer,
+                        &state.device,
+                        &prompt,
+                        SUMMARY_MAX_TOKENS,
+                        state.context_length,
+                    )
+                })
+        };
+        let summarizer: Option<Summarizer<'_>> = if req.rolling_summary.unwrap_or(false) {
+            Some(&mut summarize)
+        } else {
+            None
+        };
+        render_request_prompt(
+            &req,
+            tokenizer,
+            &state.chat_template,
+            state.context_length,
             bos_opt.clone(),
-            prompt_limit,
+            summarizer,
         )?
-    } else {
-        prompt_str
     };
+    if let Some(report) = rendered.report {
+        if !report.dropped.is_empty() {
+            log_infer!(
+                "context report: dropped {} messages, summarized={}, prompt_tokens={}/{}",
+                report.dropped.len(),
+                report.summarized,
+                report.prompt_tokens,
+                report.budget
+            );
+        }
+        backend.emit(GenerationEvent::ContextReport(report));
+    }
+    let (prompt, prompt_limit) = (rendered.prompt, rendered.limit);
 
     // Detect implicit thinking: if prompt ends with <think>, start parser in thinking mode
     let starts_in_thinking = prompt.trim_end().ends_with("<think>");
@@ -234,19 +206,61 @@ pub fn generate_stream_with_backend(
         .context_length
         .saturating_sub(ctx_slice.base_context_len)
         .saturating_sub(1);
-    let to_sample_soft_cap = req
-        .max_new_tokens
-        .unwrap_or(context_slack)
-        .min(context_slack);
-    let seed: u64 = req.seed.unwrap_or(42);
-    let sampling_options = SamplingOptions {
-        temperature,
-        top_k,
-        top_p,
-        min_p,
-        seed: Some(seed),
-        repeat_penalty,
-        repeat_last_n: req.repeat_last_n,
+
+    // Потоковый режим внимания: модель сама вытесняет KV-кэш (attention sinks + окно),
+    // поэтому генерация не ограничена context_slack
+    let streaming = guard
+        .scheduler
+        .active_model
+        .as_ref()
+        .and_then(|entry| entry.model.streaming_window());
+    if let Some(window) = streaming {
+        log_infer!(
+            "streaming attention: sink_tokens={}, window={}",
+            window.sink_tokens,
+            window.window
+        );
+    }
+
+    // Context shift: при заполнении KV-кэша отбрасываем старейшие токены
+    // (кроме системного промпта) вместо остановки на context_slack
+    let context_shift = if streaming.is_some() {
+        None
+    } else if req.context_shift.unwrap_or(false) {
+        let supported = guard
+            .scheduler
+            .active_model
+            .as_ref()
+            .is_some_and(|entry| entry.model.supports_context_shift());
+        if supported {
+            let system_tokens = req.messages.as_deref().map_or(0, |msgs| {
+                system_prompt_tokens(
+                    tos.tokenizer(),
+                    &guard.chat_template,
+                    msgs,
+                    bos_opt.clone(),
+                    &effective_context_tokens,
+                )
+            });
+            let shift =
+                ContextShift::new(system_tokens, req.context_shift_keep, guard.context_length);
+            log_infer!("context shift enabled: keep={}", shift.keep);
+            Some(shift)
+        } else {
+            log_infer!("context shift requested but not supported by model, ignoring");
+            None
+        }
+    } else {
+        None
+    };
+    let to_sample_soft_cap = match (context_shift, streaming) {
+        (None, None) => req
+            .max_new_tokens
+            .unwrap_or(context_slack)
+            .min(context_slack),
+        // Потоковый режим вытесняет KV окном: без max_new_tokens сессия не ограничена
+        (_, Some(_)) => req.max_new_tokens.unwrap_or(usize::MAX),
+        (Some(_), None) => req.max_new_tokens.unwrap_or(context_slack),
     };
     let (mut logits_processor, sampling_desc) =
         build_logits_processor_from_options(&sampling_options);
@@ -269,85 +283,108 @@ pub fn generate_stream_with_backend(
         log_infer!("prefix cache MISS");
     }
 
+    // Восстановленная сессия действует только для ближайшего запроса
+    let restored_session = guard.restored_session.take();
+    guard.kv_prompt_tokens.clear();
+    let recurrent = guard
+        .scheduler
+        .active_model
+        .as_ref()
+        .is_some_and(|entry| entry.model.is_recurrent());
+    // Сколько токенов промпта уже лежит в KV-кэше (сессия или disk tier).
+    // Состояние рекуррентной модели после prefix hit включает ответ, его нужно восстановить
+    let restored = if prefix_hit && !recurrent {
+        0
+    } else {
+        restore_prompt_state(&mut guard, &effective_context_tokens, restored_session)
+    };
+
     // Сбрасываем KV-кэш ТОЛЬКО если нет prefix match
     // При prefix hit модель должна сохранить KV-кэш с предыдущего запроса
-    if !prefix_hit && let Some(entry) = guard.scheduler.active_model.as_mut() {
+    if !prefix_hit
+        && restored == 0
+        && let Some(entry) = guard.scheduler.active_model.as_mut()
+    {
         entry.model.clear_kv_cache();
     }
 
     // Начинаем prefill
     inference_tracker.start_prefill();
 
-    let mut next_token = {
-        let split_prompt = req.split_prompt.unwrap_or(false);
-        let do_batched = !split_prompt && effective_context_tokens.len() > 8;
-        if do_batched {
-            let input = Tensor::new(effective_context_tokens.as_slice(), &guard.device)
-                .map_err(|e| e.to_string())?
-                .unsqueeze(0)
-                .map_err(|e| e.to_string())?;
-            let logits = match guard.scheduler.take_model() {
-                Some(mut entry) => {
-                    let res = entry.model.forward_layered(&input, 0);
-                    match res {
-                        Ok(v) => {
-                            guard.scheduler.restore_model(entry);
-                            v
-                        }
-                        Err(e) => {
-                            guard.scheduler.restore_model(entry);
-                            return Err(e.to_string());
-                        }
-                    }
-                }
-                _ => {
-                    return Err("Model is not loaded".into());
-                }
-            };
-            let logits = logits.squeeze(0).map_err(|e| e.to_string())?;
-            // Convert to F32 for sampling (like candle examples)
-            let logits = logits.to_dtype(DType::F32).map_err(|e| e.to_string())?;
-            let logits = minp.apply(&logits)?;
-            logits_processor
-                .sample(&logits)
-                .map_err(|e| e.to_string())?
-        } else {
-            let mut last_logits_opt: Option<Tensor> = None;
-            for (i, &tok) in effective_context_tokens.iter().enumerate() {
-                let input = Tensor::new(&[tok], &guard.device)
-                    .map_err(|e| e.to_string())?
-                    .unsqueeze(0)
-                    .map_err(|e| e.to_string())?;
-                let logits = match guard.scheduler.take_model() {
-                    Some(mut entry) => {
-                        let res = entry.model.forward_layered(&input, i);
-                        match res {
-                            Ok(v) => {
-                                guard.scheduler.restore_model(entry);
-                                v
-                            }
-                            Err(e) => {
-                                guard.scheduler.restore_model(entry);
-                                return Err(e.to_string());
-                            }
-                        }
-                    }
-                    _ => {
-                        return Err("Model is not loaded".into());
-                    }
-                };
-                last_logits_opt = Some(logits);
+    let split_prompt = req.split_prompt.unwrap_or(false);
+    let do_batched = !split_prompt && effective_context_tokens.len() > 8;
+    let supports_chunked = guard
+        .scheduler
+        .active_model
+        .as_ref()
+        .is_some_and(|entry| entry.model.supports_chunked_prefill());
+    let total_prompt = effective_context_tokens.len();
+    // Модели без поддержки смещённой causal-маски получают весь промпт одним проходом
+    let chunk_size = if !do_batched {
+        1
+    } else if supports_chunked {
+        req.prefill_chunk_size
+            .unwrap_or(DEFAULT_PREFILL_CHUNK)
+            .max(1)
+    } else {
+        total_prompt
+    };
+    log_infer!(
+        "prefill: {} tokens, chunk_size={}",
+        total_prompt,
+        chunk_size
+    );
+
+    // Рекуррентная модель снимает состояние перед последним токеном промпта:
+    // после генерации откатиться к промпту уже нельзя
+    let snapshot_at = if recurrent {
+        total_prompt.saturating_sub(1).max(restored)
+    } else {
+        total_prompt
+    };
+    let (head, tail) = effective_context_tokens.split_at(snapshot_at);
+
+    let mut last_logits: Option<Tensor> = None;
+    let mut processed = restored;
+    let mut reported = restored;
+    for chunk in head[restored..]
+        .chunks(chunk_size)
+        .chain(tail.chunks(chunk_size))
+    {
+        if is_generation_cancelled(request_id) {
+            log_infer!("cancelled during prefill at {}/{}", processed, total_prompt);
+            if let Some(entry) = guard.scheduler.active_model.as_mut() {
+                entry.model.clear_kv_cache();
             }
-            let logits = last_logits_opt.ok_or_else(|| "Empty context".to_string())?;
-            let logits = logits.squeeze(0).map_err(|e| e.to_string())?;
-            // Convert to F32 for sampling (like candle examples)
-            let logits = logits.to_dtype(DType::F32).map_err(|e| e.to_string())?;
-            let logits = minp.apply(&logits)?;
-            logits_processor
-                .sample(&logits)
-                .map_err(|e| e.to_string())?
+            clear_request_cancel(request_id);
+            backend.emit(GenerationEvent::Done);
+            return Ok(());
         }
-    };
+        if recurrent && processed == snapshot_at && processed > 0 {
+            snapshot_recurrent_prompt(&mut guard, &effective_context_tokens[..processed]);
+        }
+        let input = Tensor::new(chunk, &guard.device)
+            .map_err(|e| e.to_string())?
+            .unsqueeze(0)
+            .map_err(|e| e.to_string())?;
+        let logits = with_active_model(&mut guard.scheduler, |model| {
+            model.forward_layered(&input, processed)
+        })?;
+        processed += chunk.len();
+        last_logits = Some(logits);
+        if total_prompt > chunk_size
+            && (processed - reported >= PREFILL_PROGRESS_STEP || processed == total_prompt)
+        {
+            reported = processed;
+            backend.emit(GenerationEvent::PrefillProgress(PrefillProgress {
+                processed,
+                total: total_prompt,
+            }));
+        }
+    }
+    let logits = last_logits.ok_or_else(|| "Empty context".to_string())?;
+    let logits = logits.squeeze(0).map_err(|e| e.to_string())?;
+    let mut next_token = sample_logits(&logits, &mut logits_processor, &mut minp, None, 0, &[])?;
 
     // Начинаем generation
     inference_tracker.start_generation();
@@ -419,65 +456,142 @@ pub fn generate_stream_with_backend(
     }
     let eos_token = stop_ids[0];
 
+    // Prompt lookup decoding: только для моделей с forward_all + откатом KV-кэша
+    let mut prompt_lookup = if req.prompt_lookup.unwrap_or(false) {
+        let supported = guard
+            .scheduler
+            .active_model
+            .as_ref()
+            .is_some_and(|entry| entry.model.supports_speculative());
+        if supported {
+            log_infer!("prompt lookup decoding enabled");
+            Some(PromptLookup::default())
+        } else {
+            log_infer!("prompt lookup decoding requested but not supported by model, ignoring");
+            None
+        }
+    } else {
+        None
+    };
+
+    if let Some(rp) = repeat_penalty
+        && (rp - 1.0).abs() > f32::EPSILON
+    {
+        log_infer!(
+            "repeat_penalty enabled: value={:.3}, last_n={}",
+            rp,
+            req.repeat_last_n
+        );
+    }
+
     let mut all_tokens: Vec<u32> = vec![next_token];
     let mut stop_text_buf = String::new();
+    // Токены, уже проверенные моделью за один проход (принятые черновики)
+    let mut pending_tokens: VecDeque<u32> = VecDeque::new();
+    // Позиция KV-кэша: сколько токенов уже обработано моделью
+    let mut kv_pos = ctx_slice.base_context_len;
+    let mut context_shifted = false;
     for index in 0..to_sample_soft_cap {
         let _span = tracing::info_span!("decode", index).entered();
-        if CANCEL_GENERATION.load(Ordering::SeqCst) {
+        if is_generation_cancelled(request_id) {
             log_infer!("cancelled by user");
             break;
         }
-        let input = Tensor::new(&[next_token], &guard.device)
-            .map_err(|e| e.to_string())?
-            .unsqueeze(0)
-            .map_err(|e| e.to_string())?;
-        let logits = match guard.scheduler.take_model() {
-            Some(mut entry) => {
-                let res = entry
-                    .model
-                    .forward_layered(&input, ctx_slice.base_context_len + index);
-                match res {
-                    Ok(v) => {
-                        guard.scheduler.restore_model(entry);
-                        v
-                    }
-                    Err(e) => {
-                        guard.scheduler.restore_model(entry);
-                        return Err(e.to_string());
-                    }
-                }
-            }
-            _ => {
-                return Err("Model is not loaded".into());
-            }
+        let drafts = match (&prompt_lookup, pending_tokens.is_empty()) {
+            (Some(pl), true) => pl.propose(
+                &effective_context_tokens,
+                &all_tokens,
+                to_sample_soft_cap - index - 1,
+            ),
+            _ => Vec::new(),
         };
-        let logits = logits.squeeze(0).map_err(|e| e.to_string())?;
-        // Convert to F32 for sampling (like candle examples)
-        let mut logits = logits.to_dtype(DType::F32).map_err(|e| e.to_string())?;
-        if let Some(rp) = repeat_penalty
-            && (rp - 1.0).abs() > f32::EPSILON
+        // Context shift: освобождаем место в KV-кэше перед очередным forward
+        if let Some(shift) = context_shift
+            && pending_tokens.is_empty()
+            && kv_pos + drafts.len() + 1 > guard.context_length
         {
-            let start_at = all_tokens.len().saturating_sub(req.repeat_last_n);
-            let penalty_tokens = &all_tokens[start_at..];
-            // Only apply penalty if we have tokens to penalize (avoids shape mismatch with empty slice)
-            if !penalty_tokens.is_empty() {
-                if index == 0 {
-                    log_infer!(
-                        "repeat_penalty enabled: value={:.3}, last_n={}, applying to {} tokens",
-                        rp,
-                        req.repeat_last_n,
-                        penalty_tokens.len()
-                    );
+            let discard = shift.discard(kv_pos);
+            let shifted = with_active_model(&mut guard.scheduler, |model| {
+                Ok(model.shift_kv_cache(shift.keep, discard))
+            })?;
+            if !shifted {
+                return Err("context shift: failed to shift KV cache".into());
+            }
+            kv_pos -= discard;
+            context_shifted = true;
+            log_infer!(
+                "context shift: kept {}, discarded {}, kv_pos={}",
+                shift.keep,
+                discard,
+                kv_pos
+            );
+        }
+        if let Some(tok) = pending_tokens.pop_front() {
+            next_token = tok;
+        } else if drafts.is_empty() {
+            let input = Tensor::new(&[next_token], &guard.device)
+                .map_err(|e| e.to_string())?
+                .unsqueeze(0)
+                .map_err(|e| e.to_string())?;
+            let logits = with_active_model(&mut guard.scheduler, |model| {
+                model.forward_layered(&input, kv_pos)
+            })?;
+            kv_pos += 1;
+            let logits = logits.squeeze(0).map_err(|e| e.to_string())?;
+            next_token = sample_logits(
+                &logits,
+                &mut logits_processor,
+                &mut minp,
+                repeat_penalty,
+                req.repeat_last_n,
+                &all_tokens,
+            )?;
+        } else {
+            // Верифицируем [next_token, drafts...] одним проходом
+            let mut input_ids = Vec::with_capacity(drafts.len() + 1);
+            input_ids.push(next_token);
+            input_ids.extend_from_slice(&drafts);
+            let input = Tensor::new(input_ids.as_slice(), &guard.device)
+                .map_err(|e| e.to_string())?
+                .unsqueeze(0)
+                .map_err(|e| e.to_string())?;
+            let logits = with_active_model(&mut guard.scheduler, |model| {
+                model.forward_all(&input, kv_pos)
+            })?;
+            let mut history = all_tokens.clone();
+            let mut verified: Vec<u32> = Vec::with_capacity(input_ids.len());
+            for i in 0..input_ids.len() {
+                let row = logits.get(i).map_err(|e| e.to_string())?;
+                let tok = sample_logits(
+                    &row,
+                    &mut logits_processor,
+                    &mut minp,
+                    repeat_penalty,
+                    req.repeat_last_n,
+                    &history,
+                )?;
+                verified.push(tok);
+                history.push(tok);
+                if drafts.get(i) != Some(&tok) {
+                    break;
                 }
-                logits =
-                    candle_transformers::utils::apply_repeat_penalty(&logits, rp, penalty_tokens)
-                        .map_err(|e| e.to_string())?;
             }
+            let accepted = verified.len() - 1;
+            kv_pos += 1 + accepted;
+            // Откатываем KV-кэш отклонённых черновиков
+            let truncated = with_active_model(&mut guard.scheduler, |model| {
+                Ok(model.truncate_kv_cache(kv_pos))
+            })?;
+            if !truncated {
+                return Err("prompt lookup: failed to truncate KV cache".into());
+            }
+            if let Some(pl) = prompt_lookup.as_mut() {
+                pl.record(drafts.len(), accepted);
+            }
+            let mut verified = verified.into_iter();
+            next_token = verified.next().ok_or("prompt lookup: empty verification")?;
+            pending_tokens.extend(verified);
         }
-        let logits = minp.apply(&logits)?;
-        next_token = logits_processor
-            .sample(&logits)
-            .map_err(|e| e.to_string())?;
         all_tokens.push(next_token);
         inference_tracker.increment_generated_tokens();
 
@@ -521,16 +635,7 @@ pub fn generate_stream_with_backend(
                 }
             }
             emitter.emit_message(chunk);
-            stop_text_buf.push_str(&t);
-            if stop_text_buf.len() > 128 {
-                let mut cut = stop_text_buf.len() - 128;
-                while cut < stop_text_buf.len() && !stop_text_buf.is_char_boundary(cut) {
-                    cut += 1;
-                }
-                if cut > 0 && cut <= stop_text_buf.len() {
-                    let _ = stop_text_buf.drain(..cut);
-                }
-            }
+            push_stop_window(&mut stop_text_buf, &t);
 
             // Check user-provided stop sequences first
             if req
@@ -543,16 +648,24 @@ pub fn generate_stream_with_backend(
             }
 
             // Fallback to hardcoded EOS sequences
-            if stop_text_buf.contains("<end_of_turn>")
-                || stop_text_buf.contains("<|end_of_turn|>")
-                || stop_text_buf.contains("<|eot_id|>")
-                || stop_text_buf.contains("</s>")
-            {
+            if STOP_MARKERS.iter().any(|m| stop_text_buf.contains(m)) {
                 break;
             }
         }
     }
 
+    if let Some(pl) = prompt_lookup.take() {
+        let stats = pl.stats().clone();
+        log_infer!(
+            "prompt lookup: steps={}, drafted={}, accepted={}, acceptance={:.1}%",
+            stats.draft_steps,
+            stats.drafted_tokens,
+            stats.accepted_tokens,
+            stats.acceptance_rate() * 100.0
+        );
+        inference_tracker.set_speculative_stats(stats);
+    }
+
     if let Some(rest) = tos.decode_rest().map_err(|e| e.to_string())? {
         let chunk = thinking_parser.process_token(&rest);
         emitter.emit_message(chunk);
@@ -562,18 +675,31 @@ pub fn generate_stream_with_backend(
     emitter.emit_message(final_chunk);
     emitter.finalize();
 
+    // В потоковом режиме модель вытеснила часть KV-кэша, если он превысил окно
+    let streaming_evicted = streaming.is_some_and(|window| kv_pos > window.capacity());
+
     // ============ Prefix Cache: сохраняем позицию ============
-    // Сохраняем prompt tokens и позицию KV-кэша для будущих запросов
-    // kv_position = количество токенов промпта (без сгенерированных)
-    let kv_position = effective_context_tokens.len();
-    guard
-        .prefix_cache
-        .insert(&effective_context_tokens, kv_position);
-    log_infer!(
-        "prefix cache INSERT: {} tokens at position {}",
-        effective_context_tokens.len(),
-        kv_position
-    );
+    if context_shifted || streaming_evicted {
+        // После сдвига/вытеснения KV-кэш не соответствует промпту: переиспользовать его нельзя
+        guard.prefix_cache.clear();
+        if let Some(entry) = guard.scheduler.active_model.as_mut() {
+            entry.model.clear_kv_cache();
+        }
+    } else {
+        // Сохраняем prompt tokens и позицию KV-кэша для будущих запросов
+        // kv_position = количество токенов промпта (без сгенерированных)
+        let kv_position = effective_context_tokens.len();
+        guard
+            .prefix_cache
+            .insert(&effective_context_tokens, kv_position);
+        log_infer!(
+            "prefix cache INSERT: {} tokens at position {}",
+            effective_context_tokens.len(),
+            kv_position
+        );
+        guard.kv_prompt_tokens = effective_context_tokens.clone();
+        persist_prompt_state(&state, &mut guard, &effective_context_tokens);
+    }
 
     // НЕ очищаем KV-кэш после запроса если prefix cache включён
     // Это позволяет переиспользовать KV-кэш для следующего запроса
@@ -581,6 +707,7 @@ pub fn generate_stream_with_backend(
         && let Some(entry) = guard.scheduler.active_model.as_mut()
     {
         entry.model.clear_kv_cache();
+        guard.kv_prompt_tokens.clear();
     }
 
     // Финализируем метрики inference - используем существующий runtime если доступен
@@ -605,10 +732,443 @@ pub fn generate_stream_with_backend(
 
     // Отправляем метрики на фронтенд
     emitter.emit_metrics(inference_metrics);
+    clear_request_cancel(request_id);
 
     Ok(())
 }
 
+/// Подгружает в KV-кэш уже посчитанный префикс промпта
+///
+/// Источники: сессия, восстановленная командой `restore_kv_session`, или файл
+/// disk tier prefix cache. Возвращает число токенов в KV-кэше (0 — prefill с нуля).
+/// Хотя бы один токен промпта всегда остаётся для prefill, чтобы получить logits.
+fn restore_prompt_state(
+    state: &mut ModelState,
+    tokens: &[u32],
+    restored_session: Option<Vec<u32>>,
+) -> usize {
+    let supported = state.scheduler.active_model.as_ref().is_some_and(|entry| {
+        (entry.model.supports_kv_snapshot() || entry.model.is_recurrent())
+            && entry.model.supports_chunked_prefill()
+    });
+    if !supported {
+        return 0;
+    }
+    if let Some(session) = restored_session
+        && tokens.len() > session.len()
+        && tokens.starts_with(&session)
+    {
+        log_infer!("KV session reused: {} tokens", session.len());
+        return session.len();
+    }
+    if let Some(n) = restore_recurrent_prompt(state, tokens) {
+        return n;
+    }
+
+    let Some(disk_match) = state.prefix_cache.match_disk(tokens) else {
+        return 0;
+    };
+    let Some(fingerprint) = model_fingerprint(state) else {
+        return 0;
+    };
+    let restored = KvSession::load(&disk_match.path, &state.device).and_then(|mut session| {
+        if !tokens.starts_with(&session.tokens) {
+            return Err("prompt cache file does not match its prompt".to_string());
+        }
+        if session.is_recurrent() && session.tokens.len() >= tokens.len() {
+            // Состояние нельзя откатить: промпт целиком совпадает с записью
+            return Ok(0);
+        }
+        session.truncate(session.tokens.len().min(tokens.len() - 1))?;
+        if session.tokens.is_empty() {
+            return Ok(0);
+        }
+        let entry = state
+            .scheduler
+            .active_model
+            .as_mut()
+            .ok_or_else(|| "Model is not loaded".to_string())?;
+        session.restore(entry.model.as_mut(), &fingerprint)?;
+        Ok(session.tokens.len())
+    });
+    match restored {
+        Ok(n) => {
+            log_infer!("prompt cache disk HIT: {} tokens", n);
+            n
+        }
+        Err(e) => {
+            log_infer!("prompt cache disk entry rejected: {}", e);
+            state.prefix_cache.remove_disk(&disk_match.path);
+            0
+        }
+    }
+}
+
+/// Восстанавливает снимок рекуррентной модели после предыдущего промпта
+///
+/// Возвращает число восстановленных токенов, если промпт продолжает снимок.
+fn restore_recurrent_prompt(state: &mut ModelState, tokens: &[u32]) -> Option<usize> {
+    if !state.prefix_cache.enabled() {
+        return None;
+    }
+    let session = state
+        .recurrent_prompt
+        .as_ref()
+        .filter(|s| tokens.len() > s.tokens.len() && tokens.starts_with(&s.tokens))?;
+    let fingerprint = model_fingerprint(state)?;
+    let entry = state.scheduler.active_model.as_mut()?;
+    if !entry.model.is_recurrent() {
+        return None;
+    }
+    match session.restore(entry.model.as_mut(), &fingerprint) {
+        Ok(()) => {
+            log_infer!("recurrent state reused: {} tokens", session.tokens.len());
+            Some(session.tokens.len())
+        }
+        Err(e) => {
+            log_infer!("recurrent state snapshot rejected: {}", e);
+            state.recurrent_prompt = None;
+            None
+        }
+    }
+}
+
+/// Запоминает состояние рекуррентной модели после `tokens`
+fn snapshot_recurrent_prompt(state: &mut ModelState, tokens: &[u32]) {
+    let Some(fingerprint) = model_fingerprint(state) else {
+        return;
+    };
+    let Some(entry) = state.scheduler.active_model.as_ref() else {
+        return;
+    };
+    match KvSession::capture(entry.model.as_ref(), fingerprint, tokens) {
+        Ok(session) => state.recurrent_prompt = Some(session),
+        Err(e) => {
+            log_infer!("recurrent state snapshot failed: {}", e);
+            state.recurrent_prompt = None;
+        }
+    }
+}
+
+/// Сохраняет KV-кэш длинного промпта в disk tier prefix cache
+///
+/// Под блокировкой только снимается снимок; файл пишется в фоновом потоке
+/// и регистрируется в кэше после записи.
+fn persist_prompt_state(shared: &SharedState, state: &mut ModelState, tokens: &[u32]) {
+    let recurrent = state
+        .scheduler
+        .active_model
+        .as_ref()
+        .is_some_and(|entry| entry.model.is_recurrent());
+    let session = if recurrent {
+        state
+            .recurrent_prompt
+            .as_ref()
+            .filter(|s| tokens.starts_with(&s.tokens))
+            .cloned()
+    } else {
+        capture_prompt_state(state, tokens)
+    };
+    let Some(session) = session else {
+        return;
+    };
+    let Some(path) = state.prefix_cache.reserve_disk(&session.tokens) else {
+        return;
+    };
+    let shared = shared.clone();
+    std::thread::spawn(move || {
+        let saved = session.save(&path);
+        let Ok(mut guard) = shared.lock() else {
+            return;
+        };
+        match saved {
+            Ok(()) => {
+                log_infer!("prompt cache disk WRITE: {} tokens", session.tokens.len());
+                guard.prefix_cache.register_disk(&session.tokens, path);
+            }
+            Err(e) => {
+                log_infer!("prompt cache disk write failed: {}", e);
+                guard.prefix_cache.release_disk(&session.tokens);
+            }
+        }
+    });
+}
+
+/// Снимок KV-кэша промпта для disk tier (в формате KV-кэша модели)
+fn capture_prompt_state(state: &ModelState, tokens: &[u32]) -> Option<KvSession> {
+    state.prefix_cache.disk_path(tokens)?;
+    let fingerprint = model_fingerprint(state)?;
+    let entry = state.scheduler.active_model.as_ref()?;
+    if !entry.model.supports_kv_snapshot() {
+        return None;
+    }
+    match KvSession::capture(entry.model.as_ref(), fingerprint, tokens) {
+        Ok(session) => Some(session.with_cache_type(state.kv_cache_type)),
+        Err(e) => {
+            log_infer!("prompt cache snapshot failed: {}", e);
+            None
+        }
+    }
+}
+
+/// Текстовые маркеры конца хода (fallback, если EOS-токен не распознан)
+pub(crate) const STOP_MARKERS: [&str; 4] =
+    ["<end_of_turn>", "<|end_of_turn|>", "<|eot_id|>", "</s>"];
+
+/// Добавляет текст в скользящее окно (последние 128 байт) для поиска stop-последовательностей
+pub(crate) fn push_stop_window(buf: &mut String, text: &str) {
+    buf.push_str(text);
+    if buf.len() > 128 {
+        let mut cut = buf.len() - 128;
+        while cut < buf.len() && !buf.is_char_boundary(cut) {
+            cut += 1;
+        }
+        if cut > 0 && cut <= buf.len() {
+            let _ = buf.drain(..cut);
+        }
+    }
+}
+
+/// Эффективные параметры семплинга запроса (дефолты или пользовательские)
+pub(crate) fn resolve_sampling_options(req: &GenerateRequest) -> SamplingOptions {
+    // Дефолты семплинга не зависят от режима размышлений.
+    let (def_temp, def_top_p, def_min_p, def_top_k) =
+        (0.7_f64, Some(0.9_f64), Some(0.0_f64), Some(20_usize));
+    // Вычисляем эффективные значения. Если пользовательские параметры включены,
+    // не используем дефолты, а берём только переданные параметры; для температуры
+    // нейтральным значением считаем 1.0.
+    let temperature: f64 = if req.use_custom_params {
+        req.temperature.unwrap_or(1.0_f64)
+    } else {
+        def_temp
+    };
+    let top_p: Option<f64> = if req.use_custom_params {
+        req.top_p
+    } else {
+        def_top_p
+    };
+    let top_k: Option<usize> = if req.use_custom_params {
+        req.top_k
+    } else {
+        def_top_k
+    };
+    let min_p: Option<f64> = if req.use_custom_params {
+        req.min_p
+    } else {
+        def_min_p
+    };
+    // Включаем лёгкий repeat_penalty по умолчанию только когда пользовательские параметры выключены
+    let repeat_penalty: Option<f32> = if req.use_custom_params {
+        req.repeat_penalty
+    } else {
+        Some(1.1_f32)
+    };
+    log_infer!(
+        "request: prompt_len={}, temperature={:.3}, top_k={:?}, top_p={:?}, min_p={:?}, repeat_penalty={:?}, repeat_last_n={}, use_custom_params={}",
+        req.prompt.len(),
+        temperature,
+        top_k,
+        top_p,
+        min_p,
+        repeat_penalty,
+        req.repeat_last_n,
+        req.use_custom_params
+    );
+    SamplingOptions {
+        temperature,
+        top_k,
+        top_p,
+        min_p,
+        seed: Some(req.seed.unwrap_or(42)),
+        repeat_penalty,
+        repeat_last_n: req.repeat_last_n,
+    }
+}
+
+/// Сводка отброшенных сообщений для rolling summary
+pub(crate) type Summarizer<'a> = &'a mut dyn FnMut(&[ChatMessage]) -> Result<String, String>;
+
+/// Промпт запроса после усечения под контекст
+pub(crate) struct RequestPrompt {
+    pub prompt: String,
+    /// Лимит токенов промпта (context_length минус резерв на генерацию)
+    pub limit: usize,
+    /// Отчёт об усечении истории (только для запросов с `messages`)
+    pub report: Option<ContextReport>,
+}
+
+/// Собирает промпт запроса (вложения + chat template + smart truncation)
+///
+/// С `summarizer` отброшенная история не теряется, а заменяется сводкой
+/// в системном промпте (rolling summary).
+pub(crate) fn render_request_prompt(
+    req: &GenerateRequest,
+    tokenizer: &tokenizers::Tokenizer,
+    chat_template: &Option<String>,
+    context_length: usize,
+    bos: Option<String>,
+    summarizer: Option<Summarizer<'_>>,
+) -> Result<RequestPrompt, String> {
+    // Текстовые вложения (.txt/.md): читаем и подмешиваем в последний user или в prompt
+    let mut msgs = req.messages.clone();
+    let mut prompt_str = req.prompt.clone();
+    if let Some(attachments) = req.attachments.as_ref() {
+        let combined = gather_text_from_attachments(attachments).map_err(|e| e.to_string())?;
+        if !combined.is_empty() {
+            if let Some(ref mut m) = msgs {
+                if let Some(last) = m.last_mut() {
+                    if last.role.to_lowercase() == "user" {
+                        last.content = format!("{}\n\n{}", last.content, combined);
+                    } else {
+                        m.push(ChatMessage {
+                            role: "user".into(),
+                            content: combined,
+                        });
+                    }
+                } else {
+                    m.push(ChatMessage {
+                        role: "user".into(),
+                        content: combined,
+                    });
+                }
+            } else if !prompt_str.is_empty() {
+                prompt_str = format!("{}\n\n{}", prompt_str, combined);
+            } else {
+                prompt_str = combined;
+            }
+        }
+    }
+
+    // Determine limit for prompt: context_length - reservation
+    // This ensures we always have space for generation.
+    let reserve_default = 512;
+    let limit_reserve = (context_length as f64 * 0.4) as usize;
+    let generation_reserve = req
+        .max_new_tokens
+        .unwrap_or(reserve_default)
+        .min(limit_reserve)
+        .max(64);
+    let prompt_limit = context_length.saturating_sub(generation_reserve).max(1);
+
+    // Ollama-style "smart" truncation via ctx::smart_truncate
+    let Some(messages) = msgs else {
+        return Ok(RequestPrompt {
+            prompt: prompt_str,
+            limit: prompt_limit,
+            report: None,
+        });
+    };
+    let mut truncated = smart_truncate_report(
+        tokenizer,
+        chat_template,
+        &messages,
+        bos.clone(),
+        prompt_limit,
+    )?;
+    let mut summarized = false;
+    if !truncated.dropped.is_empty()
+        && let Some(summarize) = summarizer
+    {
+        // Резервируем место под сводку и заново выбираем, что отбросить
+        let reserve = SUMMARY_MAX_TOKENS.min(prompt_limit / 4);
+        let reduced = smart_truncate_report(
+            tokenizer,
+            chat_template,
+            &messages,
+            bos.clone(),
+            prompt_limit - reserve,
+        )?;
+        let dropped: Vec<ChatMessage> = reduced
+            .dropped
+            .iter()
+            .map(|&i| messages[i].clone())
+            .collect();
+        match summarize(&dropped) {
+            Ok(summary) if !summary.is_empty() => {
+                let condensed: Vec<crate::core::prompt::ChatMessage> =
+                    with_summary(&messages, &reduced.dropped, &summary)
+                        .into_iter()
+                        .map(|m| crate::core::prompt::ChatMessage {
+                            role: m.role,
+                            content: m.content,
+                        })
+                        .collect();
+                let prompt = PromptBuilder::new(chat_template.clone())
+                    .with_bos(bos)
+                    .render_prompt(condensed)?;
+                let prompt_tokens = tokenizer
+                    .encode(prompt.as_str(), true)
+                    .map_err(|e| e.to_string())?
+                    .len();
+                log_infer!(
+                    "rolling summary: {} messages condensed into {} chars",
+                    reduced.dropped.len(),
+                    summary.len()
+                );
+                truncated = TruncatedPrompt {
+                    prompt,
+                    dropped: reduced.dropped,
+                    prompt_tokens,
+                };
+                summarized = true;
+            }
+            Ok(_) => log_infer!("rolling summary: empty summary, dropping history"),
+            Err(e) => log_infer!("rolling summary failed: {}, dropping history", e),
+        }
+    }
+    Ok(RequestPrompt {
+        prompt: truncated.prompt,
+        limit: prompt_limit,
+        report: Some(ContextReport {
+            dropped: truncated.dropped,
+            summarized,
+            prompt_tokens: truncated.prompt_tokens,
+            budget: prompt_limit,
+            context_length,
+        }),
+    })
+}
+
+/// Выполняет операцию над активной моделью (take_model / restore_model)
+pub(crate) fn with_active_model<T>(
+    scheduler: &mut ModelScheduler,
+    f: impl FnOnce(&mut (dyn ModelBackend + Send)) -> candle::Result<T>,
+) -> Result<T, String> {
+    let mut entry = scheduler
+        .take_model()
+        .ok_or_else(|| "Model is not loaded".to_string())?;
+    let res = f(entry.model.as_mut());
+    scheduler.restore_model(entry);
+    res.map_err(|e| e.to_string())
+}
+
+/// Сэмплирует токен из логитов последней позиции [vocab_size]
+/// с учётом repeat penalty по истории и min-p фильтра
+pub(crate) fn sample_logits(
+    logits: &Tensor,
+    logits_processor: &mut candle_transformers::generation::LogitsProcessor,
+    minp: &mut MinPFilter,
+    repeat_penalty: Option<f32>,
+    repeat_last_n: usize,
+    history: &[u32],
+) -> Result<u32, String> {
+    // Convert to F32 for sampling (like candle examples)
+    let mut logits = logits.to_dtype(DType::F32).map_err(|e| e.to_string())?;
+    if let Some(rp) = repeat_penalty
+        && (rp - 1.0).abs() > f32::EPSILON
+    {
+        let start_at = history.len().saturating_sub(repeat_last_n);
+        let penalty_tokens = &history[start_at..];
+        // Only apply penalty if we have tokens to penalize (avoids shape mismatch with empty slice)
+        if !penalty_tokens.is_empty() {
+            logits = candle_transformers::utils::apply_repeat_penalty(&logits, rp, penalty_tokens)
+                .map_err(|e| e.to_string())?;
+        }
+    }
+    let logits = minp.apply(&logits)?;
+    logits_processor.sample(&logits).map_err(|e| e.to_string())
+}
+
 /// Build a prompt using the prompt builder with chat template support
 pub fn build_prompt_with_template_bos(
     chat_template: &Option<String>,
@@ -0,0 +1,319 @@
+//! Rolling summary: сжатие отброшенной истории диалога загруженной моделью
+//!
+//! Когда smart truncation отбрасывает старые сообщения, они заменяются одной
+//! сводкой, которая дописывается к системному промпту. Сводки кэшируются по
+//! `session_id`: при следующем усечении модель досуммирует только новые
+//! отброшенные сообщения поверх предыдущей сводки.
+
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::Instant;
+
+use candle::{D, DType, Device, Tensor};
+use tokenizers::Tokenizer;
+
+use super::ctx::ContextSlice;
+use super::stream::with_active_model;
+use crate::core::prompt::PromptBuilder;
+use crate::core::scheduler::ModelScheduler;
+use crate::core::tokenizer::extract_eos_ids;
+use crate::core::types::ChatMessage;
+
+/// Максимальная длина сводки в токенах
+pub const SUMMARY_MAX_TOKENS: usize = 256;
+/// Сколько сессий хранить в кэше сводок
+const MAX_SESSIONS: usize = 32;
+/// Заголовок сводки в системном промпте
+const SUMMARY_HEADER: &str = "Summary of the earlier conversation:";
+
+#[derive(Debug, Clone)]
+struct SummaryEntry {
+    /// Hash каждого вошедшего в сводку сообщения (по порядку)
+    covered: Vec<u64>,
+    summary: String,
+    last_used: Instant,
+}
+
+/// Кэш rolling summary по сессиям (LRU по числу сессий)
+#[derive(Debug, Default)]
+pub struct SummaryCache {
+    entries: HashMap<String, SummaryEntry>,
+}
+
+impl SummaryCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Возвращает сводку отброшенных сообщений `dropped`
+    ///
+    /// `summarize(previous, messages)` вызывается только для сообщений, которых
+    /// ещё нет в сводке сессии; без `session_id` сводка строится заново.
+    pub fn summarize(
+        &mut self,
+        session_id: Option<&str>,
+        dropped: &[ChatMessage],
+        mut summarize: impl FnMut(Option<&str>, &[ChatMessage]) -> Result<String, String>,
+    ) -> Result<String, String> {
+        let Some(session_id) = session_id else {
+            return summarize(None, dropped);
+        };
+        let hashes: Vec<u64> = dropped.iter().map(message_hash).collect();
+        let cached = self
+            .entries
+            .get(session_id)
+            .filter(|entry| hashes.starts_with(&entry.covered));
+        let summary = match cached {
+            Some(entry) if entry.covered.len() == hashes.len() => entry.summary.clone(),
+            Some(entry) => summarize(Some(&entry.summary), &dropped[entry.covered.len()..])?,
+            None => summarize(None, dropped)?,
+        };
+        self.insert(session_id, hashes, summary.clone());
+        Ok(summary)
+    }
+
+    fn insert(&mut self, session_id: &str, covered: Vec<u64>, summary: String) {
+        if !self.entries.contains_key(session_id)
+            && self.entries.len() >= MAX_SESSIONS
+            && let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+        {
+            self.entries.remove(&oldest);
+        }
+        self.entries.insert(
+            session_id.to_string(),
+            SummaryEntry {
+                covered,
+                summary,
+                last_used: Instant::now(),
+            },
+        );
+    }
+
+    /// Удаляет сводку сессии
+    pub fn remove(&mut self, session_id: &str) {
+        self.entries.remove(session_id);
+    }
+
+    /// Очищает кэш
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Число сессий в кэше
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+fn message_hash(message: &ChatMessage) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    message.role.hash(&mut hasher);
+    message.content.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Промпт запроса на сводку: предыдущая сводка + новые сообщения + инструкция
+pub fn summary_prompt(
+    chat_template: &Option<String>,
+    bos_token: Option<String>,
+    previous: Option<&str>,
+    messages: &[ChatMessage],
+) -> Result<String, String> {
+    let mut transcript = String::new();
+    if let Some(previous) = previous {
+        transcript.push_str(&format!("[earlier summary]\n{previous}\n\n"));
+    }
+    for message in messages {
+        transcript.push_str(&format!("[{}]\n{}\n\n", message.role, message.content));
+    }
+    let request = vec![
+        crate::core::prompt::ChatMessage {
+            role: "system".into(),
+            content: "You condense chat history. Reply with the summary only.".into(),
+        },
+        crate::core::prompt::ChatMessage {
+            role: "user".into(),
+            content: format!(
+                "{transcript}Summarize the conversation above in a few sentences. \
+                 Keep names, facts, decisions and open questions."
+            ),
+        },
+    ];
+    PromptBuilder::new(chat_template.clone())
+        .with_bos(bos_token)
+        .render_prompt(request)
+}
+
+/// Заменяет сообщения `dropped` сводкой, дописанной к первому системному сообщению
+///
+/// Без системного сообщения сводка вставляется новым системным сообщением в начало.
+pub fn with_summary(
+    messages: &[ChatMessage],
+    dropped: &[usize],
+    summary: &str,
+) -> Vec<ChatMessage> {
+    let note = format!("{SUMMARY_HEADER}\n{summary}");
+    let mut condensed: Vec<ChatMessage> = messages
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| dropped.binary_search(i).is_err())
+        .map(|(_, m)| m.clone())
+        .collect();
+    match condensed.iter_mut().find(|m| m.role == "system") {
+        Some(system) => system.content = format!("{}\n\n{note}", system.content),
+        None => condensed.insert(
+            0,
+            ChatMessage {
+                role: "system".into(),
+                content: note,
+            },
+        ),
+    }
+    condensed
+}
+
+/// Жадная генерация активной моделью для служебных запросов (сводка истории)
+///
+/// Сбрасывает KV-кэш модели: вызывающая сторона обязана инвалидировать prefix cache.
+pub(crate) fn complete_greedy(
+    scheduler: &mut ModelScheduler,
+    tokenizer: &Tokenizer,
+    device: &Device,
+    prompt: &str,
+    max_new_tokens: usize,
+    context_length: usize,
+) -> Result<String, String> {
+    let encoded = tokenizer
+        .encode(prompt, true)
+        .map_err(|e| e.to_string())?
+        .get_ids()
+        .to_vec();
+    // Хвост промпта (инструкция + generation prompt) важнее начала транскрипта
+    let limit = context_length.saturating_sub(max_new_tokens).max(1);
+    let mut input = ContextSlice::new(encoded, limit).effective_context_tokens;
+    let stop_ids = extract_eos_ids(tokenizer);
+
+    with_active_model(scheduler, |model| {
+        model.clear_kv_cache();
+        Ok(())
+    })?;
+    let mut pos = 0;
+    let mut generated = Vec::new();
+    for _ in 0..max_new_tokens {
+        let tensor = Tensor::new(input.as_slice(), device)
+            .and_then(|t| t.unsqueeze(0))
+            .map_err(|e| e.to_string())?;
+        let logits = with_active_model(scheduler, |model| model.forward_layered(&tensor, pos))?;
+        pos += input.len();
+        let next = logits
+            .squeeze(0)
+            .and_then(|l| l.to_dtype(DType::F32))
+            .and_then(|l| l.argmax(D::Minus1))
+            .and_then(|t| t.to_scalar::<u32>())
+            .map_err(|e| e.to_string())?;
+        if stop_ids.contains(&next) {
+            break;
+        }
+        generated.push(next);
+        input = vec![next];
+    }
+    with_active_model(scheduler, |model| {
+        model.clear_kv_cache();
+        Ok(())
+    })?;
+
+    let text = tokenizer
+        .decode(&generated, true)
+        .map_err(|e| e.to_string())?;
+    Ok(strip_thinking(&text).trim().to_string())
+}
+
+/// Убирает блок рассуждений `<think>...</think>` из ответа
+fn strip_thinking(text: &str) -> &str {
+    if let Some(end) = text.rfind("</think>") {
+        &text[end + "</think>".len()..]
+    } else if let Some(start) = text.find("<think>") {
+        &text[..start]
+    } else {
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(role: &str, content: &str) -> ChatMessage {
+        ChatMessage {
+            role: role.into(),
+            content: content.into(),
+        }
+    }
+
+    #[test]
+    fn summary_cache_extends_previous_summary() {
+        let mut cache = SummaryCache::new();
+        let history = vec![msg("user", "a"), msg("assistant", "b"), msg("user", "c")];
+
+        let first = cache
+            .summarize(Some("s1"), &history[..2], |prev, msgs| {
+                assert!(prev.is_none());
+                assert_eq!(msgs.len(), 2);
+                Ok("ab".into())
+            })
+            .unwrap();
+        assert_eq!(first, "ab");
+
+        // Тот же набор: берём из кэша без вызова модели
+        let cached = cache
+            .summarize(Some("s1"), &history[..2], |_, _| Err("unexpected".into()))
+            .unwrap();
+        assert_eq!(cached, "ab");
+
+        // Отброшено ещё одно сообщение: досуммируем только его
+        let rolled = cache
+            .summarize(Some("s1"), &history, |prev, msgs| {
+                assert_eq!(prev, Some("ab"));
+                assert_eq!(msgs.len(), 1);
+                Ok("abc".into())
+            })
+            .unwrap();
+        assert_eq!(rolled, "abc");
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn with_summary_replaces_dropped_messages() {
+        let messages = vec![
+            msg("system", "sys"),
+            msg("user", "old"),
+            msg("assistant", "older reply"),
+            msg("user", "new"),
+        ];
+        let condensed = with_summary(&messages, &[1, 2], "talked about old things");
+        assert_eq!(condensed.len(), 2);
+        assert!(condensed[0].content.starts_with("sys"));
+        assert!(condensed[0].content.contains("talked about old things"));
+        assert_eq!(condensed[1].content, "new");
+
+        let condensed = with_summary(&messages[1..], &[0], "s");
+        assert_eq!(condensed[0].role, "system");
+        assert_eq!(condensed.len(), 3);
+    }
+
+    #[test]
+    fn strip_thinking_keeps_answer() {
+        assert_eq!(strip_thinking("<think>hmm</think>\nanswer"), "\nanswer");
+        assert_eq!(strip_thinking("answer<think>cut"), "answer");
+        assert_eq!(strip_thinking("plain"), "plain");
+    }
+}
@@ -2,6 +2,8 @@
 
 use candle::Tensor;
 
+use crate::models::common::{KvCacheType, LayerKv, LoraAdapter, RecurrentState, StreamingWindow};
+
 /// Основной trait, который должны реализовывать все модели
 pub trait ModelBackend: Send {
     /// Forward pass модели
@@ -76,10 +78,192 @@ pub trait ModelBackend: Send {
         false // По умолчанию: не поддерживается
     }
 
+    /// Проверяет, поддерживает ли модель prefill чанками
+    /// (многотокенный forward при `pos > 0` с корректной causal-маской)
+    fn supports_chunked_prefill(&self) -> bool {
+        false // По умолчанию: весь промпт одним проходом
+    }
+
+    // ============ Speculative Decoding Support ============
+
+    /// Forward pass, возвращающий логиты для КАЖДОЙ позиции входа
+    ///
+    /// Используется для верификации черновых токенов (prompt lookup decoding):
+    /// все черновики проверяются за один проход модели.
+    ///
+    /// # Returns
+    /// Логиты [seq_len, vocab_size]
+    fn forward_all(&mut self, _input: &Tensor, _pos: usize) -> candle::Result<Tensor> {
+        candle::bail!("forward_all is not supported for this model type")
+    }
+
+    /// Обрезает KV-кэш до `len` токенов (откат отклонённых черновиков)
+    ///
+    /// # Returns
+    /// `true` если модель поддерживает откат KV-кэша, `false` иначе
+    fn truncate_kv_cache(&mut self, _len: usize) -> bool {
+        false // По умолчанию: откат не поддерживается
+    }
+
+    /// Проверяет, поддерживает ли модель speculative decoding
+    /// (`forward_all` + `truncate_kv_cache`)
+    fn supports_speculative(&self) -> bool {
+        false // По умолчанию: не поддерживается
+    }
+
+    // ============ Context Shift Support ============
+
+    /// Проверяет, поддерживает ли модель сдвиг контекста (`shift_kv_cache`)
+    fn supports_context_shift(&self) -> bool {
+        false // По умолчанию: генерация останавливается на context_length
+    }
+
+    /// Удаляет из KV-кэша позиции [keep, keep + discard)
+    ///
+    /// Ключи оставшегося хвоста ре-ротируются RoPE на `discard` позиций назад,
+    /// поэтому генерация продолжается с позиции `kv_len - discard`.
+    ///
+    /// # Returns
+    /// `true` если сдвиг выполнен, `false` если модель его не поддерживает
+    fn shift_kv_cache(&mut self, _keep: usize, _discard: usize) -> bool {
+        false // По умолчанию: сдвиг не поддерживается
+    }
+
+    // ============ Streaming Attention Support ============
+
+    /// Включает/выключает потоковый режим внимания (attention sinks + скользящее окно KV)
+    ///
+    /// В этом режиме модель сама определяет позиции по длине своего KV-кэша
+    /// и вытесняет старые записи, поэтому генерация не ограничена context_length.
+    ///
+    /// # Returns
+    /// `true` если модель поддерживает режим
+    fn set_streaming_window(&mut self, _window: Option<StreamingWindow>) -> bool {
+        false // По умолчанию: не поддерживается
+    }
+
+    /// Текущая конфигурация потокового режима (`None` — выключен)
+    fn streaming_window(&self) -> Option<StreamingWindow> {
+        None
+    }
+
+    // ============ KV Session Support ============
+
+    /// Проверяет, поддерживает ли модель снимки KV-кэша (`export_kv_cache` / `import_kv_cache`)
+    fn supports_kv_snapshot(&self) -> bool {
+        false // По умолчанию: не поддерживается
+    }
+
+    /// Снимок активного KV-кэша: по паре (K, V) на слой
+    fn export_kv_cache(&self) -> candle::Result<Vec<LayerKv>> {
+        candle::bail!("KV cache export is not supported for this model type")
+    }
+
+    /// Заменяет активный KV-кэш снимком из `export_kv_cache`
+    fn import_kv_cache(&mut self, _layers: &[LayerKv]) -> candle::Result<()> {
+        candle::bail!("KV cache import is not supported for this model type")
+    }
+
+    // ============ Recurrent State Support ============
+
+    /// Хранит ли модель рекуррентное состояние вместо KV-кэша (Mamba, RWKV)
+    ///
+    /// Состояние фиксированного размера отражает все обработанные токены:
+    /// `forward` с `pos == 0` начинает новую последовательность, иначе `pos`
+    /// должен совпадать с числом уже обработанных токенов. Откат и сдвиг
+    /// невозможны, а `clear_kv_cache` сбрасывает состояние.
+    fn is_recurrent(&self) -> bool {
+        false // По умолчанию: позиционный KV-кэш
+    }
+
+    /// Снимок рекуррентного состояния после всех обработанных токенов
+    fn export_state(&self) -> candle::Result<RecurrentState> {
+        candle::bail!("Recurrent state export is not supported for this model type")
+    }
+
+    /// Заменяет рекуррентное состояние снимком из `export_state`
+    fn import_state(&mut self, _state: &RecurrentState) -> candle::Result<()> {
+        candle::bail!("Recurrent state import is not supported for this model type")
+    }
+
+    // ============ LoRA Adapters ============
+
+    /// Можно ли менять LoRA-адаптеры без перезагрузки весов (side-car слои)
+    fn supports_lora(&self) -> bool {
+        false // По умолчанию: адаптеры не поддерживаются
+    }
+
+    /// Заменяет набор активных LoRA-адаптеров; пустой срез отключает LoRA
+    ///
+    /// При ошибке активные адаптеры не меняются.
+    fn set_lora_adapters(&mut self, _adapters: &[LoraAdapter]) -> candle::Result<()> {
+        candle::bail!("LoRA adapters are not supported for this model type")
+    }
+
+    // ============ KV Cache Storage ============
+
+    /// Задаёт формат хранения KV-кэша (полная точность или блоки Q8_0/Q4_0)
+    ///
+    /// Квантованный кэш деквантуется на лету в attention.
+    ///
+    /// # Returns
+    /// `true` если модель поддерживает выбранный формат
+    fn set_kv_cache_type(&mut self, cache_type: KvCacheType) -> bool {
+        cache_type == KvCacheType::Full // По умолчанию: только полная точность
+    }
+
+    // ============ Continuous Batching Support ============
+
+    /// Проверяет, поддерживает ли модель слоты KV-кэша для continuous batching
+    fn supports_batching(&self) -> bool {
+        false // По умолчанию: не поддерживается
+    }
+
+    /// Prefill последовательности в слот `slot` (отдельный KV-кэш)
+    ///
+    /// `pos == 0` начинает новую последовательность в слоте.
+    /// Активный KV-кэш (используемый `forward`) не затрагивается.
+    ///
+    /// # Returns
+    /// Логиты последнего токена [1, vocab_size]
+    fn prefill_slot(
+        &mut self,
+        _slot: usize,
+        _input: &Tensor,
+        _pos: usize,
+    ) -> candle::Result<Tensor> {
+        candle::bail!("Cache slots are not supported for this model type")
+    }
+
+    /// Шаг декодирования для нескольких последовательностей за один проход
+    ///
+    /// # Arguments
+    /// * `tokens` - По одному токену на последовательность
+    /// * `slots` - Слот KV-кэша каждой последовательности
+    /// * `positions` - Позиция каждого токена в своей последовательности
+    ///
+    /// # Returns
+    /// Логиты [batch_size, vocab_size]
+    fn forward_batch(
+        &mut self,
+        _tokens: &[u32],
+        _slots: &[usize],
+        _positions: &[usize],
+    ) -> candle::Result<Tensor> {
+        candle::bail!("Batched decoding is not supported for this model type")
+    }
+
+    /// Освобождает KV-кэш слота
+    fn release_slot(&mut self, _slot: usize) {}
+
     /// Возвращает эмбеддинги для входного тензора
     ///
+    /// # Arguments
+    /// * `input` - Токены [batch_size, seq_len], строки дополнены паддингом справа
+    ///
     /// # Returns
-    /// Тензор эмбеддингов (скрытые состояния последнего слоя)
+    /// Скрытые состояния последнего слоя после финальной нормализации
+    /// [batch_size, seq_len, hidden_size]; прогон идёт с позиции 0
     fn get_embeddings(&mut self, _input: &Tensor) -> candle::Result<Tensor> {
         candle::bail!("Embeddings not supported for this model type")
     }
@@ -0,0 +1,110 @@
+//! Attention шага декодирования для батча последовательностей со своими KV-кэшами
+//!
+//! Используется continuous batching (`ModelBackend::forward_batch`): проекции
+//! считаются на весь батч, а каждая строка дописывает K/V в кэш своего слота.
+
+use candle::{Result, Tensor};
+use candle_transformers::utils::repeat_kv;
+
+use super::KvCache;
+
+/// Attention одного шага декодирования; строка `i` батча работает с кэшем `caches[i]`
+///
+/// Кэши разной длины дополняются нулями справа до общей длины, дополнение
+/// маскируется, и scores со взвешиванием V считаются одним matmul на весь батч.
+///
+/// `q` — [batch, heads, 1, head_dim] после RoPE, `k`/`v` — [batch, kv_heads, 1, head_dim].
+/// Возвращает контекст [batch, heads, 1, head_dim].
+pub fn batched_decode_attention(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    caches: &mut [&mut KvCache],
+    num_kv_groups: usize,
+) -> Result<Tensor> {
+    let (b_sz, _, seq_len, head_dim) = q.dims4()?;
+    if seq_len != 1 || caches.len() != b_sz {
+        candle::bail!("batched decode attention expects one token per cache")
+    }
+    let mut rows = Vec::with_capacity(b_sz);
+    for (i, cache) in caches.iter_mut().enumerate() {
+        let k_i = k.narrow(0, i, 1)?.contiguous()?;
+        let v_i = v.narrow(0, i, 1)?.contiguous()?;
+        rows.push(cache.append(&k_i, &v_i)?);
+    }
+    let lens = rows
+        .iter()
+        .map(|(k, _)| k.dim(2))
+        .collect::<Result<Vec<_>>>()?;
+    let max_len = lens.iter().copied().max().unwrap_or(0);
+
+    let mut ks = Vec::with_capacity(b_sz);
+    let mut vs = Vec::with_capacity(b_sz);
+    for ((k_i, v_i), &len) in rows.iter().zip(&lens) {
+        ks.push(k_i.pad_with_zeros(2, 0, max_len - len)?);
+        vs.push(v_i.pad_with_zeros(2, 0, max_len - len)?);
+    }
+    let k = repeat_kv(Tensor::cat(&ks, 0)?, num_kv_groups)?.contiguous()?;
+    let v = repeat_kv(Tensor::cat(&vs, 0)?, num_kv_groups)?.contiguous()?;
+
+    // Дополнение до общей длины не участвует в softmax
+    let mask: Vec<f32> = lens
+        .iter()
+        .flat_map(|&len| (0..max_len).map(move |j| if j < len { 0. } else { f32::NEG_INFINITY }))
+        .collect();
+    let mask = Tensor::from_vec(mask, (b_sz, 1, 1, max_len), q.device())?.to_dtype(q.dtype())?;
+    let scores = (q.contiguous()?.matmul(&k.t()?)? / (head_dim as f64).sqrt())?;
+    let probs = candle_nn::ops::softmax_last_dim(&scores.broadcast_add(&mask)?)?;
+    probs.matmul(&v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle::Device;
+
+    fn max_abs_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+        (a - b)?.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()
+    }
+
+    #[test]
+    fn padded_batch_matches_per_sequence_attention() -> Result<()> {
+        let dev = Device::Cpu;
+        let (heads, kv_heads, head_dim) = (4, 2, 8);
+        let prefix_lens = [3, 7, 1];
+
+        let mut batched: Vec<KvCache> = Vec::new();
+        let mut single: Vec<KvCache> = Vec::new();
+        for &len in &prefix_lens {
+            let kv = Tensor::randn(0f32, 1., (1, kv_heads, len, head_dim), &dev)?;
+            for caches in [&mut batched, &mut single] {
+                let mut cache = KvCache::new(2);
+                cache.append(&kv, &kv)?;
+                caches.push(cache);
+            }
+        }
+
+        let b = prefix_lens.len();
+        let q = Tensor::randn(0f32, 1., (b, heads, 1, head_dim), &dev)?;
+        let k = Tensor::randn(0f32, 1., (b, kv_heads, 1, head_dim), &dev)?;
+        let v = Tensor::randn(0f32, 1., (b, kv_heads, 1, head_dim), &dev)?;
+        let mut refs: Vec<&mut KvCache> = batched.iter_mut().collect();
+        let ctx = batched_decode_attention(&q, &k, &v, &mut refs, heads / kv_heads)?;
+        assert_eq!(ctx.dims(), &[b, heads, 1, head_dim]);
+
+        for (i, cache) in single.iter_mut().enumerate() {
+            let ctx_i = batched_decode_attention(
+                &q.narrow(0, i, 1)?,
+                &k.narrow(0, i, 1)?,
+                &v.narrow(0, i, 1)?,
+                &mut [cache],
+                heads / kv_heads,
+            )?;
+            assert!(max_abs_diff(&ctx.narrow(0, i, 1)?, &ctx_i)? < 1e-5);
+        }
+        for (a, b) in batched.iter().zip(&single) {
+            assert_eq!(a.current_seq_len(), b.current_seq_len());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,278 @@
+//! Context shifting helpers
+//!
+//! Сдвиг контекста при достижении лимита: из KV-кэша удаляется окно самых старых
+//! токенов (после закреплённого префикса), а ключи оставшегося хвоста
+//! ре-ротируются RoPE на величину сдвига, чтобы их позиции снова шли подряд.
+//!
+//! Тот же механизм лежит в основе потокового режима внимания (StreamingLLM,
+//! [`StreamingWindow`]): кэш постоянно держит sink-токены + окно последних записей.
+
+use candle::{DType, Result, Tensor};
+use candle_nn::kv_cache::ConcatKvCache;
+use serde::{Deserialize, Serialize};
+
+use super::kv_cache::KvCache;
+use super::rope_scaling::{RopeScaling, rope_inv_freqs};
+
+/// Параметры RoPE, необходимые для ре-ротации ключей
+#[derive(Debug, Clone, Copy)]
+pub struct RopeShift {
+    /// Размерность головы, к которой применяется RoPE
+    ///
+    /// При частичном RoPE (Phi) меньше размерности ключей: остальные
+    /// измерения не поворачиваются.
+    pub head_dim: usize,
+    /// База частот (`rope_theta` / `rope.freq_base`)
+    pub theta: f64,
+    /// Чередующаяся раскладка пар (`rope_i`, GGUF Llama) вместо половинной (`rope`)
+    pub interleaved: bool,
+    /// Масштабирование частот, с которым построены таблицы модели
+    pub scaling: Option<RopeScaling>,
+}
+
+impl RopeShift {
+    pub fn new(head_dim: usize, theta: f64) -> Self {
+        Self {
+            head_dim,
+            theta,
+            interleaved: false,
+            scaling: None,
+        }
+    }
+
+    pub fn interleaved(head_dim: usize, theta: f64) -> Self {
+        Self {
+            head_dim,
+            theta,
+            interleaved: true,
+            scaling: None,
+        }
+    }
+
+    pub fn with_scaling(mut self, scaling: Option<RopeScaling>) -> Self {
+        self.scaling = scaling;
+        self
+    }
+
+    /// Поворачивает ключи [batch, heads, seq_len, head_dim] на `delta` позиций назад
+    ///
+    /// RoPE аддитивна по позиции: R(p - delta) = R(-delta) · R(p), поэтому для
+    /// всего хвоста достаточно одного угла на частоту.
+    pub fn rerotate(&self, k: &Tensor, delta: usize) -> Result<Tensor> {
+        let (_, _, seq_len, key_dim) = k.dims4()?;
+        if key_dim > self.head_dim {
+            let rotated = self.rerotate(&k.narrow(3, 0, self.head_dim)?, delta)?;
+            let pass = k.narrow(3, self.head_dim, key_dim - self.head_dim)?;
+            return Tensor::cat(&[&rotated, &pass], 3);
+        }
+        let half = self.head_dim / 2;
+        let (cos, sin): (Vec<f32>, Vec<f32>) =
+            rope_inv_freqs(self.head_dim, self.theta, self.scaling.as_ref())
+                .into_iter()
+                .map(|inv_freq| {
+                    let angle = -(delta as f64) * inv_freq;
+                    (angle.cos() as f32, angle.sin() as f32)
+                })
+                .unzip();
+        let cos = Tensor::from_vec(cos, (1, half), k.device())?
+            .broadcast_as((seq_len, half))?
+            .contiguous()?;
+        let sin = Tensor::from_vec(sin, (1, half), k.device())?
+            .broadcast_as((seq_len, half))?
+            .contiguous()?;
+
+        // Ре-ротация в F32: ошибка округления не накапливается между сдвигами
+        let dtype = k.dtype();
+        let k = k.to_dtype(DType::F32)?.contiguous()?;
+        let rotated = if self.interleaved {
+            candle_nn::rotary_emb::rope_i(&k, &cos, &sin)?
+        } else {
+            candle_nn::rotary_emb::rope(&k, &cos, &sin)?
+        };
+        rotated.to_dtype(dtype)
+    }
+}
+
+/// Удаляет позиции [keep, keep + discard) из пары тензоров K/V [batch, heads, seq_len, head_dim]
+/// и ре-ротирует ключи хвоста на `discard` позиций назад
+pub fn shift_kv(
+    k: &Tensor,
+    v: &Tensor,
+    keep: usize,
+    discard: usize,
+    rope: &RopeShift,
+) -> Result<(Tensor, Tensor)> {
+    let seq_len = k.dim(2)?;
+    if discard == 0 || keep >= seq_len {
+        return Ok((k.clone(), v.clone()));
+    }
+    let discard = discard.min(seq_len - keep);
+    let tail = seq_len - keep - discard;
+    if tail == 0 {
+        return Ok((k.narrow(2, 0, keep)?, v.narrow(2, 0, keep)?));
+    }
+
+    let k_tail = rope.rerotate(&k.narrow(2, keep + discard, tail)?, discard)?;
+    let v_tail = v.narrow(2, keep + discard, tail)?;
+    if keep == 0 {
+        return Ok((k_tail, v_tail.contiguous()?));
+    }
+    let k = Tensor::cat(&[&k.narrow(2, 0, keep)?, &k_tail], 2)?;
+    let v = Tensor::cat(&[&v.narrow(2, 0, keep)?, &v_tail], 2)?;
+    Ok((k, v))
+}
+
+/// Сдвигает `ConcatKvCache` (dim = 2): см. [`shift_kv`]
+pub fn shift_concat_cache(
+    cache: &mut ConcatKvCache,
+    keep: usize,
+    discard: usize,
+    rope: &RopeShift,
+) -> Result<()> {
+    let (Some(k), Some(v)) = (cache.k(), cache.v()) else {
+        return Ok(());
+    };
+    let (k, v) = shift_kv(k, v, keep, discard, rope)?;
+    if let Some(slot) = cache.k_mut() {
+        *slot = k;
+    }
+    if let Some(slot) = cache.v_mut() {
+        *slot = v;
+    }
+    Ok(())
+}
+
+fn default_sink_tokens() -> usize {
+    StreamingWindow::DEFAULT_SINK_TOKENS
+}
+
+/// Потоковый режим внимания (StreamingLLM)
+///
+/// KV-кэш хранит первые `sink_tokens` записей ("attention sinks") и скользящее
+/// окно из `window` последних; всё между ними вытесняется с ре-ротацией RoPE,
+/// поэтому память фиксирована, а генерация может продолжаться неограниченно.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StreamingWindow {
+    /// Сколько первых токенов хранить всегда
+    #[serde(default = "default_sink_tokens")]
+    pub sink_tokens: usize,
+    /// Размер окна последних KV-записей
+    pub window: usize,
+}
+
+impl StreamingWindow {
+    pub const DEFAULT_SINK_TOKENS: usize = 4;
+
+    /// Максимальная длина KV-кэша в этом режиме
+    pub fn capacity(&self) -> usize {
+        self.sink_tokens + self.window
+    }
+
+    /// Проверяет конфигурацию относительно лимита позиций модели
+    pub fn validate(&self, max_positions: usize) -> std::result::Result<(), String> {
+        if self.window == 0 {
+            return Err("streaming attention: window must be greater than zero".into());
+        }
+        if self.capacity() > max_positions {
+            return Err(format!(
+                "streaming attention: sink_tokens + window = {} exceeds model positions {}",
+                self.capacity(),
+                max_positions
+            ));
+        }
+        Ok(())
+    }
+
+    /// Вытесняет записи между sink-токенами и окном, если кэш переполнен
+    pub fn evict(&self, cache: &mut KvCache, rope: &RopeShift) -> Result<()> {
+        let len = cache.current_seq_len();
+        if len <= self.capacity() {
+            return Ok(());
+        }
+        cache.shift(self.sink_tokens, len - self.capacity(), rope)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle::Device;
+
+    fn rope_at(x: &Tensor, pos: usize, rope: &RopeShift) -> Result<Tensor> {
+        // Прямое применение RoPE на позиции `pos`
+        let half = rope.head_dim / 2;
+        let (cos, sin): (Vec<f32>, Vec<f32>) = (0..rope.head_dim)
+            .step_by(2)
+            .map(|i| {
+                let inv_freq = 1f64 / rope.theta.powf(i as f64 / rope.head_dim as f64);
+                let angle = pos as f64 * inv_freq;
+                (angle.cos() as f32, angle.sin() as f32)
+            })
+            .unzip();
+        let cos = Tensor::from_vec(cos, (1, half), x.device())?;
+        let sin = Tensor::from_vec(sin, (1, half), x.device())?;
+        candle_nn::rotary_emb::rope(&x.contiguous()?, &cos, &sin)
+    }
+
+    #[test]
+    fn rerotate_matches_direct_rope() -> Result<()> {
+        let dev = Device::Cpu;
+        let rope = RopeShift::new(8, 10000.0);
+        let x = Tensor::arange(0f32, 8f32, &dev)?.reshape((1, 1, 1, 8))?;
+        let at_10 = rope_at(&x, 10, &rope)?;
+        let at_3 = rope_at(&x, 3, &rope)?;
+        let shifted = rope.rerotate(&at_10, 7)?;
+        let diff = (shifted - at_3)?.abs()?.max_all()?.to_scalar::<f32>()?;
+        assert!(diff < 1e-4, "diff = {diff}");
+        Ok(())
+    }
+
+    #[test]
+    fn partial_rerotate_keeps_pass_through_dims() -> Result<()> {
+        let dev = Device::Cpu;
+        let rope = RopeShift::new(4, 10000.0);
+        let x = Tensor::arange(0f32, 8f32, &dev)?.reshape((1, 1, 1, 8))?;
+        let shifted = rope.rerotate(&x, 5)?;
+        let expected = rope.rerotate(&x.narrow(3, 0, 4)?, 5)?;
+        let diff = (shifted.narrow(3, 0, 4)? - expected)?
+            .abs()?
+            .max_all()?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-6, "diff = {diff}");
+        let pass: Vec<f32> = shifted.narrow(3, 4, 4)?.flatten_all()?.to_vec1()?;
+        assert_eq!(pass, vec![4., 5., 6., 7.]);
+        Ok(())
+    }
+
+    #[test]
+    fn shift_kv_drops_window_and_keeps_prefix() -> Result<()> {
+        let dev = Device::Cpu;
+        let rope = RopeShift::new(2, 10000.0);
+        let v = Tensor::arange(0f32, 10f32, &dev)?.reshape((1, 1, 5, 2))?;
+        let (k, v) = shift_kv(&v, &v, 1, 2, &rope)?;
+        assert_eq!(k.dims4()?, (1, 1, 3, 2));
+        let v: Vec<f32> = v.flatten_all()?.to_vec1()?;
+        assert_eq!(v, vec![0., 1., 6., 7., 8., 9.]);
+        Ok(())
+    }
+
+    #[test]
+    fn streaming_window_evicts_between_sinks_and_window() -> Result<()> {
+        let dev = Device::Cpu;
+        let rope = RopeShift::new(2, 10000.0);
+        let window = StreamingWindow {
+            sink_tokens: 1,
+            window: 2,
+        };
+        let mut cache = KvCache::new(2);
+        let kv = Tensor::arange(0f32, 10f32, &dev)?.reshape((1, 1, 5, 2))?;
+        cache.append(&kv, &kv)?;
+        window.evict(&mut cache, &rope)?;
+        assert_eq!(cache.current_seq_len(), 3);
+        let (_, v) = cache.materialize()?.unwrap();
+        let v: Vec<f32> = v.flatten_all()?.to_vec1()?;
+        assert_eq!(v, vec![0., 1., 6., 7., 8., 9.]);
+        assert!(window.validate(2).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,255 @@
+//! Эмбеддинги текстов из скрытых состояний декодера
+//!
+//! Тексты батча дополняются справа до общей длины. Causal-маска не даёт
+//! реальным токенам видеть паддинг, поэтому их скрытые состояния совпадают
+//! с прогоном по одному тексту, а длины текстов исключают паддинг из пулинга.
+
+use candle::{DType, Device, Result, Tensor};
+use serde::{Deserialize, Serialize};
+
+use crate::models::ModelBackend;
+
+/// Сколько текстов прогоняется через модель за один forward
+pub const EMBEDDING_BATCH_SIZE: usize = 8;
+
+/// Способ свёртки скрытых состояний текста в один вектор
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pooling {
+    /// Среднее по токенам текста
+    #[default]
+    Mean,
+    /// Состояние последнего токена (E5-Mistral, gte-Qwen, Qwen3-Embedding)
+    #[serde(alias = "last")]
+    LastToken,
+    /// Состояние первого токена
+    Cls,
+}
+
+/// Параметры построения эмбеддингов
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EmbeddingOptions {
+    pub pooling: Pooling,
+    /// L2-нормализация итогового вектора
+    pub normalize: bool,
+    /// Оставить первые N компонент (Matryoshka); нормализация идёт после обрезки
+    pub dimensions: Option<usize>,
+}
+
+/// Ошибка `get_embeddings` для вариантов модели, не отдающих скрытые состояния
+pub fn embeddings_unsupported(model: &str) -> candle::Error {
+    candle::Error::Msg(format!("Embeddings are not supported for {model}"))
+}
+
+/// Дополняет последовательности справа до общей длины: [batch, max_len]
+pub fn pad_batch(batch: &[Vec<u32>], pad_id: u32, device: &Device) -> Result<Tensor> {
+    let max_len = batch.iter().map(Vec::len).max().unwrap_or(0);
+    let ids: Vec<u32> = batch
+        .iter()
+        .flat_map(|tokens| {
+            tokens
+                .iter()
+                .copied()
+                .chain(std::iter::repeat_n(pad_id, max_len - tokens.len()))
+        })
+        .collect();
+    Tensor::from_vec(ids, (batch.len(), max_len), device)
+}
+
+/// Сворачивает скрытые состояния [batch, seq_len, hidden] в векторы текстов
+///
+/// `lengths` — число реальных токенов каждой строки батча.
+pub fn pool(
+    hidden: &Tensor,
+    lengths: &[usize],
+    options: &EmbeddingOptions,
+) -> Result<Vec<Vec<f32>>> {
+    let hidden = hidden.to_dtype(DType::F32)?;
+    let (batch, seq_len, hidden_size) = hidden.dims3()?;
+    if lengths.len() != batch {
+        candle::bail!("{} lengths for a batch of {}", lengths.len(), batch);
+    }
+    if lengths.iter().any(|&len| len == 0 || len > seq_len) {
+        candle::bail!("Sequence lengths must be within 1..={}", seq_len);
+    }
+    let device = hidden.device();
+
+    let pooled = match options.pooling {
+        Pooling::Mean => {
+            let mask: Vec<f32> = lengths
+                .iter()
+                .flat_map(|&len| (0..seq_len).map(move |i| if i < len { 1.0 } else { 0.0 }))
+                .collect();
+            let mask = Tensor::from_vec(mask, (batch, seq_len, 1), device)?;
+            let counts: Vec<f32> = lengths.iter().map(|&len| len as f32).collect();
+            let counts = Tensor::from_vec(counts, (batch, 1), device)?;
+            hidden
+                .broadcast_mul(&mask)?
+                .sum(1)?
+                .broadcast_div(&counts)?
+        }
+        Pooling::LastToken => {
+            let rows = lengths
+                .iter()
+                .enumerate()
+                .map(|(i, &len)| hidden.get(i)?.get(len - 1))
+                .collect::<Result<Vec<_>>>()?;
+            Tensor::stack(&rows, 0)?
+        }
+        Pooling::Cls => hidden.narrow(1, 0, 1)?.squeeze(1)?,
+    };
+
+    shape_embeddings(pooled.to_vec2()?, hidden_size, options).map_err(candle::Error::Msg)
+}
+
+/// Обрезает векторы до `options.dimensions` (Matryoshka) и нормализует после обрезки
+///
+/// Ошибка означает `dimensions` вне 1..=`hidden_size`: это ошибка запроса, а не модели.
+pub fn shape_embeddings(
+    mut vectors: Vec<Vec<f32>>,
+    hidden_size: usize,
+    options: &EmbeddingOptions,
+) -> std::result::Result<Vec<Vec<f32>>, String> {
+    if let Some(dims) = options.dimensions {
+        if dims == 0 || dims > hidden_size {
+            return Err(format!("dimensions must be within 1..={}", hidden_size));
+        }
+        for vector in vectors.iter_mut() {
+            vector.truncate(dims);
+        }
+    }
+    if options.normalize {
+        for vector in vectors.iter_mut() {
+            let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt().max(1e-12);
+            vector.iter_mut().for_each(|x| *x /= norm);
+        }
+    }
+    Ok(vectors)
+}
+
+/// Эмбеддинги токенизированных текстов батчами по [`EMBEDDING_BATCH_SIZE`]
+///
+/// Прогон идёт с позиции 0, поэтому KV-кэш модели сбрасывается до и после:
+/// вызывающая сторона должна забыть сохранённые префиксы.
+pub fn embed_token_batches(
+    model: &mut dyn ModelBackend,
+    texts: &[Vec<u32>],
+    options: &EmbeddingOptions,
+    device: &Device,
+) -> Result<Vec<Vec<f32>>> {
+    if texts.iter().any(Vec::is_empty) {
+        candle::bail!("Cannot embed an empty input");
+    }
+    let mut embeddings = Vec::with_capacity(texts.len());
+    let result = texts
+        .chunks(EMBEDDING_BATCH_SIZE)
+        .try_for_each(|chunk| -> Result<()> {
+            model.clear_kv_cache();
+            // Значение паддинга не влияет на реальные токены
+            let input = pad_batch(chunk, 0, device)?;
+            let lengths: Vec<usize> = chunk.iter().map(Vec::len).collect();
+            let hidden = model.get_embeddings(&input)?;
+            embeddings.extend(pool(&hidden, &lengths, options)?);
+            Ok(())
+        });
+    model.clear_kv_cache();
+    result.map(|_| embeddings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Causal-модель: состояние токена — накопленная сумма id до него включительно
+    struct PrefixSumModel;
+
+    impl ModelBackend for PrefixSumModel {
+        fn forward(&mut self, _input: &Tensor, _pos: usize) -> Result<Tensor> {
+            candle::bail!("unused")
+        }
+
+        fn clear_kv_cache(&mut self) {}
+
+        fn model_type(&self) -> &str {
+            "prefix-sum"
+        }
+
+        fn vocab_size(&self) -> usize {
+            16
+        }
+
+        fn get_embeddings(&mut self, input: &Tensor) -> Result<Tensor> {
+            let ids = input.to_dtype(DType::F32)?.cumsum(1)?;
+            Tensor::stack(&[&ids, &(ids.clone() * 2.0)?], 2)
+        }
+    }
+
+    #[test]
+    fn pooling_ignores_padding() -> Result<()> {
+        let dev = Device::Cpu;
+        // Строка 0: 3 токена, строка 1: 1 токен + 2 позиции паддинга
+        let hidden = Tensor::new(
+            &[
+                [[1f32, 0.], [3., 4.], [5., 8.]],
+                [[2., 2.], [9., 9.], [9., 9.]],
+            ],
+            &dev,
+        )?;
+        let lengths = [3, 1];
+        let opts = |pooling| EmbeddingOptions {
+            pooling,
+            ..Default::default()
+        };
+
+        let mean = pool(&hidden, &lengths, &opts(Pooling::Mean))?;
+        assert_eq!(mean, vec![vec![3., 4.], vec![2., 2.]]);
+        let last = pool(&hidden, &lengths, &opts(Pooling::LastToken))?;
+        assert_eq!(last, vec![vec![5., 8.], vec![2., 2.]]);
+        let cls = pool(&hidden, &lengths, &opts(Pooling::Cls))?;
+        assert_eq!(cls, vec![vec![1., 0.], vec![2., 2.]]);
+
+        let matryoshka = EmbeddingOptions {
+            pooling: Pooling::LastToken,
+            normalize: true,
+            dimensions: Some(1),
+        };
+        assert_eq!(
+            pool(&hidden, &lengths, &matryoshka)?,
+            vec![vec![1.], vec![1.]]
+        );
+        let normalized = EmbeddingOptions {
+            normalize: true,
+            ..Default::default()
+        };
+        let v = &pool(&hidden, &lengths, &normalized)?[0];
+        assert!((v[0] - 0.6).abs() < 1e-6 && (v[1] - 0.8).abs() < 1e-6);
+
+        let too_wide = EmbeddingOptions {
+            dimensions: Some(3),
+            ..Default::default()
+        };
+        assert!(pool(&hidden, &lengths, &too_wide).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn batched_embeddings_match_single_inputs() -> Result<()> {
+        let dev = Device::Cpu;
+        let texts: Vec<Vec<u32>> = (1..=11).map(|n| (1..=n).collect()).collect();
+        let opts = EmbeddingOptions {
+            pooling: Pooling::Mean,
+            normalize: true,
+            dimensions: None,
+        };
+        let batched = embed_token_batches(&mut PrefixSumModel, &texts, &opts, &dev)?;
+        assert_eq!(batched.len(), texts.len());
+        for (text, embedding) in texts.iter().zip(&batched) {
+            let single =
+                embed_token_batches(&mut PrefixSumModel, std::slice::from_ref(text), &opts, &dev)?;
+            assert_eq!(&single[0], embedding);
+        }
+        assert!(embed_token_batches(&mut PrefixSumModel, &[vec![]], &opts, &dev).is_err());
+        Ok(())
+    }
+}
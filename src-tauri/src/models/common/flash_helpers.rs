@@ -98,6 +98,23 @@ fn create_causal_mask(
         .unsqueeze(0) // [1, 1, seq_len, seq_len] для broadcasting
 }
 
+/// Causal-маска [b, 1, tgt, tgt + offset]
+pub fn causal_mask(
+    b: usize,
+    tgt: usize,
+    offset: usize,
+    dtype: candle::DType,
+    device: &candle::Device,
+) -> Result<Tensor> {
+    let minf = f32::NEG_INFINITY;
+    let mask: Vec<_> = (0..tgt)
+        .flat_map(|i| (0..(tgt + offset)).map(move |j| if j <= i + offset { 0. } else { minf }))
+        .collect();
+    Tensor::from_slice(&mask, (tgt, tgt + offset), device)?
+        .expand((b, 1, tgt, tgt + offset))?
+        .to_dtype(dtype)
+}
+
 /// Проверяет, доступен ли Flash Attention для текущей конфигурации
 pub fn is_flash_attention_available() -> bool {
     #[cfg(feature = "flash-attn")]
@@ -0,0 +1,310 @@
+//! GGUF через mmap
+//!
+//! Файлы модели отображаются в память с копированием при записи. На CPU
+//! блочно-квантованные тензоры ссылаются прямо на страницы отображения, а не
+//! копируются в кучу: загрузка не вычитывает файл целиком и не держит вторую
+//! копию весов, а страницы подтягиваются ОС по мере обращения. Для остальных
+//! устройств и для F32/F16/BF16 тензоры читаются из того же отображения обычным
+//! путём `Content::tensor`.
+
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::Arc;
+
+use candle::quantized::gguf_file::Content;
+use candle::quantized::k_quants::{self, GgmlType};
+use candle::quantized::{GgmlDType, QStorage, QTensor, QuantizedType};
+use candle::{CpuStorage, Device, Result};
+use candle_transformers::models::with_tracing::QMatMul;
+use candle_transformers::quantized_nn::RmsNorm;
+use half::f16;
+use memmap2::{MmapOptions, MmapRaw};
+
+/// Источник данных GGUF; отображённые в память источники отдают участки без копирования
+pub trait GgufRead: Read + Seek {
+    /// Участок `[offset, offset + len)` потока, если источник отображён в память
+    fn mapped(&self, _offset: u64, _len: usize) -> Option<MappedSlice> {
+        None
+    }
+}
+
+impl GgufRead for File {}
+impl<R: Read + Seek> GgufRead for BufReader<R> {}
+impl<T: AsRef<[u8]>> GgufRead for Cursor<T> {}
+
+impl<R: GgufRead + ?Sized> GgufRead for &mut R {
+    fn mapped(&self, offset: u64, len: usize) -> Option<MappedSlice> {
+        (**self).mapped(offset, len)
+    }
+}
+
+/// Участок отображения файла; держит отображение живым
+#[derive(Clone)]
+pub struct MappedSlice {
+    map: Arc<MmapRaw>,
+    start: usize,
+    len: usize,
+}
+
+/// Чтение файла, отображённого в память
+pub struct MmapReader {
+    map: Arc<MmapRaw>,
+    pos: u64,
+}
+
+impl MmapReader {
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file = File::open(path)?;
+        // SAFETY: отображение приватное (copy-on-write), запись в него не
+        // доходит до файла. Как и llama.cpp, считаем, что файл модели не
+        // меняется снаружи, пока модель загружена.
+        let map = unsafe { MmapOptions::new().map_copy(&file)? };
+        Ok(Self {
+            map: Arc::new(MmapRaw::from(map)),
+            pos: 0,
+        })
+    }
+}
+
+impl Read for MmapReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.map.len();
+        let start = usize::try_from(self.pos).map_or(len, |p| p.min(len));
+        let n = buf.len().min(len - start);
+        // SAFETY: участок лежит внутри отображения
+        let src = unsafe { std::slice::from_raw_parts(self.map.as_ptr().add(start), n) };
+        buf[..n].copy_from_slice(src);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for MmapReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => (self.map.len() as u64).checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        };
+        self.pos = target.ok_or_else(|| std::io::Error::other("seek before the start of GGUF"))?;
+        Ok(self.pos)
+    }
+}
+
+impl GgufRead for MmapReader {
+    fn mapped(&self, offset: u64, len: usize) -> Option<MappedSlice> {
+        let start = usize::try_from(offset).ok()?;
+        (start.checked_add(len)? <= self.map.len()).then(|| MappedSlice {
+            map: self.map.clone(),
+            start,
+            len,
+        })
+    }
+}
+
+/// Блоки квантованного тензора, лежащие в отображении файла
+struct MappedBlocks<T> {
+    map: Arc<MmapRaw>,
+    start: usize,
+    len: usize,
+    _blocks: PhantomData<T>,
+}
+
+impl<T: GgmlType> MappedBlocks<T> {
+    /// `None`, если участок не выровнен под блок или не кратен его размеру
+    fn new(slice: MappedSlice) -> Option<Self> {
+        let size = std::mem::size_of::<T>();
+        let ptr = slice.map.as_ptr().wrapping_add(slice.start);
+        (ptr.align_offset(std::mem::align_of::<T>()) == 0 && slice.len.is_multiple_of(size)).then(
+            || Self {
+                map: slice.map,
+                start: slice.start,
+                len: slice.len / size,
+                _blocks: PhantomData,
+            },
+        )
+    }
+
+    fn blocks(&self) -> &[T] {
+        // SAFETY: выравнивание и границы проверены в `new`; блоки — POD
+        unsafe { std::slice::from_raw_parts(self.map.as_ptr().add(self.start).cast(), self.len) }
+    }
+
+    fn blocks_mut(&mut self) -> &mut [T] {
+        // SAFETY: как в `blocks`; участок принадлежит только этому тензору,
+        // а запись уходит в приватные копии страниц
+        unsafe {
+            std::slice::from_raw_parts_mut(self.map.as_mut_ptr().add(self.start).cast(), self.len)
+        }
+    }
+}
+
+impl<T: GgmlType> QuantizedType for MappedBlocks<T> {
+    fn dtype(&self) -> GgmlDType {
+        T::DTYPE
+    }
+
+    fn matmul_t(&self, mkn: (usize, usize, usize), lhs: &[f32], dst: &mut [f32]) -> Result<()> {
+        k_quants::matmul(mkn, lhs, self.blocks(), dst)
+    }
+
+    fn matmul_t_f16(&self, mkn: (usize, usize, usize), lhs: &[f16], dst: &mut [f16]) -> Result<()> {
+        k_quants::matmul_f16(mkn, lhs, self.blocks(), dst)
+    }
+
+    fn dequantize(&self, elem_count: usize) -> Result<CpuStorage> {
+        let mut ys = vec![0.0f32; elem_count];
+        T::to_float(self.blocks(), &mut ys);
+        Ok(CpuStorage::F32(ys))
+    }
+
+    fn storage_size_in_bytes(&self) -> usize {
+        self.len * std::mem::size_of::<T>()
+    }
+
+    fn as_ptr(&self) -> *const u8 {
+        self.blocks().as_ptr().cast()
+    }
+
+    fn block_size(&self) -> usize {
+        T::BLCK_SIZE
+    }
+
+    fn from_float(&mut self, xs: &[f32]) {
+        T::from_float(xs, self.blocks_mut())
+    }
+
+    fn from_float_imatrix(&mut self, xs: &[f32], imatrix_weights: &[f32], n_per_row: usize) {
+        T::from_float_imatrix(xs, self.blocks_mut(), imatrix_weights, n_per_row)
+    }
+
+    fn size(&self) -> usize {
+        self.storage_size_in_bytes()
+    }
+}
+
+fn mapped_storage(dtype: GgmlDType, slice: MappedSlice) -> Option<Box<dyn QuantizedType>> {
+    fn boxed<T: GgmlType + 'static>(slice: MappedSlice) -> Option<Box<dyn QuantizedType>> {
+        MappedBlocks::<T>::new(slice).map(|b| Box::new(b) as Box<dyn QuantizedType>)
+    }
+    match dtype {
+        GgmlDType::Q4_0 => boxed::<k_quants::BlockQ4_0>(slice),
+        GgmlDType::Q4_1 => boxed::<k_quants::BlockQ4_1>(slice),
+        GgmlDType::Q5_0 => boxed::<k_quants::BlockQ5_0>(slice),
+        GgmlDType::Q5_1 => boxed::<k_quants::BlockQ5_1>(slice),
+        GgmlDType::Q8_0 => boxed::<k_quants::BlockQ8_0>(slice),
+        GgmlDType::Q8_1 => boxed::<k_quants::BlockQ8_1>(slice),
+        GgmlDType::Q2K => boxed::<k_quants::BlockQ2K>(slice),
+        GgmlDType::Q3K => boxed::<k_quants::BlockQ3K>(slice),
+        GgmlDType::Q4K => boxed::<k_quants::BlockQ4K>(slice),
+        GgmlDType::Q5K => boxed::<k_quants::BlockQ5K>(slice),
+        GgmlDType::Q6K => boxed::<k_quants::BlockQ6K>(slice),
+        GgmlDType::Q8K => boxed::<k_quants::BlockQ8K>(slice),
+        // Плотные веса всё равно деквантуются при сборке QMatMul/RmsNorm
+        GgmlDType::F32 | GgmlDType::F16 | GgmlDType::BF16 => None,
+    }
+}
+
+/// Тензор GGUF: на CPU из отображения без копирования, иначе через `Content::tensor`
+pub fn gguf_tensor<R: GgufRead>(
+    ct: &Content,
+    reader: &mut R,
+    name: &str,
+    device: &Device,
+) -> Result<QTensor> {
+    if device.is_cpu()
+        && let Some(info) = ct.tensor_infos.get(name)
+    {
+        let dtype = info.ggml_dtype;
+        let size = info.shape.elem_count() / dtype.block_size() * dtype.type_size();
+        if let Some(storage) = reader
+            .mapped(ct.tensor_data_offset + info.offset, size)
+            .and_then(|slice| mapped_storage(dtype, slice))
+        {
+            return QTensor::new(QStorage::Cpu(storage), info.shape.clone());
+        }
+    }
+    ct.tensor(reader, name, device)
+}
+
+/// Замена `candle_transformers::models::quantized_qwen3::Gguf`, читающая тензоры через [`gguf_tensor`]
+pub struct Gguf<R: GgufRead> {
+    ct: Content,
+    reader: R,
+    device: Device,
+}
+
+impl<R: GgufRead> Gguf<R> {
+    pub fn new(ct: Content, reader: R, device: Device) -> Self {
+        Self { ct, reader, device }
+    }
+
+    pub fn qmatmul(&mut self, name: &str) -> Result<QMatMul> {
+        let ws = self.tensor(name)?;
+        QMatMul::from_weights(ws.into())
+    }
+
+    pub fn rms_norm(&mut self, name: &str, eps: f64) -> Result<RmsNorm> {
+        let ws = self.tensor(name)?;
+        RmsNorm::from_qtensor(ws, eps)
+    }
+
+    pub fn metadata(
+        &self,
+    ) -> &std::collections::HashMap<String, candle::quantized::gguf_file::Value> {
+        &self.ct.metadata
+    }
+
+    pub fn tensor(&mut self, name: &str) -> Result<QTensor> {
+        gguf_tensor(&self.ct, &mut self.reader, name, &self.device)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle::quantized::gguf_file;
+    use candle::{Module, Tensor};
+
+    #[test]
+    fn cpu_tensors_reference_the_mapping() -> Result<()> {
+        let dev = Device::Cpu;
+        let weights = Tensor::arange(0f32, 512., &dev)?
+            .reshape((2, 256))?
+            .affine(0.01, -1.)?;
+        let q4k = QTensor::quantize(&weights, GgmlDType::Q4K)?;
+        let norm = QTensor::quantize(
+            &Tensor::ones(256, candle::DType::F32, &dev)?,
+            GgmlDType::F32,
+        )?;
+        let path =
+            std::env::temp_dir().join(format!("oxide-gguf-mmap-{}.gguf", std::process::id()));
+        let mut file = File::create(&path)?;
+        gguf_file::write(&mut file, &[], &[("w", &q4k), ("norm", &norm)])?;
+        drop(file);
+
+        let mut reader = MmapReader::open(&path)?;
+        let ct = Content::read(&mut reader)?;
+        let mapped = gguf_tensor(&ct, &mut reader, "w", &dev)?;
+        let copied = ct.tensor(&mut reader, "w", &dev)?;
+        let dense = gguf_tensor(&ct, &mut reader, "norm", &dev)?;
+        std::fs::remove_file(&path).ok();
+
+        // Данные тензора лежат внутри отображения
+        let base = reader.map.as_ptr() as usize;
+        let data = mapped.data()?;
+        let ptr = data.as_ptr() as usize;
+        assert!(ptr >= base && ptr + data.len() <= base + reader.map.len());
+        assert_eq!(data.len(), copied.storage_size_in_bytes());
+        drop(data);
+
+        let xs = Tensor::arange(0f32, 256., &dev)?.reshape((1, 256))?;
+        let mapped_out = candle::quantized::QMatMul::from_qtensor(mapped)?.forward(&xs)?;
+        let copied_out = candle::quantized::QMatMul::from_qtensor(copied)?.forward(&xs)?;
+        assert_eq!(mapped_out.to_vec2::<f32>()?, copied_out.to_vec2::<f32>()?);
+        assert_eq!(dense.dequantize(&dev)?.to_vec1::<f32>()?, vec![1f32; 256]);
+        Ok(())
+    }
+}
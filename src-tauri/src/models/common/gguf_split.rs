@@ -0,0 +1,262 @@
+//! GGUF, разбитые на шарды (`model-00001-of-00003.gguf`)
+//!
+//! llama.cpp (`gguf-split`) пишет метаданные модели в первый шард, а тензоры
+//! распределяет по всем файлам; каждый шард — самостоятельный GGUF с ключами
+//! `split.no` / `split.count`. Индексы тензоров шардов сливаются в один
+//! `Content`, смещения которого указывают в склеенный поток всех файлов,
+//! поэтому загрузчики моделей работают с шардами так же, как с одним файлом.
+
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use candle::quantized::gguf_file::{Content, TensorInfo};
+
+use super::gguf_mmap::{GgufRead, MappedSlice, MmapReader};
+
+/// Имя шарда: `<prefix>-<index>-of-<count>.gguf` (нумерация с 1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShardName<'a> {
+    pub prefix: &'a str,
+    pub index: u32,
+    pub count: u32,
+}
+
+impl ShardName<'_> {
+    /// Имя файла шарда `index` той же группы
+    pub fn file_name(&self, index: u32) -> String {
+        format!("{}-{:05}-of-{:05}.gguf", self.prefix, index, self.count)
+    }
+
+    /// Имена всех файлов группы по порядку
+    pub fn file_names(&self) -> Vec<String> {
+        (1..=self.count).map(|i| self.file_name(i)).collect()
+    }
+}
+
+/// Разбирает имя файла шарда; `None` для обычных GGUF
+pub fn parse_shard_name(file_name: &str) -> Option<ShardName<'_>> {
+    let stem = file_name
+        .strip_suffix(".gguf")
+        .or_else(|| file_name.strip_suffix(".GGUF"))?;
+    let (rest, count) = stem.rsplit_once("-of-")?;
+    let (prefix, index) = rest.rsplit_once('-')?;
+    let is_number = |s: &str| s.len() == 5 && s.bytes().all(|b| b.is_ascii_digit());
+    if prefix.is_empty() || !is_number(index) || !is_number(count) {
+        return None;
+    }
+    let (index, count) = (index.parse().ok()?, count.parse().ok()?);
+    (1..=count).contains(&index).then_some(ShardName {
+        prefix,
+        index,
+        count,
+    })
+}
+
+/// Пути всех файлов модели: группа шардов или сам `path`
+pub fn gguf_paths(path: &Path) -> Vec<PathBuf> {
+    let shard = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .and_then(parse_shard_name);
+    match shard {
+        Some(shard) => shard
+            .file_names()
+            .into_iter()
+            .map(|name| path.with_file_name(name))
+            .collect(),
+        None => vec![path.to_path_buf()],
+    }
+}
+
+/// Поток, склеивающий файлы шардов друг за другом
+pub struct ShardedReader<R> {
+    /// Файл и его смещение в склеенном потоке
+    parts: Vec<(R, u64)>,
+    len: u64,
+    pos: u64,
+}
+
+impl<R: Read + Seek> Read for ShardedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let i = self.parts.partition_point(|(_, start)| *start <= self.pos) - 1;
+        let end = self.parts.get(i + 1).map_or(self.len, |(_, start)| *start);
+        let (part, start) = &mut self.parts[i];
+        part.seek(SeekFrom::Start(self.pos - *start))?;
+        let max = (end - self.pos).min(buf.len() as u64) as usize;
+        let n = part.read(&mut buf[..max])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R> Seek for ShardedReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.len.checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        };
+        self.pos = target.ok_or_else(|| std::io::Error::other("seek before the start of GGUF"))?;
+        Ok(self.pos)
+    }
+}
+
+impl<R: GgufRead> GgufRead for ShardedReader<R> {
+    fn mapped(&self, offset: u64, len: usize) -> Option<MappedSlice> {
+        let i = self
+            .parts
+            .partition_point(|(_, start)| *start <= offset)
+            .checked_sub(1)?;
+        let end = self.parts.get(i + 1).map_or(self.len, |(_, start)| *start);
+        let (part, start) = &self.parts[i];
+        if offset.checked_add(len as u64)? > end {
+            return None;
+        }
+        part.mapped(offset - start, len)
+    }
+}
+
+/// Сливает заголовки шардов в один `Content` над склеенным потоком
+///
+/// Метаданные берутся из первого шарда. Шарды передаются по порядку.
+pub fn merge_shards<R: Read + Seek>(
+    readers: Vec<R>,
+) -> candle::Result<(Content, ShardedReader<R>)> {
+    let count = readers.len();
+    let mut merged: Option<Content> = None;
+    let mut parts = Vec::with_capacity(count);
+    let mut start = 0u64;
+    for (i, mut reader) in readers.into_iter().enumerate() {
+        let content = Content::read(&mut reader)?;
+        let len = reader.seek(SeekFrom::End(0))?;
+        if let Some(declared) = content.metadata.get("split.count") {
+            let declared = declared.to_u16()? as usize;
+            if declared != count {
+                candle::bail!(
+                    "GGUF shard {} declares {declared} shards, got {count}",
+                    i + 1
+                );
+            }
+        }
+        let base = start + content.tensor_data_offset;
+        let merged = merged.get_or_insert_with(|| Content {
+            magic: content.magic,
+            metadata: content.metadata.clone(),
+            tensor_infos: Default::default(),
+            tensor_data_offset: 0,
+        });
+        for (name, info) in content.tensor_infos {
+            let info = TensorInfo {
+                offset: base + info.offset,
+                ..info
+            };
+            if merged.tensor_infos.insert(name.clone(), info).is_some() {
+                candle::bail!("tensor {name} is present in several GGUF shards");
+            }
+        }
+        parts.push((reader, start));
+        start += len;
+    }
+    let content = merged.ok_or_else(|| candle::Error::Msg("no GGUF files to load".into()))?;
+    Ok((
+        content,
+        ShardedReader {
+            parts,
+            len: start,
+            pos: 0,
+        },
+    ))
+}
+
+/// Открывает GGUF по пути одного файла или любого из шардов (через mmap)
+pub fn open_gguf(path: &Path) -> Result<(Content, ShardedReader<MmapReader>), String> {
+    let files = gguf_paths(path)
+        .iter()
+        .map(|p| {
+            MmapReader::open(p)
+                .map_err(|e| format!("Failed to open GGUF file {}: {}", p.display(), e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    merge_shards(files).map_err(|e| e.with_path(path).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle::quantized::{GgmlDType, QTensor, gguf_file};
+    use candle::{Device, Tensor};
+    use std::io::Cursor;
+
+    #[test]
+    fn parses_shard_names() {
+        let shard = parse_shard_name("Qwen3-32B-Q4_K_M-00002-of-00003.gguf").unwrap();
+        assert_eq!(shard.prefix, "Qwen3-32B-Q4_K_M");
+        assert_eq!((shard.index, shard.count), (2, 3));
+        assert_eq!(
+            shard.file_names(),
+            [
+                "Qwen3-32B-Q4_K_M-00001-of-00003.gguf",
+                "Qwen3-32B-Q4_K_M-00002-of-00003.gguf",
+                "Qwen3-32B-Q4_K_M-00003-of-00003.gguf",
+            ]
+        );
+        assert_eq!(parse_shard_name("model-Q4_K_M.gguf"), None);
+        assert_eq!(parse_shard_name("model-00004-of-00003.gguf"), None);
+        assert_eq!(parse_shard_name("model-1-of-3.gguf"), None);
+    }
+
+    fn shard(
+        metadata: &[(&str, &gguf_file::Value)],
+        tensors: &[(&str, &QTensor)],
+    ) -> candle::Result<Cursor<Vec<u8>>> {
+        let mut buf = Cursor::new(Vec::new());
+        gguf_file::write(&mut buf, metadata, tensors)?;
+        buf.set_position(0);
+        Ok(buf)
+    }
+
+    #[test]
+    fn merges_tensor_indices_across_shards() -> candle::Result<()> {
+        let dev = Device::Cpu;
+        let a = QTensor::quantize(&Tensor::arange(0f32, 64., &dev)?, GgmlDType::F32)?;
+        let b = QTensor::quantize(
+            &Tensor::arange(0f32, 64., &dev)?.reshape((2, 32))?,
+            GgmlDType::Q8_0,
+        )?;
+        let c = QTensor::quantize(&Tensor::ones(8, candle::DType::F32, &dev)?, GgmlDType::F32)?;
+        let count = gguf_file::Value::U16(2);
+        let arch = gguf_file::Value::String("llama".into());
+        let first = shard(
+            &[("general.architecture", &arch), ("split.count", &count)],
+            &[("a", &a)],
+        )?;
+        let second = shard(&[("split.count", &count)], &[("b", &b), ("c", &c)])?;
+
+        let (content, mut reader) = merge_shards(vec![first, second])?;
+        assert_eq!(content.tensor_infos.len(), 3);
+        assert!(content.metadata.contains_key("general.architecture"));
+        let load = |name: &str, reader: &mut ShardedReader<_>| -> candle::Result<Vec<f32>> {
+            content
+                .tensor(reader, name, &dev)?
+                .dequantize(&dev)?
+                .flatten_all()?
+                .to_vec1()
+        };
+        assert_eq!(
+            load("a", &mut reader)?,
+            a.dequantize(&dev)?.to_vec1::<f32>()?
+        );
+        assert_eq!(
+            load("b", &mut reader)?,
+            b.dequantize(&dev)?.flatten_all()?.to_vec1::<f32>()?
+        );
+        assert_eq!(load("c", &mut reader)?, vec![1f32; 8]);
+
+        let lone = shard(&[("split.count", &count)], &[("a", &a)])?;
+        assert!(merge_shards(vec![lone]).is_err());
+        Ok(())
+    }
+}
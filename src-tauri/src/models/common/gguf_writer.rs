@@ -0,0 +1,223 @@
+//! Потоковая запись GGUF v3
+//!
+//! `candle::quantized::gguf_file::write` принимает все тензоры сразу как
+//! `&[(&str, &QTensor)]`, требует `Seek` и пишет GGUF v2, а сериализация
+//! значений и коды типов у него закрыты. Конвертеру нужно иначе: заголовок
+//! со смещениями пишется до квантования, затем тензоры по одному, так что
+//! в памяти находится только текущий тензор, а приёмником служит любой `Write`.
+
+use std::io::Write;
+
+use candle::quantized::GgmlDType;
+use candle::quantized::gguf_file::{self, Value};
+
+/// Выравнивание данных тензоров в записываемых GGUF
+pub const GGUF_ALIGNMENT: u64 = gguf_file::DEFAULT_ALIGNMENT;
+
+/// Описание тензора в заголовке: форма и формат известны до квантования
+#[derive(Debug, Clone)]
+pub struct TensorHeader {
+    pub name: String,
+    pub dims: Vec<usize>,
+    pub dtype: GgmlDType,
+}
+
+impl TensorHeader {
+    /// Размер данных тензора в байтах (без выравнивания)
+    pub fn size_in_bytes(&self) -> u64 {
+        let elems: usize = self.dims.iter().product();
+        (elems / self.dtype.block_size() * self.dtype.type_size()) as u64
+    }
+}
+
+/// Код типа тензора в заголовке GGUF
+fn ggml_type_id(dtype: GgmlDType) -> u32 {
+    match dtype {
+        GgmlDType::F32 => 0,
+        GgmlDType::F16 => 1,
+        GgmlDType::Q4_0 => 2,
+        GgmlDType::Q4_1 => 3,
+        GgmlDType::Q5_0 => 6,
+        GgmlDType::Q5_1 => 7,
+        GgmlDType::Q8_0 => 8,
+        GgmlDType::Q8_1 => 9,
+        GgmlDType::Q2K => 10,
+        GgmlDType::Q3K => 11,
+        GgmlDType::Q4K => 12,
+        GgmlDType::Q5K => 13,
+        GgmlDType::Q6K => 14,
+        GgmlDType::Q8K => 15,
+        GgmlDType::BF16 => 30,
+    }
+}
+
+/// Код типа значения метаданных GGUF
+fn value_type_id(value: &Value) -> u32 {
+    match value {
+        Value::U8(_) => 0,
+        Value::I8(_) => 1,
+        Value::U16(_) => 2,
+        Value::I16(_) => 3,
+        Value::U32(_) => 4,
+        Value::I32(_) => 5,
+        Value::F32(_) => 6,
+        Value::Bool(_) => 7,
+        Value::String(_) => 8,
+        Value::Array(_) => 9,
+        Value::U64(_) => 10,
+        Value::I64(_) => 11,
+        Value::F64(_) => 12,
+    }
+}
+
+fn write_string<W: Write>(w: &mut W, s: &str) -> std::io::Result<()> {
+    w.write_all(&(s.len() as u64).to_le_bytes())?;
+    w.write_all(s.as_bytes())
+}
+
+fn write_value<W: Write>(w: &mut W, value: &Value) -> std::io::Result<()> {
+    match value {
+        Value::U8(v) => w.write_all(&v.to_le_bytes()),
+        Value::I8(v) => w.write_all(&v.to_le_bytes()),
+        Value::U16(v) => w.write_all(&v.to_le_bytes()),
+        Value::I16(v) => w.write_all(&v.to_le_bytes()),
+        Value::U32(v) => w.write_all(&v.to_le_bytes()),
+        Value::I32(v) => w.write_all(&v.to_le_bytes()),
+        Value::U64(v) => w.write_all(&v.to_le_bytes()),
+        Value::I64(v) => w.write_all(&v.to_le_bytes()),
+        Value::F32(v) => w.write_all(&v.to_le_bytes()),
+        Value::F64(v) => w.write_all(&v.to_le_bytes()),
+        Value::Bool(v) => w.write_all(&[u8::from(*v)]),
+        Value::String(v) => write_string(w, v),
+        Value::Array(items) => {
+            // Тип пустого массива не важен
+            let item_type = items.first().map_or(4, value_type_id);
+            if items.iter().any(|v| value_type_id(v) != item_type) {
+                return Err(std::io::Error::other(
+                    "GGUF array holds values of different types",
+                ));
+            }
+            w.write_all(&item_type.to_le_bytes())?;
+            w.write_all(&(items.len() as u64).to_le_bytes())?;
+            items.iter().try_for_each(|v| write_value(w, v))
+        }
+    }
+}
+
+/// Писатель GGUF v3, считающий записанные байты (для выравнивания без Seek)
+pub struct GgufWriter<W> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> GgufWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, written: 0 }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.inner.write_all(buf)?;
+        self.written += buf.len() as u64;
+        Ok(())
+    }
+
+    fn pad(&mut self) -> std::io::Result<()> {
+        let padding = self.written.next_multiple_of(GGUF_ALIGNMENT) - self.written;
+        self.write_all(&vec![0u8; padding as usize])
+    }
+
+    /// Пишет метаданные и описания тензоров со смещениями, выравнивая начало данных
+    ///
+    /// Данные тензоров затем передаются в [`GgufWriter::write_tensor`] в том же порядке.
+    pub fn write_header(
+        &mut self,
+        metadata: &[(&str, &Value)],
+        tensors: &[TensorHeader],
+    ) -> std::io::Result<()> {
+        let mut buf = Vec::new();
+        buf.write_all(b"GGUF")?;
+        buf.write_all(&3u32.to_le_bytes())?;
+        buf.write_all(&(tensors.len() as u64).to_le_bytes())?;
+        buf.write_all(&(metadata.len() as u64).to_le_bytes())?;
+        for (key, value) in metadata {
+            write_string(&mut buf, key)?;
+            buf.write_all(&value_type_id(value).to_le_bytes())?;
+            write_value(&mut buf, value)?;
+        }
+        let mut offset = 0u64;
+        for tensor in tensors {
+            write_string(&mut buf, &tensor.name)?;
+            buf.write_all(&(tensor.dims.len() as u32).to_le_bytes())?;
+            for &dim in tensor.dims.iter().rev() {
+                buf.write_all(&(dim as u64).to_le_bytes())?;
+            }
+            buf.write_all(&ggml_type_id(tensor.dtype).to_le_bytes())?;
+            buf.write_all(&offset.to_le_bytes())?;
+            offset += tensor.size_in_bytes().next_multiple_of(GGUF_ALIGNMENT);
+        }
+        self.write_all(&buf)?;
+        self.pad()
+    }
+
+    /// Пишет данные очередного тензора и выравнивание после них
+    pub fn write_tensor(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.write_all(data)?;
+        self.pad()
+    }
+
+    /// Сбрасывает буферы приёмника
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle::{Device, Tensor};
+    use std::io::Cursor;
+
+    #[test]
+    fn candle_reads_streamed_gguf() -> candle::Result<()> {
+        let name = Value::String("tiny".into());
+        let ids = Value::Array(vec![Value::U32(1), Value::U32(2)]);
+        let tensors = vec![
+            TensorHeader {
+                name: "a".into(),
+                dims: vec![2, 3],
+                dtype: GgmlDType::F32,
+            },
+            TensorHeader {
+                name: "b".into(),
+                dims: vec![5],
+                dtype: GgmlDType::F32,
+            },
+        ];
+        let a = Tensor::arange(0f32, 6., &Device::Cpu)?;
+        let b = Tensor::arange(10f32, 15., &Device::Cpu)?;
+
+        let mut w = GgufWriter::new(Vec::new());
+        w.write_header(&[("general.name", &name), ("ids", &ids)], &tensors)?;
+        for t in [&a, &b] {
+            let bytes: Vec<u8> = t
+                .to_vec1::<f32>()?
+                .iter()
+                .flat_map(|v| v.to_le_bytes())
+                .collect();
+            w.write_tensor(&bytes)?;
+        }
+        let mut file = Cursor::new(w.finish()?);
+
+        let content = gguf_file::Content::read(&mut file)?;
+        assert!(matches!(content.magic, gguf_file::VersionedMagic::GgufV3));
+        assert_eq!(content.metadata["general.name"].to_string()?, "tiny");
+        assert_eq!(content.metadata["ids"].to_vec()?.len(), 2);
+        let b_read = content
+            .tensor(&mut file, "b", &Device::Cpu)?
+            .dequantize(&Device::Cpu)?;
+        assert_eq!(b_read.dims(), &[5]);
+        assert_eq!(b_read.to_vec1::<f32>()?, b.to_vec1::<f32>()?);
+        Ok(())
+    }
+}
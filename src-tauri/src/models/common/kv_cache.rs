@@ -0,0 +1,481 @@
+//! KV-кэш с опциональным квантованием записей (Q8_0 / Q4_0)
+//!
+//! В режиме `Full` ведёт себя как `ConcatKvCache`. В квантованных режимах
+//! записи копятся в хвосте полной точности и группами по [`QUANT_GROUP`] позиций
+//! дописываются блоками GGML в общее хранилище: уже упакованные блоки не
+//! переквантуются ни при дописывании, ни при обрезке и сдвиге (кроме ключей,
+//! которые сдвиг поворачивает). Для attention хранилище деквантуется одним
+//! проходом на шаг. Память на позицию: ~1.06 байта на элемент для Q8_0 и ~0.56
+//! для Q4_0 против 4 байт F32.
+
+use std::sync::Arc;
+
+use candle::quantized::ggml_file::qtensor_from_ggml;
+use candle::quantized::{GgmlDType, QTensor};
+use candle::{DType, Result, Tensor};
+use candle_nn::kv_cache::ConcatKvCache;
+use serde::{Deserialize, Serialize};
+
+use super::context_shift::{RopeShift, shift_concat_cache};
+use super::kv_state::LayerKv;
+
+/// Сколько позиций хвоста упаковывается за раз
+pub const QUANT_GROUP: usize = 64;
+
+/// Формат хранения KV-кэша
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KvCacheType {
+    /// dtype модели (без квантования)
+    #[default]
+    Full,
+    /// 8-битные блоки Q8_0
+    Q8,
+    /// 4-битные блоки Q4_0
+    Q4,
+}
+
+impl KvCacheType {
+    /// Тип GGML-блоков (`None` для `Full`)
+    pub fn ggml_dtype(self) -> Option<GgmlDType> {
+        match self {
+            Self::Full => None,
+            Self::Q8 => Some(GgmlDType::Q8_0),
+            Self::Q4 => Some(GgmlDType::Q4_0),
+        }
+    }
+
+    /// Имя формата, как в настройках (`full`, `q8`, `q4`)
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Full => "full",
+            Self::Q8 => "q8",
+            Self::Q4 => "q4",
+        }
+    }
+
+    /// Разбирает имя из [`KvCacheType::as_str`]
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Self::Full, Self::Q8, Self::Q4]
+            .into_iter()
+            .find(|t| t.as_str() == name)
+    }
+
+    /// Байт на элемент KV-кэша; `full_size` — размер элемента dtype модели
+    pub fn bytes_per_element(self, full_size: usize) -> f64 {
+        match self.ggml_dtype() {
+            None => full_size as f64,
+            Some(dtype) => dtype.type_size() as f64 / dtype.block_size() as f64,
+        }
+    }
+
+    /// Можно ли квантовать записи с такой размерностью головы
+    pub fn supports_head_dim(self, head_dim: usize) -> bool {
+        self.ggml_dtype()
+            .is_none_or(|dtype| head_dim.is_multiple_of(dtype.block_size()))
+    }
+}
+
+/// Упакованные позиции: строки по `head_dim` элементов в порядке
+/// (позиция, batch, голова), поэтому позиции дописываются и срезаются целыми блоками
+#[derive(Clone)]
+struct Packed {
+    k: Arc<QTensor>,
+    v: Arc<QTensor>,
+    len: usize,
+    batch: usize,
+    heads: usize,
+    head_dim: usize,
+}
+
+impl std::fmt::Debug for Packed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Packed").field("len", &self.len).finish()
+    }
+}
+
+impl Packed {
+    /// Квантует K/V [batch, kv_heads, seq, head_dim]
+    fn quantize(k: &Tensor, v: &Tensor, dtype: GgmlDType) -> Result<Self> {
+        let (batch, heads, len, head_dim) = k.dims4()?;
+        Ok(Self {
+            k: quantize_rows(k, dtype)?,
+            v: quantize_rows(v, dtype)?,
+            len,
+            batch,
+            heads,
+            head_dim,
+        })
+    }
+
+    fn pos_bytes(&self) -> usize {
+        let dtype = self.k.dtype();
+        self.batch * self.heads * self.head_dim / dtype.block_size() * dtype.type_size()
+    }
+
+    fn with_bytes(&self, k: &[u8], v: &[u8], len: usize) -> Result<Self> {
+        let dims = vec![len * self.batch * self.heads, self.head_dim];
+        let device = self.k.device();
+        Ok(Self {
+            k: Arc::new(qtensor_from_ggml(self.k.dtype(), k, dims.clone(), &device)?),
+            v: Arc::new(qtensor_from_ggml(self.v.dtype(), v, dims, &device)?),
+            len,
+            ..*self
+        })
+    }
+
+    /// Позиции [start, start + len) без переквантования
+    fn slice(&self, start: usize, len: usize) -> Result<Self> {
+        let step = self.pos_bytes();
+        let range = start * step..(start + len) * step;
+        self.with_bytes(&self.k.data()?[range.clone()], &self.v.data()?[range], len)
+    }
+
+    /// Дописывает блоки `other` в конец
+    fn concat(&self, other: &Self) -> Result<Self> {
+        let join = |a: &QTensor, b: &QTensor| -> Result<Vec<u8>> {
+            let mut bytes = a.data()?.into_owned();
+            bytes.extend_from_slice(&b.data()?);
+            Ok(bytes)
+        };
+        self.with_bytes(
+            &join(&self.k, &other.k)?,
+            &join(&self.v, &other.v)?,
+            self.len + other.len,
+        )
+    }
+
+    fn dequantize_rows(&self, q: &QTensor, dtype: DType) -> Result<Tensor> {
+        q.dequantize(&q.device())?
+            .reshape((self.len, self.batch, self.heads, self.head_dim))?
+            .permute((1, 2, 0, 3))?
+            .to_dtype(dtype)
+    }
+
+    /// K/V [batch, kv_heads, len, head_dim] в `dtype`
+    fn dequantize(&self, dtype: DType) -> Result<(Tensor, Tensor)> {
+        Ok((
+            self.dequantize_rows(&self.k, dtype)?,
+            self.dequantize_rows(&self.v, dtype)?,
+        ))
+    }
+
+    fn storage_size_in_bytes(&self) -> usize {
+        self.k.storage_size_in_bytes() + self.v.storage_size_in_bytes()
+    }
+}
+
+/// Квантует [batch, kv_heads, seq, head_dim] в строки (позиция, batch, голова)
+fn quantize_rows(t: &Tensor, dtype: GgmlDType) -> Result<Arc<QTensor>> {
+    let (batch, heads, len, head_dim) = t.dims4()?;
+    let rows = t
+        .permute((2, 0, 1, 3))?
+        .contiguous()?
+        .reshape((len * batch * heads, head_dim))?;
+    Ok(Arc::new(QTensor::quantize(&rows, dtype)?))
+}
+
+/// KV-кэш по оси `dim`; квантование поддерживается для раскладки
+/// [batch, kv_heads, seq, head_dim] (`dim = 2`)
+#[derive(Debug, Clone)]
+pub struct KvCache {
+    dim: usize,
+    cache_type: KvCacheType,
+    /// Хвост полной точности (весь кэш в режиме `Full`)
+    tail: ConcatKvCache,
+    /// Упакованные позиции перед хвостом
+    packed: Option<Packed>,
+    /// dtype записей (для деквантования)
+    dtype: Option<DType>,
+}
+
+impl KvCache {
+    pub fn new(dim: usize) -> Self {
+        Self::with_type(dim, KvCacheType::Full)
+    }
+
+    pub fn with_type(dim: usize, cache_type: KvCacheType) -> Self {
+        Self {
+            dim,
+            cache_type,
+            tail: ConcatKvCache::new(dim),
+            packed: None,
+            dtype: None,
+        }
+    }
+
+    pub fn cache_type(&self) -> KvCacheType {
+        self.cache_type
+    }
+
+    /// Меняет формат хранения; текущее содержимое переупаковывается при следующем `append`
+    pub fn set_cache_type(&mut self, cache_type: KvCacheType) -> Result<()> {
+        if cache_type != self.cache_type
+            && let Some((k, v)) = self.materialize()?
+        {
+            self.packed = None;
+            self.tail.reset();
+            self.tail.append(&k, &v)?;
+        }
+        self.cache_type = cache_type;
+        Ok(())
+    }
+
+    /// Добавляет записи и возвращает полный K/V в dtype модели
+    pub fn append(&mut self, k: &Tensor, v: &Tensor) -> Result<(Tensor, Tensor)> {
+        self.dtype = Some(k.dtype());
+        let (k_all, v_all) = self.tail.append(k, v)?;
+        if self.cache_type == KvCacheType::Full {
+            return Ok((k_all, v_all));
+        }
+        self.pack()?;
+        self.materialize()
+            .map(|kv| kv.expect("KV cache is non-empty after append"))
+    }
+
+    pub fn reset(&mut self) {
+        self.tail.reset();
+        self.packed = None;
+    }
+
+    fn packed_len(&self) -> usize {
+        self.packed.as_ref().map_or(0, |p| p.len)
+    }
+
+    pub fn current_seq_len(&self) -> usize {
+        self.packed_len() + self.tail.current_seq_len()
+    }
+
+    /// Полный K/V в dtype модели (`None` для пустого кэша)
+    pub fn materialize(&self) -> Result<Option<(Tensor, Tensor)>> {
+        let tail = self.tail.k().cloned().zip(self.tail.v().cloned());
+        let Some(packed) = &self.packed else {
+            return Ok(tail);
+        };
+        let (k, v) = packed.dequantize(self.dtype.unwrap_or(DType::F32))?;
+        match tail {
+            None => Ok(Some((k, v))),
+            Some((k_tail, v_tail)) => Ok(Some((
+                Tensor::cat(&[&k, &k_tail], self.dim)?,
+                Tensor::cat(&[&v, &v_tail], self.dim)?,
+            ))),
+        }
+    }
+
+    /// Удаляет позиции [keep, keep + discard) с ре-ротацией RoPE хвоста
+    ///
+    /// Упакованные блоки срезаются без переквантования; заново квантуются
+    /// только повёрнутые ключи после удалённого окна.
+    pub fn shift(&mut self, keep: usize, discard: usize, rope: &RopeShift) -> Result<()> {
+        let packed_len = self.packed_len();
+        if discard == 0 {
+            return Ok(());
+        }
+        if keep >= packed_len {
+            return shift_concat_cache(&mut self.tail, keep - packed_len, discard, rope);
+        }
+        let len = self.current_seq_len();
+        let Some(packed) = self.packed.take() else {
+            return Ok(());
+        };
+        let discard = discard.min(len - keep);
+        let window_end = keep + discard;
+
+        // Упакованная часть: префикс как есть, после окна — повёрнутые ключи
+        let mut kept = (keep > 0).then(|| packed.slice(0, keep)).transpose()?;
+        if window_end < packed_len {
+            let rest = packed.slice(window_end, packed_len - window_end)?;
+            let dtype = self.dtype.unwrap_or(DType::F32);
+            let k = rest.dequantize_rows(&rest.k, dtype)?;
+            let rotated = Packed {
+                k: quantize_rows(&rope.rerotate(&k, discard)?, rest.k.dtype())?,
+                ..rest
+            };
+            kept = Some(match kept {
+                Some(prefix) => prefix.concat(&rotated)?,
+                None => rotated,
+            });
+        }
+        self.packed = kept;
+
+        // Хвост: позиции, попавшие в окно, удаляются, остальные поворачиваются
+        if let (Some(k), Some(v)) = (self.tail.k().cloned(), self.tail.v().cloned()) {
+            let tail_len = k.dim(self.dim)?;
+            let dropped = window_end.saturating_sub(packed_len).min(tail_len);
+            self.tail.reset();
+            if dropped < tail_len {
+                let k = k.narrow(self.dim, dropped, tail_len - dropped)?;
+                let v = v.narrow(self.dim, dropped, tail_len - dropped)?;
+                let k = rope.rerotate(&k, discard)?;
+                self.tail.append(&k, &v.contiguous()?)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Снимок кэша в dtype модели (`None` для пустого кэша)
+    pub fn export(&self) -> Result<Option<LayerKv>> {
+        self.materialize()
+    }
+
+    /// Заменяет содержимое кэша снимком `kv`
+    pub fn import(&mut self, kv: &LayerKv) -> Result<()> {
+        self.reset();
+        self.append(&kv.0, &kv.1)?;
+        Ok(())
+    }
+
+    /// Обрезает кэш до первых `len` позиций (упакованные блоки срезаются как есть)
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        if len == 0 {
+            self.reset();
+            return Ok(());
+        }
+        if len >= self.current_seq_len() {
+            return Ok(());
+        }
+        let packed_len = self.packed_len();
+        if let Some(packed) = &self.packed
+            && len <= packed_len
+        {
+            self.packed = Some(packed.slice(0, len)?);
+            self.tail.reset();
+            return Ok(());
+        }
+        let keep = len - packed_len;
+        if let Some(k) = self.tail.k_mut() {
+            *k = k.narrow(self.dim, 0, keep)?;
+        }
+        if let Some(v) = self.tail.v_mut() {
+            *v = v.narrow(self.dim, 0, keep)?;
+        }
+        Ok(())
+    }
+
+    /// Дописывает целые группы хвоста в упакованное хранилище
+    fn pack(&mut self) -> Result<()> {
+        let Some(dtype) = self.cache_type.ggml_dtype() else {
+            return Ok(());
+        };
+        let (Some(k), Some(v)) = (self.tail.k().cloned(), self.tail.v().cloned()) else {
+            return Ok(());
+        };
+        if self.dim != 2 || !self.cache_type.supports_head_dim(k.dim(candle::D::Minus1)?) {
+            // Раскладка не подходит для блоков GGML: остаёмся в полной точности
+            return Ok(());
+        }
+        let len = k.dim(self.dim)?;
+        let packed_len = len / QUANT_GROUP * QUANT_GROUP;
+        if packed_len == 0 {
+            return Ok(());
+        }
+        let group = Packed::quantize(
+            &k.narrow(self.dim, 0, packed_len)?,
+            &v.narrow(self.dim, 0, packed_len)?,
+            dtype,
+        )?;
+        self.packed = Some(match self.packed.take() {
+            Some(packed) => packed.concat(&group)?,
+            None => group,
+        });
+        self.tail.reset();
+        if packed_len < len {
+            let rest = len - packed_len;
+            self.tail.append(
+                &k.narrow(self.dim, packed_len, rest)?.contiguous()?,
+                &v.narrow(self.dim, packed_len, rest)?.contiguous()?,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Байт, занимаемых кэшем
+    pub fn storage_size_in_bytes(&self) -> usize {
+        let packed = self
+            .packed
+            .as_ref()
+            .map_or(0, Packed::storage_size_in_bytes);
+        let tail = [self.tail.k(), self.tail.v()]
+            .into_iter()
+            .flatten()
+            .map(|t| t.elem_count() * t.dtype().size_in_bytes())
+            .sum::<usize>();
+        packed + tail
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle::Device;
+
+    fn max_abs_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+        (a - b)?.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()
+    }
+
+    #[test]
+    fn quantized_cache_tracks_full_cache() -> Result<()> {
+        let dev = Device::Cpu;
+        let mut full = KvCache::new(2);
+        let mut q8 = KvCache::with_type(2, KvCacheType::Q8);
+
+        // Prefill 100 позиций + 40 шагов decode
+        let prefill = Tensor::randn(0f32, 1., (1, 2, 100, 32), &dev)?;
+        full.append(&prefill, &prefill)?;
+        let (k, _) = q8.append(&prefill, &prefill)?;
+        assert_eq!(k.dims(), &[1, 2, 100, 32]);
+        for _ in 0..40 {
+            let step = Tensor::randn(0f32, 1., (1, 2, 1, 32), &dev)?;
+            full.append(&step, &step)?;
+            q8.append(&step, &step)?;
+        }
+        assert_eq!(q8.current_seq_len(), 140);
+        assert_eq!(q8.packed_len(), 128);
+        assert!(q8.storage_size_in_bytes() < full.storage_size_in_bytes() / 2);
+
+        let (fk, _) = full.materialize()?.unwrap();
+        let (qk, _) = q8.materialize()?.unwrap();
+        assert!(max_abs_diff(&fk, &qk)? < 0.05);
+
+        // Обрезка внутри упакованной группы
+        q8.truncate(70)?;
+        assert_eq!(q8.current_seq_len(), 70);
+        let (qk, _) = q8.materialize()?.unwrap();
+        assert!(max_abs_diff(&fk.narrow(2, 0, 70)?, &qk)? < 0.05);
+        Ok(())
+    }
+
+    #[test]
+    fn truncate_and_shift_keep_packed_blocks() -> Result<()> {
+        let dev = Device::Cpu;
+        let rope = RopeShift::new(32, 10000.0);
+        let mut q4 = KvCache::with_type(2, KvCacheType::Q4);
+        let kv = Tensor::randn(0f32, 1., (1, 2, 200, 32), &dev)?;
+        q4.append(&kv, &kv)?;
+        let (_, v) = q4.materialize()?.unwrap();
+
+        // Повторные обрезки и сдвиги не меняют уже упакованные значения
+        q4.truncate(150)?;
+        q4.truncate(100)?;
+        let (_, v_cut) = q4.materialize()?.unwrap();
+        assert_eq!(max_abs_diff(&v.narrow(2, 0, 100)?, &v_cut)?, 0.0);
+
+        for _ in 0..5 {
+            q4.shift(4, 3, &rope)?;
+        }
+        assert_eq!(q4.current_seq_len(), 85);
+        let (_, v_shifted) = q4.materialize()?.unwrap();
+        let expected = Tensor::cat(&[&v.narrow(2, 0, 4)?, &v.narrow(2, 19, 81)?], 2)?;
+        assert_eq!(max_abs_diff(&expected, &v_shifted)?, 0.0);
+        Ok(())
+    }
+
+    #[test]
+    fn memory_per_element() {
+        assert_eq!(KvCacheType::Full.bytes_per_element(4), 4.0);
+        assert!((KvCacheType::Q8.bytes_per_element(4) - 34.0 / 32.0).abs() < 1e-9);
+        assert!((KvCacheType::Q4.bytes_per_element(4) - 18.0 / 32.0).abs() < 1e-9);
+        assert!(!KvCacheType::Q4.supports_head_dim(80));
+        assert!(KvCacheType::Full.supports_head_dim(80));
+    }
+}
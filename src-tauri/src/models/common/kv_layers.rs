@@ -0,0 +1,132 @@
+//! Операции над KV-кэшами всех слоёв модели
+//!
+//! Модели, у которых в каждом слое свой [`KvCache`], реализуют [`KvLayers`]
+//! и получают усечение, сдвиг контекста, снимки и смену формата кэша
+//! без собственных циклов по слоям. Бекенд с несколькими вариантами весов
+//! приводит активную модель к `&dyn KvLayers` и вызывает эти методы напрямую.
+
+use candle::Result;
+
+use super::context_shift::RopeShift;
+use super::kv_cache::{KvCache, KvCacheType};
+use super::kv_state::{LayerKv, check_layer_count};
+
+/// Модель с отдельным [`KvCache`] в каждом слое
+pub trait KvLayers {
+    /// KV-кэши слоёв в порядке слоёв
+    fn kv_caches(&self) -> Vec<&KvCache>;
+
+    /// KV-кэши слоёв для изменения, в том же порядке
+    fn kv_caches_mut(&mut self) -> Vec<&mut KvCache>;
+
+    /// RoPE для ре-ротации ключей слоя `layer` при сдвиге контекста
+    ///
+    /// Ошибка означает, что сдвиг для этой модели невозможен.
+    fn shift_rope(&self, layer: usize) -> Result<RopeShift>;
+
+    /// Обрезает KV-кэш всех слоёв до `len` позиций
+    fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.kv_caches_mut()
+            .into_iter()
+            .try_for_each(|cache| cache.truncate(len))
+    }
+
+    /// Удаляет позиции [keep, keep + discard) из KV-кэша всех слоёв
+    /// с ре-ротацией RoPE оставшихся ключей
+    fn shift_kv_cache(&mut self, keep: usize, discard: usize) -> Result<()> {
+        let ropes = (0..self.kv_caches().len())
+            .map(|layer| self.shift_rope(layer))
+            .collect::<Result<Vec<_>>>()?;
+        for (cache, rope) in self.kv_caches_mut().into_iter().zip(&ropes) {
+            cache.shift(keep, discard, rope)?;
+        }
+        Ok(())
+    }
+
+    /// Снимок KV-кэша всех слоёв (ошибка, если кэш пуст)
+    fn export_kv_cache(&self) -> Result<Vec<LayerKv>> {
+        let layers = self
+            .kv_caches()
+            .into_iter()
+            .map(KvCache::export)
+            .collect::<Result<Vec<_>>>()?;
+        layers
+            .into_iter()
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| candle::Error::Msg("KV cache is empty".into()))
+    }
+
+    /// Заменяет KV-кэш всех слоёв снимком из `export_kv_cache`
+    fn import_kv_cache(&mut self, layers: &[LayerKv]) -> Result<()> {
+        let caches = self.kv_caches_mut();
+        check_layer_count(layers, caches.len())?;
+        caches
+            .into_iter()
+            .zip(layers)
+            .try_for_each(|(cache, kv)| cache.import(kv))
+    }
+
+    /// Меняет формат хранения KV-кэша всех слоёв (полная точность или блоки Q8/Q4)
+    fn set_kv_cache_type(&mut self, cache_type: KvCacheType) -> Result<()> {
+        self.kv_caches_mut()
+            .into_iter()
+            .try_for_each(|cache| cache.set_cache_type(cache_type))
+    }
+
+    /// Байт, занимаемых KV-кэшем всех слоёв
+    fn kv_cache_size_in_bytes(&self) -> usize {
+        self.kv_caches()
+            .into_iter()
+            .map(KvCache::storage_size_in_bytes)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle::{Device, Tensor};
+
+    struct TwoLayers {
+        caches: [KvCache; 2],
+    }
+
+    impl KvLayers for TwoLayers {
+        fn kv_caches(&self) -> Vec<&KvCache> {
+            self.caches.iter().collect()
+        }
+
+        fn kv_caches_mut(&mut self) -> Vec<&mut KvCache> {
+            self.caches.iter_mut().collect()
+        }
+
+        fn shift_rope(&self, _layer: usize) -> Result<RopeShift> {
+            candle::bail!("no RoPE")
+        }
+    }
+
+    #[test]
+    fn layers_round_trip_and_truncate() -> Result<()> {
+        let dev = Device::Cpu;
+        let mut model = TwoLayers {
+            caches: [KvCache::new(2), KvCache::new(2)],
+        };
+        assert!(model.export_kv_cache().is_err());
+
+        let k = Tensor::arange(0f32, 24., &dev)?.reshape((1, 2, 3, 4))?;
+        for cache in model.kv_caches_mut() {
+            cache.append(&k, &k)?;
+        }
+        let snapshot = model.export_kv_cache()?;
+        assert_eq!(snapshot.len(), 2);
+
+        model.truncate_kv_cache(1)?;
+        assert!(model.kv_caches().iter().all(|c| c.current_seq_len() == 1));
+        assert!(model.shift_kv_cache(0, 1).is_err());
+
+        model.import_kv_cache(&snapshot)?;
+        assert!(model.kv_caches().iter().all(|c| c.current_seq_len() == 3));
+        assert!(model.import_kv_cache(&snapshot[..1]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+//! Снимки KV-кэша и рекуррентного состояния для сохранения и восстановления промпта
+
+use candle::{Result, Tensor};
+use candle_nn::kv_cache::ConcatKvCache;
+
+/// K и V одного слоя: [batch, kv_heads, seq, head_dim]
+pub type LayerKv = (Tensor, Tensor);
+
+/// Снимок рекуррентного состояния (Mamba, RWKV)
+///
+/// В отличие от KV-кэша состояние имеет фиксированный размер и отражает сразу
+/// все `seq_len` обработанных токенов: обрезать его до более короткого префикса нельзя.
+#[derive(Debug, Clone)]
+pub struct RecurrentState {
+    /// Число токенов, отражённых в состоянии
+    pub seq_len: usize,
+    /// Тензоры состояния в порядке, определённом моделью
+    pub tensors: Vec<Tensor>,
+}
+
+/// Снимок ConcatKvCache (`None` для пустого кэша)
+pub fn export_concat_cache(cache: &ConcatKvCache) -> Option<LayerKv> {
+    Some((cache.k()?.clone(), cache.v()?.clone()))
+}
+
+/// Заменяет содержимое ConcatKvCache снимком `kv`
+pub fn import_concat_cache(cache: &mut ConcatKvCache, kv: &LayerKv) -> Result<()> {
+    cache.reset();
+    cache.append(&kv.0, &kv.1)?;
+    Ok(())
+}
+
+/// Проверяет, что снимок содержит по записи на каждый слой модели
+pub fn check_layer_count(layers: &[LayerKv], expected: usize) -> Result<()> {
+    if layers.len() != expected {
+        candle::bail!(
+            "KV snapshot has {} layers, model has {}",
+            layers.len(),
+            expected
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle::{DType, Device};
+
+    #[test]
+    fn concat_cache_round_trip() -> Result<()> {
+        let dev = Device::Cpu;
+        let mut cache = ConcatKvCache::new(2);
+        assert!(export_concat_cache(&cache).is_none());
+
+        let k = Tensor::arange(0f32, 24., &dev)?.reshape((1, 2, 3, 4))?;
+        let v = (&k * 2.)?;
+        cache.append(&k, &v)?;
+        let snapshot = export_concat_cache(&cache).expect("non-empty cache");
+
+        let mut restored = ConcatKvCache::new(2);
+        import_concat_cache(&mut restored, &snapshot)?;
+        assert_eq!(restored.current_seq_len(), 3);
+        let diff = (restored.v().unwrap() - &v)?
+            .abs()?
+            .sum_all()?
+            .to_dtype(DType::F32)?
+            .to_scalar::<f32>()?;
+        assert_eq!(diff, 0.0);
+        Ok(())
+    }
+}
@@ -0,0 +1,567 @@
+//! LoRA-адаптеры в формате PEFT
+//!
+//! Адаптер — каталог с `adapter_config.json` и `adapter_model.safetensors`.
+//! Применяется одним из двух способов:
+//!
+//! - side-car: [`LoraLinear`] добавляет `scale · B(A·x)` к выходу базового слоя.
+//!   Веса модели не меняются, поэтому адаптеры можно заменить на лету
+//!   (локальные реализации Llama GGUF и Qwen3);
+//! - слияние при загрузке: [`merged_var_builder`] отдаёт `W + scale · B·A` вместо `W`
+//!   для моделей candle_transformers, слои которых недоступны снаружи.
+//!
+//! Имена модулей — как в HuggingFace (`model.layers.0.self_attn.q_proj`).
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use candle::{DType, Device, Module, Result, Shape, Tensor};
+use candle_nn::VarBuilder;
+use candle_nn::var_builder::SimpleBackend;
+use serde::{Deserialize, Serialize};
+
+const ADAPTER_CONFIG: &str = "adapter_config.json";
+const ADAPTER_WEIGHTS: &str = "adapter_model.safetensors";
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+/// Адаптер, запрошенный пользователем: каталог PEFT и множитель силы
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoraAdapterSpec {
+    /// Каталог с `adapter_config.json` и `adapter_model.safetensors`
+    pub path: String,
+    /// Множитель поверх `lora_alpha / r` (0 — адаптер выключен)
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+}
+
+impl LoraAdapterSpec {
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if !self.scale.is_finite() {
+            return Err(format!("LoRA scale must be finite, got {}", self.scale));
+        }
+        if !Path::new(&self.path).join(ADAPTER_CONFIG).is_file() {
+            return Err(format!(
+                "LoRA adapter {}: {} not found",
+                self.path, ADAPTER_CONFIG
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Низкоранговое обновление одного линейного слоя: `ΔW = scale · B·A`
+#[derive(Debug, Clone)]
+pub struct LoraWeights {
+    /// [r, in]
+    pub a: Tensor,
+    /// [out, r]
+    pub b: Tensor,
+    pub scale: f64,
+}
+
+impl LoraWeights {
+    /// `scale · B·A` [out, in] в F32
+    fn delta(&self) -> Result<Tensor> {
+        self.b
+            .to_dtype(DType::F32)?
+            .matmul(&self.a.to_dtype(DType::F32)?)?
+            .affine(self.scale, 0.)
+    }
+}
+
+/// Загруженный адаптер: обновления по именам модулей
+#[derive(Debug, Clone)]
+pub struct LoraAdapter {
+    /// Имя для логов (последний компонент пути)
+    pub name: String,
+    pub modules: HashMap<String, LoraWeights>,
+}
+
+/// `adapter_config.json` (поля, влияющие на применение)
+#[derive(Debug, Deserialize)]
+struct PeftConfig {
+    #[serde(default)]
+    peft_type: Option<String>,
+    lora_alpha: f64,
+    #[serde(default)]
+    use_rslora: bool,
+    #[serde(default)]
+    use_dora: bool,
+    #[serde(default)]
+    fan_in_fan_out: bool,
+    /// Переопределения alpha для отдельных модулей (суффикс имени)
+    #[serde(default)]
+    alpha_pattern: HashMap<String, f64>,
+}
+
+/// Разбирает ключ PEFT: `base_model.model.{module}.lora_A[.adapter].weight`
+///
+/// Возвращает имя модуля и `true` для матрицы A.
+fn parse_key(key: &str) -> Option<(&str, bool)> {
+    let key = key.strip_prefix("base_model.model.").unwrap_or(key);
+    let (module, is_a, rest) = if let Some((module, rest)) = key.split_once(".lora_A.") {
+        (module, true, rest)
+    } else {
+        let (module, rest) = key.split_once(".lora_B.")?;
+        (module, false, rest)
+    };
+    rest.ends_with("weight").then_some((module, is_a))
+}
+
+/// Совпадает ли модуль с ключом `alpha_pattern` (полное имя или суффикс по компонентам)
+fn pattern_matches(module: &str, pattern: &str) -> bool {
+    module == pattern || module.ends_with(&format!(".{pattern}"))
+}
+
+impl LoraAdapter {
+    /// Читает адаптер PEFT; тензоры загружаются на `device`
+    pub fn load(spec: &LoraAdapterSpec, device: &Device) -> std::result::Result<Self, String> {
+        let dir = PathBuf::from(&spec.path);
+        let name = dir
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| spec.path.clone());
+
+        let config_data = std::fs::read(dir.join(ADAPTER_CONFIG))
+            .map_err(|e| format!("LoRA adapter {name}: failed to read {ADAPTER_CONFIG}: {e}"))?;
+        let config: PeftConfig = serde_json::from_slice(&config_data)
+            .map_err(|e| format!("LoRA adapter {name}: failed to parse {ADAPTER_CONFIG}: {e}"))?;
+        if let Some(kind) = &config.peft_type
+            && kind != "LORA"
+        {
+            return Err(format!(
+                "LoRA adapter {name}: unsupported PEFT type '{kind}'"
+            ));
+        }
+        if config.use_dora {
+            return Err(format!(
+                "LoRA adapter {name}: DoRA adapters are not supported"
+            ));
+        }
+        if config.fan_in_fan_out {
+            return Err(format!(
+                "LoRA adapter {name}: fan_in_fan_out (Conv1D) adapters are not supported"
+            ));
+        }
+
+        let weights_path = dir.join(ADAPTER_WEIGHTS);
+        if !weights_path.is_file() {
+            return Err(format!(
+                "LoRA adapter {name}: {ADAPTER_WEIGHTS} not found (convert adapter_model.bin to safetensors)"
+            ));
+        }
+        let tensors = candle::safetensors::load(&weights_path, device)
+            .map_err(|e| format!("LoRA adapter {name}: failed to read weights: {e}"))?;
+
+        let mut pairs: HashMap<String, (Option<Tensor>, Option<Tensor>)> = HashMap::new();
+        for (key, tensor) in tensors {
+            let Some((module, is_a)) = parse_key(&key) else {
+                return Err(format!(
+                    "LoRA adapter {name}: unsupported tensor '{key}' (only linear LoRA layers are supported)"
+                ));
+            };
+            let entry = pairs.entry(module.to_string()).or_default();
+            if is_a {
+                entry.0 = Some(tensor);
+            } else {
+                entry.1 = Some(tensor);
+            }
+        }
+
+        let mut modules = HashMap::with_capacity(pairs.len());
+        for (module, pair) in pairs {
+            let (Some(a), Some(b)) = pair else {
+                return Err(format!(
+                    "LoRA adapter {name}: module '{module}' lacks lora_A or lora_B"
+                ));
+            };
+            let (rank, _) = a.dims2().map_err(|e| format!("{module}.lora_A: {e}"))?;
+            let (_, rank_b) = b.dims2().map_err(|e| format!("{module}.lora_B: {e}"))?;
+            if rank == 0 || rank != rank_b {
+                return Err(format!(
+                    "LoRA adapter {name}: module '{module}' has mismatched ranks {rank} / {rank_b}"
+                ));
+            }
+            let alpha = config
+                .alpha_pattern
+                .iter()
+                .find(|(pattern, _)| pattern_matches(&module, pattern))
+                .map_or(config.lora_alpha, |(_, &alpha)| alpha);
+            let denominator = if config.use_rslora {
+                (rank as f64).sqrt()
+            } else {
+                rank as f64
+            };
+            let scale = alpha / denominator * spec.scale;
+            modules.insert(module, LoraWeights { a, b, scale });
+        }
+        if modules.is_empty() {
+            return Err(format!("LoRA adapter {name}: no LoRA weights found"));
+        }
+        Ok(Self { name, modules })
+    }
+}
+
+/// Загружает все адаптеры запроса
+pub fn load_adapters(
+    specs: &[LoraAdapterSpec],
+    device: &Device,
+) -> std::result::Result<Vec<LoraAdapter>, String> {
+    specs
+        .iter()
+        .map(|spec| LoraAdapter::load(spec, device))
+        .collect()
+}
+
+/// Подготовленное обновление: `y += (x·Aᵀ)·(scale·B)ᵀ`
+#[derive(Debug, Clone)]
+struct LoraDelta {
+    /// [in, r]
+    a_t: Tensor,
+    /// [r, out], масштаб уже учтён
+    b_t: Tensor,
+}
+
+/// Side-car обновления одного линейного слоя
+#[derive(Debug, Clone)]
+pub struct LoraDeltas {
+    in_dim: usize,
+    out_dim: usize,
+    /// Строки B переставляются как веса Q/K при конвертации Llama в GGUF
+    /// (half-rotation RoPE → interleaved); значение — число голов
+    permute_heads: Option<usize>,
+    deltas: Vec<LoraDelta>,
+}
+
+impl LoraDeltas {
+    pub fn new(in_dim: usize, out_dim: usize) -> Self {
+        Self {
+            in_dim,
+            out_dim,
+            permute_heads: None,
+            deltas: Vec::new(),
+        }
+    }
+
+    /// Слой Q/K из GGUF Llama: строки весов переставлены конвертером
+    pub fn with_permuted_heads(mut self, n_heads: usize) -> Self {
+        self.permute_heads = Some(n_heads);
+        self
+    }
+
+    /// Добавляет обновления к выходу базового слоя `ys = base(xs)`
+    pub fn apply(&self, xs: &Tensor, ys: Tensor) -> Result<Tensor> {
+        self.deltas.iter().try_fold(ys, |ys, delta| {
+            let update = xs
+                .broadcast_matmul(&delta.a_t)?
+                .broadcast_matmul(&delta.b_t)?;
+            ys + update
+        })
+    }
+
+    /// Цель для [`assign_lora`]
+    pub fn target(&mut self, module: String) -> LoraTarget<'_> {
+        LoraTarget {
+            module,
+            deltas: self,
+        }
+    }
+
+    fn prepare(&self, module: &str, weights: &LoraWeights, dtype: DType) -> Result<LoraDelta> {
+        let (rank, in_dim) = weights.a.dims2()?;
+        let (out_dim, _) = weights.b.dims2()?;
+        if in_dim != self.in_dim || out_dim != self.out_dim {
+            candle::bail!(
+                "LoRA update for '{module}' is [{out_dim}, {in_dim}], layer is [{}, {}]",
+                self.out_dim,
+                self.in_dim
+            );
+        }
+        let mut b = weights.b.to_dtype(DType::F32)?;
+        if let Some(heads) = self.permute_heads {
+            let head_dim = out_dim / heads;
+            if head_dim * heads != out_dim || head_dim % 2 != 0 {
+                candle::bail!(
+                    "LoRA update for '{module}': {out_dim} rows do not split into {heads} heads"
+                );
+            }
+            b = b
+                .reshape((heads, 2, head_dim / 2, rank))?
+                .transpose(1, 2)?
+                .reshape((out_dim, rank))?;
+        }
+        Ok(LoraDelta {
+            a_t: weights.a.to_dtype(dtype)?.t()?.contiguous()?,
+            b_t: b
+                .affine(weights.scale, 0.)?
+                .t()?
+                .contiguous()?
+                .to_dtype(dtype)?,
+        })
+    }
+}
+
+/// Линейный слой модели, доступный для side-car LoRA
+pub struct LoraTarget<'a> {
+    module: String,
+    deltas: &'a mut LoraDeltas,
+}
+
+/// Заменяет активные адаптеры во всех слоях модели
+///
+/// Пустой `adapters` отключает LoRA. Если какой-то модуль адаптера не найден
+/// среди `targets` или не совпадает по форме, ничего не меняется.
+pub fn assign_lora(
+    targets: Vec<LoraTarget<'_>>,
+    adapters: &[LoraAdapter],
+    dtype: DType,
+) -> Result<()> {
+    let known: HashSet<&str> = targets.iter().map(|t| t.module.as_str()).collect();
+    for adapter in adapters {
+        if let Some(module) = adapter.modules.keys().find(|m| !known.contains(m.as_str())) {
+            candle::bail!(
+                "LoRA adapter {}: module '{}' is not supported by this model",
+                adapter.name,
+                module
+            );
+        }
+    }
+
+    let prepared = targets
+        .iter()
+        .map(|target| {
+            adapters
+                .iter()
+                .filter_map(|adapter| adapter.modules.get(&target.module))
+                .map(|weights| target.deltas.prepare(&target.module, weights, dtype))
+                .collect::<Result<Vec<_>>>()
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for (target, deltas) in targets.into_iter().zip(prepared) {
+        target.deltas.deltas = deltas;
+    }
+    Ok(())
+}
+
+/// Линейный слой с side-car LoRA
+#[derive(Debug, Clone)]
+pub struct LoraLinear<M> {
+    base: M,
+    lora: LoraDeltas,
+}
+
+impl<M> LoraLinear<M> {
+    /// Базовый слой `in_dim → out_dim` без адаптеров
+    pub fn new(base: M, in_dim: usize, out_dim: usize) -> Self {
+        Self {
+            base,
+            lora: LoraDeltas::new(in_dim, out_dim),
+        }
+    }
+
+    pub fn target(&mut self, module: String) -> LoraTarget<'_> {
+        self.lora.target(module)
+    }
+}
+
+impl<M: Module> Module for LoraLinear<M> {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let ys = self.base.forward(xs)?;
+        self.lora.apply(xs, ys)
+    }
+}
+
+/// Источник весов, добавляющий к `{module}.weight` обновления адаптеров
+struct MergedLora {
+    base: VarBuilder<'static>,
+    updates: HashMap<String, Vec<LoraWeights>>,
+    applied: Arc<Mutex<HashSet<String>>>,
+}
+
+impl SimpleBackend for MergedLora {
+    fn get(
+        &self,
+        s: Shape,
+        name: &str,
+        h: candle_nn::Init,
+        dtype: DType,
+        dev: &Device,
+    ) -> Result<Tensor> {
+        let weight = self.base.get_with_hints_dtype(s, name, h, dtype)?;
+        let Some(updates) = name
+            .strip_suffix(".weight")
+            .and_then(|module| self.updates.get(module))
+        else {
+            return Ok(weight);
+        };
+        let mut merged = weight.to_dtype(DType::F32)?;
+        for update in updates {
+            merged = (merged + update.delta()?.to_device(dev)?)?;
+        }
+        if let Ok(mut applied) = self.applied.lock() {
+            applied.insert(name.trim_end_matches(".weight").to_string());
+        }
+        merged.to_dtype(dtype)
+    }
+
+    fn get_unchecked(&self, name: &str, dtype: DType, _dev: &Device) -> Result<Tensor> {
+        self.base.get_unchecked_dtype(name, dtype)
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        self.base.contains_tensor(name)
+    }
+}
+
+/// Проверка после сборки модели из [`merged_var_builder`]
+pub struct LoraMerge {
+    modules: Vec<String>,
+    applied: Arc<Mutex<HashSet<String>>>,
+}
+
+impl LoraMerge {
+    /// Ошибка, если модель не загрузила какой-либо слой, который меняют адаптеры
+    pub fn finish(self) -> std::result::Result<(), String> {
+        let applied = self.applied.lock().map_err(|e| e.to_string())?;
+        match self.modules.iter().find(|m| !applied.contains(*m)) {
+            Some(module) => Err(format!(
+                "LoRA module '{module}' is not a linear layer of this model"
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// VarBuilder поверх SafeTensors, в котором веса уже объединены с адаптерами
+///
+/// Без адаптеров возвращает обычный VarBuilder.
+pub fn merged_var_builder(
+    filenames: &[PathBuf],
+    dtype: DType,
+    device: &Device,
+    adapters: &[LoraAdapter],
+) -> Result<(VarBuilder<'static>, LoraMerge)> {
+    let base = unsafe { VarBuilder::from_mmaped_safetensors(filenames, dtype, device)? };
+    let applied = Arc::new(Mutex::new(HashSet::new()));
+    if adapters.is_empty() {
+        let merge = LoraMerge {
+            modules: Vec::new(),
+            applied,
+        };
+        return Ok((base, merge));
+    }
+    let mut updates: HashMap<String, Vec<LoraWeights>> = HashMap::new();
+    for adapter in adapters {
+        for (module, weights) in &adapter.modules {
+            updates
+                .entry(module.clone())
+                .or_default()
+                .push(weights.clone());
+        }
+    }
+    let merge = LoraMerge {
+        modules: updates.keys().cloned().collect(),
+        applied: applied.clone(),
+    };
+    let backend = MergedLora {
+        base,
+        updates,
+        applied,
+    };
+    Ok((
+        VarBuilder::from_backend(Box::new(backend), dtype, device.clone()),
+        merge,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn adapter(module: &str, a: Tensor, b: Tensor, scale: f64) -> LoraAdapter {
+        LoraAdapter {
+            name: "test".into(),
+            modules: HashMap::from([(module.to_string(), LoraWeights { a, b, scale })]),
+        }
+    }
+
+    #[test]
+    fn parses_peft_keys() {
+        assert_eq!(
+            parse_key("base_model.model.model.layers.0.self_attn.q_proj.lora_A.weight"),
+            Some(("model.layers.0.self_attn.q_proj", true))
+        );
+        assert_eq!(
+            parse_key("base_model.model.model.layers.3.mlp.down_proj.lora_B.default.weight"),
+            Some(("model.layers.3.mlp.down_proj", false))
+        );
+        assert_eq!(
+            parse_key("base_model.model.model.embed_tokens.lora_embedding_A"),
+            None
+        );
+        assert!(pattern_matches(
+            "model.layers.1.self_attn.q_proj",
+            "self_attn.q_proj"
+        ));
+        assert!(!pattern_matches("model.layers.1.self_attn.q_proj", "_proj"));
+    }
+
+    #[test]
+    fn side_car_matches_merged_weight() -> Result<()> {
+        let dev = Device::Cpu;
+        let w = Tensor::arange(0f32, 12., &dev)?.reshape((3, 4))?;
+        let a = Tensor::new(&[[0.5f32, -1., 0., 2.], [1., 0., 1., 0.]], &dev)?;
+        let b = Tensor::new(&[[1f32, 0.], [0., 2.], [-1., 1.]], &dev)?;
+        let adapters = [adapter("proj", a, b, 0.5)];
+        let xs = Tensor::new(&[[[1f32, 2., 3., 4.], [0., -1., 0.5, 2.]]], &dev)?;
+
+        let mut layer = LoraLinear::new(candle_nn::Linear::new(w.clone(), None), 4, 3);
+        assign_lora(vec![layer.target("proj".into())], &adapters, DType::F32)?;
+        let side_car = layer.forward(&xs)?;
+
+        let merged_w = (w + adapters[0].modules["proj"].delta()?)?;
+        let merged = candle_nn::Linear::new(merged_w, None).forward(&xs)?;
+        let diff = (side_car - merged)?.abs()?.max_all()?.to_scalar::<f32>()?;
+        assert!(diff < 1e-5, "diff {diff}");
+
+        // Пустой набор отключает адаптер
+        assign_lora(vec![layer.target("proj".into())], &[], DType::F32)?;
+        let base = layer.forward(&xs)?;
+        let plain = candle_nn::Linear::new(Tensor::arange(0f32, 12., &dev)?.reshape((3, 4))?, None)
+            .forward(&xs)?;
+        assert_eq!(base.to_vec3::<f32>()?, plain.to_vec3::<f32>()?);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_unknown_modules_and_shapes() -> Result<()> {
+        let dev = Device::Cpu;
+        let a = Tensor::zeros((2, 4), DType::F32, &dev)?;
+        let b = Tensor::zeros((3, 2), DType::F32, &dev)?;
+        let mut layer = LoraLinear::new(candle_nn::Linear::new(a.clone(), None), 4, 5);
+
+        let unknown = [adapter("other", a.clone(), b.clone(), 1.)];
+        assert!(assign_lora(vec![layer.target("proj".into())], &unknown, DType::F32).is_err());
+        let wrong_shape = [adapter("proj", a, b, 1.)];
+        assert!(assign_lora(vec![layer.target("proj".into())], &wrong_shape, DType::F32).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn permuted_heads_follow_gguf_row_order() -> Result<()> {
+        let dev = Device::Cpu;
+        // 2 головы по 4 строки: порядок HF [0 1 2 3] -> GGUF [0 2 1 3] внутри головы
+        let a = Tensor::ones((1, 1), DType::F32, &dev)?;
+        let b = Tensor::arange(0f32, 8., &dev)?.reshape((8, 1))?;
+        let deltas = LoraDeltas::new(1, 8).with_permuted_heads(2);
+        let prepared = deltas.prepare("q", &LoraWeights { a, b, scale: 1. }, DType::F32)?;
+        assert_eq!(
+            prepared.b_t.flatten_all()?.to_vec1::<f32>()?,
+            vec![0., 2., 1., 3., 4., 6., 5., 7.]
+        );
+        Ok(())
+    }
+}
@@ -1,5 +1,33 @@
 //! Common utilities for model backends
 
+pub mod batch_attention;
+pub mod context_shift;
+pub mod embeddings;
 pub mod flash_helpers;
+pub mod gguf_mmap;
+pub mod gguf_split;
+pub mod gguf_writer;
+pub mod kv_cache;
+pub mod kv_layers;
+pub mod kv_state;
+pub mod lora;
+pub mod quantize;
+pub mod rope_scaling;
 
-pub use flash_helpers::{is_flash_attention_available, scaled_dot_product_attention};
+pub use batch_attention::batched_decode_attention;
+pub use context_shift::{RopeShift, StreamingWindow, shift_concat_cache, shift_kv};
+pub use embeddings::{
+    EmbeddingOptions, Pooling, embed_token_batches, embeddings_unsupported, shape_embeddings,
+};
+pub use flash_helpers::{causal_mask, is_flash_attention_available, scaled_dot_product_attention};
+pub use kv_cache::{KvCache, KvCacheType};
+pub use kv_layers::KvLayers;
+pub use kv_state::{
+    LayerKv, RecurrentState, check_layer_count, export_concat_cache, import_concat_cache,
+};
+pub use lora::{
+    LoraAdapter, LoraAdapterSpec, LoraDeltas, LoraLinear, LoraTarget, assign_lora, load_adapters,
+};
+pub use rope_scaling::{
+    LongRope, PartialRotaryEmbedding, RopeScaling, RopeScalingKind, RotaryEmbedding,
+};
@@ -0,0 +1,685 @@
+//! Квантование SafeTensors весов при загрузке
+//!
+//! Тензоры читаются из mmap по одному, матрицы весов квантуются в блоки GGML
+//! и складываются в GGUF в памяти. Такой GGUF загружается теми же
+//! квантизированными моделями, что и файлы с диска, либо потоково записываются
+//! в файл GGUF v3 для конвертации моделей (см. [`super::gguf_writer`]).
+
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use candle::quantized::gguf_file::{self, TensorInfo, Value, VersionedMagic};
+use candle::quantized::{GgmlDType, QTensor};
+use candle::safetensors::MmapedSafetensors;
+use candle::{DType, Device, Tensor};
+
+use super::gguf_mmap::GgufRead;
+use super::gguf_writer::{GgufWriter, TensorHeader};
+use super::rope_scaling::{RopeScaling, resolve_rope_scaling};
+
+/// Раскладка GGUF, в которую переводятся SafeTensors веса
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GgufLayout {
+    /// Llama, Mistral и совместимые: строки Q/K переставлены под interleaved RoPE
+    Llama,
+    Qwen2,
+    Qwen3,
+}
+
+impl GgufLayout {
+    /// Значение `general.architecture`
+    pub fn arch(self) -> &'static str {
+        match self {
+            Self::Llama => "llama",
+            Self::Qwen2 => "qwen2",
+            Self::Qwen3 => "qwen3",
+        }
+    }
+
+    /// Имя тензора в GGUF для имени HuggingFace (`None` — тензор не нужен модели)
+    pub fn tensor_name(self, hf: &str) -> Option<String> {
+        match hf {
+            "model.embed_tokens.weight" => return Some("token_embd.weight".into()),
+            "model.norm.weight" => return Some("output_norm.weight".into()),
+            "lm_head.weight" => return Some("output.weight".into()),
+            _ => {}
+        }
+        let rest = hf.strip_prefix("model.layers.")?;
+        let (layer, module) = rest.split_once('.')?;
+        let layer: usize = layer.parse().ok()?;
+        let (module, kind) = module.rsplit_once('.')?;
+        let gguf = match module {
+            "input_layernorm" => "attn_norm",
+            "post_attention_layernorm" => "ffn_norm",
+            "self_attn.q_proj" => "attn_q",
+            "self_attn.k_proj" => "attn_k",
+            "self_attn.v_proj" => "attn_v",
+            "self_attn.o_proj" => "attn_output",
+            "self_attn.q_norm" if self == Self::Qwen3 => "attn_q_norm",
+            "self_attn.k_norm" if self == Self::Qwen3 => "attn_k_norm",
+            "mlp.gate_proj" => "ffn_gate",
+            "mlp.up_proj" => "ffn_up",
+            "mlp.down_proj" => "ffn_down",
+            _ => return None,
+        };
+        Some(format!("blk.{layer}.{gguf}.{kind}"))
+    }
+
+    /// Метаданные GGUF из config.json
+    pub fn metadata(
+        self,
+        config: &serde_json::Value,
+        rope_scaling: Option<RopeScaling>,
+    ) -> Result<HashMap<String, Value>, String> {
+        let get = |key: &str| config.get(key).and_then(|v| v.as_u64()).map(|v| v as u32);
+        let require = |key: &str| get(key).ok_or_else(|| format!("config.json: missing {key}"));
+        if config.get("num_local_experts").is_some() || config.get("num_experts").is_some() {
+            return Err("MoE models cannot be quantized at load time".into());
+        }
+        let hidden = require("hidden_size")?;
+        let heads = require("num_attention_heads")?;
+        let head_dim = get("head_dim").unwrap_or(hidden / heads.max(1));
+        let context = get("max_position_embeddings").unwrap_or(4096);
+        let float = |key: &str, default: f64| {
+            config.get(key).and_then(|v| v.as_f64()).unwrap_or(default) as f32
+        };
+
+        let arch = self.arch();
+        let mut metadata = HashMap::from([(
+            "general.architecture".to_string(),
+            Value::String(arch.to_string()),
+        )]);
+        let mut set = |key: &str, value: Value| {
+            metadata.insert(format!("{arch}.{key}"), value);
+        };
+        set("block_count", Value::U32(require("num_hidden_layers")?));
+        set("embedding_length", Value::U32(hidden));
+        set(
+            "feed_forward_length",
+            Value::U32(require("intermediate_size")?),
+        );
+        set("context_length", Value::U32(context));
+        set("vocab_size", Value::U32(require("vocab_size")?));
+        set("attention.head_count", Value::U32(heads));
+        set(
+            "attention.head_count_kv",
+            Value::U32(get("num_key_value_heads").unwrap_or(heads)),
+        );
+        set("attention.key_length", Value::U32(head_dim));
+        set("attention.value_length", Value::U32(head_dim));
+        set(
+            "attention.layer_norm_rms_epsilon",
+            Value::F32(float("rms_norm_eps", 1e-6)),
+        );
+        set("rope.dimension_count", Value::U32(head_dim));
+        set("rope.freq_base", Value::F32(float("rope_theta", 10000.)));
+
+        if let Some(scaling) = resolve_rope_scaling(config, rope_scaling, context as usize) {
+            scaling.apply_to_gguf(&mut metadata);
+        }
+        Ok(metadata)
+    }
+
+    /// Переставляет строки Q/K так же, как конвертер llama.cpp
+    fn permute(
+        self,
+        gguf_name: &str,
+        tensor: Tensor,
+        metadata: &HashMap<String, Value>,
+    ) -> candle::Result<Tensor> {
+        if self != Self::Llama || !gguf_name.ends_with(".weight") {
+            return Ok(tensor);
+        }
+        let heads_key = if gguf_name.contains(".attn_q.") {
+            "llama.attention.head_count"
+        } else if gguf_name.contains(".attn_k.") {
+            "llama.attention.head_count_kv"
+        } else {
+            return Ok(tensor);
+        };
+        let heads = metadata
+            .get(heads_key)
+            .and_then(|v| v.to_u32().ok())
+            .unwrap_or(1) as usize;
+        let (rows, cols) = tensor.dims2()?;
+        tensor
+            .reshape((heads, 2, rows / heads / 2, cols))?
+            .transpose(1, 2)?
+            .reshape((rows, cols))
+    }
+}
+
+/// Итог квантования
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct QuantizeStats {
+    /// Число записанных тензоров
+    pub tensors: usize,
+    /// Размер тех же весов в dtype обычной загрузки SafeTensors
+    pub source_bytes: u64,
+    /// Размер квантованных весов
+    pub quantized_bytes: u64,
+}
+
+impl QuantizeStats {
+    /// Сэкономленная память в мегабайтах
+    pub fn saved_mb(&self) -> f64 {
+        self.source_bytes.saturating_sub(self.quantized_bytes) as f64 / (1024.0 * 1024.0)
+    }
+}
+
+/// Формат хранения тензора: матрицы квантуются, векторы остаются в F32
+fn storage_dtype(dims: &[usize], target: GgmlDType) -> GgmlDType {
+    let cols = dims.last().copied().unwrap_or(0);
+    if dims.len() < 2 {
+        GgmlDType::F32
+    } else if cols.is_multiple_of(target.block_size()) {
+        target
+    } else if cols.is_multiple_of(GgmlDType::Q8_0.block_size()) {
+        // Например, Q4_K требует строк, кратных 256
+        GgmlDType::Q8_0
+    } else {
+        GgmlDType::F16
+    }
+}
+
+fn open_safetensors(filenames: &[PathBuf]) -> Result<MmapedSafetensors, String> {
+    unsafe { MmapedSafetensors::multi(filenames) }
+        .map_err(|e| format!("Failed to load SafeTensors: {}", e))
+}
+
+/// Пары (имя HuggingFace, имя GGUF) нужных модели тензоров в стабильном порядке
+fn mapped_tensors(st: &MmapedSafetensors, layout: GgufLayout) -> Vec<(String, String)> {
+    let mut names: Vec<(String, String)> = st
+        .tensors()
+        .into_iter()
+        .filter_map(|(hf, _)| {
+            let gguf = layout.tensor_name(&hf);
+            if gguf.is_none() {
+                log::debug!("quantize: skipping tensor {}", hf);
+            }
+            Some((hf, gguf?))
+        })
+        .collect();
+    names.sort();
+    names
+}
+
+/// Квантует веса SafeTensors по одному тензору и передаёт их в `sink`
+///
+/// Тензоры, не нужные модели этой раскладки, пропускаются. `baseline` — dtype,
+/// в котором веса хранились бы без квантования (для подсчёта экономии).
+pub fn quantize_safetensors(
+    filenames: &[PathBuf],
+    layout: GgufLayout,
+    metadata: &HashMap<String, Value>,
+    target: GgmlDType,
+    baseline: DType,
+    mut sink: impl FnMut(String, QTensor) -> Result<(), String>,
+) -> Result<QuantizeStats, String> {
+    let st = open_safetensors(filenames)?;
+    let mut stats = QuantizeStats::default();
+    for (hf, gguf) in mapped_tensors(&st, layout) {
+        let quantize = || -> candle::Result<QTensor> {
+            let tensor = st.load(&hf, &Device::Cpu)?.to_dtype(DType::F32)?;
+            let tensor = layout.permute(&gguf, tensor, metadata)?;
+            QTensor::quantize(&tensor, storage_dtype(tensor.dims(), target))
+        };
+        let qtensor = quantize().map_err(|e| format!("Failed to quantize {}: {}", hf, e))?;
+        stats.tensors += 1;
+        stats.source_bytes += (qtensor.shape().elem_count() * baseline.size_in_bytes()) as u64;
+        stats.quantized_bytes += qtensor.storage_size_in_bytes() as u64;
+        sink(gguf, qtensor)?;
+    }
+    Ok(stats)
+}
+
+/// Данные тензоров GGUF в памяти
+///
+/// Блок тензора освобождается, как только загрузчик дочитал его до конца,
+/// поэтому пиковое потребление не превышает размер квантованной модели.
+#[derive(Debug, Default)]
+pub struct TensorData {
+    /// Смещение блока и его байты (`None` — уже прочитан)
+    chunks: Vec<(u64, Option<Vec<u8>>)>,
+    /// Блоки, которые читаются несколько раз (общие для нескольких тензоров)
+    pinned: Vec<bool>,
+    len: u64,
+    pos: u64,
+}
+
+impl TensorData {
+    /// Добавляет блок и возвращает его смещение
+    fn push(&mut self, bytes: Vec<u8>) -> u64 {
+        let offset = self.len;
+        self.len += bytes.len() as u64;
+        self.chunks.push((offset, Some(bytes)));
+        self.pinned.push(false);
+        offset
+    }
+
+    fn pin(&mut self, offset: u64) {
+        if let Ok(i) = self.chunks.binary_search_by_key(&offset, |(o, _)| *o) {
+            self.pinned[i] = true;
+        }
+    }
+}
+
+impl Read for TensorData {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let i = self.chunks.partition_point(|(o, _)| *o <= self.pos) - 1;
+        let (offset, bytes) = &mut self.chunks[i];
+        let Some(data) = bytes.as_ref() else {
+            return Err(std::io::Error::other("tensor data was already consumed"));
+        };
+        let start = (self.pos - *offset) as usize;
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        self.pos += n as u64;
+        if start + n == data.len() && !self.pinned[i] {
+            *bytes = None;
+        }
+        Ok(n)
+    }
+}
+
+impl Seek for TensorData {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.len.checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        };
+        self.pos =
+            target.ok_or_else(|| std::io::Error::other("seek before the start of tensor data"))?;
+        Ok(self.pos)
+    }
+}
+
+impl GgufRead for TensorData {}
+
+/// GGUF в памяти, собранный из SafeTensors
+pub struct QuantizedGguf {
+    pub content: gguf_file::Content,
+    pub data: TensorData,
+    pub stats: QuantizeStats,
+}
+
+/// Квантует SafeTensors модель в GGUF в памяти
+pub fn quantize_to_gguf(
+    filenames: &[PathBuf],
+    layout: GgufLayout,
+    config: &serde_json::Value,
+    target: GgmlDType,
+    baseline: DType,
+    rope_scaling: Option<RopeScaling>,
+) -> Result<QuantizedGguf, String> {
+    let metadata = layout.metadata(config, rope_scaling)?;
+    let mut tensor_infos = HashMap::new();
+    let mut data = TensorData::default();
+    let stats = quantize_safetensors(
+        filenames,
+        layout,
+        &metadata,
+        target,
+        baseline,
+        |name, qtensor| {
+            let bytes = qtensor.data().map_err(|e| e.to_string())?.into_owned();
+            let info = TensorInfo {
+                ggml_dtype: qtensor.dtype(),
+                shape: qtensor.shape().clone(),
+                offset: data.push(bytes),
+            };
+            tensor_infos.insert(name, info);
+            Ok(())
+        },
+    )?;
+
+    // Tied embeddings: output.weight ссылается на блок token_embd
+    if !tensor_infos.contains_key("output.weight")
+        && let Some(embd) = tensor_infos.get("token_embd.weight")
+    {
+        let alias = TensorInfo {
+            ggml_dtype: embd.ggml_dtype,
+            shape: embd.shape.clone(),
+            offset: embd.offset,
+        };
+        data.pin(alias.offset);
+        tensor_infos.insert("output.weight".to_string(), alias);
+    }
+
+    let content = gguf_file::Content {
+        magic: VersionedMagic::GgufV3,
+        metadata,
+        tensor_infos,
+        tensor_data_offset: 0,
+    };
+    Ok(QuantizedGguf {
+        content,
+        data,
+        stats,
+    })
+}
+
+/// Значение `general.file_type` (llama_ftype) для формата квантования
+pub fn gguf_file_type(target: GgmlDType) -> Option<u32> {
+    let file_type = match target {
+        GgmlDType::F32 => 0,
+        GgmlDType::F16 => 1,
+        GgmlDType::Q4_0 => 2,
+        GgmlDType::Q4_1 => 3,
+        GgmlDType::Q8_0 => 7,
+        GgmlDType::Q5_0 => 8,
+        GgmlDType::Q5_1 => 9,
+        GgmlDType::Q2K => 10,
+        // Равномерные K-кванты ближе всего к вариантам _S
+        GgmlDType::Q3K => 11,
+        GgmlDType::Q4K => 14,
+        GgmlDType::Q5K => 16,
+        GgmlDType::Q6K => 18,
+        GgmlDType::BF16 => 32,
+        GgmlDType::Q8_1 | GgmlDType::Q8K => return None,
+    };
+    Some(file_type)
+}
+
+/// Тензор будущего GGUF вместе с его источником в SafeTensors
+struct PlannedTensor {
+    hf: String,
+    header: TensorHeader,
+    source_bytes: u64,
+}
+
+/// Квантует SafeTensors модель и записывает её в файл GGUF v3
+///
+/// Смещения тензоров считаются заранее по формам из заголовков SafeTensors,
+/// поэтому заголовок GGUF пишется первым, а в памяти одновременно находится
+/// только один тензор. `progress` получает число обработанных и общее число
+/// байт SafeTensors; ошибка из него прерывает запись.
+pub fn write_gguf<W: Write>(
+    writer: W,
+    filenames: &[PathBuf],
+    layout: GgufLayout,
+    metadata: &HashMap<String, Value>,
+    target: GgmlDType,
+    mut progress: impl FnMut(u64, u64) -> Result<(), String>,
+) -> Result<QuantizeStats, String> {
+    let st = open_safetensors(filenames)?;
+    let mut planned = Vec::new();
+    for (hf, gguf) in mapped_tensors(&st, layout) {
+        let view = st
+            .get(&hf)
+            .map_err(|e| format!("Failed to read {}: {}", hf, e))?;
+        let dims = view.shape().to_vec();
+        planned.push(PlannedTensor {
+            source_bytes: view.data().len() as u64,
+            header: TensorHeader {
+                name: gguf,
+                dtype: storage_dtype(&dims, target),
+                dims,
+            },
+            hf,
+        });
+    }
+    let total: u64 = planned.iter().map(|t| t.source_bytes).sum();
+
+    // general.* первыми, остальные ключи по алфавиту
+    let mut entries: Vec<(&str, &Value)> = metadata.iter().map(|(k, v)| (k.as_str(), v)).collect();
+    entries.sort_by_key(|(k, _)| (!k.starts_with("general."), *k));
+    let headers: Vec<TensorHeader> = planned.iter().map(|t| t.header.clone()).collect();
+
+    let io_err = |e: std::io::Error| format!("Failed to write GGUF: {}", e);
+    let mut w = GgufWriter::new(writer);
+    w.write_header(&entries, &headers).map_err(io_err)?;
+
+    let mut stats = QuantizeStats::default();
+    let mut done = 0u64;
+    progress(done, total)?;
+    for tensor in &planned {
+        let header = &tensor.header;
+        let quantize = || -> candle::Result<QTensor> {
+            let t = st.load(&tensor.hf, &Device::Cpu)?.to_dtype(DType::F32)?;
+            let t = layout.permute(&header.name, t, metadata)?;
+            QTensor::quantize(&t, header.dtype)
+        };
+        let qtensor = quantize().map_err(|e| format!("Failed to quantize {}: {}", tensor.hf, e))?;
+        let data = qtensor.data().map_err(|e| e.to_string())?;
+        if data.len() as u64 != header.size_in_bytes() {
+            return Err(format!(
+                "Unexpected size of quantized {}: {} bytes",
+                tensor.hf,
+                data.len()
+            ));
+        }
+        w.write_tensor(&data).map_err(io_err)?;
+
+        stats.tensors += 1;
+        stats.source_bytes += tensor.source_bytes;
+        stats.quantized_bytes += data.len() as u64;
+        done += tensor.source_bytes;
+        progress(done, total)?;
+    }
+    w.finish().map_err(io_err)?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::common::gguf_writer::GGUF_ALIGNMENT;
+
+    #[test]
+    fn maps_hf_names_to_gguf() {
+        let llama = GgufLayout::Llama;
+        assert_eq!(
+            llama.tensor_name("model.layers.3.self_attn.o_proj.weight"),
+            Some("blk.3.attn_output.weight".into())
+        );
+        assert_eq!(
+            llama.tensor_name("model.layers.0.post_attention_layernorm.weight"),
+            Some("blk.0.ffn_norm.weight".into())
+        );
+        assert_eq!(
+            GgufLayout::Qwen2.tensor_name("model.layers.1.self_attn.k_proj.bias"),
+            Some("blk.1.attn_k.bias".into())
+        );
+        assert_eq!(
+            GgufLayout::Qwen3.tensor_name("model.layers.1.self_attn.q_norm.weight"),
+            Some("blk.1.attn_q_norm.weight".into())
+        );
+        assert_eq!(
+            llama.tensor_name("model.layers.0.self_attn.rotary_emb.inv_freq"),
+            None
+        );
+    }
+
+    #[test]
+    fn tensor_data_frees_chunks_after_read() {
+        let mut data = TensorData::default();
+        let a = data.push(vec![1, 2, 3]);
+        let b = data.push(vec![4, 5]);
+        data.pin(b);
+
+        let mut buf = [0u8; 2];
+        data.seek(SeekFrom::Start(b)).unwrap();
+        data.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [4, 5]);
+
+        let mut buf = [0u8; 3];
+        data.seek(SeekFrom::Start(a)).unwrap();
+        data.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3]);
+        data.seek(SeekFrom::Start(a)).unwrap();
+        assert!(data.read_exact(&mut buf).is_err());
+
+        // Закреплённый блок читается повторно
+        let mut buf = [0u8; 2];
+        data.seek(SeekFrom::Start(b)).unwrap();
+        data.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [4, 5]);
+    }
+
+    /// Маленькая Llama в SafeTensors: (config.json, файлы весов)
+    fn tiny_llama(dir: &std::path::Path) -> Result<(serde_json::Value, Vec<PathBuf>), String> {
+        std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        let config = serde_json::json!({
+            "hidden_size": 64,
+            "intermediate_size": 128,
+            "vocab_size": 32,
+            "num_hidden_layers": 1,
+            "num_attention_heads": 2,
+            "num_key_value_heads": 1,
+            "rms_norm_eps": 1e-5,
+            "rope_theta": 10000.0,
+            "max_position_embeddings": 64,
+            "tie_word_embeddings": false
+        });
+        let config_path = dir.join("config.json");
+        std::fs::write(&config_path, config.to_string()).map_err(|e| e.to_string())?;
+
+        let dev = Device::Cpu;
+        let weights = || -> candle::Result<HashMap<String, Tensor>> {
+            let randn = |shape: (usize, usize)| Tensor::randn(0f32, 0.1, shape, &dev);
+            let ones = |n: usize| Tensor::ones(n, DType::F32, &dev);
+            let mut w = HashMap::new();
+            w.insert("model.embed_tokens.weight".into(), randn((32, 64))?);
+            w.insert("model.norm.weight".into(), ones(64)?);
+            w.insert("lm_head.weight".into(), randn((32, 64))?);
+            let p = "model.layers.0";
+            w.insert(format!("{p}.input_layernorm.weight"), ones(64)?);
+            w.insert(format!("{p}.post_attention_layernorm.weight"), ones(64)?);
+            w.insert(format!("{p}.self_attn.q_proj.weight"), randn((64, 64))?);
+            w.insert(format!("{p}.self_attn.k_proj.weight"), randn((32, 64))?);
+            w.insert(format!("{p}.self_attn.v_proj.weight"), randn((32, 64))?);
+            w.insert(format!("{p}.self_attn.o_proj.weight"), randn((64, 64))?);
+            w.insert(format!("{p}.mlp.gate_proj.weight"), randn((128, 64))?);
+            w.insert(format!("{p}.mlp.up_proj.weight"), randn((128, 64))?);
+            w.insert(format!("{p}.mlp.down_proj.weight"), randn((64, 128))?);
+            Ok(w)
+        };
+        let weights_path = dir.join("model.safetensors");
+        weights()
+            .and_then(|w| candle::safetensors::save(&w, &weights_path))
+            .map_err(|e| e.to_string())?;
+        Ok((config, vec![weights_path]))
+    }
+
+    #[test]
+    fn quantized_llama_matches_safetensors() -> Result<(), String> {
+        use crate::models::ModelBackend;
+        use crate::models::llama::LlamaBackend;
+
+        let dir = std::env::temp_dir().join(format!("oxide-quantize-{}", std::process::id()));
+        let (config, files) = tiny_llama(&dir)?;
+        let config_path = dir.join("config.json");
+        let dev = Device::Cpu;
+
+        let gguf = quantize_to_gguf(
+            &files,
+            GgufLayout::Llama,
+            &config,
+            GgmlDType::Q8_0,
+            DType::F32,
+            None,
+        )?;
+        assert_eq!(gguf.stats.tensors, 12);
+        assert!(gguf.stats.saved_mb() > 0.0);
+        let (content, mut data) = (gguf.content, gguf.data);
+        let mut quantized = LlamaBackend::from_gguf(content, &mut data, &dev)?;
+        let mut full =
+            LlamaBackend::from_safetensors(&files, &config_path, &dev, DType::F32, None, &[])?;
+
+        let mut logits = || -> candle::Result<(Vec<f32>, Vec<f32>)> {
+            let input = Tensor::new(&[[1u32, 5, 9, 3]], &dev)?;
+            let q = quantized.forward(&input, 0)?.flatten_all()?.to_vec1()?;
+            let f = full.forward(&input, 0)?.flatten_all()?.to_vec1()?;
+            Ok((q, f))
+        };
+        let (q, f) = logits().map_err(|e| e.to_string())?;
+        let _ = std::fs::remove_dir_all(&dir);
+        let scale = f.iter().fold(0f32, |m, v| m.max(v.abs()));
+        let diff = q
+            .iter()
+            .zip(&f)
+            .fold(0f32, |m, (a, b)| m.max((a - b).abs()));
+        assert!(diff < 0.05 * scale, "diff {diff}, scale {scale}");
+        Ok(())
+    }
+
+    #[test]
+    fn written_gguf_matches_in_memory_quantization() -> Result<(), String> {
+        use crate::models::ModelBackend;
+        use crate::models::llama::LlamaBackend;
+
+        let dir = std::env::temp_dir().join(format!("oxide-write-gguf-{}", std::process::id()));
+        let (config, files) = tiny_llama(&dir)?;
+        let dev = Device::Cpu;
+        let layout = GgufLayout::Llama;
+        let metadata = layout.metadata(&config, None)?;
+
+        let mut bytes = Vec::new();
+        let mut reported = Vec::new();
+        let stats = write_gguf(
+            &mut bytes,
+            &files,
+            layout,
+            &metadata,
+            GgmlDType::Q8_0,
+            |done, total| {
+                reported.push((done, total));
+                Ok(())
+            },
+        )?;
+        let memory = quantize_to_gguf(&files, layout, &config, GgmlDType::Q8_0, DType::F32, None)?;
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(stats.tensors, 12);
+        assert_eq!(stats.quantized_bytes, memory.stats.quantized_bytes);
+        assert_eq!(reported.len(), 13);
+        assert!(
+            reported
+                .iter()
+                .all(|&(_, total)| total == stats.source_bytes)
+        );
+        assert_eq!(reported.last().map(|r| r.0), Some(stats.source_bytes));
+
+        let mut cursor = std::io::Cursor::new(bytes);
+        let content = gguf_file::Content::read(&mut cursor).map_err(|e| e.to_string())?;
+        assert!(matches!(content.magic, VersionedMagic::GgufV3));
+        assert_eq!(content.tensor_infos.len(), 12);
+        assert_eq!(content.tensor_data_offset % GGUF_ALIGNMENT, 0);
+        assert_eq!(
+            content.metadata["llama.attention.head_count_kv"]
+                .to_u32()
+                .map_err(|e| e.to_string())?,
+            1
+        );
+
+        let (memory_content, mut memory_data) = (memory.content, memory.data);
+        let mut from_file = LlamaBackend::from_gguf(content, &mut cursor, &dev)?;
+        let mut from_memory = LlamaBackend::from_gguf(memory_content, &mut memory_data, &dev)?;
+        let mut logits = || -> candle::Result<(Vec<f32>, Vec<f32>)> {
+            let input = Tensor::new(&[[1u32, 5, 9, 3]], &dev)?;
+            let a = from_file.forward(&input, 0)?.flatten_all()?.to_vec1()?;
+            let b = from_memory.forward(&input, 0)?.flatten_all()?.to_vec1()?;
+            Ok((a, b))
+        };
+        let (a, b) = logits().map_err(|e| e.to_string())?;
+        assert_eq!(a, b);
+        Ok(())
+    }
+
+    #[test]
+    fn falls_back_to_q8_for_short_rows() -> candle::Result<()> {
+        let dev = Device::Cpu;
+        let short = Tensor::zeros((4, 64), DType::F32, &dev)?;
+        let wide = Tensor::zeros((4, 256), DType::F32, &dev)?;
+        let norm = Tensor::zeros(64, DType::F32, &dev)?;
+        assert_eq!(storage_dtype(short.dims(), GgmlDType::Q4K), GgmlDType::Q8_0);
+        assert_eq!(storage_dtype(wide.dims(), GgmlDType::Q4K), GgmlDType::Q4K);
+        assert_eq!(storage_dtype(norm.dims(), GgmlDType::Q4K), GgmlDType::F32);
+        Ok(())
+    }
+}
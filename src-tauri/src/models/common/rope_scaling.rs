@@ -0,0 +1,581 @@
+//! RoPE scaling (linear / NTK / YaRN) и общие реализации RoPE
+//!
+//! Параметры масштабирования позиций читаются из `rope_scaling` в config.json или
+//! из ключей `{arch}.rope.scaling.*` GGUF; пользователь может переопределить их
+//! в `LoadRequest`, чтобы расширить контекст сверх обученной длины.
+//!
+//! Локальные реализации (Qwen2, Qwen3, Qwen3-MoE, Llama GGUF) строят таблицы cos/sin
+//! по точным формулам. Llama SafeTensors поверх candle_transformers принимает только
+//! базу частот: NTK-aware масштабирование выражается ею точно
+//! ([`RopeScaling::effective_theta`]), остальные методы для неё отклоняются.
+//!
+//! [`RotaryEmbedding`] поворачивает всю голову (Qwen), [`PartialRotaryEmbedding`] —
+//! только её часть и поддерживает LongRoPE (Phi, Phi-3, GLM-4).
+
+use std::collections::HashMap;
+
+use candle::quantized::gguf_file::Value;
+use candle::{DType, Device, Result, Tensor};
+use serde::{Deserialize, Serialize};
+
+const YARN_BETA_FAST: f64 = 32.0;
+const YARN_BETA_SLOW: f64 = 1.0;
+
+/// Метод масштабирования RoPE
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RopeScalingKind {
+    /// Position interpolation: все частоты делятся на `factor`
+    Linear,
+    /// NTK-aware: увеличенная база частот
+    Ntk,
+    /// YaRN: интерполяция низких частот + температура внимания
+    Yarn,
+}
+
+impl RopeScalingKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Linear => "linear",
+            Self::Ntk => "ntk",
+            Self::Yarn => "yarn",
+        }
+    }
+}
+
+/// Параметры масштабирования RoPE
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RopeScaling {
+    #[serde(rename = "type")]
+    pub kind: RopeScalingKind,
+    /// Во сколько раз расширяется контекст
+    pub factor: f64,
+    /// Контекст, на котором модель обучалась (по умолчанию — из конфигурации модели)
+    #[serde(default)]
+    pub original_context_length: Option<usize>,
+    /// YaRN: граница высоких частот (оборотов на контекст)
+    #[serde(default)]
+    pub beta_fast: Option<f64>,
+    /// YaRN: граница низких частот
+    #[serde(default)]
+    pub beta_slow: Option<f64>,
+    /// YaRN: множитель cos/sin (по умолчанию 0.1·ln(factor) + 1)
+    #[serde(default)]
+    pub attention_factor: Option<f64>,
+}
+
+impl RopeScaling {
+    pub fn new(kind: RopeScalingKind, factor: f64) -> Self {
+        Self {
+            kind,
+            factor,
+            original_context_length: None,
+            beta_fast: None,
+            beta_slow: None,
+            attention_factor: None,
+        }
+    }
+
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if !self.factor.is_finite() || self.factor < 1.0 {
+            return Err(format!(
+                "RoPE scaling factor must be >= 1.0, got {}",
+                self.factor
+            ));
+        }
+        if self.original_context_length == Some(0) {
+            return Err("RoPE scaling original_context_length must be positive".into());
+        }
+        Ok(())
+    }
+
+    /// `rope_scaling` из config.json (формат transformers)
+    ///
+    /// `llama3` обрабатывается candle Llama, `dynamic` (динамический NTK) не
+    /// применяется: он меняет базу только за пределами обученного контекста,
+    /// а таблицы RoPE здесь статические.
+    pub fn from_config_json(config: &serde_json::Value) -> Option<Self> {
+        let rs = config.get("rope_scaling")?.as_object()?;
+        let kind = rs
+            .get("rope_type")
+            .or_else(|| rs.get("type"))
+            .and_then(|v| v.as_str())?;
+        let kind = match kind {
+            "linear" => RopeScalingKind::Linear,
+            "ntk" => RopeScalingKind::Ntk,
+            "yarn" => RopeScalingKind::Yarn,
+            "default" => return None,
+            other => {
+                log::info!("rope_scaling type '{}' is not applied", other);
+                return None;
+            }
+        };
+        let get_f64 = |key: &str| rs.get(key).and_then(|v| v.as_f64());
+        let scaling = Self {
+            kind,
+            factor: get_f64("factor")?,
+            original_context_length: rs
+                .get("original_max_position_embeddings")
+                .and_then(|v| v.as_u64())
+                .map(|v| v as usize),
+            beta_fast: get_f64("beta_fast"),
+            beta_slow: get_f64("beta_slow"),
+            attention_factor: get_f64("attention_factor"),
+        };
+        scaling.usable()
+    }
+
+    /// Ключи `{arch}.rope.scaling.*` (и устаревший `{arch}.rope.scale_linear`) из GGUF
+    pub fn from_gguf(metadata: &HashMap<String, Value>, arch: &str) -> Option<Self> {
+        let get = |key: &str| metadata.get(&format!("{arch}.{key}"));
+        let get_f64 = |key: &str| get(key).and_then(|v| v.to_f32().ok()).map(f64::from);
+        let factor = get_f64("rope.scaling.factor").or_else(|| get_f64("rope.scale_linear"))?;
+        let kind = match get("rope.scaling.type").and_then(|v| v.to_string().ok()) {
+            None => RopeScalingKind::Linear,
+            Some(kind) => match kind.as_str() {
+                "linear" => RopeScalingKind::Linear,
+                "ntk" => RopeScalingKind::Ntk,
+                "yarn" => RopeScalingKind::Yarn,
+                "none" => return None,
+                other => {
+                    log::info!("GGUF rope scaling type '{}' is not applied", other);
+                    return None;
+                }
+            },
+        };
+        let scaling = Self {
+            kind,
+            factor,
+            original_context_length: get("rope.scaling.original_context_length")
+                .and_then(|v| v.to_u32().ok())
+                .map(|v| v as usize),
+            beta_fast: get_f64("rope.scaling.yarn_beta_fast"),
+            beta_slow: get_f64("rope.scaling.yarn_beta_slow"),
+            attention_factor: get_f64("rope.scaling.attn_factor"),
+        };
+        scaling.usable()
+    }
+
+    /// Записывает параметры в метаданные GGUF поверх собственных ключей модели
+    pub fn apply_to_gguf(&self, metadata: &mut HashMap<String, Value>) {
+        let Some(arch) = metadata
+            .get("general.architecture")
+            .and_then(|v| v.to_string().ok())
+            .cloned()
+        else {
+            return;
+        };
+        let original = self.original_context_length.or_else(|| {
+            metadata
+                .get(&format!("{arch}.context_length"))
+                .and_then(|v| v.to_u32().ok())
+                .map(|v| v as usize)
+        });
+        let prefix = format!("{arch}.rope.scal");
+        metadata.retain(|key, _| !key.starts_with(&prefix));
+        let mut set = |key: &str, value: Value| {
+            metadata.insert(format!("{arch}.rope.scaling.{key}"), value);
+        };
+        set("type", Value::String(self.kind.as_str().to_string()));
+        set("factor", Value::F32(self.factor as f32));
+        if let Some(original) = original {
+            set("original_context_length", Value::U32(original as u32));
+        }
+        if let Some(beta_fast) = self.beta_fast {
+            set("yarn_beta_fast", Value::F32(beta_fast as f32));
+        }
+        if let Some(beta_slow) = self.beta_slow {
+            set("yarn_beta_slow", Value::F32(beta_slow as f32));
+        }
+        if let Some(attention_factor) = self.attention_factor {
+            set("attn_factor", Value::F32(attention_factor as f32));
+        }
+    }
+
+    /// Подставляет обученный контекст модели, если он не задан явно
+    pub fn resolve(mut self, trained_context: usize) -> Self {
+        self.original_context_length.get_or_insert(trained_context);
+        self
+    }
+
+    /// Длина контекста после масштабирования
+    pub fn context_length(&self, trained_context: usize) -> usize {
+        let original = self.original_context_length.unwrap_or(trained_context);
+        ((original as f64 * self.factor) as usize).max(trained_context)
+    }
+
+    /// Множитель cos/sin (температура внимания YaRN)
+    pub fn attention_factor(&self) -> f64 {
+        match self.kind {
+            RopeScalingKind::Yarn => self
+                .attention_factor
+                .unwrap_or_else(|| 0.1 * self.factor.ln() + 1.0),
+            _ => 1.0,
+        }
+    }
+
+    /// NTK-aware база частот (точная для [`RopeScalingKind::Ntk`])
+    pub fn effective_theta(&self, head_dim: usize, theta: f64) -> f64 {
+        if head_dim <= 2 {
+            return theta;
+        }
+        theta * self.factor.powf(head_dim as f64 / (head_dim as f64 - 2.0))
+    }
+
+    /// Обратные частоты для пар измерений головы
+    pub fn inv_freqs(&self, head_dim: usize, theta: f64) -> Vec<f64> {
+        let base = default_inv_freqs(head_dim, theta);
+        match self.kind {
+            RopeScalingKind::Linear => base.into_iter().map(|f| f / self.factor).collect(),
+            RopeScalingKind::Ntk => {
+                default_inv_freqs(head_dim, self.effective_theta(head_dim, theta))
+            }
+            RopeScalingKind::Yarn => {
+                let original = self.original_context_length.unwrap_or(4096) as f64;
+                let correction_dim = |rotations: f64| {
+                    head_dim as f64 * (original / (rotations * 2.0 * std::f64::consts::PI)).ln()
+                        / (2.0 * theta.ln())
+                };
+                let low = correction_dim(self.beta_fast.unwrap_or(YARN_BETA_FAST))
+                    .floor()
+                    .max(0.0);
+                let high = correction_dim(self.beta_slow.unwrap_or(YARN_BETA_SLOW))
+                    .ceil()
+                    .min(head_dim as f64 - 1.0);
+                let high = if high <= low { low + 0.001 } else { high };
+                base.into_iter()
+                    .enumerate()
+                    .map(|(i, freq)| {
+                        // 0 — экстраполяция (высокие частоты), 1 — интерполяция
+                        let ramp = ((i as f64 - low) / (high - low)).clamp(0.0, 1.0);
+                        freq / self.factor * ramp + freq * (1.0 - ramp)
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    fn usable(self) -> Option<Self> {
+        (self.factor.is_finite() && self.factor > 1.0).then_some(self)
+    }
+}
+
+/// Итоговое масштабирование для модели из config.json: override пользователя важнее
+pub fn resolve_rope_scaling(
+    config: &serde_json::Value,
+    override_scaling: Option<RopeScaling>,
+    trained_context: usize,
+) -> Option<RopeScaling> {
+    let scaling = override_scaling
+        .or_else(|| RopeScaling::from_config_json(config))?
+        .resolve(trained_context);
+    log::info!(
+        "RoPE scaling: {:?} x{} (context {} -> {})",
+        scaling.kind,
+        scaling.factor,
+        trained_context,
+        scaling.context_length(trained_context)
+    );
+    Some(scaling)
+}
+
+fn default_inv_freqs(head_dim: usize, theta: f64) -> Vec<f64> {
+    (0..head_dim)
+        .step_by(2)
+        .map(|i| 1.0 / theta.powf(i as f64 / head_dim as f64))
+        .collect()
+}
+
+/// Обратные частоты RoPE с учётом масштабирования
+pub fn rope_inv_freqs(head_dim: usize, theta: f64, scaling: Option<&RopeScaling>) -> Vec<f64> {
+    match scaling {
+        Some(scaling) => scaling.inv_freqs(head_dim, theta),
+        None => default_inv_freqs(head_dim, theta),
+    }
+}
+
+/// Число позиций в таблицах RoPE: обученный контекст, расширенный масштабированием
+pub fn rope_table_len(trained_context: usize, scaling: Option<&RopeScaling>) -> usize {
+    scaling.map_or(trained_context, |s| s.context_length(trained_context))
+}
+
+/// Таблицы cos/sin [max_len, head_dim / 2] в F32
+pub fn rope_tables(
+    head_dim: usize,
+    theta: f64,
+    scaling: Option<&RopeScaling>,
+    max_len: usize,
+    device: &Device,
+) -> Result<(Tensor, Tensor)> {
+    let inv_freq: Vec<f32> = rope_inv_freqs(head_dim, theta, scaling)
+        .into_iter()
+        .map(|f| f as f32)
+        .collect();
+    let inv_freq_len = inv_freq.len();
+    let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), device)?;
+    let t = Tensor::arange(0u32, max_len as u32, device)?
+        .to_dtype(DType::F32)?
+        .reshape((max_len, 1))?;
+    let freqs = t.matmul(&inv_freq)?;
+    let mscale = scaling.map_or(1.0, RopeScaling::attention_factor);
+    Ok(((freqs.cos()? * mscale)?, (freqs.sin()? * mscale)?))
+}
+
+/// RoPE с половинной раскладкой пар (Qwen) и опциональным масштабированием
+#[derive(Debug, Clone)]
+pub struct RotaryEmbedding {
+    sin: Tensor,
+    cos: Tensor,
+}
+
+impl RotaryEmbedding {
+    pub fn new(
+        dtype: DType,
+        head_dim: usize,
+        max_position_embeddings: usize,
+        rope_theta: f64,
+        scaling: Option<&RopeScaling>,
+        dev: &Device,
+    ) -> Result<Self> {
+        let max_len = rope_table_len(max_position_embeddings, scaling);
+        let (cos, sin) = rope_tables(head_dim, rope_theta, scaling, max_len, dev)?;
+        Ok(Self {
+            sin: sin.to_dtype(dtype)?,
+            cos: cos.to_dtype(dtype)?,
+        })
+    }
+
+    /// Apply RoPE (q, k shape: B x H x L x D)
+    pub fn apply(&self, q: &Tensor, k: &Tensor, offset: usize) -> Result<(Tensor, Tensor)> {
+        let (_, _, seq_len, _) = q.dims4()?;
+        let cos = self.cos.narrow(0, offset, seq_len)?.to_dtype(q.dtype())?;
+        let sin = self.sin.narrow(0, offset, seq_len)?.to_dtype(q.dtype())?;
+        let q_embed = candle_nn::rotary_emb::rope(&q.contiguous()?, &cos, &sin)?;
+        let k_embed = candle_nn::rotary_emb::rope(&k.contiguous()?, &cos, &sin)?;
+        Ok((q_embed, k_embed))
+    }
+}
+
+/// Параметры LongRoPE
+#[derive(Debug, Clone, PartialEq)]
+pub struct LongRope {
+    /// Делители частот для последовательностей до `original_max_position_embeddings`
+    pub short_factor: Vec<f64>,
+    /// Делители частот для более длинных последовательностей
+    pub long_factor: Vec<f64>,
+    pub original_max_position_embeddings: usize,
+    /// Множитель cos/sin
+    pub attention_factor: f64,
+}
+
+impl LongRope {
+    /// Множитель cos/sin по умолчанию: `sqrt(1 + ln(scale) / ln(original))`
+    pub fn default_attention_factor(max_position_embeddings: usize, original: usize) -> f64 {
+        let scale = max_position_embeddings as f64 / original as f64;
+        if scale <= 1.0 {
+            1.0
+        } else {
+            (1.0 + scale.ln() / (original as f64).ln()).sqrt()
+        }
+    }
+
+    /// Обратные частоты для `rotary_dim` с делителями `factors`
+    pub fn inv_freqs(factors: &[f64], rotary_dim: usize, theta: f64) -> Vec<f64> {
+        (0..rotary_dim)
+            .step_by(2)
+            .zip(factors)
+            .map(|(i, factor)| 1.0 / (factor * theta.powf(i as f64 / rotary_dim as f64)))
+            .collect()
+    }
+
+    /// Проверяет, что множителей по одному на пару измерений `rotary_dim`
+    pub fn validate(&self, rotary_dim: usize) -> std::result::Result<(), String> {
+        let half = rotary_dim / 2;
+        if self.short_factor.len() != half || self.long_factor.len() != half {
+            return Err(format!(
+                "LongRoPE factors have {}/{} entries, expected {}",
+                self.short_factor.len(),
+                self.long_factor.len(),
+                half
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// RoPE по первым `rotary_dim` измерениям головы с опциональным LongRoPE
+///
+/// Остальные измерения проходят без изменений. Phi использует половинную
+/// раскладку пар, GLM — чередующуюся ([`PartialRotaryEmbedding::interleaved`]).
+/// С LongRoPE строятся две таблицы; как и в HF, длинная используется, когда
+/// последовательность выходит за `original_max_position_embeddings`.
+#[derive(Debug, Clone)]
+pub struct PartialRotaryEmbedding {
+    rotary_dim: usize,
+    /// Чередующаяся раскладка пар (`rope_i`) вместо половинной
+    interleaved: bool,
+    /// (cos, sin) для коротких последовательностей (или единственная таблица)
+    short: (Tensor, Tensor),
+    /// (cos, sin) LongRoPE для длинных последовательностей
+    long: Option<(Tensor, Tensor)>,
+    original_max_position_embeddings: usize,
+}
+
+fn freq_tables(
+    inv_freqs: Vec<f64>,
+    mscale: f64,
+    max_len: usize,
+    dtype: DType,
+    device: &Device,
+) -> Result<(Tensor, Tensor)> {
+    let inv_freq: Vec<f32> = inv_freqs.into_iter().map(|f| f as f32).collect();
+    let inv_freq_len = inv_freq.len();
+    let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), device)?;
+    let t = Tensor::arange(0u32, max_len as u32, device)?
+        .to_dtype(DType::F32)?
+        .reshape((max_len, 1))?;
+    let freqs = t.matmul(&inv_freq)?;
+    Ok((
+        (freqs.cos()? * mscale)?.to_dtype(dtype)?,
+        (freqs.sin()? * mscale)?.to_dtype(dtype)?,
+    ))
+}
+
+impl PartialRotaryEmbedding {
+    pub fn new(
+        dtype: DType,
+        rotary_dim: usize,
+        theta: f64,
+        max_len: usize,
+        scaling: Option<&RopeScaling>,
+        long_rope: Option<&LongRope>,
+        device: &Device,
+    ) -> Result<Self> {
+        match long_rope {
+            Some(lr) => {
+                let original = lr.original_max_position_embeddings.min(max_len);
+                let short = freq_tables(
+                    LongRope::inv_freqs(&lr.short_factor, rotary_dim, theta),
+                    lr.attention_factor,
+                    original,
+                    dtype,
+                    device,
+                )?;
+                let long = freq_tables(
+                    LongRope::inv_freqs(&lr.long_factor, rotary_dim, theta),
+                    lr.attention_factor,
+                    max_len,
+                    dtype,
+                    device,
+                )?;
+                Ok(Self {
+                    rotary_dim,
+                    interleaved: false,
+                    short,
+                    long: Some(long),
+                    original_max_position_embeddings: original,
+                })
+            }
+            None => {
+                let (cos, sin) = rope_tables(rotary_dim, theta, scaling, max_len, device)?;
+                Ok(Self {
+                    rotary_dim,
+                    interleaved: false,
+                    short: (cos.to_dtype(dtype)?, sin.to_dtype(dtype)?),
+                    long: None,
+                    original_max_position_embeddings: max_len,
+                })
+            }
+        }
+    }
+
+    /// Переключает на чередующуюся раскладку пар (GLM)
+    pub fn interleaved(mut self) -> Self {
+        self.interleaved = true;
+        self
+    }
+
+    /// Apply RoPE (q, k shape: B x H x L x D)
+    pub fn apply(&self, q: &Tensor, k: &Tensor, offset: usize) -> Result<(Tensor, Tensor)> {
+        let (_, _, seq_len, head_dim) = q.dims4()?;
+        let (cos, sin) = match &self.long {
+            Some(long) if offset + seq_len > self.original_max_position_embeddings => long,
+            _ => &self.short,
+        };
+        let cos = cos.narrow(0, offset, seq_len)?.to_dtype(q.dtype())?;
+        let sin = sin.narrow(0, offset, seq_len)?.to_dtype(q.dtype())?;
+        let rope = |x: &Tensor| {
+            if self.interleaved {
+                candle_nn::rotary_emb::rope_i(x, &cos, &sin)
+            } else {
+                candle_nn::rotary_emb::rope(x, &cos, &sin)
+            }
+        };
+        let rotate = |x: &Tensor| -> Result<Tensor> {
+            if self.rotary_dim == head_dim {
+                return rope(&x.contiguous()?);
+            }
+            let rot = x.narrow(3, 0, self.rotary_dim)?.contiguous()?;
+            let pass = x.narrow(3, self.rotary_dim, head_dim - self.rotary_dim)?;
+            let rot = rope(&rot)?;
+            Tensor::cat(&[&rot, &pass], 3)?.contiguous()
+        };
+        Ok((rotate(q)?, rotate(k)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_config_and_gguf_scaling() {
+        let config = serde_json::json!({
+            "rope_scaling": {"type": "yarn", "factor": 4.0, "original_max_position_embeddings": 32768}
+        });
+        let scaling = RopeScaling::from_config_json(&config).unwrap();
+        assert_eq!(scaling.kind, RopeScalingKind::Yarn);
+        assert_eq!(scaling.context_length(40960), 131072);
+        assert!(
+            RopeScaling::from_config_json(&serde_json::json!({"rope_scaling": null})).is_none()
+        );
+
+        let mut metadata = HashMap::new();
+        metadata.insert(
+            "general.architecture".to_string(),
+            Value::String("qwen3".into()),
+        );
+        metadata.insert("qwen3.context_length".to_string(), Value::U32(32768));
+        metadata.insert("qwen3.rope.scale_linear".to_string(), Value::F32(2.0));
+        let linear = RopeScaling::from_gguf(&metadata, "qwen3").unwrap();
+        assert_eq!(linear.kind, RopeScalingKind::Linear);
+
+        scaling.apply_to_gguf(&mut metadata);
+        assert_eq!(RopeScaling::from_gguf(&metadata, "qwen3"), Some(scaling));
+    }
+
+    #[test]
+    fn scaled_frequencies() {
+        let theta = 10000.0;
+        let base = rope_inv_freqs(128, theta, None);
+
+        let linear = RopeScaling::new(RopeScalingKind::Linear, 4.0);
+        for (f, b) in linear.inv_freqs(128, theta).iter().zip(&base) {
+            assert!((f - b / 4.0).abs() < 1e-12);
+        }
+
+        // NTK: высокие частоты почти не меняются, самая низкая делится на factor
+        let ntk = RopeScaling::new(RopeScalingKind::Ntk, 4.0).inv_freqs(128, theta);
+        assert_eq!(ntk[0], base[0]);
+        let last = base.len() - 1;
+        assert!((ntk[last] / base[last] - 0.25).abs() < 0.01);
+
+        // YaRN: высокие частоты экстраполируются, низкие интерполируются
+        let yarn = RopeScaling::new(RopeScalingKind::Yarn, 4.0)
+            .resolve(4096)
+            .inv_freqs(128, theta);
+        assert_eq!(yarn[0], base[0]);
+        assert!((yarn[last] - base[last] / 4.0).abs() < 1e-12);
+        assert!(RopeScaling::new(RopeScalingKind::Yarn, 4.0).attention_factor() > 1.0);
+    }
+}
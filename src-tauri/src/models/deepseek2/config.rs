@@ -0,0 +1,429 @@
+//! DeepSeek-V2 / V3 configuration
+//!
+//! Конфигурация читается из `config.json` (`deepseek_v2` / `deepseek_v3`) или из
+//! метаданных GGUF (`deepseek2.*`, llama.cpp использует эту архитектуру и для V3).
+//!
+//! Размерности MLA:
+//! - `kv_lora_rank` — размер сжатого латентного вектора KV (он и хранится в кэше);
+//! - `qk_nope_head_dim` / `qk_rope_head_dim` — части головы Q/K без RoPE и с RoPE;
+//! - `v_head_dim` — размер головы V.
+
+use std::collections::HashMap;
+
+use candle::quantized::gguf_file::Value;
+use serde::Deserialize;
+
+use crate::models::common::rope_scaling::rope_table_len;
+use crate::models::common::{RopeScaling, RopeScalingKind, RopeShift};
+
+/// Функция оценки экспертов роутером
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScoringFunc {
+    /// DeepSeek-V2
+    Softmax,
+    /// DeepSeek-V3 (с поправочным смещением `e_score_correction_bias`)
+    Sigmoid,
+}
+
+/// Параметры выбора экспертов
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Routing {
+    pub num_experts_per_tok: usize,
+    pub scoring_func: ScoringFunc,
+    /// Число групп экспертов (1 — группы не используются)
+    pub n_group: usize,
+    /// Сколько групп остаётся после отбора
+    pub topk_group: usize,
+    pub norm_topk_prob: bool,
+    pub routed_scaling_factor: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    /// Размер MLP плотных слоёв
+    pub intermediate_size: usize,
+    /// Размер одного эксперта
+    pub moe_intermediate_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    /// Число routed экспертов (0 — модель без MoE)
+    pub n_routed_experts: usize,
+    pub n_shared_experts: usize,
+    /// Первые слои с обычным MLP
+    pub first_k_dense_replace: usize,
+    pub moe_layer_freq: usize,
+    pub routing: Routing,
+    /// `None` — Q проецируется напрямую (V2-Lite), иначе через LoRA-сжатие
+    pub q_lora_rank: Option<usize>,
+    pub kv_lora_rank: usize,
+    pub qk_nope_head_dim: usize,
+    pub qk_rope_head_dim: usize,
+    pub v_head_dim: usize,
+    pub rms_norm_eps: f64,
+    pub rope_theta: f64,
+    pub max_position_embeddings: usize,
+    pub rope_scaling: Option<RopeScaling>,
+    /// YaRN `mscale_all_dim`: температура softmax внимания
+    pub mscale_all_dim: f64,
+    pub tie_word_embeddings: bool,
+}
+
+/// `config.json` DeepSeek-V2 / V3
+#[derive(Debug, Deserialize)]
+struct HfConfig {
+    vocab_size: usize,
+    hidden_size: usize,
+    intermediate_size: usize,
+    moe_intermediate_size: Option<usize>,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    n_routed_experts: Option<usize>,
+    n_shared_experts: Option<usize>,
+    num_experts_per_tok: Option<usize>,
+    #[serde(default)]
+    first_k_dense_replace: usize,
+    moe_layer_freq: Option<usize>,
+    #[serde(default)]
+    norm_topk_prob: bool,
+    routed_scaling_factor: Option<f64>,
+    scoring_func: Option<ScoringFunc>,
+    topk_method: Option<String>,
+    n_group: Option<usize>,
+    topk_group: Option<usize>,
+    q_lora_rank: Option<usize>,
+    kv_lora_rank: usize,
+    qk_nope_head_dim: usize,
+    qk_rope_head_dim: usize,
+    v_head_dim: usize,
+    rms_norm_eps: Option<f64>,
+    rope_theta: Option<f64>,
+    max_position_embeddings: usize,
+    #[serde(default)]
+    tie_word_embeddings: bool,
+}
+
+/// Множитель YaRN `yarn_get_mscale` из реализации DeepSeek
+fn yarn_mscale(factor: f64, mscale: f64) -> f64 {
+    if factor <= 1.0 {
+        1.0
+    } else {
+        0.1 * mscale * factor.ln() + 1.0
+    }
+}
+
+impl Config {
+    /// Разбирает `config.json`; `rope_scaling` — override из запроса загрузки
+    pub fn from_hf_json(
+        config: &serde_json::Value,
+        rope_scaling: Option<RopeScaling>,
+    ) -> Result<Self, String> {
+        let hf: HfConfig = serde_json::from_value(config.clone())
+            .map_err(|e| format!("Failed to parse DeepSeek config.json: {}", e))?;
+        let yarn = config.get("rope_scaling").and_then(|v| v.as_object());
+        let yarn_f64 = |key: &str| yarn.and_then(|rs| rs.get(key)).and_then(|v| v.as_f64());
+        let mscale = yarn_f64("mscale").unwrap_or(1.0);
+        let mscale_all_dim = yarn_f64("mscale_all_dim").unwrap_or(0.0);
+
+        let rope_scaling = rope_scaling
+            .or_else(|| RopeScaling::from_config_json(config))
+            .map(|s| {
+                let mut s = s.resolve(hf.max_position_embeddings);
+                // У DeepSeek множитель cos/sin — отношение двух mscale (обычно 1),
+                // а температура mscale_all_dim переносится в масштаб softmax
+                if s.kind == RopeScalingKind::Yarn && s.attention_factor.is_none() {
+                    s.attention_factor =
+                        Some(yarn_mscale(s.factor, mscale) / yarn_mscale(s.factor, mscale_all_dim));
+                }
+                s
+            });
+
+        // V2: greedy / group_limited_greedy, V3: noaux_tc; без групп все методы
+        // сводятся к обычному top-k
+        let grouped = hf.topk_method.as_deref().is_some_and(|m| m != "greedy");
+        let cfg = Self {
+            vocab_size: hf.vocab_size,
+            hidden_size: hf.hidden_size,
+            intermediate_size: hf.intermediate_size,
+            moe_intermediate_size: hf.moe_intermediate_size.unwrap_or(hf.intermediate_size),
+            num_hidden_layers: hf.num_hidden_layers,
+            num_attention_heads: hf.num_attention_heads,
+            n_routed_experts: hf.n_routed_experts.unwrap_or(0),
+            n_shared_experts: hf.n_shared_experts.unwrap_or(0),
+            first_k_dense_replace: hf.first_k_dense_replace,
+            moe_layer_freq: hf.moe_layer_freq.unwrap_or(1).max(1),
+            routing: Routing {
+                num_experts_per_tok: hf.num_experts_per_tok.unwrap_or(0),
+                scoring_func: hf.scoring_func.unwrap_or(ScoringFunc::Softmax),
+                n_group: if grouped { hf.n_group.unwrap_or(1) } else { 1 },
+                topk_group: if grouped {
+                    hf.topk_group.unwrap_or(1)
+                } else {
+                    1
+                },
+                norm_topk_prob: hf.norm_topk_prob,
+                routed_scaling_factor: hf.routed_scaling_factor.unwrap_or(1.0),
+            },
+            q_lora_rank: hf.q_lora_rank,
+            kv_lora_rank: hf.kv_lora_rank,
+            qk_nope_head_dim: hf.qk_nope_head_dim,
+            qk_rope_head_dim: hf.qk_rope_head_dim,
+            v_head_dim: hf.v_head_dim,
+            rms_norm_eps: hf.rms_norm_eps.unwrap_or(1e-6),
+            rope_theta: hf.rope_theta.unwrap_or(10_000.),
+            max_position_embeddings: hf.max_position_embeddings,
+            rope_scaling,
+            mscale_all_dim,
+            tie_word_embeddings: hf.tie_word_embeddings,
+        };
+        cfg.validate()?;
+        Ok(cfg)
+    }
+
+    /// Читает конфигурацию из метаданных GGUF (`deepseek2.*`)
+    pub fn from_gguf(metadata: &HashMap<String, Value>) -> candle::Result<Self> {
+        let arch = metadata
+            .get("general.architecture")
+            .and_then(|v| v.to_string().ok())
+            .cloned()
+            .unwrap_or_else(|| "deepseek2".to_string());
+        let get = |key: &str| metadata.get(&format!("{arch}.{key}"));
+        let get_u32 = |key: &str| -> candle::Result<usize> {
+            get(key)
+                .ok_or_else(|| candle::Error::Msg(format!("cannot find {arch}.{key} in metadata")))?
+                .to_u32()
+                .map(|v| v as usize)
+        };
+        let get_f64 = |key: &str| get(key).and_then(|v| v.to_f32().ok()).map(f64::from);
+
+        let num_attention_heads = get_u32("attention.head_count")?;
+        let qk_rope_head_dim = get_u32("rope.dimension_count")?;
+        let kv_lora_rank = get_u32("attention.kv_lora_rank")?;
+        // Новые конвертеры llama.cpp записывают в key_length/value_length размеры
+        // латентного пространства, а исходные размеры головы — в *_mla
+        let q_head_dim =
+            get_u32("attention.key_length_mla").or_else(|_| get_u32("attention.key_length"))?;
+        let v_head_dim =
+            get_u32("attention.value_length_mla").or_else(|_| get_u32("attention.value_length"))?;
+        if q_head_dim <= qk_rope_head_dim {
+            candle::bail!(
+                "DeepSeek GGUF: key length {q_head_dim} does not exceed rope dim {qk_rope_head_dim}"
+            );
+        }
+        let max_position_embeddings = get_u32("context_length").unwrap_or(4096);
+        let vocab_size = get_u32("vocab_size").ok().unwrap_or_else(|| {
+            metadata
+                .get("tokenizer.ggml.tokens")
+                .and_then(|v| v.to_vec().ok())
+                .map_or(102400, |tokens| tokens.len())
+        });
+        let n_routed_experts = get_u32("expert_count").unwrap_or(0);
+        let n_group = get_u32("expert_group_count").unwrap_or(1).max(1);
+
+        // yarn_log_multiplier = 0.1 * mscale_all_dim
+        let mscale_all_dim = get_f64("rope.scaling.yarn_log_multiplier").unwrap_or(0.0) * 10.0;
+        let rope_scaling = RopeScaling::from_gguf(metadata, &arch).map(|s| {
+            let mut s = s.resolve(max_position_embeddings);
+            if s.kind == RopeScalingKind::Yarn && s.attention_factor.is_none() {
+                s.attention_factor = Some(1.0);
+            }
+            s
+        });
+
+        let cfg = Self {
+            vocab_size,
+            hidden_size: get_u32("embedding_length")?,
+            intermediate_size: get_u32("feed_forward_length")?,
+            moe_intermediate_size: get_u32("expert_feed_forward_length").unwrap_or(0),
+            num_hidden_layers: get_u32("block_count")?,
+            num_attention_heads,
+            n_routed_experts,
+            n_shared_experts: get_u32("expert_shared_count").unwrap_or(0),
+            first_k_dense_replace: get_u32("leading_dense_block_count").unwrap_or(0),
+            moe_layer_freq: 1,
+            routing: Routing {
+                num_experts_per_tok: get_u32("expert_used_count").unwrap_or(0),
+                scoring_func: match get_u32("expert_gating_func") {
+                    Ok(2) => ScoringFunc::Sigmoid,
+                    _ => ScoringFunc::Softmax,
+                },
+                n_group,
+                topk_group: get_u32("expert_group_used_count").unwrap_or(n_group),
+                norm_topk_prob: get("expert_weights_norm")
+                    .and_then(|v| v.to_bool().ok())
+                    .unwrap_or(false),
+                routed_scaling_factor: get_f64("expert_weights_scale").unwrap_or(1.0),
+            },
+            q_lora_rank: get_u32("attention.q_lora_rank").ok().filter(|&r| r > 0),
+            kv_lora_rank,
+            qk_nope_head_dim: q_head_dim - qk_rope_head_dim,
+            qk_rope_head_dim,
+            v_head_dim,
+            rms_norm_eps: get_f64("attention.layer_norm_rms_epsilon").unwrap_or(1e-6),
+            rope_theta: get_f64("rope.freq_base").unwrap_or(10_000.),
+            max_position_embeddings,
+            rope_scaling,
+            mscale_all_dim,
+            tie_word_embeddings: false,
+        };
+        cfg.validate().map_err(candle::Error::Msg)?;
+        Ok(cfg)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.qk_rope_head_dim == 0 || !self.qk_rope_head_dim.is_multiple_of(2) {
+            return Err(format!(
+                "DeepSeek config: invalid qk_rope_head_dim {}",
+                self.qk_rope_head_dim
+            ));
+        }
+        if self.n_routed_experts > 0 {
+            let r = &self.routing;
+            if r.num_experts_per_tok == 0 || r.num_experts_per_tok > self.n_routed_experts {
+                return Err(format!(
+                    "DeepSeek config: num_experts_per_tok {} is invalid for {} experts",
+                    r.num_experts_per_tok, self.n_routed_experts
+                ));
+            }
+            if !self.n_routed_experts.is_multiple_of(r.n_group) || r.topk_group > r.n_group {
+                return Err(format!(
+                    "DeepSeek config: {} experts cannot be split into {} groups (top {})",
+                    self.n_routed_experts, r.n_group, r.topk_group
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Размер головы Q/K
+    pub fn q_head_dim(&self) -> usize {
+        self.qk_nope_head_dim + self.qk_rope_head_dim
+    }
+
+    /// Является ли слой MoE
+    pub fn is_moe_layer(&self, layer_idx: usize) -> bool {
+        self.n_routed_experts > 0
+            && layer_idx >= self.first_k_dense_replace
+            && layer_idx.is_multiple_of(self.moe_layer_freq)
+    }
+
+    /// Масштаб логитов внимания: 1/sqrt(q_head_dim) с поправкой YaRN
+    pub fn softmax_scale(&self) -> f64 {
+        let scale = 1.0 / (self.q_head_dim() as f64).sqrt();
+        match &self.rope_scaling {
+            Some(s) if s.kind == RopeScalingKind::Yarn => {
+                let mscale = yarn_mscale(s.factor, self.mscale_all_dim);
+                scale * mscale * mscale
+            }
+            _ => scale,
+        }
+    }
+
+    /// Длина таблиц RoPE
+    pub fn max_seq_len(&self) -> usize {
+        rope_table_len(self.max_position_embeddings, self.rope_scaling.as_ref())
+    }
+
+    /// Параметры ре-ротации ключей (только RoPE-часть `k_pe`)
+    pub fn rope_shift(&self) -> RopeShift {
+        RopeShift::interleaved(self.qk_rope_head_dim, self.rope_theta)
+            .with_scaling(self.rope_scaling)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2_lite_json() -> serde_json::Value {
+        serde_json::json!({
+            "model_type": "deepseek_v2",
+            "vocab_size": 102400,
+            "hidden_size": 2048,
+            "intermediate_size": 10944,
+            "moe_intermediate_size": 1408,
+            "num_hidden_layers": 27,
+            "num_attention_heads": 16,
+            "n_routed_experts": 64,
+            "n_shared_experts": 2,
+            "num_experts_per_tok": 6,
+            "first_k_dense_replace": 1,
+            "moe_layer_freq": 1,
+            "norm_topk_prob": false,
+            "routed_scaling_factor": 1.0,
+            "scoring_func": "softmax",
+            "topk_method": "greedy",
+            "n_group": 1,
+            "topk_group": 1,
+            "q_lora_rank": null,
+            "kv_lora_rank": 512,
+            "qk_nope_head_dim": 128,
+            "qk_rope_head_dim": 64,
+            "v_head_dim": 128,
+            "rms_norm_eps": 1e-6,
+            "rope_theta": 10000,
+            "max_position_embeddings": 163840,
+            "rope_scaling": {
+                "type": "yarn",
+                "factor": 40,
+                "original_max_position_embeddings": 4096,
+                "beta_fast": 32,
+                "beta_slow": 1,
+                "mscale": 0.707,
+                "mscale_all_dim": 0.707
+            }
+        })
+    }
+
+    #[test]
+    fn parses_v2_lite_config() {
+        let cfg = Config::from_hf_json(&v2_lite_json(), None).unwrap();
+        assert_eq!(cfg.q_lora_rank, None);
+        assert_eq!(cfg.q_head_dim(), 192);
+        assert!(!cfg.is_moe_layer(0) && cfg.is_moe_layer(1));
+        assert_eq!(cfg.routing.n_group, 1);
+
+        let scaling = cfg.rope_scaling.unwrap();
+        assert_eq!(scaling.original_context_length, Some(4096));
+        assert_eq!(scaling.attention_factor, Some(1.0));
+        assert_eq!(cfg.max_seq_len(), 163840);
+        // mscale_all_dim увеличивает масштаб softmax: (0.1 * 0.707 * ln 40 + 1)^2
+        let expected = (0.1 * 0.707 * 40f64.ln() + 1.0).powi(2) / 192f64.sqrt();
+        assert!((cfg.softmax_scale() - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn reads_mla_dims_from_gguf() {
+        let mut md = HashMap::new();
+        let mut set = |k: &str, v: Value| md.insert(k.to_string(), v);
+        set("general.architecture", Value::String("deepseek2".into()));
+        for (key, value) in [
+            ("attention.head_count", 16),
+            ("rope.dimension_count", 64),
+            ("attention.kv_lora_rank", 512),
+            // Новый формат: key_length/value_length описывают латентное пространство
+            ("attention.key_length", 576),
+            ("attention.value_length", 512),
+            ("attention.key_length_mla", 192),
+            ("attention.value_length_mla", 128),
+            ("embedding_length", 2048),
+            ("feed_forward_length", 10944),
+            ("expert_feed_forward_length", 1408),
+            ("block_count", 27),
+            ("expert_count", 64),
+            ("expert_used_count", 6),
+            ("expert_shared_count", 2),
+            ("leading_dense_block_count", 1),
+        ] {
+            set(&format!("deepseek2.{key}"), Value::U32(value));
+        }
+        let cfg = Config::from_gguf(&md).unwrap();
+        assert_eq!(cfg.qk_nope_head_dim, 128);
+        assert_eq!(cfg.v_head_dim, 128);
+        assert_eq!(cfg.q_lora_rank, None);
+        assert_eq!(cfg.routing.scoring_func, ScoringFunc::Softmax);
+        assert!(cfg.is_moe_layer(1));
+    }
+}
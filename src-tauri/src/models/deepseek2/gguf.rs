@@ -0,0 +1,142 @@
+//! DeepSeek-V2 / V3 GGUF loading
+//!
+//! Загрузка квантизированных моделей архитектуры llama.cpp `deepseek2`.
+//! Упакованные тензоры экспертов `ffn_*_exps` режутся на отдельные матрицы,
+//! а если конвертер разделил `attn_kv_b` на `attn_k_b` / `attn_v_b`, исходная
+//! матрица собирается обратно.
+
+use std::sync::Arc;
+
+use candle::quantized::{QMatMul, QTensor, gguf_file};
+use candle::{Device, Tensor};
+
+use super::DeepSeek2Backend;
+use super::config::Config;
+use super::model::{DeepSeek2, ExpertProj, WeightSource};
+use crate::models::api::optimization::OptimizationConfig;
+use crate::models::common::gguf_mmap::{GgufRead, gguf_tensor};
+use crate::models::common::gguf_split::open_gguf;
+use crate::models::qwen3_moe::fused_moe::split_experts;
+
+struct GgufWeights<'a, R: GgufRead> {
+    content: &'a gguf_file::Content,
+    reader: &'a mut R,
+    device: Device,
+}
+
+impl<R: GgufRead> GgufWeights<'_, R> {
+    fn qtensor(&mut self, name: &str) -> candle::Result<QTensor> {
+        gguf_tensor(self.content, self.reader, name, &self.device)
+    }
+
+    fn dequantize(&mut self, name: &str, shape: &[usize]) -> candle::Result<Tensor> {
+        self.qtensor(name)?.dequantize(&self.device)?.reshape(shape)
+    }
+}
+
+impl<R: GgufRead> WeightSource for GgufWeights<'_, R> {
+    fn contains(&self, name: &str) -> bool {
+        self.content
+            .tensor_infos
+            .contains_key(&format!("{name}.weight"))
+    }
+
+    fn matmul(&mut self, name: &str, shape: (usize, usize)) -> candle::Result<QMatMul> {
+        let qt = self.qtensor(&format!("{name}.weight"))?;
+        if qt.shape().dims2()? != shape {
+            candle::bail!("{name}: expected shape {:?}, got {:?}", shape, qt.shape());
+        }
+        QMatMul::from_arc(Arc::new(qt))
+    }
+
+    fn tensor(&mut self, name: &str, shape: &[usize]) -> candle::Result<Tensor> {
+        self.dequantize(&format!("{name}.weight"), shape)
+    }
+
+    fn experts(
+        &mut self,
+        layer: usize,
+        proj: ExpertProj,
+        count: usize,
+        (out, inp): (usize, usize),
+    ) -> candle::Result<Vec<QMatMul>> {
+        let name = format!("blk.{layer}.ffn_{}_exps.weight", proj.as_str());
+        let packed = self.qtensor(&name)?;
+        if packed.shape().dims3()? != (count, out, inp) {
+            candle::bail!(
+                "{name}: expected shape {:?}, got {:?}",
+                (count, out, inp),
+                packed.shape()
+            );
+        }
+        split_experts(&packed)
+    }
+
+    fn router_bias(&mut self, layer: usize, count: usize) -> candle::Result<Option<Tensor>> {
+        let name = format!("blk.{layer}.exp_probs_b.bias");
+        if !self.content.tensor_infos.contains_key(&name) {
+            return Ok(None);
+        }
+        self.dequantize(&name, &[count]).map(Some)
+    }
+
+    fn kv_b_proj(&mut self, layer: usize, cfg: &Config) -> candle::Result<Tensor> {
+        let (heads, nope, v, rank) = (
+            cfg.num_attention_heads,
+            cfg.qk_nope_head_dim,
+            cfg.v_head_dim,
+            cfg.kv_lora_rank,
+        );
+        let prefix = format!("blk.{layer}");
+        if self.contains(&format!("{prefix}.attn_kv_b")) {
+            return self.tensor(&format!("{prefix}.attn_kv_b"), &[heads * (nope + v), rank]);
+        }
+        // attn_k_b хранится транспонированным: [heads, rank, nope]; attn_v_b: [heads, v, rank]
+        let k_b = self.tensor(&format!("{prefix}.attn_k_b"), &[heads, rank, nope])?;
+        let v_b = self.tensor(&format!("{prefix}.attn_v_b"), &[heads, v, rank])?;
+        Tensor::cat(&[k_b.transpose(1, 2)?, v_b], 1)?.reshape((heads * (nope + v), rank))
+    }
+}
+
+impl DeepSeek2Backend {
+    /// Создаёт бекенд из GGUF Content
+    pub fn from_gguf<R: GgufRead>(
+        content: gguf_file::Content,
+        file: &mut R,
+        device: &Device,
+    ) -> Result<Self, String> {
+        let cfg = Config::from_gguf(&content.metadata)
+            .map_err(|e| format!("Failed to read DeepSeek2 GGUF config: {}", e))?;
+
+        log::info!(
+            "Loading DeepSeek2 GGUF: layers={}, experts={}/{}, kv_lora_rank={}, q_lora_rank={:?}",
+            cfg.num_hidden_layers,
+            cfg.routing.num_experts_per_tok,
+            cfg.n_routed_experts,
+            cfg.kv_lora_rank,
+            cfg.q_lora_rank
+        );
+
+        let mut weights = GgufWeights {
+            content: &content,
+            reader: file,
+            device: device.clone(),
+        };
+        let model = DeepSeek2::load(&mut weights, &cfg, device)
+            .map_err(|e| format!("Failed to load DeepSeek2 GGUF model: {}", e))?;
+
+        Ok(Self::new(
+            model,
+            device.clone(),
+            cfg.vocab_size,
+            cfg.max_seq_len(),
+            OptimizationConfig::for_gguf(),
+        ))
+    }
+
+    /// Создаёт бекенд из пути к GGUF файлу
+    pub fn from_gguf_path(path: &std::path::Path, device: &Device) -> Result<Self, String> {
+        let (content, mut reader) = open_gguf(path)?;
+        Self::from_gguf(content, &mut reader, device)
+    }
+}
@@ -0,0 +1,153 @@
+//! DeepSeek-V2 / V3 model backend
+//!
+//! Mixture of Experts с Multi-head Latent Attention (MLA): DeepSeek-V2, V2-Lite,
+//! V2.5 и V3 (`deepseek_v2` / `deepseek_v3` в config.json, `deepseek2` в GGUF).
+//! Дистилляты R1 на базе Llama/Qwen сюда не относятся.
+//!
+//! # Структура
+//! - `mod.rs` - DeepSeek2Backend и ModelBackend реализация
+//! - `config.rs` - конфигурация из config.json или метаданных GGUF
+//! - `model.rs` - декодер с латентным KV-кэшем (общий для обоих форматов)
+//! - `gguf.rs` - загрузка из GGUF формата
+//! - `safetensors.rs` - загрузка из SafeTensors формата
+
+pub mod config;
+mod gguf;
+pub mod model;
+mod safetensors;
+
+pub use config::Config;
+
+use candle::{Device, Tensor};
+
+use crate::models::ModelBackend;
+use crate::models::api::optimization::{OptimizationConfig, WeightFormat};
+use crate::models::common::{KvCacheType, KvLayers, LayerKv};
+
+use model::DeepSeek2;
+
+/// DeepSeek-V2 / V3 бекенд
+///
+/// Одна модель для GGUF и SafeTensors; KV-кэш хранится в сжатом латентном виде,
+/// поэтому откат, сдвиг контекста и снимки доступны в обоих форматах.
+pub struct DeepSeek2Backend {
+    model: DeepSeek2,
+    device: Device,
+    vocab_size: usize,
+    max_seq_len: usize,
+    optimization: OptimizationConfig,
+}
+
+impl DeepSeek2Backend {
+    /// Создаёт бекенд (используется из gguf.rs и safetensors.rs)
+    pub(crate) fn new(
+        model: DeepSeek2,
+        device: Device,
+        vocab_size: usize,
+        max_seq_len: usize,
+        optimization: OptimizationConfig,
+    ) -> Self {
+        Self {
+            model,
+            device,
+            vocab_size,
+            max_seq_len,
+            optimization,
+        }
+    }
+
+    /// Возвращает устройство
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    /// Проверяет, квантизирована ли модель
+    pub fn is_quantized(&self) -> bool {
+        self.optimization.weight_format() == WeightFormat::Gguf
+    }
+
+    /// Возвращает конфигурацию оптимизаций
+    pub fn optimization(&self) -> &OptimizationConfig {
+        &self.optimization
+    }
+
+    /// Размер латентного KV-кэша в байтах
+    pub fn kv_cache_size_in_bytes(&self) -> usize {
+        self.model.kv_cache_size_in_bytes()
+    }
+}
+
+impl ModelBackend for DeepSeek2Backend {
+    fn forward(&mut self, input: &Tensor, pos: usize) -> candle::Result<Tensor> {
+        self.model.forward(input, pos)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.model.clear_kv_cache()
+    }
+
+    fn model_type(&self) -> &str {
+        if self.is_quantized() {
+            "deepseek2-gguf"
+        } else {
+            "deepseek2"
+        }
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.vocab_size
+    }
+
+    fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+
+    fn supports_flash_attn(&self) -> bool {
+        // Внимание считается в латентном пространстве, flash-attn неприменим
+        false
+    }
+
+    fn supports_chunked_prefill(&self) -> bool {
+        true
+    }
+
+    fn forward_all(&mut self, input: &Tensor, pos: usize) -> candle::Result<Tensor> {
+        self.model.forward_all(input, pos)
+    }
+
+    fn truncate_kv_cache(&mut self, len: usize) -> bool {
+        self.model.truncate_kv_cache(len).is_ok()
+    }
+
+    fn supports_speculative(&self) -> bool {
+        true
+    }
+
+    fn supports_context_shift(&self) -> bool {
+        true
+    }
+
+    fn shift_kv_cache(&mut self, keep: usize, discard: usize) -> bool {
+        self.model.shift_kv_cache(keep, discard).is_ok()
+    }
+
+    fn supports_kv_snapshot(&self) -> bool {
+        true
+    }
+
+    fn export_kv_cache(&self) -> candle::Result<Vec<LayerKv>> {
+        self.model.export_kv_cache()
+    }
+
+    fn import_kv_cache(&mut self, layers: &[LayerKv]) -> candle::Result<()> {
+        self.model.import_kv_cache(layers)
+    }
+
+    fn set_kv_cache_type(&mut self, cache_type: KvCacheType) -> bool {
+        self.model.set_kv_cache_type(cache_type).is_ok()
+    }
+
+    fn get_embeddings(&mut self, input: &Tensor) -> candle::Result<Tensor> {
+        self.model.get_hidden_states(input, 0)
+    }
+}
@@ -0,0 +1,620 @@
+//! DeepSeek-V2 / V3 decoder with Multi-head Latent Attention
+//!
+//! Одна реализация для GGUF и SafeTensors: проекции хранятся как
+//! `candle::quantized::QMatMul` (квантизированный тензор или тензор полной точности),
+//! а веса поставляет [`WeightSource`] с именами в стиле GGUF.
+//!
+//! # Латентный KV-кэш
+//! В кэше слоя лежат не K/V по головам, а сжатый вектор `c_kv` (`kv_lora_rank`)
+//! и общий для всех голов RoPE-ключ `k_pe` (`qk_rope_head_dim`). Для V2-Lite это
+//! 576 чисел на позицию вместо 16 × (192 + 128). Матрица `kv_b_proj` поглощается
+//! в запрос и выход внимания:
+//! - `score = (q_nope · W_uk) · c_kv + q_pe · k_pe`;
+//! - `out = (softmax · c_kv) · W_uv`.
+//!
+//! `k_pe` хранится на месте ключей `KvCache`, поэтому сдвиг контекста
+//! ре-ротирует именно его; `c_kv` позиционно-независим.
+
+use std::collections::HashMap;
+
+use candle::quantized::QMatMul;
+use candle::{D, DType, Device, Module, Result, Tensor};
+use candle_nn::{Embedding, RmsNorm};
+
+use super::config::{Config, Routing, ScoringFunc};
+use crate::models::common::rope_scaling::rope_tables;
+use crate::models::common::{KvCache, KvLayers, RopeShift, causal_mask};
+
+/// Проекция эксперта
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpertProj {
+    Gate,
+    Up,
+    Down,
+}
+
+impl ExpertProj {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Gate => "gate",
+            Self::Up => "up",
+            Self::Down => "down",
+        }
+    }
+}
+
+/// Источник весов; имена тензоров — в стиле GGUF без суффикса `.weight`
+/// (`token_embd`, `blk.0.attn_kv_a_mqa`, ...)
+pub(crate) trait WeightSource {
+    fn contains(&self, name: &str) -> bool;
+    /// Матрица проекции [out, in]
+    fn matmul(&mut self, name: &str, shape: (usize, usize)) -> Result<QMatMul>;
+    /// Тензор в dtype активаций (нормы, эмбеддинги)
+    fn tensor(&mut self, name: &str, shape: &[usize]) -> Result<Tensor>;
+    /// Эксперты слоя, по одной матрице [out, in] на эксперта
+    fn experts(
+        &mut self,
+        layer: usize,
+        proj: ExpertProj,
+        count: usize,
+        shape: (usize, usize),
+    ) -> Result<Vec<QMatMul>>;
+    /// Поправочное смещение роутера DeepSeek-V3 (`None`, если его нет)
+    fn router_bias(&mut self, layer: usize, count: usize) -> Result<Option<Tensor>>;
+
+    /// `kv_b_proj` [heads * (qk_nope_head_dim + v_head_dim), kv_lora_rank]
+    fn kv_b_proj(&mut self, layer: usize, cfg: &Config) -> Result<Tensor> {
+        let rows = cfg.num_attention_heads * (cfg.qk_nope_head_dim + cfg.v_head_dim);
+        self.tensor(&format!("blk.{layer}.attn_kv_b"), &[rows, cfg.kv_lora_rank])
+    }
+}
+
+fn rms_norm(ws: &mut dyn WeightSource, name: &str, size: usize, eps: f64) -> Result<RmsNorm> {
+    Ok(RmsNorm::new(ws.tensor(name, &[size])?, eps))
+}
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    gate: QMatMul,
+    up: QMatMul,
+    down: QMatMul,
+}
+
+impl Mlp {
+    fn load(
+        ws: &mut dyn WeightSource,
+        prefix: &str,
+        hidden: usize,
+        intermediate: usize,
+    ) -> Result<Self> {
+        Ok(Self {
+            gate: ws.matmul(&format!("{prefix}.ffn_gate"), (intermediate, hidden))?,
+            up: ws.matmul(&format!("{prefix}.ffn_up"), (intermediate, hidden))?,
+            down: ws.matmul(&format!("{prefix}.ffn_down"), (hidden, intermediate))?,
+        })
+    }
+
+    fn load_shared(
+        ws: &mut dyn WeightSource,
+        prefix: &str,
+        hidden: usize,
+        intermediate: usize,
+    ) -> Result<Self> {
+        Ok(Self {
+            gate: ws.matmul(&format!("{prefix}.ffn_gate_shexp"), (intermediate, hidden))?,
+            up: ws.matmul(&format!("{prefix}.ffn_up_shexp"), (intermediate, hidden))?,
+            down: ws.matmul(&format!("{prefix}.ffn_down_shexp"), (hidden, intermediate))?,
+        })
+    }
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let gate = candle_nn::ops::silu(&self.gate.forward(xs)?)?;
+        let up = self.up.forward(xs)?;
+        self.down.forward(&(gate * up)?)
+    }
+}
+
+/// Выбирает экспертов для одного токена по оценкам роутера.
+///
+/// `bias` (DeepSeek-V3) влияет только на выбор, веса берутся из исходных оценок.
+/// При группировке сначала отбираются `topk_group` групп: по максимуму оценки (V2)
+/// или по сумме двух лучших оценок группы (V3, при наличии `bias`).
+pub(crate) fn select_experts(
+    scores: &[f32],
+    bias: Option<&[f32]>,
+    routing: &Routing,
+) -> Vec<(usize, f32)> {
+    let mut choice: Vec<f32> = match bias {
+        Some(bias) => scores.iter().zip(bias).map(|(s, b)| s + b).collect(),
+        None => scores.to_vec(),
+    };
+
+    if routing.n_group > 1 && routing.topk_group < routing.n_group {
+        let group_size = choice.len() / routing.n_group;
+        let mut groups: Vec<(usize, f32)> = choice
+            .chunks(group_size)
+            .map(|group| {
+                let mut sorted = group.to_vec();
+                sorted.sort_by(|a, b| b.total_cmp(a));
+                if bias.is_some() {
+                    sorted.iter().take(2).sum()
+                } else {
+                    sorted[0]
+                }
+            })
+            .enumerate()
+            .collect();
+        groups.sort_by(|a, b| b.1.total_cmp(&a.1));
+        for &(group, _) in &groups[routing.topk_group..] {
+            choice[group * group_size..(group + 1) * group_size].fill(f32::NEG_INFINITY);
+        }
+    }
+
+    let mut order: Vec<usize> = (0..choice.len()).collect();
+    order.sort_by(|&a, &b| choice[b].total_cmp(&choice[a]));
+    let mut selected: Vec<(usize, f32)> = order
+        .into_iter()
+        .take(routing.num_experts_per_tok)
+        .map(|e| (e, scores[e]))
+        .collect();
+
+    if routing.norm_topk_prob {
+        let sum: f32 = selected.iter().map(|(_, w)| w).sum::<f32>() + 1e-20;
+        for (_, w) in &mut selected {
+            *w /= sum;
+        }
+    }
+    for (_, w) in &mut selected {
+        *w *= routing.routed_scaling_factor as f32;
+    }
+    selected
+}
+
+struct Moe {
+    /// Веса роутера [n_experts, hidden] в F32
+    router: Tensor,
+    router_bias: Option<Vec<f32>>,
+    experts: Vec<Mlp>,
+    shared: Option<Mlp>,
+    routing: Routing,
+}
+
+impl Moe {
+    fn load(ws: &mut dyn WeightSource, layer: usize, cfg: &Config) -> Result<Self> {
+        let prefix = format!("blk.{layer}");
+        let (hidden, inter, n) = (
+            cfg.hidden_size,
+            cfg.moe_intermediate_size,
+            cfg.n_routed_experts,
+        );
+        let router = ws
+            .tensor(&format!("{prefix}.ffn_gate_inp"), &[n, hidden])?
+            .to_dtype(DType::F32)?;
+        let router_bias = ws
+            .router_bias(layer, n)?
+            .map(|b| b.to_dtype(DType::F32)?.to_vec1::<f32>())
+            .transpose()?;
+        let gate = ws.experts(layer, ExpertProj::Gate, n, (inter, hidden))?;
+        let up = ws.experts(layer, ExpertProj::Up, n, (inter, hidden))?;
+        let down = ws.experts(layer, ExpertProj::Down, n, (hidden, inter))?;
+        let experts = gate
+            .into_iter()
+            .zip(up)
+            .zip(down)
+            .map(|((gate, up), down)| Mlp { gate, up, down })
+            .collect();
+        let shared = if cfg.n_shared_experts > 0 {
+            Some(Mlp::load_shared(
+                ws,
+                &prefix,
+                hidden,
+                inter * cfg.n_shared_experts,
+            )?)
+        } else {
+            None
+        };
+        Ok(Self {
+            router,
+            router_bias,
+            experts,
+            shared,
+            routing: cfg.routing,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let (b, s, hidden) = xs.dims3()?;
+        let xs = xs.reshape((b * s, hidden))?;
+        let logits = xs.to_dtype(DType::F32)?.matmul(&self.router.t()?)?;
+        let scores = match self.routing.scoring_func {
+            ScoringFunc::Softmax => candle_nn::ops::softmax_last_dim(&logits)?,
+            ScoringFunc::Sigmoid => candle_nn::ops::sigmoid(&logits)?,
+        };
+
+        let mut rows: HashMap<usize, (Vec<u32>, Vec<f32>)> = HashMap::new();
+        for (token, scores) in scores.to_vec2::<f32>()?.iter().enumerate() {
+            for (expert, weight) in
+                select_experts(scores, self.router_bias.as_deref(), &self.routing)
+            {
+                let entry = rows.entry(expert).or_default();
+                entry.0.push(token as u32);
+                entry.1.push(weight);
+            }
+        }
+
+        let mut ys = xs.zeros_like()?;
+        for (expert, (tokens, weights)) in rows {
+            let idx = Tensor::new(tokens.as_slice(), xs.device())?;
+            let weights = Tensor::new(weights.as_slice(), xs.device())?
+                .reshape(((), 1))?
+                .to_dtype(xs.dtype())?;
+            let out = self.experts[expert]
+                .forward(&xs.index_select(&idx, 0)?)?
+                .broadcast_mul(&weights)?;
+            ys = ys.index_add(&idx, &out, 0)?;
+        }
+        if let Some(shared) = &self.shared {
+            ys = (ys + shared.forward(&xs)?)?;
+        }
+        ys.reshape((b, s, hidden))
+    }
+}
+
+enum FeedForward {
+    Dense(Mlp),
+    Moe(Box<Moe>),
+}
+
+impl FeedForward {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Dense(mlp) => mlp.forward(xs),
+            Self::Moe(moe) => moe.forward(xs),
+        }
+    }
+}
+
+/// Проекция Q: напрямую (V2-Lite) или через LoRA-сжатие
+enum QProj {
+    Plain(QMatMul),
+    Lora {
+        a: QMatMul,
+        norm: RmsNorm,
+        b: QMatMul,
+    },
+}
+
+impl Module for QProj {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Plain(q) => q.forward(xs),
+            Self::Lora { a, norm, b } => b.forward(&norm.forward(&a.forward(xs)?)?),
+        }
+    }
+}
+
+struct Attention {
+    q: QProj,
+    /// hidden -> [c_kv | k_pe]
+    kv_a: QMatMul,
+    kv_a_norm: RmsNorm,
+    /// Поглощённая часть kv_b_proj для ключей [heads, qk_nope_head_dim, kv_lora_rank] (F32)
+    w_uk: Tensor,
+    /// Поглощённая часть kv_b_proj для значений [heads, kv_lora_rank, v_head_dim] (F32)
+    w_uv: Tensor,
+    o_proj: QMatMul,
+    num_heads: usize,
+    nope_dim: usize,
+    rope_dim: usize,
+    kv_lora_rank: usize,
+    v_head_dim: usize,
+    softmax_scale: f64,
+    /// Латентный кэш: k = k_pe [b, 1, T, rope_dim], v = c_kv [b, 1, T, kv_lora_rank]
+    kv_cache: KvCache,
+}
+
+impl Attention {
+    fn load(ws: &mut dyn WeightSource, layer: usize, cfg: &Config) -> Result<Self> {
+        let prefix = format!("blk.{layer}");
+        let (hidden, heads) = (cfg.hidden_size, cfg.num_attention_heads);
+        let q_out = heads * cfg.q_head_dim();
+        let q = match cfg.q_lora_rank {
+            Some(rank) => QProj::Lora {
+                a: ws.matmul(&format!("{prefix}.attn_q_a"), (rank, hidden))?,
+                norm: rms_norm(
+                    ws,
+                    &format!("{prefix}.attn_q_a_norm"),
+                    rank,
+                    cfg.rms_norm_eps,
+                )?,
+                b: ws.matmul(&format!("{prefix}.attn_q_b"), (q_out, rank))?,
+            },
+            None => QProj::Plain(ws.matmul(&format!("{prefix}.attn_q"), (q_out, hidden))?),
+        };
+        let kv_a = ws.matmul(
+            &format!("{prefix}.attn_kv_a_mqa"),
+            (cfg.kv_lora_rank + cfg.qk_rope_head_dim, hidden),
+        )?;
+        let kv_a_norm = rms_norm(
+            ws,
+            &format!("{prefix}.attn_kv_a_norm"),
+            cfg.kv_lora_rank,
+            cfg.rms_norm_eps,
+        )?;
+        let kv_b = ws.kv_b_proj(layer, cfg)?.to_dtype(DType::F32)?.reshape((
+            heads,
+            cfg.qk_nope_head_dim + cfg.v_head_dim,
+            cfg.kv_lora_rank,
+        ))?;
+        let w_uk = kv_b.narrow(1, 0, cfg.qk_nope_head_dim)?.contiguous()?;
+        let w_uv = kv_b
+            .narrow(1, cfg.qk_nope_head_dim, cfg.v_head_dim)?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let o_proj = ws.matmul(
+            &format!("{prefix}.attn_output"),
+            (hidden, heads * cfg.v_head_dim),
+        )?;
+        Ok(Self {
+            q,
+            kv_a,
+            kv_a_norm,
+            w_uk,
+            w_uv,
+            o_proj,
+            num_heads: heads,
+            nope_dim: cfg.qk_nope_head_dim,
+            rope_dim: cfg.qk_rope_head_dim,
+            kv_lora_rank: cfg.kv_lora_rank,
+            v_head_dim: cfg.v_head_dim,
+            softmax_scale: cfg.softmax_scale(),
+            kv_cache: KvCache::new(2),
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        mask: Option<&Tensor>,
+        (cos, sin): (&Tensor, &Tensor),
+    ) -> Result<Tensor> {
+        let (b, s, _) = xs.dims3()?;
+        let (h, rank) = (self.num_heads, self.kv_lora_rank);
+        let dtype = xs.dtype();
+
+        let q = self
+            .q
+            .forward(xs)?
+            .reshape((b, s, h, self.nope_dim + self.rope_dim))?
+            .transpose(1, 2)?
+            .to_dtype(DType::F32)?;
+        let q_nope = q.narrow(D::Minus1, 0, self.nope_dim)?.contiguous()?;
+        let q_pe = q
+            .narrow(D::Minus1, self.nope_dim, self.rope_dim)?
+            .contiguous()?;
+
+        let kv = self.kv_a.forward(xs)?;
+        let c_kv = self
+            .kv_a_norm
+            .forward(&kv.narrow(D::Minus1, 0, rank)?.contiguous()?)?
+            .unsqueeze(1)?;
+        let k_pe = kv
+            .narrow(D::Minus1, rank, self.rope_dim)?
+            .to_dtype(DType::F32)?
+            .reshape((b, 1, s, self.rope_dim))?;
+
+        let q_pe = candle_nn::rotary_emb::rope_i(&q_pe, cos, sin)?;
+        let k_pe = candle_nn::rotary_emb::rope_i(&k_pe, cos, sin)?;
+
+        let (k_pe, c_kv) = self.kv_cache.append(&k_pe.to_dtype(dtype)?, &c_kv)?;
+        let t = k_pe.dim(2)?;
+        let k_pe = k_pe.squeeze(1)?.to_dtype(DType::F32)?;
+        let c_kv = c_kv.squeeze(1)?.to_dtype(DType::F32)?;
+
+        // Запрос в латентном пространстве: [b, h, s, rank]
+        let q_latent = q_nope.broadcast_matmul(&self.w_uk)?;
+        let scores = (q_latent.reshape((b, h * s, rank))?.matmul(&c_kv.t()?)?
+            + q_pe
+                .reshape((b, h * s, self.rope_dim))?
+                .matmul(&k_pe.t()?)?)?;
+        let mut scores = (scores * self.softmax_scale)?.reshape((b, h, s, t))?;
+        if let Some(mask) = mask {
+            scores = scores.broadcast_add(mask)?;
+        }
+        let probs = candle_nn::ops::softmax_last_dim(&scores)?;
+
+        let ctx = probs
+            .reshape((b, h * s, t))?
+            .matmul(&c_kv)?
+            .reshape((b, h, s, rank))?;
+        let out = ctx
+            .broadcast_matmul(&self.w_uv)?
+            .transpose(1, 2)?
+            .reshape((b, s, h * self.v_head_dim))?
+            .to_dtype(dtype)?;
+        self.o_proj.forward(&out)
+    }
+}
+
+struct Layer {
+    attn_norm: RmsNorm,
+    attn: Attention,
+    ffn_norm: RmsNorm,
+    ffn: FeedForward,
+}
+
+pub struct DeepSeek2 {
+    embed_tokens: Embedding,
+    layers: Vec<Layer>,
+    norm: RmsNorm,
+    lm_head: QMatMul,
+    /// Таблицы RoPE [max_len, qk_rope_head_dim / 2] в F32
+    cos: Tensor,
+    sin: Tensor,
+    rope_shift: RopeShift,
+    device: Device,
+}
+
+impl DeepSeek2 {
+    pub(crate) fn load(ws: &mut dyn WeightSource, cfg: &Config, device: &Device) -> Result<Self> {
+        let hidden = cfg.hidden_size;
+        let embeddings = ws.tensor("token_embd", &[cfg.vocab_size, hidden])?;
+        let lm_head = if ws.contains("output") && !cfg.tie_word_embeddings {
+            ws.matmul("output", (cfg.vocab_size, hidden))?
+        } else {
+            QMatMul::Tensor(embeddings.clone())
+        };
+        let norm = rms_norm(ws, "output_norm", hidden, cfg.rms_norm_eps)?;
+
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        for layer in 0..cfg.num_hidden_layers {
+            let prefix = format!("blk.{layer}");
+            let ffn = if cfg.is_moe_layer(layer) {
+                FeedForward::Moe(Box::new(Moe::load(ws, layer, cfg)?))
+            } else {
+                FeedForward::Dense(Mlp::load(ws, &prefix, hidden, cfg.intermediate_size)?)
+            };
+            layers.push(Layer {
+                attn_norm: rms_norm(ws, &format!("{prefix}.attn_norm"), hidden, cfg.rms_norm_eps)?,
+                attn: Attention::load(ws, layer, cfg)?,
+                ffn_norm: rms_norm(ws, &format!("{prefix}.ffn_norm"), hidden, cfg.rms_norm_eps)?,
+                ffn,
+            });
+        }
+
+        let (cos, sin) = rope_tables(
+            cfg.qk_rope_head_dim,
+            cfg.rope_theta,
+            cfg.rope_scaling.as_ref(),
+            cfg.max_seq_len(),
+            device,
+        )?;
+        Ok(Self {
+            embed_tokens: Embedding::new(embeddings, hidden),
+            layers,
+            norm,
+            lm_head,
+            cos,
+            sin,
+            rope_shift: cfg.rope_shift(),
+            device: device.clone(),
+        })
+    }
+
+    /// Прогоняет стек декодера, возвращает скрытые состояния [batch, seq_len, hidden]
+    fn forward_hidden(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        let (b, s) = input.dims2()?;
+        let mask = if s == 1 {
+            None
+        } else {
+            Some(causal_mask(b, s, offset, DType::F32, &self.device)?)
+        };
+        let cos = self.cos.narrow(0, offset, s)?;
+        let sin = self.sin.narrow(0, offset, s)?;
+
+        let mut xs = self.embed_tokens.forward(input)?;
+        for layer in &mut self.layers {
+            let h = layer.attn_norm.forward(&xs)?;
+            let h = layer.attn.forward(&h, mask.as_ref(), (&cos, &sin))?;
+            let xs_attn = (h + xs)?;
+            let h = layer.ffn.forward(&layer.ffn_norm.forward(&xs_attn)?)?;
+            xs = (h + xs_attn)?;
+        }
+        Ok(xs)
+    }
+
+    /// Логиты последнего токена [batch, vocab_size]
+    pub fn forward(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        let (_, s) = input.dims2()?;
+        let xs = self.forward_hidden(input, offset)?.narrow(1, s - 1, 1)?;
+        self.lm_head
+            .forward(&self.norm.forward(&xs)?)?
+            .to_dtype(DType::F32)?
+            .squeeze(1)
+    }
+
+    /// Скрытые состояния после финальной нормализации [batch, seq_len, hidden]
+    pub fn get_hidden_states(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        let xs = self.forward_hidden(input, offset)?;
+        self.norm.forward(&xs)
+    }
+
+    /// Логиты для всех позиций [seq_len, vocab_size]
+    pub fn forward_all(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        let xs = self.forward_hidden(input, offset)?;
+        self.lm_head
+            .forward(&self.norm.forward(&xs)?)?
+            .to_dtype(DType::F32)?
+            .squeeze(0)
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        for layer in &mut self.layers {
+            layer.attn.kv_cache.reset();
+        }
+    }
+}
+
+impl KvLayers for DeepSeek2 {
+    fn kv_caches(&self) -> Vec<&KvCache> {
+        self.layers.iter().map(|l| &l.attn.kv_cache).collect()
+    }
+
+    fn kv_caches_mut(&mut self) -> Vec<&mut KvCache> {
+        self.layers
+            .iter_mut()
+            .map(|l| &mut l.attn.kv_cache)
+            .collect()
+    }
+
+    // В латентном кэше на месте ключей лежит `k_pe`: ре-ротируется только он
+    fn shift_rope(&self, _layer: usize) -> Result<RopeShift> {
+        Ok(self.rope_shift)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routing(top_k: usize, n_group: usize, topk_group: usize) -> Routing {
+        Routing {
+            num_experts_per_tok: top_k,
+            scoring_func: ScoringFunc::Softmax,
+            n_group,
+            topk_group,
+            norm_topk_prob: false,
+            routed_scaling_factor: 1.0,
+        }
+    }
+
+    #[test]
+    fn greedy_routing_picks_top_scores() {
+        let scores = [0.1, 0.4, 0.05, 0.3, 0.15];
+        let selected = select_experts(&scores, None, &routing(2, 1, 1));
+        assert_eq!(selected, vec![(1, 0.4), (3, 0.3)]);
+    }
+
+    #[test]
+    fn group_limited_routing_masks_weak_groups() {
+        // Группы: [0, 1] max 0.3, [2, 3] max 0.35, [4, 5] max 0.2
+        let scores = [0.3, 0.25, 0.35, 0.01, 0.2, 0.19];
+        let selected = select_experts(&scores, None, &routing(3, 3, 2));
+        let experts: Vec<usize> = selected.iter().map(|(e, _)| *e).collect();
+        assert_eq!(experts, vec![2, 0, 1]);
+    }
+
+    #[test]
+    fn bias_affects_choice_but_not_weights() {
+        let mut r = routing(1, 1, 1);
+        r.norm_topk_prob = true;
+        r.routed_scaling_factor = 2.5;
+        let scores = [0.6, 0.4];
+        let selected = select_experts(&scores, Some(&[0.0, 0.5]), &r);
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].0, 1);
+        assert!((selected[0].1 - 2.5).abs() < 1e-5);
+    }
+}
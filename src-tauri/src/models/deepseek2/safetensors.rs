@@ -0,0 +1,162 @@
+//! DeepSeek-V2 / V3 SafeTensors loading
+//!
+//! Имена тензоров HuggingFace (`model.layers.N.self_attn.kv_a_proj_with_mqa`, ...)
+//! отображаются на имена в стиле GGUF, с которыми работает [`DeepSeek2`].
+
+use std::path::{Path, PathBuf};
+
+use candle::quantized::QMatMul;
+use candle::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+
+use super::DeepSeek2Backend;
+use super::config::Config;
+use super::model::{DeepSeek2, ExpertProj, WeightSource};
+use crate::models::api::optimization::OptimizationConfig;
+use crate::models::common::RopeScaling;
+
+/// Переводит имя тензора в стиле GGUF в имя HuggingFace (без `.weight`)
+fn hf_name(name: &str) -> String {
+    match name {
+        "token_embd" => return "model.embed_tokens".to_string(),
+        "output_norm" => return "model.norm".to_string(),
+        "output" => return "lm_head".to_string(),
+        _ => {}
+    }
+    let Some((layer, tensor)) = name
+        .strip_prefix("blk.")
+        .and_then(|rest| rest.split_once('.'))
+    else {
+        return name.to_string();
+    };
+    let tensor = match tensor {
+        "attn_norm" => "input_layernorm",
+        "ffn_norm" => "post_attention_layernorm",
+        "attn_q" => "self_attn.q_proj",
+        "attn_q_a" => "self_attn.q_a_proj",
+        "attn_q_a_norm" => "self_attn.q_a_layernorm",
+        "attn_q_b" => "self_attn.q_b_proj",
+        "attn_kv_a_mqa" => "self_attn.kv_a_proj_with_mqa",
+        "attn_kv_a_norm" => "self_attn.kv_a_layernorm",
+        "attn_kv_b" => "self_attn.kv_b_proj",
+        "attn_output" => "self_attn.o_proj",
+        "ffn_gate" => "mlp.gate_proj",
+        "ffn_up" => "mlp.up_proj",
+        "ffn_down" => "mlp.down_proj",
+        "ffn_gate_inp" => "mlp.gate",
+        "ffn_gate_shexp" => "mlp.shared_experts.gate_proj",
+        "ffn_up_shexp" => "mlp.shared_experts.up_proj",
+        "ffn_down_shexp" => "mlp.shared_experts.down_proj",
+        other => other,
+    };
+    format!("model.layers.{layer}.{tensor}")
+}
+
+struct SafetensorsWeights<'a> {
+    vb: VarBuilder<'a>,
+}
+
+impl WeightSource for SafetensorsWeights<'_> {
+    fn contains(&self, name: &str) -> bool {
+        self.vb
+            .contains_tensor(&format!("{}.weight", hf_name(name)))
+    }
+
+    fn matmul(&mut self, name: &str, shape: (usize, usize)) -> candle::Result<QMatMul> {
+        self.tensor(name, &[shape.0, shape.1]).map(QMatMul::Tensor)
+    }
+
+    fn tensor(&mut self, name: &str, shape: &[usize]) -> candle::Result<Tensor> {
+        self.vb.get(shape, &format!("{}.weight", hf_name(name)))
+    }
+
+    fn experts(
+        &mut self,
+        layer: usize,
+        proj: ExpertProj,
+        count: usize,
+        shape: (usize, usize),
+    ) -> candle::Result<Vec<QMatMul>> {
+        (0..count)
+            .map(|e| {
+                let name = format!(
+                    "model.layers.{layer}.mlp.experts.{e}.{}_proj.weight",
+                    proj.as_str()
+                );
+                self.vb.get(shape, &name).map(QMatMul::Tensor)
+            })
+            .collect()
+    }
+
+    fn router_bias(&mut self, layer: usize, count: usize) -> candle::Result<Option<Tensor>> {
+        let name = format!("model.layers.{layer}.mlp.gate.e_score_correction_bias");
+        if !self.vb.contains_tensor(&name) {
+            return Ok(None);
+        }
+        // Смещение обучено в F32, в BF16 близкие оценки начинают совпадать
+        self.vb
+            .get_with_hints_dtype(count, &name, Default::default(), DType::F32)
+            .map(Some)
+    }
+}
+
+impl DeepSeek2Backend {
+    /// Создаёт бекенд из SafeTensors файлов
+    pub fn from_safetensors(
+        filenames: &[PathBuf],
+        config_path: &Path,
+        device: &Device,
+        dtype: DType,
+        rope_scaling: Option<RopeScaling>,
+    ) -> Result<Self, String> {
+        let config_data =
+            std::fs::read(config_path).map_err(|e| format!("Failed to read config.json: {}", e))?;
+        let config_json: serde_json::Value = serde_json::from_slice(&config_data)
+            .map_err(|e| format!("Failed to parse config.json: {}", e))?;
+        let cfg = Config::from_hf_json(&config_json, rope_scaling)?;
+
+        let vb = unsafe {
+            VarBuilder::from_mmaped_safetensors(filenames, dtype, device)
+                .map_err(|e| format!("Failed to load SafeTensors: {}", e))?
+        };
+
+        log::info!(
+            "Loading DeepSeek2 SafeTensors: dtype={:?}, layers={}, experts={}/{}, kv_lora_rank={}",
+            dtype,
+            cfg.num_hidden_layers,
+            cfg.routing.num_experts_per_tok,
+            cfg.n_routed_experts,
+            cfg.kv_lora_rank
+        );
+
+        let model = DeepSeek2::load(&mut SafetensorsWeights { vb }, &cfg, device)
+            .map_err(|e| format!("Failed to build DeepSeek2 model: {}", e))?;
+
+        Ok(Self::new(
+            model,
+            device.clone(),
+            cfg.vocab_size,
+            cfg.max_seq_len(),
+            OptimizationConfig::for_safetensors(dtype),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_gguf_names_to_hf() {
+        assert_eq!(hf_name("token_embd"), "model.embed_tokens");
+        assert_eq!(
+            hf_name("blk.3.attn_kv_a_mqa"),
+            "model.layers.3.self_attn.kv_a_proj_with_mqa"
+        );
+        assert_eq!(
+            hf_name("blk.12.ffn_down_shexp"),
+            "model.layers.12.mlp.shared_experts.down_proj"
+        );
+        assert_eq!(hf_name("blk.1.ffn_gate_inp"), "model.layers.1.mlp.gate");
+    }
+}
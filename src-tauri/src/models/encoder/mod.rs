@@ -0,0 +1,493 @@
+//! Энкодерные модели эмбеддингов и реранкеры (семейство BERT)
+//!
+//! В отличие от декодерных LLM, энкодер видит весь текст в обе стороны и
+//! обучен именно под поиск. Поддерживаются BERT (BGE, MiniLM, E5),
+//! Jina BERT v2, nomic-bert и XLM-RoBERTa (bge-m3); кросс-энкодеры
+//! `*ForSequenceClassification` (ms-marco, bge-reranker) оценивают пары
+//! запрос–документ.
+
+pub mod nomic_bert;
+
+use candle::{DType, Device, IndexOp, Module, Result, Tensor};
+use candle_nn::{Linear, VarBuilder, linear};
+use candle_transformers::models::{bert, jina_bert, xlm_roberta};
+use serde::{Deserialize, Serialize};
+
+use crate::models::common::embeddings::{EMBEDDING_BATCH_SIZE, pad_batch, pool};
+use crate::models::common::{EmbeddingOptions, Pooling};
+
+/// Веса энкодеров хранятся и считаются в F32
+pub const ENCODER_DTYPE: DType = DType::F32;
+
+/// Архитектура энкодера
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EncoderArch {
+    Bert,
+    JinaBert,
+    NomicBert,
+    XlmRoberta,
+}
+
+/// Назначение энкодерной модели
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EncoderTask {
+    /// Векторы текстов (`/v1/embeddings`)
+    Embedding,
+    /// Оценка пар запрос–документ (`/v1/rerank`)
+    Rerank,
+}
+
+/// Определяет архитектуру и назначение энкодера по config.json
+pub fn detect_encoder(config: &serde_json::Value) -> Option<(EncoderArch, EncoderTask)> {
+    let model_type = config.get("model_type")?.as_str()?.to_lowercase();
+    let architectures: Vec<&str> = config
+        .get("architectures")
+        .and_then(|v| v.as_array())
+        .map(|items| items.iter().filter_map(|v| v.as_str()).collect())
+        .unwrap_or_default();
+    let alibi = config
+        .get("position_embedding_type")
+        .and_then(|v| v.as_str())
+        == Some("alibi");
+
+    let arch = match model_type.as_str() {
+        "nomic_bert" => EncoderArch::NomicBert,
+        "xlm-roberta" | "xlm_roberta" => EncoderArch::XlmRoberta,
+        "bert" if alibi || architectures.iter().any(|a| a.starts_with("JinaBert")) => {
+            EncoderArch::JinaBert
+        }
+        "bert" => EncoderArch::Bert,
+        _ => return None,
+    };
+    let task = if architectures
+        .iter()
+        .any(|a| a.ends_with("ForSequenceClassification"))
+    {
+        EncoderTask::Rerank
+    } else {
+        EncoderTask::Embedding
+    };
+    // Jina и nomic загружаются только как энкодеры эмбеддингов
+    if task == EncoderTask::Rerank && !matches!(arch, EncoderArch::Bert | EncoderArch::XlmRoberta) {
+        return None;
+    }
+    Some((arch, task))
+}
+
+/// Пулинг и нормализация из sentence-transformers (`1_Pooling/config.json`, `modules.json`)
+///
+/// Без этих файлов BERT-подобные модели берут CLS, а Jina и nomic — среднее.
+pub fn default_embedding_options(
+    arch: EncoderArch,
+    pooling_config: Option<&serde_json::Value>,
+    modules: Option<&serde_json::Value>,
+) -> EmbeddingOptions {
+    let flag = |key: &str| {
+        pooling_config
+            .and_then(|cfg| cfg.get(key))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false)
+    };
+    let pooling = if flag("pooling_mode_cls_token") {
+        Pooling::Cls
+    } else if flag("pooling_mode_lasttoken") {
+        Pooling::LastToken
+    } else if flag("pooling_mode_mean_tokens") {
+        Pooling::Mean
+    } else {
+        match arch {
+            EncoderArch::Bert | EncoderArch::XlmRoberta => Pooling::Cls,
+            EncoderArch::JinaBert | EncoderArch::NomicBert => Pooling::Mean,
+        }
+    };
+    let normalize = modules.and_then(|m| m.as_array()).is_some_and(|items| {
+        items.iter().any(|item| {
+            item.get("type")
+                .and_then(|t| t.as_str())
+                .is_some_and(|t| t.ends_with("Normalize"))
+        })
+    });
+    EmbeddingOptions {
+        pooling,
+        normalize,
+        dimensions: None,
+    }
+}
+
+enum EncoderNet {
+    Bert(bert::BertModel),
+    JinaBert(jina_bert::BertModel),
+    NomicBert(nomic_bert::NomicBertModel),
+    XlmRoberta(xlm_roberta::XLMRobertaModel),
+}
+
+/// Голова BertForSequenceClassification: tanh(pooler(CLS)) → classifier
+struct BertClassifier {
+    model: bert::BertModel,
+    pooler: Linear,
+    classifier: Linear,
+}
+
+enum CrossEncoderNet {
+    Bert(BertClassifier),
+    XlmRoberta(xlm_roberta::XLMRobertaForSequenceClassification),
+}
+
+enum Net {
+    Encoder(EncoderNet),
+    CrossEncoder(CrossEncoderNet),
+}
+
+/// Загруженная энкодерная модель
+pub struct EncoderModel {
+    net: Net,
+    arch: EncoderArch,
+    hidden_size: usize,
+    max_length: usize,
+    pad_id: u32,
+    num_labels: usize,
+    device: Device,
+}
+
+fn parse<T: serde::de::DeserializeOwned>(config: &serde_json::Value) -> Result<T> {
+    serde_json::from_value(config.clone()).map_err(|e| candle::Error::Msg(e.to_string()))
+}
+
+/// Префикс, под которым в чекпоинте лежат веса базовой модели
+fn base_prefix<'a>(vb: &VarBuilder<'a>, prefix: &str) -> VarBuilder<'a> {
+    if vb.contains_tensor("embeddings.word_embeddings.weight") {
+        vb.clone()
+    } else {
+        vb.pp(prefix)
+    }
+}
+
+impl EncoderModel {
+    /// Собирает модель по config.json; архитектура и назначение — из [`detect_encoder`]
+    pub fn load(config: &serde_json::Value, vb: VarBuilder) -> Result<Self> {
+        let Some((arch, task)) = detect_encoder(config) else {
+            candle::bail!("config.json does not describe a supported encoder model");
+        };
+        let device = vb.device().clone();
+        let num_labels = config
+            .get("id2label")
+            .and_then(|v| v.as_object())
+            .map(|labels| labels.len())
+            .or_else(|| {
+                config
+                    .get("num_labels")
+                    .and_then(|v| v.as_u64())
+                    .map(|n| n as usize)
+            })
+            .unwrap_or(1)
+            .max(1);
+        let pad_id = config
+            .get("pad_token_id")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0) as u32;
+
+        let (net, hidden_size, max_length) = match (arch, task) {
+            (EncoderArch::Bert, task) => {
+                let cfg: bert::Config = parse(config)?;
+                let base = base_prefix(&vb, "bert");
+                let model = bert::BertModel::load(base.clone(), &cfg)?;
+                let net = match task {
+                    EncoderTask::Embedding => Net::Encoder(EncoderNet::Bert(model)),
+                    EncoderTask::Rerank => {
+                        Net::CrossEncoder(CrossEncoderNet::Bert(BertClassifier {
+                            model,
+                            pooler: linear(
+                                cfg.hidden_size,
+                                cfg.hidden_size,
+                                base.pp("pooler.dense"),
+                            )?,
+                            classifier: linear(cfg.hidden_size, num_labels, vb.pp("classifier"))?,
+                        }))
+                    }
+                };
+                (net, cfg.hidden_size, cfg.max_position_embeddings)
+            }
+            (EncoderArch::XlmRoberta, task) => {
+                let cfg: xlm_roberta::Config = parse(config)?;
+                let net = match task {
+                    EncoderTask::Embedding => Net::Encoder(EncoderNet::XlmRoberta(
+                        xlm_roberta::XLMRobertaModel::new(&cfg, base_prefix(&vb, "roberta"))?,
+                    )),
+                    EncoderTask::Rerank => Net::CrossEncoder(CrossEncoderNet::XlmRoberta(
+                        xlm_roberta::XLMRobertaForSequenceClassification::new(
+                            num_labels, &cfg, vb,
+                        )?,
+                    )),
+                };
+                // Позиции RoBERTa начинаются после pad_token_id
+                let max_length = cfg
+                    .max_position_embeddings
+                    .saturating_sub(cfg.pad_token_id as usize + 1);
+                (net, cfg.hidden_size, max_length)
+            }
+            (EncoderArch::JinaBert, _) => {
+                let cfg: jina_bert::Config = parse(config)?;
+                let model = jina_bert::BertModel::new(base_prefix(&vb, "bert"), &cfg)?;
+                (
+                    Net::Encoder(EncoderNet::JinaBert(model)),
+                    cfg.hidden_size,
+                    cfg.max_position_embeddings,
+                )
+            }
+            (EncoderArch::NomicBert, _) => {
+                let cfg: nomic_bert::Config = parse(config)?;
+                let model = nomic_bert::NomicBertModel::new(&cfg, vb)?;
+                (
+                    Net::Encoder(EncoderNet::NomicBert(model)),
+                    cfg.n_embd,
+                    cfg.max_length(),
+                )
+            }
+        };
+        Ok(Self {
+            net,
+            arch,
+            hidden_size,
+            max_length,
+            pad_id,
+            num_labels,
+            device,
+        })
+    }
+
+    pub fn arch(&self) -> EncoderArch {
+        self.arch
+    }
+
+    pub fn task(&self) -> EncoderTask {
+        match self.net {
+            Net::Encoder(_) => EncoderTask::Embedding,
+            Net::CrossEncoder(_) => EncoderTask::Rerank,
+        }
+    }
+
+    pub fn hidden_size(&self) -> usize {
+        self.hidden_size
+    }
+
+    /// Наибольшая длина входа в токенах
+    pub fn max_length(&self) -> usize {
+        self.max_length
+    }
+
+    /// Jina BERT не принимает маску внимания, поэтому её тексты идут по одному
+    fn batch_size(&self) -> usize {
+        match self.net {
+            Net::Encoder(EncoderNet::JinaBert(_)) => 1,
+            _ => EMBEDDING_BATCH_SIZE,
+        }
+    }
+
+    /// Дополненный батч: (input_ids, token_type_ids, attention_mask)
+    fn pad_inputs(&self, batch: &[(&[u32], &[u32])]) -> Result<(Tensor, Tensor, Tensor)> {
+        let ids: Vec<Vec<u32>> = batch.iter().map(|(ids, _)| ids.to_vec()).collect();
+        let type_ids: Vec<Vec<u32>> = batch.iter().map(|(_, types)| types.to_vec()).collect();
+        let mask: Vec<Vec<u32>> = ids.iter().map(|ids| vec![1; ids.len()]).collect();
+        Ok((
+            pad_batch(&ids, self.pad_id, &self.device)?,
+            pad_batch(&type_ids, 0, &self.device)?,
+            pad_batch(&mask, 0, &self.device)?.to_dtype(ENCODER_DTYPE)?,
+        ))
+    }
+
+    fn hidden_states(
+        &self,
+        net: &EncoderNet,
+        input_ids: &Tensor,
+        token_type_ids: &Tensor,
+        mask: &Tensor,
+    ) -> Result<Tensor> {
+        match net {
+            EncoderNet::Bert(model) => model.forward(input_ids, token_type_ids, Some(mask)),
+            EncoderNet::JinaBert(model) => model.forward(input_ids),
+            EncoderNet::NomicBert(model) => model.forward(input_ids, token_type_ids, Some(mask)),
+            EncoderNet::XlmRoberta(model) => {
+                model.forward(input_ids, mask, token_type_ids, None, None, None)
+            }
+        }
+    }
+
+    /// Эмбеддинги токенизированных текстов
+    pub fn embed(&self, texts: &[Vec<u32>], options: &EmbeddingOptions) -> Result<Vec<Vec<f32>>> {
+        let Net::Encoder(net) = &self.net else {
+            candle::bail!("Reranker models cannot produce embeddings");
+        };
+        if texts.iter().any(Vec::is_empty) {
+            candle::bail!("Cannot embed an empty input");
+        }
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for chunk in texts.chunks(self.batch_size()) {
+            let zeros: Vec<Vec<u32>> = chunk.iter().map(|ids| vec![0; ids.len()]).collect();
+            let batch: Vec<(&[u32], &[u32])> = chunk
+                .iter()
+                .zip(&zeros)
+                .map(|(ids, types)| (ids.as_slice(), types.as_slice()))
+                .collect();
+            let (input_ids, token_type_ids, mask) = self.pad_inputs(&batch)?;
+            let hidden = self.hidden_states(net, &input_ids, &token_type_ids, &mask)?;
+            let lengths: Vec<usize> = chunk.iter().map(Vec::len).collect();
+            embeddings.extend(pool(&hidden, &lengths, options)?);
+        }
+        Ok(embeddings)
+    }
+
+    /// Оценки релевантности пар (ids, type_ids) запрос–документ в диапазоне 0..1
+    ///
+    /// Одна метка — сигмоида логита, несколько — вероятность последней (положительной).
+    pub fn score(&self, pairs: &[(Vec<u32>, Vec<u32>)]) -> Result<Vec<f32>> {
+        let Net::CrossEncoder(net) = &self.net else {
+            candle::bail!("Embedding models cannot score query-document pairs");
+        };
+        let mut scores = Vec::with_capacity(pairs.len());
+        for chunk in pairs.chunks(EMBEDDING_BATCH_SIZE) {
+            let batch: Vec<(&[u32], &[u32])> = chunk
+                .iter()
+                .map(|(ids, types)| (ids.as_slice(), types.as_slice()))
+                .collect();
+            let (input_ids, token_type_ids, mask) = self.pad_inputs(&batch)?;
+            let logits = match net {
+                CrossEncoderNet::Bert(head) => {
+                    let hidden = head
+                        .model
+                        .forward(&input_ids, &token_type_ids, Some(&mask))?;
+                    let pooled = head.pooler.forward(&hidden.i((.., 0))?)?.tanh()?;
+                    head.classifier.forward(&pooled)?
+                }
+                CrossEncoderNet::XlmRoberta(model) => {
+                    model.forward(&input_ids, &mask, &token_type_ids)?
+                }
+            };
+            let logits = logits.to_dtype(DType::F32)?;
+            let relevance = if self.num_labels == 1 {
+                candle_nn::ops::sigmoid(&logits.squeeze(1)?)?
+            } else {
+                candle_nn::ops::softmax_last_dim(&logits)?.i((.., self.num_labels - 1))?
+            };
+            scores.extend(relevance.to_vec1::<f32>()?);
+        }
+        Ok(scores)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_nn::VarMap;
+    use serde_json::json;
+
+    fn tiny_bert(architectures: &[&str]) -> serde_json::Value {
+        json!({
+            "architectures": architectures,
+            "model_type": "bert",
+            "vocab_size": 32,
+            "hidden_size": 16,
+            "num_hidden_layers": 2,
+            "num_attention_heads": 2,
+            "intermediate_size": 24,
+            "hidden_act": "gelu",
+            "hidden_dropout_prob": 0.0,
+            "max_position_embeddings": 64,
+            "type_vocab_size": 2,
+            "initializer_range": 0.02,
+            "layer_norm_eps": 1e-12,
+            "pad_token_id": 0
+        })
+    }
+
+    #[test]
+    fn detects_encoder_families() {
+        let detect = |cfg: serde_json::Value| detect_encoder(&cfg);
+        assert_eq!(
+            detect(tiny_bert(&["BertModel"])),
+            Some((EncoderArch::Bert, EncoderTask::Embedding))
+        );
+        assert_eq!(
+            detect(tiny_bert(&["BertForSequenceClassification"])),
+            Some((EncoderArch::Bert, EncoderTask::Rerank))
+        );
+        assert_eq!(
+            detect(json!({"model_type": "bert", "position_embedding_type": "alibi"})),
+            Some((EncoderArch::JinaBert, EncoderTask::Embedding))
+        );
+        assert_eq!(
+            detect(json!({"model_type": "nomic_bert"})),
+            Some((EncoderArch::NomicBert, EncoderTask::Embedding))
+        );
+        assert_eq!(
+            detect(json!({
+                "model_type": "xlm-roberta",
+                "architectures": ["XLMRobertaForSequenceClassification"]
+            })),
+            Some((EncoderArch::XlmRoberta, EncoderTask::Rerank))
+        );
+        assert_eq!(detect(json!({"model_type": "qwen2"})), None);
+
+        let pooling = json!({"pooling_mode_mean_tokens": true});
+        let modules = json!([{"type": "sentence_transformers.models.Normalize"}]);
+        let opts = default_embedding_options(EncoderArch::Bert, Some(&pooling), Some(&modules));
+        assert_eq!((opts.pooling, opts.normalize), (Pooling::Mean, true));
+        let opts = default_embedding_options(EncoderArch::Bert, None, None);
+        assert_eq!((opts.pooling, opts.normalize), (Pooling::Cls, false));
+    }
+
+    #[test]
+    fn batched_bert_matches_single_inputs() -> Result<()> {
+        let dev = Device::Cpu;
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, ENCODER_DTYPE, &dev);
+        let model = EncoderModel::load(&tiny_bert(&["BertModel"]), vb)?;
+        assert_eq!(model.task(), EncoderTask::Embedding);
+
+        let texts: Vec<Vec<u32>> = vec![vec![2, 5, 9, 3], vec![2, 7, 3], vec![2, 3]];
+        let opts = EmbeddingOptions {
+            pooling: Pooling::Mean,
+            normalize: true,
+            dimensions: Some(8),
+        };
+        let batched = model.embed(&texts, &opts)?;
+        for (text, embedding) in texts.iter().zip(&batched) {
+            assert_eq!(embedding.len(), 8);
+            let single = model.embed(std::slice::from_ref(text), &opts)?;
+            let diff = single[0]
+                .iter()
+                .zip(embedding)
+                .map(|(a, b)| (a - b).abs())
+                .fold(0f32, f32::max);
+            assert!(diff < 1e-4, "max diff {diff}");
+        }
+        assert!(model.score(&[(vec![2, 3], vec![0, 0])]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn bert_cross_encoder_scores_pairs() -> Result<()> {
+        let dev = Device::Cpu;
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, ENCODER_DTYPE, &dev);
+        let model = EncoderModel::load(&tiny_bert(&["BertForSequenceClassification"]), vb)?;
+        assert_eq!(model.task(), EncoderTask::Rerank);
+
+        let pairs = vec![
+            (vec![2, 5, 3, 9, 3], vec![0, 0, 0, 1, 1]),
+            (vec![2, 5, 3, 7, 8, 3], vec![0, 0, 0, 1, 1, 1]),
+        ];
+        let scores = model.score(&pairs)?;
+        assert_eq!(scores.len(), 2);
+        assert!(scores.iter().all(|s| (0.0..=1.0).contains(s)));
+        let single = model.score(&pairs[..1])?;
+        assert!((single[0] - scores[0]).abs() < 1e-5);
+        assert!(
+            model
+                .embed(&[vec![2, 3]], &EmbeddingOptions::default())
+                .is_err()
+        );
+        Ok(())
+    }
+}
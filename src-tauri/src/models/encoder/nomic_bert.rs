@@ -0,0 +1,346 @@
+//! Nomic BERT encoder (nomic-embed-text v1 / v1.5)
+//!
+//! candle-transformers has no nomic-bert port, so this follows the reference
+//! `NomicBertModel`: rotary position embeddings instead of learned positions,
+//! a fused QKV projection, a SwiGLU MLP and post-norm residual blocks.
+
+use candle::{D, DType, Device, Module, Result, Tensor};
+use candle_nn::{Embedding, LayerNorm, Linear, VarBuilder, embedding, layer_norm, linear_b};
+use serde::Deserialize;
+
+fn default_rotary_fraction() -> f64 {
+    1.0
+}
+
+fn default_rotary_base() -> f64 {
+    10_000.0
+}
+
+fn default_activation() -> String {
+    "swiglu".to_string()
+}
+
+/// Subset of the `nomic_bert` config.json used for inference
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub vocab_size: usize,
+    pub n_embd: usize,
+    pub n_head: usize,
+    pub n_layer: usize,
+    pub n_inner: Option<usize>,
+    pub n_positions: usize,
+    #[serde(default)]
+    pub max_trained_positions: Option<usize>,
+    #[serde(default)]
+    pub type_vocab_size: usize,
+    pub layer_norm_epsilon: f64,
+    #[serde(default = "default_rotary_base")]
+    pub rotary_emb_base: f64,
+    #[serde(default = "default_rotary_fraction")]
+    pub rotary_emb_fraction: f64,
+    #[serde(default)]
+    pub rotary_emb_interleaved: bool,
+    #[serde(default)]
+    pub qkv_proj_bias: bool,
+    #[serde(default)]
+    pub mlp_fc1_bias: bool,
+    #[serde(default)]
+    pub mlp_fc2_bias: bool,
+    #[serde(default)]
+    pub prenorm: bool,
+    #[serde(default = "default_activation")]
+    pub activation_function: String,
+    #[serde(default)]
+    pub moe_every_n_layers: usize,
+}
+
+impl Config {
+    fn head_dim(&self) -> usize {
+        self.n_embd / self.n_head
+    }
+
+    fn intermediate_size(&self) -> usize {
+        self.n_inner.unwrap_or(4 * self.n_embd)
+    }
+
+    /// Longest sequence the model was trained on
+    pub fn max_length(&self) -> usize {
+        self.max_trained_positions.unwrap_or(self.n_positions)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.prenorm {
+            candle::bail!("Pre-norm nomic-bert checkpoints are not supported");
+        }
+        if self.activation_function != "swiglu" {
+            candle::bail!(
+                "Unsupported nomic-bert activation '{}'",
+                self.activation_function
+            );
+        }
+        if self.moe_every_n_layers > 0 {
+            candle::bail!("Mixture-of-experts nomic-bert checkpoints are not supported");
+        }
+        if !self.n_embd.is_multiple_of(self.n_head) {
+            candle::bail!(
+                "n_embd {} is not divisible by n_head {}",
+                self.n_embd,
+                self.n_head
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RotaryEmbedding {
+    sin: Tensor,
+    cos: Tensor,
+    dim: usize,
+    interleaved: bool,
+}
+
+impl RotaryEmbedding {
+    fn new(cfg: &Config, dtype: DType, dev: &Device) -> Result<Self> {
+        let dim = ((cfg.head_dim() as f64 * cfg.rotary_emb_fraction) as usize) & !1;
+        let inv_freq: Vec<f32> = (0..dim)
+            .step_by(2)
+            .map(|i| 1f32 / cfg.rotary_emb_base.powf(i as f64 / dim as f64) as f32)
+            .collect();
+        let inv_freq_len = inv_freq.len();
+        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?;
+        let t = Tensor::arange(0u32, cfg.n_positions as u32, dev)?
+            .to_dtype(DType::F32)?
+            .reshape((cfg.n_positions, 1))?;
+        let freqs = t.matmul(&inv_freq)?;
+        Ok(Self {
+            sin: freqs.sin()?.to_dtype(dtype)?,
+            cos: freqs.cos()?.to_dtype(dtype)?,
+            dim,
+            interleaved: cfg.rotary_emb_interleaved,
+        })
+    }
+
+    /// Rotates the first `dim` channels of [batch, heads, seq_len, head_dim]
+    fn apply(&self, x: &Tensor) -> Result<Tensor> {
+        let (_b, _h, seq_len, head_dim) = x.dims4()?;
+        let cos = self.cos.narrow(0, 0, seq_len)?;
+        let sin = self.sin.narrow(0, 0, seq_len)?;
+        let rope = |x: &Tensor| {
+            if self.interleaved {
+                candle_nn::rotary_emb::rope_i(&x.contiguous()?, &cos, &sin)
+            } else {
+                candle_nn::rotary_emb::rope(&x.contiguous()?, &cos, &sin)
+            }
+        };
+        if self.dim == head_dim {
+            return rope(x);
+        }
+        let rotated = rope(&x.narrow(D::Minus1, 0, self.dim)?)?;
+        let pass = x.narrow(D::Minus1, self.dim, head_dim - self.dim)?;
+        Tensor::cat(&[&rotated, &pass], D::Minus1)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Attention {
+    wqkv: Linear,
+    out_proj: Linear,
+    num_heads: usize,
+    head_dim: usize,
+}
+
+impl Attention {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let hidden = cfg.n_embd;
+        Ok(Self {
+            wqkv: linear_b(hidden, 3 * hidden, cfg.qkv_proj_bias, vb.pp("Wqkv"))?,
+            out_proj: linear_b(hidden, hidden, cfg.qkv_proj_bias, vb.pp("out_proj"))?,
+            num_heads: cfg.n_head,
+            head_dim: cfg.head_dim(),
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, mask: &Tensor, rotary: &RotaryEmbedding) -> Result<Tensor> {
+        let (b_sz, seq_len, hidden) = xs.dims3()?;
+        let qkv =
+            self.wqkv
+                .forward(xs)?
+                .reshape((b_sz, seq_len, 3, self.num_heads, self.head_dim))?;
+        let heads = |i: usize| qkv.narrow(2, i, 1)?.squeeze(2)?.transpose(1, 2);
+        let q = rotary.apply(&heads(0)?)?.contiguous()?;
+        let k = rotary.apply(&heads(1)?)?.contiguous()?;
+        let v = heads(2)?.contiguous()?;
+
+        let scale = 1f64 / (self.head_dim as f64).sqrt();
+        let scores = (q.matmul(&k.t()?)? * scale)?.broadcast_add(mask)?;
+        let probs = candle_nn::ops::softmax_last_dim(&scores)?;
+        let out = probs
+            .matmul(&v)?
+            .transpose(1, 2)?
+            .reshape((b_sz, seq_len, hidden))?;
+        self.out_proj.forward(&out)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct GatedMlp {
+    fc11: Linear,
+    fc12: Linear,
+    fc2: Linear,
+}
+
+impl GatedMlp {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let (hidden, inner) = (cfg.n_embd, cfg.intermediate_size());
+        Ok(Self {
+            fc11: linear_b(hidden, inner, cfg.mlp_fc1_bias, vb.pp("fc11"))?,
+            fc12: linear_b(hidden, inner, cfg.mlp_fc1_bias, vb.pp("fc12"))?,
+            fc2: linear_b(inner, hidden, cfg.mlp_fc2_bias, vb.pp("fc2"))?,
+        })
+    }
+}
+
+impl Module for GatedMlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let up = self.fc11.forward(xs)?;
+        let gate = self.fc12.forward(xs)?.silu()?;
+        self.fc2.forward(&(up * gate)?)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Block {
+    attn: Attention,
+    mlp: GatedMlp,
+    norm1: LayerNorm,
+    norm2: LayerNorm,
+}
+
+impl Block {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            attn: Attention::new(cfg, vb.pp("attn"))?,
+            mlp: GatedMlp::new(cfg, vb.pp("mlp"))?,
+            norm1: layer_norm(cfg.n_embd, cfg.layer_norm_epsilon, vb.pp("norm1"))?,
+            norm2: layer_norm(cfg.n_embd, cfg.layer_norm_epsilon, vb.pp("norm2"))?,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, mask: &Tensor, rotary: &RotaryEmbedding) -> Result<Tensor> {
+        let attn = self.attn.forward(xs, mask, rotary)?;
+        let xs = self.norm1.forward(&(attn + xs)?)?;
+        let mlp = self.mlp.forward(&xs)?;
+        self.norm2.forward(&(mlp + xs)?)
+    }
+}
+
+/// Nomic BERT encoder returning the last hidden states
+#[derive(Debug, Clone)]
+pub struct NomicBertModel {
+    word_embeddings: Embedding,
+    token_type_embeddings: Option<Embedding>,
+    emb_ln: LayerNorm,
+    layers: Vec<Block>,
+    rotary: RotaryEmbedding,
+    pub device: Device,
+}
+
+impl NomicBertModel {
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        cfg.validate()?;
+        let vb_emb = vb.pp("embeddings");
+        let word_embeddings = embedding(cfg.vocab_size, cfg.n_embd, vb_emb.pp("word_embeddings"))?;
+        let token_type_embeddings = if cfg.type_vocab_size > 0 {
+            Some(embedding(
+                cfg.type_vocab_size,
+                cfg.n_embd,
+                vb_emb.pp("token_type_embeddings"),
+            )?)
+        } else {
+            None
+        };
+        let emb_ln = layer_norm(cfg.n_embd, cfg.layer_norm_epsilon, vb.pp("emb_ln"))?;
+        let vb_layers = vb.pp("encoder").pp("layers");
+        let layers = (0..cfg.n_layer)
+            .map(|i| Block::new(cfg, vb_layers.pp(i)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            word_embeddings,
+            token_type_embeddings,
+            emb_ln,
+            layers,
+            rotary: RotaryEmbedding::new(cfg, vb.dtype(), vb.device())?,
+            device: vb.device().clone(),
+        })
+    }
+
+    /// `attention_mask` is [batch, seq_len] with 1 for real tokens and 0 for padding
+    pub fn forward(
+        &self,
+        input_ids: &Tensor,
+        token_type_ids: &Tensor,
+        attention_mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len) = input_ids.dims2()?;
+        let mut xs = self.word_embeddings.forward(input_ids)?;
+        if let Some(token_types) = &self.token_type_embeddings {
+            xs = (xs + token_types.forward(token_type_ids)?)?;
+        }
+        let mut xs = self.emb_ln.forward(&xs)?;
+
+        let dtype = xs.dtype();
+        let mask = match attention_mask {
+            Some(mask) => {
+                let mask = mask.to_dtype(DType::F32)?;
+                ((mask.ones_like()? - mask)? * f32::MIN as f64)?
+                    .reshape((b_sz, 1, 1, seq_len))?
+                    .to_dtype(dtype)?
+            }
+            None => Tensor::zeros((b_sz, 1, 1, seq_len), dtype, &self.device)?,
+        };
+        for layer in &self.layers {
+            xs = layer.forward(&xs, &mask, &self.rotary)?;
+        }
+        Ok(xs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_nn::VarMap;
+
+    #[test]
+    fn padding_does_not_change_real_tokens() -> Result<()> {
+        let dev = Device::Cpu;
+        let cfg: Config = serde_json::from_value(serde_json::json!({
+            "vocab_size": 32,
+            "n_embd": 16,
+            "n_head": 2,
+            "n_layer": 2,
+            "n_inner": 24,
+            "n_positions": 64,
+            "type_vocab_size": 2,
+            "layer_norm_epsilon": 1e-12,
+            "rotary_emb_base": 1000,
+            "rotary_emb_fraction": 0.5
+        }))
+        .unwrap();
+        let varmap = VarMap::new();
+        let model = NomicBertModel::new(&cfg, VarBuilder::from_varmap(&varmap, DType::F32, &dev))?;
+
+        let single = Tensor::new(&[[3u32, 7, 11]], &dev)?;
+        let alone = model.forward(&single, &single.zeros_like()?, None)?;
+
+        let padded = Tensor::new(&[[3u32, 7, 11, 0, 0], [5, 6, 8, 9, 10]], &dev)?;
+        let mask = Tensor::new(&[[1u32, 1, 1, 0, 0], [1, 1, 1, 1, 1]], &dev)?;
+        let batched = model.forward(&padded, &padded.zeros_like()?, Some(&mask))?;
+        let diff = (batched.get(0)?.narrow(0, 0, 3)? - alone.get(0)?)?
+            .abs()?
+            .max_all()?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-4, "max diff {diff}");
+        Ok(())
+    }
+}
@@ -0,0 +1,439 @@
+//! Gemma / Gemma 2 / Gemma 3 configuration
+//!
+//! Общая конфигурация для обоих форматов: читается из `config.json`
+//! (HF, в т.ч. вложенного `text_config` у мультимодальной Gemma 3)
+//! или из метаданных GGUF (`gemma.*`, `gemma2.*`, `gemma3.*`).
+
+use std::collections::HashMap;
+
+use candle::quantized::gguf_file::Value;
+use serde::Deserialize;
+
+use crate::models::common::{RopeScaling, RopeScalingKind, RopeShift};
+
+/// Поколение Gemma: определяет структуру слоя и схему чередования окон внимания
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GemmaVariant {
+    /// Gemma 1: pre-norm слоя, глобальное внимание во всех слоях
+    Gemma,
+    /// Gemma 2: post-norm, soft-capping внимания и логитов, каждый чётный слой — скользящее окно
+    Gemma2,
+    /// Gemma 3: q/k-нормализация, 5 локальных слоёв на 1 глобальный, отдельная база RoPE
+    Gemma3,
+}
+
+impl GemmaVariant {
+    /// Префикс ключей GGUF (`general.architecture`)
+    pub fn gguf_arch(self) -> &'static str {
+        match self {
+            Self::Gemma => "gemma",
+            Self::Gemma2 => "gemma2",
+            Self::Gemma3 => "gemma3",
+        }
+    }
+
+    fn from_gguf_arch(arch: &str) -> Option<Self> {
+        match arch {
+            "gemma" => Some(Self::Gemma),
+            "gemma2" => Some(Self::Gemma2),
+            "gemma3" | "gemma3_text" => Some(Self::Gemma3),
+            _ => None,
+        }
+    }
+
+    /// Есть ли в слое post-norm после attention и MLP
+    pub fn has_post_norms(self) -> bool {
+        !matches!(self, Self::Gemma)
+    }
+
+    /// Тип модели для `ModelBackend::model_type`
+    pub fn model_type(self, quantized: bool) -> &'static str {
+        match (self, quantized) {
+            (Self::Gemma, false) => "gemma",
+            (Self::Gemma, true) => "gemma-gguf",
+            (Self::Gemma2, false) => "gemma2",
+            (Self::Gemma2, true) => "gemma2-gguf",
+            (Self::Gemma3, false) => "gemma3",
+            (Self::Gemma3, true) => "gemma3-gguf",
+        }
+    }
+}
+
+/// Gemma 3 по умолчанию: 5 слоёв со скользящим окном, затем 1 глобальный
+const DEFAULT_SLIDING_WINDOW_PATTERN: usize = 6;
+const DEFAULT_ROPE_LOCAL_BASE_FREQ: f64 = 10_000.;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub variant: GemmaVariant,
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: usize,
+    pub head_dim: usize,
+    pub rms_norm_eps: f64,
+    /// База RoPE глобальных слоёв
+    pub rope_theta: f64,
+    /// База RoPE слоёв со скользящим окном (Gemma 3)
+    pub rope_local_base_freq: f64,
+    /// Масштабирование RoPE глобальных слоёв
+    pub rope_scaling: Option<RopeScaling>,
+    /// Скор внимания умножается на `query_pre_attn_scalar^-0.5`
+    pub query_pre_attn_scalar: f64,
+    pub attn_logit_softcapping: Option<f64>,
+    pub final_logit_softcapping: Option<f64>,
+    pub sliding_window: Option<usize>,
+    /// Слои со скользящим окном внимания
+    pub sliding_layers: Vec<bool>,
+    pub max_position_embeddings: usize,
+}
+
+/// Поля `config.json` (Gemma, Gemma 2, Gemma 3 text)
+#[derive(Debug, Deserialize)]
+struct HfConfig {
+    model_type: Option<String>,
+    vocab_size: usize,
+    hidden_size: usize,
+    intermediate_size: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    num_key_value_heads: Option<usize>,
+    head_dim: Option<usize>,
+    rms_norm_eps: Option<f64>,
+    rope_theta: Option<f64>,
+    rope_local_base_freq: Option<f64>,
+    query_pre_attn_scalar: Option<f64>,
+    attn_logit_softcapping: Option<f64>,
+    final_logit_softcapping: Option<f64>,
+    sliding_window: Option<usize>,
+    sliding_window_pattern: Option<usize>,
+    layer_types: Option<Vec<String>>,
+    max_position_embeddings: Option<usize>,
+}
+
+impl Config {
+    /// Разбирает `config.json`; `rope_scaling` — override из запроса загрузки
+    pub fn from_hf_json(
+        config: &serde_json::Value,
+        rope_scaling: Option<RopeScaling>,
+    ) -> Result<Self, String> {
+        // Мультимодальная Gemma 3 хранит параметры языковой модели в `text_config`
+        let text = config.get("text_config").unwrap_or(config);
+        let hf: HfConfig = serde_json::from_value(text.clone())
+            .map_err(|e| format!("Failed to parse Gemma config.json: {}", e))?;
+        let model_type = hf
+            .model_type
+            .as_deref()
+            .or_else(|| config.get("model_type").and_then(|v| v.as_str()))
+            .unwrap_or("gemma");
+        let variant = if model_type.starts_with("gemma3") {
+            GemmaVariant::Gemma3
+        } else if model_type.starts_with("gemma2") {
+            GemmaVariant::Gemma2
+        } else {
+            GemmaVariant::Gemma
+        };
+
+        let head_dim = hf
+            .head_dim
+            .unwrap_or(hf.hidden_size / hf.num_attention_heads);
+        let max_position_embeddings = hf.max_position_embeddings.unwrap_or(8192);
+        let sliding_layers = match &hf.layer_types {
+            Some(types) => types.iter().map(|t| t == "sliding_attention").collect(),
+            None => sliding_pattern(
+                variant,
+                hf.num_hidden_layers,
+                hf.sliding_window_pattern
+                    .unwrap_or(DEFAULT_SLIDING_WINDOW_PATTERN),
+            ),
+        };
+        let (attn_softcap, final_softcap) = match variant {
+            // Gemma 2 без явных значений использует 50 / 30
+            GemmaVariant::Gemma2 => (
+                hf.attn_logit_softcapping.or(Some(50.)),
+                hf.final_logit_softcapping.or(Some(30.)),
+            ),
+            _ => (hf.attn_logit_softcapping, hf.final_logit_softcapping),
+        };
+
+        let cfg = Self {
+            variant,
+            vocab_size: hf.vocab_size,
+            hidden_size: hf.hidden_size,
+            intermediate_size: hf.intermediate_size,
+            num_hidden_layers: hf.num_hidden_layers,
+            num_attention_heads: hf.num_attention_heads,
+            num_key_value_heads: hf.num_key_value_heads.unwrap_or(hf.num_attention_heads),
+            head_dim,
+            rms_norm_eps: hf.rms_norm_eps.unwrap_or(1e-6),
+            rope_theta: hf.rope_theta.unwrap_or(match variant {
+                GemmaVariant::Gemma3 => 1_000_000.,
+                _ => 10_000.,
+            }),
+            rope_local_base_freq: hf
+                .rope_local_base_freq
+                .unwrap_or(DEFAULT_ROPE_LOCAL_BASE_FREQ),
+            rope_scaling: match rope_scaling {
+                Some(scaling) => Some(scaling.resolve(max_position_embeddings)),
+                None => native_rope_scaling(
+                    variant,
+                    RopeScaling::from_config_json(text),
+                    max_position_embeddings,
+                ),
+            },
+            query_pre_attn_scalar: hf.query_pre_attn_scalar.unwrap_or(head_dim as f64),
+            attn_logit_softcapping: attn_softcap,
+            final_logit_softcapping: final_softcap,
+            sliding_window: hf.sliding_window,
+            sliding_layers,
+            max_position_embeddings,
+        };
+        cfg.validate()?;
+        Ok(cfg)
+    }
+
+    /// Читает конфигурацию из метаданных GGUF
+    pub fn from_gguf(metadata: &HashMap<String, Value>) -> candle::Result<Self> {
+        let arch = metadata
+            .get("general.architecture")
+            .and_then(|v| v.to_string().ok())
+            .map(String::as_str)
+            .unwrap_or("gemma");
+        let variant = GemmaVariant::from_gguf_arch(arch)
+            .ok_or_else(|| candle::Error::Msg(format!("Unsupported Gemma architecture: {arch}")))?;
+        let prefix = variant.gguf_arch();
+        let get = |key: &str| metadata.get(&format!("{prefix}.{key}"));
+        let get_u32 = |key: &str| -> candle::Result<usize> {
+            get(key)
+                .ok_or_else(|| {
+                    candle::Error::Msg(format!("cannot find {prefix}.{key} in metadata"))
+                })?
+                .to_u32()
+                .map(|v| v as usize)
+        };
+        let get_f64 = |key: &str| get(key).and_then(|v| v.to_f32().ok()).map(f64::from);
+
+        let num_attention_heads = get_u32("attention.head_count")?;
+        let num_key_value_heads = get_u32("attention.head_count_kv")?;
+        let num_hidden_layers = get_u32("block_count")?;
+        let hidden_size = get_u32("embedding_length")?;
+        let head_dim = get_u32("attention.key_length").unwrap_or(hidden_size / num_attention_heads);
+        let max_position_embeddings = get_u32("context_length").unwrap_or(8192);
+        let pattern =
+            get_u32("attention.sliding_window_type").unwrap_or(DEFAULT_SLIDING_WINDOW_PATTERN);
+        let vocab_size = metadata
+            .get("tokenizer.ggml.tokens")
+            .and_then(|v| v.to_vec().ok())
+            .map(|tokens| tokens.len())
+            .unwrap_or(256000);
+
+        // Как в llama.cpp: 27B модели масштабируют запросы по n_embd / n_head
+        let is_27b = matches!(
+            (variant, num_hidden_layers),
+            (GemmaVariant::Gemma2, 46) | (GemmaVariant::Gemma3, 62)
+        );
+        let query_pre_attn_scalar = if is_27b {
+            (hidden_size / num_attention_heads) as f64
+        } else {
+            head_dim as f64
+        };
+        let (attn_softcap, final_softcap) = match variant {
+            GemmaVariant::Gemma2 => (
+                get_f64("attn_logit_softcapping").or(Some(50.)),
+                get_f64("final_logit_softcapping").or(Some(30.)),
+            ),
+            _ => (
+                get_f64("attn_logit_softcapping"),
+                get_f64("final_logit_softcapping"),
+            ),
+        };
+
+        let cfg = Self {
+            variant,
+            vocab_size,
+            hidden_size,
+            intermediate_size: get_u32("feed_forward_length").unwrap_or(0),
+            num_hidden_layers,
+            num_attention_heads,
+            num_key_value_heads,
+            head_dim,
+            rms_norm_eps: get_f64("attention.layer_norm_rms_epsilon").unwrap_or(1e-6),
+            rope_theta: get_f64("rope.freq_base").unwrap_or(match variant {
+                GemmaVariant::Gemma3 => 1_000_000.,
+                _ => 10_000.,
+            }),
+            rope_local_base_freq: get_f64("rope.local_freq_base")
+                .unwrap_or(DEFAULT_ROPE_LOCAL_BASE_FREQ),
+            rope_scaling: native_rope_scaling(
+                variant,
+                RopeScaling::from_gguf(metadata, prefix),
+                max_position_embeddings,
+            ),
+            query_pre_attn_scalar,
+            attn_logit_softcapping: attn_softcap,
+            final_logit_softcapping: final_softcap,
+            sliding_window: get_u32("attention.sliding_window").ok(),
+            sliding_layers: sliding_pattern(variant, num_hidden_layers, pattern),
+            max_position_embeddings,
+        };
+        cfg.validate().map_err(candle::Error::Msg)?;
+        Ok(cfg)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.num_key_value_heads == 0
+            || !self
+                .num_attention_heads
+                .is_multiple_of(self.num_key_value_heads)
+        {
+            return Err(format!(
+                "Gemma config: {} attention heads are not divisible by {} KV heads",
+                self.num_attention_heads, self.num_key_value_heads
+            ));
+        }
+        if self.sliding_layers.len() != self.num_hidden_layers {
+            return Err(format!(
+                "Gemma config: layer_types has {} entries for {} layers",
+                self.sliding_layers.len(),
+                self.num_hidden_layers
+            ));
+        }
+        Ok(())
+    }
+
+    /// Окно внимания слоя (`None` — глобальное внимание)
+    pub fn layer_window(&self, layer_idx: usize) -> Option<usize> {
+        if self.sliding_layers[layer_idx] {
+            self.sliding_window
+        } else {
+            None
+        }
+    }
+
+    /// Использует ли слой локальную базу RoPE (слои со скользящим окном Gemma 3)
+    pub fn uses_local_rope(&self, layer_idx: usize) -> bool {
+        self.variant == GemmaVariant::Gemma3 && self.sliding_layers[layer_idx]
+    }
+
+    /// База и масштабирование RoPE слоя: у локальных слоёв Gemma 3 своя база без scaling
+    pub fn layer_rope(&self, layer_idx: usize) -> (f64, Option<RopeScaling>) {
+        if self.uses_local_rope(layer_idx) {
+            (self.rope_local_base_freq, None)
+        } else {
+            (self.rope_theta, self.rope_scaling)
+        }
+    }
+
+    /// Параметры ре-ротации ключей слоя при сдвиге контекста
+    pub fn layer_rope_shift(&self, layer_idx: usize) -> RopeShift {
+        let (theta, scaling) = self.layer_rope(layer_idx);
+        RopeShift::new(self.head_dim, theta).with_scaling(scaling)
+    }
+}
+
+/// Масштабирование RoPE из весов модели
+///
+/// Линейное масштабирование глобальных слоёв Gemma 3 — родное для модели:
+/// `max_position_embeddings` уже учитывает его, поэтому таблицы RoPE не удлиняются.
+fn native_rope_scaling(
+    variant: GemmaVariant,
+    scaling: Option<RopeScaling>,
+    max_position_embeddings: usize,
+) -> Option<RopeScaling> {
+    let scaling = scaling?;
+    let trained = if variant == GemmaVariant::Gemma3 && scaling.kind == RopeScalingKind::Linear {
+        (max_position_embeddings as f64 / scaling.factor) as usize
+    } else {
+        max_position_embeddings
+    };
+    Some(scaling.resolve(trained))
+}
+
+/// Чередование слоёв со скользящим окном
+///
+/// Gemma 2: чётные слои локальные, нечётные глобальные.
+/// Gemma 3: каждый `pattern`-й слой глобальный, остальные локальные.
+fn sliding_pattern(variant: GemmaVariant, num_layers: usize, pattern: usize) -> Vec<bool> {
+    (0..num_layers)
+        .map(|i| match variant {
+            GemmaVariant::Gemma => false,
+            GemmaVariant::Gemma2 => i % 2 == 0,
+            GemmaVariant::Gemma3 => pattern == 0 || !(i + 1).is_multiple_of(pattern),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sliding_layers_follow_variant_pattern() {
+        assert_eq!(
+            sliding_pattern(GemmaVariant::Gemma2, 4, DEFAULT_SLIDING_WINDOW_PATTERN),
+            vec![true, false, true, false]
+        );
+        let gemma3 = sliding_pattern(GemmaVariant::Gemma3, 12, 6);
+        assert_eq!(gemma3.iter().filter(|s| !**s).count(), 2);
+        assert!(!gemma3[5] && !gemma3[11] && gemma3[0]);
+        assert!(
+            sliding_pattern(GemmaVariant::Gemma, 3, 6)
+                .iter()
+                .all(|s| !s)
+        );
+    }
+
+    #[test]
+    fn parses_gemma3_text_config() {
+        let config = serde_json::json!({
+            "model_type": "gemma3",
+            "text_config": {
+                "model_type": "gemma3_text",
+                "vocab_size": 262208,
+                "hidden_size": 2560,
+                "intermediate_size": 10240,
+                "num_hidden_layers": 6,
+                "num_attention_heads": 8,
+                "num_key_value_heads": 4,
+                "head_dim": 256,
+                "query_pre_attn_scalar": 256,
+                "sliding_window": 1024,
+                "rope_scaling": {"rope_type": "linear", "factor": 8.0},
+                "max_position_embeddings": 131072
+            }
+        });
+        let cfg = Config::from_hf_json(&config, None).unwrap();
+        assert_eq!(cfg.variant, GemmaVariant::Gemma3);
+        assert_eq!(cfg.layer_window(0), Some(1024));
+        assert_eq!(cfg.layer_window(5), None);
+        assert_eq!(cfg.layer_rope(0), (DEFAULT_ROPE_LOCAL_BASE_FREQ, None));
+        let (theta, scaling) = cfg.layer_rope(5);
+        assert_eq!(theta, 1_000_000.);
+        assert_eq!(scaling.map(|s| s.factor), Some(8.0));
+        // Родное масштабирование не расширяет контекст сверх max_position_embeddings
+        assert_eq!(scaling.unwrap().context_length(131072), 131072);
+        assert_eq!(cfg.final_logit_softcapping, None);
+    }
+
+    #[test]
+    fn gemma2_defaults_to_softcapping() {
+        let config = serde_json::json!({
+            "model_type": "gemma2",
+            "vocab_size": 256000,
+            "hidden_size": 2304,
+            "intermediate_size": 9216,
+            "num_hidden_layers": 26,
+            "num_attention_heads": 8,
+            "num_key_value_heads": 4,
+            "head_dim": 256,
+            "sliding_window": 4096
+        });
+        let cfg = Config::from_hf_json(&config, None).unwrap();
+        assert_eq!(cfg.attn_logit_softcapping, Some(50.));
+        assert_eq!(cfg.final_logit_softcapping, Some(30.));
+        assert_eq!(cfg.query_pre_attn_scalar, 256.);
+        assert_eq!(cfg.layer_rope(0).0, 10_000.);
+    }
+}
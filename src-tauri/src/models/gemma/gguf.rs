@@ -0,0 +1,52 @@
+//! Gemma GGUF loading
+//!
+//! Загрузка квантизированных Gemma / Gemma 2 / Gemma 3 моделей из GGUF формата.
+
+use candle::Device;
+use candle::quantized::gguf_file;
+
+use super::GemmaBackend;
+use super::quantized_model::ModelWeights;
+use crate::models::common::gguf_mmap::GgufRead;
+use crate::models::common::gguf_split::open_gguf;
+use crate::models::common::rope_scaling::rope_table_len;
+
+impl GemmaBackend {
+    /// Создаёт бекенд из GGUF Content
+    pub fn from_gguf<R: GgufRead>(
+        content: gguf_file::Content,
+        file: &mut R,
+        device: &Device,
+    ) -> Result<Self, String> {
+        let inner = ModelWeights::from_gguf(content, file, device)
+            .map_err(|e| format!("Failed to load Gemma GGUF model: {}", e))?;
+
+        let cfg = inner.config();
+        let variant = cfg.variant;
+        let vocab_size = cfg.vocab_size;
+        let max_seq_len = rope_table_len(cfg.max_position_embeddings, cfg.rope_scaling.as_ref());
+
+        log::info!(
+            "Loading {:?} GGUF: layers={}, sliding_window={:?}, softcapping attn={:?} final={:?}",
+            variant,
+            cfg.num_hidden_layers,
+            cfg.sliding_window,
+            cfg.attn_logit_softcapping,
+            cfg.final_logit_softcapping
+        );
+
+        Ok(Self::new_quantized(
+            inner,
+            variant,
+            device.clone(),
+            vocab_size,
+            max_seq_len,
+        ))
+    }
+
+    /// Создаёт бекенд из пути к GGUF файлу
+    pub fn from_gguf_path(path: &std::path::Path, device: &Device) -> Result<Self, String> {
+        let (content, mut reader) = open_gguf(path)?;
+        Self::from_gguf(content, &mut reader, device)
+    }
+}
@@ -0,0 +1,218 @@
+//! Gemma / Gemma 2 / Gemma 3 model backend
+//!
+//! Все три поколения обслуживаются одной реализацией: структура слоя,
+//! soft-capping и чередование окон внимания задаются [`Config`].
+//!
+//! # Структура
+//! - `mod.rs` - общий GemmaBackend и ModelBackend реализация
+//! - `config.rs` - конфигурация из config.json или метаданных GGUF
+//! - `gguf.rs` - загрузка из GGUF формата
+//! - `safetensors.rs` - загрузка из SafeTensors формата
+//! - `model.rs` - модель полной точности
+//! - `quantized_model.rs` - квантизированная модель
+
+pub mod config;
+mod gguf;
+pub mod model;
+pub mod quantized_model;
+mod safetensors;
+
+pub use config::{Config, GemmaVariant};
+
+use candle::{Device, Tensor};
+use quantized_model::ModelWeights as QuantizedGemma;
+
+use crate::models::ModelBackend;
+use crate::models::api::optimization::OptimizationConfig;
+use crate::models::common::{KvCacheType, KvLayers, LayerKv, embeddings_unsupported};
+
+use model::Model;
+
+/// Gemma бекенд
+///
+/// Поддерживает как квантизированные (GGUF) так и полные (SafeTensors) модели.
+/// Flash Attention не используется: он несовместим с soft-capping внимания.
+pub struct GemmaBackend {
+    inner: GemmaInner,
+    variant: GemmaVariant,
+    device: Device,
+    vocab_size: usize,
+    max_seq_len: usize,
+    optimization: OptimizationConfig,
+}
+
+/// Внутреннее представление модели
+enum GemmaInner {
+    /// Квантизированная модель из GGUF
+    Quantized(QuantizedGemma),
+    /// Полная модель из SafeTensors
+    Full(Model),
+}
+
+impl GemmaInner {
+    /// KV-кэши слоёв активной модели
+    fn kv_layers(&self) -> &dyn KvLayers {
+        match self {
+            GemmaInner::Quantized(model) => model,
+            GemmaInner::Full(model) => model,
+        }
+    }
+
+    fn kv_layers_mut(&mut self) -> &mut dyn KvLayers {
+        match self {
+            GemmaInner::Quantized(model) => model,
+            GemmaInner::Full(model) => model,
+        }
+    }
+}
+
+impl GemmaBackend {
+    /// Создаёт квантизированный бекенд (используется из gguf.rs)
+    pub(crate) fn new_quantized(
+        model: QuantizedGemma,
+        variant: GemmaVariant,
+        device: Device,
+        vocab_size: usize,
+        max_seq_len: usize,
+    ) -> Self {
+        Self {
+            inner: GemmaInner::Quantized(model),
+            variant,
+            device,
+            vocab_size,
+            max_seq_len,
+            optimization: OptimizationConfig::for_gguf(),
+        }
+    }
+
+    /// Создаёт полный бекенд (используется из safetensors.rs)
+    pub(crate) fn new_full(
+        model: Model,
+        variant: GemmaVariant,
+        device: Device,
+        vocab_size: usize,
+        max_seq_len: usize,
+        optimization: OptimizationConfig,
+    ) -> Self {
+        Self {
+            inner: GemmaInner::Full(model),
+            variant,
+            device,
+            vocab_size,
+            max_seq_len,
+            optimization,
+        }
+    }
+
+    /// Возвращает устройство
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    /// Поколение модели
+    pub fn variant(&self) -> GemmaVariant {
+        self.variant
+    }
+
+    /// Проверяет, квантизирована ли модель
+    pub fn is_quantized(&self) -> bool {
+        matches!(self.inner, GemmaInner::Quantized(_))
+    }
+
+    /// Возвращает конфигурацию оптимизаций
+    pub fn optimization(&self) -> &OptimizationConfig {
+        &self.optimization
+    }
+}
+
+impl ModelBackend for GemmaBackend {
+    fn forward(&mut self, input: &Tensor, pos: usize) -> candle::Result<Tensor> {
+        match &mut self.inner {
+            // GGUF модель возвращает [batch, vocab_size] - только последний токен
+            GemmaInner::Quantized(model) => model.forward(input, pos),
+            // [batch, 1, vocab_size] -> [batch, vocab_size]
+            GemmaInner::Full(model) => model.forward(input, pos)?.squeeze(1),
+        }
+    }
+
+    fn clear_kv_cache(&mut self) {
+        match &mut self.inner {
+            GemmaInner::Quantized(model) => model.clear_kv_cache(),
+            GemmaInner::Full(model) => model.clear_kv_cache(),
+        }
+    }
+
+    fn model_type(&self) -> &str {
+        self.variant.model_type(self.is_quantized())
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.vocab_size
+    }
+
+    fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+
+    fn supports_chunked_prefill(&self) -> bool {
+        true
+    }
+
+    fn forward_all(&mut self, input: &Tensor, pos: usize) -> candle::Result<Tensor> {
+        match &mut self.inner {
+            // [batch, seq_len, vocab_size] -> [seq_len, vocab_size]
+            GemmaInner::Full(model) => model.forward_all(input, pos)?.squeeze(0),
+            GemmaInner::Quantized(_) => {
+                candle::bail!("forward_all is not supported for Gemma-GGUF")
+            }
+        }
+    }
+
+    fn truncate_kv_cache(&mut self, len: usize) -> bool {
+        match &mut self.inner {
+            GemmaInner::Full(model) => model.truncate_kv_cache(len).is_ok(),
+            GemmaInner::Quantized(_) => false,
+        }
+    }
+
+    fn supports_speculative(&self) -> bool {
+        matches!(self.inner, GemmaInner::Full(_))
+    }
+
+    fn supports_context_shift(&self) -> bool {
+        true
+    }
+
+    fn shift_kv_cache(&mut self, keep: usize, discard: usize) -> bool {
+        self.inner
+            .kv_layers_mut()
+            .shift_kv_cache(keep, discard)
+            .is_ok()
+    }
+
+    fn supports_kv_snapshot(&self) -> bool {
+        true
+    }
+
+    fn export_kv_cache(&self) -> candle::Result<Vec<LayerKv>> {
+        self.inner.kv_layers().export_kv_cache()
+    }
+
+    fn import_kv_cache(&mut self, layers: &[LayerKv]) -> candle::Result<()> {
+        self.inner.kv_layers_mut().import_kv_cache(layers)
+    }
+
+    fn set_kv_cache_type(&mut self, cache_type: KvCacheType) -> bool {
+        self.inner
+            .kv_layers_mut()
+            .set_kv_cache_type(cache_type)
+            .is_ok()
+    }
+
+    fn get_embeddings(&mut self, input: &Tensor) -> candle::Result<Tensor> {
+        match &mut self.inner {
+            GemmaInner::Full(model) => model.get_hidden_states(input, 0),
+            GemmaInner::Quantized(_) => Err(embeddings_unsupported("Gemma-GGUF")),
+        }
+    }
+}
@@ -0,0 +1,501 @@
+//! Gemma / Gemma 2 / Gemma 3 (SafeTensors)
+//!
+//! Локальная реализация вместо candle_transformers::models::gemma{,2,3}:
+//! - окно внимания применяется только к локальным слоям (в candle gemma2 — ко всем);
+//! - KV-кэш открыт для сдвига контекста, снимков и квантования.
+//!
+//! KV-кэш локальных слоёв хранит все позиции, окно задаётся маской (prefill)
+//! или срезом кэша (декодирование), поэтому сдвиг и снимки работают одинаково
+//! для всех слоёв.
+
+use candle::{DType, Device, Module, Result, Tensor};
+use candle_nn::{Activation, VarBuilder};
+use candle_transformers::models::with_tracing::{Linear, linear_no_bias};
+use candle_transformers::utils::repeat_kv;
+use std::sync::Arc;
+
+use super::config::Config;
+use crate::models::common::rope_scaling::rope_table_len;
+use crate::models::common::{KvCache, KvLayers, RopeShift, RotaryEmbedding};
+
+/// Soft-capping: `tanh(x / cap) * cap`
+pub(crate) fn softcap(x: &Tensor, cap: Option<f64>) -> Result<Tensor> {
+    match cap {
+        Some(cap) => (x / cap)?.tanh()? * cap,
+        None => Ok(x.clone()),
+    }
+}
+
+/// Causal-маска [b, 1, tgt, tgt + offset]; с `window` позиция видит только
+/// последние `window` токенов (включая себя)
+pub(crate) fn attention_mask(
+    b: usize,
+    tgt: usize,
+    offset: usize,
+    window: Option<usize>,
+    dtype: DType,
+    device: &Device,
+) -> Result<Tensor> {
+    let minf = f32::NEG_INFINITY;
+    let mask: Vec<_> = (0..tgt)
+        .flat_map(|i| {
+            (0..(tgt + offset)).map(move |j| {
+                let pos = i + offset;
+                let visible = j <= pos && window.is_none_or(|w| pos - j < w);
+                if visible { 0. } else { minf }
+            })
+        })
+        .collect();
+    Tensor::from_slice(&mask, (tgt, tgt + offset), device)?
+        .expand((b, 1, tgt, tgt + offset))?
+        .to_dtype(dtype)
+}
+
+/// Маски для prefill: глобальная и (если в модели есть локальные слои) оконная
+pub(crate) struct LayerMasks {
+    pub global: Tensor,
+    pub sliding: Option<Tensor>,
+}
+
+impl LayerMasks {
+    pub fn new(
+        cfg: &Config,
+        b: usize,
+        tgt: usize,
+        offset: usize,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self> {
+        let global = attention_mask(b, tgt, offset, None, dtype, device)?;
+        let sliding = match cfg.sliding_window {
+            Some(w) if cfg.sliding_layers.iter().any(|s| *s) => {
+                Some(attention_mask(b, tgt, offset, Some(w), dtype, device)?)
+            }
+            _ => None,
+        };
+        Ok(Self { global, sliding })
+    }
+
+    pub fn for_window(&self, window: Option<usize>) -> &Tensor {
+        match (window, &self.sliding) {
+            (Some(_), Some(sliding)) => sliding,
+            _ => &self.global,
+        }
+    }
+}
+
+/// Последние `window` позиций кэша для шага декодирования локального слоя
+pub(crate) fn window_kv(k: Tensor, v: Tensor, window: Option<usize>) -> Result<(Tensor, Tensor)> {
+    let kv_len = k.dim(2)?;
+    match window {
+        Some(w) if kv_len > w => Ok((k.narrow(2, kv_len - w, w)?, v.narrow(2, kv_len - w, w)?)),
+        _ => Ok((k, v)),
+    }
+}
+
+/// RmsNorm Gemma: вес хранится как отклонение от 1
+#[derive(Debug, Clone)]
+struct GemmaRmsNorm(candle_nn::RmsNorm);
+
+impl GemmaRmsNorm {
+    fn new(size: usize, eps: f64, vb: VarBuilder) -> Result<Self> {
+        let weight = vb.get(size, "weight")?;
+        Ok(Self(candle_nn::RmsNorm::new((weight + 1.0)?, eps)))
+    }
+}
+
+impl Module for GemmaRmsNorm {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        self.0.forward(x)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    gate_proj: Linear,
+    up_proj: Linear,
+    down_proj: Linear,
+    act_fn: Activation,
+}
+
+impl Mlp {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            gate_proj: linear_no_bias(cfg.hidden_size, cfg.intermediate_size, vb.pp("gate_proj"))?,
+            up_proj: linear_no_bias(cfg.hidden_size, cfg.intermediate_size, vb.pp("up_proj"))?,
+            down_proj: linear_no_bias(cfg.intermediate_size, cfg.hidden_size, vb.pp("down_proj"))?,
+            act_fn: Activation::GeluPytorchTanh,
+        })
+    }
+}
+
+impl Module for Mlp {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let gate = self.gate_proj.forward(x)?.apply(&self.act_fn)?;
+        let up = self.up_proj.forward(x)?;
+        self.down_proj.forward(&(gate * up)?)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Attention {
+    q_proj: Linear,
+    k_proj: Linear,
+    v_proj: Linear,
+    o_proj: Linear,
+    /// q/k-нормализация (Gemma 3)
+    q_norm: Option<GemmaRmsNorm>,
+    k_norm: Option<GemmaRmsNorm>,
+    num_heads: usize,
+    num_kv_heads: usize,
+    num_kv_groups: usize,
+    head_dim: usize,
+    scale: f64,
+    softcap: Option<f64>,
+    window: Option<usize>,
+    rotary_emb: Arc<RotaryEmbedding>,
+    rope: RopeShift,
+    kv_cache: KvCache,
+}
+
+impl Attention {
+    fn new(
+        cfg: &Config,
+        layer_idx: usize,
+        rotary_emb: Arc<RotaryEmbedding>,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let (hidden, head_dim) = (cfg.hidden_size, cfg.head_dim);
+        let q_dim = cfg.num_attention_heads * head_dim;
+        let kv_dim = cfg.num_key_value_heads * head_dim;
+        let (q_norm, k_norm) = if vb.contains_tensor("q_norm.weight") {
+            (
+                Some(GemmaRmsNorm::new(
+                    head_dim,
+                    cfg.rms_norm_eps,
+                    vb.pp("q_norm"),
+                )?),
+                Some(GemmaRmsNorm::new(
+                    head_dim,
+                    cfg.rms_norm_eps,
+                    vb.pp("k_norm"),
+                )?),
+            )
+        } else {
+            (None, None)
+        };
+        Ok(Self {
+            q_proj: linear_no_bias(hidden, q_dim, vb.pp("q_proj"))?,
+            k_proj: linear_no_bias(hidden, kv_dim, vb.pp("k_proj"))?,
+            v_proj: linear_no_bias(hidden, kv_dim, vb.pp("v_proj"))?,
+            o_proj: linear_no_bias(q_dim, hidden, vb.pp("o_proj"))?,
+            q_norm,
+            k_norm,
+            num_heads: cfg.num_attention_heads,
+            num_kv_heads: cfg.num_key_value_heads,
+            num_kv_groups: cfg.num_attention_heads / cfg.num_key_value_heads,
+            head_dim,
+            scale: 1.0 / cfg.query_pre_attn_scalar.sqrt(),
+            softcap: cfg.attn_logit_softcapping,
+            window: cfg.layer_window(layer_idx),
+            rotary_emb,
+            rope: cfg.layer_rope_shift(layer_idx),
+            kv_cache: KvCache::new(2),
+        })
+    }
+
+    fn forward(&mut self, x: &Tensor, masks: Option<&LayerMasks>, offset: usize) -> Result<Tensor> {
+        let (b, l, _) = x.dims3()?;
+
+        let q = self
+            .q_proj
+            .forward(x)?
+            .reshape((b, l, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let k = self
+            .k_proj
+            .forward(x)?
+            .reshape((b, l, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let v = self
+            .v_proj
+            .forward(x)?
+            .reshape((b, l, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+
+        let (q, k) = match (&self.q_norm, &self.k_norm) {
+            (Some(q_norm), Some(k_norm)) => (q_norm.forward(&q)?, k_norm.forward(&k)?),
+            _ => (q, k),
+        };
+        let (q, k) = self.rotary_emb.apply(&q, &k, offset)?;
+
+        let (k, v) = self.kv_cache.append(&k, &v)?;
+        let (k, v) = if masks.is_none() {
+            window_kv(k, v, self.window)?
+        } else {
+            (k, v)
+        };
+
+        let k = repeat_kv(k, self.num_kv_groups)?.contiguous()?;
+        let v = repeat_kv(v, self.num_kv_groups)?.contiguous()?;
+
+        let scores = (q.matmul(&k.transpose(2, 3)?)? * self.scale)?;
+        let mut scores = softcap(&scores, self.softcap)?;
+        if let Some(masks) = masks {
+            let mask = masks.for_window(self.window);
+            scores = scores.broadcast_add(&mask.to_dtype(scores.dtype())?)?;
+        }
+        let probs =
+            candle_nn::ops::softmax_last_dim(&scores.to_dtype(DType::F32)?)?.to_dtype(v.dtype())?;
+        let ctx = probs.matmul(&v)?;
+        let ctx = ctx
+            .transpose(1, 2)?
+            .reshape((b, l, self.num_heads * self.head_dim))?;
+        self.o_proj.forward(&ctx)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DecoderLayer {
+    self_attn: Attention,
+    mlp: Mlp,
+    input_layernorm: GemmaRmsNorm,
+    /// Gemma 2/3: норма выхода attention
+    post_attention_layernorm: Option<GemmaRmsNorm>,
+    pre_feedforward_layernorm: GemmaRmsNorm,
+    /// Gemma 2/3: норма выхода MLP
+    post_feedforward_layernorm: Option<GemmaRmsNorm>,
+}
+
+impl DecoderLayer {
+    fn new(
+        cfg: &Config,
+        layer_idx: usize,
+        rotary_emb: Arc<RotaryEmbedding>,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let (size, eps) = (cfg.hidden_size, cfg.rms_norm_eps);
+        let input_layernorm = GemmaRmsNorm::new(size, eps, vb.pp("input_layernorm"))?;
+        let (post_attention_layernorm, pre_feedforward_layernorm, post_feedforward_layernorm) =
+            if cfg.variant.has_post_norms() {
+                (
+                    Some(GemmaRmsNorm::new(
+                        size,
+                        eps,
+                        vb.pp("post_attention_layernorm"),
+                    )?),
+                    GemmaRmsNorm::new(size, eps, vb.pp("pre_feedforward_layernorm"))?,
+                    Some(GemmaRmsNorm::new(
+                        size,
+                        eps,
+                        vb.pp("post_feedforward_layernorm"),
+                    )?),
+                )
+            } else {
+                // В Gemma 1 `post_attention_layernorm` — это pre-norm MLP
+                (
+                    None,
+                    GemmaRmsNorm::new(size, eps, vb.pp("post_attention_layernorm"))?,
+                    None,
+                )
+            };
+        Ok(Self {
+            self_attn: Attention::new(cfg, layer_idx, rotary_emb, vb.pp("self_attn"))?,
+            mlp: Mlp::new(cfg, vb.pp("mlp"))?,
+            input_layernorm,
+            post_attention_layernorm,
+            pre_feedforward_layernorm,
+            post_feedforward_layernorm,
+        })
+    }
+
+    fn forward(&mut self, x: &Tensor, masks: Option<&LayerMasks>, offset: usize) -> Result<Tensor> {
+        let h = self.input_layernorm.forward(x)?;
+        let h = self.self_attn.forward(&h, masks, offset)?;
+        let h = match &self.post_attention_layernorm {
+            Some(norm) => norm.forward(&h)?,
+            None => h,
+        };
+        let x = (x + h)?;
+        let h = self
+            .pre_feedforward_layernorm
+            .forward(&x)?
+            .apply(&self.mlp)?;
+        let h = match &self.post_feedforward_layernorm {
+            Some(norm) => norm.forward(&h)?,
+            None => h,
+        };
+        x + h
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Model {
+    embed_tokens: candle_nn::Embedding,
+    layers: Vec<DecoderLayer>,
+    norm: GemmaRmsNorm,
+    lm_head: Linear,
+    cfg: Config,
+    device: Device,
+    dtype: DType,
+}
+
+impl Model {
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        // Gemma3ForConditionalGeneration хранит текстовую модель под `language_model`
+        let vb_m = ["model", "language_model.model", "model.language_model"]
+            .into_iter()
+            .map(|prefix| vb.pp(prefix))
+            .find(|vb| vb.contains_tensor("embed_tokens.weight"))
+            .unwrap_or_else(|| vb.pp("model"));
+
+        let embed_tokens =
+            candle_nn::embedding(cfg.vocab_size, cfg.hidden_size, vb_m.pp("embed_tokens"))?;
+        let dtype = vb.dtype();
+        let device = vb.device().clone();
+
+        let max_seq_len = rope_table_len(cfg.max_position_embeddings, cfg.rope_scaling.as_ref());
+        let global_rope = Arc::new(RotaryEmbedding::new(
+            dtype,
+            cfg.head_dim,
+            cfg.max_position_embeddings,
+            cfg.rope_theta,
+            cfg.rope_scaling.as_ref(),
+            &device,
+        )?);
+        let local_rope = Arc::new(RotaryEmbedding::new(
+            dtype,
+            cfg.head_dim,
+            max_seq_len,
+            cfg.rope_local_base_freq,
+            None,
+            &device,
+        )?);
+
+        let vb_l = vb_m.pp("layers");
+        let layers = (0..cfg.num_hidden_layers)
+            .map(|i| {
+                let rotary = if cfg.uses_local_rope(i) {
+                    local_rope.clone()
+                } else {
+                    global_rope.clone()
+                };
+                DecoderLayer::new(cfg, i, rotary, vb_l.pp(i))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            lm_head: Linear::from_weights(embed_tokens.embeddings().clone(), None),
+            embed_tokens,
+            layers,
+            norm: GemmaRmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb_m.pp("norm"))?,
+            cfg: cfg.clone(),
+            device,
+            dtype,
+        })
+    }
+
+    /// Скрытые состояния после финальной нормализации [batch, seq_len, hidden_size]
+    fn forward_hidden(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        let (b, l) = input.dims2()?;
+        let mut h = (self.embed_tokens.forward(input)? * (self.cfg.hidden_size as f64).sqrt())?;
+        let masks = if l == 1 {
+            None
+        } else {
+            Some(LayerMasks::new(
+                &self.cfg,
+                b,
+                l,
+                offset,
+                self.dtype,
+                &self.device,
+            )?)
+        };
+        for layer in &mut self.layers {
+            h = layer.forward(&h, masks.as_ref(), offset)?;
+        }
+        self.norm.forward(&h)
+    }
+
+    fn logits(&self, h: &Tensor) -> Result<Tensor> {
+        let logits = h.apply(&self.lm_head)?;
+        softcap(&logits, self.cfg.final_logit_softcapping)
+    }
+
+    /// Логиты последней позиции [batch, 1, vocab_size]
+    pub fn forward(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        let (_, l) = input.dims2()?;
+        let h = self.forward_hidden(input, offset)?.narrow(1, l - 1, 1)?;
+        self.logits(&h)
+    }
+
+    /// Returns logits for every input position [batch, seq_len, vocab_size]
+    pub fn forward_all(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        let h = self.forward_hidden(input, offset)?;
+        self.logits(&h)
+    }
+
+    /// Returns hidden states of the last layer after normalization [batch, seq_len, hidden_size]
+    pub fn get_hidden_states(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        self.forward_hidden(input, offset)
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        for layer in &mut self.layers {
+            layer.self_attn.kv_cache.reset();
+        }
+    }
+}
+
+impl KvLayers for Model {
+    fn kv_caches(&self) -> Vec<&KvCache> {
+        self.layers.iter().map(|l| &l.self_attn.kv_cache).collect()
+    }
+
+    fn kv_caches_mut(&mut self) -> Vec<&mut KvCache> {
+        self.layers
+            .iter_mut()
+            .map(|l| &mut l.self_attn.kv_cache)
+            .collect()
+    }
+
+    // Local sliding-window layers rotate with their own RoPE base
+    fn shift_rope(&self, layer: usize) -> Result<RopeShift> {
+        Ok(self.layers[layer].self_attn.rope)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle::D;
+
+    #[test]
+    fn sliding_mask_limits_visible_positions() {
+        let mask = attention_mask(1, 3, 2, Some(2), DType::F32, &Device::Cpu)
+            .unwrap()
+            .squeeze(0)
+            .unwrap()
+            .squeeze(0)
+            .unwrap()
+            .to_vec2::<f32>()
+            .unwrap();
+        // Позиция 2 видит 1..=2, позиция 4 — 3..=4
+        assert!(mask[0][0].is_infinite() && mask[0][1] == 0. && mask[0][2] == 0.);
+        assert!(mask[0][3].is_infinite());
+        assert!(mask[2][2].is_infinite() && mask[2][3] == 0. && mask[2][4] == 0.);
+    }
+
+    #[test]
+    fn softcap_bounds_values() {
+        let x = Tensor::new(&[-1000f32, 0., 1000.], &Device::Cpu).unwrap();
+        let capped = softcap(&x, Some(30.))
+            .unwrap()
+            .max(D::Minus1)
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap();
+        assert!((capped - 30.).abs() < 1e-3);
+    }
+}
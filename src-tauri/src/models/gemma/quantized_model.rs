@@ -0,0 +1,338 @@
+//! Квантизированная Gemma / Gemma 2 / Gemma 3 из GGUF
+//!
+//! Аналог candle_transformers::models::quantized_gemma3, расширенный на Gemma 1/2
+//! (soft-capping, чередование локальных и глобальных слоёв) и с открытым KV-кэшем.
+//! Веса норм в GGUF уже содержат +1 (конвертер llama.cpp), поэтому используется
+//! обычный RmsNorm.
+
+use crate::models::common::gguf_mmap::{Gguf, GgufRead};
+use candle::quantized::gguf_file;
+use candle::{DType, Device, Result, Tensor};
+use candle_nn::{Activation, Embedding, Module};
+use candle_transformers::models::with_tracing::QMatMul;
+use candle_transformers::quantized_nn::RmsNorm;
+use candle_transformers::utils::repeat_kv;
+use std::sync::Arc;
+
+use super::config::Config;
+use super::model::{LayerMasks, softcap, window_kv};
+use crate::models::common::rope_scaling::rope_table_len;
+use crate::models::common::{KvCache, KvLayers, RopeShift, RotaryEmbedding};
+
+#[derive(Debug, Clone)]
+struct MlpWeights {
+    gate_proj: QMatMul,
+    up_proj: QMatMul,
+    down_proj: QMatMul,
+    act_fn: Activation,
+}
+
+impl MlpWeights {
+    fn new<R: GgufRead>(gg: &mut Gguf<R>, prefix: &str) -> Result<Self> {
+        Ok(Self {
+            gate_proj: gg.qmatmul(&format!("{prefix}.ffn_gate.weight"))?,
+            up_proj: gg.qmatmul(&format!("{prefix}.ffn_up.weight"))?,
+            down_proj: gg.qmatmul(&format!("{prefix}.ffn_down.weight"))?,
+            act_fn: Activation::GeluPytorchTanh,
+        })
+    }
+}
+
+impl Module for MlpWeights {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let gate = self.gate_proj.forward(x)?.apply(&self.act_fn)?;
+        let up = self.up_proj.forward(x)?;
+        self.down_proj.forward(&(gate * up)?)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct AttentionWeights {
+    q_proj: QMatMul,
+    k_proj: QMatMul,
+    v_proj: QMatMul,
+    o_proj: QMatMul,
+    /// q/k-нормализация (Gemma 3)
+    q_norm: Option<RmsNorm>,
+    k_norm: Option<RmsNorm>,
+    num_heads: usize,
+    num_kv_heads: usize,
+    num_kv_groups: usize,
+    head_dim: usize,
+    scale: f64,
+    softcap: Option<f64>,
+    window: Option<usize>,
+    rotary_emb: Arc<RotaryEmbedding>,
+    rope: RopeShift,
+    kv_cache: KvCache,
+}
+
+impl AttentionWeights {
+    fn new<R: GgufRead>(
+        gg: &mut Gguf<R>,
+        cfg: &Config,
+        layer_idx: usize,
+        has_qk_norm: bool,
+        rotary_emb: Arc<RotaryEmbedding>,
+        prefix: &str,
+    ) -> Result<Self> {
+        let (q_norm, k_norm) = if has_qk_norm {
+            (
+                Some(gg.rms_norm(&format!("{prefix}.attn_q_norm.weight"), cfg.rms_norm_eps)?),
+                Some(gg.rms_norm(&format!("{prefix}.attn_k_norm.weight"), cfg.rms_norm_eps)?),
+            )
+        } else {
+            (None, None)
+        };
+        Ok(Self {
+            q_proj: gg.qmatmul(&format!("{prefix}.attn_q.weight"))?,
+            k_proj: gg.qmatmul(&format!("{prefix}.attn_k.weight"))?,
+            v_proj: gg.qmatmul(&format!("{prefix}.attn_v.weight"))?,
+            o_proj: gg.qmatmul(&format!("{prefix}.attn_output.weight"))?,
+            q_norm,
+            k_norm,
+            num_heads: cfg.num_attention_heads,
+            num_kv_heads: cfg.num_key_value_heads,
+            num_kv_groups: cfg.num_attention_heads / cfg.num_key_value_heads,
+            head_dim: cfg.head_dim,
+            scale: 1.0 / cfg.query_pre_attn_scalar.sqrt(),
+            softcap: cfg.attn_logit_softcapping,
+            window: cfg.layer_window(layer_idx),
+            rotary_emb,
+            rope: cfg.layer_rope_shift(layer_idx),
+            kv_cache: KvCache::new(2),
+        })
+    }
+
+    fn forward(&mut self, x: &Tensor, masks: Option<&LayerMasks>, offset: usize) -> Result<Tensor> {
+        let (b, l, _) = x.dims3()?;
+
+        let q = self
+            .q_proj
+            .forward(x)?
+            .reshape((b, l, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let k = self
+            .k_proj
+            .forward(x)?
+            .reshape((b, l, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let v = self
+            .v_proj
+            .forward(x)?
+            .reshape((b, l, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+
+        let (q, k) = match (&self.q_norm, &self.k_norm) {
+            (Some(q_norm), Some(k_norm)) => {
+                let q = q_norm.forward(&q.flatten(0, 2)?)?;
+                let k = k_norm.forward(&k.flatten(0, 2)?)?;
+                (
+                    q.reshape((b, self.num_heads, l, self.head_dim))?,
+                    k.reshape((b, self.num_kv_heads, l, self.head_dim))?,
+                )
+            }
+            _ => (q, k),
+        };
+        let (q, k) = self.rotary_emb.apply(&q, &k, offset)?;
+
+        let (k, v) = self.kv_cache.append(&k, &v)?;
+        let (k, v) = if masks.is_none() {
+            window_kv(k, v, self.window)?
+        } else {
+            (k, v)
+        };
+
+        let k = repeat_kv(k, self.num_kv_groups)?.contiguous()?;
+        let v = repeat_kv(v, self.num_kv_groups)?.contiguous()?;
+
+        let scores = (q.matmul(&k.transpose(2, 3)?)? * self.scale)?;
+        let mut scores = softcap(&scores, self.softcap)?;
+        if let Some(masks) = masks {
+            let mask = masks.for_window(self.window);
+            scores = scores.broadcast_add(&mask.to_dtype(scores.dtype())?)?;
+        }
+        let probs = candle_nn::ops::softmax_last_dim(&scores)?;
+        let ctx = probs.matmul(&v)?; // (B, H, L, D)
+        let ctx = ctx
+            .transpose(1, 2)?
+            .reshape((b, l, self.num_heads * self.head_dim))?;
+        self.o_proj.forward(&ctx)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct LayerWeights {
+    self_attn: AttentionWeights,
+    mlp: MlpWeights,
+    attn_norm: RmsNorm,
+    /// Gemma 2/3: норма выхода attention
+    post_attn_norm: Option<RmsNorm>,
+    ffn_norm: RmsNorm,
+    /// Gemma 2/3: норма выхода MLP
+    post_ffn_norm: Option<RmsNorm>,
+}
+
+impl LayerWeights {
+    fn forward(&mut self, x: &Tensor, masks: Option<&LayerMasks>, offset: usize) -> Result<Tensor> {
+        let h = self.attn_norm.forward(x)?;
+        let h = self.self_attn.forward(&h, masks, offset)?;
+        let h = match &self.post_attn_norm {
+            Some(norm) => norm.forward(&h)?,
+            None => h,
+        };
+        let x = (x + h)?;
+        let h = self.ffn_norm.forward(&x)?.apply(&self.mlp)?;
+        let h = match &self.post_ffn_norm {
+            Some(norm) => norm.forward(&h)?,
+            None => h,
+        };
+        x + h
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ModelWeights {
+    embed_tokens: Embedding,
+    layers: Vec<LayerWeights>,
+    norm: RmsNorm,
+    lm_head: QMatMul,
+    cfg: Config,
+    device: Device,
+    dtype: DType,
+}
+
+impl ModelWeights {
+    pub fn from_gguf<R: GgufRead>(
+        ct: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+    ) -> Result<Self> {
+        let cfg = Config::from_gguf(&ct.metadata)?;
+        let has_output = ct.tensor_infos.contains_key("output.weight");
+        let has_qk_norm = ct.tensor_infos.contains_key("blk.0.attn_q_norm.weight");
+        let mut gg = Gguf::new(ct, reader, device.clone());
+        let dtype = DType::F32;
+
+        let embed_tensor = gg.tensor("token_embd.weight")?;
+        let embed_tokens = Embedding::new(embed_tensor.dequantize(device)?, cfg.hidden_size);
+        // Gemma связывает выходную проекцию с эмбеддингами
+        let lm_head = if has_output {
+            gg.qmatmul("output.weight")?
+        } else {
+            QMatMul::from_weights(Arc::new(embed_tensor))?
+        };
+
+        let max_seq_len = rope_table_len(cfg.max_position_embeddings, cfg.rope_scaling.as_ref());
+        let global_rope = Arc::new(RotaryEmbedding::new(
+            dtype,
+            cfg.head_dim,
+            cfg.max_position_embeddings,
+            cfg.rope_theta,
+            cfg.rope_scaling.as_ref(),
+            device,
+        )?);
+        let local_rope = Arc::new(RotaryEmbedding::new(
+            dtype,
+            cfg.head_dim,
+            max_seq_len,
+            cfg.rope_local_base_freq,
+            None,
+            device,
+        )?);
+
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        for i in 0..cfg.num_hidden_layers {
+            let prefix = format!("blk.{i}");
+            let rotary = if cfg.uses_local_rope(i) {
+                local_rope.clone()
+            } else {
+                global_rope.clone()
+            };
+            let (post_attn_norm, post_ffn_norm) = if cfg.variant.has_post_norms() {
+                (
+                    Some(gg.rms_norm(
+                        &format!("{prefix}.post_attention_norm.weight"),
+                        cfg.rms_norm_eps,
+                    )?),
+                    Some(gg.rms_norm(&format!("{prefix}.post_ffw_norm.weight"), cfg.rms_norm_eps)?),
+                )
+            } else {
+                (None, None)
+            };
+            layers.push(LayerWeights {
+                self_attn: AttentionWeights::new(&mut gg, &cfg, i, has_qk_norm, rotary, &prefix)?,
+                mlp: MlpWeights::new(&mut gg, &prefix)?,
+                attn_norm: gg.rms_norm(&format!("{prefix}.attn_norm.weight"), cfg.rms_norm_eps)?,
+                post_attn_norm,
+                ffn_norm: gg.rms_norm(&format!("{prefix}.ffn_norm.weight"), cfg.rms_norm_eps)?,
+                post_ffn_norm,
+            });
+        }
+
+        Ok(Self {
+            embed_tokens,
+            layers,
+            norm: gg.rms_norm("output_norm.weight", cfg.rms_norm_eps)?,
+            lm_head,
+            cfg,
+            device: device.clone(),
+            dtype,
+        })
+    }
+
+    /// Конфигурация модели, прочитанная из GGUF
+    pub fn config(&self) -> &Config {
+        &self.cfg
+    }
+
+    /// Логиты последней позиции [batch, vocab_size]
+    pub fn forward(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        let (b, l) = input.dims2()?;
+        let mut h = (self.embed_tokens.forward(input)? * (self.cfg.hidden_size as f64).sqrt())?;
+        let masks = if l == 1 {
+            None
+        } else {
+            Some(LayerMasks::new(
+                &self.cfg,
+                b,
+                l,
+                offset,
+                self.dtype,
+                &self.device,
+            )?)
+        };
+        for layer in &mut self.layers {
+            h = layer.forward(&h, masks.as_ref(), offset)?;
+        }
+        let h = self.norm.forward(&h)?;
+        let last_hidden = h.narrow(1, l - 1, 1)?;
+        let logits = self.lm_head.forward(&last_hidden)?.squeeze(1)?;
+        softcap(&logits, self.cfg.final_logit_softcapping)
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        for layer in &mut self.layers {
+            layer.self_attn.kv_cache.reset();
+        }
+    }
+}
+
+impl KvLayers for ModelWeights {
+    fn kv_caches(&self) -> Vec<&KvCache> {
+        self.layers.iter().map(|l| &l.self_attn.kv_cache).collect()
+    }
+
+    fn kv_caches_mut(&mut self) -> Vec<&mut KvCache> {
+        self.layers
+            .iter_mut()
+            .map(|l| &mut l.self_attn.kv_cache)
+            .collect()
+    }
+
+    // Локальные слои со скользящим окном вращаются со своей базой RoPE
+    fn shift_rope(&self, layer: usize) -> Result<RopeShift> {
+        Ok(self.layers[layer].self_attn.rope)
+    }
+}
@@ -0,0 +1,59 @@
+//! Gemma SafeTensors loading
+//!
+//! Загрузка Gemma / Gemma 2 / Gemma 3 моделей из SafeTensors формата.
+
+use candle::{DType, Device};
+use candle_nn::VarBuilder;
+use std::path::{Path, PathBuf};
+
+use super::GemmaBackend;
+use super::config::Config;
+use super::model::Model;
+use crate::models::api::optimization::OptimizationConfig;
+use crate::models::common::RopeScaling;
+use crate::models::common::rope_scaling::rope_table_len;
+
+impl GemmaBackend {
+    /// Создаёт бекенд из SafeTensors файлов
+    pub fn from_safetensors(
+        filenames: &[PathBuf],
+        config_path: &Path,
+        device: &Device,
+        dtype: DType,
+        rope_scaling: Option<RopeScaling>,
+    ) -> Result<Self, String> {
+        let config_data =
+            std::fs::read(config_path).map_err(|e| format!("Failed to read config.json: {}", e))?;
+        let config_json: serde_json::Value = serde_json::from_slice(&config_data)
+            .map_err(|e| format!("Failed to parse config.json: {}", e))?;
+        let config = Config::from_hf_json(&config_json, rope_scaling)?;
+        let max_seq_len =
+            rope_table_len(config.max_position_embeddings, config.rope_scaling.as_ref());
+
+        let vb = unsafe {
+            VarBuilder::from_mmaped_safetensors(filenames, dtype, device)
+                .map_err(|e| format!("Failed to load SafeTensors: {}", e))?
+        };
+
+        log::info!(
+            "Loading {:?}: dtype={:?}, sliding_window={:?}, softcapping attn={:?} final={:?}",
+            config.variant,
+            dtype,
+            config.sliding_window,
+            config.attn_logit_softcapping,
+            config.final_logit_softcapping
+        );
+
+        let inner =
+            Model::new(&config, vb).map_err(|e| format!("Failed to build Gemma model: {}", e))?;
+
+        Ok(Self::new_full(
+            inner,
+            config.variant,
+            device.clone(),
+            config.vocab_size,
+            max_seq_len,
+            OptimizationConfig::for_safetensors(dtype),
+        ))
+    }
+}
@@ -0,0 +1,321 @@
+//! GLM-4 configuration
+//!
+//! Поддерживаются три раскладки одной архитектуры:
+//! - `chatglm` — оригинальные GLM-4-9B / ChatGLM3 (`transformer.encoder.*`, слитый `query_key_value`);
+//! - `glm` — GLM-4-9B в формате transformers (`GlmForCausalLM`);
+//! - `glm4` — GLM-4-0414 с дополнительными нормализациями после внимания и MLP.
+//!
+//! Во всех RoPE с чередующимися парами применяется к половине головы.
+
+use std::collections::HashMap;
+
+use candle::quantized::gguf_file::Value;
+use serde::Deserialize;
+
+use crate::models::common::rope_scaling::rope_table_len;
+use crate::models::common::{RopeScaling, RopeShift};
+
+/// Раскладка весов GLM
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GlmVariant {
+    /// `ChatGLMModel`: GLM-4-9B, ChatGLM3
+    ChatGlm,
+    /// `GlmForCausalLM`
+    Glm,
+    /// `Glm4ForCausalLM` (GLM-4-0414): post-norm после внимания и MLP
+    Glm4,
+}
+
+impl GlmVariant {
+    /// Есть ли нормализации после внимания и MLP
+    pub fn has_post_norms(self) -> bool {
+        matches!(self, GlmVariant::Glm4)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub variant: GlmVariant,
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: usize,
+    pub head_dim: usize,
+    /// Число измерений головы, к которым применяется RoPE
+    pub rotary_dim: usize,
+    /// Смещения в проекциях Q/K/V
+    pub attention_bias: bool,
+    /// Смещение в выходной проекции внимания (`add_bias_linear` ChatGLM)
+    pub output_bias: bool,
+    pub rms_norm_eps: f64,
+    pub rope_theta: f64,
+    pub max_position_embeddings: usize,
+    pub rope_scaling: Option<RopeScaling>,
+    pub tie_word_embeddings: bool,
+}
+
+/// `config.json` оригинального ChatGLM
+#[derive(Debug, Deserialize)]
+struct ChatGlmConfig {
+    num_layers: usize,
+    padded_vocab_size: usize,
+    hidden_size: usize,
+    ffn_hidden_size: usize,
+    kv_channels: usize,
+    num_attention_heads: usize,
+    seq_length: usize,
+    layernorm_epsilon: Option<f64>,
+    #[serde(default)]
+    multi_query_attention: bool,
+    multi_query_group_num: Option<usize>,
+    #[serde(default)]
+    add_bias_linear: bool,
+    #[serde(default = "default_true")]
+    add_qkv_bias: bool,
+    rope_ratio: Option<f64>,
+}
+
+/// `config.json` в формате transformers (`glm` / `glm4`)
+#[derive(Debug, Deserialize)]
+struct HfConfig {
+    vocab_size: usize,
+    hidden_size: usize,
+    intermediate_size: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    num_key_value_heads: Option<usize>,
+    head_dim: Option<usize>,
+    partial_rotary_factor: Option<f64>,
+    attention_bias: Option<bool>,
+    rms_norm_eps: Option<f64>,
+    rope_theta: Option<f64>,
+    max_position_embeddings: usize,
+    #[serde(default)]
+    tie_word_embeddings: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Config {
+    /// Разбирает `config.json`; `rope_scaling` — override из запроса загрузки
+    pub fn from_hf_json(
+        config: &serde_json::Value,
+        rope_scaling: Option<RopeScaling>,
+    ) -> Result<Self, String> {
+        let model_type = config
+            .get("model_type")
+            .and_then(|v| v.as_str())
+            .unwrap_or_default();
+        let cfg = if model_type == "chatglm" || config.get("kv_channels").is_some() {
+            Self::from_chatglm_json(config, rope_scaling)?
+        } else {
+            let hf: HfConfig = serde_json::from_value(config.clone())
+                .map_err(|e| format!("Failed to parse GLM config.json: {}", e))?;
+            let head_dim = hf
+                .head_dim
+                .unwrap_or(hf.hidden_size / hf.num_attention_heads);
+            Self {
+                variant: if model_type == "glm4" {
+                    GlmVariant::Glm4
+                } else {
+                    GlmVariant::Glm
+                },
+                vocab_size: hf.vocab_size,
+                hidden_size: hf.hidden_size,
+                intermediate_size: hf.intermediate_size,
+                num_hidden_layers: hf.num_hidden_layers,
+                num_attention_heads: hf.num_attention_heads,
+                num_key_value_heads: hf.num_key_value_heads.unwrap_or(hf.num_attention_heads),
+                head_dim,
+                rotary_dim: (head_dim as f64 * hf.partial_rotary_factor.unwrap_or(0.5)) as usize,
+                attention_bias: hf.attention_bias.unwrap_or(true),
+                output_bias: false,
+                rms_norm_eps: hf.rms_norm_eps.unwrap_or(1e-5),
+                rope_theta: hf.rope_theta.unwrap_or(10_000.),
+                max_position_embeddings: hf.max_position_embeddings,
+                rope_scaling: rope_scaling
+                    .or_else(|| RopeScaling::from_config_json(config))
+                    .map(|s| s.resolve(hf.max_position_embeddings)),
+                tie_word_embeddings: hf.tie_word_embeddings,
+            }
+        };
+        cfg.validate()?;
+        Ok(cfg)
+    }
+
+    fn from_chatglm_json(
+        config: &serde_json::Value,
+        rope_scaling: Option<RopeScaling>,
+    ) -> Result<Self, String> {
+        let hf: ChatGlmConfig = serde_json::from_value(config.clone())
+            .map_err(|e| format!("Failed to parse ChatGLM config.json: {}", e))?;
+        let num_key_value_heads = if hf.multi_query_attention {
+            hf.multi_query_group_num.unwrap_or(1)
+        } else {
+            hf.num_attention_heads
+        };
+        Ok(Self {
+            variant: GlmVariant::ChatGlm,
+            vocab_size: hf.padded_vocab_size,
+            hidden_size: hf.hidden_size,
+            intermediate_size: hf.ffn_hidden_size,
+            num_hidden_layers: hf.num_layers,
+            num_attention_heads: hf.num_attention_heads,
+            num_key_value_heads,
+            head_dim: hf.kv_channels,
+            rotary_dim: hf.kv_channels / 2,
+            attention_bias: hf.add_qkv_bias || hf.add_bias_linear,
+            output_bias: hf.add_bias_linear,
+            rms_norm_eps: hf.layernorm_epsilon.unwrap_or(1e-5),
+            // ChatGLM масштабирует базу частот через rope_ratio (500 у моделей на 1M токенов)
+            rope_theta: 10_000. * hf.rope_ratio.unwrap_or(1.0),
+            max_position_embeddings: hf.seq_length,
+            rope_scaling: rope_scaling.map(|s| s.resolve(hf.seq_length)),
+            tie_word_embeddings: false,
+        })
+    }
+
+    /// Читает конфигурацию из метаданных GGUF (`chatglm.*` или `glm4.*`)
+    pub fn from_gguf(metadata: &HashMap<String, Value>) -> candle::Result<Self> {
+        let arch = metadata
+            .get("general.architecture")
+            .and_then(|v| v.to_string().ok())
+            .cloned()
+            .unwrap_or_else(|| "glm4".to_string());
+        let get = |key: &str| metadata.get(&format!("{arch}.{key}"));
+        let get_u32 = |key: &str| -> candle::Result<usize> {
+            get(key)
+                .ok_or_else(|| candle::Error::Msg(format!("cannot find {arch}.{key} in metadata")))?
+                .to_u32()
+                .map(|v| v as usize)
+        };
+        let get_f64 = |key: &str| get(key).and_then(|v| v.to_f32().ok()).map(f64::from);
+
+        let num_attention_heads = get_u32("attention.head_count")?;
+        let hidden_size = get_u32("embedding_length")?;
+        let head_dim = get_u32("attention.key_length").unwrap_or(hidden_size / num_attention_heads);
+        let max_position_embeddings = get_u32("context_length").unwrap_or(8192);
+        let vocab_size = metadata
+            .get("tokenizer.ggml.tokens")
+            .and_then(|v| v.to_vec().ok())
+            .map(|tokens| tokens.len())
+            .unwrap_or(151552);
+
+        let cfg = Self {
+            variant: if arch == "chatglm" {
+                GlmVariant::ChatGlm
+            } else {
+                GlmVariant::Glm4
+            },
+            vocab_size,
+            hidden_size,
+            intermediate_size: get_u32("feed_forward_length")?,
+            num_hidden_layers: get_u32("block_count")?,
+            num_attention_heads,
+            num_key_value_heads: get_u32("attention.head_count_kv").unwrap_or(num_attention_heads),
+            head_dim,
+            rotary_dim: get_u32("rope.dimension_count").unwrap_or(head_dim / 2),
+            attention_bias: true,
+            output_bias: false,
+            rms_norm_eps: get_f64("attention.layer_norm_rms_epsilon").unwrap_or(1e-5),
+            rope_theta: get_f64("rope.freq_base").unwrap_or(10_000.),
+            max_position_embeddings,
+            rope_scaling: RopeScaling::from_gguf(metadata, &arch)
+                .map(|s| s.resolve(max_position_embeddings)),
+            tie_word_embeddings: false,
+        };
+        cfg.validate().map_err(candle::Error::Msg)?;
+        Ok(cfg)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.num_key_value_heads == 0
+            || !self
+                .num_attention_heads
+                .is_multiple_of(self.num_key_value_heads)
+        {
+            return Err(format!(
+                "GLM config: {} attention heads are not divisible by {} KV heads",
+                self.num_attention_heads, self.num_key_value_heads
+            ));
+        }
+        if self.rotary_dim == 0
+            || self.rotary_dim > self.head_dim
+            || !self.rotary_dim.is_multiple_of(2)
+        {
+            return Err(format!(
+                "GLM config: invalid rotary dimension {} for head_dim {}",
+                self.rotary_dim, self.head_dim
+            ));
+        }
+        Ok(())
+    }
+
+    /// Длина таблиц RoPE
+    pub fn max_seq_len(&self) -> usize {
+        rope_table_len(self.max_position_embeddings, self.rope_scaling.as_ref())
+    }
+
+    /// Параметры ре-ротации ключей
+    pub fn rope_shift(&self) -> RopeShift {
+        RopeShift::interleaved(self.rotary_dim, self.rope_theta).with_scaling(self.rope_scaling)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_chatglm_config() {
+        let json = serde_json::json!({
+            "model_type": "chatglm",
+            "num_layers": 40,
+            "padded_vocab_size": 151552,
+            "hidden_size": 4096,
+            "ffn_hidden_size": 13696,
+            "kv_channels": 128,
+            "num_attention_heads": 32,
+            "seq_length": 131072,
+            "layernorm_epsilon": 1.5625e-07,
+            "multi_query_attention": true,
+            "multi_query_group_num": 2,
+            "add_bias_linear": false,
+            "add_qkv_bias": true,
+            "rope_ratio": 500
+        });
+        let cfg = Config::from_hf_json(&json, None).unwrap();
+        assert_eq!(cfg.variant, GlmVariant::ChatGlm);
+        assert_eq!(cfg.num_key_value_heads, 2);
+        assert_eq!(cfg.rotary_dim, 64);
+        assert!(cfg.attention_bias && !cfg.output_bias);
+        assert_eq!(cfg.rope_theta, 5_000_000.);
+        assert!(cfg.rope_shift().interleaved);
+    }
+
+    #[test]
+    fn parses_glm4_0414_config() {
+        let json = serde_json::json!({
+            "model_type": "glm4",
+            "vocab_size": 151552,
+            "hidden_size": 4096,
+            "intermediate_size": 13696,
+            "num_hidden_layers": 40,
+            "num_attention_heads": 32,
+            "num_key_value_heads": 2,
+            "head_dim": 128,
+            "partial_rotary_factor": 0.5,
+            "attention_bias": true,
+            "max_position_embeddings": 32768,
+            "rope_theta": 10000.0
+        });
+        let cfg = Config::from_hf_json(&json, None).unwrap();
+        assert_eq!(cfg.variant, GlmVariant::Glm4);
+        assert!(cfg.variant.has_post_norms());
+        assert_eq!(cfg.rotary_dim, 64);
+    }
+}
@@ -0,0 +1,48 @@
+//! GLM-4 GGUF loading
+//!
+//! Загрузка квантизированных GLM-4 моделей (`chatglm` и `glm4`) из GGUF формата.
+
+use candle::Device;
+use candle::quantized::gguf_file;
+
+use super::Glm4Backend;
+use super::quantized_model::ModelWeights;
+use crate::models::common::gguf_mmap::GgufRead;
+use crate::models::common::gguf_split::open_gguf;
+
+impl Glm4Backend {
+    /// Создаёт бекенд из GGUF Content
+    pub fn from_gguf<R: GgufRead>(
+        content: gguf_file::Content,
+        file: &mut R,
+        device: &Device,
+    ) -> Result<Self, String> {
+        let inner = ModelWeights::from_gguf(content, file, device)
+            .map_err(|e| format!("Failed to load GLM-4 GGUF model: {}", e))?;
+
+        let cfg = inner.config();
+        let vocab_size = cfg.vocab_size;
+        let max_seq_len = cfg.max_seq_len();
+
+        log::info!(
+            "Loading GLM-4 GGUF ({:?}): layers={}, rotary_dim={}/{}",
+            cfg.variant,
+            cfg.num_hidden_layers,
+            cfg.rotary_dim,
+            cfg.head_dim
+        );
+
+        Ok(Self::new_quantized(
+            inner,
+            device.clone(),
+            vocab_size,
+            max_seq_len,
+        ))
+    }
+
+    /// Создаёт бекенд из пути к GGUF файлу
+    pub fn from_gguf_path(path: &std::path::Path, device: &Device) -> Result<Self, String> {
+        let (content, mut reader) = open_gguf(path)?;
+        Self::from_gguf(content, &mut reader, device)
+    }
+}
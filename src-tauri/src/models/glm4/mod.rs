@@ -0,0 +1,211 @@
+//! GLM-4 model backend
+//!
+//! GLM-4-9B (в оригинальной раскладке ChatGLM и в формате transformers) и
+//! GLM-4-0414. Раскладка весов задаётся [`GlmVariant`].
+//!
+//! # Структура
+//! - `mod.rs` - общий Glm4Backend и ModelBackend реализация
+//! - `config.rs` - конфигурация из config.json или метаданных GGUF
+//! - `gguf.rs` - загрузка из GGUF формата
+//! - `safetensors.rs` - загрузка из SafeTensors формата
+//! - `model.rs` - модель полной точности
+//! - `quantized_model.rs` - квантизированная модель
+
+pub mod config;
+mod gguf;
+pub mod model;
+pub mod quantized_model;
+mod safetensors;
+
+pub use config::{Config, GlmVariant};
+
+use candle::{Device, Tensor};
+use quantized_model::ModelWeights as QuantizedGlm4;
+
+use crate::models::ModelBackend;
+use crate::models::api::optimization::OptimizationConfig;
+use crate::models::common::{KvCacheType, KvLayers, LayerKv, embeddings_unsupported};
+
+use model::Model;
+
+/// GLM-4 бекенд
+///
+/// Поддерживает как квантизированные (GGUF) так и полные (SafeTensors) модели.
+pub struct Glm4Backend {
+    inner: Glm4Inner,
+    device: Device,
+    vocab_size: usize,
+    max_seq_len: usize,
+    optimization: OptimizationConfig,
+}
+
+/// Внутреннее представление модели
+enum Glm4Inner {
+    /// Квантизированная модель из GGUF
+    Quantized(QuantizedGlm4),
+    /// Полная модель из SafeTensors
+    Full(Model),
+}
+
+impl Glm4Inner {
+    /// KV-кэши слоёв активной модели
+    fn kv_layers(&self) -> &dyn KvLayers {
+        match self {
+            Glm4Inner::Quantized(model) => model,
+            Glm4Inner::Full(model) => model,
+        }
+    }
+
+    fn kv_layers_mut(&mut self) -> &mut dyn KvLayers {
+        match self {
+            Glm4Inner::Quantized(model) => model,
+            Glm4Inner::Full(model) => model,
+        }
+    }
+}
+
+impl Glm4Backend {
+    /// Создаёт квантизированный бекенд (используется из gguf.rs)
+    pub(crate) fn new_quantized(
+        model: QuantizedGlm4,
+        device: Device,
+        vocab_size: usize,
+        max_seq_len: usize,
+    ) -> Self {
+        Self {
+            inner: Glm4Inner::Quantized(model),
+            device,
+            vocab_size,
+            max_seq_len,
+            optimization: OptimizationConfig::for_gguf(),
+        }
+    }
+
+    /// Создаёт полный бекенд (используется из safetensors.rs)
+    pub(crate) fn new_full(
+        model: Model,
+        device: Device,
+        vocab_size: usize,
+        max_seq_len: usize,
+        optimization: OptimizationConfig,
+    ) -> Self {
+        Self {
+            inner: Glm4Inner::Full(model),
+            device,
+            vocab_size,
+            max_seq_len,
+            optimization,
+        }
+    }
+
+    /// Возвращает устройство
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    /// Проверяет, квантизирована ли модель
+    pub fn is_quantized(&self) -> bool {
+        matches!(self.inner, Glm4Inner::Quantized(_))
+    }
+
+    /// Возвращает конфигурацию оптимизаций
+    pub fn optimization(&self) -> &OptimizationConfig {
+        &self.optimization
+    }
+}
+
+impl ModelBackend for Glm4Backend {
+    fn forward(&mut self, input: &Tensor, pos: usize) -> candle::Result<Tensor> {
+        match &mut self.inner {
+            // GGUF модель возвращает [batch, vocab_size] - только последний токен
+            Glm4Inner::Quantized(model) => model.forward(input, pos),
+            // [batch, 1, vocab_size] -> [batch, vocab_size]
+            Glm4Inner::Full(model) => model.forward(input, pos)?.squeeze(1),
+        }
+    }
+
+    fn clear_kv_cache(&mut self) {
+        match &mut self.inner {
+            Glm4Inner::Quantized(model) => model.clear_kv_cache(),
+            Glm4Inner::Full(model) => model.clear_kv_cache(),
+        }
+    }
+
+    fn model_type(&self) -> &str {
+        if self.is_quantized() {
+            "glm4-gguf"
+        } else {
+            "glm4"
+        }
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.vocab_size
+    }
+
+    fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+
+    fn supports_chunked_prefill(&self) -> bool {
+        true
+    }
+
+    fn forward_all(&mut self, input: &Tensor, pos: usize) -> candle::Result<Tensor> {
+        match &mut self.inner {
+            // [batch, seq_len, vocab_size] -> [seq_len, vocab_size]
+            Glm4Inner::Full(model) => model.forward_all(input, pos)?.squeeze(0),
+            Glm4Inner::Quantized(_) => {
+                candle::bail!("forward_all is not supported for GLM4-GGUF")
+            }
+        }
+    }
+
+    fn truncate_kv_cache(&mut self, len: usize) -> bool {
+        match &mut self.inner {
+            Glm4Inner::Full(model) => model.truncate_kv_cache(len).is_ok(),
+            Glm4Inner::Quantized(_) => false,
+        }
+    }
+
+    fn supports_speculative(&self) -> bool {
+        matches!(self.inner, Glm4Inner::Full(_))
+    }
+
+    fn supports_context_shift(&self) -> bool {
+        true
+    }
+
+    fn shift_kv_cache(&mut self, keep: usize, discard: usize) -> bool {
+        self.inner
+            .kv_layers_mut()
+            .shift_kv_cache(keep, discard)
+            .is_ok()
+    }
+
+    fn supports_kv_snapshot(&self) -> bool {
+        true
+    }
+
+    fn export_kv_cache(&self) -> candle::Result<Vec<LayerKv>> {
+        self.inner.kv_layers().export_kv_cache()
+    }
+
+    fn import_kv_cache(&mut self, layers: &[LayerKv]) -> candle::Result<()> {
+        self.inner.kv_layers_mut().import_kv_cache(layers)
+    }
+
+    fn set_kv_cache_type(&mut self, cache_type: KvCacheType) -> bool {
+        self.inner
+            .kv_layers_mut()
+            .set_kv_cache_type(cache_type)
+            .is_ok()
+    }
+
+    fn get_embeddings(&mut self, input: &Tensor) -> candle::Result<Tensor> {
+        match &mut self.inner {
+            Glm4Inner::Full(model) => model.get_hidden_states(input, 0),
+            Glm4Inner::Quantized(_) => Err(embeddings_unsupported("GLM4-GGUF")),
+        }
+    }
+}
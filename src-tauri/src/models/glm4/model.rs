@@ -0,0 +1,329 @@
+//! GLM-4 (SafeTensors)
+//!
+//! Одна реализация для раскладок `chatglm`, `glm` и `glm4` (см. [`GlmVariant`]):
+//! отличаются только имена весов, слитость Q/K/V и post-norm слои GLM-4-0414.
+
+use candle::{D, DType, Device, Module, Result, Tensor};
+use candle_nn::{Activation, VarBuilder};
+use candle_transformers::models::with_tracing::{Linear, RmsNorm, linear_b, linear_no_bias};
+use candle_transformers::utils::repeat_kv;
+use std::sync::Arc;
+
+use super::config::{Config, GlmVariant};
+use crate::models::common::{KvCache, KvLayers, PartialRotaryEmbedding, RopeShift, causal_mask};
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    /// Выход [gate | up] (`gate_up_proj` / `dense_h_to_4h`)
+    gate_up_proj: Linear,
+    down_proj: Linear,
+    intermediate_size: usize,
+}
+
+impl Mlp {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let (gate_up, down) = match cfg.variant {
+            GlmVariant::ChatGlm => ("dense_h_to_4h", "dense_4h_to_h"),
+            GlmVariant::Glm | GlmVariant::Glm4 => ("gate_up_proj", "down_proj"),
+        };
+        Ok(Self {
+            gate_up_proj: linear_b(
+                cfg.hidden_size,
+                2 * cfg.intermediate_size,
+                cfg.output_bias,
+                vb.pp(gate_up),
+            )?,
+            down_proj: linear_b(
+                cfg.intermediate_size,
+                cfg.hidden_size,
+                cfg.output_bias,
+                vb.pp(down),
+            )?,
+            intermediate_size: cfg.intermediate_size,
+        })
+    }
+}
+
+impl Module for Mlp {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let up_states = self.gate_up_proj.forward(x)?;
+        let gate = up_states.narrow(D::Minus1, 0, self.intermediate_size)?;
+        let up = up_states.narrow(D::Minus1, self.intermediate_size, self.intermediate_size)?;
+        self.down_proj
+            .forward(&(gate.apply(&Activation::Silu)? * up)?)
+    }
+}
+
+/// Проекции Q/K/V: слитая `query_key_value` (ChatGLM) или раздельные
+#[derive(Debug, Clone)]
+enum QkvProj {
+    Fused(Linear),
+    Split { q: Linear, k: Linear, v: Linear },
+}
+
+#[derive(Debug, Clone)]
+struct Attention {
+    qkv: QkvProj,
+    o_proj: Linear,
+    num_heads: usize,
+    num_kv_heads: usize,
+    num_kv_groups: usize,
+    head_dim: usize,
+    rotary_emb: Arc<PartialRotaryEmbedding>,
+    kv_cache: KvCache,
+}
+
+impl Attention {
+    fn new(cfg: &Config, rotary_emb: Arc<PartialRotaryEmbedding>, vb: VarBuilder) -> Result<Self> {
+        let head_dim = cfg.head_dim;
+        let q_dim = cfg.num_attention_heads * head_dim;
+        let kv_dim = cfg.num_key_value_heads * head_dim;
+        let bias = cfg.attention_bias;
+        let (qkv, o_proj) = match cfg.variant {
+            GlmVariant::ChatGlm => (
+                QkvProj::Fused(linear_b(
+                    cfg.hidden_size,
+                    q_dim + 2 * kv_dim,
+                    bias,
+                    vb.pp("query_key_value"),
+                )?),
+                linear_b(q_dim, cfg.hidden_size, cfg.output_bias, vb.pp("dense"))?,
+            ),
+            GlmVariant::Glm | GlmVariant::Glm4 => (
+                QkvProj::Split {
+                    q: linear_b(cfg.hidden_size, q_dim, bias, vb.pp("q_proj"))?,
+                    k: linear_b(cfg.hidden_size, kv_dim, bias, vb.pp("k_proj"))?,
+                    v: linear_b(cfg.hidden_size, kv_dim, bias, vb.pp("v_proj"))?,
+                },
+                linear_no_bias(q_dim, cfg.hidden_size, vb.pp("o_proj"))?,
+            ),
+        };
+        Ok(Self {
+            qkv,
+            o_proj,
+            num_heads: cfg.num_attention_heads,
+            num_kv_heads: cfg.num_key_value_heads,
+            num_kv_groups: cfg.num_attention_heads / cfg.num_key_value_heads,
+            head_dim,
+            rotary_emb,
+            kv_cache: KvCache::new(2),
+        })
+    }
+
+    fn forward(&mut self, x: &Tensor, mask: Option<&Tensor>, offset: usize) -> Result<Tensor> {
+        let (b, l, _) = x.dims3()?;
+        let q_dim = self.num_heads * self.head_dim;
+        let kv_dim = self.num_kv_heads * self.head_dim;
+        let (q, k, v) = match &self.qkv {
+            QkvProj::Fused(proj) => {
+                let qkv = proj.forward(x)?;
+                (
+                    qkv.narrow(D::Minus1, 0, q_dim)?,
+                    qkv.narrow(D::Minus1, q_dim, kv_dim)?,
+                    qkv.narrow(D::Minus1, q_dim + kv_dim, kv_dim)?,
+                )
+            }
+            QkvProj::Split { q, k, v } => (q.forward(x)?, k.forward(x)?, v.forward(x)?),
+        };
+        let q = q
+            .reshape((b, l, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let k = k
+            .reshape((b, l, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let v = v
+            .reshape((b, l, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+
+        let (q, k) = self.rotary_emb.apply(&q, &k, offset)?;
+        let (k, v) = self.kv_cache.append(&k, &v)?;
+
+        let k = repeat_kv(k, self.num_kv_groups)?.contiguous()?;
+        let v = repeat_kv(v, self.num_kv_groups)?.contiguous()?;
+
+        let scale = 1.0 / (self.head_dim as f64).sqrt();
+        let mut scores = (q.matmul(&k.transpose(2, 3)?)? * scale)?;
+        if let Some(m) = mask {
+            scores = scores.broadcast_add(&m.to_dtype(scores.dtype())?)?;
+        }
+        let probs = candle_nn::ops::softmax_last_dim(&scores)?;
+        let ctx = probs.matmul(&v)?;
+        let ctx = ctx.transpose(1, 2)?.reshape((b, l, q_dim))?;
+        self.o_proj.forward(&ctx)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DecoderLayer {
+    self_attn: Attention,
+    mlp: Mlp,
+    input_layernorm: RmsNorm,
+    post_attention_layernorm: RmsNorm,
+    /// GLM-4-0414: нормализации выходов внимания и MLP перед residual
+    post_self_attn_layernorm: Option<RmsNorm>,
+    post_mlp_layernorm: Option<RmsNorm>,
+}
+
+impl DecoderLayer {
+    fn new(cfg: &Config, rotary_emb: Arc<PartialRotaryEmbedding>, vb: VarBuilder) -> Result<Self> {
+        let norm = |name: &str| RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb.pp(name));
+        let attn_name = match cfg.variant {
+            GlmVariant::ChatGlm => "self_attention",
+            GlmVariant::Glm | GlmVariant::Glm4 => "self_attn",
+        };
+        let (post_self_attn_layernorm, post_mlp_layernorm) = if cfg.variant.has_post_norms() {
+            (
+                Some(norm("post_self_attn_layernorm")?),
+                Some(norm("post_mlp_layernorm")?),
+            )
+        } else {
+            (None, None)
+        };
+        Ok(Self {
+            self_attn: Attention::new(cfg, rotary_emb, vb.pp(attn_name))?,
+            mlp: Mlp::new(cfg, vb.pp("mlp"))?,
+            input_layernorm: norm("input_layernorm")?,
+            post_attention_layernorm: norm("post_attention_layernorm")?,
+            post_self_attn_layernorm,
+            post_mlp_layernorm,
+        })
+    }
+
+    fn forward(&mut self, x: &Tensor, mask: Option<&Tensor>, offset: usize) -> Result<Tensor> {
+        let h = self.input_layernorm.forward(x)?;
+        let mut h = self.self_attn.forward(&h, mask, offset)?;
+        if let Some(norm) = &self.post_self_attn_layernorm {
+            h = norm.forward(&h)?;
+        }
+        let x = (x + h)?;
+        let mut h = self
+            .post_attention_layernorm
+            .forward(&x)?
+            .apply(&self.mlp)?;
+        if let Some(norm) = &self.post_mlp_layernorm {
+            h = norm.forward(&h)?;
+        }
+        x + h
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Model {
+    embed_tokens: candle_nn::Embedding,
+    layers: Vec<DecoderLayer>,
+    norm: RmsNorm,
+    lm_head: Linear,
+    rope: RopeShift,
+    device: Device,
+    dtype: DType,
+}
+
+impl Model {
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let (embed_name, layers_name, norm_name) = match cfg.variant {
+            GlmVariant::ChatGlm => (
+                "transformer.embedding.word_embeddings",
+                "transformer.encoder.layers",
+                "transformer.encoder.final_layernorm",
+            ),
+            GlmVariant::Glm | GlmVariant::Glm4 => {
+                ("model.embed_tokens", "model.layers", "model.norm")
+            }
+        };
+        let embed_tokens =
+            candle_nn::embedding(cfg.vocab_size, cfg.hidden_size, vb.pp(embed_name))?;
+        let rotary_emb = Arc::new(
+            PartialRotaryEmbedding::new(
+                vb.dtype(),
+                cfg.rotary_dim,
+                cfg.rope_theta,
+                cfg.max_seq_len(),
+                cfg.rope_scaling.as_ref(),
+                None,
+                vb.device(),
+            )?
+            .interleaved(),
+        );
+        let vb_l = vb.pp(layers_name);
+        let layers = (0..cfg.num_hidden_layers)
+            .map(|i| DecoderLayer::new(cfg, rotary_emb.clone(), vb_l.pp(i)))
+            .collect::<Result<Vec<_>>>()?;
+        let lm_head = match cfg.variant {
+            GlmVariant::ChatGlm => linear_no_bias(
+                cfg.hidden_size,
+                cfg.vocab_size,
+                vb.pp("transformer.output_layer"),
+            )?,
+            _ if cfg.tie_word_embeddings => {
+                Linear::from_weights(embed_tokens.embeddings().clone(), None)
+            }
+            _ => linear_no_bias(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head"))?,
+        };
+        Ok(Self {
+            embed_tokens,
+            layers,
+            norm: RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb.pp(norm_name))?,
+            lm_head,
+            rope: cfg.rope_shift(),
+            device: vb.device().clone(),
+            dtype: vb.dtype(),
+        })
+    }
+
+    /// Скрытые состояния после финальной нормализации [batch, seq_len, hidden_size]
+    fn forward_hidden(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        let (b, l) = input.dims2()?;
+        let mut h = self.embed_tokens.forward(input)?;
+        let mask = if l == 1 {
+            None
+        } else {
+            Some(causal_mask(b, l, offset, self.dtype, &self.device)?)
+        };
+        for layer in &mut self.layers {
+            h = layer.forward(&h, mask.as_ref(), offset)?;
+        }
+        self.norm.forward(&h)
+    }
+
+    /// Логиты последней позиции [batch, 1, vocab_size]
+    pub fn forward(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        let (_, l) = input.dims2()?;
+        self.forward_hidden(input, offset)?
+            .narrow(1, l - 1, 1)?
+            .apply(&self.lm_head)
+    }
+
+    /// Returns logits for every input position [batch, seq_len, vocab_size]
+    pub fn forward_all(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        self.forward_hidden(input, offset)?.apply(&self.lm_head)
+    }
+
+    /// Returns hidden states of the last layer after normalization [batch, seq_len, hidden_size]
+    pub fn get_hidden_states(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        self.forward_hidden(input, offset)
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        for layer in &mut self.layers {
+            layer.self_attn.kv_cache.reset();
+        }
+    }
+}
+
+impl KvLayers for Model {
+    fn kv_caches(&self) -> Vec<&KvCache> {
+        self.layers.iter().map(|l| &l.self_attn.kv_cache).collect()
+    }
+
+    fn kv_caches_mut(&mut self) -> Vec<&mut KvCache> {
+        self.layers
+            .iter_mut()
+            .map(|l| &mut l.self_attn.kv_cache)
+            .collect()
+    }
+
+    fn shift_rope(&self, _layer: usize) -> Result<RopeShift> {
+        Ok(self.rope)
+    }
+}
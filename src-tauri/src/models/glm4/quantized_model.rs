@@ -0,0 +1,296 @@
+//! Квантизированная GLM-4 из GGUF
+//!
+//! Аналог candle_transformers::models::quantized_glm4 с открытым KV-кэшем.
+//! Читает обе архитектуры llama.cpp: `chatglm` (GLM-4-9B, слитый `attn_qkv`)
+//! и `glm4` (GLM-4-0414, `post_attention_norm` / `post_ffw_norm`).
+
+use crate::models::common::gguf_mmap::{Gguf, GgufRead};
+use candle::quantized::gguf_file;
+use candle::{D, DType, Device, Module, Result, Tensor};
+use candle_nn::{Activation, Embedding};
+use candle_transformers::models::with_tracing::QMatMul;
+use candle_transformers::quantized_nn::RmsNorm;
+use candle_transformers::utils::repeat_kv;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use super::config::Config;
+use crate::models::common::{KvCache, KvLayers, PartialRotaryEmbedding, RopeShift, causal_mask};
+
+/// Квантизированный линейный слой с необязательным смещением
+#[derive(Debug, Clone)]
+struct QLinear {
+    weight: QMatMul,
+    bias: Option<Tensor>,
+}
+
+impl QLinear {
+    fn load<R: GgufRead>(
+        gg: &mut Gguf<R>,
+        names: &HashSet<String>,
+        name: &str,
+        device: &Device,
+    ) -> Result<Self> {
+        let bias_name = format!("{name}.bias");
+        let bias = if names.contains(&bias_name) {
+            Some(gg.tensor(&bias_name)?.dequantize(device)?)
+        } else {
+            None
+        };
+        Ok(Self {
+            weight: gg.qmatmul(&format!("{name}.weight"))?,
+            bias,
+        })
+    }
+}
+
+impl Module for QLinear {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let y = self.weight.forward(x)?;
+        match &self.bias {
+            Some(bias) => y.broadcast_add(bias),
+            None => Ok(y),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum QkvProj {
+    Fused(QLinear),
+    Split { q: QLinear, k: QLinear, v: QLinear },
+}
+
+#[derive(Debug, Clone)]
+struct LayerWeights {
+    qkv: QkvProj,
+    attn_output: QLinear,
+    attn_norm: RmsNorm,
+    attn_post_norm: Option<RmsNorm>,
+    /// Выход [gate | up]
+    ffn_up: QLinear,
+    ffn_down: QLinear,
+    ffn_norm: RmsNorm,
+    ffn_post_norm: Option<RmsNorm>,
+    num_heads: usize,
+    num_kv_heads: usize,
+    num_kv_groups: usize,
+    head_dim: usize,
+    intermediate_size: usize,
+    rotary_emb: Arc<PartialRotaryEmbedding>,
+    kv_cache: KvCache,
+}
+
+impl LayerWeights {
+    fn attention(&mut self, x: &Tensor, mask: Option<&Tensor>, offset: usize) -> Result<Tensor> {
+        let (b, l, _) = x.dims3()?;
+        let q_dim = self.num_heads * self.head_dim;
+        let kv_dim = self.num_kv_heads * self.head_dim;
+        let (q, k, v) = match &self.qkv {
+            QkvProj::Fused(proj) => {
+                let qkv = proj.forward(x)?;
+                (
+                    qkv.narrow(D::Minus1, 0, q_dim)?,
+                    qkv.narrow(D::Minus1, q_dim, kv_dim)?,
+                    qkv.narrow(D::Minus1, q_dim + kv_dim, kv_dim)?,
+                )
+            }
+            QkvProj::Split { q, k, v } => (q.forward(x)?, k.forward(x)?, v.forward(x)?),
+        };
+        let q = q
+            .reshape((b, l, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let k = k
+            .reshape((b, l, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let v = v
+            .reshape((b, l, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+
+        let (q, k) = self.rotary_emb.apply(&q, &k, offset)?;
+        let (k, v) = self.kv_cache.append(&k, &v)?;
+
+        let k = repeat_kv(k, self.num_kv_groups)?.contiguous()?;
+        let v = repeat_kv(v, self.num_kv_groups)?.contiguous()?;
+
+        let scale = 1.0 / (self.head_dim as f64).sqrt();
+        let mut scores = (q.matmul(&k.transpose(2, 3)?)? * scale)?;
+        if let Some(m) = mask {
+            scores = scores.broadcast_add(&m.to_dtype(scores.dtype())?)?;
+        }
+        let probs = candle_nn::ops::softmax_last_dim(&scores)?;
+        let ctx = probs.matmul(&v)?;
+        let ctx = ctx.transpose(1, 2)?.reshape((b, l, q_dim))?;
+        self.attn_output.forward(&ctx)
+    }
+
+    fn mlp(&self, x: &Tensor) -> Result<Tensor> {
+        let up_states = self.ffn_up.forward(x)?;
+        let n = self.intermediate_size;
+        let gate = up_states.narrow(D::Minus1, 0, n)?;
+        let up = up_states.narrow(D::Minus1, n, n)?;
+        self.ffn_down
+            .forward(&(gate.apply(&Activation::Silu)? * up)?)
+    }
+
+    fn forward(&mut self, x: &Tensor, mask: Option<&Tensor>, offset: usize) -> Result<Tensor> {
+        let h = self.attn_norm.forward(x)?;
+        let mut h = self.attention(&h, mask, offset)?;
+        if let Some(norm) = &self.attn_post_norm {
+            h = norm.forward(&h)?;
+        }
+        let x = (x + h)?;
+        let mut h = self.mlp(&self.ffn_norm.forward(&x)?)?;
+        if let Some(norm) = &self.ffn_post_norm {
+            h = norm.forward(&h)?;
+        }
+        x + h
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ModelWeights {
+    embed_tokens: Embedding,
+    layers: Vec<LayerWeights>,
+    norm: RmsNorm,
+    lm_head: QMatMul,
+    cfg: Config,
+    rope: RopeShift,
+    device: Device,
+    dtype: DType,
+}
+
+impl ModelWeights {
+    pub fn from_gguf<R: GgufRead>(
+        ct: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+    ) -> Result<Self> {
+        let cfg = Config::from_gguf(&ct.metadata)?;
+        let names: HashSet<String> = ct.tensor_infos.keys().cloned().collect();
+        let mut gg = Gguf::new(ct, reader, device.clone());
+        let dtype = DType::F32;
+
+        let embed_tensor = gg.tensor("token_embd.weight")?;
+        let embed_tokens = Embedding::new(embed_tensor.dequantize(device)?, cfg.hidden_size);
+        let lm_head = if names.contains("output.weight") {
+            gg.qmatmul("output.weight")?
+        } else {
+            QMatMul::from_weights(Arc::new(embed_tensor))?
+        };
+
+        let rotary_emb = Arc::new(
+            PartialRotaryEmbedding::new(
+                dtype,
+                cfg.rotary_dim,
+                cfg.rope_theta,
+                cfg.max_seq_len(),
+                cfg.rope_scaling.as_ref(),
+                None,
+                device,
+            )?
+            .interleaved(),
+        );
+
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        for i in 0..cfg.num_hidden_layers {
+            let prefix = format!("blk.{i}");
+            let mut linear =
+                |name: &str| QLinear::load(&mut gg, &names, &format!("{prefix}.{name}"), device);
+            let qkv = if names.contains(&format!("{prefix}.attn_qkv.weight")) {
+                QkvProj::Fused(linear("attn_qkv")?)
+            } else {
+                QkvProj::Split {
+                    q: linear("attn_q")?,
+                    k: linear("attn_k")?,
+                    v: linear("attn_v")?,
+                }
+            };
+            let attn_output = linear("attn_output")?;
+            let ffn_up = linear("ffn_up")?;
+            let ffn_down = linear("ffn_down")?;
+
+            let mut norm = |name: &str| -> Result<Option<RmsNorm>> {
+                let name = format!("{prefix}.{name}.weight");
+                if names.contains(&name) {
+                    Ok(Some(gg.rms_norm(&name, cfg.rms_norm_eps)?))
+                } else {
+                    Ok(None)
+                }
+            };
+            let attn_post_norm = norm("post_attention_norm")?;
+            let ffn_post_norm = norm("post_ffw_norm")?;
+
+            layers.push(LayerWeights {
+                qkv,
+                attn_output,
+                attn_norm: gg.rms_norm(&format!("{prefix}.attn_norm.weight"), cfg.rms_norm_eps)?,
+                attn_post_norm,
+                ffn_up,
+                ffn_down,
+                ffn_norm: gg.rms_norm(&format!("{prefix}.ffn_norm.weight"), cfg.rms_norm_eps)?,
+                ffn_post_norm,
+                num_heads: cfg.num_attention_heads,
+                num_kv_heads: cfg.num_key_value_heads,
+                num_kv_groups: cfg.num_attention_heads / cfg.num_key_value_heads,
+                head_dim: cfg.head_dim,
+                intermediate_size: cfg.intermediate_size,
+                rotary_emb: rotary_emb.clone(),
+                kv_cache: KvCache::new(2),
+            });
+        }
+
+        Ok(Self {
+            embed_tokens,
+            layers,
+            norm: gg.rms_norm("output_norm.weight", cfg.rms_norm_eps)?,
+            lm_head,
+            rope: cfg.rope_shift(),
+            cfg,
+            device: device.clone(),
+            dtype,
+        })
+    }
+
+    /// Конфигурация модели, прочитанная из GGUF
+    pub fn config(&self) -> &Config {
+        &self.cfg
+    }
+
+    /// Логиты последней позиции [batch, vocab_size]
+    pub fn forward(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        let (b, l) = input.dims2()?;
+        let mut h = self.embed_tokens.forward(input)?;
+        let mask = if l == 1 {
+            None
+        } else {
+            Some(causal_mask(b, l, offset, self.dtype, &self.device)?)
+        };
+        for layer in &mut self.layers {
+            h = layer.forward(&h, mask.as_ref(), offset)?;
+        }
+        let h = self.norm.forward(&h)?;
+        let last_hidden = h.narrow(1, l - 1, 1)?;
+        self.lm_head.forward(&last_hidden)?.squeeze(1)
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        for layer in &mut self.layers {
+            layer.kv_cache.reset();
+        }
+    }
+}
+
+impl KvLayers for ModelWeights {
+    fn kv_caches(&self) -> Vec<&KvCache> {
+        self.layers.iter().map(|l| &l.kv_cache).collect()
+    }
+
+    fn kv_caches_mut(&mut self) -> Vec<&mut KvCache> {
+        self.layers.iter_mut().map(|l| &mut l.kv_cache).collect()
+    }
+
+    fn shift_rope(&self, _layer: usize) -> Result<RopeShift> {
+        Ok(self.rope)
+    }
+}
@@ -0,0 +1,54 @@
+//! GLM-4 SafeTensors loading
+//!
+//! Загрузка GLM-4 и ChatGLM моделей из SafeTensors формата.
+
+use candle::{DType, Device};
+use candle_nn::VarBuilder;
+use std::path::{Path, PathBuf};
+
+use super::Glm4Backend;
+use super::config::Config;
+use super::model::Model;
+use crate::models::api::optimization::OptimizationConfig;
+use crate::models::common::RopeScaling;
+
+impl Glm4Backend {
+    /// Создаёт бекенд из SafeTensors файлов
+    pub fn from_safetensors(
+        filenames: &[PathBuf],
+        config_path: &Path,
+        device: &Device,
+        dtype: DType,
+        rope_scaling: Option<RopeScaling>,
+    ) -> Result<Self, String> {
+        let config_data =
+            std::fs::read(config_path).map_err(|e| format!("Failed to read config.json: {}", e))?;
+        let config_json: serde_json::Value = serde_json::from_slice(&config_data)
+            .map_err(|e| format!("Failed to parse config.json: {}", e))?;
+        let config = Config::from_hf_json(&config_json, rope_scaling)?;
+
+        let vb = unsafe {
+            VarBuilder::from_mmaped_safetensors(filenames, dtype, device)
+                .map_err(|e| format!("Failed to load SafeTensors: {}", e))?
+        };
+
+        log::info!(
+            "Loading GLM-4 ({:?}): dtype={:?}, rotary_dim={}/{}",
+            config.variant,
+            dtype,
+            config.rotary_dim,
+            config.head_dim
+        );
+
+        let inner =
+            Model::new(&config, vb).map_err(|e| format!("Failed to build GLM-4 model: {}", e))?;
+
+        Ok(Self::new_full(
+            inner,
+            device.clone(),
+            config.vocab_size,
+            config.max_seq_len(),
+            OptimizationConfig::for_safetensors(dtype),
+        ))
+    }
+}
@@ -2,16 +2,19 @@
 
 use candle::Device;
 use candle::quantized::gguf_file;
-use candle_transformers::models::quantized_llama::ModelWeights;
-use std::fs::File;
 
 use super::LlamaBackend;
+use super::quantized_model::ModelWeights;
+use crate::models::common::RopeScaling;
+use crate::models::common::gguf_mmap::GgufRead;
+use crate::models::common::gguf_split::open_gguf;
+use crate::models::common::rope_scaling::rope_table_len;
 
 impl LlamaBackend {
     /// Создаёт бекенд из GGUF Content
-    pub fn from_gguf(
+    pub fn from_gguf<R: GgufRead>(
         content: gguf_file::Content,
-        file: &mut File,
+        file: &mut R,
         device: &Device,
     ) -> Result<Self, String> {
         let vocab_size = content
@@ -28,6 +31,8 @@ impl LlamaBackend {
             .or_else(|| content.metadata.get("mistral.context_length"))
             .and_then(|v| v.to_u32().ok())
             .unwrap_or(4096) as usize;
+        let rope_scaling = RopeScaling::from_gguf(&content.metadata, "llama");
+        let max_seq_len = rope_table_len(max_seq_len, rope_scaling.as_ref());
 
         let inner = ModelWeights::from_gguf(content, file, device)
             .map_err(|e| format!("Failed to load Llama GGUF model: {}", e))?;
@@ -41,9 +46,7 @@ impl LlamaBackend {
     }
 
     pub fn from_gguf_path(path: &std::path::Path, device: &Device) -> Result<Self, String> {
-        let mut file = File::open(path).map_err(|e| format!("Failed to open GGUF file: {}", e))?;
-        let content = gguf_file::Content::read(&mut file)
-            .map_err(|e| format!("Failed to read GGUF header: {}", e))?;
-        Self::from_gguf(content, &mut file, device)
+        let (content, mut reader) = open_gguf(path)?;
+        Self::from_gguf(content, &mut reader, device)
     }
 }
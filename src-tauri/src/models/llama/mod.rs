@@ -7,16 +7,19 @@
 //! - `mod.rs` - общий LlamaBackend и ModelBackend реализация
 //! - `gguf.rs` - загрузка из GGUF формата
 //! - `safetensors.rs` - загрузка из SafeTensors формата
+//! - `quantized_model.rs` - квантизированная модель с доступом к KV-кэшу
 
 mod gguf;
+pub mod quantized_model;
 mod safetensors;
 
 use candle::{Device, Tensor};
 use candle_transformers::models::llama::{Cache, Llama};
-use candle_transformers::models::quantized_llama::ModelWeights;
+use quantized_model::ModelWeights;
 
 use crate::models::ModelBackend;
 use crate::models::api::optimization::{OptimizationConfig, WeightFormat};
+use crate::models::common::{KvCacheType, LayerKv, LoraAdapter, embeddings_unsupported};
 
 /// Внутреннее представление модели
 enum LlamaInner {
@@ -89,21 +92,14 @@ impl ModelBackend for LlamaBackend {
     fn forward(&mut self, input: &Tensor, pos: usize) -> candle::Result<Tensor> {
         match &mut self.inner {
             LlamaInner::Quantized(model) => model.forward(input, pos),
-            LlamaInner::Full { model, cache } => {
-                let logits = model.forward(input, pos, cache)?;
-                let seq_len = logits.dim(1)?;
-                // Берём логиты последнего токена: [batch, vocab_size]
-                logits.narrow(1, seq_len - 1, 1)?.squeeze(1)
-            }
+            // candle Llama уже возвращает логиты последнего токена: [batch, vocab_size]
+            LlamaInner::Full { model, cache } => model.forward(input, pos, cache),
         }
     }
 
     fn clear_kv_cache(&mut self) {
         match &mut self.inner {
-            LlamaInner::Quantized(_) => {
-                // quantized_llama::ModelWeights автоматически сбрасывает kv_cache
-                // при index_pos == 0 в forward()
-            }
+            LlamaInner::Quantized(model) => model.clear_kv_cache(),
             LlamaInner::Full { .. } => {
                 // Для full модели сбрасываем cache
                 // Cache имеет внутреннюю структуру, которую можно пересоздать или сбросить,
@@ -139,4 +135,240 @@ impl ModelBackend for LlamaBackend {
     fn supports_flash_attn(&self) -> bool {
         self.optimization.uses_flash_attn()
     }
+
+    fn supports_context_shift(&self) -> bool {
+        matches!(self.inner, LlamaInner::Quantized(_))
+    }
+
+    fn shift_kv_cache(&mut self, keep: usize, discard: usize) -> bool {
+        match &mut self.inner {
+            LlamaInner::Quantized(model) => model.shift_kv_cache(keep, discard).is_ok(),
+            // candle Llama Cache не даёт доступа к KV-тензорам
+            LlamaInner::Full { .. } => false,
+        }
+    }
+
+    fn supports_kv_snapshot(&self) -> bool {
+        matches!(self.inner, LlamaInner::Quantized(_))
+    }
+
+    fn export_kv_cache(&self) -> candle::Result<Vec<LayerKv>> {
+        match &self.inner {
+            LlamaInner::Quantized(model) => model
+                .export_kv_cache()?
+                .ok_or_else(|| candle::Error::Msg("KV cache is empty".into())),
+            LlamaInner::Full { .. } => {
+                candle::bail!("KV cache export is not supported for safetensors Llama")
+            }
+        }
+    }
+
+    fn import_kv_cache(&mut self, layers: &[LayerKv]) -> candle::Result<()> {
+        match &mut self.inner {
+            LlamaInner::Quantized(model) => model.import_kv_cache(layers),
+            LlamaInner::Full { .. } => {
+                candle::bail!("KV cache import is not supported for safetensors Llama")
+            }
+        }
+    }
+
+    fn set_kv_cache_type(&mut self, cache_type: KvCacheType) -> bool {
+        match &mut self.inner {
+            LlamaInner::Quantized(model) => model.set_kv_cache_type(cache_type).is_ok(),
+            // candle Llama Cache хранит KV в dtype модели
+            LlamaInner::Full { .. } => cache_type == KvCacheType::Full,
+        }
+    }
+
+    fn supports_lora(&self) -> bool {
+        matches!(self.inner, LlamaInner::Quantized(_))
+    }
+
+    fn set_lora_adapters(&mut self, adapters: &[LoraAdapter]) -> candle::Result<()> {
+        match &mut self.inner {
+            LlamaInner::Quantized(model) => model.set_lora_adapters(adapters),
+            // candle Llama не даёт доступа к слоям: адаптеры вливаются в веса при загрузке
+            LlamaInner::Full { .. } => candle::bail!(
+                "LoRA adapters of safetensors Llama are merged at load time; reload the model to change them"
+            ),
+        }
+    }
+
+    // candle Llama принимает один index_pos на батч и не даёт доступа к KV:
+    // continuous batching только для GGUF
+    fn supports_batching(&self) -> bool {
+        matches!(self.inner, LlamaInner::Quantized(_))
+    }
+
+    fn prefill_slot(&mut self, slot: usize, input: &Tensor, pos: usize) -> candle::Result<Tensor> {
+        match &mut self.inner {
+            LlamaInner::Quantized(model) => model.prefill_slot(slot, input, pos),
+            LlamaInner::Full { .. } => {
+                candle::bail!("Cache slots are not supported for safetensors Llama")
+            }
+        }
+    }
+
+    fn forward_batch(
+        &mut self,
+        tokens: &[u32],
+        slots: &[usize],
+        positions: &[usize],
+    ) -> candle::Result<Tensor> {
+        match &mut self.inner {
+            LlamaInner::Quantized(model) => model.forward_batch(tokens, slots, positions),
+            LlamaInner::Full { .. } => {
+                candle::bail!("Batched decoding is not supported for safetensors Llama")
+            }
+        }
+    }
+
+    fn release_slot(&mut self, slot: usize) {
+        if let LlamaInner::Quantized(model) = &mut self.inner {
+            model.release_slot(slot);
+        }
+    }
+
+    fn get_embeddings(&mut self, input: &Tensor) -> candle::Result<Tensor> {
+        match &mut self.inner {
+            LlamaInner::Quantized(model) => model.get_hidden_states(input, 0),
+            // candle Llama отдаёт только логиты последнего токена: эмбеддинги
+            // доступны для GGUF Llama или энкодера из слота эмбеддингов
+            LlamaInner::Full { .. } => Err(embeddings_unsupported("Llama SafeTensors")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use candle::quantized::{GgmlDType, QTensor, gguf_file};
+
+    use super::*;
+
+    const VOCAB: usize = 48;
+
+    /// Маленькая случайная Llama в GGUF (F32), загруженная из памяти
+    fn tiny_backend(device: &Device) -> candle::Result<LlamaBackend> {
+        let (hidden, ff, layers) = (32, 64, 2);
+        let metadata = [
+            ("llama.attention.head_count", gguf_file::Value::U32(4)),
+            ("llama.attention.head_count_kv", gguf_file::Value::U32(2)),
+            ("llama.block_count", gguf_file::Value::U32(layers as u32)),
+            (
+                "llama.embedding_length",
+                gguf_file::Value::U32(hidden as u32),
+            ),
+            ("llama.rope.dimension_count", gguf_file::Value::U32(8)),
+            (
+                "llama.attention.layer_norm_rms_epsilon",
+                gguf_file::Value::F32(1e-5),
+            ),
+        ];
+        let mut shapes = vec![
+            ("token_embd.weight".to_string(), vec![VOCAB, hidden]),
+            ("output_norm.weight".to_string(), vec![hidden]),
+            ("output.weight".to_string(), vec![VOCAB, hidden]),
+        ];
+        for i in 0..layers {
+            let p = format!("blk.{i}");
+            shapes.extend([
+                (format!("{p}.attn_q.weight"), vec![hidden, hidden]),
+                (format!("{p}.attn_k.weight"), vec![hidden / 2, hidden]),
+                (format!("{p}.attn_v.weight"), vec![hidden / 2, hidden]),
+                (format!("{p}.attn_output.weight"), vec![hidden, hidden]),
+                (format!("{p}.attn_norm.weight"), vec![hidden]),
+                (format!("{p}.ffn_norm.weight"), vec![hidden]),
+                (format!("{p}.ffn_gate.weight"), vec![ff, hidden]),
+                (format!("{p}.ffn_up.weight"), vec![ff, hidden]),
+                (format!("{p}.ffn_down.weight"), vec![hidden, ff]),
+            ]);
+        }
+        let tensors = shapes
+            .iter()
+            .map(|(name, shape)| {
+                let t = if shape.len() == 1 {
+                    Tensor::ones(shape.as_slice(), candle::DType::F32, device)?
+                } else {
+                    Tensor::randn(0f32, 0.3, shape.as_slice(), device)?
+                };
+                Ok((name.as_str(), QTensor::quantize(&t, GgmlDType::F32)?))
+            })
+            .collect::<candle::Result<Vec<_>>>()?;
+
+        let mut buf = Cursor::new(Vec::new());
+        let metadata: Vec<_> = metadata.iter().map(|(k, v)| (*k, v)).collect();
+        let tensors: Vec<_> = tensors.iter().map(|(k, t)| (*k, t)).collect();
+        gguf_file::write(&mut buf, &metadata, &tensors)?;
+        buf.set_position(0);
+        let content = gguf_file::Content::read(&mut buf)?;
+        let model = ModelWeights::from_gguf(content, &mut buf, device)?;
+        Ok(LlamaBackend::new_quantized(
+            model,
+            device.clone(),
+            VOCAB,
+            quantized_model::MAX_SEQ_LEN,
+        ))
+    }
+
+    fn argmax(logits: &Tensor) -> candle::Result<u32> {
+        logits.argmax(0)?.to_scalar::<u32>()
+    }
+
+    fn max_abs_diff(a: &Tensor, b: &Tensor) -> candle::Result<f32> {
+        (a - b)?.abs()?.max(0)?.to_scalar::<f32>()
+    }
+
+    #[test]
+    fn batched_decoding_matches_sequential() -> candle::Result<()> {
+        let device = Device::Cpu;
+        let mut model = tiny_backend(&device)?;
+        assert!(model.supports_batching());
+        let prompts: [&[u32]; 3] = [&[1, 5, 9], &[2, 7, 3, 11, 4, 8], &[6]];
+        let steps = 4;
+
+        // Эталон: жадная генерация каждой последовательности через активный кэш
+        let mut expected = Vec::new();
+        for prompt in prompts {
+            model.clear_kv_cache();
+            let input = Tensor::new(prompt, &device)?.unsqueeze(0)?;
+            let mut logits = model.forward(&input, 0)?.squeeze(0)?;
+            let mut rows = Vec::new();
+            for step in 0..steps {
+                let input = Tensor::new(&[argmax(&logits)?], &device)?.unsqueeze(0)?;
+                logits = model.forward(&input, prompt.len() + step)?.squeeze(0)?;
+                rows.push(logits.clone());
+            }
+            expected.push(rows);
+        }
+
+        // Как в batch engine: prefill в слоты, затем общий шаг для всех слотов
+        let slots = [0, 1, 2];
+        let mut next = Vec::new();
+        let mut positions = Vec::new();
+        for (&slot, prompt) in slots.iter().zip(prompts) {
+            let input = Tensor::new(prompt, &device)?.unsqueeze(0)?;
+            next.push(argmax(&model.prefill_slot(slot, &input, 0)?.squeeze(0)?)?);
+            positions.push(prompt.len());
+        }
+        for step in 0..steps {
+            let logits = model.forward_batch(&next, &slots, &positions)?;
+            assert_eq!(logits.dims(), &[slots.len(), VOCAB]);
+            for (i, rows) in expected.iter().enumerate() {
+                let row = logits.get(i)?;
+                assert!(max_abs_diff(&row, &rows[step])? < 1e-4);
+                next[i] = argmax(&row)?;
+                positions[i] += 1;
+            }
+        }
+
+        model.release_slot(1);
+        assert!(
+            model
+                .forward_batch(&[next[0]], &[0, 0], &[positions[0]])
+                .is_err()
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,685 @@
+//! Local copy of quantized_llama with KV-cache manipulation support
+//!
+//! This is a modified version of candle_transformers::models::quantized_llama
+//! (GGUF loading only) that exposes the KV cache for clearing and context
+//! shifting (drop + RoPE re-rotation), which the original keeps private.
+//! Per-slot KV caches back batched decoding for continuous batching.
+//! Linear layers carry side-car LoRA deltas that can be swapped at runtime.
+
+use std::collections::{HashMap, HashSet};
+
+use candle::quantized::{QTensor, gguf_file};
+use candle::{DType, Device, IndexOp, Result, Tensor};
+use candle_nn::{Embedding, Module};
+use candle_transformers::quantized_nn::RmsNorm;
+
+use crate::models::common::gguf_mmap::{GgufRead, gguf_tensor};
+use crate::models::common::rope_scaling::rope_tables;
+use crate::models::common::{
+    KvCache, KvCacheType, LayerKv, LoraAdapter, LoraDeltas, LoraTarget, RopeScaling, RopeShift,
+    assign_lora, batched_decode_attention, check_layer_count,
+};
+
+pub const MAX_SEQ_LEN: usize = 4096;
+
+#[derive(Debug, Clone)]
+struct QMatMul {
+    inner: candle::quantized::QMatMul,
+    lora: LoraDeltas,
+}
+
+impl QMatMul {
+    fn from_qtensor(qtensor: QTensor) -> Result<Self> {
+        let (out_dim, in_dim) = qtensor.shape().dims2()?;
+        let inner = candle::quantized::QMatMul::from_qtensor(qtensor)?;
+        Ok(Self {
+            inner,
+            lora: LoraDeltas::new(in_dim, out_dim),
+        })
+    }
+
+    /// Q/K: конвертер llama.cpp переставляет строки под interleaved RoPE
+    fn with_permuted_heads(mut self, n_heads: usize) -> Self {
+        self.lora = self.lora.with_permuted_heads(n_heads);
+        self
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let ys = self.inner.forward(xs)?;
+        self.lora.apply(xs, ys)
+    }
+
+    fn lora_target(&mut self, module: String) -> LoraTarget<'_> {
+        self.lora.target(module)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    feed_forward_w1: QMatMul,
+    feed_forward_w2: QMatMul,
+    feed_forward_w3: QMatMul,
+}
+
+impl Mlp {
+    fn from_gguf<R: GgufRead>(
+        ct: &gguf_file::Content,
+        reader: &mut R,
+        prefix: &str,
+        suffix: &str,
+        device: &Device,
+    ) -> Result<Self> {
+        let w1 = gguf_tensor(
+            ct,
+            reader,
+            &format!("{prefix}.ffn_gate{suffix}.weight"),
+            device,
+        )?;
+        let w2 = gguf_tensor(
+            ct,
+            reader,
+            &format!("{prefix}.ffn_down{suffix}.weight"),
+            device,
+        )?;
+        let w3 = gguf_tensor(
+            ct,
+            reader,
+            &format!("{prefix}.ffn_up{suffix}.weight"),
+            device,
+        )?;
+        Ok(Self {
+            feed_forward_w1: QMatMul::from_qtensor(w1)?,
+            feed_forward_w2: QMatMul::from_qtensor(w2)?,
+            feed_forward_w3: QMatMul::from_qtensor(w3)?,
+        })
+    }
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let w1 = self.feed_forward_w1.forward(xs)?;
+        let w3 = self.feed_forward_w3.forward(xs)?;
+        self.feed_forward_w2
+            .forward(&(candle_nn::ops::silu(&w1)? * w3)?)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum MlpOrMoe {
+    Mlp(Mlp),
+    MoE {
+        n_expert_used: usize,
+        feed_forward_gate_inp: QMatMul,
+        experts: Vec<Mlp>,
+    },
+}
+
+impl Module for MlpOrMoe {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::MoE {
+                feed_forward_gate_inp,
+                experts,
+                n_expert_used,
+            } => {
+                let (b_size, seq_len, hidden_dim) = xs.dims3()?;
+                let xs = xs.reshape(((), hidden_dim))?;
+                let router_logits = feed_forward_gate_inp.forward(&xs)?;
+                let routing_weights = candle_nn::ops::softmax_last_dim(&router_logits)?;
+                let routing_weights = routing_weights.to_dtype(DType::F32)?.to_vec2::<f32>()?;
+
+                // top_x — индексы строк, которые обрабатывает каждый эксперт
+                let mut top_x = vec![vec![]; experts.len()];
+                let mut selected_rws = vec![vec![]; experts.len()];
+                for (row_idx, rw) in routing_weights.iter().enumerate() {
+                    let mut dst = (0..rw.len() as u32).collect::<Vec<u32>>();
+                    dst.sort_by(|&i, &j| rw[j as usize].total_cmp(&rw[i as usize]));
+                    let sum_routing_weights: f32 = dst
+                        .iter()
+                        .take(*n_expert_used)
+                        .map(|&idx| rw[idx as usize])
+                        .sum();
+                    for &expert_idx in dst.iter().take(*n_expert_used) {
+                        let expert_idx = expert_idx as usize;
+                        top_x[expert_idx].push(row_idx as u32);
+                        selected_rws[expert_idx].push(rw[expert_idx] / sum_routing_weights);
+                    }
+                }
+
+                let mut ys = xs.zeros_like()?;
+                for (expert_idx, expert_layer) in experts.iter().enumerate() {
+                    let top_x = &top_x[expert_idx];
+                    if top_x.is_empty() {
+                        continue;
+                    }
+                    let top_x = Tensor::new(top_x.as_slice(), xs.device())?;
+                    let selected_rws =
+                        Tensor::new(selected_rws[expert_idx].as_slice(), xs.device())?
+                            .reshape(((), 1))?;
+                    let current_state = xs.index_select(&top_x, 0)?.reshape(((), hidden_dim))?;
+                    let current_hidden_states = expert_layer
+                        .forward(&current_state)?
+                        .broadcast_mul(&selected_rws)?;
+                    ys = ys.index_add(&top_x, &current_hidden_states, 0)?;
+                }
+
+                ys.reshape((b_size, seq_len, hidden_dim))
+            }
+            Self::Mlp(mlp) => mlp.forward(xs),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct LayerWeights {
+    attention_wq: QMatMul,
+    attention_wk: QMatMul,
+    attention_wv: QMatMul,
+    attention_wo: QMatMul,
+    attention_norm: RmsNorm,
+    mlp_or_moe: MlpOrMoe,
+    ffn_norm: RmsNorm,
+    n_head: usize,
+    n_kv_head: usize,
+    head_dim: usize,
+    cos: Tensor,
+    sin: Tensor,
+    neg_inf: Tensor,
+    kv_cache: KvCache,
+}
+
+fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: &Tensor) -> Result<Tensor> {
+    let shape = mask.shape();
+    mask.where_cond(&on_true.broadcast_as(shape.dims())?, on_false)
+}
+
+impl LayerWeights {
+    fn apply_rotary_emb(&self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let (_b_sz, _n_head, seq_len, _n_embd) = x.dims4()?;
+        let cos = self.cos.narrow(0, index_pos, seq_len)?;
+        let sin = self.sin.narrow(0, index_pos, seq_len)?;
+        candle_nn::rotary_emb::rope_i(&x.contiguous()?, &cos, &sin)
+    }
+
+    fn forward_attn(
+        &mut self,
+        x: &Tensor,
+        mask: Option<&Tensor>,
+        index_pos: usize,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, n_embd) = x.dims3()?;
+        let q = self
+            .attention_wq
+            .forward(x)?
+            .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
+            .transpose(1, 2)?;
+        let k = self
+            .attention_wk
+            .forward(x)?
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?;
+        let v = self
+            .attention_wv
+            .forward(x)?
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+
+        let q = self.apply_rotary_emb(&q, index_pos)?;
+        let k = self.apply_rotary_emb(&k, index_pos)?;
+
+        if index_pos == 0 {
+            self.kv_cache.reset();
+        }
+        let (k, v) = self.kv_cache.append(&k, &v)?;
+
+        let y = if q.device().is_metal() && seq_len == 1 {
+            // SDPA сам выполняет MQA
+            candle_nn::ops::sdpa(
+                &q,
+                &k,
+                &v,
+                None,
+                false,
+                1. / (self.head_dim as f32).sqrt(),
+                1.,
+            )?
+        } else {
+            let k = candle_transformers::utils::repeat_kv(k, self.n_head / self.n_kv_head)?;
+            let v = candle_transformers::utils::repeat_kv(v, self.n_head / self.n_kv_head)?;
+
+            let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
+            let att = match mask {
+                None => att,
+                Some(mask) => {
+                    let mask = mask.broadcast_as(att.shape())?;
+                    masked_fill(&att, &mask, &self.neg_inf)?
+                }
+            };
+            let att = candle_nn::ops::softmax_last_dim(&att)?;
+            att.matmul(&v.contiguous()?)?
+        };
+
+        let y = y.transpose(1, 2)?.reshape(&[b_sz, seq_len, n_embd])?;
+        self.attention_wo.forward(&y)
+    }
+
+    /// Шаг декодирования батча: строка `i` — один токен на позиции `positions[i]`
+    /// со своим KV-кэшем `caches[i]`
+    fn forward_attn_batch(
+        &self,
+        x: &Tensor,
+        caches: &mut [&mut KvCache],
+        positions: &Tensor,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, n_embd) = x.dims3()?;
+        let q = self
+            .attention_wq
+            .forward(x)?
+            .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
+            .transpose(1, 2)?;
+        let k = self
+            .attention_wk
+            .forward(x)?
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?;
+        let v = self
+            .attention_wv
+            .forward(x)?
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?;
+
+        // RoPE со своей позицией для каждой строки: cos/sin [batch, 1, rope_dim / 2]
+        let cos = self.cos.index_select(positions, 0)?.unsqueeze(1)?;
+        let sin = self.sin.index_select(positions, 0)?.unsqueeze(1)?;
+        let q = candle_nn::rotary_emb::rope_i(&q.contiguous()?, &cos, &sin)?;
+        let k = candle_nn::rotary_emb::rope_i(&k.contiguous()?, &cos, &sin)?;
+
+        let y = batched_decode_attention(&q, &k, &v, caches, self.n_head / self.n_kv_head)?;
+        let y = y.transpose(1, 2)?.reshape(&[b_sz, seq_len, n_embd])?;
+        self.attention_wo.forward(&y)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ModelWeights {
+    tok_embeddings: Embedding,
+    layers: Vec<LayerWeights>,
+    norm: RmsNorm,
+    output: QMatMul,
+    masks: HashMap<usize, Tensor>,
+    rope: RopeShift,
+    /// KV-кэши слотов continuous batching (slot id -> кэши слоёв)
+    slots: HashMap<usize, Vec<KvCache>>,
+}
+
+fn precomput_freqs_cis(
+    head_dim: usize,
+    freq_base: f32,
+    scaling: Option<&RopeScaling>,
+    max_len: usize,
+    device: &Device,
+) -> Result<(Tensor, Tensor)> {
+    rope_tables(head_dim, freq_base as f64, scaling, max_len, device)
+}
+
+impl ModelWeights {
+    pub fn from_gguf<R: GgufRead>(
+        ct: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+    ) -> Result<Self> {
+        let md_get = |s: &str| match ct.metadata.get(s) {
+            None => candle::bail!("cannot find {s} in metadata"),
+            Some(v) => Ok(v),
+        };
+
+        let n_expert = md_get("llama.expert_count")
+            .and_then(|v| v.to_u32())
+            .unwrap_or(0) as usize;
+        let n_expert_used = md_get("llama.expert_used_count")
+            .and_then(|v| v.to_u32())
+            .unwrap_or(0) as usize;
+        let head_count = md_get("llama.attention.head_count")?.to_u32()? as usize;
+        let head_count_kv = md_get("llama.attention.head_count_kv")?.to_u32()? as usize;
+        let block_count = md_get("llama.block_count")?.to_u32()? as usize;
+        let embedding_length = md_get("llama.embedding_length")?.to_u32()? as usize;
+        let rope_dim = md_get("llama.rope.dimension_count")?.to_u32()? as usize;
+        let rms_norm_eps = md_get("llama.attention.layer_norm_rms_epsilon")?.to_f32()? as f64;
+
+        let rope_freq_base = md_get("llama.rope.freq_base")
+            .and_then(|m| m.to_f32())
+            .unwrap_or(10000f32);
+        let rope_scaling = md_get("llama.context_length")
+            .and_then(|v| v.to_u32())
+            .ok()
+            .and_then(|ctx| {
+                RopeScaling::from_gguf(&ct.metadata, "llama").map(|s| s.resolve(ctx as usize))
+            });
+        // Таблицы покрывают расширенный контекст, если задано масштабирование
+        let rope_len = rope_scaling
+            .as_ref()
+            .map_or(MAX_SEQ_LEN, |s| s.context_length(MAX_SEQ_LEN));
+        let (cos, sin) = precomput_freqs_cis(
+            rope_dim,
+            rope_freq_base,
+            rope_scaling.as_ref(),
+            rope_len,
+            device,
+        )?;
+        let neg_inf = Tensor::new(f32::NEG_INFINITY, device)?;
+
+        let tok_embeddings_q = gguf_tensor(&ct, reader, "token_embd.weight", device)?;
+        let tok_embeddings = tok_embeddings_q.dequantize(device)?;
+        let norm = RmsNorm::from_qtensor(
+            gguf_tensor(&ct, reader, "output_norm.weight", device)?,
+            rms_norm_eps,
+        )?;
+        let output = match gguf_tensor(&ct, reader, "output.weight", device) {
+            Ok(tensor) => tensor,
+            Err(_) => tok_embeddings_q,
+        };
+        let mut layers = Vec::with_capacity(block_count);
+        for layer_idx in 0..block_count {
+            let prefix = format!("blk.{layer_idx}");
+            let mlp_or_moe = if n_expert <= 1 {
+                MlpOrMoe::Mlp(Mlp::from_gguf(&ct, reader, &prefix, "", device)?)
+            } else {
+                let experts = (0..n_expert)
+                    .map(|i| Mlp::from_gguf(&ct, reader, &prefix, &format!(".{i}"), device))
+                    .collect::<Result<Vec<_>>>()?;
+                let feed_forward_gate_inp = gguf_tensor(
+                    &ct,
+                    reader,
+                    &format!("{prefix}.ffn_gate_inp.weight"),
+                    device,
+                )?;
+                MlpOrMoe::MoE {
+                    n_expert_used,
+                    feed_forward_gate_inp: QMatMul::from_qtensor(feed_forward_gate_inp)?,
+                    experts,
+                }
+            };
+            let attention_wq =
+                gguf_tensor(&ct, reader, &format!("{prefix}.attn_q.weight"), device)?;
+            let attention_wk =
+                gguf_tensor(&ct, reader, &format!("{prefix}.attn_k.weight"), device)?;
+            let attention_wv =
+                gguf_tensor(&ct, reader, &format!("{prefix}.attn_v.weight"), device)?;
+            let attention_wo =
+                gguf_tensor(&ct, reader, &format!("{prefix}.attn_output.weight"), device)?;
+            let attention_norm =
+                gguf_tensor(&ct, reader, &format!("{prefix}.attn_norm.weight"), device)?;
+            let ffn_norm = gguf_tensor(&ct, reader, &format!("{prefix}.ffn_norm.weight"), device)?;
+            layers.push(LayerWeights {
+                attention_wq: QMatMul::from_qtensor(attention_wq)?.with_permuted_heads(head_count),
+                attention_wk: QMatMul::from_qtensor(attention_wk)?
+                    .with_permuted_heads(head_count_kv),
+                attention_wv: QMatMul::from_qtensor(attention_wv)?,
+                attention_wo: QMatMul::from_qtensor(attention_wo)?,
+                attention_norm: RmsNorm::from_qtensor(attention_norm, rms_norm_eps)?,
+                mlp_or_moe,
+                ffn_norm: RmsNorm::from_qtensor(ffn_norm, rms_norm_eps)?,
+                n_head: head_count,
+                n_kv_head: head_count_kv,
+                head_dim: embedding_length / head_count,
+                cos: cos.clone(),
+                sin: sin.clone(),
+                neg_inf: neg_inf.clone(),
+                kv_cache: KvCache::new(2),
+            })
+        }
+        Ok(Self {
+            tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+            layers,
+            norm,
+            output: QMatMul::from_qtensor(output)?,
+            masks: HashMap::new(),
+            rope: RopeShift::interleaved(rope_dim, rope_freq_base as f64)
+                .with_scaling(rope_scaling),
+            slots: HashMap::new(),
+        })
+    }
+
+    fn mask(&mut self, t: usize, device: &Device) -> Result<Tensor> {
+        if let Some(mask) = self.masks.get(&t) {
+            Ok(mask.clone())
+        } else {
+            let mask: Vec<_> = (0..t)
+                .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
+                .collect();
+            let mask = Tensor::from_slice(&mask, (t, t), device)?;
+            self.masks.insert(t, mask.clone());
+            Ok(mask)
+        }
+    }
+
+    pub fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let (_b_sz, seq_len) = x.dims2()?;
+        let x = self.get_hidden_states(x, index_pos)?;
+        let x = x.i((.., seq_len - 1, ..))?;
+        self.output.forward(&x)
+    }
+
+    /// Скрытые состояния после финальной нормализации [batch, seq_len, hidden]
+    pub fn get_hidden_states(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let (_b_sz, seq_len) = x.dims2()?;
+        let mask = if seq_len == 1 {
+            None
+        } else {
+            Some(self.mask(seq_len, x.device())?)
+        };
+        let mut layer_in = self.tok_embeddings.forward(x)?;
+        for layer in self.layers.iter_mut() {
+            let x = layer_in;
+            let residual = &x;
+            let x = layer.attention_norm.forward(&x)?;
+            let attn = layer.forward_attn(&x, mask.as_ref(), index_pos)?;
+            let x = (attn + residual)?;
+
+            let residual = &x;
+            let x = layer.ffn_norm.forward(&x)?;
+            let x = layer.mlp_or_moe.forward(&x)?;
+            layer_in = (x + residual)?;
+        }
+        self.norm.forward(&layer_in)
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        for layer in &mut self.layers {
+            layer.kv_cache.reset();
+        }
+    }
+
+    /// Пустые кэши слоёв для нового слота (в формате активного кэша)
+    fn new_slot_caches(&self) -> Vec<KvCache> {
+        self.layers
+            .iter()
+            .map(|l| KvCache::with_type(2, l.kv_cache.cache_type()))
+            .collect()
+    }
+
+    fn swap_caches(&mut self, caches: &mut [KvCache]) {
+        for (layer, cache) in self.layers.iter_mut().zip(caches) {
+            std::mem::swap(&mut layer.kv_cache, cache);
+        }
+    }
+
+    /// Prefill в KV-кэш слота `slot`; активный кэш `forward` не затрагивается
+    ///
+    /// `index_pos == 0` начинает новую последовательность в слоте.
+    pub fn prefill_slot(&mut self, slot: usize, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let mut caches = match self.slots.remove(&slot) {
+            Some(caches) if index_pos > 0 => caches,
+            _ => self.new_slot_caches(),
+        };
+        self.swap_caches(&mut caches);
+        let logits = self.forward(x, index_pos);
+        self.swap_caches(&mut caches);
+        self.slots.insert(slot, caches);
+        logits
+    }
+
+    /// Шаг декодирования нескольких слотов одним проходом: логиты [batch, vocab_size]
+    ///
+    /// Матричные умножения весов идут по всему батчу, attention — по кэшам
+    /// слотов, дополненным до общей длины с маской.
+    pub fn forward_batch(
+        &mut self,
+        tokens: &[u32],
+        slots: &[usize],
+        positions: &[usize],
+    ) -> Result<Tensor> {
+        let b_sz = tokens.len();
+        if b_sz == 0 || slots.len() != b_sz || positions.len() != b_sz {
+            candle::bail!("forward_batch expects one token, slot and position per sequence")
+        }
+        let mut seen = HashSet::new();
+        if !slots.iter().all(|slot| seen.insert(*slot)) {
+            candle::bail!("forward_batch got the same slot twice")
+        }
+        let device = self.tok_embeddings.embeddings().device().clone();
+        let input = Tensor::from_slice(tokens, (b_sz, 1), &device)?;
+        let positions = positions.iter().map(|&p| p as u32).collect::<Vec<_>>();
+        let positions = Tensor::from_vec(positions, b_sz, &device)?;
+
+        let mut caches = Vec::with_capacity(b_sz);
+        for slot in slots {
+            let slot_caches = match self.slots.remove(slot) {
+                Some(slot_caches) => slot_caches,
+                None => self.new_slot_caches(),
+            };
+            caches.push(slot_caches);
+        }
+        let logits = self.forward_batch_layers(&input, &positions, &mut caches);
+        for (slot, slot_caches) in slots.iter().zip(caches) {
+            self.slots.insert(*slot, slot_caches);
+        }
+        logits
+    }
+
+    fn forward_batch_layers(
+        &self,
+        input: &Tensor,
+        positions: &Tensor,
+        caches: &mut [Vec<KvCache>],
+    ) -> Result<Tensor> {
+        let mut layer_in = self.tok_embeddings.forward(input)?;
+        for (idx, layer) in self.layers.iter().enumerate() {
+            let mut layer_caches: Vec<&mut KvCache> =
+                caches.iter_mut().map(|c| &mut c[idx]).collect();
+            let x = layer_in;
+            let residual = &x;
+            let x = layer.attention_norm.forward(&x)?;
+            let attn = layer.forward_attn_batch(&x, &mut layer_caches, positions)?;
+            let x = (attn + residual)?;
+
+            let residual = &x;
+            let x = layer.ffn_norm.forward(&x)?;
+            let x = layer.mlp_or_moe.forward(&x)?;
+            layer_in = (x + residual)?;
+        }
+        let x = self.norm.forward(&layer_in)?;
+        self.output.forward(&x.i((.., 0, ..))?)
+    }
+
+    /// Освобождает KV-кэш слота
+    pub fn release_slot(&mut self, slot: usize) {
+        self.slots.remove(&slot);
+    }
+
+    /// Текущая длина KV-кэша (по первому слою)
+    pub fn kv_cache_len(&self) -> usize {
+        self.layers
+            .first()
+            .map(|l| l.kv_cache.current_seq_len())
+            .unwrap_or(0)
+    }
+
+    /// Удаляет позиции [keep, keep + discard) из KV-кэша всех слоёв
+    /// с ре-ротацией RoPE оставшихся ключей
+    pub fn shift_kv_cache(&mut self, keep: usize, discard: usize) -> Result<()> {
+        let rope = self.rope;
+        for layer in &mut self.layers {
+            layer.kv_cache.shift(keep, discard, &rope)?;
+        }
+        Ok(())
+    }
+
+    /// Снимок KV-кэша всех слоёв (`None` если кэш пуст)
+    pub fn export_kv_cache(&self) -> Result<Option<Vec<LayerKv>>> {
+        let layers = self
+            .layers
+            .iter()
+            .map(|l| l.kv_cache.export())
+            .collect::<Result<Vec<_>>>()?;
+        Ok(layers.into_iter().collect())
+    }
+
+    /// Меняет формат хранения KV-кэша всех слоёв (полная точность или блоки Q8/Q4)
+    pub fn set_kv_cache_type(&mut self, cache_type: KvCacheType) -> Result<()> {
+        for layer in &mut self.layers {
+            layer.kv_cache.set_cache_type(cache_type)?;
+        }
+        for cache in self.slots.values_mut().flatten() {
+            cache.set_cache_type(cache_type)?;
+        }
+        Ok(())
+    }
+
+    /// Заменяет side-car LoRA-адаптеры (имена модулей HuggingFace)
+    ///
+    /// Эксперты MoE адаптерами не покрываются.
+    pub fn set_lora_adapters(&mut self, adapters: &[LoraAdapter]) -> Result<()> {
+        let mut targets = Vec::new();
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            let prefix = format!("model.layers.{i}");
+            targets.push(
+                layer
+                    .attention_wq
+                    .lora_target(format!("{prefix}.self_attn.q_proj")),
+            );
+            targets.push(
+                layer
+                    .attention_wk
+                    .lora_target(format!("{prefix}.self_attn.k_proj")),
+            );
+            targets.push(
+                layer
+                    .attention_wv
+                    .lora_target(format!("{prefix}.self_attn.v_proj")),
+            );
+            targets.push(
+                layer
+                    .attention_wo
+                    .lora_target(format!("{prefix}.self_attn.o_proj")),
+            );
+            if let MlpOrMoe::Mlp(mlp) = &mut layer.mlp_or_moe {
+                targets.push(
+                    mlp.feed_forward_w1
+                        .lora_target(format!("{prefix}.mlp.gate_proj")),
+                );
+                targets.push(
+                    mlp.feed_forward_w3
+                        .lora_target(format!("{prefix}.mlp.up_proj")),
+                );
+                targets.push(
+                    mlp.feed_forward_w2
+                        .lora_target(format!("{prefix}.mlp.down_proj")),
+                );
+            }
+        }
+        targets.push(self.output.lora_target("lm_head".to_string()));
+        // Активации квантизированной модели — F32
+        assign_lora(targets, adapters, DType::F32)
+    }
+
+    /// Заменяет KV-кэш всех слоёв снимком из `export_kv_cache`
+    pub fn import_kv_cache(&mut self, layers: &[LayerKv]) -> Result<()> {
+        check_layer_count(layers, self.layers.len())?;
+        for (layer, kv) in self.layers.iter_mut().zip(layers) {
+            layer.kv_cache.import(kv)?;
+        }
+        Ok(())
+    }
+}
@@ -3,13 +3,31 @@
 //! Загрузка Llama-подобных моделей из SafeTensors формата.
 
 use candle::{DType, Device};
-use candle_nn::VarBuilder;
-use candle_transformers::models::llama::{Cache, Llama, LlamaConfig};
+use candle_transformers::models::llama::{
+    Cache, Llama, Llama3RopeConfig, Llama3RopeType, LlamaConfig,
+};
 use std::path::{Path, PathBuf};
 
 use super::LlamaBackend;
 use crate::models::api::optimization::OptimizationConfig;
-use crate::models::common::is_flash_attention_available;
+use crate::models::common::lora::{LoraAdapter, merged_var_builder};
+use crate::models::common::rope_scaling::resolve_rope_scaling;
+use crate::models::common::{RopeScaling, RopeScalingKind, is_flash_attention_available};
+
+/// Linear-масштабирование в виде llama3-конфига candle
+///
+/// candle делит частоту на `factor`, если длина волны больше
+/// `original / low_freq_factor`; при бесконечных границах это верно для всех
+/// частот, и таблица совпадает с position interpolation.
+fn linear_rope_config(scaling: &RopeScaling) -> Llama3RopeConfig {
+    Llama3RopeConfig {
+        factor: scaling.factor as f32,
+        low_freq_factor: f32::INFINITY,
+        high_freq_factor: f32::INFINITY,
+        original_max_position_embeddings: scaling.original_context_length.unwrap_or(1),
+        rope_type: Llama3RopeType::Llama3,
+    }
+}
 
 impl LlamaBackend {
     /// Создаёт бекенд из SafeTensors файлов
@@ -18,13 +36,29 @@ impl LlamaBackend {
         config_path: &Path,
         device: &Device,
         dtype: DType,
+        rope_scaling: Option<RopeScaling>,
+        adapters: &[LoraAdapter],
     ) -> Result<Self, String> {
         // Загружаем конфигурацию
         let config_data =
             std::fs::read(config_path).map_err(|e| format!("Failed to read config.json: {}", e))?;
 
-        // Десериализуем сразу в LlamaConfig из candle_transformers
-        let mut llama_config: LlamaConfig = serde_json::from_slice(&config_data)
+        let mut config_json: serde_json::Value = serde_json::from_slice(&config_data)
+            .map_err(|e| format!("Failed to parse config.json: {}", e))?;
+        let max_position_embeddings = config_json
+            .get("max_position_embeddings")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(4096) as usize;
+        let rope_scaling =
+            resolve_rope_scaling(&config_json, rope_scaling, max_position_embeddings);
+        if rope_scaling.is_some()
+            && let Some(obj) = config_json.as_object_mut()
+        {
+            obj.remove("rope_scaling");
+        }
+
+        // Десериализуем в LlamaConfig из candle_transformers
+        let mut llama_config: LlamaConfig = serde_json::from_value(config_json)
             .map_err(|e| format!("Failed to parse config.json: {}", e))?;
 
         // Heuristic fix for Llama 3 if rope_theta is missing/default
@@ -38,6 +72,30 @@ impl LlamaBackend {
             llama_config.rope_theta = 500000.0;
         }
 
+        // candle Llama строит RoPE сам и не даёт подменить таблицы cos/sin (поля
+        // Cache приватны), поэтому точно выражаются только NTK и linear. YaRN
+        // требует рампы по измерениям и множителя cos/sin — для него нужен GGUF
+        if let Some(scaling) = rope_scaling {
+            match scaling.kind {
+                // NTK-aware масштабирование — это в точности новая база частот
+                RopeScalingKind::Ntk => {
+                    let head_dim = llama_config.hidden_size / llama_config.num_attention_heads;
+                    llama_config.rope_theta =
+                        scaling.effective_theta(head_dim, llama_config.rope_theta as f64) as f32;
+                }
+                RopeScalingKind::Linear => {
+                    llama_config.rope_scaling = Some(linear_rope_config(&scaling));
+                }
+                RopeScalingKind::Yarn => {
+                    return Err("YaRN RoPE scaling is not supported for SafeTensors Llama \
+                         models; use linear or NTK scaling or load the model as GGUF"
+                        .to_string());
+                }
+            }
+            llama_config.max_position_embeddings =
+                scaling.context_length(llama_config.max_position_embeddings);
+        }
+
         // Сохраняем vocab_size и max_pos до перемещения в into_config
         let vocab_size = llama_config.vocab_size;
         let max_seq_len = llama_config.max_position_embeddings;
@@ -53,11 +111,9 @@ impl LlamaBackend {
             max_seq_len
         );
 
-        // Создаём VarBuilder из SafeTensors
-        let vb = unsafe {
-            VarBuilder::from_mmaped_safetensors(filenames, dtype, device)
-                .map_err(|e| format!("Failed to load SafeTensors: {}", e))?
-        };
+        // Создаём VarBuilder из SafeTensors; LoRA-адаптеры вливаются в веса при чтении
+        let (vb, lora_merge) = merged_var_builder(filenames, dtype, device, adapters)
+            .map_err(|e| format!("Failed to load SafeTensors: {}", e))?;
 
         // Создаём конфигурацию оптимизаций
         let optimization = OptimizationConfig::for_safetensors(dtype);
@@ -83,6 +139,7 @@ impl LlamaBackend {
 
         let model =
             Llama::load(vb, &config).map_err(|e| format!("Failed to build Llama model: {}", e))?;
+        lora_merge.finish()?;
 
         Ok(Self::new_full(
             model,
@@ -106,7 +163,7 @@ impl LlamaBackend {
         }
 
         let filenames = Self::find_weight_files(model_dir)?;
-        Self::from_safetensors(&filenames, &config_path, device, dtype)
+        Self::from_safetensors(&filenames, &config_path, device, dtype, None, &[])
     }
 
     /// Находит файлы весов в директории
@@ -0,0 +1,175 @@
+//! Mamba configuration
+//!
+//! Конфигурация читается из `config.json` (transformers `MambaConfig` или исходный
+//! формат state-spaces с `d_model` / `n_layer`) или из метаданных GGUF (`mamba.*`).
+
+use std::collections::HashMap;
+
+use candle::quantized::gguf_file::Value;
+use serde::Deserialize;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub vocab_size: usize,
+    pub d_model: usize,
+    pub n_layer: usize,
+    /// Ширина SSM (обычно `expand * d_model`)
+    pub d_inner: usize,
+    /// Размер скрытого состояния SSM на канал
+    pub d_state: usize,
+    /// Ширина причинной свёртки
+    pub d_conv: usize,
+    /// Ранг проекции шага дискретизации Δ
+    pub dt_rank: usize,
+    pub rms_norm_eps: f64,
+    /// Номинальная длина контекста (состояние от неё не зависит)
+    pub max_seq_len: usize,
+}
+
+/// `config.json` Mamba: имена transformers с алиасами исходного формата
+#[derive(Debug, Deserialize)]
+struct HfConfig {
+    #[serde(alias = "d_model")]
+    hidden_size: usize,
+    #[serde(alias = "n_layer")]
+    num_hidden_layers: usize,
+    vocab_size: usize,
+    pad_vocab_size_multiple: Option<usize>,
+    #[serde(alias = "d_state")]
+    state_size: Option<usize>,
+    expand: Option<usize>,
+    #[serde(alias = "d_conv")]
+    conv_kernel: Option<usize>,
+    /// Число или `"auto"`
+    time_step_rank: Option<serde_json::Value>,
+    layer_norm_epsilon: Option<f64>,
+    /// Параметры SSM в исходном формате (`{"d_state": 16, ...}`)
+    #[serde(default)]
+    ssm_cfg: HashMap<String, serde_json::Value>,
+}
+
+/// Максимальная длина контекста, если модель её не объявляет
+const DEFAULT_MAX_SEQ_LEN: usize = 1 << 20;
+
+impl Config {
+    /// Разбирает `config.json`
+    pub fn from_hf_json(config: &serde_json::Value) -> Result<Self, String> {
+        let hf: HfConfig = serde_json::from_value(config.clone())
+            .map_err(|e| format!("Failed to parse Mamba config.json: {}", e))?;
+        let ssm = |key: &str| {
+            hf.ssm_cfg
+                .get(key)
+                .and_then(|v| v.as_u64())
+                .map(|v| v as usize)
+        };
+
+        // Исходные чекпоинты дополняют словарь до кратного pad_vocab_size_multiple
+        let vocab_size = match hf.pad_vocab_size_multiple {
+            Some(m) if m > 1 => hf.vocab_size.next_multiple_of(m),
+            _ => hf.vocab_size,
+        };
+        let expand = hf.expand.or_else(|| ssm("expand")).unwrap_or(2);
+        let dt_rank = hf
+            .time_step_rank
+            .as_ref()
+            .and_then(|v| v.as_u64())
+            .map(|v| v as usize)
+            .or_else(|| ssm("dt_rank"))
+            .unwrap_or_else(|| hf.hidden_size.div_ceil(16));
+
+        let cfg = Self {
+            vocab_size,
+            d_model: hf.hidden_size,
+            n_layer: hf.num_hidden_layers,
+            d_inner: expand * hf.hidden_size,
+            d_state: hf.state_size.or_else(|| ssm("d_state")).unwrap_or(16),
+            d_conv: hf.conv_kernel.or_else(|| ssm("d_conv")).unwrap_or(4),
+            dt_rank,
+            rms_norm_eps: hf.layer_norm_epsilon.unwrap_or(1e-5),
+            max_seq_len: DEFAULT_MAX_SEQ_LEN,
+        };
+        cfg.validate()?;
+        Ok(cfg)
+    }
+
+    /// Читает конфигурацию из метаданных GGUF (`mamba.*`)
+    pub fn from_gguf(metadata: &HashMap<String, Value>) -> candle::Result<Self> {
+        let get_u32 = |key: &str| -> candle::Result<usize> {
+            metadata
+                .get(&format!("mamba.{key}"))
+                .ok_or_else(|| candle::Error::Msg(format!("cannot find mamba.{key} in metadata")))?
+                .to_u32()
+                .map(|v| v as usize)
+        };
+
+        let vocab_size = get_u32("vocab_size").ok().unwrap_or_else(|| {
+            metadata
+                .get("tokenizer.ggml.tokens")
+                .and_then(|v| v.to_vec().ok())
+                .map_or(50280, |tokens| tokens.len())
+        });
+        let cfg = Self {
+            vocab_size,
+            d_model: get_u32("embedding_length")?,
+            n_layer: get_u32("block_count")?,
+            d_inner: get_u32("ssm.inner_size")?,
+            d_state: get_u32("ssm.state_size")?,
+            d_conv: get_u32("ssm.conv_kernel")?,
+            dt_rank: get_u32("ssm.time_step_rank")?,
+            rms_norm_eps: metadata
+                .get("mamba.attention.layer_norm_rms_epsilon")
+                .and_then(|v| v.to_f32().ok())
+                .map_or(1e-5, f64::from),
+            max_seq_len: get_u32("context_length").unwrap_or(DEFAULT_MAX_SEQ_LEN),
+        };
+        cfg.validate().map_err(candle::Error::Msg)?;
+        Ok(cfg)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.d_inner == 0 || self.d_state == 0 || self.d_conv == 0 || self.dt_rank == 0 {
+            return Err(format!(
+                "Mamba config: invalid SSM sizes (d_inner={}, d_state={}, d_conv={}, dt_rank={})",
+                self.d_inner, self.d_state, self.d_conv, self.dt_rank
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_transformers_and_original_configs() {
+        let hf = Config::from_hf_json(&serde_json::json!({
+            "model_type": "mamba",
+            "hidden_size": 768,
+            "num_hidden_layers": 24,
+            "vocab_size": 50280,
+            "state_size": 16,
+            "expand": 2,
+            "conv_kernel": 4,
+            "time_step_rank": 48,
+            "layer_norm_epsilon": 1e-5
+        }))
+        .unwrap();
+        assert_eq!(
+            (hf.d_inner, hf.d_state, hf.d_conv, hf.dt_rank),
+            (1536, 16, 4, 48)
+        );
+
+        let original = Config::from_hf_json(&serde_json::json!({
+            "d_model": 768,
+            "n_layer": 24,
+            "vocab_size": 50277,
+            "ssm_cfg": {},
+            "pad_vocab_size_multiple": 8
+        }))
+        .unwrap();
+        assert_eq!(original.vocab_size, 50280);
+        assert_eq!(original.d_inner, 1536);
+        assert_eq!(original.dt_rank, 48);
+    }
+}
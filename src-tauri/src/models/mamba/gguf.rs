@@ -0,0 +1,93 @@
+//! Mamba GGUF loading
+//!
+//! Загрузка квантизированных моделей архитектуры llama.cpp `mamba`.
+
+use std::sync::Arc;
+
+use candle::Device;
+use candle::quantized::{QMatMul, gguf_file};
+use candle::{Result, Tensor};
+
+use super::MambaBackend;
+use super::config::Config;
+use super::model::{Mamba, WeightSource};
+use crate::models::api::optimization::OptimizationConfig;
+use crate::models::common::gguf_mmap::{GgufRead, gguf_tensor};
+use crate::models::common::gguf_split::open_gguf;
+
+struct GgufWeights<'a, R: GgufRead> {
+    content: &'a gguf_file::Content,
+    reader: &'a mut R,
+    device: Device,
+}
+
+impl<R: GgufRead> WeightSource for GgufWeights<'_, R> {
+    fn contains(&self, name: &str) -> bool {
+        self.content.tensor_infos.contains_key(name)
+    }
+
+    fn matmul(&mut self, name: &str, shape: (usize, usize)) -> Result<QMatMul> {
+        let qt = gguf_tensor(self.content, self.reader, name, &self.device)?;
+        if qt.shape().dims2()? != shape {
+            candle::bail!("{name}: expected shape {:?}, got {:?}", shape, qt.shape());
+        }
+        QMatMul::from_arc(Arc::new(qt))
+    }
+
+    fn tensor(&mut self, name: &str, shape: &[usize]) -> Result<Tensor> {
+        self.content
+            .tensor(self.reader, name, &self.device)?
+            .dequantize(&self.device)?
+            .reshape(shape)
+    }
+}
+
+impl MambaBackend {
+    /// Создаёт бекенд из GGUF Content
+    pub fn from_gguf<R: GgufRead>(
+        content: gguf_file::Content,
+        file: &mut R,
+        device: &Device,
+    ) -> std::result::Result<Self, String> {
+        let mut cfg = Config::from_gguf(&content.metadata)
+            .map_err(|e| format!("Failed to read Mamba GGUF config: {}", e))?;
+        // Словарь эмбеддингов дополнен до кратного 8, токенов в метаданных меньше
+        if let Some(info) = content.tensor_infos.get("token_embd.weight")
+            && let Ok((vocab_size, _)) = info.shape.dims2()
+        {
+            cfg.vocab_size = vocab_size;
+        }
+
+        log::info!(
+            "Loading Mamba GGUF: layers={}, d_inner={}, d_state={}",
+            cfg.n_layer,
+            cfg.d_inner,
+            cfg.d_state
+        );
+
+        let mut weights = GgufWeights {
+            content: &content,
+            reader: file,
+            device: device.clone(),
+        };
+        let model = Mamba::load(&mut weights, &cfg, device)
+            .map_err(|e| format!("Failed to load Mamba GGUF model: {}", e))?;
+
+        Ok(Self::new(
+            model,
+            device.clone(),
+            cfg.vocab_size,
+            cfg.max_seq_len,
+            OptimizationConfig::for_gguf(),
+        ))
+    }
+
+    /// Создаёт бекенд из пути к GGUF файлу
+    pub fn from_gguf_path(
+        path: &std::path::Path,
+        device: &Device,
+    ) -> std::result::Result<Self, String> {
+        let (content, mut reader) = open_gguf(path)?;
+        Self::from_gguf(content, &mut reader, device)
+    }
+}
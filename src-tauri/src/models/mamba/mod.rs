@@ -0,0 +1,120 @@
+//! Mamba model backend
+//!
+//! Selective state space model (Mamba-1): `mamba` в config.json и GGUF.
+//! Вместо растущего KV-кэша модель хранит рекуррентное состояние фиксированного
+//! размера, поэтому память и время на токен не зависят от длины документа.
+//!
+//! # Структура
+//! - `mod.rs` - MambaBackend и ModelBackend реализация
+//! - `config.rs` - конфигурация из config.json или метаданных GGUF
+//! - `model.rs` - модель (общая для обоих форматов)
+//! - `gguf.rs` - загрузка из GGUF формата
+//! - `safetensors.rs` - загрузка из SafeTensors формата
+
+pub mod config;
+mod gguf;
+pub mod model;
+mod safetensors;
+
+pub use config::Config;
+
+use candle::{Device, Tensor};
+
+use crate::models::ModelBackend;
+use crate::models::api::optimization::{OptimizationConfig, WeightFormat};
+use crate::models::common::RecurrentState;
+
+use model::Mamba;
+
+/// Mamba бекенд
+///
+/// Откат, сдвиг контекста и снимки KV-кэша неприменимы; для переиспользования
+/// префикса используются снимки рекуррентного состояния.
+pub struct MambaBackend {
+    model: Mamba,
+    device: Device,
+    vocab_size: usize,
+    max_seq_len: usize,
+    optimization: OptimizationConfig,
+}
+
+impl MambaBackend {
+    /// Создаёт бекенд (используется из gguf.rs и safetensors.rs)
+    pub(crate) fn new(
+        model: Mamba,
+        device: Device,
+        vocab_size: usize,
+        max_seq_len: usize,
+        optimization: OptimizationConfig,
+    ) -> Self {
+        Self {
+            model,
+            device,
+            vocab_size,
+            max_seq_len,
+            optimization,
+        }
+    }
+
+    /// Возвращает устройство
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    /// Проверяет, квантизирована ли модель
+    pub fn is_quantized(&self) -> bool {
+        self.optimization.weight_format() == WeightFormat::Gguf
+    }
+
+    /// Возвращает конфигурацию оптимизаций
+    pub fn optimization(&self) -> &OptimizationConfig {
+        &self.optimization
+    }
+
+    /// Размер рекуррентного состояния в байтах
+    pub fn state_size_in_bytes(&self) -> usize {
+        self.model.state_size_in_bytes()
+    }
+}
+
+impl ModelBackend for MambaBackend {
+    fn forward(&mut self, input: &Tensor, pos: usize) -> candle::Result<Tensor> {
+        self.model.forward(input, pos)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.model.clear_state()
+    }
+
+    fn model_type(&self) -> &str {
+        if self.is_quantized() {
+            "mamba-gguf"
+        } else {
+            "mamba"
+        }
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.vocab_size
+    }
+
+    fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+
+    fn supports_chunked_prefill(&self) -> bool {
+        true
+    }
+
+    fn is_recurrent(&self) -> bool {
+        true
+    }
+
+    fn export_state(&self) -> candle::Result<RecurrentState> {
+        self.model.export_state()
+    }
+
+    fn import_state(&mut self, state: &RecurrentState) -> candle::Result<()> {
+        self.model.import_state(state)
+    }
+}
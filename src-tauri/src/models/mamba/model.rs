@@ -0,0 +1,464 @@
+//! Mamba (selective state space model)
+//!
+//! Одна реализация для GGUF и SafeTensors: проекции хранятся как
+//! `candle::quantized::QMatMul`, веса поставляет [`WeightSource`] с именами в стиле GGUF.
+//!
+//! Вместо KV-кэша каждый слой хранит состояние фиксированного размера:
+//! - `conv` — последние `d_conv - 1` входов причинной свёртки [batch, d_conv - 1, d_inner];
+//! - `ssm` — скрытое состояние SSM [batch, d_inner, d_state].
+//!
+//! Проекции считаются сразу для всего чанка, а свёртка и selective scan —
+//! последовательно по времени на CPU (каналы параллельно через rayon).
+
+use candle::quantized::QMatMul;
+use candle::{DType, Device, Module, Result, Tensor};
+use candle_nn::{Embedding, RmsNorm};
+use rayon::prelude::*;
+
+use super::config::Config;
+use crate::models::common::RecurrentState;
+
+/// Источник весов; имена тензоров — в стиле GGUF (`blk.0.ssm_in.weight`, `blk.0.ssm_a`, ...)
+pub(crate) trait WeightSource {
+    fn contains(&self, name: &str) -> bool;
+    /// Матрица проекции [out, in]
+    fn matmul(&mut self, name: &str, shape: (usize, usize)) -> Result<QMatMul>;
+    /// Тензор в dtype активаций. `ssm_a` уже содержит `-exp(A_log)`,
+    /// `ssm_conv1d.weight` имеет форму [d_inner, d_conv]
+    fn tensor(&mut self, name: &str, shape: &[usize]) -> Result<Tensor>;
+}
+
+fn to_f32_vec(t: Tensor) -> Result<Vec<f32>> {
+    t.to_dtype(DType::F32)?.flatten_all()?.to_vec1::<f32>()
+}
+
+fn softplus(x: f32) -> f32 {
+    if x > 20.0 { x } else { x.exp().ln_1p() }
+}
+
+fn silu(x: f32) -> f32 {
+    x / (1.0 + (-x).exp())
+}
+
+/// Состояние одного слоя для всего батча
+#[derive(Debug, Clone)]
+struct LayerState {
+    /// [batch, d_conv - 1, d_inner]
+    conv: Vec<f32>,
+    /// [batch, d_inner, d_state]
+    ssm: Vec<f32>,
+}
+
+struct Mixer {
+    in_proj: QMatMul,
+    /// [d_inner, d_conv]
+    conv_weight: Vec<f32>,
+    conv_bias: Vec<f32>,
+    x_proj: QMatMul,
+    dt_proj: QMatMul,
+    dt_bias: Vec<f32>,
+    /// -exp(A_log) [d_inner, d_state]
+    a: Vec<f32>,
+    d: Vec<f32>,
+    out_proj: QMatMul,
+    d_inner: usize,
+    d_state: usize,
+    d_conv: usize,
+    dt_rank: usize,
+}
+
+impl Mixer {
+    fn load(ws: &mut dyn WeightSource, layer: usize, cfg: &Config) -> Result<Self> {
+        let p = format!("blk.{layer}");
+        let (d_model, d_inner, d_state, d_conv, dt_rank) = (
+            cfg.d_model,
+            cfg.d_inner,
+            cfg.d_state,
+            cfg.d_conv,
+            cfg.dt_rank,
+        );
+        Ok(Self {
+            in_proj: ws.matmul(&format!("{p}.ssm_in.weight"), (2 * d_inner, d_model))?,
+            conv_weight: to_f32_vec(
+                ws.tensor(&format!("{p}.ssm_conv1d.weight"), &[d_inner, d_conv])?,
+            )?,
+            conv_bias: to_f32_vec(ws.tensor(&format!("{p}.ssm_conv1d.bias"), &[d_inner])?)?,
+            x_proj: ws.matmul(
+                &format!("{p}.ssm_x.weight"),
+                (dt_rank + 2 * d_state, d_inner),
+            )?,
+            dt_proj: ws.matmul(&format!("{p}.ssm_dt.weight"), (d_inner, dt_rank))?,
+            dt_bias: to_f32_vec(ws.tensor(&format!("{p}.ssm_dt.bias"), &[d_inner])?)?,
+            a: to_f32_vec(ws.tensor(&format!("{p}.ssm_a"), &[d_inner, d_state])?)?,
+            d: to_f32_vec(ws.tensor(&format!("{p}.ssm_d"), &[d_inner])?)?,
+            out_proj: ws.matmul(&format!("{p}.ssm_out.weight"), (d_model, d_inner))?,
+            d_inner,
+            d_state,
+            d_conv,
+            dt_rank,
+        })
+    }
+
+    fn empty_state(&self, batch: usize) -> LayerState {
+        LayerState {
+            conv: vec![0.0; batch * (self.d_conv - 1) * self.d_inner],
+            ssm: vec![0.0; batch * self.d_inner * self.d_state],
+        }
+    }
+
+    /// Причинная depthwise-свёртка с SiLU; `xs` — [seq_len, d_inner] одного элемента батча
+    fn conv(&self, xs: &[f32], state: &mut [f32]) -> Vec<f32> {
+        let (di, k) = (self.d_inner, self.d_conv);
+        let mut window: Vec<f32> = state.to_vec();
+        let mut out = Vec::with_capacity(xs.len());
+        for x_t in xs.chunks(di) {
+            window.extend_from_slice(x_t);
+            for i in 0..di {
+                let mut acc = self.conv_bias[i];
+                for j in 0..k {
+                    acc += self.conv_weight[i * k + j] * window[j * di + i];
+                }
+                out.push(silu(acc));
+            }
+            window.drain(..di);
+        }
+        state.copy_from_slice(&window);
+        out
+    }
+
+    /// Selective scan одного элемента батча; возвращает y [seq_len, d_inner]
+    ///
+    /// `dt` — сырой выход dt_proj [seq_len, d_inner], `bc` — B и C [seq_len, 2 * d_state]
+    fn scan(&self, xs: &[f32], dt: &[f32], bc: &[f32], h: &mut [f32]) -> Vec<f32> {
+        let (di, n) = (self.d_inner, self.d_state);
+        let seq_len = xs.len() / di;
+        let columns: Vec<Vec<f32>> = h
+            .par_chunks_mut(n)
+            .enumerate()
+            .map(|(i, h)| {
+                let a = &self.a[i * n..(i + 1) * n];
+                (0..seq_len)
+                    .map(|t| {
+                        let delta = softplus(dt[t * di + i] + self.dt_bias[i]);
+                        let x = xs[t * di + i];
+                        let (b, c) = bc[t * 2 * n..(t + 1) * 2 * n].split_at(n);
+                        let mut y = 0.0;
+                        for s in 0..n {
+                            h[s] = (delta * a[s]).exp() * h[s] + delta * b[s] * x;
+                            y += h[s] * c[s];
+                        }
+                        y + self.d[i] * x
+                    })
+                    .collect()
+            })
+            .collect();
+        let mut ys = vec![0.0; seq_len * di];
+        for (i, column) in columns.iter().enumerate() {
+            for (t, y) in column.iter().enumerate() {
+                ys[t * di + i] = *y;
+            }
+        }
+        ys
+    }
+
+    fn forward(&self, xs: &Tensor, state: &mut LayerState) -> Result<Tensor> {
+        let (b, seq_len, _) = xs.dims3()?;
+        let (di, n) = (self.d_inner, self.d_state);
+        let dtype = xs.dtype();
+        let device = xs.device();
+
+        let xz = self.in_proj.forward(xs)?;
+        let x = to_f32_vec(xz.narrow(2, 0, di)?)?;
+        let z = xz.narrow(2, di, di)?.to_dtype(DType::F32)?;
+
+        let conv_len = (self.d_conv - 1) * di;
+        let mut x_conv = Vec::with_capacity(x.len());
+        for (batch, x) in x.chunks(seq_len * di).enumerate() {
+            let conv_state = &mut state.conv[batch * conv_len..(batch + 1) * conv_len];
+            x_conv.extend(self.conv(x, conv_state));
+        }
+        let x_conv_t = Tensor::from_vec(x_conv.clone(), (b, seq_len, di), device)?;
+
+        let x_dbl = self.x_proj.forward(&x_conv_t.to_dtype(dtype)?)?;
+        let dt = to_f32_vec(
+            self.dt_proj
+                .forward(&x_dbl.narrow(2, 0, self.dt_rank)?.contiguous()?)?,
+        )?;
+        let bc = to_f32_vec(x_dbl.narrow(2, self.dt_rank, 2 * n)?)?;
+
+        let mut ys = Vec::with_capacity(b * seq_len * di);
+        for batch in 0..b {
+            let span = batch * seq_len * di..(batch + 1) * seq_len * di;
+            let bc_span = batch * seq_len * 2 * n..(batch + 1) * seq_len * 2 * n;
+            let h = &mut state.ssm[batch * di * n..(batch + 1) * di * n];
+            ys.extend(self.scan(&x_conv[span.clone()], &dt[span], &bc[bc_span], h));
+        }
+        let ys = Tensor::from_vec(ys, (b, seq_len, di), device)?;
+        let ys = (ys * candle_nn::ops::silu(&z)?)?.to_dtype(dtype)?;
+        self.out_proj.forward(&ys)
+    }
+}
+
+struct Layer {
+    norm: RmsNorm,
+    mixer: Mixer,
+}
+
+pub struct Mamba {
+    embedding: Embedding,
+    layers: Vec<Layer>,
+    norm_f: RmsNorm,
+    lm_head: QMatMul,
+    /// Состояние по слоям (`None` до первого forward)
+    state: Option<Vec<LayerState>>,
+    batch: usize,
+    /// Сколько токенов отражено в состоянии
+    seq_len: usize,
+    device: Device,
+}
+
+impl Mamba {
+    pub(crate) fn load(ws: &mut dyn WeightSource, cfg: &Config, device: &Device) -> Result<Self> {
+        let embeddings = ws.tensor("token_embd.weight", &[cfg.vocab_size, cfg.d_model])?;
+        let lm_head = if ws.contains("output.weight") {
+            ws.matmul("output.weight", (cfg.vocab_size, cfg.d_model))?
+        } else {
+            QMatMul::Tensor(embeddings.clone())
+        };
+        let layers = (0..cfg.n_layer)
+            .map(|layer| {
+                Ok(Layer {
+                    norm: RmsNorm::new(
+                        ws.tensor(&format!("blk.{layer}.attn_norm.weight"), &[cfg.d_model])?,
+                        cfg.rms_norm_eps,
+                    ),
+                    mixer: Mixer::load(ws, layer, cfg)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let norm_f = RmsNorm::new(
+            ws.tensor("output_norm.weight", &[cfg.d_model])?,
+            cfg.rms_norm_eps,
+        );
+        Ok(Self {
+            embedding: Embedding::new(embeddings, cfg.d_model),
+            layers,
+            norm_f,
+            lm_head,
+            state: None,
+            batch: 0,
+            seq_len: 0,
+            device: device.clone(),
+        })
+    }
+
+    /// Логиты последнего токена [batch, vocab_size]
+    ///
+    /// `pos == 0` сбрасывает состояние; иначе `pos` должен совпадать с числом
+    /// обработанных токенов.
+    pub fn forward(&mut self, input: &Tensor, pos: usize) -> Result<Tensor> {
+        let (b, seq_len) = input.dims2()?;
+        if pos == 0 || self.state.is_none() {
+            if pos != 0 {
+                candle::bail!(
+                    "Mamba: recurrent state is empty, cannot continue from position {pos}"
+                );
+            }
+            self.state = Some(self.layers.iter().map(|l| l.mixer.empty_state(b)).collect());
+            self.batch = b;
+            self.seq_len = 0;
+        } else if pos != self.seq_len || b != self.batch {
+            candle::bail!(
+                "Mamba: recurrent state holds {} tokens (batch {}), got position {} (batch {})",
+                self.seq_len,
+                self.batch,
+                pos,
+                b
+            );
+        }
+
+        let state = self.state.as_mut().expect("state initialized above");
+        let mut xs = self.embedding.forward(input)?;
+        for (layer, state) in self.layers.iter().zip(state.iter_mut()) {
+            let h = layer.mixer.forward(&layer.norm.forward(&xs)?, state)?;
+            xs = (xs + h)?;
+        }
+        self.seq_len += seq_len;
+
+        let xs = self.norm_f.forward(&xs.narrow(1, seq_len - 1, 1)?)?;
+        self.lm_head.forward(&xs)?.to_dtype(DType::F32)?.squeeze(1)
+    }
+
+    /// Сбрасывает рекуррентное состояние
+    pub fn clear_state(&mut self) {
+        self.state = None;
+        self.batch = 0;
+        self.seq_len = 0;
+    }
+
+    /// Снимок состояния: пара (conv, ssm) на каждый слой
+    pub fn export_state(&self) -> Result<RecurrentState> {
+        let Some(state) = &self.state else {
+            candle::bail!("Mamba: recurrent state is empty");
+        };
+        let mut tensors = Vec::with_capacity(2 * state.len());
+        for (layer, s) in self.layers.iter().zip(state) {
+            let m = &layer.mixer;
+            tensors.push(Tensor::from_slice(
+                &s.conv,
+                (self.batch, m.d_conv - 1, m.d_inner),
+                &self.device,
+            )?);
+            tensors.push(Tensor::from_slice(
+                &s.ssm,
+                (self.batch, m.d_inner, m.d_state),
+                &self.device,
+            )?);
+        }
+        Ok(RecurrentState {
+            seq_len: self.seq_len,
+            tensors,
+        })
+    }
+
+    /// Заменяет состояние снимком из `export_state`
+    pub fn import_state(&mut self, snapshot: &RecurrentState) -> Result<()> {
+        if snapshot.tensors.len() != 2 * self.layers.len() {
+            candle::bail!(
+                "Mamba: state snapshot has {} tensors, model expects {}",
+                snapshot.tensors.len(),
+                2 * self.layers.len()
+            );
+        }
+        let batch = snapshot.tensors[0].dim(0)?;
+        let state = self
+            .layers
+            .iter()
+            .zip(snapshot.tensors.chunks(2))
+            .map(|(layer, pair)| {
+                let m = &layer.mixer;
+                let (conv, ssm) = (&pair[0], &pair[1]);
+                if conv.dims() != [batch, m.d_conv - 1, m.d_inner]
+                    || ssm.dims() != [batch, m.d_inner, m.d_state]
+                {
+                    candle::bail!(
+                        "Mamba: state snapshot shapes {:?} / {:?} do not match the model",
+                        conv.dims(),
+                        ssm.dims()
+                    );
+                }
+                Ok(LayerState {
+                    conv: to_f32_vec(conv.clone())?,
+                    ssm: to_f32_vec(ssm.clone())?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.state = Some(state);
+        self.batch = batch;
+        self.seq_len = snapshot.seq_len;
+        Ok(())
+    }
+
+    /// Размер состояния в байтах (не зависит от длины контекста)
+    pub fn state_size_in_bytes(&self) -> usize {
+        self.state.as_ref().map_or(0, |state| {
+            state
+                .iter()
+                .map(|s| (s.conv.len() + s.ssm.len()) * std::mem::size_of::<f32>())
+                .sum()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Веса одного слоя с d_model = 4, d_inner = 4, d_state = 2, d_conv = 3, dt_rank = 1
+    struct TinyWeights;
+
+    impl WeightSource for TinyWeights {
+        fn contains(&self, _name: &str) -> bool {
+            false
+        }
+
+        fn matmul(&mut self, name: &str, shape: (usize, usize)) -> Result<QMatMul> {
+            self.tensor(name, &[shape.0, shape.1]).map(QMatMul::Tensor)
+        }
+
+        fn tensor(&mut self, name: &str, shape: &[usize]) -> Result<Tensor> {
+            let n: usize = shape.iter().product();
+            let seed = name.len() as f32;
+            let data: Vec<f32> = (0..n)
+                .map(|i| ((i as f32 * 0.37 + seed).sin()) * 0.5)
+                .collect();
+            let t = Tensor::from_vec(data, shape, &Device::Cpu)?;
+            if name.ends_with("ssm_a") {
+                // A отрицательна, иначе состояние расходится
+                return t.abs()?.affine(-1.0, -0.1);
+            }
+            Ok(t)
+        }
+    }
+
+    fn tiny() -> Mamba {
+        let cfg = Config {
+            vocab_size: 10,
+            d_model: 4,
+            n_layer: 2,
+            d_inner: 4,
+            d_state: 2,
+            d_conv: 3,
+            dt_rank: 1,
+            rms_norm_eps: 1e-5,
+            max_seq_len: 1024,
+        };
+        Mamba::load(&mut TinyWeights, &cfg, &Device::Cpu).unwrap()
+    }
+
+    fn max_diff(a: &Tensor, b: &Tensor) -> f32 {
+        (a - b)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .max(0)
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap()
+    }
+
+    #[test]
+    fn chunked_prefill_matches_token_by_token() -> Result<()> {
+        let tokens = [1u32, 4, 7, 2, 9, 3];
+        let dev = Device::Cpu;
+        let mut model = tiny();
+        let full = model.forward(&Tensor::new(&tokens[..], &dev)?.unsqueeze(0)?, 0)?;
+
+        let mut model = tiny();
+        let mut last = None;
+        for (pos, token) in tokens.iter().enumerate() {
+            last = Some(model.forward(&Tensor::new(&[[*token]], &dev)?, pos)?);
+        }
+        assert!(max_diff(&full, &last.unwrap()) < 1e-5);
+        Ok(())
+    }
+
+    #[test]
+    fn state_snapshot_restores_prefix() -> Result<()> {
+        let dev = Device::Cpu;
+        let mut model = tiny();
+        model.forward(&Tensor::new(&[[1u32, 4, 7]], &dev)?, 0)?;
+        let snapshot = model.export_state()?;
+        assert_eq!(snapshot.seq_len, 3);
+        let expected = model.forward(&Tensor::new(&[[2u32]], &dev)?, 3)?;
+
+        model.clear_state();
+        assert!(model.forward(&Tensor::new(&[[2u32]], &dev)?, 3).is_err());
+        model.import_state(&snapshot)?;
+        let restored = model.forward(&Tensor::new(&[[2u32]], &dev)?, 3)?;
+        assert!(max_diff(&expected, &restored) < 1e-6);
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+//! Mamba SafeTensors loading
+//!
+//! Поддерживаются чекпоинты transformers (`backbone.embeddings`) и исходного
+//! формата state-spaces (`backbone.embedding`).
+
+use std::path::{Path, PathBuf};
+
+use candle::quantized::QMatMul;
+use candle::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+
+use super::MambaBackend;
+use super::config::Config;
+use super::model::{Mamba, WeightSource};
+use crate::models::api::optimization::OptimizationConfig;
+
+/// Переводит имя тензора в стиле GGUF в имя чекпоинта
+fn hf_name(name: &str, embeddings: &str) -> String {
+    match name {
+        "token_embd.weight" => return format!("{embeddings}.weight"),
+        "output_norm.weight" => return "backbone.norm_f.weight".to_string(),
+        "output.weight" => return "lm_head.weight".to_string(),
+        _ => {}
+    }
+    let Some((layer, tensor)) = name
+        .strip_prefix("blk.")
+        .and_then(|rest| rest.split_once('.'))
+    else {
+        return name.to_string();
+    };
+    let tensor = match tensor {
+        "attn_norm.weight" => "norm.weight",
+        "ssm_in.weight" => "mixer.in_proj.weight",
+        "ssm_conv1d.weight" => "mixer.conv1d.weight",
+        "ssm_conv1d.bias" => "mixer.conv1d.bias",
+        "ssm_x.weight" => "mixer.x_proj.weight",
+        "ssm_dt.weight" => "mixer.dt_proj.weight",
+        "ssm_dt.bias" => "mixer.dt_proj.bias",
+        "ssm_a" => "mixer.A_log",
+        "ssm_d" => "mixer.D",
+        "ssm_out.weight" => "mixer.out_proj.weight",
+        other => other,
+    };
+    format!("backbone.layers.{layer}.{tensor}")
+}
+
+struct SafetensorsWeights<'a> {
+    vb: VarBuilder<'a>,
+    /// `backbone.embeddings` (transformers) или `backbone.embedding` (state-spaces)
+    embeddings: &'static str,
+}
+
+impl WeightSource for SafetensorsWeights<'_> {
+    fn contains(&self, name: &str) -> bool {
+        self.vb.contains_tensor(&hf_name(name, self.embeddings))
+    }
+
+    fn matmul(&mut self, name: &str, shape: (usize, usize)) -> candle::Result<QMatMul> {
+        self.tensor(name, &[shape.0, shape.1]).map(QMatMul::Tensor)
+    }
+
+    fn tensor(&mut self, name: &str, shape: &[usize]) -> candle::Result<Tensor> {
+        let hf = hf_name(name, self.embeddings);
+        if name.ends_with(".ssm_a") {
+            // Чекпоинт хранит A_log, модель использует A = -exp(A_log)
+            return self.vb.get(shape, &hf)?.exp()?.neg();
+        }
+        if name.ends_with(".ssm_conv1d.weight") {
+            // Conv1d: [d_inner, 1, d_conv]
+            return self.vb.get((shape[0], 1, shape[1]), &hf)?.reshape(shape);
+        }
+        self.vb.get(shape, &hf)
+    }
+}
+
+impl MambaBackend {
+    /// Создаёт бекенд из SafeTensors файлов
+    pub fn from_safetensors(
+        filenames: &[PathBuf],
+        config_path: &Path,
+        device: &Device,
+        dtype: DType,
+    ) -> Result<Self, String> {
+        let config_data =
+            std::fs::read(config_path).map_err(|e| format!("Failed to read config.json: {}", e))?;
+        let config_json: serde_json::Value = serde_json::from_slice(&config_data)
+            .map_err(|e| format!("Failed to parse config.json: {}", e))?;
+        let cfg = Config::from_hf_json(&config_json)?;
+
+        let vb = unsafe {
+            VarBuilder::from_mmaped_safetensors(filenames, dtype, device)
+                .map_err(|e| format!("Failed to load SafeTensors: {}", e))?
+        };
+        let embeddings = if vb.contains_tensor("backbone.embeddings.weight") {
+            "backbone.embeddings"
+        } else {
+            "backbone.embedding"
+        };
+
+        log::info!(
+            "Loading Mamba SafeTensors: dtype={:?}, layers={}, d_inner={}, d_state={}",
+            dtype,
+            cfg.n_layer,
+            cfg.d_inner,
+            cfg.d_state
+        );
+
+        let model = Mamba::load(&mut SafetensorsWeights { vb, embeddings }, &cfg, device)
+            .map_err(|e| format!("Failed to build Mamba model: {}", e))?;
+
+        Ok(Self::new(
+            model,
+            device.clone(),
+            cfg.vocab_size,
+            cfg.max_seq_len,
+            OptimizationConfig::for_safetensors(dtype),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_gguf_names_to_hf() {
+        assert_eq!(
+            hf_name("token_embd.weight", "backbone.embeddings"),
+            "backbone.embeddings.weight"
+        );
+        assert_eq!(
+            hf_name("blk.2.ssm_a", "backbone.embedding"),
+            "backbone.layers.2.mixer.A_log"
+        );
+        assert_eq!(
+            hf_name("blk.0.ssm_dt.bias", "backbone.embedding"),
+            "backbone.layers.0.mixer.dt_proj.bias"
+        );
+        assert_eq!(
+            hf_name("output_norm.weight", "backbone.embedding"),
+            "backbone.norm_f.weight"
+        );
+    }
+}
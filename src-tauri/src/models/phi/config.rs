@@ -0,0 +1,179 @@
+//! Phi-1 / Phi-1.5 / Phi-2 configuration
+//!
+//! Читается из `config.json` (`PhiForCausalLM`) или метаданных GGUF (`phi2.*`).
+
+use std::collections::HashMap;
+
+use candle::quantized::gguf_file::Value;
+use serde::Deserialize;
+
+use crate::models::common::rope_scaling::rope_table_len;
+use crate::models::common::{RopeScaling, RopeShift};
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: usize,
+    pub head_dim: usize,
+    /// Число измерений головы, к которым применяется RoPE (`partial_rotary_factor`)
+    pub rotary_dim: usize,
+    pub layer_norm_eps: f64,
+    pub rope_theta: f64,
+    pub max_position_embeddings: usize,
+    pub rope_scaling: Option<RopeScaling>,
+    /// LayerNorm для Q и K (есть только у части чекпоинтов)
+    pub qk_layernorm: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct HfConfig {
+    vocab_size: usize,
+    hidden_size: usize,
+    intermediate_size: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    num_key_value_heads: Option<usize>,
+    layer_norm_eps: Option<f64>,
+    rope_theta: Option<f64>,
+    max_position_embeddings: usize,
+    partial_rotary_factor: Option<f64>,
+    #[serde(default)]
+    qk_layernorm: bool,
+}
+
+impl Config {
+    /// Разбирает `config.json`; `rope_scaling` — override из запроса загрузки
+    pub fn from_hf_json(
+        config: &serde_json::Value,
+        rope_scaling: Option<RopeScaling>,
+    ) -> Result<Self, String> {
+        let hf: HfConfig = serde_json::from_value(config.clone())
+            .map_err(|e| format!("Failed to parse Phi config.json: {}", e))?;
+        let head_dim = hf.hidden_size / hf.num_attention_heads;
+        let rotary_dim = (head_dim as f64 * hf.partial_rotary_factor.unwrap_or(0.5)) as usize;
+        let cfg = Self {
+            vocab_size: hf.vocab_size,
+            hidden_size: hf.hidden_size,
+            intermediate_size: hf.intermediate_size,
+            num_hidden_layers: hf.num_hidden_layers,
+            num_attention_heads: hf.num_attention_heads,
+            num_key_value_heads: hf.num_key_value_heads.unwrap_or(hf.num_attention_heads),
+            head_dim,
+            rotary_dim,
+            layer_norm_eps: hf.layer_norm_eps.unwrap_or(1e-5),
+            rope_theta: hf.rope_theta.unwrap_or(10_000.),
+            max_position_embeddings: hf.max_position_embeddings,
+            rope_scaling: rope_scaling
+                .or_else(|| RopeScaling::from_config_json(config))
+                .map(|s| s.resolve(hf.max_position_embeddings)),
+            qk_layernorm: hf.qk_layernorm,
+        };
+        cfg.validate()?;
+        Ok(cfg)
+    }
+
+    /// Читает конфигурацию из метаданных GGUF
+    pub fn from_gguf(metadata: &HashMap<String, Value>) -> candle::Result<Self> {
+        let get = |key: &str| metadata.get(&format!("phi2.{key}"));
+        let get_u32 = |key: &str| -> candle::Result<usize> {
+            get(key)
+                .ok_or_else(|| candle::Error::Msg(format!("cannot find phi2.{key} in metadata")))?
+                .to_u32()
+                .map(|v| v as usize)
+        };
+        let get_f64 = |key: &str| get(key).and_then(|v| v.to_f32().ok()).map(f64::from);
+
+        let num_attention_heads = get_u32("attention.head_count")?;
+        let hidden_size = get_u32("embedding_length")?;
+        let head_dim = hidden_size / num_attention_heads;
+        let max_position_embeddings = get_u32("context_length").unwrap_or(2048);
+        let vocab_size = metadata
+            .get("tokenizer.ggml.tokens")
+            .and_then(|v| v.to_vec().ok())
+            .map(|tokens| tokens.len())
+            .unwrap_or(51200);
+
+        let cfg = Self {
+            vocab_size,
+            hidden_size,
+            intermediate_size: get_u32("feed_forward_length")?,
+            num_hidden_layers: get_u32("block_count")?,
+            num_attention_heads,
+            num_key_value_heads: get_u32("attention.head_count_kv").unwrap_or(num_attention_heads),
+            head_dim,
+            rotary_dim: get_u32("rope.dimension_count").unwrap_or(head_dim / 2),
+            layer_norm_eps: get_f64("attention.layer_norm_epsilon").unwrap_or(1e-5),
+            rope_theta: get_f64("rope.freq_base").unwrap_or(10_000.),
+            max_position_embeddings,
+            rope_scaling: RopeScaling::from_gguf(metadata, "phi2")
+                .map(|s| s.resolve(max_position_embeddings)),
+            qk_layernorm: false,
+        };
+        cfg.validate().map_err(candle::Error::Msg)?;
+        Ok(cfg)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.num_key_value_heads == 0
+            || !self
+                .num_attention_heads
+                .is_multiple_of(self.num_key_value_heads)
+        {
+            return Err(format!(
+                "Phi config: {} attention heads are not divisible by {} KV heads",
+                self.num_attention_heads, self.num_key_value_heads
+            ));
+        }
+        if self.rotary_dim == 0
+            || self.rotary_dim > self.head_dim
+            || !self.rotary_dim.is_multiple_of(2)
+        {
+            return Err(format!(
+                "Phi config: invalid rotary dimension {} for head_dim {}",
+                self.rotary_dim, self.head_dim
+            ));
+        }
+        Ok(())
+    }
+
+    /// Длина таблиц RoPE
+    pub fn max_seq_len(&self) -> usize {
+        rope_table_len(self.max_position_embeddings, self.rope_scaling.as_ref())
+    }
+
+    /// Параметры ре-ротации ключей (поворачиваются только первые `rotary_dim` измерений)
+    pub fn rope_shift(&self) -> RopeShift {
+        RopeShift::new(self.rotary_dim, self.rope_theta).with_scaling(self.rope_scaling)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_phi2_config() {
+        let json = serde_json::json!({
+            "model_type": "phi",
+            "vocab_size": 51200,
+            "hidden_size": 2560,
+            "intermediate_size": 10240,
+            "num_hidden_layers": 32,
+            "num_attention_heads": 32,
+            "max_position_embeddings": 2048,
+            "partial_rotary_factor": 0.4,
+            "layer_norm_eps": 1e-5,
+            "hidden_act": "gelu_new"
+        });
+        let cfg = Config::from_hf_json(&json, None).unwrap();
+        assert_eq!(cfg.head_dim, 80);
+        assert_eq!(cfg.rotary_dim, 32);
+        assert_eq!(cfg.num_key_value_heads, 32);
+        assert!(!cfg.qk_layernorm);
+        assert_eq!(cfg.rope_shift().head_dim, 32);
+    }
+}
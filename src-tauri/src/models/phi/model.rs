@@ -0,0 +1,271 @@
+//! Phi-1 / Phi-1.5 / Phi-2 (SafeTensors)
+//!
+//! Локальная версия candle_transformers::models::phi с открытым KV-кэшем.
+//! Блок параллельный: внимание и MLP получают один и тот же нормализованный
+//! вход, `x + attn(ln(x)) + mlp(ln(x))`. RoPE частичный.
+
+use candle::{DType, Device, Module, Result, Tensor};
+use candle_nn::{Activation, VarBuilder};
+use candle_transformers::models::with_tracing::{LayerNorm, Linear, layer_norm, linear};
+use candle_transformers::utils::repeat_kv;
+use std::sync::Arc;
+
+use super::config::Config;
+use crate::models::common::{KvCache, KvLayers, PartialRotaryEmbedding, RopeShift, causal_mask};
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    fc1: Linear,
+    fc2: Linear,
+}
+
+impl Mlp {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            fc1: linear(cfg.hidden_size, cfg.intermediate_size, vb.pp("fc1"))?,
+            fc2: linear(cfg.intermediate_size, cfg.hidden_size, vb.pp("fc2"))?,
+        })
+    }
+}
+
+impl Module for Mlp {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        x.apply(&self.fc1)?
+            .apply(&Activation::NewGelu)?
+            .apply(&self.fc2)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Attention {
+    q_proj: Linear,
+    k_proj: Linear,
+    v_proj: Linear,
+    dense: Linear,
+    q_layernorm: Option<LayerNorm>,
+    k_layernorm: Option<LayerNorm>,
+    num_heads: usize,
+    num_kv_heads: usize,
+    num_kv_groups: usize,
+    head_dim: usize,
+    rotary_emb: Arc<PartialRotaryEmbedding>,
+    kv_cache: KvCache,
+}
+
+impl Attention {
+    fn new(cfg: &Config, rotary_emb: Arc<PartialRotaryEmbedding>, vb: VarBuilder) -> Result<Self> {
+        let head_dim = cfg.head_dim;
+        let q_dim = cfg.num_attention_heads * head_dim;
+        let kv_dim = cfg.num_key_value_heads * head_dim;
+        let (q_layernorm, k_layernorm) = if cfg.qk_layernorm {
+            (
+                Some(layer_norm(
+                    head_dim,
+                    cfg.layer_norm_eps,
+                    vb.pp("q_layernorm"),
+                )?),
+                Some(layer_norm(
+                    head_dim,
+                    cfg.layer_norm_eps,
+                    vb.pp("k_layernorm"),
+                )?),
+            )
+        } else {
+            (None, None)
+        };
+        Ok(Self {
+            q_proj: linear(cfg.hidden_size, q_dim, vb.pp("q_proj"))?,
+            k_proj: linear(cfg.hidden_size, kv_dim, vb.pp("k_proj"))?,
+            v_proj: linear(cfg.hidden_size, kv_dim, vb.pp("v_proj"))?,
+            dense: linear(q_dim, cfg.hidden_size, vb.pp("dense"))?,
+            q_layernorm,
+            k_layernorm,
+            num_heads: cfg.num_attention_heads,
+            num_kv_heads: cfg.num_key_value_heads,
+            num_kv_groups: cfg.num_attention_heads / cfg.num_key_value_heads,
+            head_dim,
+            rotary_emb,
+            kv_cache: KvCache::new(2),
+        })
+    }
+
+    fn forward(&mut self, x: &Tensor, mask: Option<&Tensor>, offset: usize) -> Result<Tensor> {
+        let (b, l, _) = x.dims3()?;
+
+        let q = self
+            .q_proj
+            .forward(x)?
+            .reshape((b, l, self.num_heads, self.head_dim))?;
+        let k = self
+            .k_proj
+            .forward(x)?
+            .reshape((b, l, self.num_kv_heads, self.head_dim))?;
+        let v = self
+            .v_proj
+            .forward(x)?
+            .reshape((b, l, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let q = match &self.q_layernorm {
+            Some(ln) => q.apply(ln)?,
+            None => q,
+        };
+        let k = match &self.k_layernorm {
+            Some(ln) => k.apply(ln)?,
+            None => k,
+        };
+        let q = q.transpose(1, 2)?;
+        let k = k.transpose(1, 2)?;
+
+        let (q, k) = self.rotary_emb.apply(&q, &k, offset)?;
+        let (k, v) = self.kv_cache.append(&k, &v)?;
+
+        let k = repeat_kv(k, self.num_kv_groups)?.contiguous()?;
+        let v = repeat_kv(v, self.num_kv_groups)?.contiguous()?;
+
+        // Внимание в F32: в F16 логиты Phi-2 переполняются
+        let dtype = q.dtype();
+        let q = q.to_dtype(DType::F32)?;
+        let k = k.to_dtype(DType::F32)?;
+        let scale = 1.0 / (self.head_dim as f64).sqrt();
+        let mut scores = (q.matmul(&k.transpose(2, 3)?)? * scale)?;
+        if let Some(m) = mask {
+            scores = scores.broadcast_add(&m.to_dtype(DType::F32)?)?;
+        }
+        let probs = candle_nn::ops::softmax_last_dim(&scores)?.to_dtype(dtype)?;
+        let ctx = probs.matmul(&v)?;
+        let ctx = ctx
+            .transpose(1, 2)?
+            .reshape((b, l, self.num_heads * self.head_dim))?;
+        self.dense.forward(&ctx)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DecoderLayer {
+    self_attn: Attention,
+    mlp: Mlp,
+    input_layernorm: LayerNorm,
+}
+
+impl DecoderLayer {
+    fn new(cfg: &Config, rotary_emb: Arc<PartialRotaryEmbedding>, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            self_attn: Attention::new(cfg, rotary_emb, vb.pp("self_attn"))?,
+            mlp: Mlp::new(cfg, vb.pp("mlp"))?,
+            input_layernorm: layer_norm(
+                cfg.hidden_size,
+                cfg.layer_norm_eps,
+                vb.pp("input_layernorm"),
+            )?,
+        })
+    }
+
+    fn forward(&mut self, x: &Tensor, mask: Option<&Tensor>, offset: usize) -> Result<Tensor> {
+        let h = self.input_layernorm.forward(x)?;
+        let attn = self.self_attn.forward(&h, mask, offset)?;
+        let ff = self.mlp.forward(&h)?;
+        (attn + ff)? + x
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Model {
+    embed_tokens: candle_nn::Embedding,
+    layers: Vec<DecoderLayer>,
+    final_layernorm: LayerNorm,
+    lm_head: Linear,
+    rope: RopeShift,
+    device: Device,
+    dtype: DType,
+}
+
+impl Model {
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let vb_m = vb.pp("model");
+        let embed_tokens =
+            candle_nn::embedding(cfg.vocab_size, cfg.hidden_size, vb_m.pp("embed_tokens"))?;
+        let rotary_emb = Arc::new(PartialRotaryEmbedding::new(
+            vb.dtype(),
+            cfg.rotary_dim,
+            cfg.rope_theta,
+            cfg.max_seq_len(),
+            cfg.rope_scaling.as_ref(),
+            None,
+            vb.device(),
+        )?);
+        let vb_l = vb_m.pp("layers");
+        let layers = (0..cfg.num_hidden_layers)
+            .map(|i| DecoderLayer::new(cfg, rotary_emb.clone(), vb_l.pp(i)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            embed_tokens,
+            layers,
+            final_layernorm: layer_norm(
+                cfg.hidden_size,
+                cfg.layer_norm_eps,
+                vb_m.pp("final_layernorm"),
+            )?,
+            lm_head: linear(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head"))?,
+            rope: cfg.rope_shift(),
+            device: vb.device().clone(),
+            dtype: vb.dtype(),
+        })
+    }
+
+    /// Скрытые состояния после финальной нормализации [batch, seq_len, hidden_size]
+    fn forward_hidden(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        let (b, l) = input.dims2()?;
+        let mut h = self.embed_tokens.forward(input)?;
+        let mask = if l == 1 {
+            None
+        } else {
+            Some(causal_mask(b, l, offset, self.dtype, &self.device)?)
+        };
+        for layer in &mut self.layers {
+            h = layer.forward(&h, mask.as_ref(), offset)?;
+        }
+        self.final_layernorm.forward(&h)
+    }
+
+    /// Логиты последней позиции [batch, 1, vocab_size]
+    pub fn forward(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        let (_, l) = input.dims2()?;
+        self.forward_hidden(input, offset)?
+            .narrow(1, l - 1, 1)?
+            .apply(&self.lm_head)
+    }
+
+    /// Returns logits for every input position [batch, seq_len, vocab_size]
+    pub fn forward_all(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        self.forward_hidden(input, offset)?.apply(&self.lm_head)
+    }
+
+    /// Returns hidden states of the last layer after normalization [batch, seq_len, hidden_size]
+    pub fn get_hidden_states(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        self.forward_hidden(input, offset)
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        for layer in &mut self.layers {
+            layer.self_attn.kv_cache.reset();
+        }
+    }
+}
+
+impl KvLayers for Model {
+    fn kv_caches(&self) -> Vec<&KvCache> {
+        self.layers.iter().map(|l| &l.self_attn.kv_cache).collect()
+    }
+
+    fn kv_caches_mut(&mut self) -> Vec<&mut KvCache> {
+        self.layers
+            .iter_mut()
+            .map(|l| &mut l.self_attn.kv_cache)
+            .collect()
+    }
+
+    fn shift_rope(&self, _layer: usize) -> Result<RopeShift> {
+        Ok(self.rope)
+    }
+}
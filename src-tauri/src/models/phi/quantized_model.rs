@@ -0,0 +1,256 @@
+//! Квантизированная Phi-1 / Phi-1.5 / Phi-2 из GGUF
+//!
+//! Аналог candle_transformers::models::quantized_phi2 с открытым KV-кэшем.
+//! Проекции Q/K/V бывают слитыми (`attn_qkv`) или раздельными; у всех
+//! линейных слоёв есть смещения.
+
+use crate::models::common::gguf_mmap::{Gguf, GgufRead};
+use candle::quantized::gguf_file;
+use candle::{D, DType, Device, Module, Result, Tensor};
+use candle_nn::{Activation, Embedding, LayerNorm};
+use candle_transformers::models::with_tracing::QMatMul;
+use candle_transformers::utils::repeat_kv;
+use std::sync::Arc;
+
+use super::config::Config;
+use crate::models::common::{KvCache, KvLayers, PartialRotaryEmbedding, RopeShift, causal_mask};
+
+/// Квантизированный линейный слой со смещением
+#[derive(Debug, Clone)]
+struct QLinear {
+    weight: QMatMul,
+    bias: Tensor,
+}
+
+impl QLinear {
+    fn load<R: GgufRead>(gg: &mut Gguf<R>, name: &str, device: &Device) -> Result<Self> {
+        Ok(Self {
+            weight: gg.qmatmul(&format!("{name}.weight"))?,
+            bias: gg.tensor(&format!("{name}.bias"))?.dequantize(device)?,
+        })
+    }
+}
+
+impl Module for QLinear {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        self.weight.forward(x)?.broadcast_add(&self.bias)
+    }
+}
+
+fn load_layer_norm<R: GgufRead>(
+    gg: &mut Gguf<R>,
+    name: &str,
+    eps: f64,
+    device: &Device,
+) -> Result<LayerNorm> {
+    let weight = gg.tensor(&format!("{name}.weight"))?.dequantize(device)?;
+    let bias = gg.tensor(&format!("{name}.bias"))?.dequantize(device)?;
+    Ok(LayerNorm::new(weight, bias, eps))
+}
+
+#[derive(Debug, Clone)]
+enum QkvProj {
+    Fused(QLinear),
+    Split { q: QLinear, k: QLinear, v: QLinear },
+}
+
+#[derive(Debug, Clone)]
+struct LayerWeights {
+    qkv: QkvProj,
+    attn_output: QLinear,
+    attn_norm: LayerNorm,
+    ffn_up: QLinear,
+    ffn_down: QLinear,
+    num_heads: usize,
+    num_kv_heads: usize,
+    num_kv_groups: usize,
+    head_dim: usize,
+    rotary_emb: Arc<PartialRotaryEmbedding>,
+    kv_cache: KvCache,
+}
+
+impl LayerWeights {
+    fn attention(&mut self, x: &Tensor, mask: Option<&Tensor>, offset: usize) -> Result<Tensor> {
+        let (b, l, _) = x.dims3()?;
+        let q_dim = self.num_heads * self.head_dim;
+        let kv_dim = self.num_kv_heads * self.head_dim;
+        let (q, k, v) = match &self.qkv {
+            QkvProj::Fused(proj) => {
+                let qkv = proj.forward(x)?;
+                (
+                    qkv.narrow(D::Minus1, 0, q_dim)?,
+                    qkv.narrow(D::Minus1, q_dim, kv_dim)?,
+                    qkv.narrow(D::Minus1, q_dim + kv_dim, kv_dim)?,
+                )
+            }
+            QkvProj::Split { q, k, v } => (q.forward(x)?, k.forward(x)?, v.forward(x)?),
+        };
+        let q = q
+            .reshape((b, l, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let k = k
+            .reshape((b, l, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let v = v
+            .reshape((b, l, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+
+        let (q, k) = self.rotary_emb.apply(&q, &k, offset)?;
+        let (k, v) = self.kv_cache.append(&k, &v)?;
+
+        let k = repeat_kv(k, self.num_kv_groups)?.contiguous()?;
+        let v = repeat_kv(v, self.num_kv_groups)?.contiguous()?;
+
+        let scale = 1.0 / (self.head_dim as f64).sqrt();
+        let mut scores = (q.matmul(&k.transpose(2, 3)?)? * scale)?;
+        if let Some(m) = mask {
+            scores = scores.broadcast_add(&m.to_dtype(scores.dtype())?)?;
+        }
+        let probs = candle_nn::ops::softmax_last_dim(&scores)?;
+        let ctx = probs.matmul(&v)?;
+        let ctx = ctx
+            .transpose(1, 2)?
+            .reshape((b, l, self.num_heads * self.head_dim))?;
+        self.attn_output.forward(&ctx)
+    }
+
+    /// Параллельный блок: `x + attn(ln(x)) + mlp(ln(x))`
+    fn forward(&mut self, x: &Tensor, mask: Option<&Tensor>, offset: usize) -> Result<Tensor> {
+        let h = self.attn_norm.forward(x)?;
+        let attn = self.attention(&h, mask, offset)?;
+        let ff = h
+            .apply(&self.ffn_up)?
+            .apply(&Activation::NewGelu)?
+            .apply(&self.ffn_down)?;
+        (attn + ff)? + x
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ModelWeights {
+    embed_tokens: Embedding,
+    layers: Vec<LayerWeights>,
+    output_norm: LayerNorm,
+    output: QLinear,
+    cfg: Config,
+    rope: RopeShift,
+    device: Device,
+    dtype: DType,
+}
+
+impl ModelWeights {
+    pub fn from_gguf<R: GgufRead>(
+        ct: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+    ) -> Result<Self> {
+        let cfg = Config::from_gguf(&ct.metadata)?;
+        let fused_qkv = ct.tensor_infos.contains_key("blk.0.attn_qkv.weight");
+        let mut gg = Gguf::new(ct, reader, device.clone());
+        let dtype = DType::F32;
+
+        let embed_tokens = Embedding::new(
+            gg.tensor("token_embd.weight")?.dequantize(device)?,
+            cfg.hidden_size,
+        );
+        let rotary_emb = Arc::new(PartialRotaryEmbedding::new(
+            dtype,
+            cfg.rotary_dim,
+            cfg.rope_theta,
+            cfg.max_seq_len(),
+            cfg.rope_scaling.as_ref(),
+            None,
+            device,
+        )?);
+
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        for i in 0..cfg.num_hidden_layers {
+            let prefix = format!("blk.{i}");
+            let qkv = if fused_qkv {
+                QkvProj::Fused(QLinear::load(
+                    &mut gg,
+                    &format!("{prefix}.attn_qkv"),
+                    device,
+                )?)
+            } else {
+                QkvProj::Split {
+                    q: QLinear::load(&mut gg, &format!("{prefix}.attn_q"), device)?,
+                    k: QLinear::load(&mut gg, &format!("{prefix}.attn_k"), device)?,
+                    v: QLinear::load(&mut gg, &format!("{prefix}.attn_v"), device)?,
+                }
+            };
+            layers.push(LayerWeights {
+                qkv,
+                attn_output: QLinear::load(&mut gg, &format!("{prefix}.attn_output"), device)?,
+                attn_norm: load_layer_norm(
+                    &mut gg,
+                    &format!("{prefix}.attn_norm"),
+                    cfg.layer_norm_eps,
+                    device,
+                )?,
+                ffn_up: QLinear::load(&mut gg, &format!("{prefix}.ffn_up"), device)?,
+                ffn_down: QLinear::load(&mut gg, &format!("{prefix}.ffn_down"), device)?,
+                num_heads: cfg.num_attention_heads,
+                num_kv_heads: cfg.num_key_value_heads,
+                num_kv_groups: cfg.num_attention_heads / cfg.num_key_value_heads,
+                head_dim: cfg.head_dim,
+                rotary_emb: rotary_emb.clone(),
+                kv_cache: KvCache::new(2),
+            });
+        }
+
+        Ok(Self {
+            embed_tokens,
+            layers,
+            output_norm: load_layer_norm(&mut gg, "output_norm", cfg.layer_norm_eps, device)?,
+            output: QLinear::load(&mut gg, "output", device)?,
+            rope: cfg.rope_shift(),
+            cfg,
+            device: device.clone(),
+            dtype,
+        })
+    }
+
+    /// Конфигурация модели, прочитанная из GGUF
+    pub fn config(&self) -> &Config {
+        &self.cfg
+    }
+
+    /// Логиты последней позиции [batch, vocab_size]
+    pub fn forward(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        let (b, l) = input.dims2()?;
+        let mut h = self.embed_tokens.forward(input)?;
+        let mask = if l == 1 {
+            None
+        } else {
+            Some(causal_mask(b, l, offset, self.dtype, &self.device)?)
+        };
+        for layer in &mut self.layers {
+            h = layer.forward(&h, mask.as_ref(), offset)?;
+        }
+        let h = self.output_norm.forward(&h)?;
+        let last_hidden = h.narrow(1, l - 1, 1)?;
+        self.output.forward(&last_hidden)?.squeeze(1)
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        for layer in &mut self.layers {
+            layer.kv_cache.reset();
+        }
+    }
+}
+
+impl KvLayers for ModelWeights {
+    fn kv_caches(&self) -> Vec<&KvCache> {
+        self.layers.iter().map(|l| &l.kv_cache).collect()
+    }
+
+    fn kv_caches_mut(&mut self) -> Vec<&mut KvCache> {
+        self.layers.iter_mut().map(|l| &mut l.kv_cache).collect()
+    }
+
+    fn shift_rope(&self, _layer: usize) -> Result<RopeShift> {
+        Ok(self.rope)
+    }
+}
@@ -0,0 +1,54 @@
+//! Phi SafeTensors loading
+//!
+//! Загрузка Phi-1 / Phi-1.5 / Phi-2 моделей из SafeTensors формата.
+
+use candle::{DType, Device};
+use candle_nn::VarBuilder;
+use std::path::{Path, PathBuf};
+
+use super::PhiBackend;
+use super::config::Config;
+use super::model::Model;
+use crate::models::api::optimization::OptimizationConfig;
+use crate::models::common::RopeScaling;
+
+impl PhiBackend {
+    /// Создаёт бекенд из SafeTensors файлов
+    pub fn from_safetensors(
+        filenames: &[PathBuf],
+        config_path: &Path,
+        device: &Device,
+        dtype: DType,
+        rope_scaling: Option<RopeScaling>,
+    ) -> Result<Self, String> {
+        let config_data =
+            std::fs::read(config_path).map_err(|e| format!("Failed to read config.json: {}", e))?;
+        let config_json: serde_json::Value = serde_json::from_slice(&config_data)
+            .map_err(|e| format!("Failed to parse config.json: {}", e))?;
+        let config = Config::from_hf_json(&config_json, rope_scaling)?;
+
+        let vb = unsafe {
+            VarBuilder::from_mmaped_safetensors(filenames, dtype, device)
+                .map_err(|e| format!("Failed to load SafeTensors: {}", e))?
+        };
+
+        log::info!(
+            "Loading Phi: dtype={:?}, rotary_dim={}/{}, qk_layernorm={}",
+            dtype,
+            config.rotary_dim,
+            config.head_dim,
+            config.qk_layernorm
+        );
+
+        let inner =
+            Model::new(&config, vb).map_err(|e| format!("Failed to build Phi model: {}", e))?;
+
+        Ok(Self::new_full(
+            inner,
+            device.clone(),
+            config.vocab_size,
+            config.max_seq_len(),
+            OptimizationConfig::for_safetensors(dtype),
+        ))
+    }
+}
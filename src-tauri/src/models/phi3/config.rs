@@ -0,0 +1,273 @@
+//! Phi-3 / Phi-3.5 / Phi-4 configuration
+//!
+//! Читается из `config.json` (`Phi3ForCausalLM`) или метаданных GGUF (`phi3.*`).
+//! Модели с длинным контекстом используют LongRoPE: отдельные множители частот
+//! для коротких (`short_factor`) и длинных (`long_factor`) последовательностей.
+
+use std::collections::HashMap;
+
+use candle::quantized::gguf_file::Value;
+use serde::Deserialize;
+
+use crate::models::common::rope_scaling::{LongRope, rope_table_len};
+use crate::models::common::{RopeScaling, RopeShift};
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: usize,
+    pub head_dim: usize,
+    /// Число измерений головы, к которым применяется RoPE (`partial_rotary_factor`)
+    pub rotary_dim: usize,
+    pub rms_norm_eps: f64,
+    pub rope_theta: f64,
+    pub max_position_embeddings: usize,
+    /// Масштабирование RoPE (override или `rope_scaling` другого типа); заменяет LongRoPE
+    pub rope_scaling: Option<RopeScaling>,
+    pub long_rope: Option<LongRope>,
+    pub tie_word_embeddings: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct HfConfig {
+    vocab_size: usize,
+    hidden_size: usize,
+    intermediate_size: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    num_key_value_heads: Option<usize>,
+    rms_norm_eps: Option<f64>,
+    rope_theta: Option<f64>,
+    max_position_embeddings: usize,
+    original_max_position_embeddings: Option<usize>,
+    partial_rotary_factor: Option<f64>,
+    #[serde(default)]
+    tie_word_embeddings: bool,
+}
+
+impl Config {
+    /// Разбирает `config.json`; `rope_scaling` — override из запроса загрузки
+    pub fn from_hf_json(
+        config: &serde_json::Value,
+        rope_scaling: Option<RopeScaling>,
+    ) -> Result<Self, String> {
+        let hf: HfConfig = serde_json::from_value(config.clone())
+            .map_err(|e| format!("Failed to parse Phi-3 config.json: {}", e))?;
+        let head_dim = hf.hidden_size / hf.num_attention_heads;
+        let rotary_dim = (head_dim as f64 * hf.partial_rotary_factor.unwrap_or(1.0)) as usize;
+        let original = hf
+            .original_max_position_embeddings
+            .unwrap_or(hf.max_position_embeddings);
+
+        let long_rope = config.get("rope_scaling").and_then(|rs| {
+            let kind = rs
+                .get("rope_type")
+                .or_else(|| rs.get("type"))
+                .and_then(|v| v.as_str())?;
+            if kind != "longrope" && kind != "su" {
+                return None;
+            }
+            let factors = |key: &str| -> Option<Vec<f64>> {
+                rs.get(key)?
+                    .as_array()?
+                    .iter()
+                    .map(|v| v.as_f64())
+                    .collect()
+            };
+            Some(LongRope {
+                short_factor: factors("short_factor")?,
+                long_factor: factors("long_factor")?,
+                original_max_position_embeddings: original,
+                attention_factor: LongRope::default_attention_factor(
+                    hf.max_position_embeddings,
+                    original,
+                ),
+            })
+        });
+        let rope_scaling = rope_scaling
+            .or_else(|| RopeScaling::from_config_json(config))
+            .map(|s| s.resolve(hf.max_position_embeddings));
+
+        let cfg = Self {
+            vocab_size: hf.vocab_size,
+            hidden_size: hf.hidden_size,
+            intermediate_size: hf.intermediate_size,
+            num_hidden_layers: hf.num_hidden_layers,
+            num_attention_heads: hf.num_attention_heads,
+            num_key_value_heads: hf.num_key_value_heads.unwrap_or(hf.num_attention_heads),
+            head_dim,
+            rotary_dim,
+            rms_norm_eps: hf.rms_norm_eps.unwrap_or(1e-5),
+            rope_theta: hf.rope_theta.unwrap_or(10_000.),
+            max_position_embeddings: hf.max_position_embeddings,
+            long_rope: if rope_scaling.is_some() {
+                None
+            } else {
+                long_rope
+            },
+            rope_scaling,
+            tie_word_embeddings: hf.tie_word_embeddings,
+        };
+        cfg.validate()?;
+        Ok(cfg)
+    }
+
+    /// Читает конфигурацию из метаданных GGUF
+    ///
+    /// Множители LongRoPE хранятся в GGUF тензорами и задаются через [`Config::set_long_rope`].
+    pub fn from_gguf(metadata: &HashMap<String, Value>) -> candle::Result<Self> {
+        let get = |key: &str| metadata.get(&format!("phi3.{key}"));
+        let get_u32 = |key: &str| -> candle::Result<usize> {
+            get(key)
+                .ok_or_else(|| candle::Error::Msg(format!("cannot find phi3.{key} in metadata")))?
+                .to_u32()
+                .map(|v| v as usize)
+        };
+        let get_f64 = |key: &str| get(key).and_then(|v| v.to_f32().ok()).map(f64::from);
+
+        let num_attention_heads = get_u32("attention.head_count")?;
+        let hidden_size = get_u32("embedding_length")?;
+        let head_dim = hidden_size / num_attention_heads;
+        let max_position_embeddings = get_u32("context_length").unwrap_or(4096);
+        let vocab_size = metadata
+            .get("tokenizer.ggml.tokens")
+            .and_then(|v| v.to_vec().ok())
+            .map(|tokens| tokens.len())
+            .unwrap_or(32064);
+
+        let cfg = Self {
+            vocab_size,
+            hidden_size,
+            intermediate_size: get_u32("feed_forward_length")?,
+            num_hidden_layers: get_u32("block_count")?,
+            num_attention_heads,
+            num_key_value_heads: get_u32("attention.head_count_kv").unwrap_or(num_attention_heads),
+            head_dim,
+            rotary_dim: get_u32("rope.dimension_count").unwrap_or(head_dim),
+            rms_norm_eps: get_f64("attention.layer_norm_rms_epsilon").unwrap_or(1e-5),
+            rope_theta: get_f64("rope.freq_base").unwrap_or(10_000.),
+            max_position_embeddings,
+            rope_scaling: RopeScaling::from_gguf(metadata, "phi3")
+                .map(|s| s.resolve(max_position_embeddings)),
+            long_rope: None,
+            tie_word_embeddings: false,
+        };
+        cfg.validate().map_err(candle::Error::Msg)?;
+        Ok(cfg)
+    }
+
+    /// Задаёт LongRoPE из тензоров GGUF (`rope_factors_short` / `rope_factors_long`)
+    pub fn set_long_rope(
+        &mut self,
+        metadata: &HashMap<String, Value>,
+        short_factor: Vec<f64>,
+        long_factor: Vec<f64>,
+    ) -> candle::Result<()> {
+        if self.rope_scaling.is_some() {
+            log::info!("RoPE scaling override replaces Phi-3 LongRoPE factors");
+            return Ok(());
+        }
+        let get = |key: &str| metadata.get(&format!("phi3.rope.scaling.{key}"));
+        let original = get("original_context_length")
+            .and_then(|v| v.to_u32().ok())
+            .map_or(self.max_position_embeddings, |v| v as usize);
+        let attention_factor = get("attn_factor")
+            .and_then(|v| v.to_f32().ok())
+            .map(f64::from)
+            .unwrap_or_else(|| {
+                LongRope::default_attention_factor(self.max_position_embeddings, original)
+            });
+        let long_rope = LongRope {
+            short_factor,
+            long_factor,
+            original_max_position_embeddings: original,
+            attention_factor,
+        };
+        long_rope
+            .validate(self.rotary_dim)
+            .map_err(candle::Error::Msg)?;
+        self.long_rope = Some(long_rope);
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.num_key_value_heads == 0
+            || !self
+                .num_attention_heads
+                .is_multiple_of(self.num_key_value_heads)
+        {
+            return Err(format!(
+                "Phi-3 config: {} attention heads are not divisible by {} KV heads",
+                self.num_attention_heads, self.num_key_value_heads
+            ));
+        }
+        if let Some(long_rope) = &self.long_rope {
+            long_rope.validate(self.rotary_dim)?;
+        }
+        Ok(())
+    }
+
+    /// Длина таблиц RoPE
+    pub fn max_seq_len(&self) -> usize {
+        rope_table_len(self.max_position_embeddings, self.rope_scaling.as_ref())
+    }
+
+    /// Параметры ре-ротации ключей (`None` для LongRoPE: частоты зависят от длины)
+    pub fn rope_shift(&self) -> Option<RopeShift> {
+        if self.long_rope.is_some() {
+            return None;
+        }
+        Some(RopeShift::new(self.rotary_dim, self.rope_theta).with_scaling(self.rope_scaling))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn phi35_mini_config() -> serde_json::Value {
+        serde_json::json!({
+            "model_type": "phi3",
+            "vocab_size": 32064,
+            "hidden_size": 3072,
+            "intermediate_size": 8192,
+            "num_hidden_layers": 32,
+            "num_attention_heads": 32,
+            "num_key_value_heads": 32,
+            "max_position_embeddings": 131072,
+            "original_max_position_embeddings": 4096,
+            "rope_theta": 10000.0,
+            "rope_scaling": {
+                "type": "longrope",
+                "short_factor": vec![1.0; 48],
+                "long_factor": vec![2.0; 48]
+            }
+        })
+    }
+
+    #[test]
+    fn parses_longrope_config() {
+        let cfg = Config::from_hf_json(&phi35_mini_config(), None).unwrap();
+        let long_rope = cfg.long_rope.as_ref().unwrap();
+        assert_eq!(long_rope.original_max_position_embeddings, 4096);
+        assert!((long_rope.attention_factor - 1.1902).abs() < 1e-3);
+        assert_eq!(cfg.rotary_dim, 96);
+        assert!(cfg.rope_shift().is_none());
+
+        let freqs = LongRope::inv_freqs(&long_rope.long_factor, cfg.rotary_dim, cfg.rope_theta);
+        assert_eq!(freqs.len(), 48);
+        assert!((freqs[0] - 0.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn override_replaces_longrope() {
+        let scaling = RopeScaling::new(crate::models::common::RopeScalingKind::Linear, 2.0);
+        let cfg = Config::from_hf_json(&phi35_mini_config(), Some(scaling)).unwrap();
+        assert!(cfg.long_rope.is_none());
+        assert!(cfg.rope_shift().is_some());
+    }
+}
@@ -0,0 +1,48 @@
+//! Phi-3 GGUF loading
+//!
+//! Загрузка квантизированных Phi-3 / Phi-4 моделей из GGUF формата.
+
+use candle::Device;
+use candle::quantized::gguf_file;
+
+use super::Phi3Backend;
+use super::quantized_model::ModelWeights;
+use crate::models::common::gguf_mmap::GgufRead;
+use crate::models::common::gguf_split::open_gguf;
+
+impl Phi3Backend {
+    /// Создаёт бекенд из GGUF Content
+    pub fn from_gguf<R: GgufRead>(
+        content: gguf_file::Content,
+        file: &mut R,
+        device: &Device,
+    ) -> Result<Self, String> {
+        let inner = ModelWeights::from_gguf(content, file, device)
+            .map_err(|e| format!("Failed to load Phi-3 GGUF model: {}", e))?;
+
+        let cfg = inner.config();
+        let vocab_size = cfg.vocab_size;
+        let max_seq_len = cfg.max_seq_len();
+
+        log::info!(
+            "Loading Phi-3 GGUF: layers={}, rotary_dim={}/{}, longrope={}",
+            cfg.num_hidden_layers,
+            cfg.rotary_dim,
+            cfg.head_dim,
+            cfg.long_rope.is_some()
+        );
+
+        Ok(Self::new_quantized(
+            inner,
+            device.clone(),
+            vocab_size,
+            max_seq_len,
+        ))
+    }
+
+    /// Создаёт бекенд из пути к GGUF файлу
+    pub fn from_gguf_path(path: &std::path::Path, device: &Device) -> Result<Self, String> {
+        let (content, mut reader) = open_gguf(path)?;
+        Self::from_gguf(content, &mut reader, device)
+    }
+}
@@ -0,0 +1,267 @@
+//! Phi-3 / Phi-4 (SafeTensors)
+//!
+//! Локальная версия candle_transformers::models::phi3 с открытым KV-кэшем
+//! (сдвиг контекста, снимки, квантование) и частичным RoPE (Phi-4-mini).
+//! Проекции слитые: `qkv_proj` (Q | K | V) и `gate_up_proj` (gate | up).
+
+use candle::{D, DType, Device, Module, Result, Tensor};
+use candle_nn::{Activation, VarBuilder};
+use candle_transformers::models::with_tracing::{Linear, RmsNorm, linear_no_bias};
+use candle_transformers::utils::repeat_kv;
+use std::sync::Arc;
+
+use super::config::Config;
+use crate::models::common::{
+    KvCache, KvLayers, PartialRotaryEmbedding, RopeShift, causal_mask,
+};
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    gate_up_proj: Linear,
+    down_proj: Linear,
+    act_fn: Activation,
+    intermediate_size: usize,
+}
+
+impl Mlp {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            gate_up_proj: linear_no_bias(
+                cfg.hidden_size,
+                2 * cfg.intermediate_size,
+                vb.pp("gate_up_proj"),
+            )?,
+            down_proj: linear_no_bias(cfg.intermediate_size, cfg.hidden_size, vb.pp("down_proj"))?,
+            act_fn: Activation::Silu,
+            intermediate_size: cfg.intermediate_size,
+        })
+    }
+}
+
+impl Module for Mlp {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let up_states = self.gate_up_proj.forward(x)?;
+        let gate = up_states.narrow(D::Minus1, 0, self.intermediate_size)?;
+        let up = up_states.narrow(D::Minus1, self.intermediate_size, self.intermediate_size)?;
+        self.down_proj.forward(&(gate.apply(&self.act_fn)? * up)?)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Attention {
+    qkv_proj: Linear,
+    o_proj: Linear,
+    num_heads: usize,
+    num_kv_heads: usize,
+    num_kv_groups: usize,
+    head_dim: usize,
+    rotary_emb: Arc<PartialRotaryEmbedding>,
+    kv_cache: KvCache,
+}
+
+impl Attention {
+    fn new(cfg: &Config, rotary_emb: Arc<PartialRotaryEmbedding>, vb: VarBuilder) -> Result<Self> {
+        let head_dim = cfg.head_dim;
+        let op_size = (cfg.num_attention_heads + 2 * cfg.num_key_value_heads) * head_dim;
+        Ok(Self {
+            qkv_proj: linear_no_bias(cfg.hidden_size, op_size, vb.pp("qkv_proj"))?,
+            o_proj: linear_no_bias(
+                cfg.num_attention_heads * head_dim,
+                cfg.hidden_size,
+                vb.pp("o_proj"),
+            )?,
+            num_heads: cfg.num_attention_heads,
+            num_kv_heads: cfg.num_key_value_heads,
+            num_kv_groups: cfg.num_attention_heads / cfg.num_key_value_heads,
+            head_dim,
+            rotary_emb,
+            kv_cache: KvCache::new(2),
+        })
+    }
+
+    fn forward(&mut self, x: &Tensor, mask: Option<&Tensor>, offset: usize) -> Result<Tensor> {
+        let (b, l, _) = x.dims3()?;
+
+        let qkv = self.qkv_proj.forward(x)?;
+        let q_dim = self.num_heads * self.head_dim;
+        let kv_dim = self.num_kv_heads * self.head_dim;
+        let q = qkv
+            .narrow(D::Minus1, 0, q_dim)?
+            .reshape((b, l, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let k = qkv
+            .narrow(D::Minus1, q_dim, kv_dim)?
+            .reshape((b, l, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let v = qkv
+            .narrow(D::Minus1, q_dim + kv_dim, kv_dim)?
+            .reshape((b, l, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+
+        let (q, k) = self.rotary_emb.apply(&q, &k, offset)?;
+        let (k, v) = self.kv_cache.append(&k, &v)?;
+
+        let k = repeat_kv(k, self.num_kv_groups)?.contiguous()?;
+        let v = repeat_kv(v, self.num_kv_groups)?.contiguous()?;
+
+        let scale = 1.0 / (self.head_dim as f64).sqrt();
+        let mut scores = (q.matmul(&k.transpose(2, 3)?)? * scale)?;
+        if let Some(m) = mask {
+            scores = scores.broadcast_add(&m.to_dtype(scores.dtype())?)?;
+        }
+        let probs = candle_nn::ops::softmax_last_dim(&scores)?;
+        let ctx = probs.matmul(&v)?;
+        let ctx = ctx
+            .transpose(1, 2)?
+            .reshape((b, l, self.num_heads * self.head_dim))?;
+        self.o_proj.forward(&ctx)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DecoderLayer {
+    self_attn: Attention,
+    mlp: Mlp,
+    input_layernorm: RmsNorm,
+    post_attention_layernorm: RmsNorm,
+}
+
+impl DecoderLayer {
+    fn new(cfg: &Config, rotary_emb: Arc<PartialRotaryEmbedding>, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            self_attn: Attention::new(cfg, rotary_emb, vb.pp("self_attn"))?,
+            mlp: Mlp::new(cfg, vb.pp("mlp"))?,
+            input_layernorm: RmsNorm::new(
+                cfg.hidden_size,
+                cfg.rms_norm_eps,
+                vb.pp("input_layernorm"),
+            )?,
+            post_attention_layernorm: RmsNorm::new(
+                cfg.hidden_size,
+                cfg.rms_norm_eps,
+                vb.pp("post_attention_layernorm"),
+            )?,
+        })
+    }
+
+    fn forward(&mut self, x: &Tensor, mask: Option<&Tensor>, offset: usize) -> Result<Tensor> {
+        let h = self.input_layernorm.forward(x)?;
+        let h = self.self_attn.forward(&h, mask, offset)?;
+        let x = (x + h)?;
+        let h = self
+            .post_attention_layernorm
+            .forward(&x)?
+            .apply(&self.mlp)?;
+        x + h
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Model {
+    embed_tokens: candle_nn::Embedding,
+    layers: Vec<DecoderLayer>,
+    norm: RmsNorm,
+    lm_head: Linear,
+    rope: Option<RopeShift>,
+    device: Device,
+    dtype: DType,
+}
+
+impl Model {
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let vb_m = vb.pp("model");
+        let embed_tokens =
+            candle_nn::embedding(cfg.vocab_size, cfg.hidden_size, vb_m.pp("embed_tokens"))?;
+        let rotary_emb = Arc::new(PartialRotaryEmbedding::new(
+            vb.dtype(),
+            cfg.rotary_dim,
+            cfg.rope_theta,
+            cfg.max_seq_len(),
+            cfg.rope_scaling.as_ref(),
+            cfg.long_rope.as_ref(),
+            vb.device(),
+        )?);
+        let vb_l = vb_m.pp("layers");
+        let layers = (0..cfg.num_hidden_layers)
+            .map(|i| DecoderLayer::new(cfg, rotary_emb.clone(), vb_l.pp(i)))
+            .collect::<Result<Vec<_>>>()?;
+        let lm_head = if cfg.tie_word_embeddings {
+            Linear::from_weights(embed_tokens.embeddings().clone(), None)
+        } else {
+            linear_no_bias(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head"))?
+        };
+        Ok(Self {
+            embed_tokens,
+            layers,
+            norm: RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb_m.pp("norm"))?,
+            lm_head,
+            rope: cfg.rope_shift(),
+            device: vb.device().clone(),
+            dtype: vb.dtype(),
+        })
+    }
+
+    /// Скрытые состояния после финальной нормализации [batch, seq_len, hidden_size]
+    fn forward_hidden(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        let (b, l) = input.dims2()?;
+        let mut h = self.embed_tokens.forward(input)?;
+        let mask = if l == 1 {
+            None
+        } else {
+            Some(causal_mask(b, l, offset, self.dtype, &self.device)?)
+        };
+        for layer in &mut self.layers {
+            h = layer.forward(&h, mask.as_ref(), offset)?;
+        }
+        self.norm.forward(&h)
+    }
+
+    /// Логиты последней позиции [batch, 1, vocab_size]
+    pub fn forward(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        let (_, l) = input.dims2()?;
+        self.forward_hidden(input, offset)?
+            .narrow(1, l - 1, 1)?
+            .apply(&self.lm_head)
+    }
+
+    /// Returns logits for every input position [batch, seq_len, vocab_size]
+    pub fn forward_all(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        self.forward_hidden(input, offset)?.apply(&self.lm_head)
+    }
+
+    /// Returns hidden states of the last layer after normalization [batch, seq_len, hidden_size]
+    pub fn get_hidden_states(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        self.forward_hidden(input, offset)
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        for layer in &mut self.layers {
+            layer.self_attn.kv_cache.reset();
+        }
+    }
+
+    /// Whether context shifting is possible (not with LongRoPE)
+    pub fn supports_context_shift(&self) -> bool {
+        self.rope.is_some()
+    }
+}
+
+impl KvLayers for Model {
+    fn kv_caches(&self) -> Vec<&KvCache> {
+        self.layers.iter().map(|l| &l.self_attn.kv_cache).collect()
+    }
+
+    fn kv_caches_mut(&mut self) -> Vec<&mut KvCache> {
+        self.layers
+            .iter_mut()
+            .map(|l| &mut l.self_attn.kv_cache)
+            .collect()
+    }
+
+    fn shift_rope(&self, _layer: usize) -> Result<RopeShift> {
+        self.rope.ok_or_else(|| {
+            candle::Error::Msg("Context shift is not supported with LongRoPE".into())
+        })
+    }
+}
@@ -0,0 +1,304 @@
+//! Квантизированная Phi-3 / Phi-4 из GGUF
+//!
+//! Аналог candle_transformers::models::quantized_phi3 с открытым KV-кэшем,
+//! частичным RoPE и LongRoPE (тензоры `rope_factors_short` / `rope_factors_long`).
+//! Поддерживаются как слитые (`attn_qkv`, `ffn_up` = gate | up), так и
+//! раздельные проекции.
+
+use crate::models::common::gguf_mmap::{Gguf, GgufRead};
+use candle::quantized::gguf_file;
+use candle::{D, DType, Device, Result, Tensor};
+use candle_nn::{Activation, Embedding, Module};
+use candle_transformers::models::with_tracing::QMatMul;
+use candle_transformers::quantized_nn::RmsNorm;
+use candle_transformers::utils::repeat_kv;
+use std::sync::Arc;
+
+use super::config::Config;
+use crate::models::common::{KvCache, KvLayers, PartialRotaryEmbedding, RopeShift, causal_mask};
+
+/// Проекции Q/K/V: слитая `attn_qkv` или раздельные
+#[derive(Debug, Clone)]
+enum QkvProj {
+    Fused(QMatMul),
+    Split { q: QMatMul, k: QMatMul, v: QMatMul },
+}
+
+#[derive(Debug, Clone)]
+enum GateUpProj {
+    /// `ffn_up` с выходом [gate | up]
+    Fused(QMatMul),
+    Split {
+        gate: QMatMul,
+        up: QMatMul,
+    },
+}
+
+#[derive(Debug, Clone)]
+struct MlpWeights {
+    gate_up: GateUpProj,
+    down_proj: QMatMul,
+    act_fn: Activation,
+    intermediate_size: usize,
+}
+
+impl Module for MlpWeights {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let (gate, up) = match &self.gate_up {
+            GateUpProj::Fused(proj) => {
+                let up_states = proj.forward(x)?;
+                let n = self.intermediate_size;
+                (
+                    up_states.narrow(D::Minus1, 0, n)?,
+                    up_states.narrow(D::Minus1, n, n)?,
+                )
+            }
+            GateUpProj::Split { gate, up } => (gate.forward(x)?, up.forward(x)?),
+        };
+        self.down_proj.forward(&(gate.apply(&self.act_fn)? * up)?)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct AttentionWeights {
+    qkv: QkvProj,
+    o_proj: QMatMul,
+    num_heads: usize,
+    num_kv_heads: usize,
+    num_kv_groups: usize,
+    head_dim: usize,
+    rotary_emb: Arc<PartialRotaryEmbedding>,
+    kv_cache: KvCache,
+}
+
+impl AttentionWeights {
+    fn forward(&mut self, x: &Tensor, mask: Option<&Tensor>, offset: usize) -> Result<Tensor> {
+        let (b, l, _) = x.dims3()?;
+        let q_dim = self.num_heads * self.head_dim;
+        let kv_dim = self.num_kv_heads * self.head_dim;
+        let (q, k, v) = match &self.qkv {
+            QkvProj::Fused(proj) => {
+                let qkv = proj.forward(x)?;
+                (
+                    qkv.narrow(D::Minus1, 0, q_dim)?,
+                    qkv.narrow(D::Minus1, q_dim, kv_dim)?,
+                    qkv.narrow(D::Minus1, q_dim + kv_dim, kv_dim)?,
+                )
+            }
+            QkvProj::Split { q, k, v } => (q.forward(x)?, k.forward(x)?, v.forward(x)?),
+        };
+        let q = q
+            .reshape((b, l, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let k = k
+            .reshape((b, l, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let v = v
+            .reshape((b, l, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+
+        let (q, k) = self.rotary_emb.apply(&q, &k, offset)?;
+        let (k, v) = self.kv_cache.append(&k, &v)?;
+
+        let k = repeat_kv(k, self.num_kv_groups)?.contiguous()?;
+        let v = repeat_kv(v, self.num_kv_groups)?.contiguous()?;
+
+        let scale = 1.0 / (self.head_dim as f64).sqrt();
+        let mut scores = (q.matmul(&k.transpose(2, 3)?)? * scale)?;
+        if let Some(m) = mask {
+            scores = scores.broadcast_add(&m.to_dtype(scores.dtype())?)?;
+        }
+        let probs = candle_nn::ops::softmax_last_dim(&scores)?;
+        let ctx = probs.matmul(&v)?; // (B, H, L, D)
+        let ctx = ctx
+            .transpose(1, 2)?
+            .reshape((b, l, self.num_heads * self.head_dim))?;
+        self.o_proj.forward(&ctx)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct LayerWeights {
+    self_attn: AttentionWeights,
+    mlp: MlpWeights,
+    attn_norm: RmsNorm,
+    ffn_norm: RmsNorm,
+}
+
+impl LayerWeights {
+    fn forward(&mut self, x: &Tensor, mask: Option<&Tensor>, offset: usize) -> Result<Tensor> {
+        let h = self.attn_norm.forward(x)?;
+        let h = self.self_attn.forward(&h, mask, offset)?;
+        let x = (x + h)?;
+        let h = self.ffn_norm.forward(&x)?.apply(&self.mlp)?;
+        x + h
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ModelWeights {
+    embed_tokens: Embedding,
+    layers: Vec<LayerWeights>,
+    norm: RmsNorm,
+    lm_head: QMatMul,
+    cfg: Config,
+    device: Device,
+    dtype: DType,
+}
+
+fn factors<R: GgufRead>(gg: &mut Gguf<R>, name: &str, device: &Device) -> Result<Vec<f64>> {
+    let t = gg.tensor(name)?.dequantize(device)?;
+    Ok(t.flatten_all()?
+        .to_vec1::<f32>()?
+        .into_iter()
+        .map(f64::from)
+        .collect())
+}
+
+impl ModelWeights {
+    pub fn from_gguf<R: GgufRead>(
+        ct: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+    ) -> Result<Self> {
+        let mut cfg = Config::from_gguf(&ct.metadata)?;
+        let has = |name: &str| ct.tensor_infos.contains_key(name);
+        let has_output = has("output.weight");
+        let fused_qkv = has("blk.0.attn_qkv.weight");
+        let fused_gate_up = !has("blk.0.ffn_gate.weight");
+        let has_rope_factors = has("rope_factors_long.weight");
+        let metadata = ct.metadata.clone();
+        let mut gg = Gguf::new(ct, reader, device.clone());
+        let dtype = DType::F32;
+
+        if has_rope_factors {
+            let short = factors(&mut gg, "rope_factors_short.weight", device)?;
+            let long = factors(&mut gg, "rope_factors_long.weight", device)?;
+            cfg.set_long_rope(&metadata, short, long)?;
+        }
+
+        let embed_tensor = gg.tensor("token_embd.weight")?;
+        let embed_tokens = Embedding::new(embed_tensor.dequantize(device)?, cfg.hidden_size);
+        // Phi-4-mini связывает выходную проекцию с эмбеддингами
+        let lm_head = if has_output {
+            gg.qmatmul("output.weight")?
+        } else {
+            QMatMul::from_weights(Arc::new(embed_tensor))?
+        };
+
+        let rotary_emb = Arc::new(PartialRotaryEmbedding::new(
+            dtype,
+            cfg.rotary_dim,
+            cfg.rope_theta,
+            cfg.max_seq_len(),
+            cfg.rope_scaling.as_ref(),
+            cfg.long_rope.as_ref(),
+            device,
+        )?);
+
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        for i in 0..cfg.num_hidden_layers {
+            let prefix = format!("blk.{i}");
+            let qkv = if fused_qkv {
+                QkvProj::Fused(gg.qmatmul(&format!("{prefix}.attn_qkv.weight"))?)
+            } else {
+                QkvProj::Split {
+                    q: gg.qmatmul(&format!("{prefix}.attn_q.weight"))?,
+                    k: gg.qmatmul(&format!("{prefix}.attn_k.weight"))?,
+                    v: gg.qmatmul(&format!("{prefix}.attn_v.weight"))?,
+                }
+            };
+            let gate_up = if fused_gate_up {
+                GateUpProj::Fused(gg.qmatmul(&format!("{prefix}.ffn_up.weight"))?)
+            } else {
+                GateUpProj::Split {
+                    gate: gg.qmatmul(&format!("{prefix}.ffn_gate.weight"))?,
+                    up: gg.qmatmul(&format!("{prefix}.ffn_up.weight"))?,
+                }
+            };
+            layers.push(LayerWeights {
+                self_attn: AttentionWeights {
+                    qkv,
+                    o_proj: gg.qmatmul(&format!("{prefix}.attn_output.weight"))?,
+                    num_heads: cfg.num_attention_heads,
+                    num_kv_heads: cfg.num_key_value_heads,
+                    num_kv_groups: cfg.num_attention_heads / cfg.num_key_value_heads,
+                    head_dim: cfg.head_dim,
+                    rotary_emb: rotary_emb.clone(),
+                    kv_cache: KvCache::new(2),
+                },
+                mlp: MlpWeights {
+                    gate_up,
+                    down_proj: gg.qmatmul(&format!("{prefix}.ffn_down.weight"))?,
+                    act_fn: Activation::Silu,
+                    intermediate_size: cfg.intermediate_size,
+                },
+                attn_norm: gg.rms_norm(&format!("{prefix}.attn_norm.weight"), cfg.rms_norm_eps)?,
+                ffn_norm: gg.rms_norm(&format!("{prefix}.ffn_norm.weight"), cfg.rms_norm_eps)?,
+            });
+        }
+
+        Ok(Self {
+            embed_tokens,
+            layers,
+            norm: gg.rms_norm("output_norm.weight", cfg.rms_norm_eps)?,
+            lm_head,
+            cfg,
+            device: device.clone(),
+            dtype,
+        })
+    }
+
+    /// Конфигурация модели, прочитанная из GGUF
+    pub fn config(&self) -> &Config {
+        &self.cfg
+    }
+
+    /// Логиты последней позиции [batch, vocab_size]
+    pub fn forward(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        let (b, l) = input.dims2()?;
+        let mut h = self.embed_tokens.forward(input)?;
+        let mask = if l == 1 {
+            None
+        } else {
+            Some(causal_mask(b, l, offset, self.dtype, &self.device)?)
+        };
+        for layer in &mut self.layers {
+            h = layer.forward(&h, mask.as_ref(), offset)?;
+        }
+        let h = self.norm.forward(&h)?;
+        let last_hidden = h.narrow(1, l - 1, 1)?;
+        self.lm_head.forward(&last_hidden)?.squeeze(1)
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        for layer in &mut self.layers {
+            layer.self_attn.kv_cache.reset();
+        }
+    }
+
+    /// Возможен ли сдвиг контекста (не с LongRoPE)
+    pub fn supports_context_shift(&self) -> bool {
+        self.cfg.long_rope.is_none()
+    }
+}
+
+impl KvLayers for ModelWeights {
+    fn kv_caches(&self) -> Vec<&KvCache> {
+        self.layers.iter().map(|l| &l.self_attn.kv_cache).collect()
+    }
+
+    fn kv_caches_mut(&mut self) -> Vec<&mut KvCache> {
+        self.layers
+            .iter_mut()
+            .map(|l| &mut l.self_attn.kv_cache)
+            .collect()
+    }
+
+    fn shift_rope(&self, _layer: usize) -> Result<RopeShift> {
+        self.cfg.rope_shift().ok_or_else(|| {
+            candle::Error::Msg("Context shift is not supported with LongRoPE".into())
+        })
+    }
+}
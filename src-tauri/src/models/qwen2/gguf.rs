@@ -1,20 +1,23 @@
 //! Qwen2/2.5 GGUF loading
 //!
 //! Загрузка квантизированных Qwen2/2.5 моделей из GGUF формата.
-//! Использует candle_transformers::models::quantized_qwen2.
+//! Использует локальную копию quantized_qwen2 (`quantized_model.rs`).
 
 use candle::Device;
 use candle::quantized::gguf_file;
-use candle_transformers::models::quantized_qwen2::ModelWeights;
-use std::fs::File;
 
 use super::Qwen2Backend;
+use super::quantized_model::ModelWeights;
+use crate::models::common::RopeScaling;
+use crate::models::common::gguf_mmap::GgufRead;
+use crate::models::common::gguf_split::open_gguf;
+use crate::models::common::rope_scaling::rope_table_len;
 
 impl Qwen2Backend {
     /// Создаёт бекенд из GGUF Content
-    pub fn from_gguf(
+    pub fn from_gguf<R: GgufRead>(
         content: gguf_file::Content,
-        file: &mut File,
+        file: &mut R,
         device: &Device,
     ) -> Result<Self, String> {
         // Извлекаем метаданные - Qwen2 использует qwen2.* префикс
@@ -31,6 +34,10 @@ impl Qwen2Backend {
             .and_then(|v| v.to_u32().ok())
             .unwrap_or(32768) as usize;
 
+        // Таблицы RoPE (с масштабированием) строит quantized_model.rs
+        let rope_scaling = RopeScaling::from_gguf(&content.metadata, "qwen2");
+        let max_seq_len = rope_table_len(max_seq_len, rope_scaling.as_ref());
+
         log::info!(
             "Loading Qwen2/2.5 GGUF: vocab_size={}, max_seq_len={}",
             vocab_size,
@@ -51,11 +58,7 @@ impl Qwen2Backend {
 
     /// Создаёт бекенд из пути к GGUF файлу
     pub fn from_gguf_path(path: &std::path::Path, device: &Device) -> Result<Self, String> {
-        let mut file = File::open(path).map_err(|e| format!("Failed to open GGUF file: {}", e))?;
-
-        let content = gguf_file::Content::read(&mut file)
-            .map_err(|e| format!("Failed to read GGUF header: {}", e))?;
-
-        Self::from_gguf(content, &mut file, device)
+        let (content, mut reader) = open_gguf(path)?;
+        Self::from_gguf(content, &mut reader, device)
     }
 }
@@ -7,16 +7,20 @@
 //! - `mod.rs` - общий Qwen2Backend и ModelBackend реализация
 //! - `gguf.rs` - загрузка из GGUF формата
 //! - `safetensors.rs` - загрузка из SafeTensors формата
+//! - `model.rs` / `quantized_model.rs` - модели с доступом к скрытым состояниям
 
 mod gguf;
+pub mod model;
+pub mod quantized_model;
 mod safetensors;
 
 use candle::{Device, Tensor};
-use candle_transformers::models::quantized_qwen2::ModelWeights as QuantizedQwen2;
-use candle_transformers::models::qwen2::ModelForCausalLM;
+use model::ModelForCausalLM;
+use quantized_model::ModelWeights as QuantizedQwen2;
 
 use crate::models::ModelBackend;
 use crate::models::api::optimization::{OptimizationConfig, WeightFormat};
+use crate::models::common::LoraAdapter;
 
 /// Qwen2/2.5 бекенд
 ///
@@ -106,11 +110,7 @@ impl ModelBackend for Qwen2Backend {
 
     fn clear_kv_cache(&mut self) {
         match &mut self.inner {
-            Qwen2Inner::Quantized(_model) => {
-                // quantized_qwen2::ModelWeights doesn't expose clear_kv_cache() in candle-transformers
-                // KV cache is internal to the model layers
-                log::debug!("clear_kv_cache called on Qwen2 GGUF (no-op)");
-            }
+            Qwen2Inner::Quantized(model) => model.clear_kv_cache(),
             Qwen2Inner::Full(model) => model.clear_kv_cache(),
         }
     }
@@ -139,4 +139,30 @@ impl ModelBackend for Qwen2Backend {
     fn supports_flash_attn(&self) -> bool {
         self.optimization.uses_flash_attn()
     }
+
+    fn supports_chunked_prefill(&self) -> bool {
+        // quantized_qwen2 строит маску (seq_len, seq_len) без учёта смещения
+        matches!(self.inner, Qwen2Inner::Full(_))
+    }
+
+    fn supports_lora(&self) -> bool {
+        matches!(self.inner, Qwen2Inner::Quantized(_))
+    }
+
+    fn set_lora_adapters(&mut self, adapters: &[LoraAdapter]) -> candle::Result<()> {
+        match &mut self.inner {
+            Qwen2Inner::Quantized(model) => model.set_lora_adapters(adapters),
+            // Полная модель вливает адаптеры в веса при загрузке
+            Qwen2Inner::Full(_) => candle::bail!(
+                "LoRA adapters of safetensors Qwen2 are merged at load time; reload the model to change them"
+            ),
+        }
+    }
+
+    fn get_embeddings(&mut self, input: &Tensor) -> candle::Result<Tensor> {
+        match &mut self.inner {
+            Qwen2Inner::Quantized(model) => model.get_hidden_states(input, 0),
+            Qwen2Inner::Full(model) => model.get_hidden_states(input, 0),
+        }
+    }
 }
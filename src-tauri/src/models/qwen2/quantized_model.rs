@@ -0,0 +1,473 @@
+//! Local copy of quantized_qwen2 with hidden-state access
+//!
+//! This is a trimmed version of candle_transformers::models::quantized_qwen2
+//! that reads tensors through the memory-mapped GGUF reader, exposes the
+//! normalized hidden states for embeddings, lets the KV cache be cleared,
+//! which the original keeps private, builds RoPE tables with the exact
+//! frequencies of the configured scaling and wraps the projections in side-car
+//! LoRA layers.
+//!
+//! `matches_candle_quantized_qwen2` checks the copy against the upstream model.
+
+use std::collections::HashMap;
+
+use candle::quantized::{QMatMul, QTensor, gguf_file};
+use candle::{DType, Device, IndexOp, Result, Tensor};
+use candle_nn::{Embedding, Module};
+use candle_transformers::quantized_nn::RmsNorm;
+use candle_transformers::utils::repeat_kv;
+
+use crate::models::common::gguf_mmap::{GgufRead, gguf_tensor};
+use crate::models::common::rope_scaling::{rope_table_len, rope_tables};
+use crate::models::common::{LoraAdapter, LoraLinear, LoraTarget, RopeScaling, assign_lora};
+
+/// Квантизированный линейный слой с side-car LoRA
+type LoraQMatMul = LoraLinear<QMatMul>;
+
+fn lora_qmatmul(tensor: QTensor) -> Result<LoraQMatMul> {
+    let (out_dim, in_dim) = tensor.shape().dims2()?;
+    Ok(LoraLinear::new(
+        QMatMul::from_qtensor(tensor)?,
+        in_dim,
+        out_dim,
+    ))
+}
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    feed_forward_w1: LoraQMatMul,
+    feed_forward_w2: LoraQMatMul,
+    feed_forward_w3: LoraQMatMul,
+}
+
+impl Mlp {
+    fn lora_targets<'a>(&'a mut self, prefix: &str, targets: &mut Vec<LoraTarget<'a>>) {
+        targets.push(self.feed_forward_w1.target(format!("{prefix}.gate_proj")));
+        targets.push(self.feed_forward_w3.target(format!("{prefix}.up_proj")));
+        targets.push(self.feed_forward_w2.target(format!("{prefix}.down_proj")));
+    }
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let w1 = self.feed_forward_w1.forward(xs)?;
+        let w3 = self.feed_forward_w3.forward(xs)?;
+        self.feed_forward_w2
+            .forward(&(candle_nn::ops::silu(&w1)? * w3)?)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct LayerWeights {
+    attention_wq: LoraQMatMul,
+    attention_wk: LoraQMatMul,
+    attention_wv: LoraQMatMul,
+    attention_bq: Tensor,
+    attention_bk: Tensor,
+    attention_bv: Tensor,
+    attention_wo: LoraQMatMul,
+    attention_norm: RmsNorm,
+    mlp: Mlp,
+    ffn_norm: RmsNorm,
+    n_head: usize,
+    n_kv_head: usize,
+    head_dim: usize,
+    cos: Tensor,
+    sin: Tensor,
+    neg_inf: Tensor,
+    kv_cache: Option<(Tensor, Tensor)>,
+}
+
+fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: &Tensor) -> Result<Tensor> {
+    let shape = mask.shape();
+    mask.where_cond(&on_true.broadcast_as(shape.dims())?, on_false)
+}
+
+impl LayerWeights {
+    fn lora_targets<'a>(&'a mut self, prefix: &str, targets: &mut Vec<LoraTarget<'a>>) {
+        targets.push(
+            self.attention_wq
+                .target(format!("{prefix}.self_attn.q_proj")),
+        );
+        targets.push(
+            self.attention_wk
+                .target(format!("{prefix}.self_attn.k_proj")),
+        );
+        targets.push(
+            self.attention_wv
+                .target(format!("{prefix}.self_attn.v_proj")),
+        );
+        targets.push(
+            self.attention_wo
+                .target(format!("{prefix}.self_attn.o_proj")),
+        );
+        self.mlp.lora_targets(&format!("{prefix}.mlp"), targets);
+    }
+
+    fn apply_rotary_emb(&self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let (_b_sz, _n_head, seq_len, _n_embd) = x.dims4()?;
+        let cos = self.cos.narrow(0, index_pos, seq_len)?;
+        let sin = self.sin.narrow(0, index_pos, seq_len)?;
+        candle_nn::rotary_emb::rope(&x.contiguous()?, &cos, &sin)
+    }
+
+    fn forward_attn(
+        &mut self,
+        x: &Tensor,
+        mask: Option<&Tensor>,
+        index_pos: usize,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, n_embd) = x.dims3()?;
+
+        let q = self
+            .attention_wq
+            .forward(x)?
+            .broadcast_add(&self.attention_bq)?;
+        let k = self
+            .attention_wk
+            .forward(x)?
+            .broadcast_add(&self.attention_bk)?;
+        let v = self
+            .attention_wv
+            .forward(x)?
+            .broadcast_add(&self.attention_bv)?;
+
+        let q = q
+            .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let k = k
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let v = v
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+
+        let q = self.apply_rotary_emb(&q, index_pos)?;
+        let k = self.apply_rotary_emb(&k, index_pos)?;
+
+        let (k, v) = match &self.kv_cache {
+            Some((k_cache, v_cache)) if index_pos > 0 => (
+                Tensor::cat(&[k_cache, &k], 2)?,
+                Tensor::cat(&[v_cache, &v], 2)?,
+            ),
+            _ => (k, v),
+        };
+        self.kv_cache = Some((k.clone(), v.clone()));
+
+        let k = repeat_kv(k, self.n_head / self.n_kv_head)?;
+        let v = repeat_kv(v, self.n_head / self.n_kv_head)?;
+
+        let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
+        let att = match mask {
+            None => att,
+            Some(mask) => {
+                let mask = mask.broadcast_as(att.shape())?;
+                masked_fill(&att, &mask, &self.neg_inf)?
+            }
+        };
+        let att = candle_nn::ops::softmax_last_dim(&att)?;
+        // Convert to contiguous as matmul doesn't support strided vs for now.
+        let y = att.matmul(&v.contiguous()?)?;
+        let y = y.transpose(1, 2)?.reshape(&[b_sz, seq_len, n_embd])?;
+        self.attention_wo.forward(&y)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ModelWeights {
+    tok_embeddings: Embedding,
+    layers: Vec<LayerWeights>,
+    norm: RmsNorm,
+    output: LoraQMatMul,
+    masks: HashMap<usize, Tensor>,
+}
+
+fn precomput_freqs_cis(
+    head_dim: usize,
+    freq_base: f32,
+    scaling: Option<&RopeScaling>,
+    context_length: usize,
+    device: &Device,
+) -> Result<(Tensor, Tensor)> {
+    let max_len = rope_table_len(context_length, scaling);
+    rope_tables(head_dim, freq_base as f64, scaling, max_len, device)
+}
+
+impl ModelWeights {
+    pub fn from_gguf<R: GgufRead>(
+        ct: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+    ) -> Result<Self> {
+        let md_get = |s: &str| match ct.metadata.get(s) {
+            None => candle::bail!("cannot find {s} in metadata"),
+            Some(v) => Ok(v),
+        };
+
+        let head_count = md_get("qwen2.attention.head_count")?.to_u32()? as usize;
+        let head_count_kv = md_get("qwen2.attention.head_count_kv")?.to_u32()? as usize;
+        let embedding_length = md_get("qwen2.embedding_length")?.to_u32()? as usize;
+        let context_length = md_get("qwen2.context_length")?.to_u32()? as usize;
+        let rope_scaling =
+            RopeScaling::from_gguf(&ct.metadata, "qwen2").map(|s| s.resolve(context_length));
+        let block_count = md_get("qwen2.block_count")?.to_u32()? as usize;
+        let rms_norm_eps = md_get("qwen2.attention.layer_norm_rms_epsilon")?.to_f32()? as f64;
+        let rope_freq_base = md_get("qwen2.rope.freq_base")
+            .and_then(|m| m.to_f32())
+            .unwrap_or(10000f32);
+
+        let head_dim = embedding_length / head_count;
+        let neg_inf = Tensor::new(f32::NEG_INFINITY, device)?;
+
+        let tok_embeddings_q = gguf_tensor(&ct, reader, "token_embd.weight", device)?;
+        let tok_embeddings = tok_embeddings_q.dequantize(device)?;
+        let norm = RmsNorm::from_qtensor(
+            gguf_tensor(&ct, reader, "output_norm.weight", device)?,
+            rms_norm_eps,
+        )?;
+        // Без output.weight выход связан с эмбеддингами (tie_word_embeddings)
+        let output = match gguf_tensor(&ct, reader, "output.weight", device) {
+            Ok(tensor) => tensor,
+            Err(_) => tok_embeddings_q,
+        };
+
+        let (cos, sin) = precomput_freqs_cis(
+            head_dim,
+            rope_freq_base,
+            rope_scaling.as_ref(),
+            context_length,
+            device,
+        )?;
+
+        let mut layers = Vec::with_capacity(block_count);
+        for layer_idx in 0..block_count {
+            let prefix = format!("blk.{layer_idx}");
+            let mut tensor =
+                |name: &str| gguf_tensor(&ct, reader, &format!("{prefix}.{name}"), device);
+            let mlp = Mlp {
+                feed_forward_w1: lora_qmatmul(tensor("ffn_gate.weight")?)?,
+                feed_forward_w2: lora_qmatmul(tensor("ffn_down.weight")?)?,
+                feed_forward_w3: lora_qmatmul(tensor("ffn_up.weight")?)?,
+            };
+            layers.push(LayerWeights {
+                attention_wq: lora_qmatmul(tensor("attn_q.weight")?)?,
+                attention_wk: lora_qmatmul(tensor("attn_k.weight")?)?,
+                attention_wv: lora_qmatmul(tensor("attn_v.weight")?)?,
+                attention_bq: tensor("attn_q.bias")?.dequantize(device)?,
+                attention_bk: tensor("attn_k.bias")?.dequantize(device)?,
+                attention_bv: tensor("attn_v.bias")?.dequantize(device)?,
+                attention_wo: lora_qmatmul(tensor("attn_output.weight")?)?,
+                attention_norm: RmsNorm::from_qtensor(tensor("attn_norm.weight")?, rms_norm_eps)?,
+                mlp,
+                ffn_norm: RmsNorm::from_qtensor(tensor("ffn_norm.weight")?, rms_norm_eps)?,
+                n_head: head_count,
+                n_kv_head: head_count_kv,
+                head_dim,
+                cos: cos.clone(),
+                sin: sin.clone(),
+                neg_inf: neg_inf.clone(),
+                kv_cache: None,
+            });
+        }
+
+        Ok(Self {
+            tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+            layers,
+            norm,
+            output: lora_qmatmul(output)?,
+            masks: HashMap::new(),
+        })
+    }
+
+    fn mask(&mut self, t: usize, device: &Device) -> Result<Tensor> {
+        if let Some(mask) = self.masks.get(&t) {
+            Ok(mask.clone())
+        } else {
+            let mask: Vec<_> = (0..t)
+                .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
+                .collect();
+            let mask = Tensor::from_slice(&mask, (t, t), device)?;
+            self.masks.insert(t, mask.clone());
+            Ok(mask)
+        }
+    }
+
+    /// Логиты последнего токена [batch, vocab_size]
+    pub fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let (_b_sz, seq_len) = x.dims2()?;
+        let x = self.get_hidden_states(x, index_pos)?;
+        self.output.forward(&x.i((.., seq_len - 1, ..))?)
+    }
+
+    /// Скрытые состояния после финальной нормализации [batch, seq_len, hidden]
+    pub fn get_hidden_states(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let (_b_sz, seq_len) = x.dims2()?;
+        let mask = if seq_len == 1 {
+            None
+        } else {
+            Some(self.mask(seq_len, x.device())?)
+        };
+        let mut layer_in = self.tok_embeddings.forward(x)?;
+        for layer in self.layers.iter_mut() {
+            let residual = &layer_in;
+            let x = layer.attention_norm.forward(&layer_in)?;
+            let attn = layer.forward_attn(&x, mask.as_ref(), index_pos)?;
+            let x = (attn + residual)?;
+
+            let residual = &x;
+            let h = layer.ffn_norm.forward(&x)?;
+            let h = layer.mlp.forward(&h)?;
+            layer_in = (h + residual)?;
+        }
+        self.norm.forward(&layer_in)
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        for layer in &mut self.layers {
+            layer.kv_cache = None;
+        }
+    }
+
+    /// Заменяет side-car LoRA-адаптеры (имена модулей HuggingFace)
+    pub fn set_lora_adapters(&mut self, adapters: &[LoraAdapter]) -> Result<()> {
+        let mut targets = Vec::new();
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            layer.lora_targets(&format!("model.layers.{i}"), &mut targets);
+        }
+        targets.push(self.output.target("lm_head".to_string()));
+        // Активации квантизированной модели — F32
+        assign_lora(targets, adapters, DType::F32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::common::lora::LoraWeights;
+    use candle::quantized::GgmlDType;
+    use candle::quantized::gguf_file::Value;
+    use std::io::Cursor;
+
+    /// Крошечная Qwen2 в GGUF со случайными F32 весами
+    fn tiny_qwen2() -> Result<Cursor<Vec<u8>>> {
+        let dev = Device::Cpu;
+        let (vocab, hidden, ffn) = (16, 8, 12);
+        let mut tensors = vec![
+            ("token_embd.weight".to_string(), (vocab, hidden)),
+            ("output_norm.weight".to_string(), (1, hidden)),
+        ];
+        for i in 0..2 {
+            for (name, shape) in [
+                ("attn_q.weight", (hidden, hidden)),
+                ("attn_k.weight", (4, hidden)),
+                ("attn_v.weight", (4, hidden)),
+                ("attn_q.bias", (1, hidden)),
+                ("attn_k.bias", (1, 4)),
+                ("attn_v.bias", (1, 4)),
+                ("attn_output.weight", (hidden, hidden)),
+                ("attn_norm.weight", (1, hidden)),
+                ("ffn_norm.weight", (1, hidden)),
+                ("ffn_gate.weight", (ffn, hidden)),
+                ("ffn_up.weight", (ffn, hidden)),
+                ("ffn_down.weight", (hidden, ffn)),
+            ] {
+                tensors.push((format!("blk.{i}.{name}"), shape));
+            }
+        }
+        let tensors = tensors
+            .into_iter()
+            .map(|(name, (rows, cols))| {
+                let t = Tensor::randn(0f32, 0.5, (rows, cols), &dev)?;
+                let t = if rows == 1 { t.squeeze(0)? } else { t };
+                Ok((name, QTensor::quantize(&t, GgmlDType::F32)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let metadata = [
+            ("general.architecture", Value::String("qwen2".into())),
+            ("qwen2.attention.head_count", Value::U32(2)),
+            ("qwen2.attention.head_count_kv", Value::U32(1)),
+            ("qwen2.embedding_length", Value::U32(hidden as u32)),
+            ("qwen2.context_length", Value::U32(32)),
+            ("qwen2.block_count", Value::U32(2)),
+            ("qwen2.attention.layer_norm_rms_epsilon", Value::F32(1e-6)),
+        ];
+        let metadata: Vec<_> = metadata.iter().map(|(k, v)| (*k, v)).collect();
+        let tensors: Vec<_> = tensors.iter().map(|(k, t)| (k.as_str(), t)).collect();
+        let mut buf = Cursor::new(Vec::new());
+        gguf_file::write(&mut buf, &metadata, &tensors)?;
+        buf.set_position(0);
+        Ok(buf)
+    }
+
+    #[test]
+    fn matches_candle_quantized_qwen2() -> Result<()> {
+        let dev = Device::Cpu;
+        let mut file = tiny_qwen2()?;
+        let ct = gguf_file::Content::read(&mut file)?;
+        let mut ours = ModelWeights::from_gguf(ct, &mut file, &dev)?;
+        file.set_position(0);
+        let ct = gguf_file::Content::read(&mut file)?;
+        let mut reference = candle_transformers::models::quantized_qwen2::ModelWeights::from_gguf(
+            ct, &mut file, &dev,
+        )?;
+
+        let prompt = Tensor::new(&[[1u32, 5, 9, 3]], &dev)?;
+        let next = Tensor::new(&[[7u32]], &dev)?;
+        let max_diff =
+            |a: Tensor, b: Tensor| -> Result<f32> { (a - b)?.abs()?.max_all()?.to_scalar::<f32>() };
+        assert!(max_diff(ours.forward(&prompt, 0)?, reference.forward(&prompt, 0)?)? < 1e-5);
+        assert!(max_diff(ours.forward(&next, 4)?, reference.forward(&next, 4)?)? < 1e-5);
+
+        // Скрытые состояния последнего токена дают те же логиты
+        ours.clear_kv_cache();
+        let hidden = ours.get_hidden_states(&prompt, 0)?;
+        assert_eq!(hidden.dims(), &[1, 4, 8]);
+        let logits = ours.output.forward(&hidden.i((.., 3, ..))?)?;
+        assert!(max_diff(logits, reference.forward(&prompt, 0)?)? < 1e-5);
+        Ok(())
+    }
+
+    #[test]
+    fn lora_adapters_swap_in_and_out() -> Result<()> {
+        let dev = Device::Cpu;
+        let mut file = tiny_qwen2()?;
+        let ct = gguf_file::Content::read(&mut file)?;
+        let mut model = ModelWeights::from_gguf(ct, &mut file, &dev)?;
+        let prompt = Tensor::new(&[[1u32, 5, 9, 3]], &dev)?;
+        let max_diff = |a: &Tensor, b: &Tensor| -> Result<f32> {
+            (a - b)?.abs()?.max_all()?.to_scalar::<f32>()
+        };
+        let base = model.forward(&prompt, 0)?;
+
+        let weights = |out_dim: usize, in_dim: usize| -> Result<LoraWeights> {
+            Ok(LoraWeights {
+                a: Tensor::randn(0f32, 0.5, (2, in_dim), &dev)?,
+                b: Tensor::randn(0f32, 0.5, (out_dim, 2), &dev)?,
+                scale: 1.,
+            })
+        };
+        let adapter = LoraAdapter {
+            name: "test".into(),
+            modules: HashMap::from([
+                (
+                    "model.layers.0.self_attn.q_proj".to_string(),
+                    weights(8, 8)?,
+                ),
+                ("model.layers.1.mlp.down_proj".to_string(), weights(8, 12)?),
+            ]),
+        };
+        model.set_lora_adapters(std::slice::from_ref(&adapter))?;
+        model.clear_kv_cache();
+        let adapted = model.forward(&prompt, 0)?;
+        assert!(max_diff(&adapted, &base)? > 1e-3);
+
+        // Пустой набор возвращает базовую модель
+        model.set_lora_adapters(&[])?;
+        model.clear_kv_cache();
+        assert!(max_diff(&model.forward(&prompt, 0)?, &base)? < 1e-6);
+        Ok(())
+    }
+}
@@ -1,15 +1,18 @@
 //! Qwen2/2.5 SafeTensors loading
 //!
 //! Загрузка Qwen2/2.5 моделей из SafeTensors формата.
-//! Использует candle_transformers::models::qwen2.
+//! Использует локальную копию candle_transformers::models::qwen2 (`model.rs`).
 
 use candle::{DType, Device};
-use candle_nn::VarBuilder;
-use candle_transformers::models::qwen2::{Config, ModelForCausalLM};
+use candle_transformers::models::qwen2::Config;
 use std::path::{Path, PathBuf};
 
 use super::Qwen2Backend;
+use super::model::ModelForCausalLM;
 use crate::models::api::optimization::OptimizationConfig;
+use crate::models::common::RopeScaling;
+use crate::models::common::lora::{LoraAdapter, merged_var_builder};
+use crate::models::common::rope_scaling::{resolve_rope_scaling, rope_table_len};
 
 impl Qwen2Backend {
     /// Создаёт бекенд из SafeTensors файлов
@@ -18,18 +21,24 @@ impl Qwen2Backend {
         config_path: &Path,
         device: &Device,
         dtype: DType,
+        rope_scaling: Option<RopeScaling>,
+        adapters: &[LoraAdapter],
     ) -> Result<Self, String> {
         // Загружаем конфигурацию
         let config_data =
             std::fs::read(config_path).map_err(|e| format!("Failed to read config.json: {}", e))?;
         let config: Config = serde_json::from_slice(&config_data)
             .map_err(|e| format!("Failed to parse config.json: {}", e))?;
+        let config_json: serde_json::Value = serde_json::from_slice(&config_data)
+            .map_err(|e| format!("Failed to parse config.json: {}", e))?;
+
+        let rope_scaling =
+            resolve_rope_scaling(&config_json, rope_scaling, config.max_position_embeddings);
+        let max_seq_len = rope_table_len(config.max_position_embeddings, rope_scaling.as_ref());
 
-        // Создаём VarBuilder из SafeTensors
-        let vb = unsafe {
-            VarBuilder::from_mmaped_safetensors(filenames, dtype, device)
-                .map_err(|e| format!("Failed to load SafeTensors: {}", e))?
-        };
+        // Создаём VarBuilder из SafeTensors; LoRA-адаптеры вливаются в веса при чтении
+        let (vb, lora_merge) = merged_var_builder(filenames, dtype, device, adapters)
+            .map_err(|e| format!("Failed to load SafeTensors: {}", e))?;
 
         // Создаём конфигурацию оптимизаций
         let optimization = OptimizationConfig::for_safetensors(dtype);
@@ -37,18 +46,19 @@ impl Qwen2Backend {
         log::info!(
             "Loading Qwen2/2.5 SafeTensors: vocab_size={}, max_position_embeddings={}",
             config.vocab_size,
-            config.max_position_embeddings
+            max_seq_len
         );
 
         // Создаём модель
-        let inner = ModelForCausalLM::new(&config, vb)
+        let inner = ModelForCausalLM::new(&config, rope_scaling.as_ref(), vb)
             .map_err(|e| format!("Failed to build Qwen2 model: {}", e))?;
+        lora_merge.finish()?;
 
         Ok(Self::new_full(
             inner,
             device.clone(),
             config.vocab_size,
-            config.max_position_embeddings,
+            max_seq_len,
             optimization,
         ))
     }
@@ -67,7 +77,7 @@ impl Qwen2Backend {
         // Определяем файлы весов
         let filenames = Self::find_weight_files(model_dir)?;
 
-        Self::from_safetensors(&filenames, &config_path, device, dtype)
+        Self::from_safetensors(&filenames, &config_path, device, dtype, None, &[])
     }
 
     /// Находит файлы весов в директории
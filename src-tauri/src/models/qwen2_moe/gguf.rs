@@ -0,0 +1,79 @@
+//! Qwen2-MoE GGUF loading
+//!
+//! Загрузка квантизированных Qwen1.5-MoE / Qwen2-MoE моделей из GGUF формата
+//! (архитектура llama.cpp `qwen2moe`).
+
+use candle::quantized::gguf_file;
+use candle::{DType, Device};
+
+use super::Qwen2MoeBackend;
+use super::quantized_model::GGUFQwen2Moe;
+use crate::models::common::RopeScaling;
+use crate::models::common::gguf_mmap::GgufRead;
+use crate::models::common::gguf_split::open_gguf;
+use crate::models::common::rope_scaling::rope_table_len;
+
+impl Qwen2MoeBackend {
+    /// Создаёт бекенд из GGUF Content
+    /// dtype - тип данных для вычислений attention (BF16 на GPU, F32 на CPU)
+    pub fn from_gguf<R: GgufRead>(
+        content: gguf_file::Content,
+        file: &mut R,
+        device: &Device,
+        dtype: DType,
+    ) -> Result<Self, String> {
+        let arch = content
+            .metadata
+            .get("general.architecture")
+            .and_then(|v| v.to_string().ok())
+            .cloned()
+            .unwrap_or_else(|| "qwen2moe".to_string());
+
+        let vocab_size = content
+            .metadata
+            .get(&format!("{arch}.vocab_size"))
+            .or_else(|| content.metadata.get("tokenizer.vocab_size"))
+            .and_then(|v| v.to_u32().ok())
+            .map(|v| v as usize)
+            .or_else(|| match content.metadata.get("tokenizer.ggml.tokens") {
+                Some(gguf_file::Value::Array(tokens)) => Some(tokens.len()),
+                _ => None,
+            })
+            .unwrap_or(151936);
+
+        let max_seq_len = content
+            .metadata
+            .get(&format!("{arch}.context_length"))
+            .and_then(|v| v.to_u32().ok())
+            .unwrap_or(32768) as usize;
+        let rope_scaling = RopeScaling::from_gguf(&content.metadata, &arch);
+        let max_seq_len = rope_table_len(max_seq_len, rope_scaling.as_ref());
+
+        log::info!(
+            "Loading Qwen2-MoE GGUF: vocab_size={}, max_seq_len={}, dtype={:?}",
+            vocab_size,
+            max_seq_len,
+            dtype
+        );
+
+        let inner = GGUFQwen2Moe::from_gguf(content, file, device, dtype)
+            .map_err(|e| format!("Failed to load Qwen2-MoE GGUF model: {}", e))?;
+
+        Ok(Self::new_quantized(
+            inner,
+            device.clone(),
+            vocab_size,
+            max_seq_len,
+        ))
+    }
+
+    /// Создаёт бекенд из пути к GGUF файлу
+    pub fn from_gguf_path(
+        path: &std::path::Path,
+        device: &Device,
+        dtype: DType,
+    ) -> Result<Self, String> {
+        let (content, mut reader) = open_gguf(path)?;
+        Self::from_gguf(content, &mut reader, device, dtype)
+    }
+}
@@ -1,32 +1,42 @@
 //! Qwen2-MoE model backend
 //!
 //! Mixture of Experts версия Qwen2.
-//! Поддерживает SafeTensors формат (GGUF пока не реализован в candle-transformers).
+//! Поддерживает:
+//! - SafeTensors (candle_transformers::models::qwen2_moe)
+//! - GGUF (локальная квантизированная реализация, эксперты через FusedMoeGGUF)
 //!
 //! Ключевые особенности Qwen2-MoE:
 //! - Sparse Mixture of Experts (разреженные эксперты)
 //! - Shared expert (общий эксперт для всех токенов)
 //! - Нет per-head RMSNorm (отличие от Qwen3-MoE)
 
+mod gguf;
+pub mod quantized_model;
 mod safetensors;
 
 use candle::{Device, Tensor};
 use candle_transformers::models::qwen2_moe::Model;
+use quantized_model::GGUFQwen2Moe;
 
 use crate::models::ModelBackend;
 use crate::models::api::optimization::{OptimizationConfig, WeightFormat};
+use crate::models::common::{
+    KvCacheType, KvLayers, LayerKv, StreamingWindow, embeddings_unsupported,
+};
 
 /// Внутреннее представление модели
 enum Qwen2MoeInner {
+    /// Квантизированная модель из GGUF
+    Quantized(GGUFQwen2Moe),
     /// Полная модель из SafeTensors
     Full(Model),
-    // GGUF не поддерживается в candle-transformers для qwen2_moe
 }
 
 /// Qwen2-MoE бекенд
 ///
-/// Поддерживает полные SafeTensors модели.
-/// Квантизированные GGUF модели пока не поддерживаются.
+/// Поддерживает как квантизированные (GGUF) так и полные (SafeTensors) модели.
+/// Откат, сдвиг и снимки KV-кэша доступны только для GGUF: модель
+/// candle-transformers не открывает свой кэш.
 pub struct Qwen2MoeBackend {
     inner: Qwen2MoeInner,
     device: Device,
@@ -36,6 +46,22 @@ pub struct Qwen2MoeBackend {
 }
 
 impl Qwen2MoeBackend {
+    /// Создаёт квантизированный бекенд (используется из gguf.rs)
+    pub(crate) fn new_quantized(
+        model: GGUFQwen2Moe,
+        device: Device,
+        vocab_size: usize,
+        max_seq_len: usize,
+    ) -> Self {
+        Self {
+            inner: Qwen2MoeInner::Quantized(model),
+            device,
+            vocab_size,
+            max_seq_len,
+            optimization: OptimizationConfig::for_gguf(),
+        }
+    }
+
     /// Создаёт полный бекенд (используется из safetensors.rs)
     pub(crate) fn new(
         model: Model,
@@ -58,9 +84,9 @@ impl Qwen2MoeBackend {
         &self.device
     }
 
-    /// Проверяет, квантизирована ли модель (всегда false для Qwen2-MoE)
+    /// Проверяет, квантизирована ли модель
     pub fn is_quantized(&self) -> bool {
-        false
+        matches!(self.inner, Qwen2MoeInner::Quantized(_))
     }
 
     /// Возвращает конфигурацию оптимизаций
@@ -72,6 +98,8 @@ impl Qwen2MoeBackend {
 impl ModelBackend for Qwen2MoeBackend {
     fn forward(&mut self, input: &Tensor, pos: usize) -> candle::Result<Tensor> {
         match &mut self.inner {
+            // GGUF модель возвращает [batch, vocab_size] - только последний токен
+            Qwen2MoeInner::Quantized(model) => model.forward(input, pos),
             // SafeTensors модель возвращает [batch, 1, vocab_size]
             // Извлекаем последнее измерение для совместимости с генерацией
             Qwen2MoeInner::Full(model) => {
@@ -85,13 +113,14 @@ impl ModelBackend for Qwen2MoeBackend {
 
     fn clear_kv_cache(&mut self) {
         match &mut self.inner {
+            Qwen2MoeInner::Quantized(model) => model.clear_kv_cache(),
             Qwen2MoeInner::Full(model) => model.clear_kv_cache(),
         }
     }
 
     fn model_type(&self) -> &str {
         match self.optimization.weight_format() {
-            WeightFormat::Gguf => "qwen2-moe-gguf", // На случай будущей поддержки
+            WeightFormat::Gguf => "qwen2-moe-gguf",
             WeightFormat::SafeTensors => "qwen2-moe",
         }
     }
@@ -108,4 +137,96 @@ impl ModelBackend for Qwen2MoeBackend {
         // qwen2_moe в candle-transformers не использует flash attention
         false
     }
+
+    fn supports_chunked_prefill(&self) -> bool {
+        true
+    }
+    fn forward_all(&mut self, input: &Tensor, pos: usize) -> candle::Result<Tensor> {
+        match &mut self.inner {
+            // GGUF модель уже возвращает [seq_len, vocab_size]
+            Qwen2MoeInner::Quantized(model) => model.forward_all(input, pos),
+            Qwen2MoeInner::Full(_) => {
+                candle::bail!("forward_all is not supported for Qwen2-MoE SafeTensors")
+            }
+        }
+    }
+
+    fn truncate_kv_cache(&mut self, len: usize) -> bool {
+        match &mut self.inner {
+            Qwen2MoeInner::Quantized(model) => model.truncate_kv_cache(len).is_ok(),
+            Qwen2MoeInner::Full(_) => false,
+        }
+    }
+
+    fn supports_speculative(&self) -> bool {
+        // В потоковом режиме позиции KV-кэша не совпадают с позициями генерации
+        self.is_quantized() && self.streaming_window().is_none()
+    }
+
+    fn set_streaming_window(&mut self, window: Option<StreamingWindow>) -> bool {
+        match &mut self.inner {
+            Qwen2MoeInner::Quantized(model) => {
+                model.set_streaming_window(window);
+                true
+            }
+            Qwen2MoeInner::Full(_) => false,
+        }
+    }
+
+    fn streaming_window(&self) -> Option<StreamingWindow> {
+        match &self.inner {
+            Qwen2MoeInner::Quantized(model) => model.streaming_window(),
+            Qwen2MoeInner::Full(_) => None,
+        }
+    }
+
+    fn supports_context_shift(&self) -> bool {
+        self.is_quantized()
+    }
+
+    fn shift_kv_cache(&mut self, keep: usize, discard: usize) -> bool {
+        match &mut self.inner {
+            Qwen2MoeInner::Quantized(model) => model.shift_kv_cache(keep, discard).is_ok(),
+            Qwen2MoeInner::Full(_) => false,
+        }
+    }
+
+    fn supports_kv_snapshot(&self) -> bool {
+        // В потоковом режиме кэш не соответствует позициям промпта
+        self.is_quantized() && self.streaming_window().is_none()
+    }
+
+    fn export_kv_cache(&self) -> candle::Result<Vec<LayerKv>> {
+        match &self.inner {
+            Qwen2MoeInner::Quantized(model) => model.export_kv_cache(),
+            Qwen2MoeInner::Full(_) => {
+                candle::bail!("KV cache export is not supported for Qwen2-MoE SafeTensors")
+            }
+        }
+    }
+
+    fn import_kv_cache(&mut self, layers: &[LayerKv]) -> candle::Result<()> {
+        match &mut self.inner {
+            Qwen2MoeInner::Quantized(model) => model.import_kv_cache(layers),
+            Qwen2MoeInner::Full(_) => {
+                candle::bail!("KV cache import is not supported for Qwen2-MoE SafeTensors")
+            }
+        }
+    }
+
+    fn set_kv_cache_type(&mut self, cache_type: KvCacheType) -> bool {
+        match &mut self.inner {
+            Qwen2MoeInner::Quantized(model) => model.set_kv_cache_type(cache_type).is_ok(),
+            Qwen2MoeInner::Full(_) => cache_type == KvCacheType::Full,
+        }
+    }
+
+    fn get_embeddings(&mut self, input: &Tensor) -> candle::Result<Tensor> {
+        match &mut self.inner {
+            Qwen2MoeInner::Quantized(model) => model.get_hidden_states(input, 0),
+            // candle qwen2_moe отдаёт только логиты последнего токена: эмбеддинги
+            // доступны для GGUF Qwen2-MoE или энкодера из слота эмбеддингов
+            Qwen2MoeInner::Full(_) => Err(embeddings_unsupported("Qwen2-MoE SafeTensors")),
+        }
+    }
 }
@@ -0,0 +1,404 @@
+//! Квантизированная Qwen2-MoE (Qwen1.5-MoE-A2.7B, Qwen2-57B-A14B) из GGUF
+//!
+//! Архитектура llama.cpp `qwen2moe`:
+//! - attention как в Qwen2 (bias у q/k/v, без per-head RMSNorm)
+//! - routed эксперты упакованы в `ffn_{gate,up,down}_exps` и считаются через
+//!   `FusedMoeGGUF` из qwen3_moe
+//! - shared эксперт (`ffn_{gate,up,down}_shexp`) с сигмоидным гейтом
+//!   `ffn_gate_inp_shexp`, его выход добавляется к выходу routed экспертов
+
+use crate::models::common::gguf_mmap::{Gguf, GgufRead};
+use candle::quantized::gguf_file;
+use candle::{DType, Device, Result, Tensor};
+use candle_nn::{Embedding, Linear, Module};
+use candle_transformers::models::with_tracing::QMatMul;
+use candle_transformers::quantized_nn::RmsNorm;
+use std::sync::Arc;
+
+use crate::models::common::{
+    KvCache, KvLayers, RopeScaling, RopeShift, RotaryEmbedding, StreamingWindow, causal_mask,
+};
+use crate::models::qwen3_moe::fused_moe::FusedMoeGGUF;
+use crate::models::qwen3_moe::quantized_model::QuantizedAttention;
+
+/// Параметры MoE из метаданных GGUF
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MoeParams {
+    pub num_experts: usize,
+    pub num_experts_per_tok: usize,
+    /// Размер shared эксперта (`None` — shared эксперта нет)
+    pub shared_expert_intermediate_size: Option<usize>,
+}
+
+impl MoeParams {
+    /// Читает `{arch}.expert_count`, `{arch}.expert_used_count`
+    /// и `{arch}.expert_shared_feed_forward_length`
+    pub fn from_gguf(
+        metadata: &std::collections::HashMap<String, gguf_file::Value>,
+        arch: &str,
+    ) -> Result<Self> {
+        let get = |key: &str| -> Option<usize> {
+            metadata
+                .get(&format!("{arch}.{key}"))
+                .and_then(|v| v.to_u32().ok())
+                .map(|v| v as usize)
+        };
+        let num_experts = get("expert_count")
+            .ok_or_else(|| candle::Error::Msg(format!("cannot find {arch}.expert_count")))?;
+        let num_experts_per_tok = get("expert_used_count")
+            .ok_or_else(|| candle::Error::Msg(format!("cannot find {arch}.expert_used_count")))?;
+        if num_experts_per_tok == 0 || num_experts_per_tok > num_experts {
+            candle::bail!(
+                "invalid expert_used_count {num_experts_per_tok} for {num_experts} experts"
+            );
+        }
+        Ok(Self {
+            num_experts,
+            num_experts_per_tok,
+            shared_expert_intermediate_size: get("expert_shared_feed_forward_length")
+                .filter(|&n| n > 0),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    gate_proj: QMatMul,
+    up_proj: QMatMul,
+    down_proj: QMatMul,
+}
+
+impl Mlp {
+    fn load<R: GgufRead>(gg: &mut Gguf<R>, prefix: &str, suffix: &str) -> Result<Self> {
+        Ok(Self {
+            gate_proj: gg.qmatmul(&format!("{prefix}.ffn_gate{suffix}.weight"))?,
+            up_proj: gg.qmatmul(&format!("{prefix}.ffn_up{suffix}.weight"))?,
+            down_proj: gg.qmatmul(&format!("{prefix}.ffn_down{suffix}.weight"))?,
+        })
+    }
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let gate = candle_nn::ops::silu(&self.gate_proj.forward(xs)?)?;
+        let up = self.up_proj.forward(xs)?;
+        self.down_proj.forward(&(gate * up)?)
+    }
+}
+
+/// Shared эксперт: обрабатывает все токены, выход масштабируется sigmoid(gate(x))
+struct SharedExpert {
+    mlp: Mlp,
+    gate: Option<Linear>,
+}
+
+impl Module for SharedExpert {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let ys = self.mlp.forward(xs)?;
+        match &self.gate {
+            Some(gate) => {
+                let scale = candle_nn::ops::sigmoid(&gate.forward(xs)?)?;
+                ys.broadcast_mul(&scale.to_dtype(ys.dtype())?)
+            }
+            None => Ok(ys),
+        }
+    }
+}
+
+struct SparseMoe {
+    routed: FusedMoeGGUF,
+    shared: Option<SharedExpert>,
+}
+
+enum FeedForward {
+    Sparse(SparseMoe),
+    Dense(Mlp),
+}
+
+impl FeedForward {
+    fn forward(&self, xs: &Tensor, is_prefill: bool) -> Result<Tensor> {
+        match self {
+            Self::Dense(mlp) => mlp.forward(xs),
+            Self::Sparse(moe) => {
+                let ys = moe.routed.forward(xs, is_prefill)?;
+                match &moe.shared {
+                    Some(shared) => ys + shared.forward(xs)?,
+                    None => Ok(ys),
+                }
+            }
+        }
+    }
+}
+
+struct LayerWeights {
+    self_attn: QuantizedAttention,
+    attention_norm: RmsNorm,
+    ffn: FeedForward,
+    ffn_norm: RmsNorm,
+}
+
+pub struct GGUFQwen2Moe {
+    tok_embeddings: Embedding,
+    layers: Vec<LayerWeights>,
+    norm: RmsNorm,
+    output: QMatMul,
+    dtype: DType,
+    device: Device,
+    rope: RopeShift,
+    streaming: Option<StreamingWindow>,
+}
+
+impl GGUFQwen2Moe {
+    pub fn from_gguf<R: GgufRead>(
+        ct: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+        dtype: DType,
+    ) -> Result<Self> {
+        let mut gg = Gguf::new(ct, reader, device.clone());
+        let md_get = |s: &str| match gg.metadata().get(s) {
+            None => candle::bail!("cannot find {s} in metadata"),
+            Some(v) => Ok(v),
+        };
+        let arch = md_get("general.architecture")?.to_string()?.clone();
+
+        let head_count =
+            md_get(format!("{arch}.attention.head_count").as_str())?.to_u32()? as usize;
+        let head_count_kv =
+            md_get(format!("{arch}.attention.head_count_kv").as_str())?.to_u32()? as usize;
+        let embedding_length =
+            md_get(format!("{arch}.embedding_length").as_str())?.to_u32()? as usize;
+        let head_dim = match md_get(format!("{arch}.attention.key_length").as_str()) {
+            Ok(v) => v.to_u32()? as usize,
+            Err(_) => embedding_length / head_count,
+        };
+        let context_length = md_get(format!("{arch}.context_length").as_str())?.to_u32()? as usize;
+        let block_count = md_get(format!("{arch}.block_count").as_str())?.to_u32()? as usize;
+        let rms_norm_eps =
+            md_get(format!("{arch}.attention.layer_norm_rms_epsilon").as_str())?.to_f32()? as f64;
+        let rope_freq_base = md_get(format!("{arch}.rope.freq_base").as_str())
+            .and_then(|m| m.to_f32())
+            .unwrap_or(1_000_000f32) as f64;
+        let rope_scaling =
+            RopeScaling::from_gguf(gg.metadata(), &arch).map(|s| s.resolve(context_length));
+        let moe = MoeParams::from_gguf(gg.metadata(), &arch)?;
+
+        let tok_embeddings = gg.tensor("token_embd.weight")?.dequantize(device)?;
+        let norm = gg.rms_norm("output_norm.weight", rms_norm_eps)?;
+        let output = match gg.qmatmul("output.weight") {
+            Ok(v) => v,
+            // tie_word_embeddings
+            _ => gg.qmatmul("token_embd.weight")?,
+        };
+
+        let rotary_emb = Arc::new(RotaryEmbedding::new(
+            dtype,
+            head_dim,
+            context_length,
+            rope_freq_base,
+            rope_scaling.as_ref(),
+            device,
+        )?);
+
+        let mut layers = Vec::with_capacity(block_count);
+        for layer_idx in 0..block_count {
+            let prefix = format!("blk.{layer_idx}");
+            // Слои без роутера (mlp_only_layers) хранят обычный MLP
+            let ffn = match gg.tensor(&format!("{prefix}.ffn_gate_inp.weight")) {
+                Ok(router) => {
+                    let router = router.dequantize(device)?.to_dtype(DType::F32)?;
+                    let routed = FusedMoeGGUF::new(
+                        Linear::new(router, None),
+                        Arc::new(gg.tensor(&format!("{prefix}.ffn_gate_exps.weight"))?),
+                        Arc::new(gg.tensor(&format!("{prefix}.ffn_up_exps.weight"))?),
+                        Arc::new(gg.tensor(&format!("{prefix}.ffn_down_exps.weight"))?),
+                        candle_nn::Activation::Silu,
+                        // Qwen2-MoE не перенормирует веса top-k экспертов
+                        false,
+                        moe.num_experts_per_tok,
+                        dtype,
+                    )?;
+                    let shared = match moe.shared_expert_intermediate_size {
+                        Some(_) => {
+                            let mlp = Mlp::load(&mut gg, &prefix, "_shexp")?;
+                            // Гейт хранится вектором [hidden], Linear ожидает [1, hidden]
+                            let gate =
+                                match gg.tensor(&format!("{prefix}.ffn_gate_inp_shexp.weight")) {
+                                    Ok(w) => {
+                                        let w = w.dequantize(device)?.to_dtype(DType::F32)?;
+                                        let w = w.reshape((1, embedding_length))?;
+                                        Some(Linear::new(w, None))
+                                    }
+                                    Err(_) => None,
+                                };
+                            Some(SharedExpert { mlp, gate })
+                        }
+                        None => None,
+                    };
+                    FeedForward::Sparse(SparseMoe { routed, shared })
+                }
+                Err(_) => FeedForward::Dense(Mlp::load(&mut gg, &prefix, "")?),
+            };
+
+            let attention_norm =
+                gg.rms_norm(&format!("{prefix}.attn_norm.weight"), rms_norm_eps)?;
+            let ffn_norm = gg.rms_norm(&format!("{prefix}.ffn_norm.weight"), rms_norm_eps)?;
+            let self_attn = QuantizedAttention::new(
+                &mut gg,
+                &prefix,
+                dtype,
+                head_count,
+                head_count_kv,
+                head_dim,
+                rms_norm_eps,
+                device,
+                rotary_emb.clone(),
+            )?;
+            layers.push(LayerWeights {
+                self_attn,
+                attention_norm,
+                ffn,
+                ffn_norm,
+            });
+        }
+
+        Ok(Self {
+            tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+            layers,
+            norm,
+            output,
+            dtype,
+            device: device.clone(),
+            rope: RopeShift::new(head_dim, rope_freq_base).with_scaling(rope_scaling),
+            streaming: None,
+        })
+    }
+
+    /// Прогоняет стек декодера, возвращает скрытые состояния [batch, seq_len, hidden]
+    fn forward_hidden(&mut self, x: &Tensor, offset: usize) -> Result<Tensor> {
+        let mut xs = self.tok_embeddings.forward(x)?;
+        let (b, l) = x.dims2()?;
+        // В потоковом режиме позиции следуют за длиной кэша после вытеснения
+        let offset = match (self.streaming, self.layers.first()) {
+            (Some(_), Some(layer)) => layer.self_attn.kv_cache_len(),
+            _ => offset,
+        };
+        let mask = if l == 1 {
+            None
+        } else {
+            Some(causal_mask(b, l, offset, self.dtype, &self.device)?)
+        };
+
+        for layer in self.layers.iter_mut() {
+            let residual = xs.clone();
+            let h = layer.attention_norm.forward(&xs)?;
+            let h = layer.self_attn.forward(&h, mask.as_ref(), offset)?;
+            let xs_attn = (h + residual)?;
+
+            let h = layer.ffn_norm.forward(&xs_attn)?.contiguous()?;
+            let h = layer.ffn.forward(&h, mask.is_some())?;
+            xs = (h + xs_attn)?;
+        }
+        Ok(xs)
+    }
+
+    /// Логиты последнего токена [batch, vocab_size]
+    pub fn forward(&mut self, x: &Tensor, offset: usize) -> Result<Tensor> {
+        let (_, l) = x.dims2()?;
+        let xs = self.forward_hidden(x, offset)?;
+        let xs = xs.narrow(1, l - 1, 1)?;
+        let xs = self.norm.forward(&xs)?;
+        self.output.forward(&xs)?.to_dtype(DType::F32)?.squeeze(1)
+    }
+
+    /// Скрытые состояния после финальной нормализации [batch, seq_len, hidden]
+    pub fn get_hidden_states(&mut self, x: &Tensor, offset: usize) -> Result<Tensor> {
+        let xs = self.forward_hidden(x, offset)?;
+        self.norm.forward(&xs)
+    }
+
+    /// Логиты для всех позиций [seq_len, vocab_size]
+    pub fn forward_all(&mut self, x: &Tensor, offset: usize) -> Result<Tensor> {
+        let xs = self.forward_hidden(x, offset)?;
+        let xs = self.norm.forward(&xs)?;
+        self.output.forward(&xs)?.to_dtype(DType::F32)?.squeeze(0)
+    }
+
+    /// Включает потоковый режим внимания (attention sinks + скользящее окно KV)
+    pub fn set_streaming_window(&mut self, window: Option<StreamingWindow>) {
+        self.streaming = window;
+        let rope = self.rope;
+        for layer in self.layers.iter_mut() {
+            layer.self_attn.set_streaming(window, rope);
+        }
+    }
+
+    /// Текущая конфигурация потокового режима
+    pub fn streaming_window(&self) -> Option<StreamingWindow> {
+        self.streaming
+    }
+
+    /// Очищает KV-кэш всех слоёв
+    pub fn clear_kv_cache(&mut self) {
+        for layer in self.layers.iter_mut() {
+            layer.self_attn.clear_kv_cache();
+        }
+    }
+}
+
+impl KvLayers for GGUFQwen2Moe {
+    fn kv_caches(&self) -> Vec<&KvCache> {
+        self.layers.iter().map(|l| l.self_attn.kv_cache()).collect()
+    }
+
+    fn kv_caches_mut(&mut self) -> Vec<&mut KvCache> {
+        self.layers
+            .iter_mut()
+            .map(|l| l.self_attn.kv_cache_mut())
+            .collect()
+    }
+
+    fn shift_rope(&self, _layer: usize) -> Result<RopeShift> {
+        Ok(self.rope)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle::quantized::gguf_file::Value;
+    use std::collections::HashMap;
+
+    fn metadata(entries: &[(&str, u32)]) -> HashMap<String, Value> {
+        entries
+            .iter()
+            .map(|(k, v)| (k.to_string(), Value::U32(*v)))
+            .collect()
+    }
+
+    #[test]
+    fn reads_moe_params_with_shared_expert() {
+        let md = metadata(&[
+            ("qwen2moe.expert_count", 60),
+            ("qwen2moe.expert_used_count", 4),
+            ("qwen2moe.expert_shared_feed_forward_length", 5632),
+        ]);
+        let params = MoeParams::from_gguf(&md, "qwen2moe").unwrap();
+        assert_eq!(params.num_experts, 60);
+        assert_eq!(params.num_experts_per_tok, 4);
+        assert_eq!(params.shared_expert_intermediate_size, Some(5632));
+    }
+
+    #[test]
+    fn rejects_invalid_moe_params() {
+        let md = metadata(&[("qwen2moe.expert_count", 4)]);
+        assert!(MoeParams::from_gguf(&md, "qwen2moe").is_err());
+
+        let md = metadata(&[
+            ("qwen2moe.expert_count", 4),
+            ("qwen2moe.expert_used_count", 8),
+            ("qwen2moe.expert_shared_feed_forward_length", 0),
+        ]);
+        assert!(MoeParams::from_gguf(&md, "qwen2moe").is_err());
+    }
+}
@@ -4,16 +4,19 @@
 
 use candle::Device;
 use candle::quantized::gguf_file;
-use candle_transformers::models::quantized_qwen3::ModelWeights;
-use std::fs::File;
 
 use super::Qwen3Backend;
+use super::quantized_model::ModelWeights;
+use crate::models::common::RopeScaling;
+use crate::models::common::gguf_mmap::GgufRead;
+use crate::models::common::gguf_split::open_gguf;
+use crate::models::common::rope_scaling::rope_table_len;
 
 impl Qwen3Backend {
     /// Создаёт бекенд из GGUF Content
-    pub fn from_gguf(
+    pub fn from_gguf<R: GgufRead>(
         content: gguf_file::Content,
-        file: &mut File,
+        file: &mut R,
         device: &Device,
     ) -> Result<Self, String> {
         // Извлекаем метаданные
@@ -29,6 +32,8 @@ impl Qwen3Backend {
             .get("qwen3.context_length")
             .and_then(|v| v.to_u32().ok())
             .unwrap_or(32768) as usize;
+        let rope_scaling = RopeScaling::from_gguf(&content.metadata, "qwen3");
+        let max_seq_len = rope_table_len(max_seq_len, rope_scaling.as_ref());
 
         // Создаём модель
         let inner = ModelWeights::from_gguf(content, file, device)
@@ -44,11 +49,7 @@ impl Qwen3Backend {
 
     /// Создаёт бекенд из пути к GGUF файлу
     pub fn from_gguf_path(path: &std::path::Path, device: &Device) -> Result<Self, String> {
-        let mut file = File::open(path).map_err(|e| format!("Failed to open GGUF file: {}", e))?;
-
-        let content = gguf_file::Content::read(&mut file)
-            .map_err(|e| format!("Failed to read GGUF header: {}", e))?;
-
-        Self::from_gguf(content, &mut file, device)
+        let (content, mut reader) = open_gguf(path)?;
+        Self::from_gguf(content, &mut reader, device)
     }
 }
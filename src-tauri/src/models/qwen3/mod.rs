@@ -8,19 +8,22 @@
 //! - `gguf.rs` - загрузка из GGUF формата
 //! - `safetensors.rs` - загрузка из SafeTensors формата
 //! - `model.rs` - модель с поддержкой flash-attn
+//! - `quantized_model.rs` - квантизированная модель с доступом к KV-кэшу
 
 mod gguf;
 pub mod model;
+pub mod quantized_model;
 mod safetensors;
 
 // Re-export types needed by qwen3_moe
 pub use model::{Config, Qwen3Attention, Qwen3MLP, Qwen3RotaryEmbedding};
 
 use candle::{Device, Tensor};
-use candle_transformers::models::quantized_qwen3::ModelWeights as QuantizedQwen3;
+use quantized_model::ModelWeights as QuantizedQwen3;
 
 use crate::models::ModelBackend;
 use crate::models::api::optimization::{OptimizationConfig, WeightFormat};
+use crate::models::common::{KvCacheType, LayerKv, LoraAdapter, StreamingWindow};
 
 // Use our local model with flash-attn support
 use model::ModelForCausalLM;
@@ -144,12 +147,141 @@ impl ModelBackend for Qwen3Backend {
         self.optimization.uses_flash_attn()
     }
 
-    fn get_embeddings(&mut self, input: &Tensor) -> candle::Result<Tensor> {
+    fn supports_chunked_prefill(&self) -> bool {
+        true
+    }
+
+    fn forward_all(&mut self, input: &Tensor, pos: usize) -> candle::Result<Tensor> {
         match &mut self.inner {
-            Qwen3Inner::Full(model) => model.get_hidden_states(input, 0),
+            // [batch, seq_len, vocab_size] -> [seq_len, vocab_size]
+            Qwen3Inner::Full(model) => model.forward_all(input, pos)?.squeeze(0),
+            Qwen3Inner::Quantized(_) => {
+                candle::bail!("forward_all is not supported for Qwen3-GGUF")
+            }
+        }
+    }
+
+    fn truncate_kv_cache(&mut self, len: usize) -> bool {
+        match &mut self.inner {
+            Qwen3Inner::Full(model) => model.truncate_kv_cache(len).is_ok(),
+            Qwen3Inner::Quantized(_) => false,
+        }
+    }
+
+    fn supports_speculative(&self) -> bool {
+        // В потоковом режиме позиции KV-кэша не совпадают с позициями генерации
+        matches!(self.inner, Qwen3Inner::Full(_)) && self.streaming_window().is_none()
+    }
+
+    fn supports_context_shift(&self) -> bool {
+        true
+    }
+
+    fn shift_kv_cache(&mut self, keep: usize, discard: usize) -> bool {
+        let res = match &mut self.inner {
+            Qwen3Inner::Quantized(model) => model.shift_kv_cache(keep, discard),
+            Qwen3Inner::Full(model) => model.shift_kv_cache(keep, discard),
+        };
+        res.is_ok()
+    }
+
+    fn supports_kv_snapshot(&self) -> bool {
+        // В потоковом режиме кэш не соответствует позициям промпта
+        self.streaming_window().is_none()
+    }
+
+    fn export_kv_cache(&self) -> candle::Result<Vec<LayerKv>> {
+        let layers = match &self.inner {
+            Qwen3Inner::Quantized(model) => model.export_kv_cache(),
+            Qwen3Inner::Full(model) => model.export_kv_cache(),
+        };
+        layers?.ok_or_else(|| candle::Error::Msg("KV cache is empty".into()))
+    }
+
+    fn import_kv_cache(&mut self, layers: &[LayerKv]) -> candle::Result<()> {
+        match &mut self.inner {
+            Qwen3Inner::Quantized(model) => model.import_kv_cache(layers),
+            Qwen3Inner::Full(model) => model.import_kv_cache(layers),
+        }
+    }
+
+    fn set_kv_cache_type(&mut self, cache_type: KvCacheType) -> bool {
+        let res = match &mut self.inner {
+            Qwen3Inner::Quantized(model) => model.set_kv_cache_type(cache_type),
+            Qwen3Inner::Full(model) => model.set_kv_cache_type(cache_type),
+        };
+        res.is_ok()
+    }
+
+    fn supports_lora(&self) -> bool {
+        true
+    }
+
+    fn set_lora_adapters(&mut self, adapters: &[LoraAdapter]) -> candle::Result<()> {
+        match &mut self.inner {
+            Qwen3Inner::Quantized(model) => model.set_lora_adapters(adapters),
+            Qwen3Inner::Full(model) => model.set_lora_adapters(adapters),
+        }
+    }
+
+    fn set_streaming_window(&mut self, window: Option<StreamingWindow>) -> bool {
+        match &mut self.inner {
+            Qwen3Inner::Full(model) => {
+                model.set_streaming_window(window);
+                true
+            }
+            Qwen3Inner::Quantized(_) => false,
+        }
+    }
+
+    fn streaming_window(&self) -> Option<StreamingWindow> {
+        match &self.inner {
+            Qwen3Inner::Full(model) => model.streaming_window(),
+            Qwen3Inner::Quantized(_) => None,
+        }
+    }
+
+    fn supports_batching(&self) -> bool {
+        matches!(self.inner, Qwen3Inner::Full(_)) && self.streaming_window().is_none()
+    }
+
+    fn prefill_slot(&mut self, slot: usize, input: &Tensor, pos: usize) -> candle::Result<Tensor> {
+        match &mut self.inner {
+            // [batch, 1, vocab_size] -> [batch, vocab_size]
+            Qwen3Inner::Full(model) => model.forward_slot(input, slot, pos)?.squeeze(1),
+            Qwen3Inner::Quantized(_) => {
+                candle::bail!("Cache slots are not supported for Qwen3-GGUF")
+            }
+        }
+    }
+
+    fn forward_batch(
+        &mut self,
+        tokens: &[u32],
+        slots: &[usize],
+        positions: &[usize],
+    ) -> candle::Result<Tensor> {
+        match &mut self.inner {
+            Qwen3Inner::Full(model) => {
+                let input = Tensor::new(tokens, &self.device)?.unsqueeze(1)?;
+                model.forward_batch(&input, slots, positions)?.squeeze(1)
+            }
             Qwen3Inner::Quantized(_) => {
-                candle::bail!("Embeddings not yet supported for Qwen3-GGUF (MVP)")
+                candle::bail!("Batched decoding is not supported for Qwen3-GGUF")
             }
         }
     }
+
+    fn release_slot(&mut self, slot: usize) {
+        if let Qwen3Inner::Full(model) = &mut self.inner {
+            model.release_slot(slot);
+        }
+    }
+
+    fn get_embeddings(&mut self, input: &Tensor) -> candle::Result<Tensor> {
+        match &mut self.inner {
+            Qwen3Inner::Full(model) => model.get_hidden_states(input, 0),
+            Qwen3Inner::Quantized(model) => model.get_hidden_states(input, 0),
+        }
+    }
 }
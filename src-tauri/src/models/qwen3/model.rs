@@ -1,8 +1,26 @@
 use candle::{DType, Device, Module, Result, Tensor};
-use candle_nn::{Activation, VarBuilder, kv_cache::ConcatKvCache};
+use candle_nn::{Activation, VarBuilder};
 use candle_transformers::models::with_tracing::{Linear, RmsNorm, linear_b, linear_no_bias};
+use std::collections::HashMap;
 use std::sync::Arc;
 
+use crate::models::common::rope_scaling::{rope_table_len, rope_tables};
+use crate::models::common::{
+    KvCache, KvCacheType, LayerKv, LoraAdapter, LoraLinear, LoraTarget, RopeScaling, RopeShift,
+    StreamingWindow, assign_lora, batched_decode_attention, check_layer_count,
+};
+
+/// Linear layer with side-car LoRA deltas
+type LoraProj = LoraLinear<Linear>;
+
+fn lora_linear(in_dim: usize, out_dim: usize, bias: bool, vb: VarBuilder) -> Result<LoraProj> {
+    Ok(LoraLinear::new(
+        linear_b(in_dim, out_dim, bias, vb)?,
+        in_dim,
+        out_dim,
+    ))
+}
+
 // repeat_kv helper function
 fn repeat_kv(x: Tensor, n_rep: usize) -> Result<Tensor> {
     if n_rep == 1 {
@@ -37,6 +55,9 @@ pub struct Config {
     pub hidden_act: Activation,
     #[serde(default = "default_use_flash_attn")]
     pub use_flash_attn: bool,
+    /// Масштабирование RoPE (заполняется загрузчиком из `rope_scaling` / override)
+    #[serde(skip)]
+    pub rope_scaling: Option<RopeScaling>,
 }
 
 #[derive(Debug, Clone)]
@@ -47,21 +68,12 @@ pub struct Qwen3RotaryEmbedding {
 
 impl Qwen3RotaryEmbedding {
     pub fn new(dtype: DType, cfg: &Config, dev: &Device) -> Result<Self> {
-        let dim = cfg.head_dim;
-        let max_seq_len = cfg.max_position_embeddings;
-        let inv_freq: Vec<_> = (0..dim)
-            .step_by(2)
-            .map(|i| 1f32 / cfg.rope_theta.powf(i as f64 / dim as f64) as f32)
-            .collect();
-        let inv_freq_len = inv_freq.len();
-        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?.to_dtype(DType::F32)?;
-        let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
-            .to_dtype(DType::F32)?
-            .reshape((max_seq_len, 1))?;
-        let freqs = t.matmul(&inv_freq)?;
+        let scaling = cfg.rope_scaling.as_ref();
+        let max_seq_len = rope_table_len(cfg.max_position_embeddings, scaling);
+        let (cos, sin) = rope_tables(cfg.head_dim, cfg.rope_theta, scaling, max_seq_len, dev)?;
         Ok(Self {
-            sin: freqs.sin()?.to_dtype(dtype)?,
-            cos: freqs.cos()?.to_dtype(dtype)?,
+            sin: sin.to_dtype(dtype)?,
+            cos: cos.to_dtype(dtype)?,
         })
     }
 
@@ -78,21 +90,42 @@ impl Qwen3RotaryEmbedding {
 
 #[derive(Debug, Clone)]
 pub struct Qwen3MLP {
-    gate_proj: Linear,
-    up_proj: Linear,
-    down_proj: Linear,
+    gate_proj: LoraProj,
+    up_proj: LoraProj,
+    down_proj: LoraProj,
     act_fn: Activation,
 }
 
 impl Qwen3MLP {
     pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
         Ok(Self {
-            gate_proj: linear_no_bias(cfg.hidden_size, cfg.intermediate_size, vb.pp("gate_proj"))?,
-            up_proj: linear_no_bias(cfg.hidden_size, cfg.intermediate_size, vb.pp("up_proj"))?,
-            down_proj: linear_no_bias(cfg.intermediate_size, cfg.hidden_size, vb.pp("down_proj"))?,
+            gate_proj: lora_linear(
+                cfg.hidden_size,
+                cfg.intermediate_size,
+                false,
+                vb.pp("gate_proj"),
+            )?,
+            up_proj: lora_linear(
+                cfg.hidden_size,
+                cfg.intermediate_size,
+                false,
+                vb.pp("up_proj"),
+            )?,
+            down_proj: lora_linear(
+                cfg.intermediate_size,
+                cfg.hidden_size,
+                false,
+                vb.pp("down_proj"),
+            )?,
             act_fn: cfg.hidden_act,
         })
     }
+
+    fn lora_targets<'a>(&'a mut self, prefix: &str, targets: &mut Vec<LoraTarget<'a>>) {
+        targets.push(self.gate_proj.target(format!("{prefix}.gate_proj")));
+        targets.push(self.up_proj.target(format!("{prefix}.up_proj")));
+        targets.push(self.down_proj.target(format!("{prefix}.down_proj")));
+    }
 }
 
 impl Module for Qwen3MLP {
@@ -122,10 +155,10 @@ fn flash_attn(_: &Tensor, _: &Tensor, _: &Tensor, _: f32, _: bool) -> Result<Ten
 #[derive(Debug, Clone)]
 pub struct Qwen3Attention {
     // projections
-    q_proj: Linear,
-    k_proj: Linear,
-    v_proj: Linear,
-    o_proj: Linear,
+    q_proj: LoraProj,
+    k_proj: LoraProj,
+    v_proj: LoraProj,
+    o_proj: LoraProj,
     // norms
     q_norm: RmsNorm,
     k_norm: RmsNorm,
@@ -138,7 +171,13 @@ pub struct Qwen3Attention {
     use_flash_attn: bool,
     // utils
     rotary_emb: Arc<Qwen3RotaryEmbedding>,
-    kv_cache: ConcatKvCache,
+    kv_cache: KvCache,
+    // KV-кэши слотов continuous batching (slot id -> cache)
+    slot_caches: HashMap<usize, KvCache>,
+    // Параметры RoPE для ре-ротации ключей при сдвиге контекста
+    rope_shift: RopeShift,
+    // Потоковый режим: sink-токены + скользящее окно KV
+    streaming: Option<StreamingWindow>,
 }
 
 impl Qwen3Attention {
@@ -156,25 +195,25 @@ impl Qwen3Attention {
         let num_kv_heads = cfg.num_key_value_heads;
         let num_kv_groups = num_heads / num_kv_heads;
 
-        let q_proj = linear_b(
+        let q_proj = lora_linear(
             cfg.hidden_size,
             num_heads * head_dim,
             cfg.attention_bias,
             vb.pp("q_proj"),
         )?;
-        let k_proj = linear_b(
+        let k_proj = lora_linear(
             cfg.hidden_size,
             num_kv_heads * head_dim,
             cfg.attention_bias,
             vb.pp("k_proj"),
         )?;
-        let v_proj = linear_b(
+        let v_proj = lora_linear(
             cfg.hidden_size,
             num_kv_heads * head_dim,
             cfg.attention_bias,
             vb.pp("v_proj"),
         )?;
-        let o_proj = linear_b(
+        let o_proj = lora_linear(
             num_heads * head_dim,
             cfg.hidden_size,
             cfg.attention_bias,
@@ -189,7 +228,7 @@ impl Qwen3Attention {
 
         // dim=2 because we concatenate along the sequence dimension
         // For tensors of shape [batch, heads, seq, head_dim]
-        let kv_cache = ConcatKvCache::new(2);
+        let kv_cache = KvCache::new(2);
 
         Ok(Self {
             q_proj,
@@ -206,15 +245,14 @@ impl Qwen3Attention {
             use_flash_attn: cfg.use_flash_attn,
             rotary_emb,
             kv_cache,
+            slot_caches: HashMap::new(),
+            rope_shift: RopeShift::new(head_dim, cfg.rope_theta).with_scaling(cfg.rope_scaling),
+            streaming: None,
         })
     }
 
-    pub fn forward(
-        &mut self,
-        x: &Tensor,
-        attn_mask: Option<&Tensor>,
-        offset: usize,
-    ) -> Result<Tensor> {
+    /// Projections + per-head RMSNorm: returns (q, k, v) in (B, H, L, D) layout
+    fn project_qkv(&self, x: &Tensor) -> Result<(Tensor, Tensor, Tensor)> {
         let (b, l, _) = x.dims3()?;
 
         // 1. Proj
@@ -240,6 +278,17 @@ impl Qwen3Attention {
         let k_flat = self.k_norm.forward(&k_flat)?;
         let q = q_flat.reshape((b, self.num_heads, l, self.head_dim))?;
         let k = k_flat.reshape((b, self.num_kv_heads, l, self.head_dim))?;
+        Ok((q, k, v))
+    }
+
+    pub fn forward(
+        &mut self,
+        x: &Tensor,
+        attn_mask: Option<&Tensor>,
+        offset: usize,
+    ) -> Result<Tensor> {
+        let (b, l, _) = x.dims3()?;
+        let (q, k, v) = self.project_qkv(x)?;
 
         // 4. RoPE
         let (q, k) = self.rotary_emb.apply(&q, &k, offset)?;
@@ -269,15 +318,142 @@ impl Qwen3Attention {
             probs.matmul(&v)? // (B, H, L, D)
         };
 
-        // 8. Output proj
+        // 8. Streaming mode: вытесняем записи за пределами окна
+        if let Some(window) = self.streaming {
+            window.evict(&mut self.kv_cache, &self.rope_shift)?;
+        }
+
+        // 9. Output proj
         ctx.transpose(1, 2)?
             .reshape((b, l, self.hidden_size))?
             .apply(&self.o_proj)
     }
 
+    /// Batched decode step: one token per sequence, each with its own cache slot
+    ///
+    /// Projections and attention run on the whole batch; slot caches of
+    /// different lengths are padded and masked by `batched_decode_attention`.
+    pub fn forward_batch(
+        &mut self,
+        x: &Tensor,
+        slots: &[usize],
+        offsets: &[usize],
+    ) -> Result<Tensor> {
+        let (b, l, _) = x.dims3()?;
+        if l != 1 || slots.len() != b || offsets.len() != b {
+            candle::bail!("forward_batch expects one token per slot")
+        }
+        let (q, k, v) = self.project_qkv(x)?;
+
+        // RoPE offset differs per sequence
+        let mut qs = Vec::with_capacity(b);
+        let mut ks = Vec::with_capacity(b);
+        for (i, &offset) in offsets.iter().enumerate() {
+            let (q_i, k_i) =
+                self.rotary_emb
+                    .apply(&q.narrow(0, i, 1)?, &k.narrow(0, i, 1)?, offset)?;
+            qs.push(q_i);
+            ks.push(k_i);
+        }
+        let q = Tensor::cat(&qs, 0)?;
+        let k = Tensor::cat(&ks, 0)?;
+
+        let cache_type = self.kv_cache.cache_type();
+        let mut caches: Vec<KvCache> = slots
+            .iter()
+            .map(|slot| {
+                self.slot_caches
+                    .remove(slot)
+                    .unwrap_or_else(|| KvCache::with_type(2, cache_type))
+            })
+            .collect();
+        let mut cache_refs: Vec<&mut KvCache> = caches.iter_mut().collect();
+        let ctx = batched_decode_attention(&q, &k, &v, &mut cache_refs, self.num_kv_groups);
+        for (slot, cache) in slots.iter().zip(caches) {
+            self.slot_caches.insert(*slot, cache);
+        }
+
+        ctx?.transpose(1, 2)?
+            .reshape((b, l, self.hidden_size))?
+            .apply(&self.o_proj)
+    }
+
+    /// Swaps the active KV cache with the cache of `slot`
+    pub fn swap_slot_cache(&mut self, slot: usize) {
+        let cache_type = self.kv_cache.cache_type();
+        let cache = self
+            .slot_caches
+            .entry(slot)
+            .or_insert_with(|| KvCache::with_type(2, cache_type));
+        std::mem::swap(&mut self.kv_cache, cache);
+    }
+
+    /// Drops the KV cache of `slot`
+    pub fn release_slot(&mut self, slot: usize) {
+        self.slot_caches.remove(&slot);
+    }
+
     pub fn clear_kv_cache(&mut self) {
         self.kv_cache.reset();
     }
+
+    /// Обрезает KV-кэш до первых `len` позиций
+    pub fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.kv_cache.truncate(len)
+    }
+
+    /// Текущая длина активного KV-кэша
+    pub fn kv_cache_len(&self) -> usize {
+        self.kv_cache.current_seq_len()
+    }
+
+    /// Включает потоковый режим (sink-токены + скользящее окно KV)
+    pub fn set_streaming(&mut self, window: Option<StreamingWindow>) {
+        self.streaming = window;
+    }
+
+    /// Формат хранения KV-кэша (действует и для новых слотов batching)
+    pub fn set_kv_cache_type(&mut self, cache_type: KvCacheType) -> Result<()> {
+        self.slot_caches.clear();
+        self.kv_cache.set_cache_type(cache_type)
+    }
+
+    /// Снимок активного KV-кэша (`None` если кэш пуст)
+    pub fn export_kv_cache(&self) -> Result<Option<LayerKv>> {
+        self.kv_cache.export()
+    }
+
+    /// Заменяет активный KV-кэш снимком
+    pub fn import_kv_cache(&mut self, kv: &LayerKv) -> Result<()> {
+        self.kv_cache.import(kv)
+    }
+
+    /// Удаляет позиции [keep, keep + discard) из KV-кэша с ре-ротацией RoPE хвоста
+    pub fn shift_kv_cache(&mut self, keep: usize, discard: usize) -> Result<()> {
+        self.kv_cache.shift(keep, discard, &self.rope_shift)
+    }
+
+    /// Активный KV-кэш слоя
+    pub fn kv_cache(&self) -> &KvCache {
+        &self.kv_cache
+    }
+
+    /// Активный KV-кэш слоя для изменения
+    pub fn kv_cache_mut(&mut self) -> &mut KvCache {
+        &mut self.kv_cache
+    }
+
+    /// Параметры RoPE для ре-ротации ключей при сдвиге контекста
+    pub fn rope_shift(&self) -> RopeShift {
+        self.rope_shift
+    }
+
+    fn lora_targets<'a>(&'a mut self, prefix: &str, targets: &mut Vec<LoraTarget<'a>>) {
+        targets.push(self.q_proj.target(format!("{prefix}.q_proj")));
+        targets.push(self.k_proj.target(format!("{prefix}.k_proj")));
+        targets.push(self.v_proj.target(format!("{prefix}.v_proj")));
+        targets.push(self.o_proj.target(format!("{prefix}.o_proj")));
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -318,6 +494,53 @@ impl DecoderLayer {
     fn clear_kv_cache(&mut self) {
         self.self_attn.clear_kv_cache();
     }
+
+    fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.self_attn.truncate_kv_cache(len)
+    }
+
+    fn shift_kv_cache(&mut self, keep: usize, discard: usize) -> Result<()> {
+        self.self_attn.shift_kv_cache(keep, discard)
+    }
+
+    fn set_streaming(&mut self, window: Option<StreamingWindow>) {
+        self.self_attn.set_streaming(window);
+    }
+
+    fn export_kv_cache(&self) -> Result<Option<LayerKv>> {
+        self.self_attn.export_kv_cache()
+    }
+
+    fn set_kv_cache_type(&mut self, cache_type: KvCacheType) -> Result<()> {
+        self.self_attn.set_kv_cache_type(cache_type)
+    }
+
+    fn import_kv_cache(&mut self, kv: &LayerKv) -> Result<()> {
+        self.self_attn.import_kv_cache(kv)
+    }
+
+    fn forward_batch(&mut self, x: &Tensor, slots: &[usize], offsets: &[usize]) -> Result<Tensor> {
+        let h = self.ln1.forward(x)?;
+        let h = self.self_attn.forward_batch(&h, slots, offsets)?;
+        let x = (x + h)?;
+        let h2 = self.ln2.forward(&x)?;
+        let h2 = h2.apply(&self.mlp)?;
+        x + h2
+    }
+
+    fn swap_slot_cache(&mut self, slot: usize) {
+        self.self_attn.swap_slot_cache(slot);
+    }
+
+    fn release_slot(&mut self, slot: usize) {
+        self.self_attn.release_slot(slot);
+    }
+
+    fn lora_targets<'a>(&'a mut self, prefix: &str, targets: &mut Vec<LoraTarget<'a>>) {
+        self.self_attn
+            .lora_targets(&format!("{prefix}.self_attn"), targets);
+        self.mlp.lora_targets(&format!("{prefix}.mlp"), targets);
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -327,6 +550,7 @@ pub struct Model {
     norm: RmsNorm,
     device: Device,
     dtype: DType,
+    streaming: Option<StreamingWindow>,
 }
 
 impl Model {
@@ -345,6 +569,7 @@ impl Model {
             norm: RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("model.norm"))?,
             device: vb.device().clone(),
             dtype: vb.dtype(),
+            streaming: None,
         })
     }
 
@@ -354,6 +579,76 @@ impl Model {
         }
     }
 
+    fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        for l in &mut self.layers {
+            l.truncate_kv_cache(len)?;
+        }
+        Ok(())
+    }
+
+    fn shift_kv_cache(&mut self, keep: usize, discard: usize) -> Result<()> {
+        for l in &mut self.layers {
+            l.shift_kv_cache(keep, discard)?;
+        }
+        Ok(())
+    }
+
+    fn set_streaming(&mut self, window: Option<StreamingWindow>) {
+        self.streaming = window;
+        for l in &mut self.layers {
+            l.set_streaming(window);
+        }
+    }
+
+    fn export_kv_cache(&self) -> Result<Option<Vec<LayerKv>>> {
+        let layers = self
+            .layers
+            .iter()
+            .map(|l| l.export_kv_cache())
+            .collect::<Result<Vec<_>>>()?;
+        Ok(layers.into_iter().collect())
+    }
+
+    fn set_kv_cache_type(&mut self, cache_type: KvCacheType) -> Result<()> {
+        for l in &mut self.layers {
+            l.set_kv_cache_type(cache_type)?;
+        }
+        Ok(())
+    }
+
+    fn import_kv_cache(&mut self, layers: &[LayerKv]) -> Result<()> {
+        check_layer_count(layers, self.layers.len())?;
+        for (l, kv) in self.layers.iter_mut().zip(layers) {
+            l.import_kv_cache(kv)?;
+        }
+        Ok(())
+    }
+
+    fn swap_slot_cache(&mut self, slot: usize) {
+        for l in &mut self.layers {
+            l.swap_slot_cache(slot);
+        }
+    }
+
+    fn release_slot(&mut self, slot: usize) {
+        for l in &mut self.layers {
+            l.release_slot(slot);
+        }
+    }
+
+    fn forward_batch(
+        &mut self,
+        input: &Tensor,
+        slots: &[usize],
+        offsets: &[usize],
+    ) -> Result<Tensor> {
+        let mut h = self.embed_tokens.forward(input)?;
+        for layer in &mut self.layers {
+            h = layer.forward_batch(&h, slots, offsets)?;
+        }
+        self.norm.forward(&h)
+    }
+
     fn causal_mask(
         &self,
         b: usize,
@@ -380,6 +675,11 @@ impl Model {
     pub fn forward(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
         let (b, l) = input.dims2()?;
         let mut h = self.embed_tokens.forward(input)?;
+        // В потоковом режиме позиция определяется длиной кэша после вытеснения
+        let offset = match (self.streaming, self.layers.first()) {
+            (Some(_), Some(layer)) => layer.self_attn.kv_cache_len(),
+            _ => offset,
+        };
 
         let causal = if l == 1 {
             None
@@ -397,7 +697,7 @@ impl Model {
 #[derive(Debug, Clone)]
 pub struct ModelForCausalLM {
     base: Model,
-    lm_head: Linear,
+    lm_head: LoraProj,
 }
 
 impl ModelForCausalLM {
@@ -408,7 +708,21 @@ impl ModelForCausalLM {
         } else {
             linear_no_bias(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head"))?
         };
-        Ok(Self { base, lm_head })
+        Ok(Self {
+            base,
+            lm_head: LoraLinear::new(lm_head, cfg.hidden_size, cfg.vocab_size),
+        })
+    }
+
+    /// Replaces the side-car LoRA adapters (HuggingFace module names)
+    pub fn set_lora_adapters(&mut self, adapters: &[LoraAdapter]) -> Result<()> {
+        let dtype = self.base.dtype;
+        let mut targets = Vec::new();
+        for (i, layer) in self.base.layers.iter_mut().enumerate() {
+            layer.lora_targets(&format!("model.layers.{i}"), &mut targets);
+        }
+        targets.push(self.lm_head.target("lm_head".to_string()));
+        assign_lora(targets, adapters, dtype)
     }
 
     pub fn forward(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
@@ -423,6 +737,78 @@ impl ModelForCausalLM {
         self.base.clear_kv_cache();
     }
 
+    /// Returns logits for every input position [batch, seq_len, vocab_size]
+    pub fn forward_all(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        self.base.forward(input, offset)?.apply(&self.lm_head)
+    }
+
+    /// Truncates the KV cache of every layer to `len` positions
+    pub fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.base.truncate_kv_cache(len)
+    }
+
+    /// Drops positions [keep, keep + discard) from every layer's KV cache,
+    /// re-rotating the remaining keys so positions stay contiguous
+    pub fn shift_kv_cache(&mut self, keep: usize, discard: usize) -> Result<()> {
+        self.base.shift_kv_cache(keep, discard)
+    }
+
+    /// Enables streaming attention (attention sinks + sliding KV window);
+    /// positions then follow the cache length instead of the caller's offset
+    pub fn set_streaming_window(&mut self, window: Option<StreamingWindow>) {
+        self.base.set_streaming(window);
+    }
+
+    /// Current streaming attention configuration
+    pub fn streaming_window(&self) -> Option<StreamingWindow> {
+        self.base.streaming
+    }
+
+    /// Snapshot of every layer's KV cache (`None` if the cache is empty)
+    pub fn export_kv_cache(&self) -> Result<Option<Vec<LayerKv>>> {
+        self.base.export_kv_cache()
+    }
+
+    /// Switches the KV cache storage format (full precision or Q8/Q4 blocks)
+    pub fn set_kv_cache_type(&mut self, cache_type: KvCacheType) -> Result<()> {
+        self.base.set_kv_cache_type(cache_type)
+    }
+
+    /// Replaces every layer's KV cache with a snapshot from `export_kv_cache`
+    pub fn import_kv_cache(&mut self, layers: &[LayerKv]) -> Result<()> {
+        self.base.import_kv_cache(layers)
+    }
+
+    /// Forward pass on the KV cache of `slot` instead of the active one
+    ///
+    /// `offset == 0` starts a new sequence in the slot.
+    pub fn forward_slot(&mut self, input: &Tensor, slot: usize, offset: usize) -> Result<Tensor> {
+        if offset == 0 {
+            self.base.release_slot(slot);
+        }
+        self.base.swap_slot_cache(slot);
+        let res = self.forward(input, offset);
+        self.base.swap_slot_cache(slot);
+        res
+    }
+
+    /// Batched decode step over cache slots: input [n, 1] -> logits [n, 1, vocab_size]
+    pub fn forward_batch(
+        &mut self,
+        input: &Tensor,
+        slots: &[usize],
+        offsets: &[usize],
+    ) -> Result<Tensor> {
+        self.base
+            .forward_batch(input, slots, offsets)?
+            .apply(&self.lm_head)
+    }
+
+    /// Drops the KV cache of `slot`
+    pub fn release_slot(&mut self, slot: usize) {
+        self.base.release_slot(slot);
+    }
+
     /// Returns hidden states of the last layer after normalization [batch, seq_len, hidden_size]
     pub fn get_hidden_states(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
         self.base.forward(input, offset)
@@ -0,0 +1,386 @@
+//! Local copy of quantized_qwen3 with KV-cache manipulation support
+//!
+//! This is a modified version of candle_transformers::models::quantized_qwen3
+//! that exposes the KV cache for context shifting (drop + RoPE re-rotation),
+//! which is not possible with the private cache of the original.
+
+use crate::models::common::gguf_mmap::{Gguf, GgufRead};
+use candle::quantized::{QTensor, gguf_file};
+use candle::{DType, Device, Result, Tensor};
+use candle_nn::{Activation, Embedding, Module};
+use candle_transformers::models::with_tracing::QMatMul;
+use candle_transformers::quantized_nn::RmsNorm;
+use candle_transformers::utils::repeat_kv;
+use std::sync::Arc;
+
+use crate::models::common::{
+    KvCache, KvCacheType, LayerKv, LoraAdapter, LoraLinear, LoraTarget, RopeScaling, RopeShift,
+    RotaryEmbedding, assign_lora, check_layer_count,
+};
+
+/// Квантизированный линейный слой с side-car LoRA
+type LoraQMatMul = LoraLinear<QMatMul>;
+
+fn lora_qmatmul(tensor: QTensor) -> Result<LoraQMatMul> {
+    let (out_dim, in_dim) = tensor.shape().dims2()?;
+    let base = QMatMul::from_weights(Arc::new(tensor))?;
+    Ok(LoraLinear::new(base, in_dim, out_dim))
+}
+
+#[derive(Debug, Clone)]
+struct MlpWeights {
+    gate_proj: LoraQMatMul,
+    up_proj: LoraQMatMul,
+    down_proj: LoraQMatMul,
+    act_fn: Activation,
+}
+
+impl MlpWeights {
+    fn new<R: GgufRead>(gg: &mut Gguf<R>, prefix: &str) -> Result<Self> {
+        Ok(Self {
+            gate_proj: lora_qmatmul(gg.tensor(&format!("{prefix}.ffn_gate.weight"))?)?,
+            up_proj: lora_qmatmul(gg.tensor(&format!("{prefix}.ffn_up.weight"))?)?,
+            down_proj: lora_qmatmul(gg.tensor(&format!("{prefix}.ffn_down.weight"))?)?,
+            act_fn: Activation::Silu,
+        })
+    }
+
+    fn lora_targets<'a>(&'a mut self, prefix: &str, targets: &mut Vec<LoraTarget<'a>>) {
+        targets.push(self.gate_proj.target(format!("{prefix}.gate_proj")));
+        targets.push(self.up_proj.target(format!("{prefix}.up_proj")));
+        targets.push(self.down_proj.target(format!("{prefix}.down_proj")));
+    }
+}
+
+impl Module for MlpWeights {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let gate = self.gate_proj.forward(x)?.apply(&self.act_fn)?;
+        let up = self.up_proj.forward(x)?;
+        self.down_proj.forward(&(gate * up)?)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct AttentionWeights {
+    q_proj: LoraQMatMul,
+    k_proj: LoraQMatMul,
+    v_proj: LoraQMatMul,
+    o_proj: LoraQMatMul,
+    q_norm: RmsNorm,
+    k_norm: RmsNorm,
+    num_heads: usize,
+    num_kv_heads: usize,
+    num_kv_groups: usize,
+    head_dim: usize,
+    rotary_emb: Arc<RotaryEmbedding>,
+    kv_cache: KvCache,
+}
+
+impl AttentionWeights {
+    fn new<R: GgufRead>(
+        gg: &mut Gguf<R>,
+        num_heads: usize,
+        num_kv_heads: usize,
+        head_dim: usize,
+        rms_norm_eps: f64,
+        rotary_emb: Arc<RotaryEmbedding>,
+        prefix: &str,
+    ) -> Result<Self> {
+        Ok(Self {
+            q_proj: lora_qmatmul(gg.tensor(&format!("{prefix}.attn_q.weight"))?)?,
+            k_proj: lora_qmatmul(gg.tensor(&format!("{prefix}.attn_k.weight"))?)?,
+            v_proj: lora_qmatmul(gg.tensor(&format!("{prefix}.attn_v.weight"))?)?,
+            o_proj: lora_qmatmul(gg.tensor(&format!("{prefix}.attn_output.weight"))?)?,
+            q_norm: gg.rms_norm(&format!("{prefix}.attn_q_norm.weight"), rms_norm_eps)?,
+            k_norm: gg.rms_norm(&format!("{prefix}.attn_k_norm.weight"), rms_norm_eps)?,
+            num_heads,
+            num_kv_heads,
+            num_kv_groups: num_heads / num_kv_heads,
+            head_dim,
+            rotary_emb,
+            kv_cache: KvCache::new(2),
+        })
+    }
+
+    fn forward(&mut self, x: &Tensor, attn_mask: Option<&Tensor>, offset: usize) -> Result<Tensor> {
+        let (b, l, _) = x.dims3()?;
+
+        let q = self
+            .q_proj
+            .forward(x)?
+            .reshape((b, l, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let k = self
+            .k_proj
+            .forward(x)?
+            .reshape((b, l, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let v = self
+            .v_proj
+            .forward(x)?
+            .reshape((b, l, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+
+        let q = self.q_norm.forward(&q.flatten(0, 2)?)?;
+        let k = self.k_norm.forward(&k.flatten(0, 2)?)?;
+        let q = q.reshape((b, self.num_heads, l, self.head_dim))?;
+        let k = k.reshape((b, self.num_kv_heads, l, self.head_dim))?;
+
+        let (q, k) = self.rotary_emb.apply(&q, &k, offset)?;
+
+        let (k, v) = self.kv_cache.append(&k, &v)?;
+
+        let k = repeat_kv(k, self.num_kv_groups)?.contiguous()?;
+        let v = repeat_kv(v, self.num_kv_groups)?.contiguous()?;
+
+        let scale = 1.0 / (self.head_dim as f64).sqrt();
+        let mut scores = (q.matmul(&k.transpose(2, 3)?)? * scale)?;
+        if let Some(m) = attn_mask {
+            scores = scores.broadcast_add(&m.to_dtype(scores.dtype())?)?;
+        }
+        let probs = candle_nn::ops::softmax_last_dim(&scores)?;
+        let ctx = probs.matmul(&v)?; // (B, H, L, D)
+        let ctx = ctx
+            .transpose(1, 2)?
+            .reshape((b, l, self.num_heads * self.head_dim))?;
+        self.o_proj.forward(&ctx)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.kv_cache.reset();
+    }
+
+    fn kv_cache_len(&self) -> usize {
+        self.kv_cache.current_seq_len()
+    }
+
+    fn shift_kv_cache(&mut self, keep: usize, discard: usize, rope: &RopeShift) -> Result<()> {
+        self.kv_cache.shift(keep, discard, rope)
+    }
+
+    fn export_kv_cache(&self) -> Result<Option<LayerKv>> {
+        self.kv_cache.export()
+    }
+
+    fn import_kv_cache(&mut self, kv: &LayerKv) -> Result<()> {
+        self.kv_cache.import(kv)
+    }
+
+    fn lora_targets<'a>(&'a mut self, prefix: &str, targets: &mut Vec<LoraTarget<'a>>) {
+        targets.push(self.q_proj.target(format!("{prefix}.q_proj")));
+        targets.push(self.k_proj.target(format!("{prefix}.k_proj")));
+        targets.push(self.v_proj.target(format!("{prefix}.v_proj")));
+        targets.push(self.o_proj.target(format!("{prefix}.o_proj")));
+    }
+}
+
+#[derive(Debug, Clone)]
+struct LayerWeights {
+    self_attn: AttentionWeights,
+    mlp: MlpWeights,
+    ln1: RmsNorm,
+    ln2: RmsNorm,
+}
+
+impl LayerWeights {
+    fn forward(&mut self, x: &Tensor, mask: Option<&Tensor>, offset: usize) -> Result<Tensor> {
+        let h = self.ln1.forward(x)?;
+        let h = self.self_attn.forward(&h, mask, offset)?;
+        let x = (x + h)?;
+        let h2 = self.ln2.forward(&x)?.apply(&self.mlp)?;
+        x + h2
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ModelWeights {
+    embed_tokens: Embedding,
+    layers: Vec<LayerWeights>,
+    norm: RmsNorm,
+    lm_head: LoraQMatMul,
+    device: Device,
+    dtype: DType,
+    rope: RopeShift,
+}
+
+impl ModelWeights {
+    pub fn from_gguf<R: GgufRead>(
+        ct: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+    ) -> Result<Self> {
+        let rope_scaling = RopeScaling::from_gguf(&ct.metadata, "qwen3");
+        let mut gg = Gguf::new(ct, reader, device.clone());
+        let md_get = |s: &str| match gg.metadata().get(s) {
+            None => candle::bail!("cannot find {s} in metadata"),
+            Some(v) => Ok(v),
+        };
+
+        let num_attention_heads = md_get("qwen3.attention.head_count")?.to_u32()? as usize;
+        let num_kv_heads = md_get("qwen3.attention.head_count_kv")?.to_u32()? as usize;
+        let head_dim = md_get("qwen3.attention.key_length")?.to_u32()? as usize;
+        let num_layers = md_get("qwen3.block_count")?.to_u32()? as usize;
+        let hidden_size = md_get("qwen3.embedding_length")?.to_u32()? as usize;
+        let max_position_embeddings = md_get("qwen3.context_length")?.to_u32()? as usize;
+        let rms_norm_eps = md_get("qwen3.attention.layer_norm_rms_epsilon")?.to_f32()? as f64;
+        let rope_freq_base = md_get("qwen3.rope.freq_base")?.to_f32()? as f64;
+        let rope_scaling = rope_scaling.map(|s| s.resolve(max_position_embeddings));
+
+        let dtype = match gg.metadata().get("general.dtype") {
+            Some(v) => match v.to_u32() {
+                Ok(0) => DType::F32,
+                _ => DType::F16,
+            },
+            None => DType::F16,
+        };
+
+        let embed_tensor = gg.tensor("token_embd.weight")?;
+        let embed_tokens = Embedding::new(embed_tensor.dequantize(device)?, hidden_size);
+
+        let rotary = Arc::new(RotaryEmbedding::new(
+            dtype,
+            head_dim,
+            max_position_embeddings,
+            rope_freq_base,
+            rope_scaling.as_ref(),
+            device,
+        )?);
+
+        let mut layers = Vec::with_capacity(num_layers);
+        for i in 0..num_layers {
+            let prefix = format!("blk.{i}");
+            let ln1 = gg.rms_norm(&format!("{prefix}.attn_norm.weight"), rms_norm_eps)?;
+            let ln2 = gg.rms_norm(&format!("{prefix}.ffn_norm.weight"), rms_norm_eps)?;
+            let self_attn = AttentionWeights::new(
+                &mut gg,
+                num_attention_heads,
+                num_kv_heads,
+                head_dim,
+                rms_norm_eps,
+                rotary.clone(),
+                &prefix,
+            )?;
+            let mlp = MlpWeights::new(&mut gg, &prefix)?;
+            layers.push(LayerWeights {
+                self_attn,
+                mlp,
+                ln1,
+                ln2,
+            });
+        }
+
+        let norm = gg.rms_norm("output_norm.weight", rms_norm_eps)?;
+        // Tied embeddings: при отсутствии output.weight используем token_embd
+        let lm_head_tensor = match gg.tensor("output.weight") {
+            Ok(tensor) => tensor,
+            Err(_) => gg.tensor("token_embd.weight")?,
+        };
+        let lm_head = lora_qmatmul(lm_head_tensor)?;
+        Ok(Self {
+            embed_tokens,
+            layers,
+            norm,
+            lm_head,
+            device: device.clone(),
+            dtype,
+            rope: RopeShift::new(head_dim, rope_freq_base).with_scaling(rope_scaling),
+        })
+    }
+
+    fn causal_mask(&self, b: usize, tgt: usize, offset: usize) -> Result<Tensor> {
+        let minf = f32::NEG_INFINITY;
+        let mask: Vec<_> = (0..tgt)
+            .flat_map(|i| (0..(tgt + offset)).map(move |j| if j <= i + offset { 0. } else { minf }))
+            .collect();
+        Tensor::from_slice(&mask, (b, 1, tgt, tgt + offset), &self.device)?.to_dtype(self.dtype)
+    }
+
+    pub fn forward(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        let (_, l) = input.dims2()?;
+        let h = self.get_hidden_states(input, offset)?;
+        let last_hidden = h.narrow(1, l - 1, 1)?;
+        self.lm_head.forward(&last_hidden)?.squeeze(1)
+    }
+
+    /// Скрытые состояния после финальной нормализации [batch, seq_len, hidden]
+    pub fn get_hidden_states(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        let (b, l) = input.dims2()?;
+        let mut h = self.embed_tokens.forward(input)?;
+        let causal_mask = if l == 1 {
+            None
+        } else {
+            Some(self.causal_mask(b, l, offset)?)
+        };
+        for layer in &mut self.layers {
+            h = layer.forward(&h, causal_mask.as_ref(), offset)?;
+        }
+        self.norm.forward(&h)
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        for layer in &mut self.layers {
+            layer.self_attn.clear_kv_cache();
+        }
+    }
+
+    /// Текущая длина KV-кэша (по первому слою)
+    pub fn kv_cache_len(&self) -> usize {
+        self.layers
+            .first()
+            .map(|l| l.self_attn.kv_cache_len())
+            .unwrap_or(0)
+    }
+
+    /// Удаляет позиции [keep, keep + discard) из KV-кэша всех слоёв
+    /// с ре-ротацией RoPE оставшихся ключей
+    pub fn shift_kv_cache(&mut self, keep: usize, discard: usize) -> Result<()> {
+        let rope = self.rope;
+        for layer in &mut self.layers {
+            layer.self_attn.shift_kv_cache(keep, discard, &rope)?;
+        }
+        Ok(())
+    }
+
+    /// Снимок KV-кэша всех слоёв (`None` если кэш пуст)
+    pub fn export_kv_cache(&self) -> Result<Option<Vec<LayerKv>>> {
+        let layers = self
+            .layers
+            .iter()
+            .map(|l| l.self_attn.export_kv_cache())
+            .collect::<Result<Vec<_>>>()?;
+        Ok(layers.into_iter().collect())
+    }
+
+    /// Меняет формат хранения KV-кэша всех слоёв (полная точность или блоки Q8/Q4)
+    pub fn set_kv_cache_type(&mut self, cache_type: KvCacheType) -> Result<()> {
+        for layer in &mut self.layers {
+            layer.self_attn.kv_cache.set_cache_type(cache_type)?;
+        }
+        Ok(())
+    }
+
+    /// Заменяет KV-кэш всех слоёв снимком из `export_kv_cache`
+    pub fn import_kv_cache(&mut self, layers: &[LayerKv]) -> Result<()> {
+        check_layer_count(layers, self.layers.len())?;
+        for (layer, kv) in self.layers.iter_mut().zip(layers) {
+            layer.self_attn.import_kv_cache(kv)?;
+        }
+        Ok(())
+    }
+    /// Заменяет side-car LoRA-адаптеры (имена модулей HuggingFace)
+    pub fn set_lora_adapters(&mut self, adapters: &[LoraAdapter]) -> Result<()> {
+        let mut targets = Vec::new();
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            let prefix = format!("model.layers.{i}");
+            layer
+                .self_attn
+                .lora_targets(&format!("{prefix}.self_attn"), &mut targets);
+            layer
+                .mlp
+                .lora_targets(&format!("{prefix}.mlp"), &mut targets);
+        }
+        targets.push(self.lm_head.target("lm_head".to_string()));
+        // Активации квантизированной модели — F32
+        assign_lora(targets, adapters, DType::F32)
+    }
+}
@@ -10,7 +10,9 @@ use std::path::{Path, PathBuf};
 use super::Qwen3Backend;
 use super::model::{Config, ModelForCausalLM};
 use crate::models::api::optimization::OptimizationConfig;
+use crate::models::common::RopeScaling;
 use crate::models::common::is_flash_attention_available;
+use crate::models::common::rope_scaling::{resolve_rope_scaling, rope_table_len};
 
 impl Qwen3Backend {
     /// Создаёт бекенд из SafeTensors файлов (как в примере qwen)
@@ -20,12 +22,19 @@ impl Qwen3Backend {
         config_path: &Path,
         device: &Device,
         dtype: DType,
+        rope_scaling: Option<RopeScaling>,
     ) -> Result<Self, String> {
         // Загружаем конфигурацию
         let config_data =
             std::fs::read(config_path).map_err(|e| format!("Failed to read config.json: {}", e))?;
         let mut config: Config = serde_json::from_slice(&config_data)
             .map_err(|e| format!("Failed to parse config.json: {}", e))?;
+        let config_json: serde_json::Value = serde_json::from_slice(&config_data)
+            .map_err(|e| format!("Failed to parse config.json: {}", e))?;
+        config.rope_scaling =
+            resolve_rope_scaling(&config_json, rope_scaling, config.max_position_embeddings);
+        let max_seq_len =
+            rope_table_len(config.max_position_embeddings, config.rope_scaling.as_ref());
 
         // Создаём VarBuilder из SafeTensors (как в примере qwen: line 334)
         let vb = unsafe {
@@ -66,7 +75,7 @@ impl Qwen3Backend {
             inner,
             device.clone(),
             config.vocab_size,
-            config.max_position_embeddings,
+            max_seq_len,
             optimization,
         ))
     }
@@ -85,7 +94,7 @@ impl Qwen3Backend {
         // Определяем файлы весов (как в примере qwen: lines 300-321)
         let filenames = Self::find_weight_files(model_dir)?;
 
-        Self::from_safetensors(&filenames, &config_path, device, dtype)
+        Self::from_safetensors(&filenames, &config_path, device, dtype, None)
     }
 
     /// Находит файлы весов в директории (логика из примера qwen)
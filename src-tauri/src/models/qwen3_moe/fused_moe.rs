@@ -2,23 +2,118 @@
 //!
 //! This is a modified version of candle_transformers::fused_moe::FusedMoeGGUF
 //! that adds contiguous() before arg_sort to fix CUDA_ERROR_INVALID_VALUE.
+//!
+//! `moe_gemm_gguf` is only implemented for CUDA, so on other devices the packed
+//! expert tensors are split into one QMatMul per expert at load time and routed
+//! tokens are dispatched expert by expert.
 
-use candle::{D, DType, Result, Tensor, quantized::QTensor};
+use candle::quantized::{QMatMul, QStorage, QTensor};
+use candle::{D, DType, Result, Tensor};
 use candle_nn::{Activation, Linear, Module, moe};
+use std::borrow::Cow;
 use std::sync::Arc;
 
+/// Weights of a single expert (CPU/Metal path)
+struct ExpertMlp {
+    gate: QMatMul,
+    up: QMatMul,
+    down: QMatMul,
+}
+
+impl ExpertMlp {
+    fn forward(&self, xs: &Tensor, act: Activation) -> Result<Tensor> {
+        let gate = self.gate.forward(xs)?.apply(&act)?;
+        let up = self.up.forward(xs)?;
+        self.down.forward(&(gate * up)?)
+    }
+}
+
+enum Experts {
+    /// Packed [num_experts, n, k] tensors consumed by `moe_gemm_gguf` (CUDA)
+    Fused {
+        gate: Arc<QTensor>,
+        up: Arc<QTensor>,
+        down: Arc<QTensor>,
+    },
+    /// One QMatMul per expert (CPU/Metal)
+    PerExpert(Vec<ExpertMlp>),
+}
+
+/// Splits a packed [num_experts, n, k] expert tensor into per-expert matmuls
+pub(crate) fn split_experts(ws: &QTensor) -> Result<Vec<QMatMul>> {
+    let (num_experts, n, k) = ws.shape().dims3()?;
+    let dtype = ws.dtype();
+    let device = ws.device();
+    let expert_bytes = n * k / dtype.block_size() * dtype.type_size();
+    let data = ws.data()?;
+    (0..num_experts)
+        .map(|e| {
+            let bytes = &data[e * expert_bytes..(e + 1) * expert_bytes];
+            let storage = QStorage::from_data(Cow::Borrowed(bytes), &device, dtype)?;
+            QMatMul::from_arc(Arc::new(QTensor::new(storage, (n, k))?))
+        })
+        .collect()
+}
+
 pub struct FusedMoeGGUF {
-    pub gate: Linear,
-    pub gate_experts: Arc<QTensor>,
-    pub up_experts: Arc<QTensor>,
-    pub down_experts: Arc<QTensor>,
-    pub act: Activation,
-    pub norm_topk_prob: bool,
-    pub num_experts_per_tok: usize,
-    pub dtype: DType,
+    gate: Linear,
+    experts: Experts,
+    act: Activation,
+    norm_topk_prob: bool,
+    num_experts_per_tok: usize,
+    dtype: DType,
 }
 
 impl FusedMoeGGUF {
+    /// Builds the MoE block from the router and packed expert tensors
+    /// (`ffn_gate_exps` / `ffn_up_exps` / `ffn_down_exps`)
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        gate: Linear,
+        gate_experts: Arc<QTensor>,
+        up_experts: Arc<QTensor>,
+        down_experts: Arc<QTensor>,
+        act: Activation,
+        norm_topk_prob: bool,
+        num_experts_per_tok: usize,
+        dtype: DType,
+    ) -> Result<Self> {
+        let experts = if gate_experts.device().is_cuda() {
+            Experts::Fused {
+                gate: gate_experts,
+                up: up_experts,
+                down: down_experts,
+            }
+        } else {
+            let gate = split_experts(&gate_experts)?;
+            let up = split_experts(&up_experts)?;
+            let down = split_experts(&down_experts)?;
+            if gate.len() != up.len() || gate.len() != down.len() {
+                candle::bail!(
+                    "expert count mismatch: gate={}, up={}, down={}",
+                    gate.len(),
+                    up.len(),
+                    down.len()
+                );
+            }
+            Experts::PerExpert(
+                gate.into_iter()
+                    .zip(up)
+                    .zip(down)
+                    .map(|((gate, up), down)| ExpertMlp { gate, up, down })
+                    .collect(),
+            )
+        };
+        Ok(Self {
+            gate,
+            experts,
+            act,
+            norm_topk_prob,
+            num_experts_per_tok,
+            dtype,
+        })
+    }
+
     pub fn forward(&self, xs: &Tensor, is_prefill: bool) -> Result<Tensor> {
         let (batch, seq_len, hidden_dim) = xs.dims3()?;
         let xs = xs.reshape(((), hidden_dim))?.contiguous()?;
@@ -49,54 +144,299 @@ impl FusedMoeGGUF {
                 .contiguous()?;
         }
 
+        let mut ys = match &self.experts {
+            Experts::Fused { gate, up, down } => {
+                let ys =
+                    self.forward_fused(&xs, (gate, up, down), &topk_ids, topk_weights, is_prefill)?;
+                ys.reshape((num_tokens, (), hidden_dim))?
+                    .sum(D::Minus2)?
+                    .contiguous()?
+            }
+            Experts::PerExpert(experts) => {
+                Self::forward_per_expert(&xs, experts, self.act, &topk_ids, &topk_weights)?
+            }
+        };
+        if ys.dtype() != original_dtype {
+            ys = ys.to_dtype(original_dtype)?.contiguous()?;
+        }
+        ys.reshape((batch, seq_len, hidden_dim))?.contiguous()
+    }
+
+    /// CUDA path: grouped GEMM over tokens sorted by expert.
+    /// Returns [num_tokens * num_experts_per_tok, hidden]
+    fn forward_fused(
+        &self,
+        xs: &Tensor,
+        (gate_experts, up_experts, down_experts): (&QTensor, &QTensor, &QTensor),
+        topk_ids: &Tensor,
+        topk_weights: Tensor,
+        is_prefill: bool,
+    ) -> Result<Tensor> {
         // Sort for expert routing (same for prefill and decode)
-        let _ = is_prefill; // Mark as used
         let (expert_ids, sorted_token_ids) = topk_ids.flatten_all()?.sort_last_dim(true)?;
         // FIX: Ensure sorted tensors are contiguous to prevent CUDA_ERROR_INVALID_VALUE
         let expert_ids = expert_ids.contiguous()?;
         let sorted_token_ids = sorted_token_ids.contiguous()?;
 
-        let ys = {
-            let gate = moe::moe_gemm_gguf(
-                &xs,
-                &self.gate_experts,
-                &None,
-                &sorted_token_ids,
-                &expert_ids,
-                self.num_experts_per_tok,
-                is_prefill,
-                self.dtype,
-            )?;
-            let up = moe::moe_gemm_gguf(
-                &xs,
-                &self.up_experts,
-                &None,
-                &sorted_token_ids,
-                &expert_ids,
-                self.num_experts_per_tok,
-                is_prefill,
-                self.dtype,
-            )?;
-
-            let down_inputs = (up * gate.apply(&self.act)?)?.contiguous()?;
-            moe::moe_gemm_gguf(
-                &down_inputs,
-                &self.down_experts,
-                &Some(topk_weights),
-                &sorted_token_ids,
-                &expert_ids,
-                self.num_experts_per_tok,
-                is_prefill,
-                self.dtype,
-            )?
+        let gate = moe::moe_gemm_gguf(
+            xs,
+            gate_experts,
+            &None,
+            &sorted_token_ids,
+            &expert_ids,
+            self.num_experts_per_tok,
+            is_prefill,
+            self.dtype,
+        )?;
+        let up = moe::moe_gemm_gguf(
+            xs,
+            up_experts,
+            &None,
+            &sorted_token_ids,
+            &expert_ids,
+            self.num_experts_per_tok,
+            is_prefill,
+            self.dtype,
+        )?;
+
+        let down_inputs = (up * gate.apply(&self.act)?)?.contiguous()?;
+        moe::moe_gemm_gguf(
+            &down_inputs,
+            down_experts,
+            &Some(topk_weights),
+            &sorted_token_ids,
+            &expert_ids,
+            self.num_experts_per_tok,
+            is_prefill,
+            self.dtype,
+        )
+    }
+
+    /// CPU/Metal path: gathers the tokens routed to each expert, runs the expert
+    /// and scatters the weighted result back. Returns [num_tokens, hidden]
+    fn forward_per_expert(
+        xs: &Tensor,
+        experts: &[ExpertMlp],
+        act: Activation,
+        topk_ids: &Tensor,
+        topk_weights: &Tensor,
+    ) -> Result<Tensor> {
+        let topk_ids = topk_ids.to_vec2::<u32>()?;
+        let topk_weights = topk_weights.to_vec2::<f32>()?;
+
+        let mut rows = vec![Vec::new(); experts.len()];
+        let mut row_weights = vec![Vec::new(); experts.len()];
+        for (token, (ids, weights)) in topk_ids.iter().zip(&topk_weights).enumerate() {
+            for (&expert, &weight) in ids.iter().zip(weights) {
+                rows[expert as usize].push(token as u32);
+                row_weights[expert as usize].push(weight);
+            }
+        }
+
+        let mut ys = xs.zeros_like()?;
+        for ((expert, rows), weights) in experts.iter().zip(&rows).zip(&row_weights) {
+            if rows.is_empty() {
+                continue;
+            }
+            let idx = Tensor::new(rows.as_slice(), xs.device())?;
+            let weights = Tensor::new(weights.as_slice(), xs.device())?.reshape(((), 1))?;
+            let current = xs.index_select(&idx, 0)?;
+            let out = expert.forward(&current, act)?.broadcast_mul(&weights)?;
+            ys = ys.index_add(&idx, &out, 0)?;
+        }
+        Ok(ys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle::Device;
+    use candle::quantized::GgmlDType;
+
+    #[test]
+    fn per_expert_path_matches_dense_reference() -> Result<()> {
+        let dev = Device::Cpu;
+        let (num_experts, hidden, inter, top_k) = (4, 32, 64, 2);
+        let packed = |n: usize, k: usize, seed: f64| -> Result<Tensor> {
+            Tensor::arange(0f32, (num_experts * n * k) as f32, &dev)?
+                .affine(0.001, seed)?
+                .sin()?
+                .reshape((num_experts, n, k))
+        };
+        let gate_w = packed(inter, hidden, 0.1)?;
+        let up_w = packed(inter, hidden, 0.7)?;
+        let down_w = packed(hidden, inter, 1.3)?;
+        let router = Tensor::arange(0f32, (num_experts * hidden) as f32, &dev)?
+            .affine(0.37, 0.0)?
+            .cos()?
+            .reshape((num_experts, hidden))?;
+        // QTensor::quantize reads the whole storage, so views are copied out first
+        let quantize = |w: &Tensor| -> Result<Arc<QTensor>> {
+            let w = Tensor::from_vec(w.flatten_all()?.to_vec1::<f32>()?, w.shape(), &dev)?;
+            QTensor::quantize(&w, GgmlDType::Q8_0).map(Arc::new)
+        };
+        // Packed GGUF layout: expert blocks stored back to back
+        let pack = |w: &Tensor| -> Result<Arc<QTensor>> {
+            let mut bytes = Vec::new();
+            for e in 0..num_experts {
+                bytes.extend_from_slice(&quantize(&w.get(e)?)?.data()?);
+            }
+            let storage = QStorage::from_data(Cow::Borrowed(&bytes), &dev, GgmlDType::Q8_0)?;
+            Ok(Arc::new(QTensor::new(storage, w.shape())?))
         };
-        let mut ys = ys
-            .reshape((num_tokens, (), hidden_dim))?
-            .sum(D::Minus2)?
+
+        let moe = FusedMoeGGUF::new(
+            Linear::new(router.clone(), None),
+            pack(&gate_w)?,
+            pack(&up_w)?,
+            pack(&down_w)?,
+            Activation::Silu,
+            true,
+            top_k,
+            DType::F32,
+        )?;
+        assert!(matches!(moe.experts, Experts::PerExpert(ref e) if e.len() == num_experts));
+
+        let xs = Tensor::arange(0f32, (3 * hidden) as f32, &dev)?
+            .affine(0.05, 0.0)?
+            .cos()?
+            .reshape((1, 3, hidden))?;
+        let ys = moe.forward(&xs, true)?.squeeze(0)?;
+
+        // Reference: per-token loop over the top-k experts, each quantized separately
+        let probs = candle_nn::ops::softmax_last_dim(&xs.squeeze(0)?.matmul(&router.t()?)?)?;
+        for token in 0..3 {
+            let p = probs.get(token)?.to_vec1::<f32>()?;
+            let mut order: Vec<usize> = (0..num_experts).collect();
+            order.sort_by(|&a, &b| p[b].total_cmp(&p[a]));
+            let top = &order[..top_k];
+            let norm: f32 = top.iter().map(|&e| p[e]).sum();
+            let x = xs.squeeze(0)?.narrow(0, token, 1)?;
+            let mut expected = Tensor::zeros((1, hidden), DType::F32, &dev)?;
+            for &e in top {
+                let expert = ExpertMlp {
+                    gate: QMatMul::from_arc(quantize(&gate_w.get(e)?)?)?,
+                    up: QMatMul::from_arc(quantize(&up_w.get(e)?)?)?,
+                    down: QMatMul::from_arc(quantize(&down_w.get(e)?)?)?,
+                };
+                let out = expert.forward(&x, Activation::Silu)?;
+                expected = (expected + (out * (p[e] / norm) as f64)?)?;
+            }
+            let diff = (ys.narrow(0, token, 1)? - expected)?
+                .abs()?
+                .max_all()?
+                .to_scalar::<f32>()?;
+            assert!(diff < 1e-4, "token {token}: max diff {diff}");
+        }
+        Ok(())
+    }
+
+    /// CPU emulation of the baseline fused path: `moe_gemm_gguf` over slots sorted
+    /// by expert, where gate/up read row `slot / top_k`, down reads row `slot` and
+    /// scales by its routing weight, and the [tokens * top_k, hidden] output is
+    /// summed over the top-k axis
+    fn fused_reference(
+        xs: &Tensor,
+        router: &Tensor,
+        (gate_w, up_w, down_w): (&[QMatMul], &[QMatMul], &[QMatMul]),
+        top_k: usize,
+    ) -> Result<Tensor> {
+        let (num_tokens, hidden) = xs.dims2()?;
+        let routing_weights = candle_nn::ops::softmax_last_dim(&xs.matmul(&router.t()?)?)?;
+        let topk_ids = routing_weights
+            .arg_sort_last_dim(false)?
+            .narrow(D::Minus1, 0, top_k)?
             .contiguous()?;
-        if ys.dtype() != original_dtype {
-            ys = ys.to_dtype(original_dtype)?.contiguous()?;
+        let topk_weights = routing_weights.gather(&topk_ids, D::Minus1)?;
+        let topk_weights = topk_weights.broadcast_div(&topk_weights.sum_keepdim(D::Minus1)?)?;
+        let (expert_ids, sorted_token_ids) = topk_ids.flatten_all()?.sort_last_dim(true)?;
+        let expert_ids = expert_ids.to_vec1::<u32>()?;
+        let sorted_token_ids = sorted_token_ids.to_vec1::<u32>()?;
+        let slot_weights = topk_weights.flatten_all()?.to_vec1::<f32>()?;
+
+        let mut slots =
+            vec![Tensor::zeros((1, hidden), DType::F32, xs.device())?; num_tokens * top_k];
+        for (&expert, &slot) in expert_ids.iter().zip(&sorted_token_ids) {
+            let (expert, slot) = (expert as usize, slot as usize);
+            let x = xs.narrow(0, slot / top_k, 1)?;
+            let gate = gate_w[expert].forward(&x)?;
+            let up = up_w[expert].forward(&x)?;
+            let down_input = (up * gate.apply(&Activation::Silu)?)?;
+            slots[slot] = (down_w[expert].forward(&down_input)? * slot_weights[slot] as f64)?;
         }
-        ys.reshape((batch, seq_len, hidden_dim))?.contiguous()
+        Tensor::cat(&slots, 0)?
+            .reshape((num_tokens, top_k, hidden))?
+            .sum(1)
+    }
+
+    #[test]
+    fn per_expert_path_matches_baseline_fused_path() -> Result<()> {
+        let dev = Device::Cpu;
+        let (num_experts, hidden, inter, top_k, num_tokens) = (8, 32, 64, 3, 5);
+        let packed = |n: usize, k: usize, seed: f64| -> Result<Arc<QTensor>> {
+            let w = Tensor::arange(0f32, (num_experts * n * k) as f32, &dev)?
+                .affine(0.003, seed)?
+                .sin()?
+                .reshape((num_experts, n, k))?;
+            QTensor::quantize(&w, GgmlDType::Q8_0).map(Arc::new)
+        };
+        let (gate_w, up_w, down_w) = (
+            packed(inter, hidden, 0.2)?,
+            packed(inter, hidden, 0.9)?,
+            packed(hidden, inter, 1.6)?,
+        );
+        let router = Tensor::arange(0f32, (num_experts * hidden) as f32, &dev)?
+            .affine(0.53, 0.0)?
+            .sin()?
+            .reshape((num_experts, hidden))?;
+        let moe = FusedMoeGGUF::new(
+            Linear::new(router.clone(), None),
+            gate_w.clone(),
+            up_w.clone(),
+            down_w.clone(),
+            Activation::Silu,
+            true,
+            top_k,
+            DType::F32,
+        )?;
+
+        // Reference experts are read straight from the packed bytes at the
+        // [expert, n, k] offsets the CUDA kernel uses
+        let experts = |ws: &QTensor| -> Result<Vec<QMatMul>> {
+            let (_, n, k) = ws.shape().dims3()?;
+            let dtype = ws.dtype();
+            let data = ws.data()?;
+            data.chunks(n * k / dtype.block_size() * dtype.type_size())
+                .map(|bytes| {
+                    let storage = QStorage::from_data(Cow::Borrowed(bytes), &dev, dtype)?;
+                    QMatMul::from_arc(Arc::new(QTensor::new(storage, (n, k))?))
+                })
+                .collect()
+        };
+        let (gate_ref, up_ref, down_ref) = (experts(&gate_w)?, experts(&up_w)?, experts(&down_w)?);
+
+        let xs = Tensor::arange(0f32, (num_tokens * hidden) as f32, &dev)?
+            .affine(0.07, 0.3)?
+            .cos()?
+            .reshape((num_tokens, hidden))?;
+        let expected = fused_reference(&xs, &router, (&gate_ref, &up_ref, &down_ref), top_k)?;
+        for (xs, expected, is_prefill) in [
+            (xs.clone(), expected.clone(), true),
+            (xs.narrow(0, 0, 1)?, expected.narrow(0, 0, 1)?, false),
+        ] {
+            let tokens = xs.dim(0)?;
+            let ys = moe.forward(&xs.reshape((1, tokens, hidden))?, is_prefill)?;
+            let diff = (ys.reshape((tokens, hidden))? - &expected)?
+                .abs()?
+                .max_all()?
+                .to_scalar::<f32>()?;
+            let scale = expected.abs()?.max_all()?.to_scalar::<f32>()?;
+            assert!(
+                diff < 1e-4 * scale.max(1.0),
+                "prefill={is_prefill}: max diff {diff}, scale {scale}"
+            );
+        }
+        Ok(())
     }
 }
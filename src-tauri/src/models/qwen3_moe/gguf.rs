@@ -5,17 +5,20 @@
 
 use candle::quantized::gguf_file;
 use candle::{DType, Device};
-use std::fs::File;
 
 use super::Qwen3MoeBackend;
 use super::quantized_model::GGUFQWenMoE;
+use crate::models::common::RopeScaling;
+use crate::models::common::gguf_mmap::GgufRead;
+use crate::models::common::gguf_split::open_gguf;
+use crate::models::common::rope_scaling::rope_table_len;
 
 impl Qwen3MoeBackend {
     /// Создаёт бекенд из GGUF Content
     /// dtype - тип данных для вычислений (BF16 или F16)
-    pub fn from_gguf(
+    pub fn from_gguf<R: GgufRead>(
         content: gguf_file::Content,
-        file: &mut File,
+        file: &mut R,
         device: &Device,
         dtype: DType,
     ) -> Result<Self, String> {
@@ -34,6 +37,12 @@ impl Qwen3MoeBackend {
             .or_else(|| content.metadata.get("qwen3.context_length"))
             .and_then(|v| v.to_u32().ok())
             .unwrap_or(40960) as usize;
+        let rope_scaling = content
+            .metadata
+            .get("general.architecture")
+            .and_then(|v| v.to_string().ok())
+            .and_then(|arch| RopeScaling::from_gguf(&content.metadata, arch));
+        let max_seq_len = rope_table_len(max_seq_len, rope_scaling.as_ref());
 
         log::info!(
             "Loading Qwen3-MoE GGUF: vocab_size={}, max_seq_len={}, dtype={:?}",
@@ -60,11 +69,7 @@ impl Qwen3MoeBackend {
         device: &Device,
         dtype: DType,
     ) -> Result<Self, String> {
-        let mut file = File::open(path).map_err(|e| format!("Failed to open GGUF file: {}", e))?;
-
-        let content = gguf_file::Content::read(&mut file)
-            .map_err(|e| format!("Failed to read GGUF header: {}", e))?;
-
-        Self::from_gguf(content, &mut file, device, dtype)
+        let (content, mut reader) = open_gguf(path)?;
+        Self::from_gguf(content, &mut reader, device, dtype)
     }
 }
@@ -8,7 +8,7 @@
 //! - SafeTensors (полные веса, BF16/F16)
 //! - GGUF (квантизированные модели)
 
-mod fused_moe;
+pub(crate) mod fused_moe;
 mod gguf;
 pub mod model;
 pub mod quantized_model;
@@ -22,6 +22,7 @@ use model::ModelForCausalLM;
 
 use crate::models::ModelBackend;
 use crate::models::api::optimization::{OptimizationConfig, WeightFormat};
+use crate::models::common::{KvCacheType, KvLayers, LayerKv, StreamingWindow};
 
 /// Внутреннее представление модели
 enum Qwen3MoeInner {
@@ -31,6 +32,23 @@ enum Qwen3MoeInner {
     Full(ModelForCausalLM),
 }
 
+impl Qwen3MoeInner {
+    /// KV-кэши слоёв активной модели
+    fn kv_layers(&self) -> &dyn KvLayers {
+        match self {
+            Qwen3MoeInner::Quantized(model) => model,
+            Qwen3MoeInner::Full(model) => model,
+        }
+    }
+
+    fn kv_layers_mut(&mut self) -> &mut dyn KvLayers {
+        match self {
+            Qwen3MoeInner::Quantized(model) => model,
+            Qwen3MoeInner::Full(model) => model,
+        }
+    }
+}
+
 /// Qwen3-MoE бекенд
 ///
 /// Поддерживает как квантизированные (GGUF) так и полные (SafeTensors) модели.
@@ -142,4 +160,79 @@ impl ModelBackend for Qwen3MoeBackend {
     fn supports_flash_attn(&self) -> bool {
         self.optimization.uses_flash_attn()
     }
+
+    fn supports_chunked_prefill(&self) -> bool {
+        true
+    }
+
+    fn forward_all(&mut self, input: &Tensor, pos: usize) -> candle::Result<Tensor> {
+        match &mut self.inner {
+            // GGUF модель уже возвращает [seq_len, vocab_size]
+            Qwen3MoeInner::Quantized(model) => model.forward_all(input, pos),
+            // [batch, seq_len, vocab_size] -> [seq_len, vocab_size]
+            Qwen3MoeInner::Full(model) => model.forward_all(input, pos)?.squeeze(0),
+        }
+    }
+
+    fn truncate_kv_cache(&mut self, len: usize) -> bool {
+        self.inner.kv_layers_mut().truncate_kv_cache(len).is_ok()
+    }
+
+    fn supports_speculative(&self) -> bool {
+        // В потоковом режиме позиции KV-кэша не совпадают с позициями генерации
+        self.streaming_window().is_none()
+    }
+
+    fn set_streaming_window(&mut self, window: Option<StreamingWindow>) -> bool {
+        match &mut self.inner {
+            Qwen3MoeInner::Quantized(model) => model.set_streaming_window(window),
+            Qwen3MoeInner::Full(model) => model.set_streaming_window(window),
+        }
+        true
+    }
+
+    fn streaming_window(&self) -> Option<StreamingWindow> {
+        match &self.inner {
+            Qwen3MoeInner::Quantized(model) => model.streaming_window(),
+            Qwen3MoeInner::Full(model) => model.streaming_window(),
+        }
+    }
+
+    fn supports_context_shift(&self) -> bool {
+        true
+    }
+
+    fn shift_kv_cache(&mut self, keep: usize, discard: usize) -> bool {
+        self.inner
+            .kv_layers_mut()
+            .shift_kv_cache(keep, discard)
+            .is_ok()
+    }
+
+    fn supports_kv_snapshot(&self) -> bool {
+        // В потоковом режиме кэш не соответствует позициям промпта
+        self.streaming_window().is_none()
+    }
+
+    fn export_kv_cache(&self) -> candle::Result<Vec<LayerKv>> {
+        self.inner.kv_layers().export_kv_cache()
+    }
+
+    fn import_kv_cache(&mut self, layers: &[LayerKv]) -> candle::Result<()> {
+        self.inner.kv_layers_mut().import_kv_cache(layers)
+    }
+
+    fn set_kv_cache_type(&mut self, cache_type: KvCacheType) -> bool {
+        self.inner
+            .kv_layers_mut()
+            .set_kv_cache_type(cache_type)
+            .is_ok()
+    }
+
+    fn get_embeddings(&mut self, input: &Tensor) -> candle::Result<Tensor> {
+        match &mut self.inner {
+            Qwen3MoeInner::Quantized(model) => model.get_hidden_states(input, 0),
+            Qwen3MoeInner::Full(model) => model.get_hidden_states(input, 0),
+        }
+    }
 }
@@ -1,3 +1,6 @@
+use crate::models::common::{
+    KvCache, KvCacheType, KvLayers, RopeScaling, RopeShift, StreamingWindow,
+};
 use crate::models::qwen3::model::{
     Config as Qwen3Config, Qwen3Attention, Qwen3MLP, Qwen3RotaryEmbedding,
 };
@@ -38,6 +41,9 @@ pub struct Config {
     pub num_experts_per_tok: usize,
     pub num_experts: usize,
     pub norm_topk_prob: bool,
+    /// Масштабирование RoPE (заполняется загрузчиком из `rope_scaling` / override)
+    #[serde(skip)]
+    pub rope_scaling: Option<RopeScaling>,
 }
 
 impl From<&Config> for Qwen3Config {
@@ -60,6 +66,7 @@ impl From<&Config> for Qwen3Config {
             use_sliding_window: val.use_sliding_window,
             hidden_act: val.hidden_act,
             use_flash_attn: val.use_flash_attn,
+            rope_scaling: val.rope_scaling,
         }
     }
 }
@@ -336,7 +343,7 @@ impl Module for Qwen3SparseMoeBlock {
 // MLP or MoE decision enum
 #[derive(Debug, Clone)]
 enum Qwen3FeedForward {
-    Mlp(Qwen3MLP),
+    Mlp(Box<Qwen3MLP>),
     NaiveMoE(Qwen3SparseMoeBlock),
     FusedMoE(FusedMoe),
 }
@@ -388,7 +395,7 @@ impl DecoderLayer {
                     Qwen3FeedForward::NaiveMoE(Qwen3SparseMoeBlock::new(cfg, vb.pp("mlp"))?)
                 }
             } else {
-                Qwen3FeedForward::Mlp(Qwen3MLP::new(&cfg.into(), vb.pp("mlp"))?)
+                Qwen3FeedForward::Mlp(Box::new(Qwen3MLP::new(&cfg.into(), vb.pp("mlp"))?))
             };
 
         let ln1 = RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("input_layernorm"))?;
@@ -418,6 +425,10 @@ impl DecoderLayer {
     fn clear_kv_cache(&mut self) {
         self.self_attn.clear_kv_cache();
     }
+
+    fn set_streaming(&mut self, window: Option<StreamingWindow>) {
+        self.self_attn.set_streaming(window);
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -427,6 +438,7 @@ pub struct Model {
     norm: RmsNorm,
     device: Device,
     dtype: DType,
+    streaming: Option<StreamingWindow>,
 }
 
 impl Model {
@@ -449,6 +461,7 @@ impl Model {
             norm: RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("model.norm"))?,
             device: vb.device().clone(),
             dtype: vb.dtype(),
+            streaming: None,
         })
     }
 
@@ -458,6 +471,13 @@ impl Model {
         }
     }
 
+    fn set_streaming(&mut self, window: Option<StreamingWindow>) {
+        self.streaming = window;
+        for l in &mut self.layers {
+            l.set_streaming(window);
+        }
+    }
+
     fn causal_mask(
         &self,
         b: usize,
@@ -484,6 +504,11 @@ impl Model {
     pub fn forward(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
         let (b, l) = input.dims2()?;
         let mut h = self.embed_tokens.forward(input)?;
+        // В потоковом режиме позиция определяется длиной кэша после вытеснения
+        let offset = match (self.streaming, self.layers.first()) {
+            (Some(_), Some(layer)) => layer.self_attn.kv_cache_len(),
+            _ => offset,
+        };
 
         let causal = if l == 1 {
             None
@@ -526,4 +551,54 @@ impl ModelForCausalLM {
     pub fn clear_kv_cache(&mut self) {
         self.base.clear_kv_cache();
     }
+
+    /// Returns hidden states of the last layer after normalization [batch, seq_len, hidden]
+    pub fn get_hidden_states(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        self.base.forward(input, offset)
+    }
+
+    /// Returns logits for every input position [batch, seq_len, vocab_size]
+    pub fn forward_all(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        self.base.forward(input, offset)?.apply(&self.lm_head)
+    }
+
+    /// Enables streaming attention (attention sinks + sliding KV window)
+    pub fn set_streaming_window(&mut self, window: Option<StreamingWindow>) {
+        self.base.set_streaming(window);
+    }
+
+    /// Current streaming attention configuration
+    pub fn streaming_window(&self) -> Option<StreamingWindow> {
+        self.base.streaming
+    }
+}
+
+impl KvLayers for ModelForCausalLM {
+    fn kv_caches(&self) -> Vec<&KvCache> {
+        self.base
+            .layers
+            .iter()
+            .map(|l| l.self_attn.kv_cache())
+            .collect()
+    }
+
+    fn kv_caches_mut(&mut self) -> Vec<&mut KvCache> {
+        self.base
+            .layers
+            .iter_mut()
+            .map(|l| l.self_attn.kv_cache_mut())
+            .collect()
+    }
+
+    fn shift_rope(&self, layer: usize) -> Result<RopeShift> {
+        Ok(self.base.layers[layer].self_attn.rope_shift())
+    }
+
+    // Switching the format also drops the continuous-batching slot caches
+    fn set_kv_cache_type(&mut self, cache_type: KvCacheType) -> Result<()> {
+        self.base
+            .layers
+            .iter_mut()
+            .try_for_each(|l| l.self_attn.set_kv_cache_type(cache_type))
+    }
 }
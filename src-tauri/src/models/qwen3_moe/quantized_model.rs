@@ -3,11 +3,10 @@
 //! This is a modified version of candle_transformers::models::quantized_qwen3_moe
 //! that adds the clear_kv_cache method which is not exposed in the original.
 
+use crate::models::common::gguf_mmap::{Gguf, GgufRead};
 use candle::quantized::gguf_file;
 use candle::{DType, Device, Result, Tensor};
-use candle_nn::kv_cache::ConcatKvCache;
 use candle_nn::{Embedding, Linear, Module};
-use candle_transformers::models::quantized_qwen3::{Gguf, RotaryEmbedding};
 use candle_transformers::models::with_tracing::QMatMul;
 // Use local FusedMoeGGUF with contiguous() fix
 use super::fused_moe::FusedMoeGGUF;
@@ -16,6 +15,10 @@ use candle_transformers::quantized_nn::RmsNorm;
 use candle_transformers::utils::repeat_kv;
 use std::sync::Arc;
 
+use crate::models::common::{
+    KvCache, KvLayers, RopeScaling, RopeShift, RotaryEmbedding, StreamingWindow,
+};
+
 #[derive(Debug, Clone)]
 struct Mlp {
     feed_forward_w1: QMatMul,
@@ -62,12 +65,14 @@ pub struct QuantizedAttention {
     num_kv_groups: usize,
     rotary_emb: Arc<RotaryEmbedding>,
     dtype: DType,
-    kv_cache: ConcatKvCache,
+    kv_cache: KvCache,
+    /// Streaming mode window and the RoPE parameters used to re-rotate after eviction
+    streaming: Option<(StreamingWindow, RopeShift)>,
 }
 
 impl QuantizedAttention {
     #[allow(clippy::too_many_arguments)]
-    pub fn new<R: std::io::Seek + std::io::Read>(
+    pub fn new<R: GgufRead>(
         gg: &mut Gguf<R>,
         prefix: &str,
         dtype: DType,
@@ -106,9 +111,14 @@ impl QuantizedAttention {
         };
 
         let attention_wo = gg.qmatmul(&format!("{prefix}.attn_output.weight"))?;
-        let q_norm = Some(gg.rms_norm(&format!("{prefix}.attn_q_norm.weight"), rms_norm_eps)?);
-        let k_norm = Some(gg.rms_norm(&format!("{prefix}.attn_k_norm.weight"), rms_norm_eps)?);
-        let kv_cache = ConcatKvCache::new(2);
+        // Per-head q/k norms are present in Qwen3-MoE and absent in Qwen2-MoE
+        let q_norm = gg
+            .rms_norm(&format!("{prefix}.attn_q_norm.weight"), rms_norm_eps)
+            .ok();
+        let k_norm = gg
+            .rms_norm(&format!("{prefix}.attn_k_norm.weight"), rms_norm_eps)
+            .ok();
+        let kv_cache = KvCache::new(2);
         Ok(QuantizedAttention {
             attention_wq,
             attention_wk,
@@ -126,6 +136,7 @@ impl QuantizedAttention {
             rotary_emb: rotary_emb.clone(),
             dtype,
             kv_cache,
+            streaming: None,
         })
     }
 
@@ -217,6 +228,10 @@ impl QuantizedAttention {
 
         let probs = candle_nn::ops::softmax_last_dim(&scores)?;
         let ctx = probs.matmul(&v)?;
+
+        if let Some((window, rope)) = &self.streaming {
+            window.evict(&mut self.kv_cache, rope)?;
+        }
         let reshaped_ctx =
             ctx.transpose(1, 2)?
                 .reshape((b, seq_len, self.n_head * self.head_dim))?;
@@ -228,6 +243,26 @@ impl QuantizedAttention {
     pub fn clear_kv_cache(&mut self) {
         self.kv_cache.reset();
     }
+
+    /// Current length of the KV cache
+    pub fn kv_cache_len(&self) -> usize {
+        self.kv_cache.current_seq_len()
+    }
+
+    /// Enable streaming mode (attention sinks + sliding KV window)
+    pub fn set_streaming(&mut self, window: Option<StreamingWindow>, rope: RopeShift) {
+        self.streaming = window.map(|w| (w, rope));
+    }
+
+    /// KV cache of this layer
+    pub fn kv_cache(&self) -> &KvCache {
+        &self.kv_cache
+    }
+
+    /// Mutable KV cache of this layer
+    pub fn kv_cache_mut(&mut self) -> &mut KvCache {
+        &mut self.kv_cache
+    }
 }
 
 struct LayerWeights {
@@ -245,6 +280,10 @@ impl LayerWeights {
     fn clear_kv_cache(&mut self) {
         self.self_attn.clear_kv_cache();
     }
+
+    fn set_streaming(&mut self, window: Option<StreamingWindow>, rope: RopeShift) {
+        self.self_attn.set_streaming(window, rope);
+    }
 }
 
 pub struct GGUFQWenMoE {
@@ -254,10 +293,12 @@ pub struct GGUFQWenMoE {
     output: QMatMul,
     dtype: DType,
     device: Device,
+    rope: RopeShift,
+    streaming: Option<StreamingWindow>,
 }
 
 impl GGUFQWenMoE {
-    pub fn from_gguf<R: std::io::Seek + std::io::Read>(
+    pub fn from_gguf<R: GgufRead>(
         ct: gguf_file::Content,
         reader: &mut R,
         device: &Device,
@@ -290,6 +331,8 @@ impl GGUFQWenMoE {
         let rope_freq_base = md_get(format!("{arch}.rope.freq_base").as_str())
             .and_then(|m| m.to_f32())
             .unwrap_or(10000f32);
+        let rope_scaling =
+            RopeScaling::from_gguf(gg.metadata(), arch).map(|s| s.resolve(context_length));
         let expert_shared_feed_forward_length =
             md_get(format!("{arch}.expert_shared_feed_forward_length").as_str());
         let shared_expert_intermediate_size = match expert_shared_feed_forward_length {
@@ -331,6 +374,7 @@ impl GGUFQWenMoE {
             head_dim,
             context_length,
             rope_freq_base as f64,
+            rope_scaling.as_ref(),
             device,
         )?);
         let mut layers = Vec::with_capacity(block_count);
@@ -347,16 +391,16 @@ impl GGUFQWenMoE {
                 let gate_experts = Arc::new(gg.tensor(&format!("{prefix}.ffn_gate_exps.weight"))?);
                 let up_experts = Arc::new(gg.tensor(&format!("{prefix}.ffn_up_exps.weight"))?);
                 let down_experts = Arc::new(gg.tensor(&format!("{prefix}.ffn_down_exps.weight"))?);
-                let moe = FusedMoeGGUF {
+                let moe = FusedMoeGGUF::new(
                     gate,
                     gate_experts,
                     up_experts,
                     down_experts,
-                    act: candle_nn::Activation::Silu,
-                    norm_topk_prob: moe_cfg.norm_topk_prob,
-                    num_experts_per_tok: moe_cfg.num_experts_per_tok,
+                    candle_nn::Activation::Silu,
+                    moe_cfg.norm_topk_prob,
+                    moe_cfg.num_experts_per_tok,
                     dtype,
-                };
+                )?;
 
                 MoeOrMlp::FusedMoe(moe)
             } else {
@@ -403,6 +447,8 @@ impl GGUFQWenMoE {
             output,
             dtype,
             device: device.clone(),
+            rope: RopeShift::new(head_dim, rope_freq_base as f64).with_scaling(rope_scaling),
+            streaming: None,
         })
     }
 
@@ -429,9 +475,15 @@ impl GGUFQWenMoE {
         Tensor::from_slice(&mask, (b, 1, tgt, tgt + offset), &self.device)?.to_dtype(self.dtype)
     }
 
-    pub fn forward(&mut self, x: &Tensor, offset: usize) -> Result<Tensor> {
+    /// Runs the decoder stack and returns hidden states [batch, seq_len, hidden]
+    fn forward_hidden(&mut self, x: &Tensor, offset: usize) -> Result<Tensor> {
         let mut xs = self.tok_embeddings.forward(x)?;
         let (b, l) = x.dims2()?;
+        // In streaming mode positions follow the cache length after eviction
+        let offset = match (self.streaming, self.layers.first()) {
+            (Some(_), Some(layer)) => layer.self_attn.kv_cache_len(),
+            _ => offset,
+        };
 
         let causal_mask = if l == 1 {
             None
@@ -456,12 +508,44 @@ impl GGUFQWenMoE {
             let x = (x + residual)?;
             xs = x;
         }
+        Ok(xs)
+    }
 
+    pub fn forward(&mut self, x: &Tensor, offset: usize) -> Result<Tensor> {
+        let (_, l) = x.dims2()?;
+        let xs = self.forward_hidden(x, offset)?;
         let xs = xs.narrow(1, l - 1, 1)?;
         let xs = self.norm.forward(&xs)?;
         self.output.forward(&xs)?.to_dtype(DType::F32)?.squeeze(1)
     }
 
+    /// Returns hidden states of the last layer after normalization [batch, seq_len, hidden]
+    pub fn get_hidden_states(&mut self, x: &Tensor, offset: usize) -> Result<Tensor> {
+        let xs = self.forward_hidden(x, offset)?;
+        self.norm.forward(&xs)
+    }
+
+    /// Returns logits for every input position [seq_len, vocab_size]
+    pub fn forward_all(&mut self, x: &Tensor, offset: usize) -> Result<Tensor> {
+        let xs = self.forward_hidden(x, offset)?;
+        let xs = self.norm.forward(&xs)?;
+        self.output.forward(&xs)?.to_dtype(DType::F32)?.squeeze(0)
+    }
+
+    /// Enable streaming attention (attention sinks + sliding KV window)
+    pub fn set_streaming_window(&mut self, window: Option<StreamingWindow>) {
+        self.streaming = window;
+        let rope = self.rope;
+        for layer in self.layers.iter_mut() {
+            layer.set_streaming(window, rope);
+        }
+    }
+
+    /// Current streaming attention configuration
+    pub fn streaming_window(&self) -> Option<StreamingWindow> {
+        self.streaming
+    }
+
     /// Clear the KV cache for all layers
     pub fn clear_kv_cache(&mut self) {
         for layer in self.layers.iter_mut() {
@@ -473,3 +557,20 @@ impl GGUFQWenMoE {
         }
     }
 }
+
+impl KvLayers for GGUFQWenMoE {
+    fn kv_caches(&self) -> Vec<&KvCache> {
+        self.layers.iter().map(|l| &l.self_attn.kv_cache).collect()
+    }
+
+    fn kv_caches_mut(&mut self) -> Vec<&mut KvCache> {
+        self.layers
+            .iter_mut()
+            .map(|l| &mut l.self_attn.kv_cache)
+            .collect()
+    }
+
+    fn shift_rope(&self, _layer: usize) -> Result<RopeShift> {
+        Ok(self.rope)
+    }
+}
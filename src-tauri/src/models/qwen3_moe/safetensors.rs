@@ -11,6 +11,8 @@ use std::path::{Path, PathBuf};
 
 use super::Qwen3MoeBackend;
 use crate::models::api::optimization::OptimizationConfig;
+use crate::models::common::RopeScaling;
+use crate::models::common::rope_scaling::{resolve_rope_scaling, rope_table_len};
 
 impl Qwen3MoeBackend {
     /// Создаёт бекенд из SafeTensors файлов (как в примере qwen: lines 344-346)
@@ -20,12 +22,19 @@ impl Qwen3MoeBackend {
         config_path: &Path,
         device: &Device,
         dtype: DType,
+        rope_scaling: Option<RopeScaling>,
     ) -> Result<Self, String> {
         // Загружаем конфигурацию (как в примере: line 345)
         let config_data =
             std::fs::read(config_path).map_err(|e| format!("Failed to read config.json: {}", e))?;
-        let config: Config = serde_json::from_slice(&config_data)
+        let mut config: Config = serde_json::from_slice(&config_data)
             .map_err(|e| format!("Failed to parse config.json: {}", e))?;
+        let config_json: serde_json::Value = serde_json::from_slice(&config_data)
+            .map_err(|e| format!("Failed to parse config.json: {}", e))?;
+        config.rope_scaling =
+            resolve_rope_scaling(&config_json, rope_scaling, config.max_position_embeddings);
+        let max_seq_len =
+            rope_table_len(config.max_position_embeddings, config.rope_scaling.as_ref());
 
         // Создаём VarBuilder из SafeTensors (как в примере qwen: line 334)
         let vb = unsafe {
@@ -44,7 +53,7 @@ impl Qwen3MoeBackend {
             inner,
             device.clone(),
             config.vocab_size,
-            config.max_position_embeddings,
+            max_seq_len,
             optimization,
         ))
     }
@@ -62,7 +71,7 @@ impl Qwen3MoeBackend {
 
         let filenames = Self::find_weight_files(model_dir)?;
 
-        Self::from_safetensors(&filenames, &config_path, device, dtype)
+        Self::from_safetensors(&filenames, &config_path, device, dtype, None)
     }
 
     /// Находит файлы весов в директории (как в примере qwen)
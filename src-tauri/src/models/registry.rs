@@ -6,17 +6,19 @@ use std::collections::HashMap;
 /// Поддерживаемые архитектуры моделей
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ArchKind {
-    Llama,    // Llama 1/2/3/4, Mistral, Mixtral, Yi, DeepSeek, SmolLM, CodeLlama, etc.
-    Gemma,    // Gemma 1
-    Gemma2,   // Gemma 2
-    Gemma3,   // Gemma 3
-    Qwen2,    // Qwen 2, Qwen 2.5
-    Qwen2Moe, // Qwen 2 MoE
-    Qwen3,    // Qwen 3
-    Qwen3Moe, // Qwen 3 MoE (30B-A3B)
-    Phi,      // Phi 1, 1.5, 2
-    Phi3,     // Phi-3, Phi-3.5, Phi-4
-    Glm4,     // GLM-4
+    Llama,     // Llama 1/2/3/4, Mistral, Mixtral, Yi, DeepSeek, SmolLM, CodeLlama, etc.
+    Gemma,     // Gemma 1
+    Gemma2,    // Gemma 2
+    Gemma3,    // Gemma 3
+    Qwen2,     // Qwen 2, Qwen 2.5
+    Qwen2Moe,  // Qwen 2 MoE
+    Qwen3,     // Qwen 3
+    Qwen3Moe,  // Qwen 3 MoE (30B-A3B)
+    Phi,       // Phi 1, 1.5, 2
+    Phi3,      // Phi-3, Phi-3.5, Phi-4
+    Glm4,      // GLM-4
+    DeepSeek2, // DeepSeek-V2 / V3 (MLA + MoE)
+    Mamba,     // Mamba (state space model, рекуррентное состояние)
 }
 
 impl ArchKind {
@@ -34,6 +36,8 @@ impl ArchKind {
             ArchKind::Phi => "Phi",
             ArchKind::Phi3 => "Phi-3",
             ArchKind::Glm4 => "GLM-4",
+            ArchKind::DeepSeek2 => "DeepSeek-V2",
+            ArchKind::Mamba => "Mamba",
         }
     }
 
@@ -47,8 +51,13 @@ impl ArchKind {
                 | ArchKind::Gemma3
                 | ArchKind::Qwen2
                 | ArchKind::Qwen3
+                | ArchKind::Qwen2Moe
                 | ArchKind::Qwen3Moe
+                | ArchKind::Phi
                 | ArchKind::Phi3
+                | ArchKind::Glm4
+                | ArchKind::DeepSeek2
+                | ArchKind::Mamba
         )
     }
 
@@ -56,6 +65,16 @@ impl ArchKind {
     pub fn supports_safetensors(&self) -> bool {
         true // Все архитектуры поддерживают SafeTensors
     }
+
+    /// Раскладка GGUF для квантования SafeTensors при загрузке
+    pub fn gguf_layout(&self) -> Option<GgufLayout> {
+        match self {
+            ArchKind::Llama => Some(GgufLayout::Llama),
+            ArchKind::Qwen2 => Some(GgufLayout::Qwen2),
+            ArchKind::Qwen3 => Some(GgufLayout::Qwen3),
+            _ => None,
+        }
+    }
 }
 
 /// Определяет архитектуру из GGUF метаданных
@@ -102,6 +121,15 @@ fn detect_arch_from_string(s: &str) -> Option<ArchKind> {
         || s_lower.contains("qwen2.5")
     {
         Some(ArchKind::Qwen2)
+    } else if s_lower.contains("deepseek2")
+        || s_lower.contains("deepseek_v2")
+        || s_lower.contains("deepseek_v3")
+    {
+        // DeepSeek-V3 в GGUF тоже записывается как `deepseek2`
+        Some(ArchKind::DeepSeek2)
+    } else if s_lower == "mamba" {
+        // Mamba-2 имеет другую архитектуру блока и не поддерживается
+        Some(ArchKind::Mamba)
     } else if s_lower.contains("gemma3") || s_lower.contains("gemma-3") {
         Some(ArchKind::Gemma3)
     } else if s_lower.contains("gemma2") || s_lower.contains("gemma-2") {
@@ -114,7 +142,11 @@ fn detect_arch_from_string(s: &str) -> Option<ArchKind> {
         Some(ArchKind::Phi3)
     } else if s_lower.contains("phi") {
         Some(ArchKind::Phi)
-    } else if s_lower.contains("glm4") || s_lower.contains("glm-4") {
+    } else if s_lower.contains("glm4")
+        || s_lower.contains("glm-4")
+        || s_lower.contains("chatglm")
+        || s_lower == "glm"
+    {
         Some(ArchKind::Glm4)
     } else {
         None
@@ -172,7 +204,11 @@ impl GgufModelInfo {
 }
 
 use super::ModelBackend;
+use super::common::quantize::{GgufLayout, QuantizeStats, quantize_to_gguf};
+use super::common::{LoraAdapter, RopeScaling};
+use crate::models::common::gguf_mmap::GgufRead;
 use candle::Device;
+use candle::quantized::GgmlDType;
 use candle::quantized::gguf_file::Content;
 use std::sync::OnceLock;
 
@@ -187,11 +223,11 @@ impl ModelFactory {
     }
 
     /// Создаёт модель из GGUF
-    pub fn build_from_gguf(
+    pub fn build_from_gguf<R: GgufRead>(
         &self,
         arch: ArchKind,
         content: Content,
-        file: &mut std::fs::File,
+        file: &mut R,
         device: &Device,
         _context_length: usize,
         _use_flash_attn: bool,
@@ -218,6 +254,16 @@ impl ModelFactory {
                 let model = Qwen3MoeBackend::from_gguf(content, file, device, dtype)?;
                 Ok(Box::new(model))
             }
+            ArchKind::Qwen2Moe => {
+                use super::qwen2_moe::Qwen2MoeBackend;
+                let dtype = if device.is_cuda() || device.is_metal() {
+                    candle::DType::BF16
+                } else {
+                    candle::DType::F32
+                };
+                let model = Qwen2MoeBackend::from_gguf(content, file, device, dtype)?;
+                Ok(Box::new(model))
+            }
             // Llama-подобные архитектуры (Llama, Mistral, Mixtral, DeepSeek, Yi, SmolLM2)
             // LlamaVariant определяется автоматически из metadata
             ArchKind::Llama => {
@@ -225,16 +271,43 @@ impl ModelFactory {
                 let model = LlamaBackend::from_gguf(content, file, device)?;
                 Ok(Box::new(model))
             }
-            // TODO: Phi3, Gemma
-            _ => Err(format!(
-                "Model building for {:?} is not yet implemented. \
-                 Currently supported: Qwen2, Qwen3, Qwen3Moe, Llama (incl. Mistral, Mixtral, DeepSeek, Yi, SmolLM2)",
-                arch
-            )),
+            // Gemma 1/2/3: поколение определяется из general.architecture
+            ArchKind::Gemma | ArchKind::Gemma2 | ArchKind::Gemma3 => {
+                use super::gemma::GemmaBackend;
+                let model = GemmaBackend::from_gguf(content, file, device)?;
+                Ok(Box::new(model))
+            }
+            ArchKind::Phi3 => {
+                use super::phi3::Phi3Backend;
+                let model = Phi3Backend::from_gguf(content, file, device)?;
+                Ok(Box::new(model))
+            }
+            ArchKind::Phi => {
+                use super::phi::PhiBackend;
+                let model = PhiBackend::from_gguf(content, file, device)?;
+                Ok(Box::new(model))
+            }
+            // GLM-4: `chatglm` (GLM-4-9B) и `glm4` (GLM-4-0414)
+            ArchKind::Glm4 => {
+                use super::glm4::Glm4Backend;
+                let model = Glm4Backend::from_gguf(content, file, device)?;
+                Ok(Box::new(model))
+            }
+            ArchKind::DeepSeek2 => {
+                use super::deepseek2::DeepSeek2Backend;
+                let model = DeepSeek2Backend::from_gguf(content, file, device)?;
+                Ok(Box::new(model))
+            }
+            ArchKind::Mamba => {
+                use super::mamba::MambaBackend;
+                let model = MambaBackend::from_gguf(content, file, device)?;
+                Ok(Box::new(model))
+            }
         }
     }
 
     /// Создаёт модель из SafeTensors
+    #[allow(clippy::too_many_arguments)]
     pub fn build_from_safetensors<P: AsRef<std::path::Path>>(
         &self,
         arch: ArchKind,
@@ -242,6 +315,8 @@ impl ModelFactory {
         config: &serde_json::Value,
         device: &Device,
         dtype: candle::DType,
+        rope_scaling: Option<RopeScaling>,
+        lora: &[LoraAdapter],
     ) -> Result<Box<dyn ModelBackend + Send>, String> {
         // Получаем config_path из первого файла (ищем config.json в той же директории)
         let config_path = files
@@ -255,43 +330,179 @@ impl ModelFactory {
 
         let _ = config; // config уже загружен, используем config_path
 
-        match arch {
+        let mut model: Box<dyn ModelBackend + Send> = match arch {
             ArchKind::Qwen3 => {
                 use super::qwen3::Qwen3Backend;
-                let model =
-                    Qwen3Backend::from_safetensors(&filenames, &config_path, device, dtype)?;
-                Ok(Box::new(model))
+                let model = Qwen3Backend::from_safetensors(
+                    &filenames,
+                    &config_path,
+                    device,
+                    dtype,
+                    rope_scaling,
+                )?;
+                Box::new(model)
             }
             ArchKind::Qwen2 => {
                 use super::qwen2::Qwen2Backend;
-                let model =
-                    Qwen2Backend::from_safetensors(&filenames, &config_path, device, dtype)?;
-                Ok(Box::new(model))
+                let model = Qwen2Backend::from_safetensors(
+                    &filenames,
+                    &config_path,
+                    device,
+                    dtype,
+                    rope_scaling,
+                    lora,
+                )?;
+                Box::new(model)
             }
             ArchKind::Qwen3Moe => {
                 use super::qwen3_moe::Qwen3MoeBackend;
-                let model =
-                    Qwen3MoeBackend::from_safetensors(&filenames, &config_path, device, dtype)?;
-                Ok(Box::new(model))
+                let model = Qwen3MoeBackend::from_safetensors(
+                    &filenames,
+                    &config_path,
+                    device,
+                    dtype,
+                    rope_scaling,
+                )?;
+                Box::new(model)
             }
             ArchKind::Qwen2Moe => {
                 use super::qwen2_moe::Qwen2MoeBackend;
+                if rope_scaling.is_some() {
+                    log::warn!("RoPE scaling override is not supported for Qwen2-MoE; ignored");
+                }
                 let model =
                     Qwen2MoeBackend::from_safetensors(&filenames, &config_path, device, dtype)?;
-                Ok(Box::new(model))
+                Box::new(model)
             }
             ArchKind::Llama => {
                 use super::llama::LlamaBackend;
+                let model = LlamaBackend::from_safetensors(
+                    &filenames,
+                    &config_path,
+                    device,
+                    dtype,
+                    rope_scaling,
+                    lora,
+                )?;
+                Box::new(model)
+            }
+            ArchKind::Gemma | ArchKind::Gemma2 | ArchKind::Gemma3 => {
+                use super::gemma::GemmaBackend;
+                let model = GemmaBackend::from_safetensors(
+                    &filenames,
+                    &config_path,
+                    device,
+                    dtype,
+                    rope_scaling,
+                )?;
+                Box::new(model)
+            }
+            ArchKind::Phi3 => {
+                use super::phi3::Phi3Backend;
+                let model = Phi3Backend::from_safetensors(
+                    &filenames,
+                    &config_path,
+                    device,
+                    dtype,
+                    rope_scaling,
+                )?;
+                Box::new(model)
+            }
+            ArchKind::Phi => {
+                use super::phi::PhiBackend;
+                let model = PhiBackend::from_safetensors(
+                    &filenames,
+                    &config_path,
+                    device,
+                    dtype,
+                    rope_scaling,
+                )?;
+                Box::new(model)
+            }
+            ArchKind::Glm4 => {
+                use super::glm4::Glm4Backend;
+                let model = Glm4Backend::from_safetensors(
+                    &filenames,
+                    &config_path,
+                    device,
+                    dtype,
+                    rope_scaling,
+                )?;
+                Box::new(model)
+            }
+            ArchKind::DeepSeek2 => {
+                use super::deepseek2::DeepSeek2Backend;
+                let model = DeepSeek2Backend::from_safetensors(
+                    &filenames,
+                    &config_path,
+                    device,
+                    dtype,
+                    rope_scaling,
+                )?;
+                Box::new(model)
+            }
+            ArchKind::Mamba => {
+                use super::mamba::MambaBackend;
+                if rope_scaling.is_some() {
+                    log::warn!("RoPE scaling override does not apply to Mamba; ignored");
+                }
                 let model =
-                    LlamaBackend::from_safetensors(&filenames, &config_path, device, dtype)?;
-                Ok(Box::new(model))
+                    MambaBackend::from_safetensors(&filenames, &config_path, device, dtype)?;
+                Box::new(model)
             }
-            _ => Err(format!(
-                "SafeTensors model building for {:?} is not yet implemented. \
-                 Currently supported: Qwen2, Qwen2Moe, Qwen3, Qwen3Moe, Llama (incl. Mistral, Mixtral, etc.)",
-                arch
-            )),
+        };
+        // Llama и Qwen2 уже влили адаптеры в веса, остальные подключают их side-car слоями
+        if !lora.is_empty() && !matches!(arch, ArchKind::Llama | ArchKind::Qwen2) {
+            model.set_lora_adapters(lora).map_err(|e| e.to_string())?;
         }
+        Ok(model)
+    }
+
+    /// Создаёт квантизированную модель из SafeTensors
+    ///
+    /// Веса квантуются в `target` при чтении и загружаются теми же моделями,
+    /// что и GGUF. `dtype` — dtype обычной загрузки (для подсчёта экономии памяти).
+    #[allow(clippy::too_many_arguments)]
+    pub fn build_quantized_from_safetensors<P: AsRef<std::path::Path>>(
+        &self,
+        arch: ArchKind,
+        files: &[P],
+        config: &serde_json::Value,
+        device: &Device,
+        target: GgmlDType,
+        dtype: candle::DType,
+        rope_scaling: Option<RopeScaling>,
+        lora: &[LoraAdapter],
+    ) -> Result<(Box<dyn ModelBackend + Send>, QuantizeStats), String> {
+        let layout = arch.gguf_layout().ok_or_else(|| {
+            format!(
+                "On-the-fly quantization is not supported for {}",
+                arch.display_name()
+            )
+        })?;
+        let filenames: Vec<std::path::PathBuf> =
+            files.iter().map(|p| p.as_ref().to_path_buf()).collect();
+        let gguf = quantize_to_gguf(&filenames, layout, config, target, dtype, rope_scaling)?;
+        let (content, mut data) = (gguf.content, gguf.data);
+
+        let mut model: Box<dyn ModelBackend + Send> = match layout {
+            GgufLayout::Llama => {
+                use super::llama::LlamaBackend;
+                Box::new(LlamaBackend::from_gguf(content, &mut data, device)?)
+            }
+            GgufLayout::Qwen2 => {
+                use super::qwen2::Qwen2Backend;
+                Box::new(Qwen2Backend::from_gguf(content, &mut data, device)?)
+            }
+            GgufLayout::Qwen3 => {
+                use super::qwen3::Qwen3Backend;
+                Box::new(Qwen3Backend::from_gguf(content, &mut data, device)?)
+            }
+        };
+        if !lora.is_empty() {
+            model.set_lora_adapters(lora).map_err(|e| e.to_string())?;
+        }
+        Ok((model, gguf.stats))
     }
 
     /// Определяет архитектуру из GGUF метаданных
@@ -330,6 +541,7 @@ mod tests {
         assert_eq!(detect_arch_from_string("gemma3"), Some(ArchKind::Gemma3));
         // mistral/mixtral/deepseek имеют architecture="llama" в GGUF
         assert_eq!(detect_arch_from_string("phi-3"), Some(ArchKind::Phi3));
+
         assert_eq!(detect_arch_from_string("unknown"), None);
     }
 }
@@ -1,5 +1,5 @@
 use candle::quantized::gguf_file::Value;
-use oxide_lib::models::registry::{ArchKind, detect_arch};
+use oxide_lib::models::registry::{ArchKind, detect_arch, detect_arch_from_config};
 use std::collections::HashMap;
 
 #[test]
@@ -48,3 +48,87 @@ fn test_architecture_detection() {
     let metadata = HashMap::new();
     assert_eq!(detect_arch(&metadata), None);
 }
+
+#[test]
+fn test_glm4_detection() {
+    // GLM-4-9B в GGUF (llama.cpp) имеет architecture="chatglm"
+    let mut metadata = HashMap::new();
+    metadata.insert(
+        "general.architecture".to_string(),
+        Value::String("chatglm".to_string()),
+    );
+    assert_eq!(detect_arch(&metadata), Some(ArchKind::Glm4));
+
+    // GLM-4-0414
+    let mut metadata = HashMap::new();
+    metadata.insert(
+        "general.architecture".to_string(),
+        Value::String("glm4".to_string()),
+    );
+    assert_eq!(detect_arch(&metadata), Some(ArchKind::Glm4));
+    assert!(ArchKind::Glm4.supports_gguf());
+
+    // config.json: оригинальный ChatGLM, transformers (`glm`) и GLM-4-0414
+    for model_type in ["chatglm", "glm", "glm4"] {
+        let config = serde_json::json!({ "model_type": model_type });
+        assert_eq!(
+            detect_arch_from_config(&config),
+            Some(ArchKind::Glm4),
+            "model_type {model_type}"
+        );
+    }
+}
+
+#[test]
+fn test_qwen2_moe_detection() {
+    // Qwen1.5-MoE / Qwen2-57B-A14B в GGUF (llama.cpp) имеют architecture="qwen2moe"
+    let mut metadata = HashMap::new();
+    metadata.insert(
+        "general.architecture".to_string(),
+        Value::String("qwen2moe".to_string()),
+    );
+    assert_eq!(detect_arch(&metadata), Some(ArchKind::Qwen2Moe));
+    assert!(ArchKind::Qwen2Moe.supports_gguf());
+
+    let config = serde_json::json!({ "model_type": "qwen2_moe" });
+    assert_eq!(detect_arch_from_config(&config), Some(ArchKind::Qwen2Moe));
+}
+
+#[test]
+fn test_deepseek2_detection() {
+    // llama.cpp записывает DeepSeek-V2 и V3 как architecture="deepseek2"
+    let mut metadata = HashMap::new();
+    metadata.insert(
+        "general.architecture".to_string(),
+        Value::String("deepseek2".to_string()),
+    );
+    assert_eq!(detect_arch(&metadata), Some(ArchKind::DeepSeek2));
+    assert!(ArchKind::DeepSeek2.supports_gguf());
+
+    for model_type in ["deepseek_v2", "deepseek_v3"] {
+        let config = serde_json::json!({ "model_type": model_type });
+        assert_eq!(detect_arch_from_config(&config), Some(ArchKind::DeepSeek2));
+    }
+
+    // Дистилляты R1 используют архитектуру базовой модели
+    let config = serde_json::json!({ "model_type": "llama" });
+    assert_eq!(detect_arch_from_config(&config), Some(ArchKind::Llama));
+}
+
+#[test]
+fn test_mamba_detection() {
+    let mut metadata = HashMap::new();
+    metadata.insert(
+        "general.architecture".to_string(),
+        Value::String("mamba".to_string()),
+    );
+    assert_eq!(detect_arch(&metadata), Some(ArchKind::Mamba));
+    assert!(ArchKind::Mamba.supports_gguf());
+
+    let config = serde_json::json!({ "model_type": "mamba" });
+    assert_eq!(detect_arch_from_config(&config), Some(ArchKind::Mamba));
+
+    // Mamba-2 имеет другой блок и не должна загружаться как Mamba
+    let config = serde_json::json!({ "model_type": "mamba2" });
+    assert_ne!(detect_arch_from_config(&config), Some(ArchKind::Mamba));
+}
@@ -30,11 +30,20 @@ fn test_generate_request_with_chat_messages() {
         use_custom_params: false,
         seed: None,
         split_prompt: None,
+        prefill_chunk_size: None,
         verbose_prompt: None,
         tracing: None,
         edit_index: None,
         format: None,
         tools: None,
+        stop_sequences: None,
+        tool_choice: None,
+        prompt_lookup: None,
+        context_shift: None,
+        context_shift_keep: None,
+        rolling_summary: None,
+        session_id: None,
+        request_id: None,
     };
 
     assert_eq!(req.prompt, "Direct prompt");
@@ -59,11 +68,20 @@ fn test_generate_request_without_chat_messages() {
         use_custom_params: false,
         seed: None,
         split_prompt: None,
+        prefill_chunk_size: None,
         verbose_prompt: None,
         tracing: None,
         edit_index: None,
         format: None,
         tools: None,
+        stop_sequences: None,
+        tool_choice: None,
+        prompt_lookup: None,
+        context_shift: None,
+        context_shift_keep: None,
+        rolling_summary: None,
+        session_id: None,
+        request_id: None,
     };
 
     assert_eq!(req.prompt, "Direct prompt");
@@ -1,7 +1,7 @@
 #[cfg(test)]
 mod tests {
     use oxide_lib::core::types::ChatMessage;
-    use oxide_lib::generate::ctx::smart_truncate;
+    use oxide_lib::generate::ctx::{smart_truncate, smart_truncate_report};
     use tokenizers::Tokenizer;
 
     // A minimal tokenizer JSON that uses WordLevel model and Whitespace pre-tokenizer.
@@ -160,4 +160,33 @@ mod tests {
         assert!(res.contains("system prompt"));
         assert!(res.contains("hello world"));
     }
+
+    #[test]
+    fn test_smart_truncate_report_lists_dropped() {
+        let tokenizer = create_dummy_tokenizer();
+        let template = Some(SIMPLE_TEMPLATE.to_string());
+
+        let mut msgs = vec![create_msg("system", "system prompt")];
+        for i in 0..10 {
+            msgs.push(create_msg("user", &format!("filler message {}", i)));
+        }
+        msgs.push(create_msg("user", "keep me"));
+
+        let report = smart_truncate_report(&tokenizer, &template, &msgs, None, 15).unwrap();
+        assert_eq!(
+            report.prompt,
+            smart_truncate(&tokenizer, &template, &msgs, None, 15).unwrap()
+        );
+        // Dropped are the oldest fillers, in order, never the system prompt or the last message
+        assert!(!report.dropped.is_empty());
+        assert_eq!(report.dropped[0], 1);
+        assert!(report.dropped.windows(2).all(|w| w[1] == w[0] + 1));
+        assert!(!report.dropped.contains(&0));
+        assert!(!report.dropped.contains(&(msgs.len() - 1)));
+        assert!(report.prompt_tokens <= 15);
+
+        // Everything fits: nothing dropped
+        let report = smart_truncate_report(&tokenizer, &template, &msgs, None, 1000).unwrap();
+        assert!(report.dropped.is_empty());
+    }
 }
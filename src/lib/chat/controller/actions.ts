@@ -34,6 +34,9 @@ export function createActions(ctx: ChatControllerCtx) {
     let loadUnlisten: (() => void) | null = null;
     let lastLoadProgressAt = 0;
 
+    // Id of the running generation, used to cancel exactly this request
+    let activeRequestId: string | null = null;
+
     async function ensureLoadProgressListener() {
         if (loadUnlisten) return;
         try {
@@ -347,6 +350,7 @@ export function createActions(ctx: ChatControllerCtx) {
     async function generateFromHistory() {
         ctx.busy = true;
         chatState.update(s => ({ ...s, busy: true }));
+        activeRequestId = crypto.randomUUID();
         try {
             await stream.ensureListener();
 
@@ -391,6 +395,7 @@ export function createActions(ctx: ChatControllerCtx) {
                     split_prompt: !!ctx.split_prompt,
                     verbose_prompt: !!ctx.verbose_prompt,
                     tracing: !!ctx.tracing,
+                    request_id: activeRequestId,
                 },
             });
         } catch (e) {
@@ -406,6 +411,7 @@ export function createActions(ctx: ChatControllerCtx) {
                 await message(err, { title: get(t)('chat.errors.generationFailed'), kind: 'error' });
             } catch { /* ignore */ }
         } finally {
+            activeRequestId = null;
             ctx.busy = false;
             chatState.update(s => ({ ...s, busy: false }));
         }
@@ -415,9 +421,14 @@ export function createActions(ctx: ChatControllerCtx) {
         console.log('[stopGenerate] called');
         try {
             const { invoke } = await import('@tauri-apps/api/core');
-            console.log('[stopGenerate] invoking cancel_generation');
-            await invoke('cancel_generation');
-            console.log('[stopGenerate] cancel_generation completed');
+            if (activeRequestId) {
+                console.log('[stopGenerate] invoking cancel_generation_request', activeRequestId);
+                await invoke('cancel_generation_request', { requestId: activeRequestId });
+            } else {
+                console.log('[stopGenerate] invoking cancel_generation');
+                await invoke('cancel_generation');
+            }
+            console.log('[stopGenerate] cancel completed');
 
             // Save partial generation
             const { chatHistory } = await import('$lib/stores/chat-history');
@@ -534,6 +545,7 @@ export function createActions(ctx: ChatControllerCtx) {
     async function generateFromHistoryWithIndex(editIndex?: number) {
         ctx.busy = true;
         chatState.update(s => ({ ...s, busy: true }));
+        activeRequestId = crypto.randomUUID();
         try {
             await stream.ensureListener();
 
@@ -566,6 +578,7 @@ export function createActions(ctx: ChatControllerCtx) {
                     verbose_prompt: !!ctx.verbose_prompt,
                     tracing: !!ctx.tracing,
                     edit_index: editIndex,
+                    request_id: activeRequestId,
                 },
             });
         } catch (e) {
@@ -581,6 +594,7 @@ export function createActions(ctx: ChatControllerCtx) {
                 await message(err, { title: get(t)('chat.errors.generationFailed'), kind: 'error' });
             } catch { /* ignore */ }
         } finally {
+            activeRequestId = null;
             ctx.busy = false;
             chatState.update(s => ({ ...s, busy: false }));
         }
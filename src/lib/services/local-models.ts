@@ -8,8 +8,10 @@ import type {
     DownloadJob,
     DownloadManagerSnapshot,
     DownloadProgressPayload,
+    EncoderModelInfo,
     FilterOptions,
     ModelInfo,
+    RemoteGGUFFile,
     RemoteModelFilters,
     RemoteModelInfo,
     SortField,
@@ -117,39 +119,56 @@ export class LocalModelsService {
 
     /**
      * Download a remote GGUF file and place it in destination directory.
+     * Split models queue every part under one `group_id`, so the download
+     * manager shows them as a single download.
      */
     static async downloadRemoteModel(
         repoId: string,
-        filename: string,
+        file: RemoteGGUFFile,
         destinationDir: string,
-        downloadUrl: string,
-        totalBytes?: number,
-        sha256?: string,
-    ): Promise<DownloadJob> {
+    ): Promise<DownloadJob[]> {
+        const parts = file.shards?.length ? file.shards : [file];
+        const groupId = parts.length > 1 ? `${repoId}::${file.filename}` : undefined;
         try {
-            const request: Record<string, unknown> = {
-                repo_id: repoId,
-                filename,
-                download_url: downloadUrl,
-                destination_dir: destinationDir,
-            };
-
-            if (typeof totalBytes === 'number') {
-                request.total_bytes = totalBytes;
-            }
-            if (sha256) {
-                request.sha256 = sha256;
+            const { invoke } = await import('@tauri-apps/api/core');
+            const jobs: DownloadJob[] = [];
+            for (const part of parts) {
+                const request: Record<string, unknown> = {
+                    repo_id: repoId,
+                    filename: part.filename,
+                    download_url: part.download_url,
+                    destination_dir: destinationDir,
+                    total_bytes: part.size,
+                };
+                if (part.sha256) {
+                    request.sha256 = part.sha256;
+                }
+                if (groupId) {
+                    request.group_id = groupId;
+                    request.display_name = `${repoId} (${file.filename})`;
+                }
+                jobs.push(await invoke<DownloadJob>('start_model_download', { request }));
             }
+            return jobs;
+        } catch (error) {
+            console.error('Failed to download model:', error);
+            throw new Error(`Failed to download model: ${error}`);
+        }
+    }
 
-
-
+    /**
+     * Load a local encoder into the embedding or reranker slot chosen by its config.
+     * The chat model stays loaded.
+     */
+    static async loadEncoderModel(modelPath: string): Promise<EncoderModelInfo> {
+        try {
             const { invoke } = await import('@tauri-apps/api/core');
-            return await invoke<DownloadJob>('start_model_download', {
-                request,
+            return await invoke<EncoderModelInfo>('load_encoder_model', {
+                req: { format: 'local_safetensors', model_path: modelPath },
             });
         } catch (error) {
-            console.error('Failed to download model:', error);
-            throw new Error(`Failed to download model: ${error}`);
+            console.error('Failed to load encoder model:', error);
+            throw new Error(`Failed to load encoder model: ${error}`);
         }
     }
 
@@ -329,7 +348,7 @@ export class LocalModelsService {
                 return false;
             }
 
-            if (options.candleOnly && !model.candle_compatible) {
+            if (options.candleOnly && !model.candle_compatible && !model.encoder_task) {
                 return false;
             }
 
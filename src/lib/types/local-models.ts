@@ -75,12 +75,27 @@ export interface ModelInfo {
     source_repo_id?: string;
     source_repo_name?: string;
     source_quantization?: string;
+    /** BERT-family encoder: loads into the embedding or reranker slot */
+    encoder_task?: 'embedding' | 'rerank';
     candle_compatible: boolean;
     validation_status: ValidationStatus;
     created_at: string;
     metadata: GGUFMetadata;
 }
 
+/**
+ * Encoder loaded into the embedding or reranker slot (`load_encoder_model`).
+ */
+export interface EncoderModelInfo {
+    id: string;
+    arch: string;
+    task: 'embedding' | 'rerank';
+    hidden_size: number;
+    max_length: number;
+    pooling: 'mean' | 'last_token' | 'cls';
+    normalize: boolean;
+}
+
 /**
  * Cache entry for local models scan results.
  */
@@ -123,6 +138,8 @@ export interface RemoteGGUFFile {
     sha256?: string;
     quantization?: string;
     download_url: string;
+    /** Parts of a split model in order; `size` is the total of all parts. */
+    shards?: RemoteGGUFFile[];
 }
 
 /**
@@ -36,6 +36,13 @@ export interface InferenceMetrics {
     prefill_tokens_per_second: number;
     memory_usage_mb: number;
     timestamp: string;
+    speculative?: SpeculativeStats;
+}
+
+export interface SpeculativeStats {
+    draft_steps: number;
+    drafted_tokens: number;
+    accepted_tokens: number;
 }
 
 export interface StartupMetrics {